@@ -0,0 +1,116 @@
+// Throughput of the scrubber, in bytes per second. Memory sizes, in
+// MiB, can be set with MEMSCRUB_BENCH_SIZES, for example:
+//
+//      MEMSCRUB_BENCH_SIZES=16,256,1024 cargo bench
+//
+// The cache geometry can be set with MEMSCRUB_BENCH_LINE, the line size in
+// bytes, and MEMSCRUB_BENCH_INDEX_WIDTH, the number of cache index bits.
+//
+// The order benchmarks walk a ScrubOrder without reading memory, to
+// measure the cost of computing the order itself. Many small areas are
+// the hard case, since most areas have no line with a given cache index.
+// The number of areas can be set with MEMSCRUB_BENCH_AREAS. The fast
+// benchmarks walk the same order with ScrubOrder::try_for_each_fast().
+
+use std::env;
+
+use criterion::{
+    black_box, criterion_group, criterion_main, BenchmarkId, Criterion,
+    Throughput,
+};
+use memscrublib::{BenchMemory, BenchStrategy, ScrubOrder};
+
+const MIB: usize = 1024 * 1024;
+
+// Read a number, or a comma-separated list of numbers, from the environment
+fn env_list(name: &str, default: &[usize]) -> Vec<usize> {
+    match env::var(name) {
+        Ok(value) => value
+            .split(',')
+            .map(|v| v.trim().parse().expect("invalid number"))
+            .collect(),
+        Err(_) => default.to_vec(),
+    }
+}
+
+fn bench_strategies(c: &mut Criterion) {
+    let sizes = env_list("MEMSCRUB_BENCH_SIZES", &[4, 64]);
+    let line = env_list("MEMSCRUB_BENCH_LINE", &[64])[0];
+    let width = env_list("MEMSCRUB_BENCH_INDEX_WIDTH", &[10])[0];
+
+    let mut group = c.benchmark_group("scrub");
+    for size in sizes {
+        let mem = BenchMemory::new(size * MIB, line, width)
+            .expect("invalid benchmark geometry");
+        group.throughput(Throughput::Bytes(mem.size() as u64));
+
+        for (name, strategy) in [
+            ("sequential", BenchStrategy::Sequential),
+            ("cache_aware", BenchStrategy::CacheAware),
+            ("batched", BenchStrategy::Batched(1024)),
+            ("fast", BenchStrategy::Fast),
+        ] {
+            group.bench_with_input(
+                BenchmarkId::new(name, format!("{}MiB", size)),
+                &strategy,
+                |b, &strategy| {
+                    b.iter(|| black_box(mem.scrub(strategy).unwrap()))
+                },
+            );
+        }
+    }
+    group.finish();
+}
+
+fn bench_order(c: &mut Criterion) {
+    let counts = env_list("MEMSCRUB_BENCH_AREAS", &[1, 64, 4096]);
+    let line = env_list("MEMSCRUB_BENCH_LINE", &[64])[0];
+    let width = env_list("MEMSCRUB_BENCH_INDEX_WIDTH", &[10])[0];
+
+    // 16 MiB of lines, split into areas each followed by a gap of a line
+    let lines = 16 * MIB / line;
+    let mut group = c.benchmark_group("order");
+    group.throughput(Throughput::Elements(lines as u64));
+    for count in counts {
+        let per_area = lines / count;
+        let extents: Vec<(usize, usize)> = (0..count)
+            .map(|i| {
+                let start = i * (per_area + 1) * line;
+                (start, start + per_area * line - 1)
+            })
+            .collect();
+        group.bench_with_input(
+            BenchmarkId::new("areas", count),
+            &extents,
+            |b, extents| {
+                b.iter(|| {
+                    let order =
+                        ScrubOrder::new(extents, line, width).unwrap();
+                    black_box(order.fold(0, |sum, addr| sum ^ addr))
+                })
+            },
+        );
+        group.bench_with_input(
+            BenchmarkId::new("fast", count),
+            &extents,
+            |b, extents| {
+                b.iter(|| {
+                    let mut order =
+                        ScrubOrder::new(extents, line, width).unwrap();
+                    let mut sum = 0;
+                    order
+                        .try_for_each_fast(|addr| {
+                            sum ^= addr;
+                            Ok::<(), ()>(())
+                        })
+                        .unwrap();
+                    black_box(sum)
+                })
+            },
+        );
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_strategies, bench_order);
+criterion_main!(benches);
@@ -0,0 +1,30 @@
+// Build script. With the cpp feature, the test of the C++ wrapper in
+// include/ is compiled into a static library, which only the tests link.
+// Tests only run on the machine doing the build, so nothing is compiled
+// when cross-compiling, where a C++20 compiler for the target may not be
+// at hand. The memscrub_cpp_test cfg tells the tests whether the library
+// was built.
+
+fn main() {
+    #[cfg(feature = "cpp")]
+    {
+        println!("cargo:rerun-if-changed=include");
+        let host = std::env::var("HOST").unwrap();
+        let target = std::env::var("TARGET").unwrap();
+        if host != target {
+            return;
+        }
+
+        cc::Build::new()
+            .cpp(true)
+            .std("c++20")
+            .flag("-fno-exceptions")
+            .flag("-fno-rtti")
+            .file("include/test/memscrub_test.cpp")
+            .cargo_metadata(false)
+            .compile("memscrub_cpp_test");
+        let out = std::env::var("OUT_DIR").unwrap();
+        println!("cargo:rustc-link-search=native={}", out);
+        println!("cargo:rustc-cfg=memscrub_cpp_test");
+    }
+}
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use memscrublib::{check_line_scrubber, FuzzInput};
+
+fuzz_target!(|input: FuzzInput| {
+    check_line_scrubber(&input);
+});
@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use memscrublib::check_parsers;
+
+fuzz_target!(|data: &[u8]| {
+    check_parsers(data);
+});
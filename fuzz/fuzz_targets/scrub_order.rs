@@ -0,0 +1,8 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use memscrublib::{check_scrub_order, FuzzInput};
+
+fuzz_target!(|input: FuzzInput| {
+    check_scrub_order(&input);
+});
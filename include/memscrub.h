@@ -0,0 +1,190 @@
+/*
+ * C interface to memscrublib, built with the ffi feature. See src/ffi.rs
+ * for details of each function. The static library to link against is
+ * built with:
+ *
+ *  cargo rustc --lib --release --features ffi --crate-type staticlib
+ */
+
+#ifndef MEMSCRUB_H
+#define MEMSCRUB_H
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+/*
+ * Version of the interface described here. Structures passed to the
+ * library carry this version, so a library built for another version can
+ * refuse them rather than misread them.
+ */
+#define MEMSCRUB_ABI_VERSION 2
+
+/*
+ * How cache lines are read. MEMSCRUB_READ_PORTABLE works everywhere and
+ * MEMSCRUB_READ_NATIVE picks the best for the architecture the library
+ * was built for. The others are only available on their architecture.
+ * MEMSCRUB_READ_X86_64 reads with a non-temporal prefetchnta and
+ * MEMSCRUB_READ_AARCH64 with a non-temporal LDNP followed by DC CIVAC,
+ * so that scrubbing disturbs the cache less.
+ */
+enum memscrub_read_strategy {
+    MEMSCRUB_READ_PORTABLE = 0,
+    MEMSCRUB_READ_NATIVE = 1,
+    MEMSCRUB_READ_X86_64 = 2,
+    MEMSCRUB_READ_AARCH64 = 3,
+    MEMSCRUB_READ_RISCV64 = 4,
+};
+
+/*
+ * Configuration of a scrubber. abi_version must be MEMSCRUB_ABI_VERSION,
+ * as set by MEMSCRUB_CONFIG_INIT. read_strategy is an
+ * enum memscrub_read_strategy.
+ */
+struct memscrub_config {
+    uint32_t abi_version;
+    uint32_t read_strategy;
+    size_t line_size;
+    size_t index_width;
+};
+
+#define MEMSCRUB_CONFIG_INIT(read_strategy, line_size, index_width) \
+    { MEMSCRUB_ABI_VERSION, (read_strategy), (line_size), (index_width) }
+
+/*
+ * A scrub area. start is on a cache line boundary and end, which is
+ * inclusive, is one less than a cache line boundary.
+ */
+struct memscrub_area {
+    uintptr_t start;
+    uintptr_t end;
+};
+
+/* Statistics for a scrubber. Times are in nanoseconds. */
+struct memscrub_stats {
+    uint64_t bytes_scrubbed;
+    uint64_t chunks;
+    uint64_t passes;
+    uint64_t epoch;
+    uint64_t pass_offset;
+    uint64_t pass_size;
+    uint64_t scrub_ns;
+    uint64_t max_chunk_ns;
+    uint64_t uptime_ns;
+    uint64_t pass_errors;
+    uint64_t last_pass_errors;
+    uint64_t errors_corrected;
+    uint64_t errors_uncorrected;
+    uint64_t n_areas;
+};
+
+/* Value of staleness_ns for an area not yet completely scrubbed */
+#define MEMSCRUB_NEVER UINT64_MAX
+
+/*
+ * Status of a scrub area. last_epoch is zero until a pass covering the
+ * area has completed. error_rate is in corrected errors per hour.
+ */
+struct memscrub_area_status {
+    uintptr_t start;
+    uintptr_t end;
+    uint64_t staleness_ns;
+    uint64_t last_epoch;
+    uint64_t errors_corrected;
+    uint64_t errors_uncorrected;
+    uint64_t excluded_lines;
+    double error_rate;
+    uint32_t priority;
+};
+
+/* Result of a call. Everything other than MEMSCRUB_OK is a failure. */
+enum memscrub_status {
+    MEMSCRUB_OK = 0,
+    MEMSCRUB_INTERNAL_ERROR = 1,
+    MEMSCRUB_UNALIGNED_START = 2,
+    MEMSCRUB_UNALIGNED_END = 3,
+    MEMSCRUB_UNALIGNED_SIZE = 4,
+    MEMSCRUB_UNALIGNED_VALUE = 5,
+    MEMSCRUB_NO_MEM_AREAS = 6,
+    MEMSCRUB_EMPTY_MEM_AREA = 7,
+    MEMSCRUB_ZERO_SIZE = 8,
+    MEMSCRUB_ITERATOR_FAILED = 9,
+    MEMSCRUB_CHECKPOINT_MISMATCH = 10,
+    MEMSCRUB_ADDRESS_OVERFLOW = 11,
+    MEMSCRUB_NULL_POINTER = 12,
+    MEMSCRUB_ABI_MISMATCH = 13,
+    MEMSCRUB_UNSUPPORTED = 14,
+    MEMSCRUB_GUARD_HIT = 15,
+    MEMSCRUB_UNTRANSLATED = 16,
+    MEMSCRUB_TOO_MANY_AREAS = 17,
+    MEMSCRUB_OVERLAP = 18,
+    MEMSCRUB_FAST_PATH_UNAVAILABLE = 19,
+    MEMSCRUB_INVERTED_RANGE = 20,
+    MEMSCRUB_IO_FAILED = 21,
+    MEMSCRUB_MIXED_LINE_SIZES = 22,
+    MEMSCRUB_NO_SUCH_AREA = 23,
+    MEMSCRUB_MAP_FAILED = 24,
+    MEMSCRUB_NO_SUCH_GROUP = 25,
+    MEMSCRUB_DUPLICATE_GROUP = 26,
+    MEMSCRUB_NOT_ADDRESS_ORDER = 27,
+    MEMSCRUB_TOO_MANY_SELECTORS = 28,
+    MEMSCRUB_SEND_FAILED = 29,
+};
+
+/* A scrubber, only ever used through a pointer */
+struct memscrub_handle;
+
+/*
+ * Returns the version of the interface implemented by the library. It
+ * should be checked against MEMSCRUB_ABI_VERSION before anything else.
+ */
+uint32_t memscrub_abi_version(void);
+
+/* Returns whether a read strategy is available in the library */
+bool memscrub_read_strategy_supported(uint32_t strategy);
+
+/*
+ * Create a scrubber for n_areas areas, which must stay mapped until the
+ * scrubber is destroyed. *handle is set to the scrubber, or to NULL on
+ * failure. Returns MEMSCRUB_ABI_MISMATCH if config is for another version
+ * of the interface, MEMSCRUB_UNSUPPORTED if the read strategy isn't
+ * available and MEMSCRUB_NULL_POINTER if an area starts at address zero.
+ */
+enum memscrub_status memscrub_create(const struct memscrub_config *config,
+    const struct memscrub_area *areas, size_t n_areas,
+    struct memscrub_handle **handle);
+
+/* Scrub the next bytes, a multiple of the cache line size */
+enum memscrub_status memscrub_scrub(struct memscrub_handle *handle,
+    size_t bytes);
+
+/* Get the statistics for a scrubber */
+enum memscrub_status memscrub_get_stats(
+    const struct memscrub_handle *handle, struct memscrub_stats *stats);
+
+/*
+ * Get the status of area number area, less than stats.n_areas. Returns
+ * MEMSCRUB_NO_SUCH_AREA if there is no such area.
+ */
+enum memscrub_status memscrub_get_area_status(
+    const struct memscrub_handle *handle, size_t area,
+    struct memscrub_area_status *status);
+
+/* Destroy a scrubber. A NULL handle is ignored. */
+void memscrub_destroy(struct memscrub_handle *handle);
+
+/*
+ * Returns the name of a status as a static string, or "unknown" for a
+ * value the library doesn't know of
+ */
+const char *memscrub_status_name(uint32_t status);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* MEMSCRUB_H */
@@ -0,0 +1,163 @@
+// C++ wrapper for the C interface to memscrublib, built with the cpp
+// feature. A memscrub::Scrubber owns its handle and destroys it when it
+// goes out of scope. Nothing here throws: failures are returned as a
+// memscrub::Status, so the wrapper can be used in code built with
+// -fno-exceptions. Requires C++20 for std::span.
+
+#ifndef MEMSCRUB_HPP
+#define MEMSCRUB_HPP
+
+#include <cstddef>
+#include <cstdint>
+#include <span>
+#include <utility>
+
+#include "memscrub.h"
+
+namespace memscrub {
+
+// Result of an operation, with the values of enum memscrub_status
+enum class Status : int {
+    Ok = MEMSCRUB_OK,
+    InternalError = MEMSCRUB_INTERNAL_ERROR,
+    UnalignedStart = MEMSCRUB_UNALIGNED_START,
+    UnalignedEnd = MEMSCRUB_UNALIGNED_END,
+    UnalignedSize = MEMSCRUB_UNALIGNED_SIZE,
+    UnalignedValue = MEMSCRUB_UNALIGNED_VALUE,
+    NoMemAreas = MEMSCRUB_NO_MEM_AREAS,
+    EmptyMemArea = MEMSCRUB_EMPTY_MEM_AREA,
+    ZeroSize = MEMSCRUB_ZERO_SIZE,
+    IteratorFailed = MEMSCRUB_ITERATOR_FAILED,
+    CheckpointMismatch = MEMSCRUB_CHECKPOINT_MISMATCH,
+    AddressOverflow = MEMSCRUB_ADDRESS_OVERFLOW,
+    NullPointer = MEMSCRUB_NULL_POINTER,
+    AbiMismatch = MEMSCRUB_ABI_MISMATCH,
+    Unsupported = MEMSCRUB_UNSUPPORTED,
+    GuardHit = MEMSCRUB_GUARD_HIT,
+    Untranslated = MEMSCRUB_UNTRANSLATED,
+    TooManyAreas = MEMSCRUB_TOO_MANY_AREAS,
+    Overlap = MEMSCRUB_OVERLAP,
+    FastPathUnavailable = MEMSCRUB_FAST_PATH_UNAVAILABLE,
+    InvertedRange = MEMSCRUB_INVERTED_RANGE,
+    IoFailed = MEMSCRUB_IO_FAILED,
+    MixedLineSizes = MEMSCRUB_MIXED_LINE_SIZES,
+    NoSuchArea = MEMSCRUB_NO_SUCH_AREA,
+    MapFailed = MEMSCRUB_MAP_FAILED,
+    NoSuchGroup = MEMSCRUB_NO_SUCH_GROUP,
+    DuplicateGroup = MEMSCRUB_DUPLICATE_GROUP,
+    NotAddressOrder = MEMSCRUB_NOT_ADDRESS_ORDER,
+    TooManySelectors = MEMSCRUB_TOO_MANY_SELECTORS,
+    SendFailed = MEMSCRUB_SEND_FAILED,
+};
+
+// How cache lines are read, with the values of enum memscrub_read_strategy
+enum class ReadStrategy : std::uint32_t {
+    Portable = MEMSCRUB_READ_PORTABLE,
+    Native = MEMSCRUB_READ_NATIVE,
+    X86_64 = MEMSCRUB_READ_X86_64,
+    Aarch64 = MEMSCRUB_READ_AARCH64,
+    Riscv64 = MEMSCRUB_READ_RISCV64,
+};
+
+// Returns whether a read strategy is available in the library
+inline bool supported(ReadStrategy strategy) noexcept
+{
+    return memscrub_read_strategy_supported(
+        static_cast<std::uint32_t>(strategy));
+}
+
+// Returns whether the library implements the interface in the headers
+inline bool abi_compatible() noexcept
+{
+    return memscrub_abi_version() == MEMSCRUB_ABI_VERSION;
+}
+
+// Returns the name of a status
+inline const char *status_name(Status status) noexcept
+{
+    return memscrub_status_name(static_cast<std::uint32_t>(status));
+}
+
+using Area = memscrub_area;
+using Stats = memscrub_stats;
+using AreaStatus = memscrub_area_status;
+
+// Returns the area covering the bytes of a span. The span must start and
+// end on cache line boundaries and must not be empty.
+template <typename T, std::size_t N>
+inline Area area(std::span<T, N> memory) noexcept
+{
+    auto bytes = std::as_bytes(memory);
+    auto start = reinterpret_cast<std::uintptr_t>(bytes.data());
+    return Area{start, start + bytes.size() - 1};
+}
+
+// A scrubber, owning its handle. Scrubbers can be moved but not copied.
+class Scrubber {
+public:
+    // Create an empty scrubber, for assigning to later
+    Scrubber() noexcept = default;
+
+    // Create a scrubber for areas that must stay mapped while it exists.
+    // On failure, the scrubber is empty and status says why.
+    Scrubber(std::span<const Area> areas, std::size_t line_size,
+        std::size_t index_width, Status &status,
+        ReadStrategy strategy = ReadStrategy::Native) noexcept
+    {
+        memscrub_config config = MEMSCRUB_CONFIG_INIT(
+            static_cast<std::uint32_t>(strategy), line_size, index_width);
+        status = static_cast<Status>(memscrub_create(&config,
+            areas.data(), areas.size(), &handle_));
+    }
+
+    Scrubber(const Scrubber &) = delete;
+    Scrubber &operator=(const Scrubber &) = delete;
+
+    Scrubber(Scrubber &&other) noexcept
+        : handle_(std::exchange(other.handle_, nullptr))
+    {
+    }
+
+    Scrubber &operator=(Scrubber &&other) noexcept
+    {
+        if (this != &other) {
+            memscrub_destroy(handle_);
+            handle_ = std::exchange(other.handle_, nullptr);
+        }
+        return *this;
+    }
+
+    ~Scrubber() { memscrub_destroy(handle_); }
+
+    // Returns whether the scrubber was created successfully
+    explicit operator bool() const noexcept { return handle_ != nullptr; }
+
+    // Scrub the next bytes, a multiple of the cache line size
+    Status scrub(std::size_t bytes) noexcept
+    {
+        return static_cast<Status>(memscrub_scrub(handle_, bytes));
+    }
+
+    // Get the statistics for the scrubber
+    Status stats(Stats &stats) const noexcept
+    {
+        return static_cast<Status>(memscrub_get_stats(handle_, &stats));
+    }
+
+    // Get the status of an area
+    Status area_status(std::size_t area, AreaStatus &status) const noexcept
+    {
+        return static_cast<Status>(
+            memscrub_get_area_status(handle_, area, &status));
+    }
+
+    // Returns the handle, for calls to the C interface
+    memscrub_handle *handle() const noexcept { return handle_; }
+
+private:
+    memscrub_handle *handle_ = nullptr;
+};
+
+} // namespace memscrub
+
+#endif // MEMSCRUB_HPP
@@ -0,0 +1,101 @@
+// Test of the C++ wrapper, compiled by build.rs with the cpp feature and
+// run by the tests in src/ffi.rs. Returns zero on success, otherwise the
+// number of the check that failed.
+
+#include <array>
+#include <cstddef>
+#include <span>
+
+#include "../memscrub.hpp"
+
+// The same layout as checked by test_layout() in src/ffi.rs
+constexpr std::size_t WORD = sizeof(std::size_t);
+constexpr std::size_t WORD_ALIGN = alignof(std::size_t);
+constexpr std::size_t U64_ALIGN = alignof(std::uint64_t);
+
+// Offset of the field after one at an offset with a size, where the field
+// has an alignment
+constexpr std::size_t after(std::size_t offset, std::size_t size,
+    std::size_t align)
+{
+    return (offset + size + align - 1) / align * align;
+}
+
+constexpr std::size_t LINE_SIZE = after(4, 4, WORD_ALIGN);
+constexpr std::size_t STALENESS = after(WORD, WORD, U64_ALIGN);
+
+static_assert(sizeof(memscrub_area) == 2 * WORD);
+static_assert(offsetof(memscrub_area, start) == 0);
+static_assert(offsetof(memscrub_area, end) == WORD);
+static_assert(offsetof(memscrub_config, abi_version) == 0);
+static_assert(offsetof(memscrub_config, read_strategy) == 4);
+static_assert(offsetof(memscrub_config, line_size) == LINE_SIZE);
+static_assert(offsetof(memscrub_config, index_width) == LINE_SIZE + WORD);
+static_assert(sizeof(memscrub_config) ==
+    after(LINE_SIZE + WORD, WORD, WORD_ALIGN > 4 ? WORD_ALIGN : 4));
+static_assert(sizeof(memscrub_stats) == 14 * 8);
+static_assert(offsetof(memscrub_stats, pass_offset) == 4 * 8);
+static_assert(offsetof(memscrub_stats, n_areas) == 13 * 8);
+static_assert(offsetof(memscrub_area_status, staleness_ns) == STALENESS);
+static_assert(offsetof(memscrub_area_status, error_rate) == STALENESS + 40);
+static_assert(offsetof(memscrub_area_status, priority) == STALENESS + 48);
+static_assert(sizeof(memscrub_area_status) == after(STALENESS + 48, 4,
+    WORD_ALIGN > U64_ALIGN ? WORD_ALIGN : U64_ALIGN));
+static_assert(sizeof(memscrub_status) == 4);
+static_assert(MEMSCRUB_ABI_MISMATCH == 13);
+static_assert(MEMSCRUB_UNSUPPORTED == 14);
+static_assert(MEMSCRUB_READ_RISCV64 == 4);
+
+extern "C" int memscrub_cpp_test()
+{
+    if (!memscrub::abi_compatible())
+        return 8;
+
+    alignas(64) static std::array<std::byte, 4096> memory;
+    std::array<memscrub::Area, 1> areas = {
+        memscrub::area(std::span(memory)),
+    };
+
+    memscrub::Status status;
+    memscrub::Scrubber scrubber(areas, 64, 4, status);
+    if (status != memscrub::Status::Ok || !scrubber)
+        return 1;
+    if (scrubber.scrub(1024) != memscrub::Status::Ok)
+        return 2;
+    if (scrubber.scrub(100) != memscrub::Status::UnalignedSize)
+        return 3;
+
+    // Moving transfers the handle
+    memscrub::Scrubber moved = std::move(scrubber);
+    if (scrubber || !moved)
+        return 4;
+    if (moved.scrub(64) != memscrub::Status::Ok)
+        return 5;
+
+    memscrub::Stats stats;
+    memscrub::AreaStatus area;
+    if (moved.stats(stats) != memscrub::Status::Ok ||
+        stats.bytes_scrubbed != 1088 || stats.n_areas != 1)
+        return 9;
+    if (moved.area_status(0, area) != memscrub::Status::Ok ||
+        area.staleness_ns != MEMSCRUB_NEVER)
+        return 10;
+
+    // The portable strategy is always available
+    if (!memscrub::supported(memscrub::ReadStrategy::Portable))
+        return 11;
+    memscrub::Scrubber portable(areas, 64, 4, status,
+        memscrub::ReadStrategy::Portable);
+    if (status != memscrub::Status::Ok || portable.scrub(64) !=
+        memscrub::Status::Ok)
+        return 12;
+
+    // A failed scrubber is empty
+    areas[0].start += 1;
+    memscrub::Scrubber bad(areas, 64, 4, status);
+    if (status != memscrub::Status::UnalignedStart || bad)
+        return 6;
+    if (bad.scrub(64) != memscrub::Status::NullPointer)
+        return 7;
+    return 0;
+}
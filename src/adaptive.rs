@@ -0,0 +1,271 @@
+// Choosing how to read memory from the state of the system. Cached
+// volatile reads are the fastest way to scrub but fill the cache with
+// lines nobody else wants, while non-temporal reads leave the cache alone
+// but are slower. Which is better depends on what else is running: while
+// the system is idle the cache has nothing worth keeping, and while the
+// foreground work is busy it matters most. AdaptiveBackend holds one
+// backend of each kind and, at the start of each batch of reads, looks at
+// how fast the throttle policies that set the scrub rate, such as an
+// ImpactGuard or a SensorThrottle on load, allow scrubbing to go. At or
+// above a threshold rate it reads with the fast backend for the batch, and
+// below it with the low impact one. A policy that changes each time it is
+// asked is given to the scrubber in a SharedThrottle, and its rate is read
+// here through a RateReader, so both see the same rate.
+//
+// The mode only changes between batches, so each batch is started and
+// ended by the same backend.
+
+use crate::backend::*;
+use crate::base::*;
+use crate::clock::*;
+use crate::throttle::*;
+
+/// How cache lines are read during a batch
+///
+/// * `Fast` - With the fast backend, such as cached volatile reads, used
+///   while the system is idle
+///
+/// * `LowImpact` - With the low impact backend, such as non-temporal
+///   reads, used while the foreground work is busy
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReadMode {
+    Fast,
+    LowImpact,
+}
+
+/// A backend switching between a fast and a low impact backend for each
+/// batch of reads according to the state of the system
+///
+/// * `fast` - Backend used while the rate allowed is at the threshold or
+///   above
+///
+/// * `low_impact` - Backend used while the rate allowed is below the
+///   threshold
+///
+/// * `signals` - Policies giving the rate allowed, the smallest of which
+///   is used
+///
+/// * `threshold` - Fraction of the full rate at or above which the fast
+///   backend is used
+///
+/// * `clock` - Source of the time passed to the policies
+///
+/// * `mode` - Mode of the current or last batch
+///
+/// * `batches` - Number of batches read in each mode, fast then low
+///   impact
+pub struct AdaptiveBackend<'a, F: ScrubBackend, L: ScrubBackend> {
+    fast: F,
+    low_impact: L,
+    signals: Vec<Box<dyn ThrottlePolicy + 'a>>,
+    threshold: f64,
+    clock: Box<dyn Clock + 'a>,
+    mode: ReadMode,
+    batches: [u64; 2],
+}
+
+impl<'a, F: ScrubBackend, L: ScrubBackend> AdaptiveBackend<'a, F, L> {
+    /// Create an AdaptiveBackend with no signals, which reads in the fast
+    /// mode until one is added
+    ///
+    /// # Arguments:
+    /// * `fast` - Backend for when the system is idle
+    ///
+    /// * `low_impact` - Backend for when the foreground work is busy
+    ///
+    /// * `threshold` - Fraction of the full rate, from 0.0 to 1.0, at or
+    ///   above which the fast backend is used
+    pub fn new(
+        fast: F,
+        low_impact: L,
+        threshold: f64,
+    ) -> AdaptiveBackend<'a, F, L> {
+        AdaptiveBackend {
+            fast,
+            low_impact,
+            signals: Vec::new(),
+            threshold: threshold.clamp(0.0, 1.0),
+            clock: Box::new(SystemClock),
+            mode: ReadMode::Fast,
+            batches: [0; 2],
+        }
+    }
+
+    /// Add a policy whose rate is used to choose the mode. The policy is
+    /// asked at the start of every batch, so one that also paces the
+    /// scrubber should be added as the RateReader of its SharedThrottle.
+    pub fn add_signal(&mut self, signal: Box<dyn ThrottlePolicy + 'a>) {
+        self.signals.push(signal);
+    }
+
+    /// Set the clock giving the time passed to the policies
+    pub fn set_clock(&mut self, clock: Box<dyn Clock + 'a>) {
+        self.clock = clock;
+    }
+
+    /// Returns the mode of the current batch, or of the last one between
+    /// batches
+    pub fn mode(&self) -> ReadMode {
+        self.mode
+    }
+
+    /// Returns the number of batches read in a mode
+    pub fn batches(&self, mode: ReadMode) -> u64 {
+        self.batches[mode as usize]
+    }
+
+    /// Returns the fast backend
+    pub fn fast(&self) -> &F {
+        &self.fast
+    }
+
+    /// Returns the low impact backend
+    pub fn low_impact(&self) -> &L {
+        &self.low_impact
+    }
+
+    // Choose the mode for the next batch from the smallest rate the
+    // policies allow
+    fn select(&mut self) -> ReadMode {
+        let now = self.clock.now();
+        let rate = self
+            .signals
+            .iter_mut()
+            .map(|s| s.rate(now))
+            .fold(1.0, f64::min);
+        match rate >= self.threshold {
+            true => ReadMode::Fast,
+            false => ReadMode::LowImpact,
+        }
+    }
+}
+
+impl<F: ScrubBackend, L: ScrubBackend> ScrubBackend
+    for AdaptiveBackend<'_, F, L>
+{
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        match self.mode {
+            ReadMode::Fast => self.fast.read_line(addr),
+            ReadMode::LowImpact => self.low_impact.read_line(addr),
+        }
+    }
+
+    fn read_words(
+        &mut self,
+        addr: usize,
+        line_size: usize,
+        reads: usize,
+    ) -> Result<(), Error> {
+        match self.mode {
+            ReadMode::Fast => self.fast.read_words(addr, line_size, reads),
+            ReadMode::LowImpact => {
+                self.low_impact.read_words(addr, line_size, reads)
+            }
+        }
+    }
+
+    fn begin_batch(&mut self) -> Result<(), Error> {
+        self.mode = self.select();
+        self.batches[self.mode as usize] += 1;
+        match self.mode {
+            ReadMode::Fast => self.fast.begin_batch(),
+            ReadMode::LowImpact => self.low_impact.begin_batch(),
+        }
+    }
+
+    fn end_batch(&mut self) -> Result<(), Error> {
+        match self.mode {
+            ReadMode::Fast => self.fast.end_batch(),
+            ReadMode::LowImpact => self.low_impact.end_batch(),
+        }
+    }
+
+    fn end_window(&mut self) -> Result<(), Error> {
+        match self.mode {
+            ReadMode::Fast => self.fast.end_window(),
+            ReadMode::LowImpact => self.low_impact.end_window(),
+        }
+    }
+
+    fn window_size(&self) -> Option<usize> {
+        match self.mode {
+            ReadMode::Fast => self.fast.window_size(),
+            ReadMode::LowImpact => self.low_impact.window_size(),
+        }
+    }
+
+    fn flush_line(&mut self, addr: usize) -> Result<(), Error> {
+        match self.mode {
+            ReadMode::Fast => self.fast.flush_line(addr),
+            ReadMode::LowImpact => self.low_impact.flush_line(addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+    use std::cell::{Cell, RefCell};
+    use std::rc::Rc;
+    use std::time::Instant;
+
+    // A rate set by the test, like one set by foreground load, counting
+    // the times it is asked
+    struct Load(Rc<Cell<f64>>, Rc<Cell<usize>>);
+
+    impl ThrottlePolicy for Load {
+        fn rate(&mut self, _now: Instant) -> f64 {
+            self.1.set(self.1.get() + 1);
+            self.0.get()
+        }
+    }
+
+    #[test]
+    fn test_modes() {
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        let load = Rc::new(Cell::new(1.0));
+        let asked = Rc::new(Cell::new(0));
+        let mut backend = AdaptiveBackend::new(
+            Logger("fast", reads.clone()),
+            Logger("low", reads.clone()),
+            0.5,
+        );
+        let idle = Load(Rc::new(Cell::new(1.0)), Rc::new(Cell::new(0)));
+        backend.add_signal(Box::new(idle));
+
+        // The load paces scrubbing and is asked only for that
+        let mut pacing = SharedThrottle::new(Box::new(Load(
+            load.clone(),
+            asked.clone(),
+        )));
+        backend.add_signal(Box::new(pacing.reader()));
+        let mut scrubber =
+            LineScrubber::new(backend, &[(0x1000, 0x10ff)], 64, 0)
+                .unwrap();
+
+        let now = Instant::now();
+        pacing.rate(now);
+        scrubber.scrub(128).unwrap();
+        load.set(0.25);
+        assert_eq!(pacing.rate(now), 0.25);
+        scrubber.scrub(128).unwrap();
+        assert_eq!(asked.get(), 2);
+        assert_eq!(
+            *reads.borrow(),
+            ["fast 0x1000", "fast 0x1040", "low 0x1080", "low 0x10c0"]
+        );
+        let backend = scrubber.backend();
+        assert_eq!(backend.mode(), ReadMode::LowImpact);
+        assert_eq!(backend.batches(ReadMode::Fast), 1);
+        assert_eq!(backend.batches(ReadMode::LowImpact), 1);
+
+        load.set(0.5);
+        scrubber.scrub(64).unwrap();
+        assert_eq!(scrubber.backend().mode(), ReadMode::LowImpact);
+        pacing.rate(now);
+        scrubber.scrub(64).unwrap();
+        assert_eq!(scrubber.backend().mode(), ReadMode::Fast);
+        assert_eq!(asked.get(), 3);
+    }
+}
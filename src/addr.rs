@@ -9,6 +9,8 @@ use core::num::ParseIntError;
 use core::ops::{Add, AddAssign, Div, Mul, Rem, Sub, SubAssign};
 use core::ops::{BitAnd, Shl, Shr};
 use num_traits::{Num, One, Unsigned, Zero};
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::convert::From;
 
 pub trait AddrImplTrait<A>:
@@ -27,9 +29,12 @@ pub trait AddrImplTrait<A>:
 {
 }
 
-// Addr definitions
+// Addr definitions. With the serde feature, an Addr<A> is serialized as the
+// underlying integer.
 
 #[derive(Copy, Clone, Debug, PartialEq, PartialOrd)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(transparent))]
 pub struct Addr<A>(pub A);
 
 impl<A> fmt::Display for Addr<A>
@@ -286,6 +291,22 @@ where
     }
 }
 
+// Parse a number from a text file or kernel interface. It is in hex if it
+// starts with 0x or 0X and in the given radix otherwise. Surrounding
+// whitespace is ignored, and None is returned if the number doesn't parse
+// or doesn't fit in the result type.
+pub(crate) fn parse_number<T: TryFrom<u64>>(
+    s: &str,
+    radix: u32,
+) -> Option<T> {
+    let s = s.trim();
+    let n = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => u64::from_str_radix(hex, 16),
+        None => u64::from_str_radix(s, radix),
+    };
+    T::try_from(n.ok()?).ok()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -457,4 +478,26 @@ mod tests {
         let x4 = x0.s >> x3;
         println!("x4 ({:x})", x4);
     }
+
+    #[test]
+    fn test_parse_number() {
+        assert_eq!(parse_number::<usize>("4096", 10), Some(4096));
+        assert_eq!(parse_number::<usize>("0x1000", 10), Some(4096));
+        assert_eq!(parse_number::<u64>(" 0X1000\n", 10), Some(4096));
+        assert_eq!(parse_number::<u64>("1000", 16), Some(4096));
+        assert_eq!(parse_number::<u64>("0x1000", 16), Some(4096));
+        assert_eq!(parse_number::<u64>("1000g", 16), None);
+        assert_eq!(parse_number::<u64>("0x", 10), None);
+        assert_eq!(parse_number::<u32>("0x100000000", 10), None);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let x: PAddr = Addr::<BasePType>(0x1000);
+        let json = serde_json::to_string(&x).unwrap();
+        assert_eq!(json, "4096");
+        let y: PAddr = serde_json::from_str(&json).unwrap();
+        assert_eq!(x, y);
+    }
 }
@@ -0,0 +1,196 @@
+// Scrubbing through an alias of memory. Some platforms recommend reading
+// memory through a second, uncached mapping so that every read goes to
+// DRAM rather than possibly being satisfied by the cache. AliasBackend
+// translates the address of each line in a primary area to the same line
+// in its alias before reading it. The scrubber itself only ever sees the
+// primary addresses, so ordering, statistics and error attribution all
+// stay keyed to the primary areas. On Linux, UncachedMapping in
+// os/uncached.rs provides such an alias.
+
+use crate::backend::*;
+use crate::base::*;
+use crate::coverage::*;
+
+/// A primary area and the alias through which it is read
+///
+/// * `start` - First address in the primary area
+///
+/// * `end` - Last address in the primary area
+///
+/// * `alias` - Address of the alias of `start`
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AreaAlias {
+    pub start: usize,
+    pub end: usize,
+    pub alias: usize,
+}
+
+/// A backend reading primary areas through their aliases
+///
+/// * `backend` - Backend making the reads, at the alias addresses
+///
+/// * `aliases` - The aliased areas, sorted by primary address
+pub struct AliasBackend<B: ScrubBackend> {
+    backend: B,
+    aliases: Vec<AreaAlias>,
+}
+
+impl<B: ScrubBackend> AliasBackend<B> {
+    /// Create an AliasBackend with no aliases, which reads every address
+    /// directly
+    pub fn new(backend: B) -> AliasBackend<B> {
+        AliasBackend {
+            backend,
+            aliases: Vec::new(),
+        }
+    }
+
+    /// Read a primary area through an alias
+    ///
+    /// # Arguments:
+    /// * `primary` - (start, end) of the primary area, end inclusive
+    ///
+    /// * `alias` - (start, end) of the alias, end inclusive
+    ///
+    /// # Returns:
+    /// Ok(()) on success, Err(Error::UnalignedEnd) if the alias is not the
+    /// same size as the primary area, or Err(Error::Overlap) if the
+    /// primary area overlaps one already aliased
+    pub fn add_alias(
+        &mut self,
+        primary: (usize, usize),
+        alias: (usize, usize),
+    ) -> Result<(), Error> {
+        if primary.0 > primary.1 || alias.0 > alias.1 {
+            return Err(Error::EmptyMemArea);
+        }
+        if primary.1 - primary.0 != alias.1 - alias.0 {
+            return Err(Error::UnalignedEnd);
+        }
+        if self
+            .aliases
+            .iter()
+            .any(|a| a.start <= primary.1 && primary.0 <= a.end)
+        {
+            return Err(Error::Overlap);
+        }
+
+        let i = self.aliases.partition_point(|a| a.start < primary.0);
+        self.aliases.insert(
+            i,
+            AreaAlias {
+                start: primary.0,
+                end: primary.1,
+                alias: alias.0,
+            },
+        );
+        Ok(())
+    }
+
+    /// Returns the aliased areas
+    pub fn aliases(&self) -> &[AreaAlias] {
+        &self.aliases
+    }
+
+    /// Returns the address read for a primary address
+    pub fn translate(&self, addr: usize) -> usize {
+        let i = self.aliases.partition_point(|a| a.end < addr);
+        match self.aliases.get(i) {
+            Some(a) if a.start <= addr => a.alias + (addr - a.start),
+            _ => addr,
+        }
+    }
+
+    /// Returns the backend making the reads
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+}
+
+impl<B: ScrubBackend> ScrubBackend for AliasBackend<B> {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        let addr = self.translate(addr);
+        self.backend.read_line(addr)
+    }
+
+    fn read_words(
+        &mut self,
+        addr: usize,
+        line_size: usize,
+        reads: usize,
+    ) -> Result<(), Error> {
+        let addr = self.translate(addr);
+        self.backend.read_words(addr, line_size, reads)
+    }
+
+    fn begin_batch(&mut self) -> Result<(), Error> {
+        self.backend.begin_batch()
+    }
+
+    fn end_batch(&mut self) -> Result<(), Error> {
+        self.backend.end_batch()
+    }
+
+    fn end_window(&mut self) -> Result<(), Error> {
+        self.backend.end_window()
+    }
+
+    fn window_size(&self) -> Option<usize> {
+        self.backend.window_size()
+    }
+
+    fn flush_line(&mut self, addr: usize) -> Result<(), Error> {
+        let addr = self.translate(addr);
+        self.backend.flush_line(addr)
+    }
+
+    fn skip_reason(&mut self, addr: usize) -> Option<SkipReason> {
+        let addr = self.translate(addr);
+        self.backend.skip_reason(addr)
+    }
+
+    // A scrub area is aliased whole or not at all, so its alias is the
+    // range between the aliases of its ends
+    fn excluded_lines(&self, start: usize, end: usize) -> Option<u64> {
+        self.backend
+            .excluded_lines(self.translate(start), self.translate(end))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::*;
+
+    #[test]
+    fn test_alias() {
+        // The alias of 0x10000-0x10fff is at 0x40000
+        let mem = SimMemory::new(0x40000, 4096, 64).unwrap();
+        let mut backend = AliasBackend::new(mem);
+        backend
+            .add_alias((0x10000, 0x10fff), (0x40000, 0x40fff))
+            .unwrap();
+        assert_eq!(
+            backend.add_alias((0x10800, 0x117ff), (0x50000, 0x50fff)),
+            Err(Error::Overlap)
+        );
+        assert_eq!(
+            backend.add_alias((0x20000, 0x20fff), (0x50000, 0x507ff)),
+            Err(Error::UnalignedEnd)
+        );
+        assert_eq!(backend.translate(0x10040), 0x40040);
+        assert_eq!(backend.translate(0x11000), 0x11000);
+
+        let mut scrubber =
+            LineScrubber::new(backend, &[(0x10000, 0x10fff)], 64, 4)
+                .unwrap();
+        scrubber.scrub(4096).unwrap();
+        assert!(scrubber
+            .backend()
+            .backend()
+            .reads()
+            .iter()
+            .all(|&r| r == 1));
+        assert_eq!(scrubber.stats().areas[0].size, 4096);
+    }
+}
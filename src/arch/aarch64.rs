@@ -0,0 +1,48 @@
+// Reading a cache line on aarch64. LDNP is a load with a non-temporal hint,
+// telling the memory system that the line is unlikely to be used again so
+// that scrubbing disturbs the cache less.
+
+use std::arch::asm;
+
+use crate::backend::*;
+use crate::base::*;
+
+/// A backend reading memory at its virtual address with LDNP
+#[derive(Debug)]
+pub struct Aarch64Backend {
+    _private: (),
+}
+
+impl Aarch64Backend {
+    /// Create a backend reading real memory
+    ///
+    /// # Safety
+    /// Every address scrubbed must be mapped and readable for the life of
+    /// the backend
+    pub unsafe fn new() -> Aarch64Backend {
+        Aarch64Backend { _private: () }
+    }
+}
+
+impl ScrubBackend for Aarch64Backend {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        // The caller of new() promised that the address is readable. The
+        // two destination registers must differ.
+        unsafe {
+            asm!(
+                "ldnp {a}, {b}, [{addr}]",
+                addr = in(reg) addr,
+                a = out(reg) _,
+                b = out(reg) _,
+                options(nostack, readonly, preserves_flags),
+            );
+        }
+        Ok(())
+    }
+
+    fn flush_line(&mut self, addr: usize) -> Result<(), Error> {
+        // The caller of new() promised that the address is mapped
+        unsafe { super::flush_line(addr) };
+        Ok(())
+    }
+}
@@ -0,0 +1,154 @@
+// Per-architecture ways of reading a cache line. A volatile read through a
+// pointer works everywhere, but leaves the compiler free to choose the load
+// instruction; the backends here pick it themselves, and on aarch64 use a
+// load that hints the line needn't be kept in the cache. Each is enabled by
+// a cargo feature and built only for its own architecture. NativeBackend is
+// the one to use for the target being built for, falling back to the
+// portable RawBackend when no specialized backend is available.
+
+#[cfg(all(feature = "arch-aarch64", target_arch = "aarch64"))]
+mod aarch64;
+#[cfg(all(feature = "arch-riscv64", target_arch = "riscv64"))]
+mod riscv64;
+#[cfg(all(feature = "arch-x86_64", target_arch = "x86_64"))]
+mod x86_64;
+
+#[cfg(all(feature = "arch-aarch64", target_arch = "aarch64"))]
+pub use crate::arch::aarch64::*;
+#[cfg(all(feature = "arch-riscv64", target_arch = "riscv64"))]
+pub use crate::arch::riscv64::*;
+#[cfg(all(feature = "arch-x86_64", target_arch = "x86_64"))]
+pub use crate::arch::x86_64::*;
+
+/// The backend best suited to the target architecture
+#[cfg(all(feature = "arch-aarch64", target_arch = "aarch64"))]
+pub type NativeBackend = Aarch64Backend;
+
+/// The backend best suited to the target architecture
+#[cfg(all(feature = "arch-riscv64", target_arch = "riscv64"))]
+pub type NativeBackend = Riscv64Backend;
+
+/// The backend best suited to the target architecture
+#[cfg(all(feature = "arch-x86_64", target_arch = "x86_64"))]
+pub type NativeBackend = X86_64Backend;
+
+/// The backend best suited to the target architecture. No specialized
+/// backend is enabled for this one, so this is the portable RawBackend.
+#[cfg(not(any(
+    all(feature = "arch-aarch64", target_arch = "aarch64"),
+    all(feature = "arch-riscv64", target_arch = "riscv64"),
+    all(feature = "arch-x86_64", target_arch = "x86_64"),
+)))]
+pub type NativeBackend = crate::backend::RawBackend;
+
+/// Wait until all earlier memory accesses have completed, as some
+/// platforms require after a burst of reads before the errors they found
+/// are reported: mfence on x86_64, dsb sy on aarch64 and fence rw, rw on
+/// riscv64. Elsewhere this is a sequentially consistent fence.
+#[inline]
+pub fn memory_barrier() {
+    // The barriers only order memory accesses
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        std::arch::asm!("mfence", options(nostack, preserves_flags));
+    }
+    #[cfg(target_arch = "aarch64")]
+    unsafe {
+        std::arch::asm!("dsb sy", options(nostack, preserves_flags));
+    }
+    #[cfg(target_arch = "riscv64")]
+    unsafe {
+        std::arch::asm!("fence rw, rw", options(nostack, preserves_flags));
+    }
+    #[cfg(not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        target_arch = "riscv64"
+    )))]
+    std::sync::atomic::fence(std::sync::atomic::Ordering::SeqCst);
+}
+
+/// Write back and invalidate the cache line holding an address, so that
+/// no copy of it is left in the cache: clflush on x86_64, dc civac on
+/// aarch64 and, with the Zicbom extension, cbo.flush on riscv64. There is
+/// no instruction for this on other architectures.
+///
+/// # Safety
+/// The address must be mapped
+///
+/// # Returns:
+/// true if the line was flushed, false if this architecture can't
+#[inline]
+pub unsafe fn flush_line(addr: usize) -> bool {
+    #[cfg(target_arch = "x86_64")]
+    {
+        std::arch::asm!(
+            "clflush [{addr}]",
+            addr = in(reg) addr,
+            options(nostack, preserves_flags),
+        );
+        true
+    }
+    #[cfg(target_arch = "aarch64")]
+    {
+        std::arch::asm!(
+            "dc civac, {addr}",
+            "dsb sy",
+            addr = in(reg) addr,
+            options(nostack, preserves_flags),
+        );
+        true
+    }
+    #[cfg(all(target_arch = "riscv64", target_feature = "zicbom"))]
+    {
+        std::arch::asm!(
+            "cbo.flush 0({addr})",
+            "fence rw, rw",
+            addr = in(reg) addr,
+            options(nostack, preserves_flags),
+        );
+        true
+    }
+    #[cfg(not(any(
+        target_arch = "x86_64",
+        target_arch = "aarch64",
+        all(target_arch = "riscv64", target_feature = "zicbom")
+    )))]
+    {
+        let _ = addr;
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::*;
+
+    #[test]
+    fn test_native() {
+        let buffer = vec![0u8; 64 * 1024 + 64];
+        let start = (buffer.as_ptr() as usize).next_multiple_of(64);
+        let extents = [(start, start + 64 * 1024 - 1)];
+
+        // The buffer outlives the backend
+        let backend = unsafe { NativeBackend::new() };
+        let mut scrubber =
+            LineScrubber::new(backend, &extents, 64, 6).unwrap();
+        scrubber.scrub(64 * 1024).unwrap();
+        assert_eq!(scrubber.stats().bytes_scrubbed, 64 * 1024);
+    }
+
+    #[test]
+    fn test_flush() {
+        let buffer = [1u8; 64];
+        memory_barrier();
+        // The buffer is mapped
+        let flushed = unsafe { flush_line(buffer.as_ptr() as usize) };
+        assert_eq!(
+            flushed,
+            cfg!(any(target_arch = "x86_64", target_arch = "aarch64"))
+        );
+        assert_eq!(buffer[0], 1);
+    }
+}
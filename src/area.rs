@@ -0,0 +1,617 @@
+// Scrub areas in a form that can't be invalid. Scrub areas come in from C
+// and from configuration as a pair of addresses, which allows a null
+// start, an end before the start and ends that aren't on cache line
+// boundaries, and says nothing of whether the addresses are virtual or
+// physical. A VirtScrubArea holds a non-null virtual base on a cache line
+// boundary and a non-zero number of cache lines that fit in the address
+// space, so once an area has been converted, at the boundary where it
+// comes in, those checks need not be made again. A PhysScrubArea holds
+// the same for physical memory, whose base may be zero. It can't be
+// scrubbed until it is mapped, as by UncachedMapping::map_area(), which
+// gives the VirtScrubArea of the mapping, so a physical address can't be
+// read as if it were virtual.
+//
+// Memory-mapped files and shared memory segments are common things to
+// scrub. A mapping starts on a page boundary but a file's length need not
+// be a multiple of the cache line size. With the memmap2 feature, an area
+// can be made from memmap2's Mmap and MmapMut, taking in the partial line
+// at the end, which is still within the last page of the mapping. Any
+// other memory is given as a byte slice to from_slice(), which leaves out
+// partial lines at either end since nothing says what lies beyond them.
+//
+// An area holds only addresses and doesn't borrow the memory. Keeping the
+// memory mapped while it is scrubbed is the obligation taken on when the
+// backend that reads it, such as a RawBackend, is created.
+//
+// A ScrubOrder made with ScrubOrder::for_virt_areas() takes the lines of
+// each area as they are, without checking them again. LineScrubber still
+// keeps (start, end) pairs, which is what extent() gives, so that areas of
+// simulated memory, which may start at zero, can be scrubbed too.
+
+use std::num::NonZeroUsize;
+use std::ptr::NonNull;
+
+use crate::base::*;
+
+/// A scrub area in virtual memory
+///
+/// * `base` - Address of the first byte, on a cache line boundary
+///
+/// * `lines` - Number of cache lines in the area
+///
+/// * `line_size` - Number of bytes in a cache line, a power of two
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VirtScrubArea {
+    base: NonNull<u8>,
+    lines: NonZeroUsize,
+    line_size: usize,
+}
+
+// The base is only an address. It is never dereferenced through the area,
+// which doesn't own or borrow the memory, so sharing or sending the area
+// between threads is no more unsafe than doing so with a usize. Reading
+// the memory is left to a backend, which makes its own guarantees.
+unsafe impl Send for VirtScrubArea {}
+unsafe impl Sync for VirtScrubArea {}
+
+impl VirtScrubArea {
+    /// Create a VirtScrubArea
+    ///
+    /// # Arguments:
+    /// * `base` - Address of the first byte, on a cache line boundary
+    ///
+    /// * `lines` - Number of cache lines in the area
+    ///
+    /// * `line_size` - Number of bytes in a cache line, a power of two
+    ///
+    /// # Returns:
+    /// Ok(VirtScrubArea) on success, otherwise Err(Error::UnalignedValue)
+    /// if the line size is not a power of two, Err(Error::UnalignedStart)
+    /// if the base is not on a cache line boundary or
+    /// Err(Error::AddressOverflow) if the area runs past the end of the
+    /// address space
+    pub fn new(
+        base: NonNull<u8>,
+        lines: NonZeroUsize,
+        line_size: usize,
+    ) -> Result<VirtScrubArea, Error> {
+        if !line_size.is_power_of_two() {
+            return Err(Error::UnalignedValue);
+        }
+        let start = base.as_ptr() as usize;
+        if !start.is_multiple_of(line_size) {
+            return Err(Error::UnalignedStart);
+        }
+        lines
+            .get()
+            .checked_mul(line_size)
+            .and_then(|size| start.checked_add(size - 1))
+            .ok_or(Error::AddressOverflow)?;
+        Ok(VirtScrubArea {
+            base,
+            lines,
+            line_size,
+        })
+    }
+
+    /// Create a VirtScrubArea from the addresses of its first and last
+    /// bytes
+    ///
+    /// # Arguments:
+    /// * `base` - Address of the first byte, on a cache line boundary
+    ///
+    /// * `end` - Address of the last byte, one less than a cache line
+    ///   boundary
+    ///
+    /// * `line_size` - Number of bytes in a cache line, a power of two
+    ///
+    /// # Returns:
+    /// Ok(VirtScrubArea) on success, otherwise Err(Error::EmptyMemArea)
+    /// if the end is before the base, Err(Error::UnalignedEnd) if it is
+    /// not one less than a cache line boundary, or as for new()
+    pub fn from_extent(
+        base: NonNull<u8>,
+        end: usize,
+        line_size: usize,
+    ) -> Result<VirtScrubArea, Error> {
+        if !line_size.is_power_of_two() {
+            return Err(Error::UnalignedValue);
+        }
+        let start = base.as_ptr() as usize;
+        if end < start {
+            return Err(Error::EmptyMemArea);
+        }
+        if !start.is_multiple_of(line_size) {
+            return Err(Error::UnalignedStart);
+        }
+        if !end.wrapping_add(1).is_multiple_of(line_size) {
+            return Err(Error::UnalignedEnd);
+        }
+        let lines = (end - start) / line_size + 1;
+        VirtScrubArea::new(
+            base,
+            NonZeroUsize::new(lines).ok_or(Error::EmptyMemArea)?,
+            line_size,
+        )
+    }
+
+    /// Create a VirtScrubArea covering the whole cache lines within a
+    /// slice. Partial lines at either end are left out. The memory must
+    /// stay valid for as long as the area is scrubbed.
+    ///
+    /// # Arguments:
+    /// * `bytes` - The memory, such as a memory-mapped file
+    ///
+    /// * `line_size` - Number of bytes in a cache line, a power of two
+    ///
+    /// # Returns:
+    /// Ok(VirtScrubArea) on success, otherwise Err(Error::EmptyMemArea)
+    /// if the slice holds no whole cache line, or as for new()
+    pub fn from_slice(
+        bytes: &[u8],
+        line_size: usize,
+    ) -> Result<VirtScrubArea, Error> {
+        let start = bytes.as_ptr() as usize;
+        match bytes.len() {
+            0 => Err(Error::EmptyMemArea),
+            len => {
+                VirtScrubArea::within(start, start + (len - 1), line_size)
+            }
+        }
+    }
+
+    /// Create a VirtScrubArea covering the whole cache lines within a
+    /// range of addresses. Partial lines at either end are left out.
+    ///
+    /// # Arguments:
+    /// * `start` - Address of the first byte of the range
+    ///
+    /// * `end` - Address of the last byte of the range, which may be the
+    ///   last address there is
+    ///
+    /// * `line_size` - Number of bytes in a cache line, a power of two
+    ///
+    /// # Returns:
+    /// Ok(VirtScrubArea) on success, otherwise Err(Error::EmptyMemArea)
+    /// if the range holds no whole cache line, or its first is at address
+    /// zero, or as for new()
+    pub fn within(
+        start: usize,
+        end: usize,
+        line_size: usize,
+    ) -> Result<VirtScrubArea, Error> {
+        if !line_size.is_power_of_two() {
+            return Err(Error::UnalignedValue);
+        }
+        let first = start
+            .checked_next_multiple_of(line_size)
+            .ok_or(Error::EmptyMemArea)?;
+        let last = match end.checked_add(1) {
+            Some(stop) => (stop & !(line_size - 1))
+                .checked_sub(1)
+                .ok_or(Error::EmptyMemArea)?,
+            None => end,
+        };
+        if end < start || last < first {
+            return Err(Error::EmptyMemArea);
+        }
+        VirtScrubArea::from_extent(
+            NonNull::new(first as *mut u8).ok_or(Error::EmptyMemArea)?,
+            last,
+            line_size,
+        )
+    }
+
+    // Create a VirtScrubArea covering a mapping of whole pages, such as a
+    // shared memory segment, taking in a partial cache line at its end.
+    // Since the mapping starts on a page boundary and a line is no larger
+    // than a page, the partial line ends within the last page.
+    //
+    // base:      Start of the mapping
+    // len:       Number of bytes in the mapping
+    // line_size: Number of bytes in a cache line, a power of two
+    // page_size: Number of bytes in a page, a power of two
+    pub(crate) fn from_pages(
+        base: NonNull<u8>,
+        len: usize,
+        line_size: usize,
+        page_size: usize,
+    ) -> Result<VirtScrubArea, Error> {
+        if !line_size.is_power_of_two()
+            || !page_size.is_power_of_two()
+            || line_size > page_size
+        {
+            return Err(Error::UnalignedValue);
+        }
+        if !(base.as_ptr() as usize).is_multiple_of(page_size) {
+            return Err(Error::UnalignedStart);
+        }
+        let lines = len.div_ceil(line_size);
+        VirtScrubArea::new(
+            base,
+            NonZeroUsize::new(lines).ok_or(Error::EmptyMemArea)?,
+            line_size,
+        )
+    }
+
+    /// Returns the address of the first byte
+    pub fn base(&self) -> NonNull<u8> {
+        self.base
+    }
+
+    /// Returns the number of cache lines in the area
+    pub fn lines(&self) -> NonZeroUsize {
+        self.lines
+    }
+
+    /// Returns the number of bytes in a cache line
+    pub fn line_size(&self) -> usize {
+        self.line_size
+    }
+
+    /// Returns the address of the first byte
+    pub fn start(&self) -> usize {
+        self.base.as_ptr() as usize
+    }
+
+    /// Returns the address of the last byte
+    pub fn end(&self) -> usize {
+        // Checked not to overflow when the area was created
+        self.start() + (self.lines.get() * self.line_size - 1)
+    }
+
+    /// Returns the address of the first and last bytes, as used for the
+    /// areas of a LineScrubber
+    pub fn extent(&self) -> (usize, usize) {
+        (self.start(), self.end())
+    }
+}
+
+// Returns the cache line size shared by some areas, or
+// Err(Error::NoMemAreas) if there are none or Err(Error::MixedLineSizes)
+// if their line sizes differ
+pub(crate) fn line_size_of(
+    areas: &[VirtScrubArea],
+) -> Result<usize, Error> {
+    let line_size = match areas.first() {
+        Some(area) => area.line_size(),
+        None => return Err(Error::NoMemAreas),
+    };
+    match areas.iter().all(|a| a.line_size() == line_size) {
+        true => Ok(line_size),
+        false => Err(Error::MixedLineSizes),
+    }
+}
+
+/// A scrub area in physical memory
+///
+/// * `base` - Physical address of the first byte, on a cache line
+///   boundary
+///
+/// * `lines` - Number of cache lines in the area
+///
+/// * `line_size` - Number of bytes in a cache line, a power of two
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PhysScrubArea {
+    base: u64,
+    lines: NonZeroUsize,
+    line_size: usize,
+}
+
+impl PhysScrubArea {
+    /// Create a PhysScrubArea
+    ///
+    /// # Arguments:
+    /// * `base` - Physical address of the first byte, on a cache line
+    ///   boundary
+    ///
+    /// * `lines` - Number of cache lines in the area
+    ///
+    /// * `line_size` - Number of bytes in a cache line, a power of two
+    ///
+    /// # Returns:
+    /// Ok(PhysScrubArea) on success, otherwise Err(Error::UnalignedValue)
+    /// if the line size is not a power of two, Err(Error::UnalignedStart)
+    /// if the base is not on a cache line boundary or
+    /// Err(Error::AddressOverflow) if the area runs past the end of the
+    /// physical address space or holds more bytes than a usize can count
+    pub fn new(
+        base: u64,
+        lines: NonZeroUsize,
+        line_size: usize,
+    ) -> Result<PhysScrubArea, Error> {
+        if !line_size.is_power_of_two() {
+            return Err(Error::UnalignedValue);
+        }
+        if !base.is_multiple_of(line_size as u64) {
+            return Err(Error::UnalignedStart);
+        }
+        lines
+            .get()
+            .checked_mul(line_size)
+            .and_then(|size| base.checked_add(size as u64 - 1))
+            .ok_or(Error::AddressOverflow)?;
+        Ok(PhysScrubArea {
+            base,
+            lines,
+            line_size,
+        })
+    }
+
+    /// Create a PhysScrubArea from the physical addresses of its first and
+    /// last bytes
+    ///
+    /// # Arguments:
+    /// * `base` - Address of the first byte, on a cache line boundary
+    ///
+    /// * `end` - Address of the last byte, one less than a cache line
+    ///   boundary
+    ///
+    /// * `line_size` - Number of bytes in a cache line, a power of two
+    ///
+    /// # Returns:
+    /// Ok(PhysScrubArea) on success, otherwise Err(Error::EmptyMemArea)
+    /// if the end is before the base, Err(Error::UnalignedEnd) if it is
+    /// not one less than a cache line boundary, or as for new()
+    pub fn from_extent(
+        base: u64,
+        end: u64,
+        line_size: usize,
+    ) -> Result<PhysScrubArea, Error> {
+        if !line_size.is_power_of_two() {
+            return Err(Error::UnalignedValue);
+        }
+        if end < base {
+            return Err(Error::EmptyMemArea);
+        }
+        if !end.wrapping_add(1).is_multiple_of(line_size as u64) {
+            return Err(Error::UnalignedEnd);
+        }
+        let lines = usize::try_from((end - base) / line_size as u64)
+            .ok()
+            .and_then(|lines| lines.checked_add(1))
+            .ok_or(Error::AddressOverflow)?;
+        PhysScrubArea::new(
+            base,
+            NonZeroUsize::new(lines).ok_or(Error::EmptyMemArea)?,
+            line_size,
+        )
+    }
+
+    /// Returns the physical address of the first byte
+    pub fn start(&self) -> u64 {
+        self.base
+    }
+
+    /// Returns the physical address of the last byte
+    pub fn end(&self) -> u64 {
+        // Checked not to overflow when the area was created
+        self.base + (self.lines.get() * self.line_size - 1) as u64
+    }
+
+    /// Returns the number of bytes in the area
+    pub fn size(&self) -> usize {
+        self.lines.get() * self.line_size
+    }
+
+    /// Returns the number of cache lines in the area
+    pub fn lines(&self) -> NonZeroUsize {
+        self.lines
+    }
+
+    /// Returns the number of bytes in a cache line
+    pub fn line_size(&self) -> usize {
+        self.line_size
+    }
+}
+
+// Returns the number of bytes in a page
+#[cfg(feature = "memmap2")]
+fn page_size() -> usize {
+    #[cfg(unix)]
+    return unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+    #[cfg(not(unix))]
+    4096
+}
+
+// Create a VirtScrubArea covering a memory-mapped file
+#[cfg(feature = "memmap2")]
+fn from_mmap(
+    bytes: &[u8],
+    line_size: usize,
+) -> Result<VirtScrubArea, Error> {
+    let base = NonNull::new(bytes.as_ptr() as *mut u8)
+        .ok_or(Error::EmptyMemArea)?;
+    VirtScrubArea::from_pages(base, bytes.len(), line_size, page_size())
+}
+
+/// Create a VirtScrubArea covering a read-only memory-mapped file, given
+/// with the number of bytes in a cache line. A partial cache line at the
+/// end of the file is included, since it ends within the mapped page.
+///
+/// # Returns:
+/// Ok(VirtScrubArea) on success, otherwise Err(Error::EmptyMemArea) if the
+/// mapping is empty, Err(Error::UnalignedStart) if it doesn't start on a
+/// page boundary, as when mapped from an offset that isn't a multiple of
+/// the page size, or Err(Error::UnalignedValue) if the line size isn't a
+/// power of two no larger than a page
+#[cfg(feature = "memmap2")]
+impl TryFrom<(&memmap2::Mmap, usize)> for VirtScrubArea {
+    type Error = Error;
+
+    fn try_from(
+        (mapping, line_size): (&memmap2::Mmap, usize),
+    ) -> Result<VirtScrubArea, Error> {
+        from_mmap(mapping, line_size)
+    }
+}
+
+/// Create a VirtScrubArea covering a writable memory-mapped file, as for
+/// a read-only one
+#[cfg(feature = "memmap2")]
+impl TryFrom<(&memmap2::MmapMut, usize)> for VirtScrubArea {
+    type Error = Error;
+
+    fn try_from(
+        (mapping, line_size): (&memmap2::MmapMut, usize),
+    ) -> Result<VirtScrubArea, Error> {
+        from_mmap(mapping, line_size)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn base(addr: usize) -> NonNull<u8> {
+        NonNull::new(addr as *mut u8).unwrap()
+    }
+
+    #[test]
+    fn test_area() {
+        let lines = NonZeroUsize::new(16).unwrap();
+        let area = VirtScrubArea::new(base(0x1000), lines, 64).unwrap();
+        assert_eq!(area.extent(), (0x1000, 0x13ff));
+        assert_eq!(area.lines(), lines);
+        assert_eq!(
+            VirtScrubArea::from_extent(base(0x1000), 0x13ff, 64),
+            Ok(area)
+        );
+
+        assert_eq!(
+            VirtScrubArea::new(base(0x1000), lines, 48),
+            Err(Error::UnalignedValue)
+        );
+        assert_eq!(
+            VirtScrubArea::new(base(0x1001), lines, 64),
+            Err(Error::UnalignedStart)
+        );
+        assert_eq!(
+            VirtScrubArea::new(base(usize::MAX - 0x3bf), lines, 64),
+            Err(Error::AddressOverflow)
+        );
+        assert_eq!(
+            VirtScrubArea::from_extent(base(0x1000), 0xfff, 64),
+            Err(Error::EmptyMemArea)
+        );
+        assert_eq!(
+            VirtScrubArea::from_extent(base(0x1000), 0x13fe, 64),
+            Err(Error::UnalignedEnd)
+        );
+
+        // An area may end at the very top of the address space
+        let top = VirtScrubArea::from_extent(
+            base(usize::MAX - 0x3ff),
+            usize::MAX,
+            64,
+        )
+        .unwrap();
+        assert_eq!(top.end(), usize::MAX);
+
+        // Partial lines at either end of a range are left out
+        assert_eq!(
+            VirtScrubArea::within(0xff1, 0x1410, 64).unwrap().extent(),
+            (0x1000, 0x13ff)
+        );
+        let within =
+            VirtScrubArea::within(usize::MAX - 0x400, usize::MAX, 64);
+        assert_eq!(within.unwrap(), top);
+        for (start, end) in [(0x1001, 0x107e), (0x1040, 0x103f), (0, 0x3f)]
+        {
+            assert_eq!(
+                VirtScrubArea::within(start, end, 64),
+                Err(Error::EmptyMemArea)
+            );
+        }
+    }
+
+    #[test]
+    fn test_phys_area() {
+        // Physical memory may start at zero
+        let area = PhysScrubArea::from_extent(0, 0x3ff, 64).unwrap();
+        assert_eq!((area.start(), area.end()), (0, 0x3ff));
+        assert_eq!(area.lines().get(), 16);
+        assert_eq!(area.size(), 0x400);
+
+        assert_eq!(
+            PhysScrubArea::from_extent(0x1000, 0xfff, 64),
+            Err(Error::EmptyMemArea)
+        );
+        assert_eq!(
+            PhysScrubArea::from_extent(0x1040, 0x13fe, 64),
+            Err(Error::UnalignedEnd)
+        );
+        assert_eq!(
+            PhysScrubArea::from_extent(0x1020, 0x13ff, 64),
+            Err(Error::UnalignedStart)
+        );
+        let lines = NonZeroUsize::new(16).unwrap();
+        assert_eq!(
+            PhysScrubArea::new(u64::MAX - 0x3bf, lines, 64),
+            Err(Error::AddressOverflow)
+        );
+    }
+
+    #[test]
+    fn test_slices() {
+        // A slice has only its whole lines
+        let buffer = vec![0u8; 8192];
+        let start = (buffer.as_ptr() as usize).next_multiple_of(64);
+        let offset = start - buffer.as_ptr() as usize;
+        let bytes = &buffer[offset..offset + 4100];
+        let area = VirtScrubArea::from_slice(&bytes[1..], 64).unwrap();
+        assert_eq!(area.extent(), (start + 64, start + 64 * 64 - 1));
+        assert_eq!(
+            VirtScrubArea::from_slice(&bytes[1..100], 64),
+            Err(Error::EmptyMemArea)
+        );
+
+        // A mapping of whole pages takes in its partial last line
+        let area = VirtScrubArea::from_pages(base(0x1000), 4100, 64, 4096)
+            .unwrap();
+        assert_eq!(area.extent(), (0x1000, 0x1000 + 65 * 64 - 1));
+        assert_eq!(
+            VirtScrubArea::from_pages(base(0x1040), 4100, 64, 4096),
+            Err(Error::UnalignedStart)
+        );
+        assert_eq!(
+            VirtScrubArea::from_pages(base(0x1000), 4100, 8192, 4096),
+            Err(Error::UnalignedValue)
+        );
+        assert_eq!(
+            VirtScrubArea::from_pages(base(0x1000), 0, 64, 4096),
+            Err(Error::EmptyMemArea)
+        );
+    }
+
+    #[cfg(feature = "memmap2")]
+    #[test]
+    fn test_mmap() {
+        use crate::testutil::*;
+        use std::fs::{self, File};
+
+        // A file of 4100 bytes ends part way through its 65th line
+        let path = scratch_path("mmap");
+        fs::write(&path, vec![0u8; 4100]).unwrap();
+        let file = File::open(&path).unwrap();
+        let mapping = unsafe { memmap2::Mmap::map(&file) }.unwrap();
+        let area = VirtScrubArea::try_from((&mapping, 64)).unwrap();
+        let start = mapping.as_ptr() as usize;
+        assert_eq!(area.extent(), (start, start + 65 * 64 - 1));
+
+        // A mapping from part way through a page doesn't start on one
+        let mapping = unsafe {
+            memmap2::MmapOptions::new().offset(64).map(&file).unwrap()
+        };
+        assert_eq!(
+            VirtScrubArea::try_from((&mapping, 64)),
+            Err(Error::UnalignedStart)
+        );
+        let mapping = memmap2::MmapMut::map_anon(0).unwrap();
+        assert_eq!(
+            VirtScrubArea::try_from((&mapping, 64)),
+            Err(Error::EmptyMemArea)
+        );
+        fs::remove_file(&path).unwrap();
+    }
+}
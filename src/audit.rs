@@ -0,0 +1,571 @@
+// Tamper-evident audit log, built with the audit feature. Certification
+// evidence that memory was scrubbed is only worth something if it can't be
+// quietly edited after the fact. An AuditLog appends a record for each
+// completed pass and each configuration change, and each record holds the
+// SHA-256 hash of the record before it as well as its own, so changing,
+// removing or inserting a record breaks the chain from that point on.
+// verify_audit() checks a log and says where it is first broken.
+//
+// Added to a LineScrubber as a policy, the log records the changes made
+// to it, such as exclusions, area rates and added or removed areas, as
+// they are made. Other changes can be recorded with record_config().
+//
+// Records are written as text, one per line:
+//
+//  <seq> <time> <kind> <prev> <hash> <detail>
+//
+// The seq numbers records from zero, the time is in seconds since the Unix
+// epoch with nanoseconds, the kind is a single word such as pass_complete
+// or config, the hashes are in hex and the detail is the rest of the line.
+// The hash of a record covers every other field and the previous hash,
+// which is all zeros for the first record.
+//
+// Cutting records off the end of a log leaves a valid, shorter chain, so
+// the head of the chain, as returned by AuditLog::head(), should also be
+// kept somewhere the log's writer can't change, such as a remote log or
+// a monotonic counter, and compared with what verify_audit() returns.
+
+use std::fmt;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use crate::clock::*;
+use crate::event::*;
+use crate::policy::*;
+
+/// Number of bytes in a record hash
+pub const AUDIT_HASH_SIZE: usize = 32;
+
+/// One record in an audit log
+///
+/// * `seq` - Position of the record in the log, from zero
+///
+/// * `time` - Wall-clock time the record was made
+///
+/// * `kind` - What the record is of, a single word
+///
+/// * `detail` - Description of what happened, on a single line
+///
+/// * `prev` - Hash of the previous record, all zeros for the first
+///
+/// * `hash` - Hash of this record
+#[derive(Clone, Debug, PartialEq)]
+pub struct AuditRecord {
+    pub seq: u64,
+    pub time: SystemTime,
+    pub kind: String,
+    pub detail: String,
+    pub prev: [u8; AUDIT_HASH_SIZE],
+    pub hash: [u8; AUDIT_HASH_SIZE],
+}
+
+/// The end of an audit chain
+///
+/// * `records` - Number of records in the chain
+///
+/// * `hash` - Hash of the last record, all zeros if there are none
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AuditHead {
+    pub records: u64,
+    pub hash: [u8; AUDIT_HASH_SIZE],
+}
+
+/// How an audit log is broken
+///
+/// * `Malformed` - The line is not a record
+///
+/// * `Sequence` - The record is not numbered after the one before it
+///
+/// * `Chain` - The previous hash is not that of the record before it
+///
+/// * `Hash` - The hash doesn't match the contents of the record
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum AuditFault {
+    Malformed,
+    Sequence,
+    Chain,
+    Hash,
+}
+
+/// Where an audit log is first broken
+///
+/// * `line` - Line number of the first bad record, from one
+///
+/// * `fault` - What is wrong with it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct AuditBreak {
+    pub line: usize,
+    pub fault: AuditFault,
+}
+
+impl fmt::Display for AuditBreak {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let fault = match self.fault {
+            AuditFault::Malformed => "malformed record",
+            AuditFault::Sequence => "record out of sequence",
+            AuditFault::Chain => "previous hash doesn't match",
+            AuditFault::Hash => "record hash doesn't match",
+        };
+        write!(f, "audit log line {}: {}", self.line, fault)
+    }
+}
+
+impl AuditRecord {
+    /// Returns the hash the record should have
+    pub fn digest(&self) -> [u8; AUDIT_HASH_SIZE] {
+        let time =
+            self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let text = format!(
+            "{} {}.{:09} {} {} {}",
+            self.seq,
+            time.as_secs(),
+            time.subsec_nanos(),
+            self.kind,
+            to_hex(&self.prev),
+            self.detail
+        );
+        Sha256::digest(text.as_bytes()).into()
+    }
+
+    /// Return the record as a line of text, without the newline
+    pub fn to_line(&self) -> String {
+        let time =
+            self.time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        format!(
+            "{} {}.{:09} {} {} {} {}",
+            self.seq,
+            time.as_secs(),
+            time.subsec_nanos(),
+            self.kind,
+            to_hex(&self.prev),
+            to_hex(&self.hash),
+            self.detail
+        )
+    }
+
+    /// Parse a record from a line of text, without checking its hash
+    pub fn parse(line: &str) -> Option<AuditRecord> {
+        let mut words = line.splitn(6, ' ');
+        let seq = words.next()?.parse().ok()?;
+        let (secs, nanos) = words.next()?.split_once('.')?;
+        let time = UNIX_EPOCH
+            + Duration::new(secs.parse().ok()?, nanos.parse().ok()?);
+        let kind = words.next()?.to_string();
+        let prev = from_hex(words.next()?)?;
+        let hash = from_hex(words.next()?)?;
+        let detail = words.next().unwrap_or("").to_string();
+        Some(AuditRecord {
+            seq,
+            time,
+            kind,
+            detail,
+            prev,
+            hash,
+        })
+    }
+}
+
+/// Check the chain of records in an audit log
+///
+/// # Arguments:
+/// * `text` - The log, as written by an AuditLog
+///
+/// # Returns:
+/// Ok(AuditHead) with the end of the chain if every record is intact,
+/// otherwise Err(AuditBreak) with the first that is not
+pub fn verify_audit(text: &str) -> Result<AuditHead, AuditBreak> {
+    let mut head = AuditHead::default();
+    for (n, line) in text.lines().enumerate() {
+        let fail = |fault| AuditBreak { line: n + 1, fault };
+        let record =
+            AuditRecord::parse(line).ok_or(fail(AuditFault::Malformed))?;
+        if record.seq != head.records {
+            return Err(fail(AuditFault::Sequence));
+        }
+        if record.prev != head.hash {
+            return Err(fail(AuditFault::Chain));
+        }
+        if record.hash != record.digest() {
+            return Err(fail(AuditFault::Hash));
+        }
+        head = AuditHead {
+            records: head.records + 1,
+            hash: record.hash,
+        };
+    }
+    Ok(head)
+}
+
+/// An append-only, hash chained log of completed passes and configuration
+/// changes. As an EventSink, or a Policy of a LineScrubber, it records
+/// each PassComplete and RateChange event. As a Policy it also records
+/// each change to the scrubber's configuration.
+///
+/// * `writer` - Where records are written
+///
+/// * `clock` - Gives the time of each record
+///
+/// * `head` - End of the chain so far
+///
+/// * `write_errors` - Number of records from events that could not be
+///   written
+pub struct AuditLog {
+    writer: Box<dyn Write>,
+    clock: Box<dyn Clock>,
+    head: AuditHead,
+    write_errors: u64,
+}
+
+impl AuditLog {
+    /// Create an AuditLog starting a new chain
+    ///
+    /// # Arguments:
+    /// * `writer` - Where records are written
+    pub fn new(writer: Box<dyn Write>) -> AuditLog {
+        AuditLog::resume(writer, AuditHead::default())
+    }
+
+    /// Create an AuditLog continuing a chain
+    ///
+    /// # Arguments:
+    /// * `writer` - Where records are written, after those already in the
+    ///   chain
+    ///
+    /// * `head` - End of the chain, as returned by verify_audit()
+    pub fn resume(writer: Box<dyn Write>, head: AuditHead) -> AuditLog {
+        AuditLog {
+            writer,
+            clock: Box::new(SystemClock),
+            head,
+            write_errors: 0,
+        }
+    }
+
+    /// Open a log file for appending, continuing the chain already in it
+    ///
+    /// # Returns:
+    /// Ok(AuditLog) on success, otherwise Err(io::Error), of kind
+    /// InvalidData if the records in the file are not intact
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<AuditLog> {
+        let path = path.as_ref();
+        let head = match fs::read_to_string(path) {
+            Ok(text) => verify_audit(&text).map_err(|e| {
+                io::Error::new(io::ErrorKind::InvalidData, e.to_string())
+            })?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                AuditHead::default()
+            }
+            Err(e) => return Err(e),
+        };
+        let file =
+            OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(AuditLog::resume(Box::new(file), head))
+    }
+
+    /// Use a different clock for the time of each record
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        self.clock = clock;
+    }
+
+    /// Returns the end of the chain so far
+    pub fn head(&self) -> AuditHead {
+        self.head
+    }
+
+    /// Returns the number of records from events that could not be
+    /// written
+    pub fn write_errors(&self) -> u64 {
+        self.write_errors
+    }
+
+    /// Append a record
+    ///
+    /// # Arguments:
+    /// * `kind` - What the record is of, a single word
+    ///
+    /// * `detail` - Description of what happened. Line breaks are
+    ///   replaced with spaces.
+    ///
+    /// # Returns:
+    /// Ok(AuditRecord) with the record written, otherwise Err(io::Error),
+    /// of kind InvalidInput if the kind is not a single word
+    pub fn append(
+        &mut self,
+        kind: &str,
+        detail: &str,
+    ) -> io::Result<AuditRecord> {
+        if kind.is_empty() || kind.contains(char::is_whitespace) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "audit record kind must be a single word",
+            ));
+        }
+        let mut record = AuditRecord {
+            seq: self.head.records,
+            time: self.clock.wall(),
+            kind: kind.to_string(),
+            detail: detail.replace(['\n', '\r'], " "),
+            prev: self.head.hash,
+            hash: [0; AUDIT_HASH_SIZE],
+        };
+        record.hash = record.digest();
+        writeln!(self.writer, "{}", record.to_line())?;
+        self.writer.flush()?;
+        self.head = AuditHead {
+            records: record.seq + 1,
+            hash: record.hash,
+        };
+        Ok(record)
+    }
+
+    /// Append a record of a configuration change
+    pub fn record_config(
+        &mut self,
+        detail: &str,
+    ) -> io::Result<AuditRecord> {
+        self.append("config", detail)
+    }
+
+    // Append a record of a pass completion or rate change event
+    fn record_event(&mut self, event: &ScrubEvent) {
+        if !matches!(
+            event,
+            ScrubEvent::PassComplete { .. }
+                | ScrubEvent::RateChange { .. }
+        ) {
+            return;
+        }
+        let detail: Vec<String> = event
+            .fields()
+            .into_iter()
+            .skip(1)
+            .map(|(name, value)| format!("{}={}", name, value))
+            .collect();
+        if self.append(event.name(), &detail.join(" ")).is_err() {
+            self.write_errors += 1;
+        }
+    }
+
+    // Append a record of a change to a scrubber's configuration
+    fn record_change(&mut self, change: &ConfigChange) {
+        let mut detail = vec![change.name().to_string()];
+        detail.extend(
+            change
+                .fields()
+                .into_iter()
+                .map(|(name, value)| format!("{}={}", name, value)),
+        );
+        if self.record_config(&detail.join(" ")).is_err() {
+            self.write_errors += 1;
+        }
+    }
+}
+
+impl EventSink for AuditLog {
+    fn event(&mut self, event: &ScrubEvent) {
+        self.record_event(event);
+    }
+}
+
+// Added to a LineScrubber as a policy, the log sees its events but never
+// asks for any action
+impl Policy for AuditLog {
+    fn event(
+        &mut self,
+        event: &ScrubEvent,
+        _now: Instant,
+    ) -> Vec<PolicyAction> {
+        self.record_event(event);
+        Vec::new()
+    }
+
+    fn config_changed(&mut self, change: &ConfigChange, _now: Instant) {
+        self.record_change(change);
+    }
+
+    fn buffers(&self) -> Vec<(usize, usize)> {
+        vec![
+            value_buffer(self.writer.as_ref()),
+            value_buffer(self.clock.as_ref()),
+        ]
+    }
+}
+
+// Returns bytes as lower case hex
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+// Parse a hash from hex
+fn from_hex(hex: &str) -> Option<[u8; AUDIT_HASH_SIZE]> {
+    if hex.len() != 2 * AUDIT_HASH_SIZE || !hex.is_ascii() {
+        return None;
+    }
+    let mut bytes = [0; AUDIT_HASH_SIZE];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * i..2 * i + 2], 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::*;
+    use crate::sim::*;
+    use crate::testutil::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // A writer whose output can be read back while the log holds it
+    #[derive(Clone, Default)]
+    struct Shared(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for Shared {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Shared {
+        fn text(&self) -> String {
+            String::from_utf8(self.0.borrow().clone()).unwrap()
+        }
+    }
+
+    #[test]
+    fn test_chain() {
+        let out = Shared::default();
+        let mut log = AuditLog::new(Box::new(out.clone()));
+        let first = log.record_config("chunk size 4096").unwrap();
+        assert_eq!(first.seq, 0);
+        assert_eq!(first.prev, [0; AUDIT_HASH_SIZE]);
+        let second = log.record_config("exclude 0x1000-0x1fff\n").unwrap();
+        assert_eq!(second.prev, first.hash);
+        assert_eq!(second.detail, "exclude 0x1000-0x1fff ");
+        assert!(log.append("two words", "").is_err());
+
+        let text = out.text();
+        assert_eq!(verify_audit(&text), Ok(log.head()));
+        assert_eq!(log.head().records, 2);
+        assert_eq!(
+            AuditRecord::parse(text.lines().next().unwrap()),
+            Some(first)
+        );
+
+        // Editing, dropping or reordering records breaks the chain
+        let edited = text.replace("4096", "8192");
+        assert_eq!(
+            verify_audit(&edited),
+            Err(AuditBreak {
+                line: 1,
+                fault: AuditFault::Hash
+            })
+        );
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(
+            verify_audit(lines[1]).unwrap_err().fault,
+            AuditFault::Sequence
+        );
+        assert_eq!(
+            verify_audit("garbage\n").unwrap_err().fault,
+            AuditFault::Malformed
+        );
+    }
+
+    #[test]
+    fn test_events() {
+        let out = Shared::default();
+        let clock = VirtualClock::new();
+        let mut log = AuditLog::new(Box::new(out.clone()));
+        log.set_clock(Box::new(clock.clone()));
+
+        let mem = SimMemory::new(0, 8192, 64).unwrap();
+        let mut scrubber =
+            LineScrubber::new(mem, &[(0, 8191)], 64, 4).unwrap();
+        scrubber.add_policy(Box::new(log));
+        scrubber.scrub(8192).unwrap();
+        scrubber.scrub(8192).unwrap();
+
+        // Only the passes are recorded
+        let text = out.text();
+        let head = verify_audit(&text).unwrap();
+        assert_eq!(head.records, 2);
+        let record =
+            AuditRecord::parse(text.lines().nth(1).unwrap()).unwrap();
+        assert_eq!(record.kind, "pass_complete");
+        assert!(record.detail.starts_with("pass=2 epoch=2 bytes=8192"));
+        assert_eq!(record.time, clock.wall());
+    }
+
+    #[test]
+    fn test_config() {
+        let out = Shared::default();
+        let mem = SimMemory::new(0, 16384, 64).unwrap();
+        let mut scrubber =
+            LineScrubber::new(mem, &[(0, 8191)], 64, 4).unwrap();
+        scrubber
+            .add_policy(Box::new(AuditLog::new(Box::new(out.clone()))));
+
+        // Excluding what is already excluded, or setting the rate an
+        // area already has, changes nothing and isn't recorded
+        scrubber.exclude(0x1000, 0x1fff);
+        scrubber.exclude(0x1000, 0x103f);
+        scrubber.set_area_rate(0, 2).unwrap();
+        scrubber.set_area_rate(0, 2).unwrap();
+        scrubber.add_area(8192, 16383).unwrap();
+        scrubber.remove_area(0).unwrap();
+
+        let text = out.text();
+        assert_eq!(verify_audit(&text).unwrap().records, 4);
+        let details: Vec<String> = text
+            .lines()
+            .map(|line| AuditRecord::parse(line).unwrap())
+            .inspect(|record| assert_eq!(record.kind, "config"))
+            .map(|record| record.detail)
+            .collect();
+        assert_eq!(
+            details,
+            [
+                "exclude start=0x1000 end=0x1fff",
+                "area_rate area=0 multiplier=2",
+                "add_area area=1 start=0x2000 end=0x3fff",
+                "remove_area area=0",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_file() {
+        let path = scratch_path("audit.log");
+        let _ = fs::remove_file(&path);
+
+        let mut log = AuditLog::open(&path).unwrap();
+        log.record_config("start").unwrap();
+        drop(log);
+        let mut log = AuditLog::open(&path).unwrap();
+        assert_eq!(log.head().records, 1);
+        log.record_config("restart").unwrap();
+        let head = log.head();
+        drop(log);
+        assert_eq!(
+            verify_audit(&fs::read_to_string(&path).unwrap()),
+            Ok(head)
+        );
+
+        // A tampered file can't be continued
+        let text = fs::read_to_string(&path).unwrap();
+        fs::write(&path, text.replace("start", "stop")).unwrap();
+        let err = AuditLog::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        fs::remove_file(&path).unwrap();
+    }
+}
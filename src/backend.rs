@@ -0,0 +1,3288 @@
+// Scrubbing through a backend. LineScrubber walks the scrub areas in the
+// order given by ScrubOrder, a chunk at a time, and leaves the reading of
+// each cache line to a ScrubBackend. RawBackend reads real memory through
+// raw pointers; SimMemory, in sim.rs, reads an owned buffer with safe
+// indexing so that the same logic can run under Miri or in tests without
+// touching real memory. Policies, in policy.rs, are given the errors and
+// chunks a LineScrubber sees and can change how it scrubs.
+//
+// scrub_pass_fast() is for a full scrub as fast as the memory allows, as
+// at boot. It checks once that nothing needs a check on each line, then
+// reads the rest of the pass without them.
+//
+// A ReadValidator, in validate.rs, can be asked about each line before it
+// is read. Lines it rejects are skipped rather than read, and the first
+// line skipped in each area in each chunk is reported to the policies as a
+// LineSkipped event, so that an area that stays unreadable doesn't flood
+// them. Memory that stays unreadable is never scrubbed, so the lines
+// skipped are also counted for each area in its statistics and, for each
+// area with lines skipped, a warning is sent to the diagnostics sink at
+// the end of a chunk, at most once per warning interval. Lines skipped
+// within the interval are held and warned about at the end of the first
+// chunk after it, whether or not any more are skipped.
+//
+// There are two kinds of excluded range. Ranges excluded by policies, as
+// after errors, can appear at any time and are checked for each line, so
+// that the pass carries on undisturbed. Ranges declared by the user, such
+// as DMA rings written by hardware, are known up front, so the areas are
+// split around them and their lines are left out of the pass altogether.
+//
+// A ChannelBalancer, in channel.rs, can reorder the lines of each sub-pass
+// a little so that successive reads go to different memory channels.
+//
+// A pass can also be walked a window of memory at a time, for a backend
+// such as WindowedBackend, in os/window.rs, that maps the memory it reads
+// a window at a time. Every line of a window is read, in the cache aware
+// order, before the pass moves on to the next window, and the backend is
+// told at each window boundary so that it can unmap the window it has
+// finished.
+//
+// A TouchSampler, in touch.rs, can count how often each pass reaches a
+// random sample of its lines, to find lines a pass misses or reaches
+// twice. A pass with such lines is reported to the diagnostics sink.
+//
+// Chunks can be made to end only after the last line of a block of
+// addresses on an alignment, such as a 2 MiB huge page or a multiple of
+// the DRAM row size, so that each chunk reads whole pages or rows. The
+// lines of a pass are otherwise ordered by cache set, so this is only
+// allowed with an index width of zero and no channel balancer, which read
+// each area in address order.
+//
+// The scrubber's own state, such as its statistics and exclusion lists,
+// may lie in a scrub area, and reading it every pass disturbs the cache
+// lines the scrubber is using and skews its measurements. With
+// set_exclude_own_state() the lines holding that state, including the
+// heap buffers of its policies, are found at the start of each pass and
+// left out of it, as though excluded. The policies that hold state keep
+// it in Vecs rather than hash maps so that it can be found.
+//
+// With coverage tracking on, the lines each pass leaves unscrubbed, and
+// why, are recorded in a PassCoverage, in coverage.rs, so that passes can
+// be compared. Backends that leave some lines unread, such as those in
+// quarantine, say so through ScrubBackend::skip_reason(), so that those
+// lines are recorded too.
+
+use std::ptr;
+use std::time::{Duration, Instant};
+
+use crate::arch::*;
+use crate::area::*;
+use crate::badblocks::*;
+use crate::base::*;
+use crate::channel::*;
+use crate::clock::*;
+use crate::coverage::*;
+use crate::diag::*;
+use crate::dryrun::*;
+use crate::event::*;
+use crate::policy::*;
+use crate::touch::*;
+use crate::stats::*;
+use crate::validate::*;
+
+/// Reads cache lines on behalf of a LineScrubber
+pub trait ScrubBackend {
+    /// Read the cache line at the given address
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error) if the address can't be read
+    fn read_line(&mut self, addr: usize) -> Result<(), Error>;
+
+    /// Read the cache line at the given address with evenly spaced reads,
+    /// for systems where one read doesn't check every ECC codeword in the
+    /// line. The default calls read_line() for each read.
+    ///
+    /// # Arguments:
+    /// * `addr` - Address of the cache line
+    ///
+    /// * `line_size` - Number of bytes in a cache line
+    ///
+    /// * `reads` - Number of reads, a power of two dividing `line_size`
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error) if the address can't be read
+    fn read_words(
+        &mut self,
+        addr: usize,
+        line_size: usize,
+        reads: usize,
+    ) -> Result<(), Error> {
+        let step = line_size / reads;
+        for offset in (0..line_size).step_by(step) {
+            self.read_line(addr + offset)?;
+        }
+        Ok(())
+    }
+
+    /// Called before each batch of reads, such as a chunk. The default
+    /// does nothing.
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error)
+    fn begin_batch(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called after each batch of reads, even if one failed, for platforms
+    /// where errors found by the reads are only reported after a barrier
+    /// or a poll of the memory controller. The default does nothing.
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error)
+    fn end_batch(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called when a pass walked a window at a time, as set with
+    /// LineScrubber::set_window_size(), has read every line of a window
+    /// and moves on to the next, so that a backend mapping the memory a
+    /// window at a time can unmap the one finished. The default does
+    /// nothing.
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error)
+    fn end_window(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Returns the number of bytes in each window, if the backend maps the
+    /// memory it reads a window at a time, so that LineScrubber::new()
+    /// walks each pass a window at a time to suit. The default maps
+    /// nothing.
+    fn window_size(&self) -> Option<usize> {
+        None
+    }
+
+    /// Write back and invalidate the cache line at the given address, so
+    /// that the next read of it comes from memory rather than the cache.
+    /// The default can't flush.
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::InternalError) if the
+    /// backend can't flush cache lines, or another Err(Error)
+    fn flush_line(&mut self, _addr: usize) -> Result<(), Error> {
+        Err(Error::InternalError)
+    }
+
+    /// Returns why the backend leaves the cache line at the given address
+    /// unread, if it does, as when the line is quarantined. LineScrubber
+    /// asks before reading each line, so that it can record the line as
+    /// left unscrubbed rather than as scrubbed. A backend wrapping another
+    /// should ask the one it wraps about lines it reads itself. The
+    /// default reads every line.
+    fn skip_reason(&mut self, _addr: usize) -> Option<SkipReason> {
+        None
+    }
+
+    /// Returns the number of cache lines in a range of addresses that the
+    /// backend leaves out of scrubbing, as a QuarantineBackend does, if it
+    /// keeps track of them. LineScrubber sets the excluded_lines of each
+    /// area from it at the end of each chunk. A backend wrapping another
+    /// should ask the one it wraps. The default keeps no track, which
+    /// leaves excluded_lines as it is.
+    ///
+    /// # Arguments:
+    /// * `start` - First address in the range
+    ///
+    /// * `end` - Last address in the range
+    fn excluded_lines(&self, _start: usize, _end: usize) -> Option<u64> {
+        None
+    }
+}
+
+/// A backend that can also return the contents of a cache line
+pub trait SnapshotBackend: ScrubBackend {
+    /// Read the cache line at the given address into a buffer
+    ///
+    /// # Arguments:
+    /// * `addr` - Address of the cache line
+    ///
+    /// * `buf` - Buffer as long as a cache line
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error) if the address can't be read
+    fn read_snapshot(
+        &mut self,
+        addr: usize,
+        buf: &mut [u8],
+    ) -> Result<(), Error>;
+}
+
+/// Widest single read made by a backend. A cache line can be read with at
+/// most its size divided by this many reads.
+pub const MAX_READ_SIZE: usize = 8;
+
+/// Size of a 2 MiB huge page, as used for the chunk alignment
+pub const HUGE_PAGE_SIZE: usize = 2 << 20;
+
+/// A backend reading memory at its virtual address
+#[derive(Debug)]
+pub struct RawBackend {
+    _private: (),
+}
+
+impl RawBackend {
+    /// Create a backend reading real memory
+    ///
+    /// # Safety
+    /// Every address scrubbed must be mapped and readable for the life of
+    /// the backend
+    pub unsafe fn new() -> RawBackend {
+        RawBackend { _private: () }
+    }
+}
+
+impl ScrubBackend for RawBackend {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        // The caller of new() promised that the address is readable
+        unsafe { ptr::read_volatile(addr as *const u8) };
+        Ok(())
+    }
+
+    fn flush_line(&mut self, addr: usize) -> Result<(), Error> {
+        // The caller of new() promised that the address is mapped
+        match unsafe { flush_line(addr) } {
+            true => Ok(()),
+            false => Err(Error::InternalError),
+        }
+    }
+}
+
+impl SnapshotBackend for RawBackend {
+    fn read_snapshot(
+        &mut self,
+        addr: usize,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        for (i, byte) in buf.iter_mut().enumerate() {
+            // The caller of new() promised that the address is readable
+            *byte = unsafe { ptr::read_volatile((addr + i) as *const u8) };
+        }
+        Ok(())
+    }
+}
+
+/// Scrubs areas a chunk at a time, continuing from pass to pass
+///
+/// * `backend` - Reads each cache line
+///
+/// * `extents` - (start, end) address of each scrub area, end inclusive
+///
+/// * `by_start` - Index of each scrub area, sorted by start address
+///
+/// * `scan` - The extents less the declared ranges, which are scrubbed
+///
+/// * `line_size` - Number of bytes in a cache line
+///
+/// * `index_width` - Number of address bits in the cache index
+///
+/// * `reads_per_line` - Number of reads made of each cache line
+///
+/// * `chunk_align` - Alignment of the blocks of addresses chunks end on
+///   the last line of
+///
+/// * `last_line` - Address of the line of the pass read last
+///
+/// * `sub_passes` - Number of interleaved sub-passes in a pass
+///
+/// * `sub_pass` - The current sub-pass
+///
+/// * `window_size` - Number of bytes in each window of memory the pass is
+///   walked a window at a time, if it is
+///
+/// * `window` - First address of the current window
+///
+/// * `order` - Position in the current sub-pass
+///
+/// * `balancer` - Reorders the lines of the pass across memory channels
+///
+/// * `boosts` - Areas scrubbed faster than the rest
+///
+/// * `excluded` - (start, end) of each range not scrubbed, sorted, end
+///   inclusive
+///
+/// * `declared` - (start, end) of each range declared never to be
+///   scrubbed, in whole cache lines, sorted, end inclusive
+///
+/// * `policies` - Policies given each event, whose actions are applied
+///
+/// * `clock` - Source of time for the statistics and for deadlines
+///
+/// * `validator` - Checks that each line can be read before reading it
+///
+/// * `skipped` - Lines rejected by the validator and not yet reported
+///
+/// * `lines_skipped` - Number of lines rejected by the validator
+///
+/// * `unread` - Bytes of the current chunk rejected by the validator
+///
+/// * `unreadable` - Warnings about the unreadable lines of each area
+///
+/// * `warn_interval` - Minimum time between warnings about the unreadable
+///   lines of an area, or None for no warnings
+///
+/// * `touches` - Counts the touches of a sample of the lines of each pass
+///
+/// * `exclude_own` - Whether the lines holding the scrubber's own state
+///   are left out of the pass
+///
+/// * `own` - (start, end) of each range of whole cache lines holding the
+///   scrubber's own state, as of the start of the pass, sorted, end
+///   inclusive
+///
+/// * `coverage` - Lines left unscrubbed so far in the current pass, if
+///   coverage is tracked
+///
+/// * `coverage_history` - Lines left unscrubbed in the most recent
+///   passes, oldest first
+///
+/// * `coverage_passes` - Number of passes kept in the coverage history
+///
+/// * `stats` - Statistics for the scrubbing done so far
+pub struct LineScrubber<B: ScrubBackend> {
+    backend: B,
+    extents: Vec<(usize, usize)>,
+    by_start: Vec<usize>,
+    scan: Vec<(usize, usize)>,
+    line_size: usize,
+    index_width: usize,
+    reads_per_line: usize,
+    chunk_align: usize,
+    last_line: usize,
+    sub_passes: usize,
+    sub_pass: usize,
+    window_size: Option<usize>,
+    window: usize,
+    order: ScrubOrder,
+    balancer: Option<ChannelBalancer>,
+    boosts: Vec<AreaBoost>,
+    excluded: Vec<(usize, usize)>,
+    declared: Vec<(usize, usize)>,
+    policies: Vec<Box<dyn Policy>>,
+    clock: Box<dyn Clock>,
+    validator: Option<Box<dyn ReadValidator>>,
+    skipped: Vec<usize>,
+    lines_skipped: u64,
+    unread: usize,
+    unreadable: Vec<UnreadableWarnings>,
+    warn_interval: Option<Duration>,
+    touches: Option<TouchSampler>,
+    exclude_own: bool,
+    own: Vec<(usize, usize)>,
+    coverage: Option<PassCoverage>,
+    coverage_history: Vec<PassCoverage>,
+    coverage_passes: usize,
+    stats: ScrubStats,
+}
+
+// An area scrubbed faster than the rest. For each line of the pass, the
+// area gets extra lines in proportion to its share of the pass.
+//
+// area:   Index of the scrub area
+// extra:  Rate multiplier set by set_area_rate(), less one
+// weight: Priority of the area, the least rate multiplier less one
+// order:  Position in the area's extra pass
+// owed:   Extra lines owed, in units of 1/(lines in a pass)
+struct AreaBoost {
+    area: usize,
+    extra: usize,
+    weight: usize,
+    order: ScrubOrder,
+    owed: usize,
+}
+
+// Warnings about the unreadable lines of an area
+//
+// warned:   Time of the last warning, if any
+// pending:  Lines skipped since the last warning
+// reported: Whether a LineSkipped event was given for the chunk
+#[derive(Clone, Copy, Debug, Default)]
+struct UnreadableWarnings {
+    warned: Option<Instant>,
+    pending: u64,
+    reported: bool,
+}
+
+/// Default minimum time between warnings about the unreadable lines of an
+/// area
+pub const DEFAULT_WARN_INTERVAL: Duration = Duration::from_secs(60);
+
+impl<B: ScrubBackend> LineScrubber<B> {
+    /// Create a new LineScrubber. If the backend maps memory a window at a
+    /// time, each pass is walked a window at a time, as with
+    /// set_window_size().
+    ///
+    /// # Arguments:
+    /// * `backend` - Reads each cache line
+    ///
+    /// * `extents` - (start, end) address of each scrub area, end inclusive
+    ///
+    /// * `line_size` - Number of bytes in a cache line
+    ///
+    /// * `index_width` - Number of address bits in the cache index
+    ///
+    /// # Returns:
+    /// The scrubber, or an Error if the areas or the backend's window size
+    /// are not valid
+    pub fn new(
+        backend: B,
+        extents: &[(usize, usize)],
+        line_size: usize,
+        index_width: usize,
+    ) -> Result<LineScrubber<B>, Error> {
+        let order = ScrubOrder::new(extents, line_size, index_width)?;
+        let sizes: Vec<usize> =
+            extents.iter().map(|&(s, e)| e - s + 1).collect();
+
+        let window_size = backend.window_size();
+        let mut scrubber = LineScrubber {
+            backend,
+            extents: extents.to_vec(),
+            by_start: sorted_by_start(extents),
+            scan: extents.to_vec(),
+            line_size,
+            index_width,
+            reads_per_line: 1,
+            chunk_align: line_size,
+            last_line: 0,
+            sub_passes: 1,
+            sub_pass: 0,
+            window_size: None,
+            window: 0,
+            order,
+            balancer: None,
+            boosts: Vec::new(),
+            excluded: Vec::new(),
+            declared: Vec::new(),
+            policies: Vec::new(),
+            clock: Box::new(SystemClock),
+            validator: None,
+            skipped: Vec::new(),
+            lines_skipped: 0,
+            unread: 0,
+            unreadable: Vec::new(),
+            warn_interval: Some(DEFAULT_WARN_INTERVAL),
+            touches: None,
+            exclude_own: false,
+            own: Vec::new(),
+            coverage: None,
+            coverage_history: Vec::new(),
+            coverage_passes: 0,
+            stats: ScrubStats::new(&sizes, Instant::now()),
+        };
+        if window_size.is_some() {
+            scrubber.set_window_size(window_size)?;
+        }
+        Ok(scrubber)
+    }
+
+    /// Create a new LineScrubber over areas of virtual memory
+    ///
+    /// # Arguments:
+    /// * `backend` - Reads each cache line
+    ///
+    /// * `areas` - The scrub areas, all with the same cache line size
+    ///
+    /// * `index_width` - Number of address bits in the cache index
+    ///
+    /// # Returns:
+    /// The scrubber, otherwise Err(Error::NoMemAreas) if there are no
+    /// areas, Err(Error::MixedLineSizes) if their cache line sizes differ,
+    /// or another Error if the areas are not valid together
+    pub fn from_areas(
+        backend: B,
+        areas: &[VirtScrubArea],
+        index_width: usize,
+    ) -> Result<LineScrubber<B>, Error> {
+        let line_size = line_size_of(areas)?;
+        let extents: Vec<(usize, usize)> =
+            areas.iter().map(|a| a.extent()).collect();
+        LineScrubber::new(backend, &extents, line_size, index_width)
+    }
+
+    /// Set the number of evenly spaced reads made of each cache line, as
+    /// given by CacheBase::reads_per_cacheline(). The default is one read,
+    /// of the start of the line.
+    ///
+    /// # Arguments:
+    /// * `reads` - Number of reads, a power of two no larger than the cache
+    ///   line size divided by MAX_READ_SIZE
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::UnalignedValue)
+    pub fn set_reads_per_line(
+        &mut self,
+        reads: usize,
+    ) -> Result<(), Error> {
+        if !reads.is_power_of_two()
+            || reads > (self.line_size / MAX_READ_SIZE).max(1)
+        {
+            return Err(Error::UnalignedValue);
+        }
+        self.reads_per_line = reads;
+        Ok(())
+    }
+
+    /// Make chunks end only after the last line of a block of addresses
+    /// on an alignment, or at the end of the pass. scrub() reads on past
+    /// the number of bytes it is asked for until it reaches such a
+    /// boundary, and scrub_until() past its deadline. The default is the
+    /// cache line size, which leaves chunks as they are. Lines must be
+    /// read in address order for chunks to cover whole blocks, so other
+    /// alignments need an index width of zero and no channel balancer.
+    ///
+    /// # Arguments:
+    /// * `align` - Alignment in bytes, such as HUGE_PAGE_SIZE or a
+    ///   multiple of the DRAM row size, a multiple of the cache line size
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::ZeroSize) if the alignment
+    /// is zero, Err(Error::UnalignedValue) if it is not a multiple of the
+    /// cache line size or Err(Error::NotAddressOrder) if lines are not
+    /// read in address order
+    pub fn set_chunk_alignment(
+        &mut self,
+        align: usize,
+    ) -> Result<(), Error> {
+        if align == 0 {
+            return Err(Error::ZeroSize);
+        }
+        if !align.is_multiple_of(self.line_size) {
+            return Err(Error::UnalignedValue);
+        }
+        if align != self.line_size
+            && (self.index_width != 0 || self.balancer.is_some())
+        {
+            return Err(Error::NotAddressOrder);
+        }
+        self.chunk_align = align;
+        Ok(())
+    }
+
+    /// Returns the alignment of the blocks that chunks end on
+    pub fn chunk_alignment(&self) -> usize {
+        self.chunk_align
+    }
+
+    // Returns whether the line read last ends a block on the chunk
+    // alignment or the pass
+    fn at_chunk_boundary(&self) -> bool {
+        self.last_line
+            .wrapping_add(self.line_size)
+            .is_multiple_of(self.chunk_align)
+            || self.pass_finished()
+    }
+
+    /// Split each pass into interleaved sub-passes, each of which scrubs
+    /// only every Kth cache set, so that scrubbing occupies at most 1/K of
+    /// the cache at any time. Every line is still scrubbed once a pass.
+    /// Scrubbing restarts at the beginning of the first sub-pass.
+    ///
+    /// # Arguments:
+    /// * `sub_passes` - Number of sub-passes, K, a power of two no larger
+    ///   than the number of cache sets. One turns interleaving off.
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::UnalignedValue)
+    pub fn set_sub_passes(
+        &mut self,
+        sub_passes: usize,
+    ) -> Result<(), Error> {
+        let window = first_window(&self.scan, self.window_size);
+        self.order = ScrubOrder::sub_pass(
+            &window_scan(&self.scan, self.window_size, window),
+            self.line_size,
+            self.index_width,
+            sub_passes,
+            0,
+        )?;
+        if let Some(balancer) = self.balancer.as_mut() {
+            balancer.clear();
+        }
+        self.sub_passes = sub_passes;
+        self.sub_pass = 0;
+        self.window = window;
+        Ok(())
+    }
+
+    /// Walk each pass a window of memory at a time, for a backend that
+    /// maps the memory it reads a window at a time. Every line of a window
+    /// is read, in the cache aware order, before the pass moves on to the
+    /// next window, when the backend's end_window() is called. Windows are
+    /// aligned on their size, which covers every cache index equally, so
+    /// reading a window disturbs the cache just as reading the same lines
+    /// in a pass over all of the memory would. With sub-passes, each
+    /// window is split into them. Scrubbing restarts at the beginning of
+    /// the pass.
+    ///
+    /// # Arguments:
+    /// * `window_size` - Number of bytes in each window, a power of two no
+    ///   smaller than the cache line size shifted left by the index width,
+    ///   or None to walk the whole pass at once
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::UnalignedValue) if the
+    /// window size isn't valid
+    pub fn set_window_size(
+        &mut self,
+        window_size: Option<usize>,
+    ) -> Result<(), Error> {
+        if let Some(size) = window_size {
+            let span = u32::try_from(self.index_width)
+                .ok()
+                .and_then(|width| 1usize.checked_shl(width))
+                .and_then(|lines| lines.checked_mul(self.line_size))
+                .ok_or(Error::UnalignedValue)?;
+            if !size.is_power_of_two() || size < span {
+                return Err(Error::UnalignedValue);
+            }
+        }
+        self.window_size = window_size;
+        self.set_extents(self.extents.clone())
+    }
+
+    /// Returns the number of bytes in each window the pass is walked a
+    /// window at a time, if it is
+    pub fn window_size(&self) -> Option<usize> {
+        self.window_size
+    }
+
+    /// Spread successive reads of the pass across memory channels rather
+    /// than following the cache aware order strictly, which can read one
+    /// channel at a time. See channel.rs. Scrubbing restarts at the
+    /// beginning of the pass.
+    ///
+    /// # Arguments:
+    /// * `balancer` - Reorders the lines of the pass, or None to follow
+    ///   the cache aware order
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::NotAddressOrder) if there
+    /// is a chunk alignment, or another Err(Error)
+    pub fn set_channel_balancer(
+        &mut self,
+        balancer: Option<ChannelBalancer>,
+    ) -> Result<(), Error> {
+        if balancer.is_some() && self.chunk_align != self.line_size {
+            return Err(Error::NotAddressOrder);
+        }
+        self.balancer = balancer;
+        self.set_extents(self.extents.clone())
+    }
+
+    /// Returns the channel balancer, if any
+    pub fn channel_balancer(&self) -> Option<&ChannelBalancer> {
+        self.balancer.as_ref()
+    }
+
+    /// Scrub one area faster than the others, as while it is having a
+    /// storm of errors. The area is scrubbed at the given multiple of the
+    /// rate at which the pass covers it, using reads in addition to those
+    /// of the pass. Extra reads are not counted in the statistics. An area
+    /// with a priority in the statistics is scrubbed at no less than one
+    /// more than its priority times the rate, whatever its multiplier.
+    /// The policies are told of the change if the multiplier is new.
+    ///
+    /// # Arguments:
+    /// * `area` - Index of the scrub area
+    ///
+    /// * `multiplier` - Rate multiplier. One returns the area to the normal
+    ///   rate.
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::ZeroSize) if the multiplier
+    /// is zero, or Err(Error::NoSuchArea) if there is no such area
+    pub fn set_area_rate(
+        &mut self,
+        area: usize,
+        multiplier: usize,
+    ) -> Result<(), Error> {
+        let extent =
+            *self.extents.get(area).ok_or(Error::NoSuchArea)?;
+        let scan = split_extents(&[extent], &self.declared);
+        if multiplier == 0 {
+            return Err(Error::ZeroSize);
+        }
+
+        let old = self.area_rate(area);
+        self.boosts.retain(|b| b.area != area);
+        if multiplier > 1 {
+            self.boosts.push(AreaBoost {
+                area,
+                extra: multiplier - 1,
+                weight: self.stats.areas[area].priority as usize,
+                order: ScrubOrder::new(
+                    &scan,
+                    self.line_size,
+                    self.index_width,
+                )?,
+                owed: 0,
+            });
+        }
+        if multiplier != old {
+            let change = ConfigChange::AreaRate { area, multiplier };
+            self.config_changed(change);
+        }
+        Ok(())
+    }
+
+    /// Returns the rate multiplier of a scrub area, as set by
+    /// set_area_rate()
+    pub fn area_rate(&self, area: usize) -> usize {
+        self.boosts
+            .iter()
+            .find(|b| b.area == area)
+            .map_or(1, |b| b.extra + 1)
+    }
+
+    // Boost each area with a priority in the statistics, which may have
+    // been seeded or changed since the last chunk, so that the pass
+    // favours the areas known to have errors
+    fn weight_by_priority(&mut self) -> Result<(), Error> {
+        for (area, stats) in self.stats.areas.iter().enumerate() {
+            let weight = stats.priority as usize;
+            match self.boosts.iter_mut().find(|b| b.area == area) {
+                Some(boost) => boost.weight = weight,
+                None if weight != 0 => self.boosts.push(AreaBoost {
+                    area,
+                    extra: 0,
+                    weight,
+                    order: ScrubOrder::new(
+                        &split_extents(
+                            &[self.extents[area]],
+                            &self.declared,
+                        ),
+                        self.line_size,
+                        self.index_width,
+                    )?,
+                    owed: 0,
+                }),
+                None => {}
+            }
+        }
+        self.boosts.retain(|b| b.extra != 0 || b.weight != 0);
+        Ok(())
+    }
+
+    /// Check that each cache line can be read before reading it. Lines
+    /// the validator rejects are skipped and counted, and the first of
+    /// them in each area in each chunk is given to the policies as a
+    /// LineSkipped event. They move the pass on but are not
+    /// counted in the bytes scrubbed. The validator is invalidated at the
+    /// start of each pass and whenever the scrub areas change.
+    ///
+    /// # Arguments:
+    /// * `validator` - The validator, or None to read every line
+    pub fn set_validator(
+        &mut self,
+        validator: Option<Box<dyn ReadValidator>>,
+    ) {
+        self.validator = validator;
+    }
+
+    /// Returns the number of lines skipped because the validator rejected
+    /// them
+    pub fn lines_skipped(&self) -> u64 {
+        self.lines_skipped
+    }
+
+    /// Set the minimum time between warnings to the diagnostics sink about
+    /// the unreadable lines of an area. The first lines an area has
+    /// skipped are warned about at the end of the chunk, and those skipped
+    /// later at the end of the first chunk after the interval has passed.
+    /// The default is
+    /// DEFAULT_WARN_INTERVAL.
+    ///
+    /// # Arguments:
+    /// * `interval` - Minimum time between warnings, or None for no
+    ///   warnings
+    pub fn set_unreadable_warning_interval(
+        &mut self,
+        interval: Option<Duration>,
+    ) {
+        self.warn_interval = interval;
+    }
+
+    /// Returns the number of bytes in a cache line
+    pub fn line_size(&self) -> usize {
+        self.line_size
+    }
+
+    /// Returns the (start, end) address of each scrub area, end inclusive
+    pub fn extents(&self) -> &[(usize, usize)] {
+        &self.extents
+    }
+
+    /// Add a scrub area. The current pass is restarted to include it.
+    ///
+    /// # Arguments:
+    /// * `start` - First address in the area, cache line aligned
+    ///
+    /// * `end` - Last address in the area
+    ///
+    /// # Returns:
+    /// Ok(index) with the index of the new area, otherwise Err(Error) if
+    /// the area is not valid
+    pub fn add_area(
+        &mut self,
+        start: usize,
+        end: usize,
+    ) -> Result<usize, Error> {
+        let mut extents = self.extents.clone();
+        extents.push((start, end));
+        self.set_extents(extents)?;
+        self.stats.areas.push(AreaStats::new(end - start + 1));
+        self.count_declared();
+        self.areas_changed();
+        let area = self.extents.len() - 1;
+        self.config_changed(ConfigChange::AddArea { area, start, end });
+        Ok(area)
+    }
+
+    /// Remove a scrub area. The current pass is restarted without it and
+    /// later areas move down one index.
+    ///
+    /// # Arguments:
+    /// * `area` - Index of the scrub area
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::NoSuchArea) if there is no
+    /// such area or Err(Error::NoMemAreas) if it is the only one
+    pub fn remove_area(&mut self, area: usize) -> Result<(), Error> {
+        if area >= self.extents.len() {
+            return Err(Error::NoSuchArea);
+        }
+        let mut extents = self.extents.clone();
+        extents.remove(area);
+        self.set_extents(extents)?;
+        self.stats.areas.remove(area);
+        if area < self.unreadable.len() {
+            self.unreadable.remove(area);
+        }
+        self.count_declared();
+        self.boosts.retain(|b| b.area != area);
+        for boost in self.boosts.iter_mut().filter(|b| b.area > area) {
+            boost.area -= 1;
+        }
+        self.areas_changed();
+        self.config_changed(ConfigChange::RemoveArea { area });
+        Ok(())
+    }
+
+    /// Replace a scrub area with another, as when the memory it was for
+    /// has gone and other memory has taken its place. The new area takes
+    /// the index of the old one, with statistics of its own, and the
+    /// current pass is restarted.
+    ///
+    /// # Arguments:
+    /// * `area` - Index of the scrub area
+    ///
+    /// * `start` - First address in the new area, cache line aligned
+    ///
+    /// * `end` - Last address in the new area
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::NoSuchArea) if there is no
+    /// such area, or Err(Error) if the new area is not valid
+    pub fn replace_area(
+        &mut self,
+        area: usize,
+        start: usize,
+        end: usize,
+    ) -> Result<(), Error> {
+        if area >= self.extents.len() {
+            return Err(Error::NoSuchArea);
+        }
+        let mut extents = self.extents.clone();
+        extents[area] = (start, end);
+        self.set_extents(extents)?;
+        self.stats.areas[area] = AreaStats::new(end - start + 1);
+        if let Some(warnings) = self.unreadable.get_mut(area) {
+            *warnings = UnreadableWarnings::default();
+        }
+        self.count_declared();
+        self.boosts.retain(|b| b.area != area);
+        self.areas_changed();
+        Ok(())
+    }
+
+    /// Move the end of a scrub area, as when the memory behind it shrinks
+    /// or grows back. The area keeps its index and statistics and the
+    /// current pass is restarted.
+    ///
+    /// # Arguments:
+    /// * `area` - Index of the scrub area
+    ///
+    /// * `end` - New last address in the area
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::NoSuchArea) if there is no
+    /// such area, or Err(Error) if the area would not be valid
+    pub fn resize_area(
+        &mut self,
+        area: usize,
+        end: usize,
+    ) -> Result<(), Error> {
+        let Some(&(start, _)) = self.extents.get(area) else {
+            return Err(Error::NoSuchArea);
+        };
+        if end < start {
+            return Err(Error::EmptyMemArea);
+        }
+        let mut extents = self.extents.clone();
+        extents[area].1 = end;
+        self.set_extents(extents)?;
+        self.stats.areas[area].size = end - start + 1;
+        self.count_declared();
+        self.areas_changed();
+        Ok(())
+    }
+
+    /// Declare a range that must never be scrubbed, such as a DMA ring
+    /// written by hardware or a structure too hot to disturb. The range is
+    /// widened to whole cache lines and the scrub areas are split around
+    /// it, so that its lines are left out of the pass rather than checked
+    /// one by one. They are counted in the declared_lines of each area's
+    /// statistics and not in the pass size. The current pass is restarted.
+    ///
+    /// # Arguments:
+    /// * `start` - First address in the range
+    ///
+    /// * `end` - Last address in the range
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::NoMemAreas) if nothing
+    /// would be left to scrub
+    pub fn declare_excluded(
+        &mut self,
+        start: usize,
+        end: usize,
+    ) -> Result<(), Error> {
+        let mask = self.line_size - 1;
+        let (mut start, mut end) =
+            (start.min(end) & !mask, start.max(end) | mask);
+        let mut declared = self.declared.clone();
+        declared.retain(|&(s, e)| {
+            let touches =
+                s <= end.saturating_add(1) && e.saturating_add(1) >= start;
+            if touches {
+                start = start.min(s);
+                end = end.max(e);
+            }
+            !touches
+        });
+        let i = declared.partition_point(|&(s, _)| s < start);
+        declared.insert(i, (start, end));
+
+        let previous = std::mem::replace(&mut self.declared, declared);
+        if let Err(err) = self.set_extents(self.extents.clone()) {
+            self.declared = previous;
+            return Err(err);
+        }
+        self.count_declared();
+        Ok(())
+    }
+
+    /// Returns the ranges declared never to be scrubbed, in whole cache
+    /// lines, sorted by address
+    pub fn declared_excluded(&self) -> &[(usize, usize)] {
+        &self.declared
+    }
+
+    // Count the lines of each area in declared ranges and the bytes of the
+    // pass left once they are taken out
+    fn count_declared(&mut self) {
+        let mut pass_size = 0;
+        for (i, &extent) in self.extents.iter().enumerate() {
+            let scanned: usize = split_extents(&[extent], &self.declared)
+                .iter()
+                .map(|&(s, e)| e - s + 1)
+                .sum();
+            let area = &mut self.stats.areas[i];
+            area.declared_lines =
+                ((area.size - scanned) / self.line_size) as u64;
+            pass_size += scanned;
+        }
+        self.stats.pass_size = pass_size;
+    }
+
+    // Tell the policies of a change to the configuration
+    fn config_changed(&mut self, change: ConfigChange) {
+        let now = self.clock.now();
+        for policy in self.policies.iter_mut() {
+            policy.config_changed(&change, now);
+        }
+    }
+
+    // Tell the policies the scrub areas have changed
+    fn areas_changed(&mut self) {
+        for policy in self.policies.iter_mut() {
+            policy.areas_changed(&self.extents);
+        }
+    }
+
+    // Change the scrub areas, restarting the pass
+    fn set_extents(
+        &mut self,
+        extents: Vec<(usize, usize)>,
+    ) -> Result<(), Error> {
+        let scan = split_extents(&extents, &self.declared);
+        let window = first_window(&scan, self.window_size);
+        let order = ScrubOrder::sub_pass(
+            &window_scan(&scan, self.window_size, window),
+            self.line_size,
+            self.index_width,
+            self.sub_passes,
+            0,
+        )?;
+
+        // A pass restarted part way through is kept with the lines it
+        // never reached
+        if self.stats.pass_offset != 0 {
+            if let Some(coverage) = self.pass_coverage()? {
+                self.push_coverage(coverage);
+            }
+        }
+
+        self.order = order;
+        if let Some(balancer) = self.balancer.as_mut() {
+            balancer.clear();
+        }
+        self.by_start = sorted_by_start(&extents);
+        self.extents = extents;
+        self.scan = scan;
+        self.sub_pass = 0;
+        self.window = window;
+        if let Some(validator) = self.validator.as_mut() {
+            validator.invalidate();
+        }
+        self.stats.pass_offset = 0;
+        self.stats.pass_started = self.clock.now();
+        if self.coverage.is_some() {
+            self.coverage = Some(self.new_coverage(self.stats.epoch)?);
+        }
+        if let Some(touches) = self.touches.as_mut() {
+            let epoch = self.stats.epoch;
+            touches.start_pass(&self.scan, self.line_size, epoch);
+        }
+        if self.exclude_own {
+            self.find_own_state();
+        }
+        Ok(())
+    }
+
+    /// Count how often each pass touches a random sample of its lines.
+    /// Sampling starts with the current pass if it has only just started,
+    /// otherwise with the next one.
+    ///
+    /// # Arguments:
+    /// * `sampler` - The sampler, or None to stop sampling
+    pub fn set_touch_sampling(&mut self, sampler: Option<TouchSampler>) {
+        self.touches = sampler;
+        if let Some(touches) = self.touches.as_mut() {
+            match self.stats.pass_offset {
+                0 => touches.start_pass(
+                    &self.scan,
+                    self.line_size,
+                    self.stats.epoch,
+                ),
+                _ => touches.abandon_pass(),
+            }
+        }
+    }
+
+    /// Returns the sampler counting the touches of a sample of the lines
+    /// of each pass, if any
+    pub fn touch_sampler(&self) -> Option<&TouchSampler> {
+        self.touches.as_ref()
+    }
+
+    // If the last line of the pass has been reached, report the touches of
+    // its sampled lines and sample the next pass
+    fn end_touch_pass(&mut self) {
+        if !self.pass_finished() {
+            return;
+        }
+        let Some(touches) = self.touches.as_mut() else {
+            return;
+        };
+        let epoch = match touches.finish_pass() {
+            Some(report) => {
+                if !report.is_clean() {
+                    diag!(
+                        Warning,
+                        "pass touched sampled lines other than once: \
+                         epoch={} sampled={} missed={} repeated={} \
+                         first_missed={:#x?} first_repeated={:#x?}",
+                        report.epoch,
+                        report.sampled,
+                        report.missed.len(),
+                        report.repeated.len(),
+                        report.missed.first(),
+                        report.repeated.first().map(|r| r.0)
+                    );
+                }
+                report.epoch + 1
+            }
+            None => self.stats.epoch + 1,
+        };
+        touches.start_pass(&self.scan, self.line_size, epoch);
+    }
+
+    /// Record the lines each pass leaves unscrubbed, and why, keeping
+    /// those of the most recent passes. Recording starts with the current
+    /// pass.
+    ///
+    /// # Arguments:
+    /// * `passes` - Number of passes to keep, or zero to stop recording
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error)
+    pub fn set_coverage_tracking(
+        &mut self,
+        passes: usize,
+    ) -> Result<(), Error> {
+        self.coverage_passes = passes;
+        if passes == 0 {
+            self.coverage = None;
+            self.coverage_history.clear();
+            return Ok(());
+        }
+        if self.coverage.is_none() {
+            self.coverage = Some(self.new_coverage(self.stats.epoch)?);
+        }
+        let extra = self.coverage_history.len().saturating_sub(passes);
+        self.coverage_history.drain(..extra);
+        Ok(())
+    }
+
+    /// Returns the lines left unscrubbed by the most recent passes, oldest
+    /// first. A pass restarted part way through is kept, with the lines it
+    /// never reached, but is not complete.
+    pub fn coverage_history(&self) -> &[PassCoverage] {
+        &self.coverage_history
+    }
+
+    /// Returns the lines the current pass has left unscrubbed so far, and
+    /// those it has yet to reach as Unfinished, as if scrubbing stopped
+    /// now. This takes time in proportion to the lines yet to be reached.
+    ///
+    /// # Returns:
+    /// Ok(Some(PassCoverage)) if coverage is tracked, Ok(None) if it is
+    /// not, otherwise Err(Error)
+    pub fn pass_coverage(&self) -> Result<Option<PassCoverage>, Error> {
+        let mut coverage = match &self.coverage {
+            Some(coverage) => coverage.clone(),
+            None => return Ok(None),
+        };
+        let mut unfinished: Vec<usize> = match &self.balancer {
+            Some(balancer) => balancer.held().collect(),
+            None => Vec::new(),
+        };
+        unfinished.extend(self.order.clone());
+        let (mut window, mut first) =
+            (Some(self.window), self.sub_pass + 1);
+        while let Some(start) = window {
+            let scan = window_scan(&self.scan, self.window_size, start);
+            for sub_pass in first..self.sub_passes {
+                unfinished.extend(ScrubOrder::sub_pass(
+                    &scan,
+                    self.line_size,
+                    self.index_width,
+                    self.sub_passes,
+                    sub_pass,
+                )?);
+            }
+            window = window_after(&self.scan, self.window_size, start);
+            first = 0;
+        }
+        unfinished.sort_unstable();
+        for addr in unfinished {
+            coverage.add_gap(
+                addr,
+                addr + (self.line_size - 1),
+                SkipReason::Unfinished,
+            )?;
+        }
+        Ok(Some(coverage))
+    }
+
+    /// Compare the lines left unscrubbed by the two most recent passes in
+    /// the coverage history
+    ///
+    /// # Returns:
+    /// The differences, or None if there are fewer than two passes
+    pub fn coverage_diff(&self) -> Option<CoverageDiff> {
+        match self.coverage_history.as_slice() {
+            [.., earlier, later] => Some(later.diff(earlier)),
+            _ => None,
+        }
+    }
+
+    // Start the coverage of a pass, with the declared ranges as gaps
+    fn new_coverage(&self, epoch: u64) -> Result<PassCoverage, Error> {
+        let mut coverage = PassCoverage::new(epoch);
+        for &(start, end) in &self.extents {
+            for &(s, e) in &self.declared {
+                if s <= end && e >= start {
+                    coverage.add_gap(
+                        s.max(start),
+                        e.min(end),
+                        SkipReason::Declared,
+                    )?;
+                }
+            }
+        }
+        Ok(coverage)
+    }
+
+    // Add the coverage of a finished or restarted pass to the history
+    fn push_coverage(&mut self, coverage: PassCoverage) {
+        self.coverage_history.push(coverage);
+        if self.coverage_history.len() > self.coverage_passes {
+            self.coverage_history.remove(0);
+        }
+    }
+
+    // Record a line left unscrubbed in the current pass
+    fn record_gap(
+        &mut self,
+        addr: usize,
+        reason: SkipReason,
+    ) -> Result<(), Error> {
+        match self.coverage.as_mut() {
+            Some(coverage) => coverage.add_gap(
+                addr,
+                addr + (self.line_size - 1),
+                reason,
+            ),
+            None => Ok(()),
+        }
+    }
+
+    // If the last line of the pass has been reached, move its coverage to
+    // the history and start that of the next pass
+    fn end_coverage_pass(&mut self) -> Result<(), Error> {
+        match self.pass_finished() {
+            true => self.finish_coverage(),
+            false => Ok(()),
+        }
+    }
+
+    // Move the coverage of the current pass to the history, complete, and
+    // start that of the next pass
+    fn finish_coverage(&mut self) -> Result<(), Error> {
+        let Some(mut coverage) = self.coverage.take() else {
+            return Ok(());
+        };
+        coverage.complete = true;
+        let epoch = coverage.epoch + 1;
+        self.push_coverage(coverage);
+        self.coverage = Some(self.new_coverage(epoch)?);
+        Ok(())
+    }
+
+    // Returns whether the last line of the pass has been reached
+    fn pass_finished(&self) -> bool {
+        self.order.len() == 0
+            && self.balancer.as_ref().is_none_or(|b| b.is_empty())
+            && self.last_sub_pass()
+    }
+
+    // Returns whether the current sub-pass is the last of the pass
+    fn last_sub_pass(&self) -> bool {
+        self.sub_pass + 1 == self.sub_passes
+            && window_after(&self.scan, self.window_size, self.window)
+                .is_none()
+    }
+
+    // Start a batch of reads, returning the time it started
+    fn begin_batch(&mut self) -> Result<Instant, Error> {
+        let started = self.clock.now();
+        self.unread = 0;
+        self.backend.begin_batch()?;
+        Ok(started)
+    }
+
+    // End a batch of reads, recording the time it took
+    fn end_batch(&mut self, started: Instant) -> Result<(), Error> {
+        self.backend.end_batch()?;
+        let now = self.clock.now();
+        self.stats
+            .record_batch(now.saturating_duration_since(started));
+        Ok(())
+    }
+
+    /// Returns the number of sub-passes in a pass and the current one
+    pub fn sub_pass(&self) -> (usize, usize) {
+        (self.sub_passes, self.sub_pass)
+    }
+
+    /// Scrub the next bytes of the pass, starting another pass as needed.
+    /// With a chunk alignment, more bytes are scrubbed so that the chunk
+    /// ends on a boundary.
+    ///
+    /// # Arguments:
+    /// * `bytes` - Number of bytes, a multiple of the cache line size
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error)
+    pub fn scrub(&mut self, bytes: usize) -> Result<(), Error> {
+        self.scrub_chunk(bytes).map(|_| ())
+    }
+
+    // Scrub the next bytes of the pass as for scrub(), returning the
+    // number of bytes scrubbed
+    fn scrub_chunk(&mut self, bytes: usize) -> Result<usize, Error> {
+        if !bytes.is_multiple_of(self.line_size) {
+            return Err(Error::UnalignedSize);
+        }
+        self.weight_by_priority()?;
+
+        let start = self.clock.now();
+        let batch = self.begin_batch()?;
+        let mut done = 0;
+        let result = loop {
+            if done >= bytes && (done == 0 || self.at_chunk_boundary()) {
+                break Ok(());
+            }
+            if let Err(err) = self.scrub_line() {
+                break Err(err);
+            }
+            done += self.line_size;
+        };
+        self.end_batch(batch)?;
+        result?;
+        let now = self.clock.now();
+        self.record_chunk(done, now - start, now)?;
+        Ok(done)
+    }
+
+    /// Scrub cache lines until a deadline is reached, as at the end of the
+    /// time slot the caller has for scrubbing. The time is checked before
+    /// each line, so the deadline is overrun by at most one line. With a
+    /// chunk alignment, lines are read past the deadline to reach the next
+    /// chunk boundary, but for no longer than the time before the
+    /// deadline, so the chunk may end short of a boundary.
+    ///
+    /// # Arguments:
+    /// * `deadline` - Time by which to stop
+    ///
+    /// # Returns:
+    /// Ok(bytes) with the number of bytes scrubbed, otherwise Err(Error)
+    pub fn scrub_until(
+        &mut self,
+        deadline: Instant,
+    ) -> Result<usize, Error> {
+        self.weight_by_priority()?;
+        let start = self.clock.now();
+        let mut now = start;
+        let mut bytes = 0;
+        let overrun = deadline
+            .checked_add(deadline.saturating_duration_since(start))
+            .unwrap_or(deadline);
+        let batch = self.begin_batch()?;
+        while now < deadline
+            || (bytes != 0 && now < overrun && !self.at_chunk_boundary())
+        {
+            if let Err(err) = self.scrub_line() {
+                self.end_batch(batch)?;
+                return Err(err);
+            }
+            bytes += self.line_size;
+            now = self.clock.now();
+        }
+        self.end_batch(batch)?;
+        if bytes != 0 {
+            self.record_chunk(bytes, now - start, now)?;
+        }
+        Ok(bytes)
+    }
+
+    /// Scrub cache lines for a length of time, as for scrub_until()
+    ///
+    /// # Arguments:
+    /// * `duration` - How long to scrub for
+    ///
+    /// # Returns:
+    /// Ok(bytes) with the number of bytes scrubbed, otherwise Err(Error)
+    pub fn scrub_for(
+        &mut self,
+        duration: Duration,
+    ) -> Result<usize, Error> {
+        self.scrub_until(self.clock.now() + duration)
+    }
+
+    /// Add a policy to be given each event, whose actions are then applied.
+    /// Policies are given events in the order they were added.
+    pub fn add_policy(&mut self, mut policy: Box<dyn Policy>) {
+        policy.areas_changed(&self.extents);
+        self.policies.push(policy);
+    }
+
+    /// Report a memory error. The error is attributed to the scrub area
+    /// containing the address, counted in the statistics, and given to the
+    /// policies.
+    ///
+    /// # Arguments:
+    /// * `error` - The error. Its area is filled in if not known.
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error) if applying the actions of a
+    /// policy failed
+    pub fn record_error(
+        &mut self,
+        error: ErrorEvent,
+    ) -> Result<(), Error> {
+        let addr = error.addr as usize;
+        let area = error.area.or_else(|| self.area_of(addr));
+        if let Some(area) = area {
+            let now = self.clock.now();
+            self.stats.record_error(
+                area,
+                error.severity == ErrorSeverity::Corrected,
+                now,
+            );
+        }
+        self.dispatch(&ScrubEvent::Error(ErrorEvent { area, ..error }))
+    }
+
+    /// Take an action, as a policy would. After a FlushReread, the
+    /// policies are given a LineFlushed event saying whether the line
+    /// could be flushed.
+    pub fn apply(&mut self, action: &PolicyAction) -> Result<(), Error> {
+        match *action {
+            PolicyAction::SetAreaRate { area, multiplier } => {
+                self.set_area_rate(area, multiplier)
+            }
+            PolicyAction::Exclude { start, end } => {
+                self.exclude(start, end);
+                Ok(())
+            }
+            PolicyAction::ScrubNear { addr, bytes } => {
+                self.scrub_near(addr, bytes).map(|_| ())
+            }
+            PolicyAction::FlushReread { addr } => {
+                let flushed = match self.flush_reread(addr) {
+                    Ok(()) => true,
+                    Err(Error::InternalError) => false,
+                    Err(e) => return Err(e),
+                };
+                self.dispatch(&ScrubEvent::LineFlushed {
+                    addr: (addr & !(self.line_size - 1)) as u64,
+                    flushed,
+                })
+            }
+        }
+    }
+
+    /// Stop scrubbing a range of addresses. Lines in the range are skipped
+    /// but still count as scrubbed in the statistics. The policies are
+    /// told of the change unless the range was already excluded.
+    ///
+    /// # Arguments:
+    /// * `start` - First address in the range
+    ///
+    /// * `end` - Last address in the range
+    pub fn exclude(&mut self, start: usize, end: usize) {
+        let (start, end) = (start.min(end), start.max(end));
+        if self.excluded.iter().any(|&(s, e)| s <= start && end <= e) {
+            return;
+        }
+        add_range(&mut self.excluded, (start, end));
+        self.config_changed(ConfigChange::Exclude { start, end });
+    }
+
+    /// Returns the ranges excluded from scrubbing, sorted by address
+    pub fn excluded(&self) -> &[(usize, usize)] {
+        &self.excluded
+    }
+
+    /// Scrub the lines in the scrub areas around an address now, out of
+    /// the order of the pass. These reads are not counted in the
+    /// statistics.
+    ///
+    /// # Arguments:
+    /// * `addr` - The address
+    ///
+    /// * `bytes` - Number of bytes to scrub on each side of it
+    ///
+    /// # Returns:
+    /// Ok(bytes) with the number of bytes scrubbed, otherwise Err(Error)
+    pub fn scrub_near(
+        &mut self,
+        addr: usize,
+        bytes: usize,
+    ) -> Result<usize, Error> {
+        let low = addr.saturating_sub(bytes);
+        self.scrub_extent(low, addr.saturating_add(bytes))
+    }
+
+    /// Scrub the lines in the scrub areas within a range now, out of the
+    /// order of the pass. These reads are not counted in the statistics.
+    ///
+    /// # Arguments:
+    /// * `start` - First address in the range
+    ///
+    /// * `end` - Last address in the range
+    ///
+    /// # Returns:
+    /// Ok(bytes) with the number of bytes scrubbed, otherwise Err(Error)
+    pub fn scrub_extent(
+        &mut self,
+        start: usize,
+        end: usize,
+    ) -> Result<usize, Error> {
+        let low = start & !(self.line_size - 1);
+        let batch = self.begin_batch()?;
+        let result = self.scrub_range(low, end);
+        self.end_batch(batch)?;
+        let scrubbed = result?;
+        self.report_skipped()?;
+        Ok(scrubbed)
+    }
+
+    /// Scrub the lines of the scrub areas within a range now as a chunk of
+    /// the pass, though out of its order, as for a scheduler that picks
+    /// which memory to scrub next. The lines are read in cache index order
+    /// and skipped as they would be by scrub(), and the range counts
+    /// towards the pass in the statistics and coverage, so a pass is
+    /// complete once as many bytes as are in it have been scrubbed,
+    /// however they were picked.
+    ///
+    /// # Arguments:
+    /// * `start` - First address in the range
+    ///
+    /// * `end` - Last address in the range
+    ///
+    /// # Returns:
+    /// Ok(bytes) with the number of bytes read, less those skipped,
+    /// otherwise Err(Error)
+    pub fn scrub_segment(
+        &mut self,
+        start: usize,
+        end: usize,
+    ) -> Result<usize, Error> {
+        let low = start & !(self.line_size - 1);
+        let high = end | (self.line_size - 1);
+        let pieces: Vec<(usize, usize)> = self
+            .scan
+            .iter()
+            .filter(|&&(s, e)| s <= high && e >= low)
+            .map(|&(s, e)| (s.max(low), e.min(high)))
+            .collect();
+        if pieces.is_empty() {
+            return Ok(0);
+        }
+        let order =
+            ScrubOrder::new(&pieces, self.line_size, self.index_width)?;
+
+        let passes = self.stats.passes;
+        let started = self.clock.now();
+        let batch = self.begin_batch()?;
+        let (mut bytes, mut scrubbed) = (0, 0);
+        let mut result = Ok(());
+        for addr in order {
+            bytes += self.line_size;
+            match self.scrub_addr(addr) {
+                Ok(true) => scrubbed += self.line_size,
+                Ok(false) => {}
+                Err(err) => {
+                    result = Err(err);
+                    break;
+                }
+            }
+        }
+        self.end_batch(batch)?;
+        self.report_skipped()?;
+        let now = self.clock.now();
+        self.record_chunk(bytes, now - started, now)?;
+        if self.coverage.is_some() && self.stats.passes != passes {
+            self.finish_coverage()?;
+        }
+        result.map(|()| scrubbed)
+    }
+
+    /// Flush the cache line holding an address and read it again, so that
+    /// it is read from memory rather than from a copy in the cache, as to
+    /// tell whether repeated corrected errors there come from the cache or
+    /// from memory. The read is not counted in the statistics. Lines
+    /// outside the scrub areas or excluded are left alone.
+    ///
+    /// # Arguments:
+    /// * `addr` - The address
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::InternalError) if the
+    /// backend can't flush cache lines, or another Err(Error)
+    pub fn flush_reread(&mut self, addr: usize) -> Result<(), Error> {
+        let line = addr & !(self.line_size - 1);
+        if !self.extents.iter().any(|&(s, e)| s <= line && line <= e)
+            || self.is_excluded(line)
+            || !self.is_readable(line)
+        {
+            return self.report_skipped();
+        }
+        self.backend.begin_batch()?;
+        let result = self.backend.flush_line(line).and_then(|_| {
+            self.backend.read_words(
+                line,
+                self.line_size,
+                self.reads_per_line,
+            )
+        });
+        self.backend.end_batch()?;
+        result
+    }
+
+    // Scrub the lines in the scrub areas between two addresses
+    fn scrub_range(
+        &mut self,
+        low: usize,
+        high: usize,
+    ) -> Result<usize, Error> {
+        let mut scrubbed = 0;
+        for i in 0..self.extents.len() {
+            let (start, end) = self.extents[i];
+            let mut line = low.max(start);
+            while line <= high.min(end) {
+                if !self.is_excluded(line) && self.is_readable(line) {
+                    self.backend.read_words(
+                        line,
+                        self.line_size,
+                        self.reads_per_line,
+                    )?;
+                    scrubbed += self.line_size;
+                }
+                line = match line.checked_add(self.line_size) {
+                    Some(line) => line,
+                    None => break,
+                };
+            }
+        }
+        Ok(scrubbed)
+    }
+
+    // Returns whether an address has been excluded from scrubbing, by a
+    // policy or a declaration
+    fn is_excluded(&self, addr: usize) -> bool {
+        [&self.excluded, &self.declared, &self.own]
+            .iter()
+            .any(|ranges| in_ranges(ranges, addr))
+    }
+
+    /// Leave the cache lines holding the scrubber's own state out of the
+    /// pass: the LineScrubber itself, the buffers of its statistics,
+    /// exclusion lists, schedule and coverage records, its validator and
+    /// clock, and its policies with the buffers they hold, as given by
+    /// Policy::buffers(). The lines are found again at the start of each
+    /// pass, since buffers move as they grow. The backend and the memory
+    /// it reads are not included.
+    ///
+    /// # Arguments:
+    /// * `exclude` - Whether to leave the scrubber's own state out
+    pub fn set_exclude_own_state(&mut self, exclude: bool) {
+        self.exclude_own = exclude;
+        match exclude {
+            true => self.find_own_state(),
+            false => self.own = Vec::new(),
+        }
+    }
+
+    /// Returns the ranges of whole cache lines holding the scrubber's own
+    /// state, as of the start of the pass, if they are left out of it
+    pub fn own_state(&self) -> &[(usize, usize)] {
+        &self.own
+    }
+
+    // Find the cache lines holding the scrubber's own state
+    fn find_own_state(&mut self) {
+        let mut found = vec![
+            value_buffer(self),
+            vec_buffer(&self.extents),
+            vec_buffer(&self.by_start),
+            vec_buffer(&self.scan),
+            vec_buffer(&self.boosts),
+            vec_buffer(&self.excluded),
+            vec_buffer(&self.declared),
+            vec_buffer(&self.policies),
+            vec_buffer(&self.skipped),
+            vec_buffer(&self.unreadable),
+            vec_buffer(&self.coverage_history),
+            vec_buffer(&self.stats.areas),
+            self.stats.chunk_latency.buffer(),
+            self.stats.batch_latency.buffer(),
+            value_buffer(self.clock.as_ref()),
+        ];
+        found.extend(
+            self.policies.iter().flat_map(|p| policy_buffers(p.as_ref())),
+        );
+        found.extend(self.validator.as_deref().map(value_buffer));
+        found.push(self.order.buffer());
+        found.extend(self.touches.iter().flat_map(|t| t.buffers()));
+        found.extend(self.boosts.iter().map(|b| b.order.buffer()));
+        found.extend(
+            self.stats
+                .areas
+                .iter()
+                .filter_map(|a| a.label.as_ref())
+                .map(|l| (l.as_ptr() as usize, l.capacity())),
+        );
+        found.extend(
+            self.coverage
+                .iter()
+                .chain(&self.coverage_history)
+                .map(|c| value_buffer(c.gaps())),
+        );
+
+        // The list of ranges is itself part of the state, so room is made
+        // for it before its own address is taken
+        let mask = self.line_size - 1;
+        let mut own = Vec::with_capacity(found.len() + 1);
+        own.extend(
+            found
+                .into_iter()
+                .filter(|&(_, len)| len != 0)
+                .map(|(addr, len)| {
+                    (addr & !mask, addr.saturating_add(len - 1) | mask)
+                }),
+        );
+        let (addr, len) = vec_buffer(&own);
+        own.push((addr & !mask, addr.saturating_add(len - 1) | mask));
+        own.sort_unstable();
+        own.dedup_by(|next, prev| {
+            if next.0 <= prev.1.saturating_add(1) {
+                prev.1 = prev.1.max(next.1);
+                true
+            } else {
+                false
+            }
+        });
+        self.own = own;
+    }
+
+    // Returns whether the validator, if any, allows a line to be read. A
+    // line that isn't is remembered for report_skipped().
+    fn is_readable(&mut self, addr: usize) -> bool {
+        let readable = match self.validator.as_mut() {
+            Some(validator) => validator.is_readable(addr, self.line_size),
+            None => true,
+        };
+        if !readable {
+            self.skipped.push(addr);
+            self.lines_skipped += 1;
+        }
+        readable
+    }
+
+    // Count the lines skipped since the last call against their areas and
+    // give the policies an event for the first skipped in each area in the
+    // chunk
+    fn report_skipped(&mut self) -> Result<(), Error> {
+        if self.skipped.is_empty() {
+            return Ok(());
+        }
+        let skipped = std::mem::take(&mut self.skipped);
+        self.unreadable
+            .resize(self.extents.len(), UnreadableWarnings::default());
+        for &addr in &skipped {
+            if let Some(i) = self.area_of(addr) {
+                if let Some(area) = self.stats.areas.get_mut(i) {
+                    area.unreadable_lines += 1;
+                }
+                let warnings = &mut self.unreadable[i];
+                warnings.pending += 1;
+                if std::mem::replace(&mut warnings.reported, true) {
+                    continue;
+                }
+            }
+            self.dispatch(&ScrubEvent::LineSkipped { addr: addr as u64 })?;
+        }
+        Ok(())
+    }
+
+    // Returns the index of the scrub area holding an address, if any
+    fn area_of(&self, addr: usize) -> Option<usize> {
+        let after = self
+            .by_start
+            .partition_point(|&i| self.extents[i].0 <= addr);
+        let i = *self.by_start.get(after.checked_sub(1)?)?;
+        (addr <= self.extents[i].1).then_some(i)
+    }
+
+    // Set the lines of each area left out of scrubbing from the backend,
+    // if it keeps track of them
+    fn count_excluded(&mut self) {
+        for (i, &(start, end)) in self.extents.iter().enumerate() {
+            let lines = self.backend.excluded_lines(start, end);
+            if let (Some(lines), Some(area)) =
+                (lines, self.stats.areas.get_mut(i))
+            {
+                area.excluded_lines = lines;
+            }
+        }
+    }
+
+    // At the end of a chunk, warn about each area with lines skipped that
+    // hasn't been warned about within the interval
+    fn warn_unreadable(&mut self) {
+        for warnings in &mut self.unreadable {
+            warnings.reported = false;
+        }
+        let Some(interval) = self.warn_interval else {
+            return;
+        };
+        let now = self.clock.now();
+        for (i, warnings) in self.unreadable.iter_mut().enumerate() {
+            if warnings.pending == 0
+                || warnings.warned.is_some_and(|warned| {
+                    now.saturating_duration_since(warned) < interval
+                })
+            {
+                continue;
+            }
+            let (start, end) = self.extents[i];
+            let (label, total) = match self.stats.areas.get_mut(i) {
+                Some(area) => {
+                    area.unreadable_warnings += 1;
+                    (area.label.as_deref(), area.unreadable_lines)
+                }
+                None => (None, warnings.pending),
+            };
+            diag!(
+                Warning,
+                "unreadable lines skipped: area={} label={} \
+                 start={:#x} end={:#x} lines={} total={}",
+                i,
+                label.unwrap_or("-"),
+                start,
+                end,
+                warnings.pending,
+                total
+            );
+            warnings.warned = Some(now);
+            warnings.pending = 0;
+        }
+    }
+
+    // Record a chunk in the statistics and give the policies the events for
+    // it
+    fn record_chunk(
+        &mut self,
+        bytes: usize,
+        duration: Duration,
+        now: Instant,
+    ) -> Result<(), Error> {
+        let passes = self.stats.passes;
+        let pass_started = self.stats.pass_started;
+        let unread = std::mem::take(&mut self.unread);
+        self.stats
+            .record_chunk_skipping(bytes, unread, duration, now);
+        self.count_excluded();
+        self.warn_unreadable();
+        if self.policies.is_empty() {
+            return Ok(());
+        }
+
+        self.dispatch(&ScrubEvent::ChunkComplete { bytes, duration })?;
+        if self.stats.passes != passes {
+            self.dispatch(&ScrubEvent::PassComplete {
+                pass: self.stats.passes,
+                epoch: self.stats.epoch - 1,
+                bytes: self.stats.pass_size,
+                duration: now - pass_started,
+            })?;
+        }
+        Ok(())
+    }
+
+    // Give an event to each policy and apply the actions they return.
+    // Exclusions are applied first, so that lines another policy asks to
+    // read, such as a poisoned line, are left alone once excluded.
+    fn dispatch(&mut self, event: &ScrubEvent) -> Result<(), Error> {
+        let now = self.clock.now();
+        let mut policies = std::mem::take(&mut self.policies);
+        let mut actions: Vec<PolicyAction> = policies
+            .iter_mut()
+            .flat_map(|p| p.event(event, now))
+            .collect();
+        self.policies = policies;
+        actions
+            .sort_by_key(|a| !matches!(a, PolicyAction::Exclude { .. }));
+        for action in &actions {
+            self.apply(action)?;
+        }
+        Ok(())
+    }
+
+    // Scrub the next line of the pass, starting another sub-pass as needed
+    fn scrub_line(&mut self) -> Result<(), Error> {
+        let addr = self.next_line()?;
+        self.last_line = addr;
+        if let Some(touches) = self.touches.as_mut() {
+            touches.touch(addr);
+        }
+        self.scrub_addr(addr)?;
+        if self.coverage.is_some() {
+            self.end_coverage_pass()?;
+        }
+        if self.touches.is_some() {
+            self.end_touch_pass();
+        }
+        if !self.boosts.is_empty() {
+            self.scrub_boosts()?;
+        }
+        self.report_skipped()
+    }
+
+    // Scrub a line of the pass unless it is to be skipped, recording why
+    // it was in the coverage. Returns whether the line was read.
+    fn scrub_addr(&mut self, addr: usize) -> Result<bool, Error> {
+        if in_ranges(&self.own, addr) {
+            self.record_gap(addr, SkipReason::OwnState)?;
+        } else if self.is_excluded(addr) {
+            self.record_gap(addr, SkipReason::Excluded)?;
+        } else if let Some(reason) = self.backend.skip_reason(addr) {
+            self.record_gap(addr, reason)?;
+        } else if !self.is_readable(addr) {
+            self.unread += self.line_size;
+            self.record_gap(addr, SkipReason::Unreadable)?;
+        } else {
+            self.backend.read_words(
+                addr,
+                self.line_size,
+                self.reads_per_line,
+            )?;
+            return Ok(true);
+        }
+        Ok(false)
+    }
+
+    // Returns the address of the next line of the pass, starting another
+    // sub-pass as needed
+    fn next_line(&mut self) -> Result<usize, Error> {
+        match self.next_in_sub_pass() {
+            Some(addr) => Ok(addr),
+            None => {
+                self.next_sub_pass()?;
+                self.next_in_sub_pass().ok_or(Error::IteratorFailed)
+            }
+        }
+    }
+
+    // Returns the address of the next line of the sub-pass, if any
+    fn next_in_sub_pass(&mut self) -> Option<usize> {
+        match self.balancer.as_mut() {
+            Some(balancer) => balancer.next_from(&mut self.order),
+            None => self.order.next(),
+        }
+    }
+
+    // Start the next sub-pass, moving on to the next window after the
+    // last sub-pass of a window, or the first of the next pass
+    fn next_sub_pass(&mut self) -> Result<(), Error> {
+        self.sub_pass = (self.sub_pass + 1) % self.sub_passes;
+        let mut new_pass = self.sub_pass == 0;
+        if new_pass && self.window_size.is_some() {
+            self.backend.end_window()?;
+            match window_after(&self.scan, self.window_size, self.window) {
+                Some(window) => {
+                    self.window = window;
+                    new_pass = false;
+                }
+                None => {
+                    self.window =
+                        first_window(&self.scan, self.window_size)
+                }
+            }
+        }
+        self.order = ScrubOrder::sub_pass(
+            &window_scan(&self.scan, self.window_size, self.window),
+            self.line_size,
+            self.index_width,
+            self.sub_passes,
+            self.sub_pass,
+        )?;
+        if new_pass {
+            if let Some(validator) = self.validator.as_mut() {
+                validator.invalidate();
+            }
+            if self.exclude_own {
+                self.find_own_state();
+            }
+        }
+        Ok(())
+    }
+
+    /// Scrub the rest of the current pass, or the whole of the next one if
+    /// the current pass has just finished, as fast as possible. Lines are
+    /// read in the same order as by scrub(), but without checking for
+    /// excluded ranges or boosted areas, or reading the clock, for each
+    /// line. Use it for a full scrub, as at boot, when nothing has been
+    /// excluded or boosted yet and there is no validator or channel
+    /// balancer. The scrubber's own state may be left out, as it is found
+    /// before the pass starts.
+    ///
+    /// # Returns:
+    /// Ok(bytes) with the number of bytes scrubbed, otherwise
+    /// Err(Error::FastPathUnavailable) if any range is excluded or area
+    /// boosted or given a priority, touches are sampled, or there is a
+    /// validator or channel balancer,
+    /// or another Err(Error) if reading a line failed. The lines passed
+    /// before a failed read, and the failed line itself, are recorded as
+    /// a chunk, so the pass continues after it.
+    pub fn scrub_pass_fast(&mut self) -> Result<usize, Error> {
+        self.weight_by_priority()?;
+        if !self.excluded.is_empty()
+            || self.touches.is_some()
+            || !self.boosts.is_empty()
+            || self.validator.is_some()
+            || self.balancer.is_some()
+        {
+            return Err(Error::FastPathUnavailable);
+        }
+
+        let start = self.clock.now();
+        if self.order.len() == 0 {
+            self.next_sub_pass()?;
+        }
+        let mut bytes = 0;
+        let batch = self.begin_batch()?;
+        let result = loop {
+            let (line_size, reads) = (self.line_size, self.reads_per_line);
+            let backend = &mut self.backend;
+            let coverage = &mut self.coverage;
+            let unread = &mut self.unread;
+            let own = &self.own;
+            let result = self.order.try_for_each_fast(|addr| {
+                bytes += line_size;
+                let skip = match in_ranges(own, addr) {
+                    true => Some(SkipReason::OwnState),
+                    false => backend.skip_reason(addr),
+                };
+                match skip {
+                    Some(reason) => match coverage.as_mut() {
+                        Some(coverage) => {
+                            let end = addr + (line_size - 1);
+                            coverage.add_gap(addr, end, reason)
+                        }
+                        None => Ok(()),
+                    },
+                    None => {
+                        let result =
+                            backend.read_words(addr, line_size, reads);
+                        if result.is_err() {
+                            *unread += line_size;
+                        }
+                        result
+                    }
+                }
+            });
+            if result.is_err() || self.last_sub_pass() {
+                break result;
+            }
+            if let Err(err) = self.next_sub_pass() {
+                break Err(err);
+            }
+        };
+        self.end_batch(batch)?;
+        if self.coverage.is_some() {
+            self.end_coverage_pass()?;
+        }
+        let now = self.clock.now();
+        if bytes != 0 {
+            self.record_chunk(bytes, now - start, now)?;
+        }
+        result.map(|()| bytes)
+    }
+
+    /// Continue from a position in the pass, as saved in the statistics
+    /// before a restart. Lines before the position are not read. They are
+    /// skipped a sub-pass at a time, unless a channel balancer reorders
+    /// them or touches are sampled, when each must be walked.
+    ///
+    /// # Arguments:
+    /// * `offset` - Number of bytes of the pass already scrubbed
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::UnalignedSize) if the offset
+    /// is not a multiple of the cache line size or
+    /// Err(Error::AddressOverflow) if it is beyond the end of the pass
+    pub fn resume_at(&mut self, offset: usize) -> Result<(), Error> {
+        if !offset.is_multiple_of(self.line_size) {
+            return Err(Error::UnalignedSize);
+        }
+        if offset >= self.stats.pass_size {
+            return Err(Error::AddressOverflow);
+        }
+        let pass_started = self.stats.pass_started;
+        self.set_extents(self.extents.clone())?;
+        let mut lines = offset / self.line_size;
+        if self.balancer.is_some() || self.touches.is_some() {
+            for _ in 0..lines {
+                let addr = self.next_line()?;
+                if let Some(touches) = self.touches.as_mut() {
+                    touches.touch(addr);
+                }
+            }
+        } else {
+            while lines > self.order.len() {
+                lines -= self.order.len();
+                self.next_sub_pass()?;
+            }
+            if let Some(skip) = lines.checked_sub(1) {
+                self.order.nth(skip).ok_or(Error::IteratorFailed)?;
+            }
+        }
+        self.stats.pass_offset = offset;
+        self.stats.pass_started = pass_started;
+        Ok(())
+    }
+
+    // Scrub the extra lines owed to boosted areas for one line of the pass
+    fn scrub_boosts(&mut self) -> Result<(), Error> {
+        let pass_lines = self.stats.pass_size / self.line_size;
+        for boost in self.boosts.iter_mut() {
+            let area = &self.stats.areas[boost.area];
+            let area_lines =
+                area.size / self.line_size - area.declared_lines as usize;
+            boost.owed += area_lines * boost.extra.max(boost.weight);
+            while boost.owed >= pass_lines {
+                boost.owed -= pass_lines;
+                let addr = match boost.order.next() {
+                    Some(addr) => addr,
+                    None => {
+                        boost.order = ScrubOrder::new(
+                            &split_extents(
+                                &[self.extents[boost.area]],
+                                &self.declared,
+                            ),
+                            self.line_size,
+                            self.index_width,
+                        )?;
+                        boost.order.next().ok_or(Error::IteratorFailed)?
+                    }
+                };
+                if in_ranges(&self.own, addr) {
+                    continue;
+                }
+                let readable = match self.validator.as_mut() {
+                    Some(validator) => {
+                        validator.is_readable(addr, self.line_size)
+                    }
+                    None => true,
+                };
+                if !readable {
+                    self.skipped.push(addr);
+                    self.lines_skipped += 1;
+                    continue;
+                }
+                self.backend.read_words(
+                    addr,
+                    self.line_size,
+                    self.reads_per_line,
+                )?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Scrub a fraction of the memory in all scrub areas, such as 0.001 for
+    /// 0.1% of it, rounded to the nearest whole cache line and, with a
+    /// chunk alignment, up to the next chunk boundary
+    ///
+    /// # Arguments:
+    /// * `fraction` - Fraction of the memory to scrub. Values greater than
+    ///   one scrub more than a pass.
+    ///
+    /// # Returns:
+    /// Ok(bytes) with the number of bytes scrubbed, otherwise
+    /// Err(Error::UnalignedValue) if the fraction is negative or not
+    /// finite, or another Err(Error) if scrubbing failed
+    pub fn scrub_fraction(
+        &mut self,
+        fraction: f64,
+    ) -> Result<usize, Error> {
+        if !fraction.is_finite() || fraction < 0.0 {
+            return Err(Error::UnalignedValue);
+        }
+        let lines = self.stats.pass_size / self.line_size;
+        let lines = (lines as f64 * fraction).round();
+        if lines >= usize::MAX as f64 / self.line_size as f64 {
+            return Err(Error::AddressOverflow);
+        }
+        self.scrub_chunk(lines as usize * self.line_size)
+    }
+
+    /// Scrub until the chunk size function returns zero
+    ///
+    /// # Arguments:
+    /// * `next` - Returns the number of bytes to scrub next, given the
+    ///   statistics so far, or zero to stop
+    ///
+    /// # Returns:
+    /// Ok(ScrubSummary) describing the scrubbing done, otherwise Err(Error)
+    pub fn run<F>(&mut self, mut next: F) -> Result<ScrubSummary, Error>
+    where
+        F: FnMut(&ScrubStats) -> usize,
+    {
+        let start_stats = self.stats.clone();
+        let started = self.clock.now();
+        loop {
+            let bytes = next(&self.stats);
+            if bytes == 0 {
+                break;
+            }
+            self.scrub(bytes)?;
+        }
+
+        Ok(ScrubSummary::new(
+            &start_stats,
+            &self.stats,
+            self.clock.now() - started,
+            StopReason::Finished,
+        ))
+    }
+
+    /// Returns the statistics for the scrubbing done so far
+    pub fn stats(&self) -> &ScrubStats {
+        &self.stats
+    }
+
+    /// Returns the statistics, as for restoring saved ones
+    pub fn stats_mut(&mut self) -> &mut ScrubStats {
+        &mut self.stats
+    }
+
+    /// Set the source of time used for the statistics, deadlines and
+    /// policies, such as a VirtualClock in tests. This should be done
+    /// before scrubbing, since the statistics are restarted at the time
+    /// of the new clock.
+    pub fn set_clock(&mut self, clock: Box<dyn Clock>) {
+        let now = clock.now();
+        self.stats.started = now;
+        self.stats.pass_started = now;
+        self.clock = clock;
+    }
+
+    /// Returns the source of time
+    pub fn clock(&self) -> &dyn Clock {
+        self.clock.as_ref()
+    }
+
+    /// Returns the time since a scrub area was last completely scrubbed,
+    /// by the scrubber's clock
+    ///
+    /// # Arguments:
+    /// * `area` - Index of the scrub area
+    ///
+    /// # Returns:
+    /// Some(Duration) once a pass covering the area has completed,
+    /// otherwise None
+    pub fn staleness(&self, area: usize) -> Option<Duration> {
+        self.stats.areas.get(area)?.staleness(self.clock.now())
+    }
+
+    /// Returns the backend
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns the backend
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    /// Consume the scrubber, returning its backend
+    pub fn into_backend(self) -> B {
+        self.backend
+    }
+}
+
+// Returns whether an address is in one of the ranges, which are sorted
+// and don't overlap
+fn in_ranges(ranges: &[(usize, usize)], addr: usize) -> bool {
+    let i = ranges.partition_point(|&(_, e)| e < addr);
+    ranges.get(i).is_some_and(|&(s, _)| s <= addr)
+}
+
+// Returns the index of each scrub area, sorted by start address
+fn sorted_by_start(extents: &[(usize, usize)]) -> Vec<usize> {
+    let mut by_start: Vec<usize> = (0..extents.len()).collect();
+    by_start.sort_by_key(|&i| extents[i].0);
+    by_start
+}
+
+// Returns the first address of the first window of a pass walked a window
+// at a time, or zero if it isn't. The pieces of the pass, like the scrub
+// areas, need not be in address order.
+fn first_window(
+    scan: &[(usize, usize)],
+    window_size: Option<usize>,
+) -> usize {
+    match (window_size, scan.iter().map(|&(s, _)| s).min()) {
+        (Some(size), Some(start)) => start & !(size - 1),
+        _ => 0,
+    }
+}
+
+// Returns the first address of the next window after one with lines of
+// the pass in it, if there is one
+fn window_after(
+    scan: &[(usize, usize)],
+    window_size: Option<usize>,
+    window: usize,
+) -> Option<usize> {
+    let size = window_size?;
+    let next = (window | (size - 1)).checked_add(1)?;
+    let start = scan
+        .iter()
+        .filter(|&&(_, e)| e >= next)
+        .map(|&(s, _)| s.max(next))
+        .min()?;
+    Some(start & !(size - 1))
+}
+
+// Returns the parts of the pieces of a pass in a window, or all of them if
+// the pass isn't walked a window at a time
+fn window_scan(
+    scan: &[(usize, usize)],
+    window_size: Option<usize>,
+    window: usize,
+) -> Vec<(usize, usize)> {
+    let Some(size) = window_size else {
+        return scan.to_vec();
+    };
+    let end = window | (size - 1);
+    scan.iter()
+        .filter(|&&(s, e)| s <= end && e >= window)
+        .map(|&(s, e)| (s.max(window), e.min(end)))
+        .collect()
+}
+
+// Returns the extents less the ranges, which are sorted and don't overlap
+pub(crate) fn split_extents(
+    extents: &[(usize, usize)],
+    ranges: &[(usize, usize)],
+) -> Vec<(usize, usize)> {
+    let mut pieces = Vec::new();
+    for &(start, end) in extents {
+        let mut next = Some(start);
+        for &(s, e) in
+            ranges.iter().filter(|&&(s, e)| s <= end && e >= start)
+        {
+            if let Some(n) = next.filter(|&n| n < s) {
+                pieces.push((n, s - 1));
+            }
+            next = e.checked_add(1);
+        }
+        if let Some(n) = next.filter(|&n| n <= end) {
+            pieces.push((n, end));
+        }
+    }
+    pieces
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_read_words() {
+        let mut recorder = Recorder::default();
+        recorder.read_words(4096, 64, 1).unwrap();
+        recorder.read_words(8192, 64, 4).unwrap();
+        assert_eq!(recorder.reads(), [4096, 8192, 8208, 8224, 8240]);
+    }
+
+    #[test]
+    fn test_sub_passes() {
+        // Sixteen sets of 64 bytes, so set i is at 64 * i
+        let mut scrubber =
+            LineScrubber::new(Recorder::default(), &[(0, 2047)], 64, 4)
+                .unwrap();
+        assert_eq!(
+            scrubber.set_sub_passes(32),
+            Err(Error::UnalignedValue)
+        );
+        scrubber.set_sub_passes(4).unwrap();
+
+        scrubber.scrub(512).unwrap();
+        let sets: Vec<usize> = scrubber
+            .backend()
+            .reads()
+            .iter()
+            .map(|a| a / 64 % 16)
+            .collect();
+        assert!(sets.iter().all(|set| set % 4 == 0));
+        assert_eq!(scrubber.sub_pass(), (4, 0));
+
+        // A whole pass reads every line once
+        scrubber.scrub(2048 - 512).unwrap();
+        assert_eq!(scrubber.sub_pass(), (4, 3));
+        assert_eq!(scrubber.stats().passes, 1);
+        let mut addrs = scrubber.backend().reads();
+        addrs.sort();
+        assert_eq!(addrs, (0..2048).step_by(64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_channel_balancer() {
+        // Sixteen sets of 64 bytes and two channels, chosen by bit 11, so
+        // without balancing the lines of a set go two to a channel
+        let map = ChannelMap::linear(11, 1).unwrap();
+        let mut scrubber =
+            LineScrubber::new(Recorder::default(), &[(0, 4095)], 64, 4)
+                .unwrap();
+        scrubber.set_sub_passes(2).unwrap();
+        let balancer = ChannelBalancer::new(map.clone(), 4).unwrap();
+        scrubber.set_channel_balancer(Some(balancer)).unwrap();
+        assert_eq!(
+            scrubber.scrub_pass_fast(),
+            Err(Error::FastPathUnavailable)
+        );
+
+        scrubber.scrub(512).unwrap();
+        let channels: Vec<usize> = scrubber
+            .backend()
+            .reads()
+            .iter()
+            .map(|&addr| map.channel(addr))
+            .collect();
+        assert_eq!(channels, [0, 1, 0, 1, 0, 1, 0, 1]);
+
+        // A whole pass still reads every line once
+        scrubber.scrub(4096 - 512).unwrap();
+        assert_eq!(scrubber.stats().passes, 1);
+        let mut addrs = scrubber.backend().reads();
+        addrs.sort();
+        assert_eq!(addrs, (0..4096).step_by(64).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_scrub_pass_fast() {
+        let extents = [(0, 1023), (4096, 5119)];
+        let mut fast =
+            LineScrubber::new(Recorder::default(), &extents, 64, 2)
+                .unwrap();
+        let mut slow =
+            LineScrubber::new(Recorder::default(), &extents, 64, 2)
+                .unwrap();
+        fast.set_sub_passes(2).unwrap();
+        slow.set_sub_passes(2).unwrap();
+
+        // The rest of a pass, in the same order as scrub()
+        fast.scrub(640).unwrap();
+        assert_eq!(fast.scrub_pass_fast(), Ok(2048 - 640));
+        slow.scrub(2048).unwrap();
+        assert_eq!(fast.backend().reads(), slow.backend().reads());
+        assert_eq!(fast.stats().passes, 1);
+        assert_eq!(fast.stats().pass_offset, 0);
+
+        // Then a whole pass
+        assert_eq!(fast.scrub_pass_fast(), Ok(2048));
+        slow.scrub(2048).unwrap();
+        assert_eq!(fast.backend().reads(), slow.backend().reads());
+        assert_eq!(fast.stats().passes, 2);
+
+        // Excluded ranges need checking for each line
+        fast.exclude(0, 63);
+        assert_eq!(
+            fast.scrub_pass_fast(),
+            Err(Error::FastPathUnavailable)
+        );
+
+        // Fails the first read of a line
+        struct FailOnce(Recorder, Option<usize>);
+
+        impl ScrubBackend for FailOnce {
+            fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+                if self.1 == Some(addr) {
+                    self.1 = None;
+                    return Err(Error::InternalError);
+                }
+                self.0.read_line(addr)
+            }
+        }
+
+        // A failed read ends the chunk after the line that failed, and
+        // the pass carries on from there
+        let backend = FailOnce(Recorder::default(), Some(256));
+        let mut scrubber =
+            LineScrubber::new(backend, &[(0, 1023)], 64, 2).unwrap();
+        assert_eq!(scrubber.scrub_pass_fast(), Err(Error::InternalError));
+        assert_eq!(scrubber.stats().pass_offset, 128);
+        assert_eq!(scrubber.stats().bytes_scrubbed, 64);
+        assert_eq!(scrubber.scrub_pass_fast(), Ok(1024 - 128));
+        assert_eq!(scrubber.stats().passes, 1);
+        let mut reads = scrubber.backend().0.reads();
+        reads.sort();
+        assert_eq!(reads.len(), 15);
+        assert!(!reads.contains(&256));
+    }
+
+    #[test]
+    fn test_resume_at() {
+        // Resuming skips to the line a scrubber walking the pass would
+        // read next, with or without walking each line
+        let extents = [(0, 1023), (4096, 4607)];
+        let mut walked =
+            LineScrubber::new(Recorder::default(), &extents, 64, 2)
+                .unwrap();
+        walked.set_sub_passes(2).unwrap();
+        walked.scrub(640).unwrap();
+        walked.backend_mut().clear();
+        walked.scrub(64 * 24 - 640).unwrap();
+
+        let mut skipped =
+            LineScrubber::new(Recorder::default(), &extents, 64, 2)
+                .unwrap();
+        skipped.set_sub_passes(2).unwrap();
+        skipped.resume_at(640).unwrap();
+        skipped.scrub(64 * 24 - 640).unwrap();
+        assert_eq!(skipped.backend().reads(), walked.backend().reads());
+        assert_eq!(skipped.stats().passes, 1);
+
+        let mut sampled =
+            LineScrubber::new(Recorder::default(), &extents, 64, 2)
+                .unwrap();
+        sampled.set_sub_passes(2).unwrap();
+        sampled.set_touch_sampling(Some(TouchSampler::new(4).unwrap()));
+        sampled.resume_at(640).unwrap();
+        sampled.scrub(64 * 24 - 640).unwrap();
+        assert_eq!(sampled.backend().reads(), walked.backend().reads());
+        assert_eq!(
+            skipped.resume_at(64 * 24),
+            Err(Error::AddressOverflow)
+        );
+    }
+
+    #[test]
+    fn test_validator() {
+        struct Skips(Rc<RefCell<Vec<u64>>>);
+
+        impl Policy for Skips {
+            fn event(
+                &mut self,
+                event: &ScrubEvent,
+                _now: Instant,
+            ) -> Vec<PolicyAction> {
+                if let ScrubEvent::LineSkipped { addr } = *event {
+                    self.0.borrow_mut().push(addr);
+                }
+                Vec::new()
+            }
+        }
+
+        let mut scrubber =
+            LineScrubber::new(Recorder::default(), &[(0, 1023)], 64, 2)
+                .unwrap();
+        let skips = Rc::new(RefCell::new(Vec::new()));
+        scrubber.add_policy(Box::new(Skips(skips.clone())));
+
+        // The second 256 bytes have gone away
+        scrubber.set_validator(Some(Box::new(|addr: usize, _| {
+            !(256..512).contains(&addr)
+        })));
+        scrubber.scrub(1024).unwrap();
+        assert_eq!(scrubber.backend().reads().len(), 12);
+        assert!(scrubber
+            .backend()
+            .reads()
+            .iter()
+            .all(|a| !(256..512).contains(a)));
+        assert_eq!(scrubber.lines_skipped(), 4);
+        assert_eq!(*skips.borrow(), [256]);
+        assert_eq!(scrubber.stats().passes, 1);
+        assert_eq!(scrubber.stats().bytes_scrubbed, 768);
+        assert_eq!(
+            scrubber.scrub_pass_fast(),
+            Err(Error::FastPathUnavailable)
+        );
+
+        scrubber.set_validator(None);
+        scrubber.scrub(1024).unwrap();
+        assert_eq!(scrubber.backend().reads().len(), 28);
+        assert_eq!(scrubber.stats().bytes_scrubbed, 1792);
+    }
+
+    #[test]
+    fn test_validator_invalidate() {
+        struct Counted(Rc<RefCell<usize>>);
+
+        impl ReadValidator for Counted {
+            fn is_readable(&mut self, _addr: usize, _len: usize) -> bool {
+                true
+            }
+
+            fn invalidate(&mut self) {
+                *self.0.borrow_mut() += 1;
+            }
+        }
+
+        let mut scrubber =
+            LineScrubber::new(Recorder::default(), &[(0, 1023)], 64, 2)
+                .unwrap();
+        let invalidated = Rc::new(RefCell::new(0));
+        let validator = Counted(invalidated.clone());
+        scrubber.set_validator(Some(Box::new(validator)));
+
+        // Once at the start of each pass
+        scrubber.scrub(1024).unwrap();
+        assert_eq!(*invalidated.borrow(), 0);
+        scrubber.scrub(64).unwrap();
+        assert_eq!(*invalidated.borrow(), 1);
+
+        // And whenever the areas change
+        scrubber.add_area(4096, 5119).unwrap();
+        assert_eq!(*invalidated.borrow(), 2);
+    }
+
+    #[test]
+    fn test_unreadable_warnings() {
+        let clock = VirtualClock::new();
+        let mut scrubber = LineScrubber::new(
+            Recorder::default(),
+            &[(0, 1023), (1024, 2047)],
+            64,
+            2,
+        )
+        .unwrap();
+        scrubber.set_clock(Box::new(clock.clone()));
+        scrubber.set_unreadable_warning_interval(Some(
+            Duration::from_secs(10),
+        ));
+        scrubber.set_validator(Some(Box::new(|addr: usize, _| {
+            !(256..512).contains(&addr)
+        })));
+
+        // The first lines skipped are warned about at once, and those of
+        // the next pass wait for the interval
+        scrubber.scrub(2048).unwrap();
+        scrubber.scrub(2048).unwrap();
+        let area = &scrubber.stats().areas[0];
+        assert_eq!(area.unreadable_lines, 8);
+        assert_eq!(area.unreadable_warnings, 1);
+        assert_eq!(scrubber.stats().areas[1].unreadable_lines, 0);
+        assert_eq!(scrubber.stats().areas[1].unreadable_warnings, 0);
+
+        clock.advance(Duration::from_secs(10));
+        scrubber.scrub(2048).unwrap();
+        assert_eq!(scrubber.stats().areas[0].unreadable_warnings, 2);
+
+        // Without warnings the lines are still counted
+        scrubber.set_unreadable_warning_interval(None);
+        clock.advance(Duration::from_secs(10));
+        scrubber.scrub(2048).unwrap();
+        let area = &scrubber.stats().areas[0];
+        assert_eq!(area.unreadable_lines, 16);
+        assert_eq!(area.unreadable_warnings, 2);
+
+        // Lines held back are warned about at the end of a chunk, though
+        // no more are skipped
+        scrubber.set_unreadable_warning_interval(Some(
+            Duration::from_secs(10),
+        ));
+        scrubber.set_validator(None);
+        scrubber.scrub(64).unwrap();
+        assert_eq!(scrubber.stats().areas[0].unreadable_warnings, 3);
+    }
+
+    #[test]
+    fn test_remove_area_warnings() {
+        let clock = VirtualClock::new();
+        let mut scrubber = LineScrubber::new(
+            Recorder::default(),
+            &[(0, 1023), (1024, 2047), (2048, 3071)],
+            64,
+            2,
+        )
+        .unwrap();
+        scrubber.set_clock(Box::new(clock.clone()));
+        scrubber.set_unreadable_warning_interval(Some(
+            Duration::from_secs(10),
+        ));
+        scrubber.set_validator(Some(Box::new(|addr: usize, _| {
+            !(2304..2560).contains(&addr)
+        })));
+        scrubber.scrub(3072).unwrap();
+        assert_eq!(scrubber.stats().areas[2].unreadable_warnings, 1);
+
+        // The last area moves down and keeps the time of its last warning,
+        // so it isn't warned about again within the interval
+        scrubber.remove_area(1).unwrap();
+        scrubber.scrub(2048).unwrap();
+        let area = &scrubber.stats().areas[1];
+        assert_eq!(area.unreadable_lines, 8);
+        assert_eq!(area.unreadable_warnings, 1);
+    }
+
+    #[test]
+    fn test_declare_excluded() {
+        let mut scrubber = LineScrubber::new(
+            Recorder::default(),
+            &[(0, 1023), (4096, 4351)],
+            64,
+            2,
+        )
+        .unwrap();
+
+        // Lines already excluded, as by a quarantine, are kept apart
+        scrubber.stats_mut().areas[0].excluded_lines = 2;
+
+        // Widened to whole lines and merged with a neighbor
+        scrubber.declare_excluded(200, 300).unwrap();
+        scrubber.declare_excluded(320, 383).unwrap();
+        assert_eq!(scrubber.declared_excluded(), [(192, 383)]);
+        scrubber.declare_excluded(4096, 4351).unwrap();
+        assert_eq!(scrubber.extents(), [(0, 1023), (4096, 4351)]);
+
+        // The excluded lines aren't part of the pass
+        assert_eq!(scrubber.stats().pass_size, 1024 - 192);
+        assert_eq!(scrubber.stats().areas[0].declared_lines, 3);
+        assert_eq!(scrubber.stats().areas[1].declared_lines, 4);
+        assert_eq!(scrubber.stats().areas[0].excluded_lines, 2);
+        assert_eq!(scrubber.stats().areas[0].unscrubbed_lines(), 5);
+        scrubber.scrub(1024 - 192).unwrap();
+        assert_eq!(scrubber.stats().passes, 1);
+        let mut addrs = scrubber.backend().reads();
+        addrs.sort();
+        let expected: Vec<usize> = (0..1024)
+            .step_by(64)
+            .filter(|a| !(192..384).contains(a))
+            .collect();
+        assert_eq!(addrs, expected);
+
+        // Nor are they scrubbed near errors
+        assert_eq!(scrubber.scrub_near(256, 64), Ok(0));
+
+        // Something must be left to scrub
+        assert_eq!(
+            scrubber.declare_excluded(0, 1023),
+            Err(Error::NoMemAreas)
+        );
+        assert_eq!(scrubber.declared_excluded().len(), 2);
+        assert_eq!(scrubber.stats().pass_size, 1024 - 192);
+    }
+
+    #[test]
+    fn test_area_rate() {
+        // Area 1 is a quarter of the pass and is scrubbed three times as
+        // fast as the rest
+        let mut scrubber = LineScrubber::new(
+            Recorder::default(),
+            &[(0, 3071), (4096, 5119)],
+            64,
+            4,
+        )
+        .unwrap();
+        assert_eq!(
+            scrubber.set_area_rate(2, 2),
+            Err(Error::NoSuchArea)
+        );
+        assert_eq!(scrubber.set_area_rate(1, 0), Err(Error::ZeroSize));
+        scrubber.set_area_rate(1, 3).unwrap();
+        assert_eq!(scrubber.area_rate(1), 3);
+
+        scrubber.scrub(4096).unwrap();
+        let reads = scrubber.backend().reads();
+        let area1 = reads.iter().filter(|&&a| a >= 4096).count();
+        assert_eq!(reads.len(), 64 + 32);
+        assert_eq!(area1, 16 * 3);
+        assert_eq!(scrubber.stats().bytes_scrubbed, 4096);
+
+        scrubber.set_area_rate(1, 1).unwrap();
+        assert_eq!(scrubber.area_rate(1), 1);
+        scrubber.scrub(4096).unwrap();
+        assert_eq!(scrubber.backend().reads().len(), 96 + 64);
+
+        // An area with priority one is scrubbed twice as fast, unless its
+        // multiplier is higher
+        scrubber.stats_mut().areas[1].priority = 1;
+        scrubber.backend().clear();
+        scrubber.scrub(4096).unwrap();
+        assert_eq!(scrubber.backend().reads().len(), 64 + 16);
+        assert_eq!(scrubber.area_rate(1), 1);
+        assert_eq!(
+            scrubber.scrub_pass_fast(),
+            Err(Error::FastPathUnavailable)
+        );
+        scrubber.set_area_rate(1, 3).unwrap();
+        scrubber.backend().clear();
+        scrubber.scrub(4096).unwrap();
+        assert_eq!(scrubber.backend().reads().len(), 64 + 32);
+        scrubber.set_area_rate(1, 1).unwrap();
+        scrubber.stats_mut().areas[1].priority = 0;
+        scrubber.backend().clear();
+        assert_eq!(scrubber.scrub_pass_fast(), Ok(4096));
+    }
+
+    #[test]
+    fn test_areas() {
+        let mut scrubber =
+            LineScrubber::new(Recorder::default(), &[(0, 1023)], 64, 4)
+                .unwrap();
+        scrubber.scrub(512).unwrap();
+        assert_eq!(scrubber.add_area(4096, 5119), Ok(1));
+        assert_eq!(
+            scrubber.add_area(8192, 8200),
+            Err(Error::UnalignedEnd)
+        );
+        assert_eq!(scrubber.stats().pass_size, 2048);
+        assert_eq!(scrubber.stats().pass_offset, 0);
+        scrubber.set_area_rate(1, 2).unwrap();
+
+        scrubber.remove_area(0).unwrap();
+        assert_eq!(scrubber.extents(), [(4096, 5119)]);
+        assert_eq!(scrubber.area_rate(0), 2);
+        assert_eq!(scrubber.remove_area(1), Err(Error::NoSuchArea));
+        assert_eq!(scrubber.remove_area(0), Err(Error::NoMemAreas));
+
+        scrubber.backend().clear();
+        scrubber.scrub(1024).unwrap();
+        assert!(scrubber.backend().reads().iter().all(|&a| a >= 4096));
+        assert_eq!(scrubber.stats().passes, 1);
+
+        // A resized area keeps its index and settings
+        scrubber.resize_area(0, 4607).unwrap();
+        assert_eq!(scrubber.extents(), [(4096, 4607)]);
+        assert_eq!(scrubber.stats().areas[0].size, 512);
+        assert_eq!(scrubber.stats().pass_size, 512);
+        assert_eq!(scrubber.area_rate(0), 2);
+        assert_eq!(
+            scrubber.resize_area(0, 4000),
+            Err(Error::EmptyMemArea)
+        );
+        assert_eq!(
+            scrubber.resize_area(0, 4600),
+            Err(Error::UnalignedEnd)
+        );
+        assert_eq!(
+            scrubber.resize_area(1, 4607),
+            Err(Error::NoSuchArea)
+        );
+        assert_eq!(scrubber.extents(), [(4096, 4607)]);
+    }
+
+    #[test]
+    fn test_scrub_until() {
+        let mut scrubber =
+            LineScrubber::new(Recorder::default(), &[(0, 2047)], 64, 4)
+                .unwrap();
+        let past = Instant::now();
+        assert_eq!(scrubber.scrub_until(past), Ok(0));
+        assert_eq!(scrubber.stats().chunks, 0);
+
+        let bytes = scrubber.scrub_for(Duration::from_millis(2)).unwrap();
+        assert!(bytes > 0 && bytes.is_multiple_of(64));
+        assert_eq!(scrubber.backend().reads().len(), bytes / 64);
+        assert_eq!(scrubber.stats().bytes_scrubbed, bytes as u64);
+    }
+
+    // A backend taking a millisecond of virtual time to read each line
+    struct Ticker(VirtualClock);
+
+    impl ScrubBackend for Ticker {
+        fn read_line(&mut self, _addr: usize) -> Result<(), Error> {
+            self.0.advance(Duration::from_millis(1));
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_virtual_clock() {
+        let clock = VirtualClock::new();
+        let mut scrubber =
+            LineScrubber::new(Ticker(clock.clone()), &[(0, 2047)], 64, 4)
+                .unwrap();
+        scrubber.set_clock(Box::new(clock.clone()));
+
+        assert_eq!(scrubber.scrub_for(Duration::from_millis(10)), Ok(640));
+        assert_eq!(scrubber.staleness(0), None);
+        scrubber.scrub(2048 - 640).unwrap();
+        assert_eq!(
+            scrubber
+                .stats()
+                .last_pass
+                .as_ref()
+                .map(|p| p.completed - p.started),
+            Some(Duration::from_millis(32))
+        );
+
+        // The chunks took 10 and 22 ms, all of it in their batches
+        let stats = scrubber.stats();
+        assert_eq!(stats.chunk_latency.count(), 2);
+        assert_eq!(
+            stats.chunk_latency.min(),
+            Some(Duration::from_millis(10))
+        );
+        assert_eq!(stats.batch_latency, stats.chunk_latency);
+        clock.advance(Duration::from_secs(3600));
+        assert_eq!(scrubber.staleness(0), Some(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn test_scrub_fraction() {
+        // 32 lines of 64 bytes
+        let mut scrubber =
+            LineScrubber::new(Recorder::default(), &[(0, 2047)], 64, 4)
+                .unwrap();
+        assert_eq!(scrubber.scrub_fraction(0.25), Ok(512));
+        assert_eq!(scrubber.scrub_fraction(0.01), Ok(0));
+        assert_eq!(scrubber.scrub_fraction(0.02), Ok(64));
+        assert_eq!(scrubber.backend().reads().len(), 9);
+        assert_eq!(scrubber.scrub_fraction(1.5), Ok(3072));
+        assert_eq!(scrubber.stats().passes, 1);
+
+        assert_eq!(scrubber.stats().bytes_scrubbed, 576 + 3072);
+
+        // Rounded up to the end of the pass, the next chunk boundary
+        let mut scrubber =
+            LineScrubber::new(Recorder::default(), &[(0, 2047)], 64, 0)
+                .unwrap();
+        scrubber.scrub(1600).unwrap();
+        scrubber.set_chunk_alignment(512).unwrap();
+        assert_eq!(scrubber.scrub_fraction(0.02), Ok(448));
+        assert_eq!(scrubber.stats().pass_offset, 0);
+        assert_eq!(
+            scrubber.scrub_fraction(-0.1),
+            Err(Error::UnalignedValue)
+        );
+        assert_eq!(
+            scrubber.scrub_fraction(f64::NAN),
+            Err(Error::UnalignedValue)
+        );
+    }
+
+    #[test]
+    fn test_coverage() {
+        // 32 lines of 64 bytes, one of them declared excluded
+        let mut scrubber =
+            LineScrubber::new(Recorder::default(), &[(0, 2047)], 64, 4)
+                .unwrap();
+        scrubber.declare_excluded(0x400, 0x43f).unwrap();
+        scrubber.set_coverage_tracking(2).unwrap();
+        scrubber.exclude(0x100, 0x13f);
+        scrubber.set_validator(Some(Box::new(|addr, _| addr != 0x200)));
+        scrubber.scrub(31 * 64).unwrap();
+
+        let history = scrubber.coverage_history();
+        assert_eq!(history.len(), 1);
+        assert!(history[0].complete);
+        assert_eq!(
+            history[0].gaps(),
+            [
+                PassGap {
+                    start: 0x100,
+                    end: 0x13f,
+                    reason: SkipReason::Excluded
+                },
+                PassGap {
+                    start: 0x200,
+                    end: 0x23f,
+                    reason: SkipReason::Unreadable
+                },
+                PassGap {
+                    start: 0x400,
+                    end: 0x43f,
+                    reason: SkipReason::Declared
+                },
+            ]
+        );
+        assert!(scrubber.coverage_diff().is_none());
+
+        // The next pass reads the line that was unreadable
+        scrubber.set_validator(None);
+        scrubber.scrub(31 * 64).unwrap();
+        let diff = scrubber.coverage_diff().unwrap();
+        assert_eq!((diff.earlier, diff.later), (1, 2));
+        assert!(diff.lost.is_empty());
+        assert_eq!(diff.regained.len(), 1);
+        assert_eq!(diff.regained[0].reason, SkipReason::Unreadable);
+
+        // Part way through, the rest of the pass is unfinished
+        scrubber.scrub(16 * 64).unwrap();
+        let coverage = scrubber.pass_coverage().unwrap().unwrap();
+        assert_eq!(coverage.bytes(Some(SkipReason::Unfinished)), 15 * 64);
+        assert!(!coverage.complete);
+
+        // Restarting the pass keeps it in the history, unfinished
+        scrubber.declare_excluded(0x7c0, 0x7ff).unwrap();
+        let history = scrubber.coverage_history();
+        assert_eq!(history.len(), 2);
+        assert!(!history[1].complete);
+        assert_eq!(history[1], coverage);
+        let diff = scrubber.coverage_diff().unwrap();
+        assert!(diff
+            .lost
+            .iter()
+            .all(|g| g.reason == SkipReason::Unfinished));
+        let lost: usize = diff.lost.iter().map(|g| g.bytes()).sum();
+        assert_eq!(lost, 15 * 64);
+
+        scrubber.set_coverage_tracking(0).unwrap();
+        assert!(scrubber.pass_coverage().unwrap().is_none());
+        assert!(scrubber.coverage_history().is_empty());
+    }
+
+    #[test]
+    fn test_own_state() {
+        // An area of 64 lines around the scrubber itself
+        let mut scrubber = Box::new(
+            LineScrubber::new(Recorder::default(), &[(0, 4095)], 64, 4)
+                .unwrap(),
+        );
+        let addr = &*scrubber as *const _ as usize;
+        let start = (addr & !63) - 2048;
+        scrubber.set_extents(vec![(start, start + 4095)]).unwrap();
+        scrubber.set_coverage_tracking(1).unwrap();
+
+        // The heap buffers of policies are part of the state
+        struct Held(Vec<u64>);
+        impl Policy for Held {
+            fn event(
+                &mut self,
+                _event: &ScrubEvent,
+                _now: Instant,
+            ) -> Vec<PolicyAction> {
+                Vec::new()
+            }
+
+            fn buffers(&self) -> Vec<(usize, usize)> {
+                vec![vec_buffer(&self.0)]
+            }
+        }
+        let held = vec![0; 32];
+        let held_addr = held.as_ptr() as usize;
+        scrubber.add_policy(Box::new(Held(held)));
+
+        scrubber.set_exclude_own_state(true);
+        let within = |own: &[(usize, usize)], a: usize| {
+            own.iter().any(|&(s, e)| s <= a && e >= a)
+        };
+        assert!(within(scrubber.own_state(), addr));
+        assert!(within(scrubber.own_state(), held_addr));
+        assert!(within(scrubber.own_state(), held_addr + 255));
+
+        // None of the lines holding the scrubber's state are read, on the
+        // fast path or otherwise
+        let own = scrubber.own_state().to_vec();
+        scrubber.scrub_pass_fast().unwrap();
+        let reads = scrubber.backend().reads();
+        assert!(reads.iter().all(|&a| !within(&own, a)));
+        scrubber.backend().clear();
+        scrubber.scrub(4096).unwrap();
+        let own = scrubber.own_state().to_vec();
+        let reads = scrubber.backend().reads();
+        assert!(reads.iter().all(|&a| !within(&own, a)));
+        for coverage in scrubber.coverage_history() {
+            assert_eq!(
+                coverage.reason_at(addr),
+                Some(SkipReason::OwnState)
+            );
+            assert!(coverage.bytes(Some(SkipReason::OwnState)) >= 64);
+        }
+
+        scrubber.set_exclude_own_state(false);
+        assert!(scrubber.own_state().is_empty());
+    }
+
+    #[test]
+    fn test_touch_sampling() {
+        let mut scrubber = LineScrubber::new(
+            Recorder::default(),
+            &[(0, 4095), (8192, 12287)],
+            64,
+            4,
+        )
+        .unwrap();
+        scrubber.set_sub_passes(4).unwrap();
+        scrubber.exclude(0x100, 0x1ff);
+        scrubber.set_touch_sampling(Some(
+            TouchSampler::with_seed(32, 7).unwrap(),
+        ));
+        assert_eq!(
+            scrubber.scrub_pass_fast(),
+            Err(Error::FastPathUnavailable)
+        );
+
+        // Every sampled line, excluded or not, is touched once a pass
+        scrubber.scrub(3 * 8192).unwrap();
+        let sampler = scrubber.touch_sampler().unwrap();
+        let totals = sampler.totals();
+        assert_eq!(totals.passes, 3);
+        assert_eq!((totals.missed, totals.repeated), (0, 0));
+        assert!(totals.sampled > 0);
+        assert_eq!(sampler.last().unwrap().epoch, 3);
+        assert!(sampler.last().unwrap().is_clean());
+
+        // Sampling turned on part way through a pass waits for the next
+        scrubber.scrub(1024).unwrap();
+        scrubber.set_touch_sampling(Some(
+            TouchSampler::with_seed(32, 7).unwrap(),
+        ));
+        scrubber.scrub(8192 - 1024).unwrap();
+        assert_eq!(scrubber.touch_sampler().unwrap().totals().passes, 0);
+        scrubber.scrub(8192).unwrap();
+        let totals = scrubber.touch_sampler().unwrap().totals();
+        assert_eq!((totals.passes, totals.missed), (1, 0));
+    }
+
+    #[test]
+    fn test_from_areas() {
+        let base = |addr| std::ptr::NonNull::new(addr as *mut u8).unwrap();
+        let areas = [
+            VirtScrubArea::from_extent(base(0x1000), 0x1fff, 64).unwrap(),
+            VirtScrubArea::from_extent(base(0x4000), 0x43ff, 64).unwrap(),
+        ];
+        let scrubber =
+            LineScrubber::from_areas(Recorder::default(), &areas, 4)
+                .unwrap();
+        assert_eq!(
+            scrubber.extents(),
+            [(0x1000, 0x1fff), (0x4000, 0x43ff)]
+        );
+
+        let mixed = [
+            areas[0],
+            VirtScrubArea::from_extent(base(0x4000), 0x43ff, 128).unwrap(),
+        ];
+        assert!(matches!(
+            LineScrubber::from_areas(Recorder::default(), &mixed, 4),
+            Err(Error::MixedLineSizes)
+        ));
+        assert!(matches!(
+            LineScrubber::from_areas(Recorder::default(), &[], 4),
+            Err(Error::NoMemAreas)
+        ));
+    }
+
+    #[test]
+    fn test_chunk_alignment() {
+        // 64 lines read in address order, in chunks ending on 1024 bytes
+        let mut scrubber =
+            LineScrubber::new(Recorder::default(), &[(0, 4095)], 64, 0)
+                .unwrap();
+        assert_eq!(scrubber.set_chunk_alignment(0), Err(Error::ZeroSize));
+        assert_eq!(
+            scrubber.set_chunk_alignment(1000),
+            Err(Error::UnalignedValue)
+        );
+        scrubber.set_chunk_alignment(1024).unwrap();
+        assert_eq!(scrubber.chunk_alignment(), 1024);
+
+        scrubber.scrub(64).unwrap();
+        assert_eq!(scrubber.stats().pass_offset, 1024);
+        let read: Vec<usize> = (0..1024).step_by(64).collect();
+        assert_eq!(scrubber.backend().reads(), read);
+        scrubber.scrub(1024).unwrap();
+        assert_eq!(scrubber.stats().pass_offset, 2048);
+        scrubber.scrub(2048 + 64).unwrap();
+        assert_eq!(scrubber.stats().passes, 1);
+        assert_eq!(scrubber.stats().pass_offset, 1024);
+
+        // The pass end is a boundary even if not on the alignment
+        scrubber.set_chunk_alignment(3072).unwrap();
+        scrubber.scrub(1024).unwrap();
+        assert_eq!(scrubber.stats().pass_offset, 3072);
+        scrubber.scrub(64).unwrap();
+        assert_eq!(scrubber.stats().passes, 2);
+        assert_eq!(scrubber.stats().pass_offset, 0);
+
+        // Chunks end on addresses on the alignment, not on offsets into
+        // the pass
+        let mut scrubber = LineScrubber::new(
+            Recorder::default(),
+            &[(0x100, 0x10ff)],
+            64,
+            0,
+        )
+        .unwrap();
+        scrubber.set_chunk_alignment(1024).unwrap();
+        scrubber.scrub(64).unwrap();
+        assert_eq!(scrubber.stats().pass_offset, 0x300);
+        assert_eq!(scrubber.backend().reads().last(), Some(&0x3c0));
+        assert_eq!(scrubber.scrub_fraction(0.25), Ok(1024));
+
+        // Lines read in cache order can't be aligned
+        let mut scrubber =
+            LineScrubber::new(Recorder::default(), &[(0, 4095)], 64, 2)
+                .unwrap();
+        assert_eq!(
+            scrubber.set_chunk_alignment(1024),
+            Err(Error::NotAddressOrder)
+        );
+        scrubber.set_chunk_alignment(64).unwrap();
+
+        // A deadline is overrun to reach the next boundary, but by no
+        // more than the time before it
+        let clock = VirtualClock::new();
+        let mut scrubber =
+            LineScrubber::new(Ticker(clock.clone()), &[(0, 4095)], 64, 0)
+                .unwrap();
+        scrubber.set_clock(Box::new(clock.clone()));
+        scrubber.set_chunk_alignment(256).unwrap();
+        assert_eq!(scrubber.scrub_for(Duration::from_millis(3)), Ok(256));
+        scrubber.set_chunk_alignment(1024).unwrap();
+        assert_eq!(scrubber.scrub_for(Duration::from_millis(3)), Ok(384));
+    }
+
+    #[test]
+    fn test_windows() {
+        // Records each read, and each window boundary as usize::MAX
+        struct Windows(Vec<usize>);
+        impl ScrubBackend for Windows {
+            fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+                self.0.push(addr);
+                Ok(())
+            }
+            fn end_window(&mut self) -> Result<(), Error> {
+                self.0.push(usize::MAX);
+                Ok(())
+            }
+        }
+
+        // 32 lines of 64 bytes with four cache indices, in areas out of
+        // address order and with no lines in the second window
+        let mut scrubber = LineScrubber::new(
+            Windows(Vec::new()),
+            &[(0x800, 0xbff), (0, 0x3ff)],
+            64,
+            2,
+        )
+        .unwrap();
+        assert_eq!(
+            scrubber.set_window_size(Some(128)),
+            Err(Error::UnalignedValue)
+        );
+        scrubber.set_window_size(Some(1024)).unwrap();
+        scrubber.set_coverage_tracking(1).unwrap();
+
+        // The first window is read in the cache aware order before the
+        // pass moves on
+        scrubber.scrub(512).unwrap();
+        assert_eq!(
+            scrubber.backend().0,
+            [0, 0x100, 0x200, 0x300, 0x40, 0x140, 0x240, 0x340]
+        );
+        let coverage = scrubber.pass_coverage().unwrap().unwrap();
+        assert_eq!(coverage.bytes(Some(SkipReason::Unfinished)), 1536);
+        scrubber.scrub(576).unwrap();
+        assert!(scrubber.backend().0[..16].iter().all(|&a| a < 0x400));
+        assert_eq!(scrubber.backend().0[16..], [usize::MAX, 0x800]);
+
+        // With sub-passes each window is split into them
+        scrubber.set_sub_passes(2).unwrap();
+        scrubber.backend_mut().0.clear();
+        scrubber.scrub(2048).unwrap();
+        let reads = &scrubber.backend().0;
+        assert_eq!(reads[..4], [0, 0x100, 0x200, 0x300]);
+        assert_eq!(reads[4..8], [0x80, 0x180, 0x280, 0x380]);
+        assert_eq!(reads[16], usize::MAX);
+        assert!(reads[17..].iter().all(|&a| a >= 0x800));
+        assert_eq!(scrubber.coverage_history().len(), 1);
+    }
+}
@@ -0,0 +1,269 @@
+// Lists of damaged memory that persist across reboots. Reading a poisoned
+// line raises a machine check, so memory known to be bad must be kept out
+// of scrubbing from the start rather than rediscovered. A BadBlockList can
+// be imported from the pmem badblocks file, from manufacturing data, or
+// from a list saved by a previous run, and is applied by retiring its lines
+// in a Quarantine or by excluding it in a CoverageDesc. At shutdown, the
+// lines a Quarantine has retired are exported so the next run starts with
+// them.
+//
+// The saved list has one range per line:
+//
+//  <start> <end>
+//
+// where the addresses are inclusive and in hex with a leading 0x. Blank
+// lines and lines starting with # are ignored.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use num_traits::{One, SaturatingAdd};
+
+use crate::addr::*;
+use crate::planner::*;
+use crate::quarantine::*;
+
+/// Number of bytes in a sector of the pmem badblocks file
+pub const BADBLOCK_SECTOR_SIZE: usize = 512;
+
+/// Ranges of bad memory, sorted and with overlapping and adjacent ranges
+/// merged
+///
+/// * `ranges` - (start, end) of each range, end inclusive
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BadBlockList {
+    ranges: Vec<(usize, usize)>,
+}
+
+// Error for a bad line in a list
+fn invalid(line: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: bad range", line + 1),
+    )
+}
+
+impl BadBlockList {
+    /// Create an empty list
+    pub fn new() -> BadBlockList {
+        BadBlockList::default()
+    }
+
+    /// Add a range of bad memory
+    ///
+    /// # Arguments:
+    /// * `start` - First bad address
+    ///
+    /// * `end` - Last bad address
+    pub fn add(&mut self, start: usize, end: usize) {
+        add_range(&mut self.ranges, (start.min(end), start.max(end)));
+    }
+
+    /// Returns the ranges, sorted by address
+    pub fn ranges(&self) -> &[(usize, usize)] {
+        &self.ranges
+    }
+
+    /// Returns whether an address is in the list
+    pub fn contains(&self, addr: usize) -> bool {
+        let i = self.ranges.partition_point(|&(_, e)| e < addr);
+        self.ranges.get(i).is_some_and(|&(s, _)| s <= addr)
+    }
+
+    /// Parse a list in the format described above
+    pub fn parse(text: &str) -> io::Result<BadBlockList> {
+        let mut list = BadBlockList::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut words =
+                line.split_whitespace().map(|w| parse_number(w, 10));
+            match (words.next(), words.next(), words.next()) {
+                (Some(Some(start)), Some(Some(end)), None) => {
+                    list.add(start, end)
+                }
+                _ => return Err(invalid(n)),
+            }
+        }
+        Ok(list)
+    }
+
+    /// Parse a pmem badblocks file, which lists the first sector and the
+    /// number of sectors of each bad range, relative to the start of the
+    /// device
+    ///
+    /// # Arguments:
+    /// * `text` - Contents of the badblocks file
+    ///
+    /// * `base` - Address at which the device is mapped
+    pub fn parse_pmem(
+        text: &str,
+        base: usize,
+    ) -> io::Result<BadBlockList> {
+        let mut list = BadBlockList::new();
+        for (n, line) in text.lines().enumerate() {
+            let mut words = line.split_whitespace();
+            let (sector, count) = match (words.next(), words.next()) {
+                (None, _) => continue,
+                (Some(sector), Some(count)) => (sector, count),
+                _ => return Err(invalid(n)),
+            };
+            let range = sector
+                .parse::<usize>()
+                .ok()
+                .zip(count.parse::<usize>().ok())
+                .filter(|&(_, count)| count != 0)
+                .and_then(|(sector, count)| {
+                    let start = sector
+                        .checked_mul(BADBLOCK_SECTOR_SIZE)?
+                        .checked_add(base)?;
+                    let len = count.checked_mul(BADBLOCK_SECTOR_SIZE)?;
+                    Some((start, start.checked_add(len - 1)?))
+                })
+                .ok_or_else(|| invalid(n))?;
+            list.add(range.0, range.1);
+        }
+        Ok(list)
+    }
+
+    /// Read a list in the format described above
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<BadBlockList> {
+        BadBlockList::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Read a pmem badblocks file, as for parse_pmem()
+    pub fn load_pmem<P: AsRef<Path>>(
+        path: P,
+        base: usize,
+    ) -> io::Result<BadBlockList> {
+        BadBlockList::parse_pmem(&fs::read_to_string(path)?, base)
+    }
+
+    /// Returns the list in the format described above
+    pub fn to_text(&self) -> String {
+        self.ranges
+            .iter()
+            .map(|(s, e)| format!("{:#x} {:#x}\n", s, e))
+            .collect()
+    }
+
+    /// Write the list in the format described above
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, self.to_text())
+    }
+
+    /// Exclude the bad memory from software scrubbing
+    pub fn exclude_from(&self, desc: &mut CoverageDesc) {
+        desc.excluded.extend_from_slice(&self.ranges);
+    }
+
+    /// Retire every line holding bad memory, so that it is skipped by a
+    /// QuarantineBackend
+    pub fn retire_in(&self, quarantine: &mut Quarantine) {
+        for &(start, end) in &self.ranges {
+            quarantine.retire_range(start, end);
+        }
+    }
+
+    /// Create a list of the lines a Quarantine has retired
+    pub fn from_quarantine(quarantine: &Quarantine) -> BadBlockList {
+        let mut list = BadBlockList::new();
+        for &line in quarantine.retired() {
+            list.add(line, line + (quarantine.line_size() - 1));
+        }
+        list
+    }
+}
+
+// Add a range to a sorted list of ranges, merging it with those it
+// overlaps or touches
+pub(crate) fn add_range<T>(ranges: &mut Vec<(T, T)>, range: (T, T))
+where
+    T: Copy + Ord + One + SaturatingAdd,
+{
+    let (mut start, mut end) = range;
+    let i = ranges
+        .partition_point(|&(_, e)| e.saturating_add(&T::one()) < start);
+    let mut j = i;
+    while j < ranges.len() && ranges[j].0 <= end.saturating_add(&T::one())
+    {
+        start = start.min(ranges[j].0);
+        end = end.max(ranges[j].1);
+        j += 1;
+    }
+    ranges.splice(i..j, [(start, end)]);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Instant;
+
+    use crate::event::*;
+
+    #[test]
+    fn test_add_range() {
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        add_range(&mut ranges, (10, 19));
+        add_range(&mut ranges, (30, 39));
+        add_range(&mut ranges, (20, 24));
+        assert_eq!(ranges, [(10, 24), (30, 39)]);
+        add_range(&mut ranges, (0, 4));
+        add_range(&mut ranges, (22, 35));
+        assert_eq!(ranges, [(0, 4), (10, 39)]);
+        add_range(&mut ranges, (usize::MAX - 1, usize::MAX));
+        add_range(&mut ranges, (40, 40));
+        assert_eq!(
+            ranges,
+            [(0, 4), (10, 40), (usize::MAX - 1, usize::MAX)]
+        );
+    }
+
+    #[test]
+    fn test_list() {
+        let mut list = BadBlockList::new();
+        list.add(0x2000, 0x2fff);
+        list.add(0x1000, 0x10ff);
+        list.add(0x1100, 0x11ff);
+        list.add(0x2800, 0x3fff);
+        assert_eq!(list.ranges(), [(0x1000, 0x11ff), (0x2000, 0x3fff)]);
+        assert!(list.contains(0x3fff));
+        assert!(!list.contains(0x1200));
+
+        let text = list.to_text();
+        assert_eq!(text, "0x1000 0x11ff\n0x2000 0x3fff\n");
+        assert_eq!(BadBlockList::parse(&text).unwrap(), list);
+        assert!(BadBlockList::parse("0x1000\n").is_err());
+
+        let pmem =
+            BadBlockList::parse_pmem("8 2\n24 1\n", 0x100000).unwrap();
+        assert_eq!(
+            pmem.ranges(),
+            [(0x101000, 0x1013ff), (0x103000, 0x1031ff)]
+        );
+        assert!(BadBlockList::parse_pmem("8 0\n", 0).is_err());
+    }
+
+    #[test]
+    fn test_quarantine() {
+        let mut list = BadBlockList::new();
+        list.add(0x1010, 0x1050);
+
+        let mut q =
+            Quarantine::new(QuarantinePolicy::default(), 64).unwrap();
+        list.retire_in(&mut q);
+        assert!(q.is_skipped(0x1000) && q.is_skipped(0x1040));
+        assert!(!q.is_skipped(0x1080));
+
+        q.record_error(0x8000, ErrorSeverity::Uncorrected, Instant::now());
+        let saved = BadBlockList::from_quarantine(&q);
+        assert_eq!(saved.ranges(), [(0x1000, 0x107f), (0x8000, 0x803f)]);
+
+        let mut desc = CoverageDesc::default();
+        saved.exclude_from(&mut desc);
+        assert_eq!(desc.excluded, saved.ranges());
+    }
+}
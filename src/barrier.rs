@@ -0,0 +1,171 @@
+// Synchronization around batches of reads. On some platforms an error
+// found by a read is only reported once the read has completed at the
+// memory controller, so a barrier such as dsb or mfence, or a poll of a
+// controller register, is needed after a burst of reads before the
+// scrubber moves on. BarrierBackend wraps another backend and runs hooks
+// before and after each batch of reads, which a LineScrubber makes once
+// per chunk.
+
+use crate::arch::*;
+use crate::backend::*;
+use crate::base::*;
+use crate::coverage::*;
+
+/// A hook run before or after a batch of reads
+pub type BatchHook = Box<dyn FnMut() -> Result<(), Error>>;
+
+/// A backend running hooks around each batch of reads made through
+/// another backend
+///
+/// * `backend` - Reads each cache line
+///
+/// * `before` - Run before each batch, if set
+///
+/// * `after` - Run after each batch, if set
+pub struct BarrierBackend<B: ScrubBackend> {
+    backend: B,
+    before: Option<BatchHook>,
+    after: Option<BatchHook>,
+}
+
+impl<B: ScrubBackend> BarrierBackend<B> {
+    /// Create a BarrierBackend with no hooks
+    pub fn new(backend: B) -> BarrierBackend<B> {
+        BarrierBackend {
+            backend,
+            before: None,
+            after: None,
+        }
+    }
+
+    /// Create a BarrierBackend that runs memory_barrier() after each batch
+    pub fn with_barrier(backend: B) -> BarrierBackend<B> {
+        let mut barrier = BarrierBackend::new(backend);
+        barrier.set_after(Some(Box::new(|| {
+            memory_barrier();
+            Ok(())
+        })));
+        barrier
+    }
+
+    /// Set the hook run before each batch of reads
+    pub fn set_before(&mut self, hook: Option<BatchHook>) {
+        self.before = hook;
+    }
+
+    /// Set the hook run after each batch of reads, such as a barrier or a
+    /// poll of the memory controller's error status
+    pub fn set_after(&mut self, hook: Option<BatchHook>) {
+        self.after = hook;
+    }
+
+    /// Returns the backend reading each cache line
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns the backend reading each cache line, for changing it
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+}
+
+impl<B: ScrubBackend> ScrubBackend for BarrierBackend<B> {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        self.backend.read_line(addr)
+    }
+
+    fn read_words(
+        &mut self,
+        addr: usize,
+        line_size: usize,
+        reads: usize,
+    ) -> Result<(), Error> {
+        self.backend.read_words(addr, line_size, reads)
+    }
+
+    fn begin_batch(&mut self) -> Result<(), Error> {
+        if let Some(hook) = self.before.as_mut() {
+            hook()?;
+        }
+        self.backend.begin_batch()
+    }
+
+    fn end_batch(&mut self) -> Result<(), Error> {
+        let result = self.backend.end_batch();
+        if let Some(hook) = self.after.as_mut() {
+            hook()?;
+        }
+        result
+    }
+
+    fn end_window(&mut self) -> Result<(), Error> {
+        self.backend.end_window()
+    }
+
+    fn window_size(&self) -> Option<usize> {
+        self.backend.window_size()
+    }
+
+    fn flush_line(&mut self, addr: usize) -> Result<(), Error> {
+        self.backend.flush_line(addr)
+    }
+
+    fn skip_reason(&mut self, addr: usize) -> Option<SkipReason> {
+        self.backend.skip_reason(addr)
+    }
+
+    fn excluded_lines(&self, start: usize, end: usize) -> Option<u64> {
+        self.backend.excluded_lines(start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Records reads and batch hooks, in order
+    #[test]
+    fn test_hooks() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut backend = BarrierBackend::new(Logger("read", log.clone()));
+        let before = log.clone();
+        backend.set_before(Some(Box::new(move || {
+            before.borrow_mut().push("before".to_string());
+            Ok(())
+        })));
+        let after = log.clone();
+        backend.set_after(Some(Box::new(move || {
+            after.borrow_mut().push("after".to_string());
+            Ok(())
+        })));
+
+        let mut scrubber =
+            LineScrubber::new(backend, &[(0, 255)], 64, 1).unwrap();
+        scrubber.scrub(128).unwrap();
+        assert_eq!(
+            *log.borrow(),
+            ["before", "read 0x0", "read 0x80", "after"]
+        );
+
+        // A failing hook fails the chunk
+        scrubber
+            .backend_mut()
+            .set_after(Some(Box::new(|| Err(Error::InternalError))));
+        assert_eq!(scrubber.scrub(64), Err(Error::InternalError));
+    }
+
+    #[test]
+    fn test_barrier() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut backend =
+            BarrierBackend::with_barrier(Logger("read", log));
+        backend.begin_batch().unwrap();
+        backend.read_line(0x40).unwrap();
+        backend.end_batch().unwrap();
+        assert_eq!(*backend.backend().1.borrow(), ["read 0x40"]);
+    }
+}
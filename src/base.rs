@@ -7,6 +7,8 @@ use core::mem;
 use core::ptr;
 //use num_traits::{PrimInt, Unsigned};
 use num_traits::PrimInt;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 use std::convert::From;
 use std::iter;
 use std::marker::PhantomData;
@@ -22,7 +24,9 @@ use crate::addr::{Addr, AddrImplTrait};
 use crate::data::DataImplTrait;
 */
 use crate::addr::*;
+use crate::checkpoint::ScrubCursor;
 use crate::data::*;
+use crate::stats::ScrubStats;
 
 // Error definitions. Because this is core software, it returns errors instread
 // of panicing wherever possible. These are the errors it can return.
@@ -39,6 +43,23 @@ pub enum Error {
     EmptyMemArea,
     ZeroSize,
     IteratorFailed,
+    CheckpointMismatch,
+    AddressOverflow,
+    GuardHit,
+    Untranslated,
+    TooManyAreas,
+    Overlap,
+    FastPathUnavailable,
+    InvertedRange,
+    IoFailed,
+    MixedLineSizes,
+    NoSuchArea,
+    MapFailed,
+    NoSuchGroup,
+    DuplicateGroup,
+    NotAddressOrder,
+    TooManySelectors,
+    SendFailed,
 }
 
 impl fmt::Display for Error {
@@ -75,6 +96,17 @@ pub trait AutoScrubDesc<
     A: AddrImplTrait<A>,
 {
     fn next(&mut self) -> Addr<A>;
+
+    /// Returns the number of bytes to scrub next, or zero to stop, given
+    /// the statistics so far. This allows the choice to adapt to the
+    /// throughput, progress through the pass, and errors seen. By default,
+    /// the statistics are ignored and next() is used.
+    ///
+    /// # Arguments:
+    /// * `stats` - Statistics for the scrubbing done so far
+    fn next_with(&mut self, _stats: &ScrubStats) -> Addr<A> {
+        self.next()
+    }
 }
 
 // This is for a generic cache. There is a two-tier implementation where
@@ -111,6 +143,15 @@ pub trait MemoryScrubberBase<
     /// Return a references to an array of MemAreas.
     fn scrub_areas(&self) -> &[MemArea<A>];
 
+    /// Returns the position at which the next scrub() starts. Scrubbers
+    /// that don't keep one always start at the beginning of the pass.
+    fn cursor(&self) -> ScrubCursor {
+        ScrubCursor::default()
+    }
+
+    /// Set the position at which the next scrub() starts
+    fn set_cursor(&self, _cursor: ScrubCursor) {}
+
     /// Check that the parameters for the cache make sense
     ///
     /// # Arguments:
@@ -157,8 +198,8 @@ pub trait MemoryScrubberBase<
     }
 
     /// This is the core of the scrubbing work. We scrub the given number
-    /// of bytes out of the total scrubbing areas supplied, starting after
-    /// the previous location.
+    /// of bytes out of the total scrubbing areas supplied, starting at the
+    /// cursor left by the previous scrub and moving it on.
     ///
     /// # Arguments:
     ///
@@ -181,18 +222,26 @@ pub trait MemoryScrubberBase<
             return Err(Error::UnalignedSize);
         }
 
+        // Start after the lines already scrubbed in this pass
+        let extents: Vec<(usize, usize)> = self
+            .scrub_areas()
+            .iter()
+            .map(|a| (usize::from(a.s.0), usize::from(a.e.0)))
+            .collect();
+        let cursor = self.cursor();
+        let skip = cursor.pass_offset(&extents) / cacheline_size;
+
         let n_scrublines = n >> Addr::<A>(cacheline_width.into());
-// FIXME: Add ? operator
         let iterator =
-            ScrubCountIterator::new(self.cache(), self.scrub_areas(), n_scrublines);
+            ScrubCountIterator::new(self.cache(), self.scrub_areas(), skip,
+                n_scrublines);
 
         // At this point, it's pretty much Iterators all the way down.
-        for p in iterator {
-            // FIXME: implement read_cacheline(p);
-            eprintln!("Implemented read_cacheline()");
-            //            Cacheline::<S, D>::read_cacheline(p);
+        for p in iterator? {
+            self.cache().read_cacheline(p);
         }
 
+        self.set_cursor(cursor.advance(&extents, usize::from(n.0)));
         Ok(())
     }
 }
@@ -213,6 +262,12 @@ where
     A: AddrImplTrait<A>,
 {
     fn new(cache: &dyn CacheBase<N, W, S, D, A>, scrub_areas: &[MemArea<A>], n: Addr<A>) -> Self;
+
+    // Move past a number of cache lines without returning them, as when
+    // resuming a pass part way through. The position is to be computed
+    // directly, not by stepping through the lines, since a resumed pass
+    // may skip most of memory.
+    fn seek(&mut self, lines: usize);
 }
 
 // Iterator for a given number of cache lines
@@ -275,9 +330,14 @@ where
     fn new(
         cache: &'a dyn CacheBase<N, W, S, D, A>,
         scrub_areas: &'a [MemArea<A>],
+        skip: usize,
         n_scrublines: Addr<A>,
     ) -> Result<ScrubCountIterator<'a, N, W, S, D, A, I>, Error> {
-        let iterator = I::new(cache, scrub_areas, n_scrublines)?;
+        let mut iterator = I::new(cache, scrub_areas, n_scrublines)?;
+
+        // Move past the lines to skip without counting them against the
+        // number to be returned
+        iterator.seek(skip);
 
         Ok(ScrubCountIterator {
             cache: cache,
@@ -370,24 +430,6 @@ where
         let start = scrub_area.start();
         let start_in_cachelines = start >> cacheline_width;
 
-        //let x = Addr(32);
-
-        //let x = Addr<u128>(32);
-
-        //let x: Addr::<u128>;
-        //let x = Addr::<u128>::(32);
-        let x = Addr::<u128>(32);
-        let y = Addr::<u128>(4);
-        //let y = Addr::<u128>::new(4);
-        println!("x({}) + y({}) = {}", x, y, x + y);
-        println!("x({}) >> y({}) = {}", x, y, x >> y);
-        println!(
-            "x({}) >> y({}) = {}",
-            start,
-            cacheline_width,
-            start >> cacheline_width
-        );
-
         // This will truncate the number of cache lines by one
         let end = scrub_area.end();
         let end_in_cachelines = end >> cacheline_width;
@@ -401,8 +443,7 @@ where
         Self: Sized,
     {
         let ptr: *mut D = p.into();
-        let a_ref: &[D; S] = unsafe { &*(ptr as *const _) };
-        unsafe { ptr::read(&a_ref[0]) }
+        unsafe { ptr::read(ptr) }
     }
 
     // Read the entire cacheline
@@ -426,9 +467,41 @@ where
     fn check_cache_params(&self) -> Result<(), Error> {
         bit_width::<usize>(N)?;
         bit_width::<usize>(W)?;
+        let reads = self.reads_per_cacheline();
+        if !reads.is_power_of_two() || reads > S / mem::size_of::<D>() {
+            return Err(Error::UnalignedValue);
+        }
         Ok(())
     }
 
+    /// Number of evenly spaced reads needed to check every ECC codeword in
+    /// a cache line. Where one read checks the whole line, as when the ECC
+    /// codeword is as wide as the line, reading more only wastes bandwidth;
+    /// where each word has its own codeword, every word must be read.
+    ///
+    /// # Returns:
+    /// A power of two no larger than the number of elements of type D in
+    /// the S bytes of a cache line. The default, 1, reads only the first
+    /// element.
+    fn reads_per_cacheline(&self) -> usize {
+        1
+    }
+
+    /// Read a cache line so that any ECC error in it is detected. This is
+    /// called by the scrubber for each cache line it scrubs. The default
+    /// reads reads_per_cacheline() evenly spaced elements of the line.
+    ///
+    /// # Arguments:
+    /// * `p` - Address of the start of the cache line
+    fn read_cacheline(&self, p: Addr<A>) {
+        let elements = (S / mem::size_of::<D>()).max(1);
+        let step = (elements / self.reads_per_cacheline().max(1)).max(1);
+        let ptr: *mut D = p.into();
+        for i in (0..elements).step_by(step) {
+            unsafe { ptr::read_volatile(ptr.add(i)) };
+        }
+    }
+
     // Return the number of bits used to index into the cache, i.e. the index
     // of a cache line in the cache. A cache with 1024 lines will have an
     // index using 10 bits.
@@ -509,13 +582,19 @@ where
 ///
 /// * end - address of the last byte of the area. Must be one less than a
 ///     multiple of the cache line size
+///
+/// With the serde feature, the addresses are serialized as integers named
+/// `start` and `end`.
 #[derive(Clone, Copy, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 #[repr(C)]
 pub struct MemArea<A>
 where
     A: AddrImplTrait<A>,
 {
+    #[cfg_attr(feature = "serde", serde(rename = "start"))]
     pub s: Addr<A>,
+    #[cfg_attr(feature = "serde", serde(rename = "end"))]
     pub e: Addr<A>,
 }
 
@@ -545,6 +624,52 @@ where
     }
 }
 
+/// Check that scrub areas are valid and that their sizes can be computed
+/// without overflow. An area may end at the very top of the address space.
+///
+/// # Arguments:
+/// * `extents` - (start, end) address of each scrub area, end inclusive
+///
+/// * `cacheline_size` - Number of bytes in a cache line
+///
+/// # Returns:
+/// Ok(n) with the total number of cache lines in the areas, otherwise
+/// Err(Error). Error::AddressOverflow means an area, or all of them
+/// together, holds more bytes than a usize can count.
+pub fn check_extents(
+    extents: &[(usize, usize)],
+    cacheline_size: usize,
+) -> Result<usize, Error> {
+    if !cacheline_size.is_power_of_two() {
+        return Err(Error::UnalignedValue);
+    }
+    if extents.is_empty() {
+        return Err(Error::NoMemAreas);
+    }
+
+    let mut bytes: usize = 0;
+    let mut lines: usize = 0;
+    for &(start, end) in extents {
+        if end < start {
+            return Err(Error::EmptyMemArea);
+        }
+        if start % cacheline_size != 0 {
+            return Err(Error::UnalignedStart);
+        }
+        if end.wrapping_add(1) % cacheline_size != 0 {
+            return Err(Error::UnalignedEnd);
+        }
+
+        let size = (end - start)
+            .checked_add(1)
+            .ok_or(Error::AddressOverflow)?;
+        bytes = bytes.checked_add(size).ok_or(Error::AddressOverflow)?;
+        lines += size / cacheline_size;
+    }
+
+    Ok(lines)
+}
+
 /// Compute the minimum number of bits required to hold a given value.
 /// The number must be a non-zero multiple of two.
 ///
@@ -0,0 +1,186 @@
+// Support for measuring scrub throughput on real hardware. BenchMemory is a
+// buffer scrubbed by a LineScrubber reading through a RawBackend, the same
+// code that scrubs real memory, so that the ways of driving the scrubber
+// can be compared at memory sizes and cache geometries of the user's
+// choosing. The criterion benchmarks in benches/ are built on this and can
+// be copied by users who want numbers for their own systems.
+
+use crate::backend::*;
+use crate::base::*;
+
+/// How the scrubber is driven through one pass
+///
+/// * `Sequential` - Each cache line in address order, in one chunk
+///
+/// * `CacheAware` - In the order given by ScrubOrder, all lines with one
+///   cache index before any with the next, in one chunk
+///
+/// * `Batched` - As CacheAware, but in chunks of the given number of
+///   lines, as a scrubber sharing the processor does
+///
+/// * `Fast` - As CacheAware, with LineScrubber::scrub_pass_fast(), which
+///   does no per-line checks
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BenchStrategy {
+    Sequential,
+    CacheAware,
+    Batched(usize),
+    Fast,
+}
+
+/// Memory to be scrubbed by a benchmark
+///
+/// * `buf` - Holds the memory, with room to align its start to a cache
+///   line
+///
+/// * `start` - Offset in buf of the first byte of the memory
+///
+/// * `size` - Number of bytes of memory
+///
+/// * `line_size` - Number of bytes in a cache line
+///
+/// * `cache_index_width` - Number of address bits in the cache index
+///
+/// * `reads_per_line` - Number of reads made of each cache line
+pub struct BenchMemory {
+    buf: Vec<u8>,
+    start: usize,
+    size: usize,
+    line_size: usize,
+    cache_index_width: usize,
+    reads_per_line: usize,
+}
+
+impl BenchMemory {
+    /// Allocate memory for a benchmark
+    ///
+    /// # Arguments:
+    /// * `size` - Number of bytes, a multiple of the cache line size
+    ///
+    /// * `line_size` - Number of bytes in a cache line, a power of two no
+    ///   smaller than MAX_READ_SIZE
+    ///
+    /// * `cache_index_width` - Number of address bits in the cache index
+    pub fn new(
+        size: usize,
+        line_size: usize,
+        cache_index_width: usize,
+    ) -> Result<BenchMemory, Error> {
+        if !line_size.is_power_of_two() || line_size < MAX_READ_SIZE {
+            return Err(Error::UnalignedValue);
+        }
+        if size == 0 {
+            return Err(Error::ZeroSize);
+        }
+        if !size.is_multiple_of(line_size) {
+            return Err(Error::UnalignedSize);
+        }
+
+        let buf = vec![0u8; size + line_size];
+        let start = buf.as_ptr().align_offset(line_size);
+        Ok(BenchMemory {
+            buf,
+            start,
+            size,
+            line_size,
+            cache_index_width,
+            reads_per_line: 1,
+        })
+    }
+
+    /// Set the number of evenly spaced reads made of each cache line. The
+    /// default is one read, of the start of the line.
+    ///
+    /// # Arguments:
+    /// * `reads` - Number of reads, a power of two no larger than the
+    ///   cache line size divided by MAX_READ_SIZE
+    pub fn set_reads_per_line(
+        &mut self,
+        reads: usize,
+    ) -> Result<(), Error> {
+        if !reads.is_power_of_two()
+            || reads > self.line_size / MAX_READ_SIZE
+        {
+            return Err(Error::UnalignedValue);
+        }
+        self.reads_per_line = reads;
+        Ok(())
+    }
+
+    /// Returns the number of bytes of memory
+    pub fn size(&self) -> usize {
+        self.size
+    }
+
+    /// Scrub every cache line once
+    ///
+    /// # Arguments:
+    /// * `strategy` - How to drive the scrubber
+    ///
+    /// # Returns:
+    /// Ok(bytes) with the number of bytes scrubbed, which is the size of
+    /// the memory for every strategy, otherwise Err(Error)
+    pub fn scrub(&self, strategy: BenchStrategy) -> Result<u64, Error> {
+        let first = self.buf[self.start..].as_ptr() as usize;
+        let extent = (first, first + self.size - 1);
+        let index_width = match strategy {
+            BenchStrategy::Sequential => 0,
+            _ => self.cache_index_width,
+        };
+
+        // The memory is borrowed from buf for as long as the scrubber
+        // lives, so every address it reads is mapped and readable
+        let backend = unsafe { RawBackend::new() };
+        let mut scrubber = LineScrubber::new(
+            backend,
+            &[extent],
+            self.line_size,
+            index_width,
+        )?;
+        scrubber.set_reads_per_line(self.reads_per_line)?;
+
+        match strategy {
+            BenchStrategy::Sequential | BenchStrategy::CacheAware => {
+                scrubber.scrub(self.size)?;
+            }
+            BenchStrategy::Batched(lines) => {
+                let chunk = lines.max(1) * self.line_size;
+                let mut done = 0;
+                while done < self.size {
+                    let bytes = chunk.min(self.size - done);
+                    scrubber.scrub(bytes)?;
+                    done += bytes;
+                }
+            }
+            BenchStrategy::Fast => {
+                scrubber.scrub_pass_fast()?;
+            }
+        }
+        Ok(scrubber.stats().bytes_scrubbed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_strategies() {
+        let mut mem = BenchMemory::new(64 * 1024, 64, 6).unwrap();
+        for strategy in [
+            BenchStrategy::Sequential,
+            BenchStrategy::CacheAware,
+            BenchStrategy::Batched(100),
+            BenchStrategy::Fast,
+        ] {
+            assert_eq!(mem.scrub(strategy), Ok(64 * 1024));
+        }
+
+        mem.set_reads_per_line(8).unwrap();
+        assert_eq!(mem.scrub(BenchStrategy::Fast), Ok(64 * 1024));
+        assert!(mem.set_reads_per_line(16).is_err());
+
+        assert!(BenchMemory::new(100, 64, 6).is_err());
+        assert!(BenchMemory::new(4096, 4, 6).is_err());
+    }
+}
@@ -0,0 +1,139 @@
+// A memory bandwidth budget shared by several scrubbers. A process may run
+// one scrubber per NUMA node or memory tier; each draws from the same
+// budget before scrubbing a chunk so that their combined traffic stays under
+// a single ceiling.
+//
+// The budget is a token bucket counting bytes. Tokens accumulate at the
+// configured rate up to the burst size. A scrubber may take more tokens than
+// are available, leaving the bucket in debt, and must then wait until the
+// debt would be repaid. Later scrubbers see the debt and wait behind it, so
+// waiting is fair in the order that chunks were reserved.
+
+use std::time::{Duration, Instant};
+
+use crate::sync::{lock, Mutex};
+
+/// A bandwidth budget shared between scrubbers, usually through an Arc
+///
+/// * `rate` - Bytes per second allowed across all scrubbers
+///
+/// * `burst` - Largest number of bytes that may accumulate while the
+///   scrubbers are idle
+///
+/// * `bucket` - Tokens available, negative when in debt, and when they
+///   were last updated
+#[derive(Debug)]
+pub struct BandwidthBudget {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<(f64, Instant)>,
+}
+
+impl BandwidthBudget {
+    /// Create a new BandwidthBudget, initially full
+    ///
+    /// # Arguments:
+    /// * `rate` - Bytes per second allowed across all scrubbers
+    ///
+    /// * `burst` - Largest number of bytes that may accumulate while the
+    ///   scrubbers are idle. This should be at least the largest chunk
+    ///   size.
+    pub fn new(rate: u64, burst: u64) -> BandwidthBudget {
+        BandwidthBudget::with_start(rate, burst, Instant::now())
+    }
+
+    /// Create a new BandwidthBudget, full at the given time. Use this when
+    /// reservations are made with times from a Clock other than the system
+    /// clock.
+    ///
+    /// # Arguments:
+    /// * `rate` - Bytes per second allowed across all scrubbers
+    ///
+    /// * `burst` - Largest number of bytes that may accumulate while the
+    ///   scrubbers are idle
+    ///
+    /// * `start` - Time at which the budget is full
+    pub fn with_start(
+        rate: u64,
+        burst: u64,
+        start: Instant,
+    ) -> BandwidthBudget {
+        BandwidthBudget {
+            rate: rate.max(1) as f64,
+            burst: burst as f64,
+            bucket: Mutex::new((burst as f64, start)),
+        }
+    }
+
+    /// Returns the number of bytes per second allowed
+    pub fn rate(&self) -> u64 {
+        self.rate as u64
+    }
+
+    /// Reserve bandwidth for scrubbing some bytes
+    ///
+    /// # Arguments:
+    /// * `bytes` - Number of bytes about to be scrubbed
+    ///
+    /// * `now` - The current time
+    ///
+    /// # Returns:
+    /// How long to wait before scrubbing the bytes
+    pub fn reserve(&self, bytes: usize, now: Instant) -> Duration {
+        let mut bucket = lock(&self.bucket);
+        let (tokens, then) = *bucket;
+        let elapsed = now.saturating_duration_since(then).as_secs_f64();
+        let tokens = (tokens + elapsed * self.rate).min(self.burst);
+        let tokens = tokens - bytes as f64;
+        *bucket = (tokens, now.max(then));
+
+        match tokens < 0.0 {
+            true => Duration::from_secs_f64(-tokens / self.rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn test_reserve() {
+        let budget = BandwidthBudget::new(1000, 500);
+        let now = Instant::now();
+
+        assert_eq!(budget.reserve(500, now), Duration::ZERO);
+        assert_eq!(budget.reserve(250, now), Duration::from_millis(250));
+        assert_eq!(budget.reserve(250, now), Duration::from_millis(500));
+
+        // A second later, 1000 bytes have accrued, repaying the debt
+        let later = now + Duration::from_secs(1);
+        assert_eq!(budget.reserve(500, later), Duration::ZERO);
+
+        // Idle time accrues no more than the burst size
+        let idle = later + Duration::from_secs(60);
+        assert_eq!(budget.reserve(500, idle), Duration::ZERO);
+        assert_eq!(budget.reserve(100, idle), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn test_shared() {
+        let budget = Arc::new(BandwidthBudget::new(1000, 0));
+        let now = Instant::now();
+        let handles: Vec<_> = (0..4)
+            .map(|_| {
+                let budget = Arc::clone(&budget);
+                thread::spawn(move || budget.reserve(100, now))
+            })
+            .collect();
+        let mut waits: Vec<Duration> =
+            handles.into_iter().map(|h| h.join().unwrap()).collect();
+        waits.sort();
+
+        // Each scrubber waits behind those that reserved before it
+        assert_eq!(waits[3], Duration::from_millis(400));
+    }
+}
@@ -0,0 +1,285 @@
+// A simple model of a set-associative cache, for checking how much a scrub
+// disturbs the cache. Feeding it the addresses from ScrubOrder, a chunk at
+// a time, shows how many cache sets each chunk touches. A cache-aware scrub
+// concentrates each chunk on a few sets while a sequential scrub spreads it
+// across all of them, and the model allows the difference to be measured
+// for a particular cache geometry.
+//
+// Only placement is modelled: there is no timing, no coherency and only a
+// single level of cache.
+
+use crate::base::*;
+use crate::rng::*;
+
+/// How a way is chosen for eviction when a set is full
+///
+/// * `Lru` - The least recently used way
+///
+/// * `Fifo` - The way filled longest ago
+///
+/// * `Random` - A pseudo-random way
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Replacement {
+    Lru,
+    Fifo,
+    Random,
+}
+
+/// Effect of a sequence of accesses on the cache
+///
+/// * `lines` - Number of accesses
+///
+/// * `hits` - Accesses found in the cache
+///
+/// * `misses` - Accesses not found in the cache
+///
+/// * `evictions` - Lines evicted to make room for a miss
+///
+/// * `sets_disturbed` - Number of distinct sets in which a line was filled
+///
+/// * `ways_filled` - Number of ways filled, counting at most every way of
+///   each set, so the most lines of other data the accesses can displace
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ChunkReport {
+    pub lines: usize,
+    pub hits: usize,
+    pub misses: usize,
+    pub evictions: usize,
+    pub sets_disturbed: usize,
+    pub ways_filled: usize,
+}
+
+// A way holding a line, with the time at which it was last used or filled,
+// depending on the replacement policy
+#[derive(Clone, Copy, Debug)]
+struct Way {
+    line: usize,
+    stamp: u64,
+}
+
+/// A simulated set-associative cache
+///
+/// * `line_size` - Number of bytes in a cache line
+///
+/// * `ways` - Number of ways per set
+///
+/// * `policy` - Replacement policy
+///
+/// * `sets` - The lines held in each set
+///
+/// * `time` - Count of accesses, used to order them
+///
+/// * `rng` - Source of random numbers for random replacement
+pub struct CacheSim {
+    line_size: usize,
+    ways: usize,
+    policy: Replacement,
+    sets: Vec<Vec<Way>>,
+    time: u64,
+    rng: XorShift64,
+}
+
+impl CacheSim {
+    /// Create an empty cache
+    ///
+    /// # Arguments:
+    /// * `sets` - Number of sets, a power of two
+    ///
+    /// * `ways` - Number of ways per set
+    ///
+    /// * `line_size` - Number of bytes in a cache line, a power of two
+    ///
+    /// * `policy` - Replacement policy
+    pub fn new(
+        sets: usize,
+        ways: usize,
+        line_size: usize,
+        policy: Replacement,
+    ) -> Result<CacheSim, Error> {
+        if !sets.is_power_of_two() || !line_size.is_power_of_two() {
+            return Err(Error::UnalignedValue);
+        }
+        if ways == 0 {
+            return Err(Error::ZeroSize);
+        }
+
+        Ok(CacheSim {
+            line_size,
+            ways,
+            policy,
+            sets: vec![Vec::with_capacity(ways); sets],
+            time: 0,
+            rng: XorShift64::new(0x2545_f491_4f6c_dd1d),
+        })
+    }
+
+    /// Returns the number of sets
+    pub fn sets(&self) -> usize {
+        self.sets.len()
+    }
+
+    /// Returns the number of ways per set
+    pub fn ways(&self) -> usize {
+        self.ways
+    }
+
+    /// Returns the set to which an address maps
+    pub fn set_index(&self, addr: usize) -> usize {
+        (addr / self.line_size) % self.sets.len()
+    }
+
+    /// Empty the cache
+    pub fn flush(&mut self) {
+        for set in &mut self.sets {
+            set.clear();
+        }
+    }
+
+    /// Access an address, filling its line into the cache on a miss
+    ///
+    /// # Returns:
+    /// Whether the access was a hit, and whether a line was evicted
+    pub fn access(&mut self, addr: usize) -> (bool, bool) {
+        let line = addr / self.line_size;
+        let index = self.set_index(addr);
+        self.time += 1;
+        let time = self.time;
+
+        if let Some(way) =
+            self.sets[index].iter_mut().find(|w| w.line == line)
+        {
+            if self.policy == Replacement::Lru {
+                way.stamp = time;
+            }
+            return (true, false);
+        }
+
+        let way = Way { line, stamp: time };
+        if self.sets[index].len() < self.ways {
+            self.sets[index].push(way);
+            return (false, false);
+        }
+
+        let victim = match self.policy {
+            Replacement::Lru | Replacement::Fifo => self.sets[index]
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, w)| w.stamp)
+                .map_or(0, |(i, _)| i),
+            Replacement::Random => {
+                self.rng.below(self.ways as u64) as usize
+            }
+        };
+        self.sets[index][victim] = way;
+        (false, true)
+    }
+
+    /// Access a sequence of addresses, such as one chunk of a scrub
+    pub fn run_chunk<I>(&mut self, addrs: I) -> ChunkReport
+    where
+        I: IntoIterator<Item = usize>,
+    {
+        let mut report = ChunkReport::default();
+        let mut filled = vec![0usize; self.sets.len()];
+
+        for addr in addrs {
+            let (hit, evicted) = self.access(addr);
+            report.lines += 1;
+            match hit {
+                true => report.hits += 1,
+                false => {
+                    report.misses += 1;
+                    filled[self.set_index(addr)] += 1;
+                }
+            }
+            if evicted {
+                report.evictions += 1;
+            }
+        }
+
+        report.sets_disturbed = filled.iter().filter(|&&f| f != 0).count();
+        report.ways_filled =
+            filled.iter().map(|&f| f.min(self.ways)).sum();
+        report
+    }
+
+    /// Access a sequence of addresses in chunks of a fixed number of lines
+    ///
+    /// # Returns:
+    /// A report for each chunk
+    pub fn run_chunks<I>(
+        &mut self,
+        addrs: I,
+        chunk_lines: usize,
+    ) -> Vec<ChunkReport>
+    where
+        I: IntoIterator<Item = usize>,
+    {
+        let chunk_lines = chunk_lines.max(1);
+        let mut addrs = addrs.into_iter().peekable();
+        let mut reports = Vec::new();
+        while addrs.peek().is_some() {
+            reports.push(self.run_chunk(addrs.by_ref().take(chunk_lines)));
+        }
+        reports
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dryrun::*;
+
+    #[test]
+    fn test_replacement() {
+        // One set of two ways
+        let mut lru = CacheSim::new(1, 2, 64, Replacement::Lru).unwrap();
+        let mut fifo = CacheSim::new(1, 2, 64, Replacement::Fifo).unwrap();
+        for cache in [&mut lru, &mut fifo] {
+            assert_eq!(cache.access(0), (false, false));
+            assert_eq!(cache.access(64), (false, false));
+            assert_eq!(cache.access(0), (true, false));
+            assert_eq!(cache.access(128), (false, true));
+        }
+
+        // LRU evicted 64, FIFO evicted 0
+        assert_eq!(lru.access(0), (true, false));
+        assert_eq!(fifo.access(64), (true, false));
+
+        let mut random =
+            CacheSim::new(4, 2, 64, Replacement::Random).unwrap();
+        let report = random.run_chunk((0..64 * 64).step_by(64));
+        assert_eq!(report.misses, 64);
+        assert_eq!(report.evictions, 56);
+    }
+
+    #[test]
+    fn test_disturbance() {
+        // 16 KiB of memory, a cache of 16 sets of 2 ways with 64-byte
+        // lines, scrubbed 16 lines at a time
+        const MEM: usize = 16 * 1024;
+        let order = ScrubOrder::new(&[(0, MEM - 1)], 64, 4).unwrap();
+
+        let mut cache =
+            CacheSim::new(16, 2, 64, Replacement::Lru).unwrap();
+        let aware = cache.run_chunks(order, 16);
+        cache.flush();
+        let sequential = cache.run_chunks((0..MEM).step_by(64), 16);
+
+        assert_eq!(aware.len(), 16);
+        assert_eq!(sequential.len(), 16);
+        assert!(aware.iter().all(|r| r.sets_disturbed == 1));
+        assert!(sequential.iter().all(|r| r.sets_disturbed == 16));
+
+        // Filling one set can displace no more than its two ways
+        assert!(aware.iter().all(|r| r.ways_filled == 2));
+        assert!(sequential.iter().all(|r| r.ways_filled == 16));
+
+        // The same lines are read either way
+        let misses = |r: &[ChunkReport]| -> usize {
+            r.iter().map(|c| c.misses).sum()
+        };
+        assert_eq!(misses(&aware), MEM / 64);
+        assert_eq!(misses(&sequential), MEM / 64);
+    }
+}
@@ -0,0 +1,349 @@
+// Spreading scrub reads across memory channels. Memory controllers
+// interleave addresses across channels, typically by XORing selected
+// address bits together into each bit of the channel number. The cache
+// aware order reads the lines of one cache index in turn, and those often
+// differ only in address bits that select the same channel, so a run of
+// reads can all go to one channel while the others sit idle. That limits
+// the bandwidth scrubbing can reach and concentrates its interference
+// with the foreground work on one channel.
+//
+// A ChannelBalancer holds a small window of upcoming addresses, queued by
+// channel, and hands them out round robin across the channels. Every
+// address is still returned exactly once, and no address is held back by
+// more than the window, so the order stays close to the cache aware one.
+// ChannelOrder applies a balancer to any iterator of addresses, such as
+// a ScrubOrder, and LineScrubber::set_channel_balancer() applies one to
+// the scrub itself.
+
+use std::collections::VecDeque;
+
+use crate::base::*;
+
+/// Maximum number of channel number bits in a ChannelMap
+pub const MAX_CHANNEL_BITS: usize = 8;
+
+/// How addresses are interleaved across memory channels. Bit i of the
+/// channel number of an address is the XOR of the address bits set in
+/// selector i.
+///
+/// * `selectors` - Mask of the address bits XORed into each bit of the
+///   channel number, least significant first
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChannelMap {
+    selectors: Vec<usize>,
+}
+
+impl ChannelMap {
+    /// Create a ChannelMap
+    ///
+    /// # Arguments:
+    /// * `selectors` - Mask of the address bits XORed into each bit of the
+    ///   channel number, least significant first. No selectors means a
+    ///   single channel.
+    ///
+    /// # Returns:
+    /// Ok(ChannelMap) on success, otherwise Err(Error::ZeroSize) if a
+    /// selector has no bits set or Err(Error::TooManySelectors) if there
+    /// are more than MAX_CHANNEL_BITS selectors
+    pub fn new(selectors: &[usize]) -> Result<ChannelMap, Error> {
+        if selectors.len() > MAX_CHANNEL_BITS {
+            return Err(Error::TooManySelectors);
+        }
+        if selectors.contains(&0) {
+            return Err(Error::ZeroSize);
+        }
+        Ok(ChannelMap {
+            selectors: selectors.to_vec(),
+        })
+    }
+
+    /// Create a ChannelMap for plain interleaving, where the channel is
+    /// given by consecutive address bits
+    ///
+    /// # Arguments:
+    /// * `shift` - Lowest address bit of the channel number, such as 6
+    ///   for channels interleaved every 64 bytes
+    ///
+    /// * `bits` - Number of bits in the channel number
+    pub fn linear(shift: usize, bits: usize) -> Result<ChannelMap, Error> {
+        if shift.saturating_add(bits) > usize::BITS as usize {
+            return Err(Error::UnalignedValue);
+        }
+        let selectors: Vec<usize> =
+            (shift..shift + bits).map(|bit| 1 << bit).collect();
+        ChannelMap::new(&selectors)
+    }
+
+    /// Returns the number of channels
+    pub fn channels(&self) -> usize {
+        1 << self.selectors.len()
+    }
+
+    /// Returns the channel holding an address
+    pub fn channel(&self, addr: usize) -> usize {
+        self.selectors
+            .iter()
+            .enumerate()
+            .map(|(bit, &mask)| {
+                ((addr & mask).count_ones() as usize & 1) << bit
+            })
+            .sum()
+    }
+}
+
+/// Reorders addresses so that successive ones go to different channels
+/// where the window allows
+///
+/// * `map` - How addresses are interleaved across channels
+///
+/// * `window` - Most addresses held at once
+///
+/// * `queues` - Addresses held, by channel, in the order they were given
+///
+/// * `channel` - Channel to return an address from next, if it has one
+///
+/// * `len` - Number of addresses held
+#[derive(Clone, Debug)]
+pub struct ChannelBalancer {
+    map: ChannelMap,
+    window: usize,
+    queues: Vec<VecDeque<usize>>,
+    channel: usize,
+    len: usize,
+}
+
+impl ChannelBalancer {
+    /// Create a ChannelBalancer
+    ///
+    /// # Arguments:
+    /// * `map` - How addresses are interleaved across channels
+    ///
+    /// * `window` - Most addresses held at once. A window of a few times
+    ///   the number of channels is usually enough.
+    ///
+    /// # Returns:
+    /// Ok(ChannelBalancer) on success, otherwise Err(Error::ZeroSize) if
+    /// the window is zero
+    pub fn new(
+        map: ChannelMap,
+        window: usize,
+    ) -> Result<ChannelBalancer, Error> {
+        if window == 0 {
+            return Err(Error::ZeroSize);
+        }
+        Ok(ChannelBalancer {
+            queues: vec![VecDeque::new(); map.channels()],
+            map,
+            window,
+            channel: 0,
+            len: 0,
+        })
+    }
+
+    /// Returns how addresses are interleaved across channels
+    pub fn map(&self) -> &ChannelMap {
+        &self.map
+    }
+
+    /// Returns the number of addresses held
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether no addresses are held
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the addresses held, by channel
+    pub fn held(&self) -> impl Iterator<Item = usize> + '_ {
+        self.queues.iter().flatten().copied()
+    }
+
+    /// Drop the addresses held, as when the order they came from is
+    /// restarted
+    pub fn clear(&mut self) {
+        self.queues.iter_mut().for_each(|q| q.clear());
+        self.channel = 0;
+        self.len = 0;
+    }
+
+    /// Returns the next address, taking more from an iterator to fill the
+    /// window first
+    ///
+    /// # Arguments:
+    /// * `addrs` - Iterator giving the addresses in their original order
+    ///
+    /// # Returns:
+    /// The address, or None once the iterator is exhausted and no
+    /// addresses are held
+    pub fn next_from<I>(&mut self, addrs: &mut I) -> Option<usize>
+    where
+        I: Iterator<Item = usize>,
+    {
+        while self.len < self.window {
+            match addrs.next() {
+                Some(addr) => {
+                    self.queues[self.map.channel(addr)].push_back(addr);
+                    self.len += 1;
+                }
+                None => break,
+            }
+        }
+
+        let channels = self.queues.len();
+        for _ in 0..channels {
+            let channel = self.channel;
+            self.channel = (channel + 1) % channels;
+            if let Some(addr) = self.queues[channel].pop_front() {
+                self.len -= 1;
+                return Some(addr);
+            }
+        }
+        None
+    }
+}
+
+/// Iterator giving the addresses of another iterator, balanced across
+/// channels by a ChannelBalancer
+///
+/// * `addrs` - Iterator giving the addresses in their original order
+///
+/// * `balancer` - Reorders the addresses
+#[derive(Clone, Debug)]
+pub struct ChannelOrder<I: Iterator<Item = usize>> {
+    addrs: I,
+    balancer: ChannelBalancer,
+}
+
+impl<I: Iterator<Item = usize>> ChannelOrder<I> {
+    /// Create a ChannelOrder
+    ///
+    /// # Arguments:
+    /// * `addrs` - Iterator giving the addresses, such as a ScrubOrder
+    ///
+    /// * `balancer` - Reorders the addresses. Any addresses it holds are
+    ///   dropped.
+    pub fn new(
+        addrs: I,
+        mut balancer: ChannelBalancer,
+    ) -> ChannelOrder<I> {
+        balancer.clear();
+        ChannelOrder { addrs, balancer }
+    }
+}
+
+impl<I: Iterator<Item = usize>> Iterator for ChannelOrder<I> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        self.balancer.next_from(&mut self.addrs)
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let (low, high) = self.addrs.size_hint();
+        let held = self.balancer.len();
+        (
+            low.saturating_add(held),
+            high.and_then(|high| high.checked_add(held)),
+        )
+    }
+}
+
+impl<I: ExactSizeIterator<Item = usize>> ExactSizeIterator
+    for ChannelOrder<I>
+{
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dryrun::*;
+
+    // Returns the longest run of successive addresses on one channel
+    fn longest_run(map: &ChannelMap, addrs: &[usize]) -> usize {
+        let mut longest = 0;
+        let mut run = 0;
+        let mut last = None;
+        for &addr in addrs {
+            let channel = map.channel(addr);
+            run = match last == Some(channel) {
+                true => run + 1,
+                false => 1,
+            };
+            last = Some(channel);
+            longest = longest.max(run);
+        }
+        longest
+    }
+
+    #[test]
+    fn test_map() {
+        // Two channels selected by bit 8 XOR bit 12
+        let map = ChannelMap::new(&[0x1100]).unwrap();
+        assert_eq!(map.channels(), 2);
+        assert_eq!(map.channel(0x0000), 0);
+        assert_eq!(map.channel(0x0100), 1);
+        assert_eq!(map.channel(0x1000), 1);
+        assert_eq!(map.channel(0x1100), 0);
+
+        let map = ChannelMap::linear(6, 2).unwrap();
+        assert_eq!(map.channels(), 4);
+        assert_eq!(map.channel(0xc0), 3);
+        assert_eq!(map.channel(0x100), 0);
+        assert_eq!(ChannelMap::new(&[]).unwrap().channels(), 1);
+
+        assert_eq!(ChannelMap::new(&[0x40, 0]), Err(Error::ZeroSize));
+        assert_eq!(
+            ChannelMap::new(&[0x40; MAX_CHANNEL_BITS + 1]),
+            Err(Error::TooManySelectors)
+        );
+        assert_eq!(
+            ChannelMap::linear(usize::BITS as usize - 1, 2),
+            Err(Error::UnalignedValue)
+        );
+    }
+
+    #[test]
+    fn test_balance() {
+        // Sixteen cache indices of 64 bytes, so the lines of an index are
+        // 1024 bytes apart, with the channel given by bit 12 XOR bit 6.
+        // Each cache index gives runs of four lines on one channel, and
+        // the last run of one index joins the first of the next.
+        let map = ChannelMap::new(&[0x1040]).unwrap();
+        let order = ScrubOrder::new(&[(0, 16383)], 64, 4).unwrap();
+        let original: Vec<usize> = order.clone().collect();
+        assert_eq!(longest_run(&map, &original), 8);
+
+        let balancer = ChannelBalancer::new(map.clone(), 8).unwrap();
+        let balanced = ChannelOrder::new(order, balancer);
+        assert_eq!(balanced.len(), original.len());
+        let balanced: Vec<usize> = balanced.collect();
+        assert_eq!(longest_run(&map, &balanced), 1);
+
+        // Every address is still given once
+        let mut sorted = balanced.clone();
+        sorted.sort();
+        let mut expected = original.clone();
+        expected.sort();
+        assert_eq!(sorted, expected);
+    }
+
+    #[test]
+    fn test_one_channel() {
+        // With every address on one channel, the order is unchanged
+        let map = ChannelMap::new(&[0x10000]).unwrap();
+        let order = ScrubOrder::new(&[(0, 4095)], 64, 2).unwrap();
+        let original: Vec<usize> = order.clone().collect();
+        let balancer = ChannelBalancer::new(map, 4).unwrap();
+        let balanced: Vec<usize> =
+            ChannelOrder::new(order, balancer).collect();
+        assert_eq!(balanced, original);
+
+        let map = ChannelMap::new(&[]).unwrap();
+        assert_eq!(
+            ChannelBalancer::new(map, 0).err(),
+            Some(Error::ZeroSize)
+        );
+    }
+}
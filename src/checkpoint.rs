@@ -0,0 +1,199 @@
+// Saved scrubbing state. A checkpoint taken when AutoScrub stops holds
+// everything needed to resume where it left off: the statistics, which
+// include the position within the current pass, and the chunk size in use.
+// The extents of the scrub areas are kept so that a checkpoint can't be
+// resumed against a different set of areas. The position is given to the
+// scrubber as a ScrubCursor, the scrub area and offset within it at which
+// the next chunk starts.
+//
+// With the serde feature a checkpoint can be serialized, so scrubbing can
+// be resumed after a restart.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use crate::base::*;
+use crate::stats::*;
+
+/// Position in a pass at which the next chunk starts, counting the scrub
+/// areas in order
+///
+/// * `area` - Index of the scrub area
+///
+/// * `offset` - Number of bytes of the area already scrubbed in this pass
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ScrubCursor {
+    pub area: usize,
+    pub offset: usize,
+}
+
+impl ScrubCursor {
+    /// Find the cursor for a position in a pass
+    ///
+    /// # Arguments:
+    /// * `extents` - (start, end) address of each scrub area, end
+    ///   inclusive
+    ///
+    /// * `pass_offset` - Number of bytes of the pass already scrubbed
+    ///
+    /// # Returns:
+    /// Ok(ScrubCursor) on success, otherwise Err(Error::AddressOverflow)
+    /// if the position is beyond the end of the pass
+    pub fn at(
+        extents: &[(usize, usize)],
+        pass_offset: usize,
+    ) -> Result<ScrubCursor, Error> {
+        let mut offset = pass_offset;
+        for (area, &(start, end)) in extents.iter().enumerate() {
+            let size = end - start + 1;
+            if offset < size {
+                return Ok(ScrubCursor { area, offset });
+            }
+            offset -= size;
+        }
+        Err(Error::AddressOverflow)
+    }
+
+    /// Returns the number of bytes of the pass already scrubbed
+    pub fn pass_offset(&self, extents: &[(usize, usize)]) -> usize {
+        extents[..self.area.min(extents.len())]
+            .iter()
+            .map(|&(start, end)| end - start + 1)
+            .sum::<usize>()
+            + self.offset
+    }
+
+    /// Returns the cursor after scrubbing a number of bytes, going back to
+    /// the start of the first area at the end of each pass
+    pub fn advance(
+        &self,
+        extents: &[(usize, usize)],
+        bytes: usize,
+    ) -> ScrubCursor {
+        let pass_size: usize =
+            extents.iter().map(|&(start, end)| end - start + 1).sum();
+        if pass_size == 0 {
+            return ScrubCursor::default();
+        }
+        let offset = (self.pass_offset(extents) % pass_size
+            + bytes % pass_size)
+            % pass_size;
+        ScrubCursor::at(extents, offset).unwrap_or_default()
+    }
+}
+
+/// State needed to resume scrubbing
+///
+/// * `extents` - (start, end) address of each scrub area, end inclusive
+///
+/// * `stats` - Statistics when the checkpoint was taken. The pass_offset
+///   is the position at which to resume.
+///
+/// * `chunk_size` - Number of bytes in the most recent chunk
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ScrubCheckpoint {
+    pub extents: Vec<(usize, usize)>,
+    pub stats: ScrubStats,
+    pub chunk_size: usize,
+}
+
+impl ScrubCheckpoint {
+    /// Returns the offset in the current pass at which scrubbing resumes
+    pub fn position(&self) -> usize {
+        self.stats.pass_offset
+    }
+
+    /// Returns the scrub area and offset within it at which scrubbing
+    /// resumes
+    ///
+    /// # Returns:
+    /// Ok(ScrubCursor) on success, otherwise Err(Error::AddressOverflow)
+    /// if the position is beyond the end of the scrub areas
+    pub fn cursor(&self) -> Result<ScrubCursor, Error> {
+        ScrubCursor::at(&self.extents, self.position())
+    }
+
+    /// Returns whether this checkpoint was taken with the given scrub
+    /// areas
+    pub fn matches(&self, extents: &[(usize, usize)]) -> bool {
+        self.extents == extents
+    }
+
+    /// Return the checkpoint as a JSON string
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        // Serializing plain numbers and strings can't fail
+        serde_json::to_string(self)
+            .expect("ScrubCheckpoint serialization failed")
+    }
+
+    /// Read a checkpoint from a JSON string
+    #[cfg(feature = "serde")]
+    pub fn from_json(json: &str) -> serde_json::Result<ScrubCheckpoint> {
+        serde_json::from_str(json)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::{Duration, Instant};
+
+    fn checkpoint() -> ScrubCheckpoint {
+        let now = Instant::now();
+        let mut stats = ScrubStats::new(&[4096, 4096], now);
+        stats.record_chunk(4096 + 1024, Duration::from_millis(1), now);
+        ScrubCheckpoint {
+            extents: vec![(0, 4095), (8192, 12287)],
+            stats,
+            chunk_size: 1024,
+        }
+    }
+
+    #[test]
+    fn test_position() {
+        let checkpoint = checkpoint();
+        assert_eq!(checkpoint.position(), 5120);
+        assert!(checkpoint.matches(&[(0, 4095), (8192, 12287)]));
+        assert!(!checkpoint.matches(&[(0, 4095)]));
+    }
+
+    #[test]
+    fn test_resume_mid_pass() {
+        let checkpoint = checkpoint();
+        let extents = &checkpoint.extents;
+        let cursor = checkpoint.cursor().unwrap();
+        assert_eq!(
+            cursor,
+            ScrubCursor {
+                area: 1,
+                offset: 1024
+            }
+        );
+        assert_eq!(cursor.pass_offset(extents), checkpoint.position());
+
+        // The rest of the pass, then round into the next one
+        let cursor = cursor.advance(extents, 3072 - 64);
+        assert_eq!((cursor.area, cursor.offset), (1, 4032));
+        let cursor = cursor.advance(extents, 128);
+        assert_eq!((cursor.area, cursor.offset), (0, 64));
+
+        assert_eq!(
+            ScrubCursor::at(extents, 8192),
+            Err(Error::AddressOverflow)
+        );
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let checkpoint = checkpoint();
+        let restored =
+            ScrubCheckpoint::from_json(&checkpoint.to_json()).unwrap();
+        assert_eq!(restored.position(), checkpoint.position());
+        assert_eq!(restored.extents, checkpoint.extents);
+        assert_eq!(restored.chunk_size, 1024);
+        assert_eq!(restored.stats.bytes_scrubbed, 5120);
+    }
+}
@@ -0,0 +1,115 @@
+// Source of time for time-based scheduling. Anything that waits or
+// measures intervals does so through a Clock so that an alternative source
+// of time can be substituted.
+//
+// VirtualClock is such a source for tests. Its time only moves when it is
+// advanced or slept on, so rate limiting, deadlines and staleness can be
+// tested without real sleeps and with the same result on every run. Clones
+// share the same time, so a test can keep one clone and hand another to the
+// code under test.
+
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+
+use crate::sync::{lock, Arc, Mutex};
+
+/// A source of time
+pub trait Clock {
+    /// Returns the current time
+    fn now(&self) -> Instant;
+
+    /// Wait for the given length of time
+    fn sleep(&self, duration: Duration);
+
+    /// Returns the current wall-clock time, for schedules tied to the time
+    /// of day
+    fn wall(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// The system clock, waiting by putting the thread to sleep
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> Instant {
+        Instant::now()
+    }
+
+    fn sleep(&self, duration: Duration) {
+        thread::sleep(duration);
+    }
+}
+
+/// A clock whose time moves only when advanced. Sleeping advances the time
+/// at once instead of waiting.
+///
+/// * `time` - The current time and wall-clock time, shared between clones
+#[derive(Clone, Debug)]
+pub struct VirtualClock {
+    time: Arc<Mutex<(Instant, SystemTime)>>,
+}
+
+impl VirtualClock {
+    /// Create a virtual clock starting at the current system time
+    pub fn new() -> VirtualClock {
+        VirtualClock::at(Instant::now(), SystemTime::now())
+    }
+
+    /// Create a virtual clock starting at the given time
+    ///
+    /// # Arguments:
+    /// * `now` - Time returned by now() until the clock is advanced
+    ///
+    /// * `wall` - Time returned by wall() until the clock is advanced
+    pub fn at(now: Instant, wall: SystemTime) -> VirtualClock {
+        VirtualClock {
+            time: Arc::new(Mutex::new((now, wall))),
+        }
+    }
+
+    /// Move the time forward, for this clock and all of its clones
+    pub fn advance(&self, duration: Duration) {
+        let mut time = lock(&self.time);
+        time.0 += duration;
+        time.1 += duration;
+    }
+}
+
+impl Default for VirtualClock {
+    fn default() -> Self {
+        VirtualClock::new()
+    }
+}
+
+impl Clock for VirtualClock {
+    fn now(&self) -> Instant {
+        lock(&self.time).0
+    }
+
+    fn sleep(&self, duration: Duration) {
+        self.advance(duration);
+    }
+
+    fn wall(&self) -> SystemTime {
+        lock(&self.time).1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_virtual_clock() {
+        let clock = VirtualClock::new();
+        let (start, wall) = (clock.now(), clock.wall());
+        let shared = clock.clone();
+
+        shared.sleep(Duration::from_secs(10));
+        clock.advance(Duration::from_secs(5));
+        assert_eq!(clock.now(), start + Duration::from_secs(15));
+        assert_eq!(shared.wall(), wall + Duration::from_secs(15));
+    }
+}
@@ -0,0 +1,313 @@
+// Evidence that memory was scrubbed as often as required. A PassHistory
+// keeps the record of each completed pass, and a ComplianceReport checks
+// it, for a window of time, against the interval configured for each scrub
+// area. This is the artifact asked for by safety cases and audits: for
+// each area, the longest time any of its bytes could have gone without
+// being scrubbed, and whether that was within the interval.
+//
+// Because a pass reads the areas in cache index order, a byte may be read
+// anywhere between the start and the completion of a pass. The longest gap
+// between two scrubs of a byte is therefore taken as the time from the
+// start of one pass to the completion of the next. Before the first pass
+// in the history the memory is treated as never scrubbed.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use crate::event::*;
+use crate::stats::*;
+
+/// The completed passes, in epoch order
+///
+/// * `passes` - Record of each pass
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct PassHistory {
+    passes: Vec<PassRecord>,
+}
+
+impl PassHistory {
+    /// Create an empty history
+    pub fn new() -> PassHistory {
+        PassHistory::default()
+    }
+
+    /// Add a completed pass. Passes with an epoch no later than that of
+    /// the last pass added are ignored.
+    pub fn record(&mut self, pass: PassRecord) {
+        if self
+            .passes
+            .last()
+            .is_none_or(|last| last.epoch < pass.epoch)
+        {
+            self.passes.push(pass);
+        }
+    }
+
+    /// Add the last pass completed according to the statistics, if it
+    /// hasn't already been added. Call this after each chunk.
+    pub fn observe(&mut self, stats: &ScrubStats) {
+        if let Some(pass) = stats.last_pass {
+            self.record(pass);
+        }
+    }
+
+    /// Returns the passes, in epoch order
+    pub fn passes(&self) -> &[PassRecord] {
+        &self.passes
+    }
+
+    /// Forget passes completed before the given time, except the last of
+    /// them, which is needed to know how recently memory was scrubbed at
+    /// that time
+    pub fn prune(&mut self, before: Instant) {
+        let old = self.passes.partition_point(|p| p.completed < before);
+        self.passes.drain(..old.saturating_sub(1));
+    }
+}
+
+impl EventSink for PassHistory {
+    // The pass completed just now, having taken the given time
+    fn event(&mut self, event: &ScrubEvent) {
+        if let ScrubEvent::PassComplete {
+            epoch, duration, ..
+        } = event
+        {
+            let now = Instant::now();
+            self.record(PassRecord {
+                epoch: *epoch,
+                started: now.checked_sub(*duration).unwrap_or(now),
+                completed: now,
+            });
+        }
+    }
+}
+
+/// Compliance of a single scrub area
+///
+/// * `label` - Name given to the area, if any
+///
+/// * `start` - Address of the first byte of the area
+///
+/// * `end` - Address of the last byte of the area
+///
+/// * `interval_secs` - Longest time allowed between scrubs of any byte
+///
+/// * `max_gap_secs` - Longest time any byte may have gone unscrubbed
+///   during the window
+///
+/// * `staleness_secs` - Seconds since the area was last completely
+///   scrubbed, at the end of the window, or None if it never was
+///
+/// * `compliant` - Whether max_gap_secs is within interval_secs
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AreaCompliance {
+    pub label: Option<String>,
+    pub start: usize,
+    pub end: usize,
+    pub interval_secs: f64,
+    pub max_gap_secs: f64,
+    pub staleness_secs: Option<f64>,
+    pub compliant: bool,
+}
+
+/// Whether every scrub area met its scrub interval over a window of time
+///
+/// * `window_secs` - Length of the window
+///
+/// * `first_epoch` - Epoch of the first pass completed in the window, if
+///   any
+///
+/// * `last_epoch` - Epoch of the last pass completed in the window, if
+///   any
+///
+/// * `passes` - Number of passes completed in the window
+///
+/// * `areas` - Compliance of each scrub area
+///
+/// * `compliant` - Whether every area is compliant
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ComplianceReport {
+    pub window_secs: f64,
+    pub first_epoch: Option<u64>,
+    pub last_epoch: Option<u64>,
+    pub passes: u64,
+    pub areas: Vec<AreaCompliance>,
+    pub compliant: bool,
+}
+
+// Returns the longest time memory may have gone unscrubbed between start
+// and end, given the passes in epoch order
+fn max_gap(
+    passes: &[PassRecord],
+    start: Instant,
+    end: Instant,
+) -> Duration {
+    // Earliest time at which a byte may last have been read. Before the
+    // first pass, only the start of the window is known.
+    let mut since = start;
+    let mut gap = Duration::ZERO;
+    for pass in passes {
+        if pass.completed > start {
+            let until = pass.completed.min(end);
+            gap = gap.max(until.saturating_duration_since(since));
+        }
+        if pass.completed >= end {
+            return gap;
+        }
+        since = pass.started;
+    }
+    gap.max(end.saturating_duration_since(since))
+}
+
+impl ComplianceReport {
+    /// Check the pass history against the scrub interval of each area
+    ///
+    /// # Arguments:
+    /// * `history` - Passes completed up to the end of the window
+    ///
+    /// * `stats` - Statistics kept by the scrubber
+    ///
+    /// * `extents` - (start, end) address of each scrub area, in the same
+    ///   order as stats.areas
+    ///
+    /// * `intervals` - Scrub interval of each area, in the same order as
+    ///   stats.areas
+    ///
+    /// * `window` - (start, end) of the window of time to check
+    pub fn new(
+        history: &PassHistory,
+        stats: &ScrubStats,
+        extents: &[(usize, usize)],
+        intervals: &[Duration],
+        window: (Instant, Instant),
+    ) -> ComplianceReport {
+        let (start, end) = window;
+        let gap = max_gap(history.passes(), start, end);
+        let in_window: Vec<&PassRecord> = history
+            .passes()
+            .iter()
+            .filter(|p| p.completed >= start && p.completed <= end)
+            .collect();
+
+        let areas: Vec<AreaCompliance> = stats
+            .areas
+            .iter()
+            .zip(extents.iter())
+            .zip(intervals.iter())
+            .map(|((area, (first, last)), interval)| AreaCompliance {
+                label: area.label.clone(),
+                start: *first,
+                end: *last,
+                interval_secs: interval.as_secs_f64(),
+                max_gap_secs: gap.as_secs_f64(),
+                staleness_secs: area
+                    .last_scrubbed
+                    .filter(|&t| t <= end)
+                    .map(|t| (end - t).as_secs_f64()),
+                compliant: gap <= *interval,
+            })
+            .collect();
+
+        ComplianceReport {
+            window_secs: end
+                .saturating_duration_since(start)
+                .as_secs_f64(),
+            first_epoch: in_window.first().map(|p| p.epoch),
+            last_epoch: in_window.last().map(|p| p.epoch),
+            passes: in_window.len() as u64,
+            compliant: areas.iter().all(|a| a.compliant),
+            areas,
+        }
+    }
+
+    /// Return the report as a JSON string
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        // Serializing plain numbers and strings can't fail
+        serde_json::to_string(self)
+            .expect("ComplianceReport serialization failed")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn secs(n: u64) -> Duration {
+        Duration::from_secs(n)
+    }
+
+    #[test]
+    fn test_max_gap() {
+        let t = Instant::now();
+        let pass = |epoch, started, completed| PassRecord {
+            epoch,
+            started: t + secs(started),
+            completed: t + secs(completed),
+        };
+        let passes = [pass(1, 0, 10), pass(2, 10, 20), pass(3, 25, 30)];
+
+        // Never scrubbed before the first pass completed
+        assert_eq!(max_gap(&passes, t, t + secs(30)), secs(20));
+        // Read as late as 25s into pass 2 and as late as 10s by pass 3
+        assert_eq!(max_gap(&passes, t + secs(12), t + secs(30)), secs(20));
+        // Not scrubbed since pass 3 started
+        assert_eq!(max_gap(&passes, t + secs(30), t + secs(60)), secs(35));
+        assert_eq!(max_gap(&[], t, t + secs(5)), secs(5));
+    }
+
+    #[test]
+    fn test_report() {
+        let t = Instant::now();
+        let mut stats = ScrubStats::new(&[64, 64], t);
+        stats.areas[1].label = Some("kernel".to_string());
+        let mut history = PassHistory::new();
+        for n in 1..=6 {
+            stats.record_chunk(128, Duration::ZERO, t + secs(10 * n));
+            history.observe(&stats);
+            history.observe(&stats);
+        }
+        assert_eq!(history.passes().len(), 6);
+
+        let report = ComplianceReport::new(
+            &history,
+            &stats,
+            &[(0, 63), (64, 127)],
+            &[secs(15), secs(30)],
+            (t + secs(15), t + secs(60)),
+        );
+        assert_eq!(
+            (report.first_epoch, report.last_epoch),
+            (Some(2), Some(6))
+        );
+        assert_eq!(report.passes, 5);
+        assert_eq!(report.areas[0].max_gap_secs, 20.0);
+        assert!(!report.areas[0].compliant);
+        assert!(report.areas[1].compliant);
+        assert_eq!(report.areas[1].staleness_secs, Some(0.0));
+        assert!(!report.compliant);
+
+        history.prune(t + secs(35));
+        assert_eq!(history.passes()[0].epoch, 3);
+    }
+
+    #[test]
+    fn test_event() {
+        let mut history = PassHistory::new();
+        history.event(&ScrubEvent::PassComplete {
+            pass: 1,
+            epoch: 7,
+            bytes: 64,
+            duration: Duration::from_millis(5),
+        });
+        let pass = history.passes()[0];
+        assert_eq!(pass.epoch, 7);
+        assert_eq!(
+            pass.completed - pass.started,
+            Duration::from_millis(5)
+        );
+    }
+}
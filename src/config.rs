@@ -0,0 +1,48 @@
+// Description of the configuration of a memory scrubber. The cache geometry
+// is fixed by the generic parameters of the scrubber, so this is mostly a
+// way of making those parameters available at run time.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::mem;
+
+/// Memory scrubber configuration
+///
+/// * `cache_lines` - Number of cache lines (N)
+///
+/// * `ways` - Number of ways per cache line (W)
+///
+/// * `cacheline_size` - Number of bytes in a cache line (S)
+///
+/// * `data_size` - Number of bytes processed by the ECC unit at a time,
+///   i.e. the size of D
+///
+/// * `addr_size` - Number of bytes in an address, i.e. the size of A
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ScrubConfig {
+    pub cache_lines: usize,
+    pub ways: usize,
+    pub cacheline_size: usize,
+    pub data_size: usize,
+    pub addr_size: usize,
+}
+
+impl ScrubConfig {
+    /// Create the configuration corresponding to a set of generic parameters
+    pub fn new<const N: usize, const W: usize, const S: usize, D, A>(
+    ) -> ScrubConfig {
+        ScrubConfig {
+            cache_lines: N,
+            ways: W,
+            cacheline_size: S,
+            data_size: mem::size_of::<D>(),
+            addr_size: mem::size_of::<A>(),
+        }
+    }
+
+    /// Return the number of bytes in the cache
+    pub fn cache_size(&self) -> usize {
+        self.cache_lines * self.ways * self.cacheline_size
+    }
+}
@@ -0,0 +1,242 @@
+// Control of a running scrub from other threads. A ScrubControl is shared
+// between the thread running AutoScrub and any number of controlling
+// threads, which can pause, resume and stop scrubbing and read a snapshot of
+// the statistics as of the last chunk.
+//
+// The paused and cancelled flags are kept under one lock, with a condition
+// variable to wake the scrubbing thread, so that cancelling a paused scrub
+// can't be missed. The statistics are kept under their own lock so that
+// reading them never holds up a pause or resume.
+
+use crate::stats::*;
+use crate::sync::{lock, Arc, Condvar, Mutex};
+
+// Flags set by the controlling threads
+#[derive(Debug, Default)]
+struct ControlState {
+    paused: bool,
+    cancelled: bool,
+}
+
+// State shared by all clones of a ScrubControl
+#[derive(Debug)]
+struct Shared {
+    state: Mutex<ControlState>,
+    wake: Condvar,
+    stats: Mutex<Option<ScrubStats>>,
+}
+
+/// A handle for pausing, resuming and stopping scrubbing from another
+/// thread. Clones share the same state.
+#[derive(Clone, Debug)]
+pub struct ScrubControl {
+    shared: Arc<Shared>,
+}
+
+impl ScrubControl {
+    /// Create a control that is neither paused nor cancelled
+    pub fn new() -> ScrubControl {
+        ScrubControl {
+            shared: Arc::new(Shared {
+                state: Mutex::new(ControlState::default()),
+                wake: Condvar::new(),
+                stats: Mutex::new(None),
+            }),
+        }
+    }
+
+    /// Pause scrubbing after the current chunk
+    pub fn pause(&self) {
+        lock(&self.shared.state).paused = true;
+    }
+
+    /// Resume paused scrubbing
+    pub fn resume(&self) {
+        lock(&self.shared.state).paused = false;
+        self.shared.wake.notify_all();
+    }
+
+    /// Stop scrubbing after the current chunk, even if paused
+    pub fn cancel(&self) {
+        lock(&self.shared.state).cancelled = true;
+        self.shared.wake.notify_all();
+    }
+
+    /// Returns whether scrubbing is paused
+    pub fn is_paused(&self) -> bool {
+        lock(&self.shared.state).paused
+    }
+
+    /// Returns whether cancel() has been called
+    pub fn is_cancelled(&self) -> bool {
+        lock(&self.shared.state).cancelled
+    }
+
+    /// Wait while scrubbing is paused. This is called by the scrubbing
+    /// thread between chunks.
+    ///
+    /// # Returns:
+    /// true if scrubbing should continue, false if it has been cancelled
+    pub fn wait(&self) -> bool {
+        let mut state = lock(&self.shared.state);
+        while state.paused && !state.cancelled {
+            state = match self.shared.wake.wait(state) {
+                Ok(state) => state,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+        }
+        !state.cancelled
+    }
+
+    /// Make a copy of the statistics available to the controlling threads
+    pub fn publish(&self, stats: &ScrubStats) {
+        *lock(&self.shared.stats) = Some(stats.clone());
+    }
+
+    /// Returns the statistics last published, if any
+    pub fn stats(&self) -> Option<ScrubStats> {
+        lock(&self.shared.stats).clone()
+    }
+}
+
+impl Default for ScrubControl {
+    fn default() -> ScrubControl {
+        ScrubControl::new()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn test_control() {
+        let control = ScrubControl::new();
+        assert!(control.wait());
+        assert!(control.stats().is_none());
+
+        let now = Instant::now();
+        let mut stats = ScrubStats::new(&[4096], now);
+        stats.record_chunk(1024, Duration::from_millis(1), now);
+        control.publish(&stats);
+        assert_eq!(control.stats().unwrap().bytes_scrubbed, 1024);
+
+        control.pause();
+        let scrubber = {
+            let control = control.clone();
+            thread::spawn(move || control.wait())
+        };
+        control.resume();
+        assert!(scrubber.join().unwrap());
+
+        control.pause();
+        let scrubber = {
+            let control = control.clone();
+            thread::spawn(move || control.wait())
+        };
+        control.cancel();
+        assert!(!scrubber.join().unwrap());
+        assert!(control.is_paused());
+    }
+}
+
+// Model tests, run with --cfg loom. Each explores every interleaving of a
+// scrubbing thread with a controlling thread.
+#[cfg(all(test, loom))]
+mod loom_tests {
+    use super::*;
+    use crate::budget::*;
+    use crate::desc::*;
+    use loom::thread;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn loom_pause_resume() {
+        loom::model(|| {
+            let control = ScrubControl::new();
+            control.pause();
+            let scrubber = {
+                let control = control.clone();
+                thread::spawn(move || control.wait())
+            };
+            control.resume();
+            assert!(scrubber.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn loom_cancel_while_paused() {
+        loom::model(|| {
+            let control = ScrubControl::new();
+            let scrubber = {
+                let control = control.clone();
+                thread::spawn(move || {
+                    control.pause();
+                    control.wait()
+                })
+            };
+            control.cancel();
+            assert!(!scrubber.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn loom_cancel_token() {
+        loom::model(|| {
+            let token = CancelToken::new();
+            let mut desc = UntilCancelled::new(token.clone(), 64);
+            let canceller = thread::spawn(move || token.cancel());
+            let first = desc.next_chunk();
+            canceller.join().unwrap();
+            assert!(first == 64 || first == 0);
+            assert_eq!(desc.next_chunk(), 0);
+        });
+    }
+
+    #[test]
+    fn loom_stats_snapshot() {
+        loom::model(|| {
+            let control = ScrubControl::new();
+            let scrubber = {
+                let control = control.clone();
+                thread::spawn(move || {
+                    let now = Instant::now();
+                    let mut stats = ScrubStats::new(&[4096], now);
+                    for _ in 0..2 {
+                        stats.record_chunk(1024, Duration::ZERO, now);
+                        control.publish(&stats);
+                    }
+                })
+            };
+
+            // A snapshot is never torn between two chunks
+            if let Some(stats) = control.stats() {
+                assert_eq!(stats.bytes_scrubbed, stats.chunks * 1024);
+            }
+            scrubber.join().unwrap();
+            assert_eq!(control.stats().unwrap().chunks, 2);
+        });
+    }
+
+    #[test]
+    fn loom_budget() {
+        loom::model(|| {
+            let budget = Arc::new(BandwidthBudget::new(1000, 0));
+            let now = Instant::now();
+            let other = {
+                let budget = Arc::clone(&budget);
+                thread::spawn(move || budget.reserve(100, now))
+            };
+            let mine = budget.reserve(100, now);
+            let theirs = other.join().unwrap();
+
+            // Whichever reserved second waits behind the first
+            let mut waits = [mine, theirs];
+            waits.sort();
+            assert_eq!(waits[0], Duration::from_millis(100));
+            assert_eq!(waits[1], Duration::from_millis(200));
+        });
+    }
+}
@@ -0,0 +1,414 @@
+// Which memory each pass left unscrubbed, and why. A pass is meant to
+// read every line of the scrub areas, but some lines are left out: ranges
+// declared never to be scrubbed, ranges excluded by policies after
+// errors, lines the validator says can't be read, lines the backend
+// skips, such as quarantined lines, and, when a pass is restarted or
+// scrubbing stops part way, the lines it never reached. A PassCoverage
+// records these gaps for one pass, and comparing the coverage of two
+// passes shows exactly which memory lost or regained its protection in
+// between, and which was left out of both for different reasons.
+//
+// LineScrubber::set_coverage_tracking() turns the recording on.
+
+use crate::base::*;
+
+/// Why lines were left unscrubbed in a pass
+///
+/// * `Declared` - Declared never to be scrubbed
+///
+/// * `Excluded` - Excluded by a policy, as after errors
+///
+/// * `Unreadable` - Rejected by the validator
+///
+/// * `Unfinished` - Not reached before the pass was restarted or
+///   scrubbing stopped
+///
+/// * `OwnState` - Holding the scrubber's own state
+///
+/// * `Quarantined` - Quarantined or retired by a QuarantineBackend
+///
+/// * `Duplicate` - Read through another view of the same memory by a
+///   DedupBackend
+///
+/// * `Ballooned` - Given back to the hypervisor, as found by a
+///   BalloonBackend
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    Declared,
+    Excluded,
+    Unreadable,
+    Unfinished,
+    OwnState,
+    Quarantined,
+    Duplicate,
+    Ballooned,
+}
+
+impl SkipReason {
+    /// Short name of the reason
+    pub fn name(&self) -> &'static str {
+        match self {
+            SkipReason::Declared => "declared",
+            SkipReason::Excluded => "excluded",
+            SkipReason::Unreadable => "unreadable",
+            SkipReason::Unfinished => "unfinished",
+            SkipReason::OwnState => "own_state",
+            SkipReason::Quarantined => "quarantined",
+            SkipReason::Duplicate => "duplicate",
+            SkipReason::Ballooned => "ballooned",
+        }
+    }
+}
+
+/// A range of memory left unscrubbed in a pass
+///
+/// * `start` - First address in the range
+///
+/// * `end` - Last address in the range
+///
+/// * `reason` - Why the range was left unscrubbed
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct PassGap {
+    pub start: usize,
+    pub end: usize,
+    pub reason: SkipReason,
+}
+
+impl PassGap {
+    /// Returns the number of bytes in the gap
+    pub fn bytes(&self) -> usize {
+        self.end - self.start + 1
+    }
+}
+
+/// The memory left unscrubbed in one pass
+///
+/// * `epoch` - Epoch of the pass
+///
+/// * `complete` - Whether the pass was completed, rather than restarted
+///   or still in progress
+///
+/// * `gaps` - Ranges left unscrubbed, sorted by address
+#[derive(Clone, Debug, PartialEq)]
+pub struct PassCoverage {
+    pub epoch: u64,
+    pub complete: bool,
+    gaps: Vec<PassGap>,
+}
+
+/// How the memory left unscrubbed differs between two passes
+///
+/// * `earlier` - Epoch of the earlier pass
+///
+/// * `later` - Epoch of the later pass
+///
+/// * `lost` - Ranges scrubbed in the earlier pass but not the later, with
+///   why they were not
+///
+/// * `regained` - Ranges not scrubbed in the earlier pass but scrubbed in
+///   the later, with why they were not before
+///
+/// * `changed` - Ranges scrubbed in neither pass, but for different
+///   reasons
+#[derive(Clone, Debug, PartialEq)]
+pub struct CoverageDiff {
+    pub earlier: u64,
+    pub later: u64,
+    pub lost: Vec<PassGap>,
+    pub regained: Vec<PassGap>,
+    pub changed: Vec<ReasonChange>,
+}
+
+impl CoverageDiff {
+    /// Returns whether both passes left the same memory unscrubbed, for
+    /// the same reasons
+    pub fn is_empty(&self) -> bool {
+        self.lost.is_empty()
+            && self.regained.is_empty()
+            && self.changed.is_empty()
+    }
+}
+
+/// A range left unscrubbed in two passes for different reasons
+///
+/// * `start` - First address in the range
+///
+/// * `end` - Last address in the range
+///
+/// * `earlier` - Why the range was left unscrubbed in the earlier pass
+///
+/// * `later` - Why the range was left unscrubbed in the later pass
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ReasonChange {
+    pub start: usize,
+    pub end: usize,
+    pub earlier: SkipReason,
+    pub later: SkipReason,
+}
+
+impl PassCoverage {
+    /// Create the coverage of a pass with no gaps
+    ///
+    /// # Arguments:
+    /// * `epoch` - Epoch of the pass
+    pub fn new(epoch: u64) -> PassCoverage {
+        PassCoverage {
+            epoch,
+            complete: false,
+            gaps: Vec::new(),
+        }
+    }
+
+    /// Record a range left unscrubbed. It is merged with gaps for the same
+    /// reason that it touches. Parts already recorded keep their reason.
+    ///
+    /// # Arguments:
+    /// * `start` - First address in the range
+    ///
+    /// * `end` - Last address in the range
+    ///
+    /// * `reason` - Why the range was left unscrubbed
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::EmptyMemArea) if end is
+    /// before start
+    pub fn add_gap(
+        &mut self,
+        start: usize,
+        end: usize,
+        reason: SkipReason,
+    ) -> Result<(), Error> {
+        if start > end {
+            return Err(Error::EmptyMemArea);
+        }
+        let new = PassGap { start, end, reason };
+        for part in subtract(&[new], &self.gaps) {
+            self.insert(part);
+        }
+        Ok(())
+    }
+
+    /// Returns the ranges left unscrubbed, sorted by address
+    pub fn gaps(&self) -> &[PassGap] {
+        &self.gaps
+    }
+
+    /// Returns the number of bytes left unscrubbed for a reason, or for
+    /// any reason if None
+    pub fn bytes(&self, reason: Option<SkipReason>) -> usize {
+        self.gaps
+            .iter()
+            .filter(|g| reason.is_none_or(|r| g.reason == r))
+            .map(|g| g.bytes())
+            .sum()
+    }
+
+    /// Returns why an address was left unscrubbed, or None if it was not
+    pub fn reason_at(&self, addr: usize) -> Option<SkipReason> {
+        let i = self.gaps.partition_point(|g| g.end < addr);
+        self.gaps
+            .get(i)
+            .filter(|g| g.start <= addr)
+            .map(|g| g.reason)
+    }
+
+    /// Compare the memory left unscrubbed with that of an earlier pass
+    ///
+    /// # Arguments:
+    /// * `earlier` - Coverage of the earlier pass
+    pub fn diff(&self, earlier: &PassCoverage) -> CoverageDiff {
+        CoverageDiff {
+            earlier: earlier.epoch,
+            later: self.epoch,
+            lost: subtract(&self.gaps, &earlier.gaps),
+            regained: subtract(&earlier.gaps, &self.gaps),
+            changed: changed(&earlier.gaps, &self.gaps),
+        }
+    }
+
+    // Insert a gap that overlaps no other, merging it with neighbours for
+    // the same reason that it touches
+    fn insert(&mut self, mut gap: PassGap) {
+        let mut i = self.gaps.partition_point(|g| g.end < gap.start);
+        if i > 0 {
+            let before = self.gaps[i - 1];
+            if before.reason == gap.reason && before.end + 1 == gap.start {
+                gap.start = before.start;
+                self.gaps.remove(i - 1);
+                i -= 1;
+            }
+        }
+        if let Some(after) = self.gaps.get(i) {
+            if after.reason == gap.reason
+                && gap.end.checked_add(1) == Some(after.start)
+            {
+                gap.end = after.end;
+                self.gaps.remove(i);
+            }
+        }
+        self.gaps.insert(i, gap);
+    }
+}
+
+// Returns the parts of some gaps not covered by others, keeping their
+// reasons. Both lists are sorted and don't overlap within themselves.
+fn subtract(gaps: &[PassGap], others: &[PassGap]) -> Vec<PassGap> {
+    let mut parts = Vec::new();
+    for gap in gaps {
+        let mut start = gap.start;
+        let first = others.partition_point(|o| o.end < gap.start);
+        for other in &others[first..] {
+            if other.start > gap.end {
+                break;
+            }
+            if other.start > start {
+                parts.push(PassGap {
+                    start,
+                    end: other.start - 1,
+                    reason: gap.reason,
+                });
+            }
+            start = match other.end.checked_add(1) {
+                Some(next) => next,
+                None => return parts,
+            };
+            if start > gap.end {
+                break;
+            }
+        }
+        if start <= gap.end {
+            parts.push(PassGap { start, ..*gap });
+        }
+    }
+    parts
+}
+
+// Returns the ranges in gaps of both lists whose reasons differ. Both
+// lists are sorted and don't overlap within themselves.
+fn changed(earlier: &[PassGap], later: &[PassGap]) -> Vec<ReasonChange> {
+    let mut changes = Vec::new();
+    let (mut i, mut j) = (0, 0);
+    while let (Some(a), Some(b)) = (earlier.get(i), later.get(j)) {
+        let (start, end) = (a.start.max(b.start), a.end.min(b.end));
+        if start <= end && a.reason != b.reason {
+            changes.push(ReasonChange {
+                start,
+                end,
+                earlier: a.reason,
+                later: b.reason,
+            });
+        }
+        match a.end < b.end {
+            true => i += 1,
+            false => j += 1,
+        }
+    }
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn gap(start: usize, end: usize, reason: SkipReason) -> PassGap {
+        PassGap { start, end, reason }
+    }
+
+    #[test]
+    fn test_gaps() {
+        let mut coverage = PassCoverage::new(1);
+        coverage
+            .add_gap(0x100, 0x13f, SkipReason::Excluded)
+            .unwrap();
+        coverage
+            .add_gap(0x1c0, 0x1ff, SkipReason::Excluded)
+            .unwrap();
+        coverage
+            .add_gap(0x140, 0x1bf, SkipReason::Excluded)
+            .unwrap();
+        assert_eq!(
+            coverage.gaps(),
+            [gap(0x100, 0x1ff, SkipReason::Excluded)]
+        );
+
+        // Overlapping parts keep the reason they were first recorded with
+        coverage
+            .add_gap(0x1c0, 0x27f, SkipReason::Unreadable)
+            .unwrap();
+        assert_eq!(
+            coverage.gaps(),
+            [
+                gap(0x100, 0x1ff, SkipReason::Excluded),
+                gap(0x200, 0x27f, SkipReason::Unreadable)
+            ]
+        );
+        assert_eq!(coverage.bytes(None), 0x180);
+        assert_eq!(coverage.bytes(Some(SkipReason::Unreadable)), 0x80);
+        assert_eq!(
+            coverage.reason_at(0x240),
+            Some(SkipReason::Unreadable)
+        );
+        assert_eq!(coverage.reason_at(0x280), None);
+        assert_eq!(
+            coverage.add_gap(2, 1, SkipReason::Declared),
+            Err(Error::EmptyMemArea)
+        );
+    }
+
+    #[test]
+    fn test_diff() {
+        let mut earlier = PassCoverage::new(1);
+        earlier
+            .add_gap(0x1000, 0x1fff, SkipReason::Declared)
+            .unwrap();
+        earlier
+            .add_gap(0x4000, 0x40ff, SkipReason::Unreadable)
+            .unwrap();
+        let mut later = PassCoverage::new(2);
+        later.add_gap(0x1000, 0x1fff, SkipReason::Declared).unwrap();
+        later.add_gap(0x4080, 0x40bf, SkipReason::Excluded).unwrap();
+        later
+            .add_gap(0x8000, 0x8fff, SkipReason::Unfinished)
+            .unwrap();
+
+        let diff = later.diff(&earlier);
+        assert_eq!((diff.earlier, diff.later), (1, 2));
+        assert_eq!(
+            diff.lost,
+            [gap(0x8000, 0x8fff, SkipReason::Unfinished)]
+        );
+        assert_eq!(
+            diff.regained,
+            [
+                gap(0x4000, 0x407f, SkipReason::Unreadable),
+                gap(0x40c0, 0x40ff, SkipReason::Unreadable)
+            ]
+        );
+        assert_eq!(
+            diff.changed,
+            [ReasonChange {
+                start: 0x4080,
+                end: 0x40bf,
+                earlier: SkipReason::Unreadable,
+                later: SkipReason::Excluded,
+            }]
+        );
+        assert!(later.diff(&later).is_empty());
+
+        // Memory left out of both passes, only for another reason, is a
+        // change too
+        let mut again = PassCoverage::new(3);
+        again.add_gap(0x1000, 0x1fff, SkipReason::Declared).unwrap();
+        again
+            .add_gap(0x4080, 0x40bf, SkipReason::Quarantined)
+            .unwrap();
+        again
+            .add_gap(0x8000, 0x8fff, SkipReason::Unfinished)
+            .unwrap();
+        let diff = again.diff(&later);
+        assert!(diff.lost.is_empty() && diff.regained.is_empty());
+        assert_eq!(diff.changed.len(), 1);
+        assert_eq!(diff.changed[0].later, SkipReason::Quarantined);
+        assert!(!diff.is_empty());
+    }
+}
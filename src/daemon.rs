@@ -0,0 +1,443 @@
+// A scrubber running in the background. ScrubberDaemon owns a LineScrubber
+// in a thread of its own, scrubbing a chunk each period, and is driven by
+// typed commands sent over a channel. Commands are handled between chunks,
+// so other subsystems can add and remove areas, change the rate, ask for
+// memory to be scrubbed at once or take a checkpoint without holding a
+// lock around the scrubber. Waiting for the next chunk is done by waiting
+// for a command, so a command is handled as soon as it arrives, but once a
+// chunk is due only one more command is taken before it is scrubbed, so
+// that a steady stream of them can't hold scrubbing off. A command that
+// can't be carried out is handed back with the error, so that the sender
+// can tell which one failed.
+//
+// A daemon started with a StateSaver restores the state saved by an earlier
+// run before scrubbing, saves it periodically between chunks and saves it
+// again when stopped.
+
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use crate::backend::*;
+use crate::base::*;
+use crate::checkpoint::*;
+use crate::diag::*;
+use crate::persist::*;
+use crate::stats::*;
+
+/// A command for a ScrubberDaemon
+///
+/// * `AddArea` - Add a scrub area
+///     * `start` - First address in the area
+///     * `end` - Last address in the area
+///
+/// * `RemoveArea` - Remove the scrub area with the given extent
+///     * `start` - First address in the area
+///     * `end` - Last address in the area
+///
+/// * `SetRate` - Change how much is scrubbed and how often
+///     * `chunk` - Number of bytes scrubbed each period
+///     * `period` - Time from the start of one chunk to the next
+///
+/// * `DemandScrub` - Scrub the memory around an address now
+///     * `addr` - The address
+///     * `bytes` - Number of bytes to scrub on each side of it
+///
+/// * `Checkpoint` - Send a checkpoint of the scrubbing state to the sender
+///
+/// * `Stop` - Stop scrubbing
+#[derive(Debug)]
+pub enum ScrubCommand {
+    AddArea { start: usize, end: usize },
+    RemoveArea { start: usize, end: usize },
+    SetRate { chunk: usize, period: Duration },
+    DemandScrub { addr: usize, bytes: usize },
+    Checkpoint(Sender<ScrubCheckpoint>),
+    Stop,
+}
+
+/// An error from a ScrubberDaemon
+///
+/// * `command` - The command that could not be carried out, or None if
+///   loading or saving the scrubbing state failed
+///
+/// * `error` - Why it failed
+#[derive(Debug)]
+pub struct DaemonError {
+    pub command: Option<ScrubCommand>,
+    pub error: Error,
+}
+
+/// A LineScrubber running in a background thread
+///
+/// * `commands` - Sends commands to the thread
+///
+/// * `errors` - Errors from commands that could not be carried out
+///
+/// * `thread` - The thread, which returns the final statistics
+pub struct ScrubberDaemon {
+    commands: Sender<ScrubCommand>,
+    errors: Receiver<DaemonError>,
+    thread: JoinHandle<Result<ScrubStats, Error>>,
+}
+
+impl ScrubberDaemon {
+    /// Start scrubbing in a background thread
+    ///
+    /// # Arguments:
+    /// * `make` - Creates the scrubber. This is called in the new thread,
+    ///   so the scrubber and its policies need not be Send.
+    ///
+    /// * `chunk` - Number of bytes scrubbed each period
+    ///
+    /// * `period` - Time from the start of one chunk to the next
+    pub fn spawn<B, F>(
+        make: F,
+        chunk: usize,
+        period: Duration,
+    ) -> ScrubberDaemon
+    where
+        B: ScrubBackend,
+        F: FnOnce() -> Result<LineScrubber<B>, Error> + Send + 'static,
+    {
+        let (commands, receiver) = mpsc::channel();
+        let (error_sender, errors) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            run(make()?, receiver, error_sender, chunk, period, None)
+        });
+
+        ScrubberDaemon {
+            commands,
+            errors,
+            thread,
+        }
+    }
+
+    /// Start scrubbing in a background thread, keeping the scrubbing state
+    /// in a file across restarts. Failures to load or save the state are
+    /// reported as Error::IoFailed, with no command, on the error receiver
+    /// and scrubbing continues.
+    ///
+    /// # Arguments:
+    /// * `make` - Creates the scrubber, as for spawn()
+    ///
+    /// * `chunk` - Number of bytes scrubbed each period
+    ///
+    /// * `period` - Time from the start of one chunk to the next
+    ///
+    /// * `saver` - Loads and saves the state
+    pub fn spawn_persistent<B, F>(
+        make: F,
+        chunk: usize,
+        period: Duration,
+        mut saver: StateSaver,
+    ) -> ScrubberDaemon
+    where
+        B: ScrubBackend,
+        F: FnOnce() -> Result<LineScrubber<B>, Error> + Send + 'static,
+    {
+        let (commands, receiver) = mpsc::channel();
+        let (error_sender, errors) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut scrubber = make()?;
+            if let Err(e) = saver.load(&mut scrubber) {
+                diag!(Warning, "can't load scrub state: {}", e);
+                let _ = error_sender.send(DaemonError {
+                    command: None,
+                    error: Error::IoFailed,
+                });
+            }
+            run(
+                scrubber,
+                receiver,
+                error_sender,
+                chunk,
+                period,
+                Some(saver),
+            )
+        });
+
+        ScrubberDaemon {
+            commands,
+            errors,
+            thread,
+        }
+    }
+
+    /// Returns a sender for commands, which can be cloned and handed to
+    /// other subsystems
+    pub fn commands(&self) -> Sender<ScrubCommand> {
+        self.commands.clone()
+    }
+
+    /// Send a command. Sending fails only if the daemon has stopped.
+    pub fn send(&self, command: ScrubCommand) -> Result<(), Error> {
+        self.commands
+            .send(command)
+            .map_err(|_| Error::InternalError)
+    }
+
+    /// Returns the receiver of errors from commands that could not be
+    /// carried out, each with its command
+    pub fn errors(&self) -> &Receiver<DaemonError> {
+        &self.errors
+    }
+
+    /// Take a checkpoint of the scrubbing state, waiting for the daemon to
+    /// reach the end of the current chunk
+    pub fn checkpoint(&self) -> Result<ScrubCheckpoint, Error> {
+        let (sender, receiver) = mpsc::channel();
+        self.send(ScrubCommand::Checkpoint(sender))?;
+        receiver.recv().map_err(|_| Error::InternalError)
+    }
+
+    /// Stop scrubbing and wait for the thread to finish
+    ///
+    /// # Returns:
+    /// Ok(ScrubStats) with the final statistics, otherwise Err(Error) if
+    /// scrubbing failed
+    pub fn stop(self) -> Result<ScrubStats, Error> {
+        // The thread may already have stopped with an error
+        let _ = self.commands.send(ScrubCommand::Stop);
+        self.thread.join().map_err(|_| Error::InternalError)?
+    }
+}
+
+// Scrub until stopped, handling commands between chunks
+fn run<B: ScrubBackend>(
+    mut scrubber: LineScrubber<B>,
+    commands: Receiver<ScrubCommand>,
+    errors: Sender<DaemonError>,
+    mut chunk: usize,
+    mut period: Duration,
+    mut saver: Option<StateSaver>,
+) -> Result<ScrubStats, Error> {
+    let mut next = Instant::now();
+    let mut handled = false;
+    loop {
+        // Once a chunk is due, one more command is taken before it
+        let now = Instant::now();
+        let command = match now >= next && handled {
+            true => None,
+            false => match commands
+                .recv_timeout(next.saturating_duration_since(now))
+            {
+                Ok(command) => Some(command),
+                Err(RecvTimeoutError::Timeout) => None,
+                Err(RecvTimeoutError::Disconnected) => break,
+            },
+        };
+        handled = command.is_some();
+        let Some(command) = command else {
+            next = Instant::now() + period;
+            scrubber.scrub(chunk)?;
+            if let Some(saver) = saver.as_mut() {
+                let saved = saver.maybe_save(&scrubber, Instant::now());
+                if let Err(e) = saved {
+                    diag!(Warning, "can't save state: {}", e);
+                    report(&errors, None, Error::IoFailed);
+                }
+            }
+            continue;
+        };
+
+        let result = match &command {
+            ScrubCommand::Stop => break,
+            &ScrubCommand::AddArea { start, end } => {
+                scrubber.add_area(start, end).map(|_| ())
+            }
+            &ScrubCommand::RemoveArea { start, end } => scrubber
+                .extents()
+                .iter()
+                .position(|&e| e == (start, end))
+                .ok_or(Error::NoSuchArea)
+                .and_then(|area| scrubber.remove_area(area)),
+            &ScrubCommand::SetRate {
+                chunk: new_chunk,
+                period: new_period,
+            } => match new_chunk % scrubber.line_size() {
+                0 => {
+                    next = next.min(now + new_period);
+                    chunk = new_chunk;
+                    period = new_period;
+                    Ok(())
+                }
+                _ => Err(Error::UnalignedSize),
+            },
+            &ScrubCommand::DemandScrub { addr, bytes } => {
+                scrubber.scrub_near(addr, bytes).map(|_| ())
+            }
+            ScrubCommand::Checkpoint(reply) => {
+                // The requester may have given up waiting
+                let _ = reply.send(ScrubCheckpoint {
+                    extents: scrubber.extents().to_vec(),
+                    stats: scrubber.stats().clone(),
+                    chunk_size: chunk,
+                });
+                Ok(())
+            }
+        };
+        if let Err(error) = result {
+            report(&errors, Some(command), error);
+        }
+    }
+    if let Some(saver) = saver.as_mut() {
+        if let Err(e) = saver.save(&scrubber, Instant::now()) {
+            diag!(Warning, "can't save scrub state: {}", e);
+            report(&errors, None, Error::IoFailed);
+        }
+    }
+    Ok(scrubber.stats().clone())
+}
+
+// Hand an error, and the command that failed if any, to the receiver of
+// errors
+fn report(
+    errors: &Sender<DaemonError>,
+    command: Option<ScrubCommand>,
+    error: Error,
+) {
+    // Nobody may be listening for errors
+    if let Err(e) = errors.send(DaemonError { command, error }) {
+        diag!(Error, "scrub error not delivered: {:?}", e.0);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::*;
+    use crate::testutil::*;
+    use std::fs;
+
+    #[test]
+    fn test_daemon() {
+        let daemon = ScrubberDaemon::spawn(
+            || {
+                let mem = SimMemory::new(0, 16384, 64)?;
+                LineScrubber::new(mem, &[(0, 4095)], 64, 4)
+            },
+            1024,
+            Duration::from_millis(1),
+        );
+
+        daemon
+            .send(ScrubCommand::AddArea {
+                start: 8192,
+                end: 12287,
+            })
+            .unwrap();
+        daemon
+            .send(ScrubCommand::AddArea {
+                start: 100,
+                end: 200,
+            })
+            .unwrap();
+        let checkpoint = daemon.checkpoint().unwrap();
+        assert_eq!(checkpoint.extents, [(0, 4095), (8192, 12287)]);
+        let failed = daemon.errors().recv().unwrap();
+        assert_eq!(failed.error, Error::UnalignedStart);
+        assert!(matches!(
+            failed.command,
+            Some(ScrubCommand::AddArea {
+                start: 100,
+                end: 200
+            })
+        ));
+
+        daemon
+            .send(ScrubCommand::RemoveArea {
+                start: 0,
+                end: 4095,
+            })
+            .unwrap();
+        daemon
+            .send(ScrubCommand::RemoveArea {
+                start: 0,
+                end: 4095,
+            })
+            .unwrap();
+        daemon
+            .send(ScrubCommand::SetRate {
+                chunk: 4096,
+                period: Duration::ZERO,
+            })
+            .unwrap();
+        daemon
+            .send(ScrubCommand::DemandScrub {
+                addr: 0x3000,
+                bytes: 0,
+            })
+            .unwrap();
+        let checkpoint = daemon.checkpoint().unwrap();
+        assert_eq!(checkpoint.extents, [(8192, 12287)]);
+        assert_eq!(checkpoint.chunk_size, 4096);
+        let failed = daemon.errors().recv().unwrap();
+        assert_eq!(failed.error, Error::NoSuchArea);
+        assert!(matches!(
+            failed.command,
+            Some(ScrubCommand::RemoveArea {
+                start: 0,
+                end: 4095
+            })
+        ));
+
+        let stats = daemon.stop().unwrap();
+        assert_eq!(stats.areas.len(), 1);
+    }
+
+    #[test]
+    fn test_fair() {
+        // A chunk is scrubbed between commands once one is due, however
+        // many are queued
+        let daemon = ScrubberDaemon::spawn(
+            || {
+                let mem = SimMemory::new(0, 4096, 64)?;
+                LineScrubber::new(mem, &[(0, 4095)], 64, 4)
+            },
+            1024,
+            Duration::ZERO,
+        );
+        for _ in 0..100 {
+            daemon
+                .send(ScrubCommand::DemandScrub { addr: 0, bytes: 0 })
+                .unwrap();
+        }
+        assert!(daemon.checkpoint().unwrap().stats.chunks >= 100);
+        daemon.stop().unwrap();
+    }
+
+    #[test]
+    fn test_persistent() {
+        let dir = scratch_path("daemon");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state");
+        let make = || {
+            let mem = SimMemory::new(0, 16384, 64)?;
+            LineScrubber::new(mem, &[(0, 4095)], 64, 4)
+        };
+
+        let daemon = ScrubberDaemon::spawn_persistent(
+            make,
+            4096,
+            Duration::from_millis(1),
+            StateSaver::new(&path, Duration::from_secs(60)),
+        );
+        while daemon.checkpoint().unwrap().stats.passes == 0 {
+            thread::sleep(Duration::from_millis(1));
+        }
+        let passes = daemon.stop().unwrap().passes;
+        assert_eq!(ScrubState::load(&path).unwrap().passes, passes);
+
+        // A new daemon carries on from the saved state
+        let daemon = ScrubberDaemon::spawn_persistent(
+            make,
+            4096,
+            Duration::from_secs(60),
+            StateSaver::new(&path, Duration::from_secs(60)),
+        );
+        assert!(daemon.checkpoint().unwrap().stats.passes >= passes);
+        daemon.stop().unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
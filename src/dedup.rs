@@ -0,0 +1,426 @@
+// Suppressing reads of memory covered by more than one scrub area. The
+// same physical memory can be reachable through several logical views,
+// such as a scrub area over all of RAM and another over the memory of one
+// process, and then each pass reads it once through each view. DedupBackend
+// translates the pages of each view to physical pages as the view is
+// added, and skips reads of pages whose physical memory an earlier view
+// already covers. The scrubber itself is unchanged, so both views keep
+// their own statistics and are reported as scrubbed: the memory behind
+// them is read once a pass, through the first view that covers it.
+//
+// Pages that can't be translated, as when they aren't present, are always
+// read. Translations change as memory is paged and remapped, so the views
+// should be cleared and added again from time to time, such as at the
+// start of each pass. On Linux, PagemapTranslator in os/pagemap.rs
+// translates the addresses of a process.
+
+use crate::backend::*;
+use crate::badblocks::*;
+use crate::base::*;
+use crate::coverage::*;
+
+/// Translates addresses to physical addresses
+pub trait AddressTranslator {
+    /// Returns the physical address of an address, or None if it has none
+    /// or it can't be found
+    fn physical(&mut self, addr: usize) -> Option<u64>;
+
+    /// Translate a run of pages at once, which a translator that looks
+    /// addresses up in a table can do in one read. The default translates
+    /// each page in turn.
+    ///
+    /// # Arguments:
+    /// * `addr` - Address in the first page
+    ///
+    /// * `page_size` - Number of bytes in a page
+    ///
+    /// * `physical` - Set to the physical address of addr plus each
+    ///   multiple of the page size, or None if it has none
+    fn physical_pages(
+        &mut self,
+        addr: usize,
+        page_size: usize,
+        physical: &mut [Option<u64>],
+    ) {
+        translate_each(self, addr, page_size, physical);
+    }
+}
+
+/// Translate a run of pages a page at a time, as the default
+/// AddressTranslator::physical_pages() does
+///
+/// # Arguments:
+/// * `translator` - Translates each address
+///
+/// * `addr` - Address in the first page
+///
+/// * `page_size` - Number of bytes in a page
+///
+/// * `physical` - Set to the physical address of addr plus each multiple
+///   of the page size, or None if it has none
+pub fn translate_each<T: AddressTranslator + ?Sized>(
+    translator: &mut T,
+    addr: usize,
+    page_size: usize,
+    physical: &mut [Option<u64>],
+) {
+    for (i, phys) in physical.iter_mut().enumerate() {
+        *phys = i
+            .checked_mul(page_size)
+            .and_then(|offset| addr.checked_add(offset))
+            .and_then(|addr| translator.physical(addr));
+    }
+}
+
+// Number of pages translated at a time by DedupBackend::add_view()
+const TRANSLATE_PAGES: usize = 512;
+
+impl<F: FnMut(usize) -> Option<u64>> AddressTranslator for F {
+    fn physical(&mut self, addr: usize) -> Option<u64> {
+        self(addr)
+    }
+}
+
+/// How much of a view duplicates memory covered by earlier views
+///
+/// * `pages` - Number of pages in the view
+///
+/// * `untranslated` - Pages with no physical address, which are read
+///
+/// * `duplicates` - Pages whose physical memory an earlier view, or an
+///   earlier page of this one, covers, which are not read
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ViewCoverage {
+    pub pages: usize,
+    pub untranslated: usize,
+    pub duplicates: usize,
+}
+
+/// A backend skipping reads of memory covered by an earlier view
+///
+/// * `backend` - Reads each cache line not skipped
+///
+/// * `page_size` - Number of bytes in a page
+///
+/// * `covered` - (start, end) physical page numbers covered by the views,
+///   sorted, end inclusive
+///
+/// * `duplicates` - (start, end) of each range skipped, sorted, end
+///   inclusive
+///
+/// * `lines_suppressed` - Number of cache line reads skipped
+pub struct DedupBackend<B: ScrubBackend> {
+    backend: B,
+    page_size: usize,
+    covered: Vec<(u64, u64)>,
+    duplicates: Vec<(usize, usize)>,
+    lines_suppressed: u64,
+}
+
+impl<B: ScrubBackend> DedupBackend<B> {
+    /// Create a DedupBackend with no views, which reads every address
+    ///
+    /// # Arguments:
+    /// * `backend` - Reads each cache line not skipped
+    ///
+    /// * `page_size` - Number of bytes in a page, a power of two
+    ///
+    /// # Returns:
+    /// Ok(DedupBackend) on success, otherwise Err(Error::UnalignedValue)
+    /// if the page size isn't a power of two
+    pub fn new(
+        backend: B,
+        page_size: usize,
+    ) -> Result<DedupBackend<B>, Error> {
+        if !page_size.is_power_of_two() {
+            return Err(Error::UnalignedValue);
+        }
+        Ok(DedupBackend {
+            backend,
+            page_size,
+            covered: Vec::new(),
+            duplicates: Vec::new(),
+            lines_suppressed: 0,
+        })
+    }
+
+    /// Add a view of memory. Views are added in order of preference: a
+    /// page is read through the first view covering its physical memory.
+    ///
+    /// # Arguments:
+    /// * `start` - First address of the view
+    ///
+    /// * `end` - Last address of the view
+    ///
+    /// * `translator` - Translates the addresses of the view
+    ///
+    /// # Returns:
+    /// Ok(ViewCoverage) with how much of the view duplicates earlier
+    /// views, otherwise Err(Error::EmptyMemArea) if end is before start
+    pub fn add_view(
+        &mut self,
+        start: usize,
+        end: usize,
+        translator: &mut dyn AddressTranslator,
+    ) -> Result<ViewCoverage, Error> {
+        if start > end {
+            return Err(Error::EmptyMemArea);
+        }
+
+        // The pages are translated a batch at a time, the first from the
+        // start of the view and the rest from the start of each page
+        let mask = self.page_size - 1;
+        let mut coverage = ViewCoverage::default();
+        let mut physical = [None; TRANSLATE_PAGES];
+        let mut page = start & !mask;
+        let mut first = start;
+        loop {
+            let left = (end - page) / self.page_size + 1;
+            let batch = &mut physical[..left.min(TRANSLATE_PAGES)];
+            translator.physical_pages(first, self.page_size, batch);
+            for &phys in batch.iter() {
+                coverage.pages += 1;
+                let first = page.max(start);
+                let last = page.saturating_add(mask).min(end);
+                match phys {
+                    None => coverage.untranslated += 1,
+                    Some(phys) => {
+                        let frame = phys / self.page_size as u64;
+                        if self.is_covered(frame) {
+                            coverage.duplicates += 1;
+                            add_range(&mut self.duplicates, (first, last));
+                        } else {
+                            add_range(&mut self.covered, (frame, frame));
+                        }
+                    }
+                }
+                page = match page.checked_add(self.page_size) {
+                    Some(page) if page <= end => page,
+                    _ => return Ok(coverage),
+                };
+            }
+            first = page;
+        }
+    }
+
+    /// Forget every view, so that every address is read until views are
+    /// added again
+    pub fn clear(&mut self) {
+        self.covered.clear();
+        self.duplicates.clear();
+    }
+
+    /// Returns the ranges skipped, sorted by address
+    pub fn duplicates(&self) -> &[(usize, usize)] {
+        &self.duplicates
+    }
+
+    /// Returns whether reads of an address are skipped
+    pub fn is_duplicate(&self, addr: usize) -> bool {
+        let i = self.duplicates.partition_point(|&(_, e)| e < addr);
+        self.duplicates.get(i).is_some_and(|&(s, _)| s <= addr)
+    }
+
+    /// Returns the number of cache line reads skipped
+    pub fn lines_suppressed(&self) -> u64 {
+        self.lines_suppressed
+    }
+
+    /// Returns the backend reading each cache line
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    // Returns whether a physical page is covered by a view
+    fn is_covered(&self, frame: u64) -> bool {
+        let i = self.covered.partition_point(|&(_, e)| e < frame);
+        self.covered.get(i).is_some_and(|&(s, _)| s <= frame)
+    }
+}
+
+impl<B: ScrubBackend> ScrubBackend for DedupBackend<B> {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        match self.is_duplicate(addr) {
+            true => {
+                self.lines_suppressed += 1;
+                Ok(())
+            }
+            false => self.backend.read_line(addr),
+        }
+    }
+
+    fn read_words(
+        &mut self,
+        addr: usize,
+        line_size: usize,
+        reads: usize,
+    ) -> Result<(), Error> {
+        match self.is_duplicate(addr) {
+            true => {
+                self.lines_suppressed += 1;
+                Ok(())
+            }
+            false => self.backend.read_words(addr, line_size, reads),
+        }
+    }
+
+    fn begin_batch(&mut self) -> Result<(), Error> {
+        self.backend.begin_batch()
+    }
+
+    fn end_batch(&mut self) -> Result<(), Error> {
+        self.backend.end_batch()
+    }
+
+    fn end_window(&mut self) -> Result<(), Error> {
+        self.backend.end_window()
+    }
+
+    fn window_size(&self) -> Option<usize> {
+        self.backend.window_size()
+    }
+
+    fn flush_line(&mut self, addr: usize) -> Result<(), Error> {
+        self.backend.flush_line(addr)
+    }
+
+    // A line skipped here is never given to read_words(), so it is counted
+    // as suppressed here instead
+    fn skip_reason(&mut self, addr: usize) -> Option<SkipReason> {
+        match self.is_duplicate(addr) {
+            true => {
+                self.lines_suppressed += 1;
+                Some(SkipReason::Duplicate)
+            }
+            false => self.backend.skip_reason(addr),
+        }
+    }
+
+    fn excluded_lines(&self, start: usize, end: usize) -> Option<u64> {
+        self.backend.excluded_lines(start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::*;
+
+    #[test]
+    fn test_views() {
+        // RAM is 16 pages at physical address 0 and seen directly at
+        // 0x10000. A process maps physical pages 2 and 3 at 0x40000, and
+        // physical page 2 again at 0x42000; its page at 0x43000 is not
+        // present.
+        let mem = SimMemory::new(0x10000, 0x50000, 64).unwrap();
+        let mut backend = DedupBackend::new(mem, 4096).unwrap();
+        let mut ram = |addr: usize| Some(addr as u64 - 0x10000);
+        let mut process = |addr: usize| match addr >> 12 {
+            0x40 => Some(0x2000 + (addr & 0xfff) as u64),
+            0x41 => Some(0x3000 + (addr & 0xfff) as u64),
+            0x42 => Some(0x2000 + (addr & 0xfff) as u64),
+            _ => None,
+        };
+
+        let ram_view =
+            backend.add_view(0x10000, 0x1ffff, &mut ram).unwrap();
+        assert_eq!(
+            ram_view,
+            ViewCoverage {
+                pages: 16,
+                untranslated: 0,
+                duplicates: 0
+            }
+        );
+        let process_view =
+            backend.add_view(0x40000, 0x43fff, &mut process).unwrap();
+        assert_eq!(
+            process_view,
+            ViewCoverage {
+                pages: 4,
+                untranslated: 1,
+                duplicates: 3
+            }
+        );
+        assert_eq!(backend.duplicates(), [(0x40000, 0x42fff)]);
+
+        // Both views are scrubbed, but the shared memory is read once
+        let mut scrubber = LineScrubber::new(
+            backend,
+            &[(0x10000, 0x1ffff), (0x40000, 0x43fff)],
+            64,
+            4,
+        )
+        .unwrap();
+        scrubber.set_coverage_tracking(1).unwrap();
+        scrubber.scrub(0x14000).unwrap();
+        assert_eq!(scrubber.stats().passes, 1);
+        let backend = scrubber.backend();
+        assert_eq!(backend.backend().reads_at(0x12000), Some(1));
+        assert_eq!(backend.backend().reads_at(0x40000), Some(0));
+        assert_eq!(backend.backend().reads_at(0x43000), Some(1));
+        assert_eq!(backend.lines_suppressed(), 3 * 64);
+
+        // The lines not read are left out of the pass's coverage, with
+        // scrub_pass_fast() too
+        let duplicate = Some(SkipReason::Duplicate);
+        let coverage = &scrubber.coverage_history()[0];
+        assert_eq!(coverage.bytes(duplicate), 0x3000);
+        assert_eq!(scrubber.scrub_pass_fast(), Ok(0x14000));
+        let coverage = &scrubber.coverage_history()[0];
+        assert_eq!(
+            (coverage.epoch, coverage.bytes(duplicate)),
+            (2, 0x3000)
+        );
+        assert_eq!(scrubber.backend().lines_suppressed(), 6 * 64);
+
+        let mut backend =
+            DedupBackend::new(SimMemory::new(0, 4096, 64).unwrap(), 4096)
+                .unwrap();
+        assert_eq!(
+            backend.add_view(4096, 0, &mut ram),
+            Err(Error::EmptyMemArea)
+        );
+        backend.clear();
+        assert!(!backend.is_duplicate(0x40000));
+        assert!(DedupBackend::new(backend, 1000).is_err());
+    }
+
+    #[test]
+    fn test_translate_batches() {
+        struct Batches(Vec<(usize, usize)>);
+
+        impl AddressTranslator for Batches {
+            fn physical(&mut self, addr: usize) -> Option<u64> {
+                Some(addr as u64)
+            }
+
+            fn physical_pages(
+                &mut self,
+                addr: usize,
+                page_size: usize,
+                physical: &mut [Option<u64>],
+            ) {
+                self.0.push((addr, physical.len()));
+                translate_each(self, addr, page_size, physical);
+            }
+        }
+
+        // A view part way into its first page is translated from its
+        // start, and then from the start of the page after each batch
+        let mut backend =
+            DedupBackend::new(SimMemory::new(0, 4096, 64).unwrap(), 4096)
+                .unwrap();
+        let mut batches = Batches(Vec::new());
+        let pages = TRANSLATE_PAGES + 88;
+        let view = backend
+            .add_view(0x800, pages * 4096 - 1, &mut batches)
+            .unwrap();
+        assert_eq!(view.pages, pages);
+        assert_eq!(view.duplicates, 0);
+        assert_eq!(
+            batches.0,
+            [(0x800, TRANSLATE_PAGES), (TRANSLATE_PAGES * 4096, 88)]
+        );
+    }
+}
@@ -0,0 +1,282 @@
+// Ready-made AutoScrubDesc implementations for the common ways of deciding
+// how much to scrub:
+//
+//  FixedTotal      Scrub a fixed number of bytes, in chunks, then stop
+//  Forever         Scrub a chunk at a time without stopping
+//  TimeSliced      Scrub a chunk once per interval, optionally for a
+//                  limited time
+//  UntilCancelled  Scrub a chunk at a time until a CancelToken is cancelled
+//
+// Each descriptor's next_chunk() returns the number of bytes to scrub next,
+// with zero meaning stop, and AutoScrubDesc::next() returns the same value
+// as an Addr.
+
+use std::time::{Duration, Instant};
+
+use crate::addr::*;
+use crate::base::*;
+use crate::clock::*;
+use crate::data::*;
+use crate::sync::{Arc, AtomicBool, Ordering};
+
+// Implement AutoScrubDesc for a descriptor with a next_chunk() method
+macro_rules! impl_auto_scrub_desc {
+    ($t:ident $(< $g:ident : $b:ident >)?) => {
+        impl<const N: usize, const S: usize, const W: usize, D, A
+            $(, $g: $b)?> AutoScrubDesc<N, S, W, D, A> for $t $(<$g>)?
+        where
+            D: DataImplTrait<D>,
+            A: AddrImplTrait<A>,
+        {
+            fn next(&mut self) -> Addr<A> {
+                self.next_chunk().into()
+            }
+        }
+    };
+}
+
+/// Scrubs a fixed total number of bytes, then stops
+///
+/// * `remaining` - Number of bytes left to scrub
+///
+/// * `chunk` - Largest number of bytes to scrub at a time
+#[derive(Clone, Debug, PartialEq)]
+pub struct FixedTotal {
+    remaining: usize,
+    chunk: usize,
+}
+
+impl FixedTotal {
+    /// Scrub total bytes in a single chunk
+    pub fn new(total: usize) -> FixedTotal {
+        FixedTotal::with_chunk(total, total)
+    }
+
+    /// Scrub total bytes, chunk bytes at a time
+    pub fn with_chunk(total: usize, chunk: usize) -> FixedTotal {
+        FixedTotal {
+            remaining: total,
+            chunk,
+        }
+    }
+
+    /// Returns the number of bytes to scrub next, or zero to stop
+    pub fn next_chunk(&mut self) -> usize {
+        let n = self.remaining.min(self.chunk);
+        self.remaining -= n;
+        n
+    }
+}
+
+impl_auto_scrub_desc!(FixedTotal);
+
+/// Scrubs a chunk at a time, forever
+///
+/// * `chunk` - Number of bytes to scrub at a time
+#[derive(Clone, Debug, PartialEq)]
+pub struct Forever {
+    chunk: usize,
+}
+
+impl Forever {
+    /// Scrub chunk bytes at a time
+    pub fn new(chunk: usize) -> Forever {
+        Forever { chunk }
+    }
+
+    /// Returns the number of bytes to scrub next
+    pub fn next_chunk(&mut self) -> usize {
+        self.chunk
+    }
+}
+
+impl_auto_scrub_desc!(Forever);
+
+/// Scrubs a chunk once per interval. Each call to next_chunk() waits until
+/// the next interval starts. If scrubbing falls behind, chunks are not
+/// saved up; the next one is simply scrubbed right away.
+///
+/// * `chunk` - Number of bytes to scrub each interval
+///
+/// * `interval` - Time between the starts of chunks
+///
+/// * `clock` - Source of time
+///
+/// * `next` - When the next chunk is due, or None before the first
+///
+/// * `end` - Time after which scrubbing stops, if limited
+pub struct TimeSliced<C: Clock> {
+    chunk: usize,
+    interval: Duration,
+    clock: C,
+    next: Option<Instant>,
+    end: Option<Instant>,
+}
+
+impl<C: Clock> TimeSliced<C> {
+    /// Create a new TimeSliced that scrubs without a time limit
+    ///
+    /// # Arguments:
+    /// * `chunk` - Number of bytes to scrub each interval
+    ///
+    /// * `interval` - Time between the starts of chunks
+    ///
+    /// * `clock` - Source of time
+    pub fn new(
+        chunk: usize,
+        interval: Duration,
+        clock: C,
+    ) -> TimeSliced<C> {
+        TimeSliced {
+            chunk,
+            interval,
+            clock,
+            next: None,
+            end: None,
+        }
+    }
+
+    /// Stop scrubbing once the given time has passed, counted from now
+    pub fn for_duration(mut self, duration: Duration) -> TimeSliced<C> {
+        self.end = Some(self.clock.now() + duration);
+        self
+    }
+
+    /// Returns the number of bytes to scrub next, or zero to stop
+    pub fn next_chunk(&mut self) -> usize {
+        let now = self.clock.now();
+        let due = match self.next {
+            None => now,
+            Some(next) if next > now => {
+                self.clock.sleep(next - now);
+                next
+            }
+            Some(_) => now,
+        };
+
+        if let Some(end) = self.end {
+            if due >= end {
+                return 0;
+            }
+        }
+
+        self.next = Some(due + self.interval);
+        self.chunk
+    }
+}
+
+impl_auto_scrub_desc!(TimeSliced<C: Clock>);
+
+/// A flag used to ask scrubbing to stop. Clones share the flag, so one can
+/// be kept to cancel scrubbing from another thread.
+#[derive(Clone, Debug)]
+pub struct CancelToken {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelToken {
+    /// Create a token that has not been cancelled
+    pub fn new() -> CancelToken {
+        CancelToken {
+            cancelled: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Ask scrubbing to stop
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::Release);
+    }
+
+    /// Returns whether cancel() has been called
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::Acquire)
+    }
+}
+
+impl Default for CancelToken {
+    fn default() -> CancelToken {
+        CancelToken::new()
+    }
+}
+
+/// Scrubs a chunk at a time until cancelled. The chunk being scrubbed when
+/// the token is cancelled is completed.
+///
+/// * `token` - Token checked before each chunk
+///
+/// * `chunk` - Number of bytes to scrub at a time
+#[derive(Clone, Debug)]
+pub struct UntilCancelled {
+    token: CancelToken,
+    chunk: usize,
+}
+
+impl UntilCancelled {
+    /// Scrub chunk bytes at a time until token is cancelled
+    pub fn new(token: CancelToken, chunk: usize) -> UntilCancelled {
+        UntilCancelled { token, chunk }
+    }
+
+    /// Returns the number of bytes to scrub next, or zero to stop
+    pub fn next_chunk(&mut self) -> usize {
+        match self.token.is_cancelled() {
+            true => 0,
+            false => self.chunk,
+        }
+    }
+}
+
+impl_auto_scrub_desc!(UntilCancelled);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fixed_total() {
+        let mut desc = FixedTotal::with_chunk(250, 100);
+        let chunks: Vec<usize> =
+            (0..4).map(|_| desc.next_chunk()).collect();
+        assert_eq!(chunks, vec![100, 100, 50, 0]);
+
+        let mut desc = FixedTotal::new(64);
+        assert_eq!(desc.next_chunk(), 64);
+        assert_eq!(desc.next_chunk(), 0);
+    }
+
+    #[test]
+    fn test_forever() {
+        let mut desc = Forever::new(4096);
+        assert!((0..1000).all(|_| desc.next_chunk() == 4096));
+    }
+
+    #[test]
+    fn test_time_sliced() {
+        let clock = VirtualClock::new();
+        let start = clock.now();
+        let interval = Duration::from_secs(10);
+        let mut desc = TimeSliced::new(64, interval, clock)
+            .for_duration(Duration::from_secs(25));
+
+        assert_eq!(desc.next_chunk(), 64);
+        assert_eq!(desc.clock.now(), start);
+        assert_eq!(desc.next_chunk(), 64);
+        assert_eq!(desc.clock.now(), start + interval);
+
+        // A late chunk is scrubbed at once
+        desc.clock.sleep(Duration::from_secs(12));
+        assert_eq!(desc.next_chunk(), 64);
+        assert_eq!(desc.clock.now(), start + Duration::from_secs(22));
+
+        assert_eq!(desc.next_chunk(), 0);
+    }
+
+    #[test]
+    fn test_until_cancelled() {
+        let token = CancelToken::new();
+        let mut desc = UntilCancelled::new(token.clone(), 128);
+        assert_eq!(desc.next_chunk(), 128);
+        token.cancel();
+        assert_eq!(desc.next_chunk(), 0);
+    }
+}
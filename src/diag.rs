@@ -0,0 +1,174 @@
+// Internal diagnostics. Some failures can't be allowed to stop scrubbing,
+// such as a history row that can't be written or an error with nobody
+// listening for it, and some code is still being filled in. Rather than
+// printing, which is unwelcome in a daemon and impossible on many embedded
+// targets, such conditions are reported to a Diagnostics sink. The sink is
+// shared by the whole process and does nothing until one is installed
+// with set_diagnostics(). Adapters for the log and tracing crates are
+// provided with the features of the same names.
+//
+// Messages are passed as fmt::Arguments, so nothing is formatted unless
+// the sink formats it.
+
+use std::fmt;
+use std::sync::RwLock;
+
+/// How serious a diagnostic is
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum DiagLevel {
+    /// Something failed and was given up on
+    Error,
+    /// Something failed but was worked around
+    Warning,
+    /// Something worth knowing about happened
+    Info,
+    /// Detail for debugging the library itself
+    Debug,
+}
+
+/// Receiver for internal diagnostics
+pub trait Diagnostics: Send + Sync {
+    /// Called for each diagnostic
+    ///
+    /// # Arguments:
+    /// * `level` - How serious the diagnostic is
+    ///
+    /// * `message` - What happened
+    fn diagnostic(&self, level: DiagLevel, message: fmt::Arguments<'_>);
+}
+
+/// Diagnostics that are dropped, the default
+#[derive(Clone, Copy, Debug, Default)]
+pub struct NullDiagnostics;
+
+impl Diagnostics for NullDiagnostics {
+    fn diagnostic(&self, _level: DiagLevel, _message: fmt::Arguments<'_>) {
+    }
+}
+
+/// Diagnostics sent to the log crate, with the target "memscrub"
+#[cfg(feature = "log")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogDiagnostics;
+
+#[cfg(feature = "log")]
+impl Diagnostics for LogDiagnostics {
+    fn diagnostic(&self, level: DiagLevel, message: fmt::Arguments<'_>) {
+        let level = match level {
+            DiagLevel::Error => log::Level::Error,
+            DiagLevel::Warning => log::Level::Warn,
+            DiagLevel::Info => log::Level::Info,
+            DiagLevel::Debug => log::Level::Debug,
+        };
+        log::log!(target: "memscrub", level, "{}", message);
+    }
+}
+
+/// Diagnostics sent to the tracing crate as events with the target
+/// "memscrub"
+#[cfg(feature = "tracing")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct TracingDiagnostics;
+
+#[cfg(feature = "tracing")]
+impl Diagnostics for TracingDiagnostics {
+    fn diagnostic(&self, level: DiagLevel, message: fmt::Arguments<'_>) {
+        match level {
+            DiagLevel::Error => {
+                tracing::error!(target: "memscrub", "{}", message)
+            }
+            DiagLevel::Warning => {
+                tracing::warn!(target: "memscrub", "{}", message)
+            }
+            DiagLevel::Info => {
+                tracing::info!(target: "memscrub", "{}", message)
+            }
+            DiagLevel::Debug => {
+                tracing::debug!(target: "memscrub", "{}", message)
+            }
+        }
+    }
+}
+
+static DIAGNOSTICS: RwLock<&'static dyn Diagnostics> =
+    RwLock::new(&NullDiagnostics);
+
+/// Install the sink for diagnostics from the whole process, replacing any
+/// installed before. The sink is never dropped, so this is meant to be
+/// called once, at startup.
+pub fn set_diagnostics(diagnostics: Box<dyn Diagnostics>) {
+    let diagnostics: &'static dyn Diagnostics = Box::leak(diagnostics);
+    match DIAGNOSTICS.write() {
+        Ok(mut sink) => *sink = diagnostics,
+        Err(poisoned) => *poisoned.into_inner() = diagnostics,
+    }
+}
+
+/// Send a diagnostic to the installed sink
+///
+/// # Arguments:
+/// * `level` - How serious the diagnostic is
+///
+/// * `message` - What happened
+pub fn diagnostic(level: DiagLevel, message: fmt::Arguments<'_>) {
+    let sink = match DIAGNOSTICS.read() {
+        Ok(sink) => *sink,
+        Err(poisoned) => *poisoned.into_inner(),
+    };
+    sink.diagnostic(level, message);
+}
+
+// Send a diagnostic to the installed sink, formatted as with format!()
+macro_rules! diag {
+    ($level:ident, $($arg:tt)+) => {
+        $crate::diag::diagnostic(
+            $crate::diag::DiagLevel::$level,
+            format_args!($($arg)+),
+        )
+    };
+}
+pub(crate) use diag;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    // Keeps the diagnostics from this module's tests. Other tests may
+    // report diagnostics at the same time, so only those with the
+    // expected text are looked at.
+    struct Recorder(Mutex<Vec<(DiagLevel, String)>>);
+
+    static RECORDED: Recorder = Recorder(Mutex::new(Vec::new()));
+
+    impl Diagnostics for &'static Recorder {
+        fn diagnostic(
+            &self,
+            level: DiagLevel,
+            message: fmt::Arguments<'_>,
+        ) {
+            self.0.lock().unwrap().push((level, message.to_string()));
+        }
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        diag!(Info, "dropped {}", 1);
+        set_diagnostics(Box::new(&RECORDED));
+        diag!(Warning, "recorded {}", 2);
+        set_diagnostics(Box::new(NullDiagnostics));
+        diag!(Info, "dropped {}", 3);
+
+        let recorded = RECORDED.0.lock().unwrap();
+        let ours: Vec<&(DiagLevel, String)> = recorded
+            .iter()
+            .filter(|(_, m)| {
+                m.starts_with("recorded ") || m.starts_with("dropped ")
+            })
+            .collect();
+        assert_eq!(
+            ours,
+            [&(DiagLevel::Warning, "recorded 2".to_string())]
+        );
+    }
+}
@@ -0,0 +1,487 @@
+// Scrubbing memory shared with devices. Buffers mapped for DMA through an
+// IOMMU or SMMU are ordinary RAM and need scrubbing like any other, but a
+// cached read can break the coherency a driver relies on: on a platform
+// where device writes don't snoop the cache, a line the scrubber brings
+// into the cache can hide data a device writes later, and a driver
+// invalidating its buffer may not expect lines it never touched. Each DMA
+// buffer is therefore registered with how it may be read:
+//
+//  * Coherent buffers, kept coherent by the hardware, are read normally.
+//  * Invalidate buffers are read normally, then each line is invalidated
+//    from the cache, as by flush_line().
+//  * NoAllocate buffers are read through a second backend that doesn't
+//    allocate cache lines, such as an AliasBackend over an uncached or
+//    write-combining mapping.
+//
+// Memory outside every registered buffer is read normally. Reading any
+// byte of a cache line brings all of it into the cache, so buffers must
+// start and end on cache line boundaries, leaving no line partly in a
+// buffer and partly out.
+
+use crate::arch::*;
+use crate::backend::*;
+use crate::base::*;
+use crate::coverage::*;
+
+/// How the memory of a DMA buffer may be read
+///
+/// * `Coherent` - The hardware keeps the cache coherent with devices, so
+///   the buffer is read normally
+///
+/// * `Invalidate` - Each line is read normally, then invalidated
+///
+/// * `NoAllocate` - Each line is read without allocating it in the cache
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DmaCoherency {
+    Coherent,
+    Invalidate,
+    NoAllocate,
+}
+
+/// A buffer devices access through DMA
+///
+/// * `start` - First address of the buffer
+///
+/// * `end` - Last address of the buffer
+///
+/// * `coherency` - How the buffer may be read
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DmaBuffer {
+    pub start: usize,
+    pub end: usize,
+    pub coherency: DmaCoherency,
+}
+
+/// A hook invalidating the cache line at an address
+pub type InvalidateHook = Box<dyn FnMut(usize) -> Result<(), Error>>;
+
+/// A backend reading each DMA buffer as its coherency allows
+///
+/// * `backend` - Reads memory outside DMA buffers, and Coherent and
+///   Invalidate buffers
+///
+/// * `line_size` - Number of bytes in a cache line
+///
+/// * `buffers` - The DMA buffers, sorted by address
+///
+/// * `no_allocate` - Reads NoAllocate buffers, if set
+///
+/// * `invalidate` - Invalidates each line of Invalidate buffers after it
+///   is read, if set
+pub struct DmaBackend<B: ScrubBackend> {
+    backend: B,
+    line_size: usize,
+    buffers: Vec<DmaBuffer>,
+    no_allocate: Option<Box<dyn ScrubBackend>>,
+    invalidate: Option<InvalidateHook>,
+}
+
+impl<B: ScrubBackend> DmaBackend<B> {
+    /// Create a DmaBackend with no DMA buffers, which reads every address
+    /// normally
+    ///
+    /// # Arguments:
+    /// * `backend` - Reads memory outside DMA buffers, and Coherent and
+    ///   Invalidate buffers
+    ///
+    /// * `line_size` - Number of bytes in a cache line, a power of two
+    ///
+    /// # Returns:
+    /// Ok(DmaBackend) on success, otherwise Err(Error::UnalignedValue) if
+    /// the line size isn't a power of two
+    pub fn new(
+        backend: B,
+        line_size: usize,
+    ) -> Result<DmaBackend<B>, Error> {
+        if !line_size.is_power_of_two() {
+            return Err(Error::UnalignedValue);
+        }
+        Ok(DmaBackend {
+            backend,
+            line_size,
+            buffers: Vec::new(),
+            no_allocate: None,
+            invalidate: None,
+        })
+    }
+
+    /// Register a DMA buffer
+    ///
+    /// # Arguments:
+    /// * `start` - First address of the buffer
+    ///
+    /// * `end` - Last address of the buffer
+    ///
+    /// * `coherency` - How the buffer may be read
+    ///
+    /// # Returns:
+    /// Ok(()) on success, Err(Error::EmptyMemArea) if end is before start,
+    /// Err(Error::UnalignedStart) or Err(Error::UnalignedEnd) if the
+    /// buffer doesn't start or end on a cache line boundary, or
+    /// Err(Error::Overlap) if it overlaps a buffer already registered
+    pub fn add_buffer(
+        &mut self,
+        start: usize,
+        end: usize,
+        coherency: DmaCoherency,
+    ) -> Result<(), Error> {
+        if start > end {
+            return Err(Error::EmptyMemArea);
+        }
+        if !start.is_multiple_of(self.line_size) {
+            return Err(Error::UnalignedStart);
+        }
+        if !end.wrapping_add(1).is_multiple_of(self.line_size) {
+            return Err(Error::UnalignedEnd);
+        }
+        if self
+            .buffers
+            .iter()
+            .any(|b| b.start <= end && start <= b.end)
+        {
+            return Err(Error::Overlap);
+        }
+
+        let i = self.buffers.partition_point(|b| b.start < start);
+        self.buffers.insert(
+            i,
+            DmaBuffer {
+                start,
+                end,
+                coherency,
+            },
+        );
+        Ok(())
+    }
+
+    /// Unregister the DMA buffer starting at an address, as when a driver
+    /// unmaps it
+    ///
+    /// # Returns:
+    /// The buffer, or None if no buffer starts at the address
+    pub fn remove_buffer(&mut self, start: usize) -> Option<DmaBuffer> {
+        let i = self.buffers.iter().position(|b| b.start == start)?;
+        Some(self.buffers.remove(i))
+    }
+
+    /// Returns the DMA buffers
+    pub fn buffers(&self) -> &[DmaBuffer] {
+        &self.buffers
+    }
+
+    /// Returns how the memory at an address may be read, Coherent if it
+    /// is in no DMA buffer
+    pub fn coherency(&self, addr: usize) -> DmaCoherency {
+        let i = self.buffers.partition_point(|b| b.end < addr);
+        match self.buffers.get(i) {
+            Some(b) if b.start <= addr => b.coherency,
+            _ => DmaCoherency::Coherent,
+        }
+    }
+
+    /// Set the backend reading NoAllocate buffers. It is given the same
+    /// addresses as this backend.
+    pub fn set_no_allocate(
+        &mut self,
+        backend: Option<Box<dyn ScrubBackend>>,
+    ) {
+        self.no_allocate = backend;
+    }
+
+    /// Set the hook invalidating each line of Invalidate buffers after it
+    /// is read
+    pub fn set_invalidate(&mut self, hook: Option<InvalidateHook>) {
+        self.invalidate = hook;
+    }
+
+    /// Invalidate lines of Invalidate buffers with flush_line()
+    ///
+    /// # Safety
+    /// Every Invalidate buffer must stay mapped at its address in this
+    /// process for as long as the hook is set
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::InternalError) if this
+    /// architecture can't flush a cache line
+    pub unsafe fn use_flush_line(&mut self) -> Result<(), Error> {
+        if !cfg!(any(target_arch = "x86_64", target_arch = "aarch64")) {
+            return Err(Error::InternalError);
+        }
+        // The caller promises the lines read are mapped
+        self.invalidate = Some(Box::new(|addr| {
+            unsafe { flush_line(addr) };
+            Ok(())
+        }));
+        Ok(())
+    }
+
+    /// Returns the backend reading everything but NoAllocate buffers
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    // Invalidate a line after reading it, even if the read failed
+    fn invalidate_after(
+        &mut self,
+        addr: usize,
+        result: Result<(), Error>,
+    ) -> Result<(), Error> {
+        let invalidated = match self.invalidate.as_mut() {
+            Some(hook) => hook(addr),
+            None => Err(Error::InternalError),
+        };
+        result.and(invalidated)
+    }
+}
+
+impl<B: ScrubBackend> ScrubBackend for DmaBackend<B> {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        match self.coherency(addr) {
+            DmaCoherency::Coherent => self.backend.read_line(addr),
+            DmaCoherency::Invalidate => {
+                if self.invalidate.is_none() {
+                    return Err(Error::InternalError);
+                }
+                let result = self.backend.read_line(addr);
+                self.invalidate_after(addr, result)
+            }
+            DmaCoherency::NoAllocate => match self.no_allocate.as_mut() {
+                Some(backend) => backend.read_line(addr),
+                None => Err(Error::InternalError),
+            },
+        }
+    }
+
+    fn read_words(
+        &mut self,
+        addr: usize,
+        line_size: usize,
+        reads: usize,
+    ) -> Result<(), Error> {
+        match self.coherency(addr) {
+            DmaCoherency::Coherent => {
+                self.backend.read_words(addr, line_size, reads)
+            }
+            // The line is only invalidated once all of it has been read
+            DmaCoherency::Invalidate => {
+                if self.invalidate.is_none() {
+                    return Err(Error::InternalError);
+                }
+                let result =
+                    self.backend.read_words(addr, line_size, reads);
+                self.invalidate_after(addr, result)
+            }
+            DmaCoherency::NoAllocate => match self.no_allocate.as_mut() {
+                Some(backend) => {
+                    backend.read_words(addr, line_size, reads)
+                }
+                None => Err(Error::InternalError),
+            },
+        }
+    }
+
+    fn begin_batch(&mut self) -> Result<(), Error> {
+        if let Some(backend) = self.no_allocate.as_mut() {
+            backend.begin_batch()?;
+        }
+        self.backend.begin_batch()
+    }
+
+    fn end_batch(&mut self) -> Result<(), Error> {
+        let result = self.backend.end_batch();
+        match self.no_allocate.as_mut() {
+            Some(backend) => result.and(backend.end_batch()),
+            None => result,
+        }
+    }
+
+    fn end_window(&mut self) -> Result<(), Error> {
+        let result = self.backend.end_window();
+        match self.no_allocate.as_mut() {
+            Some(backend) => result.and(backend.end_window()),
+            None => result,
+        }
+    }
+
+    fn window_size(&self) -> Option<usize> {
+        self.backend.window_size()
+    }
+
+    fn flush_line(&mut self, addr: usize) -> Result<(), Error> {
+        match (self.coherency(addr), self.no_allocate.as_mut()) {
+            (DmaCoherency::NoAllocate, Some(backend)) => {
+                backend.flush_line(addr)
+            }
+            _ => self.backend.flush_line(addr),
+        }
+    }
+
+    fn skip_reason(&mut self, addr: usize) -> Option<SkipReason> {
+        match (self.coherency(addr), self.no_allocate.as_mut()) {
+            (DmaCoherency::NoAllocate, Some(backend)) => {
+                backend.skip_reason(addr)
+            }
+            _ => self.backend.skip_reason(addr),
+        }
+    }
+
+    fn excluded_lines(&self, start: usize, end: usize) -> Option<u64> {
+        self.backend.excluded_lines(start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Records reads, tagged with the backend making them
+    fn dma_backend(log: &Rc<RefCell<Vec<String>>>) -> DmaBackend<Logger> {
+        let mut backend =
+            DmaBackend::new(Logger("read", log.clone()), 64).unwrap();
+        backend
+            .add_buffer(0x1000, 0x10ff, DmaCoherency::Invalidate)
+            .unwrap();
+        backend
+            .add_buffer(0x2000, 0x20ff, DmaCoherency::NoAllocate)
+            .unwrap();
+        backend
+            .add_buffer(0x3000, 0x30ff, DmaCoherency::Coherent)
+            .unwrap();
+        backend
+    }
+
+    #[test]
+    fn test_buffers() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut backend = dma_backend(&log);
+        assert_eq!(
+            backend.add_buffer(0x1080, 0x117f, DmaCoherency::Coherent),
+            Err(Error::Overlap)
+        );
+
+        // No line may be partly in a buffer
+        assert_eq!(
+            backend.add_buffer(0x4010, 0x40ff, DmaCoherency::Coherent),
+            Err(Error::UnalignedStart)
+        );
+        assert_eq!(
+            backend.add_buffer(0x4000, 0x40f0, DmaCoherency::Coherent),
+            Err(Error::UnalignedEnd)
+        );
+        assert!(DmaBackend::new(Recorder::default(), 48).is_err());
+        assert_eq!(
+            backend.add_buffer(0x5000, 0x4fff, DmaCoherency::Coherent),
+            Err(Error::EmptyMemArea)
+        );
+        assert_eq!(backend.coherency(0x1040), DmaCoherency::Invalidate);
+        assert_eq!(backend.coherency(0x20ff), DmaCoherency::NoAllocate);
+        assert_eq!(backend.coherency(0x2100), DmaCoherency::Coherent);
+
+        let removed = backend.remove_buffer(0x2000).unwrap();
+        assert_eq!(removed.coherency, DmaCoherency::NoAllocate);
+        assert_eq!(backend.coherency(0x2000), DmaCoherency::Coherent);
+        assert_eq!(backend.remove_buffer(0x2000), None);
+        assert_eq!(backend.buffers().len(), 2);
+    }
+
+    #[test]
+    fn test_reads() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut backend = dma_backend(&log);
+
+        // Without a hook or a non-allocating backend, those buffers can't
+        // be read
+        assert_eq!(backend.read_line(0x1000), Err(Error::InternalError));
+        assert_eq!(backend.read_line(0x2000), Err(Error::InternalError));
+        assert!(log.borrow().is_empty());
+
+        let invalidated = log.clone();
+        backend.set_invalidate(Some(Box::new(move |addr| {
+            invalidated.borrow_mut().push(format!("inval {:#x}", addr));
+            Ok(())
+        })));
+        backend.set_no_allocate(Some(Box::new(Logger(
+            "uncached",
+            log.clone(),
+        ))));
+
+        backend.read_line(0x0).unwrap();
+        backend.read_line(0x1040).unwrap();
+        backend.read_line(0x2040).unwrap();
+        backend.read_line(0x3040).unwrap();
+        assert_eq!(
+            *log.borrow(),
+            [
+                "read 0x0",
+                "read 0x1040",
+                "inval 0x1040",
+                "uncached 0x2040",
+                "read 0x3040"
+            ]
+        );
+
+        // A line read in words is invalidated once, after the last read
+        log.borrow_mut().clear();
+        backend.read_words(0x1080, 64, 2).unwrap();
+        assert_eq!(
+            *log.borrow(),
+            ["read 0x1080", "read 0x10a0", "inval 0x1080"]
+        );
+    }
+
+    #[test]
+    fn test_scrub() {
+        let log = Rc::new(RefCell::new(Vec::new()));
+        let mut backend = dma_backend(&log);
+        backend.set_invalidate(Some(Box::new(|_| Ok(()))));
+        backend.set_no_allocate(Some(Box::new(Logger(
+            "uncached",
+            log.clone(),
+        ))));
+
+        let extents = [(0x1000, 0x10ff), (0x2000, 0x20ff)];
+        let mut scrubber =
+            LineScrubber::new(backend, &extents, 64, 1).unwrap();
+        scrubber.scrub(512).unwrap();
+        let mut reads = log.borrow().clone();
+        reads.sort();
+        assert_eq!(
+            reads,
+            [
+                "read 0x1000",
+                "read 0x1040",
+                "read 0x1080",
+                "read 0x10c0",
+                "uncached 0x2000",
+                "uncached 0x2040",
+                "uncached 0x2080",
+                "uncached 0x20c0"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_flush_line() {
+        let buffer = vec![0u8; 256];
+        let start = (buffer.as_ptr() as usize + 63) & !63;
+        let mut backend = DmaBackend::new(
+            Logger("read", Rc::new(RefCell::new(Vec::new()))),
+            64,
+        )
+        .unwrap();
+        backend
+            .add_buffer(start, start + 127, DmaCoherency::Invalidate)
+            .unwrap();
+
+        // The lines flushed are in the buffer, which stays mapped
+        let result = unsafe { backend.use_flush_line() };
+        if result.is_ok() {
+            backend.read_line(start).unwrap();
+            backend.read_line(start + 64).unwrap();
+        }
+        assert_eq!(buffer[0], 0);
+    }
+}
@@ -0,0 +1,596 @@
+// The order in which cache lines are scrubbed, without scrubbing them.
+// ScrubOrder walks the same sequence of cache line addresses the scrubber
+// reads in a pass: every address with cache index 0 in each scrub area, then
+// every address with cache index 1, and so on. Nothing is read, so it is
+// safe to use on any set of addresses, for example to check coverage, to
+// drive a cache simulator or to precompute a schedule.
+//
+// A pass can also be split into interleaved sub-passes, each of which
+// covers only every Kth cache index, so that a sub-pass only ever disturbs
+// 1/K of the cache. The K sub-passes together cover every address.
+//
+// The walk is a single cursor over (cache index, area) rather than an
+// iterator per area. The first line and number of lines of each area are
+// computed once, so finding the lines of an area with a cache index is a
+// little arithmetic, and each line after that only decrements a count.
+// This matters with many small areas, most of which have no line with a
+// given cache index.
+//
+// try_for_each_fast() is a faster way of walking the rest of the order,
+// for a full scrub at boot. It relies on the checks made when the
+// ScrubOrder was created and makes none for each line. nth() skips the
+// lines of an area with a cache index together, so that resuming part way
+// through a pass takes time in proportion to the areas and cache indices
+// skipped rather than the lines.
+
+use crate::addr::*;
+use crate::area::*;
+use crate::base::*;
+
+// The cache lines of a scrub area
+//
+// first: Line number, address divided by the line size, of the first line
+// lines: Number of lines
+#[derive(Clone, Copy, Debug)]
+struct AreaLines {
+    first: usize,
+    lines: usize,
+}
+
+/// Iterator over the cache line addresses read in one scrub pass
+///
+/// * `areas` - The lines of each scrub area
+///
+/// * `cacheline_size` - Number of bytes in a cache line
+///
+/// * `cache_lines` - Number of cache indices
+///
+/// * `index` - Cache index currently being scrubbed
+///
+/// * `index_step` - Difference between successive cache indices scrubbed
+///
+/// * `area` - Scrub area currently being scrubbed
+///
+/// * `line` - Number of the next line to return from the current area
+///
+/// * `left` - Number of lines left to return from the current area with
+///   the current cache index
+///
+/// * `remaining` - Number of addresses not yet returned
+#[derive(Clone, Debug)]
+pub struct ScrubOrder {
+    areas: Vec<AreaLines>,
+    cacheline_size: usize,
+    cache_lines: usize,
+    index: usize,
+    index_step: usize,
+    area: usize,
+    line: usize,
+    left: usize,
+    remaining: usize,
+}
+
+impl ScrubOrder {
+    /// Create an iterator over the addresses scrubbed in one pass
+    ///
+    /// # Arguments:
+    /// * `extents` - (start, end) address of each scrub area, end inclusive.
+    ///   Areas must start and end on cache line boundaries.
+    ///
+    /// * `cacheline_size` - Number of bytes in a cache line, a power of two
+    ///
+    /// * `cache_index_width` - Number of address bits in the cache index,
+    ///   less than the number of bits in a usize
+    ///
+    /// # Returns:
+    /// The iterator or an Error if the areas are not valid. See
+    /// check_extents().
+    pub fn new(
+        extents: &[(usize, usize)],
+        cacheline_size: usize,
+        cache_index_width: usize,
+    ) -> Result<ScrubOrder, Error> {
+        ScrubOrder::sub_pass(
+            extents,
+            cacheline_size,
+            cache_index_width,
+            1,
+            0,
+        )
+    }
+
+    /// Create an iterator over the addresses scrubbed in one sub-pass of a
+    /// pass split into interleaved sub-passes. Sub-pass i covers the cache
+    /// indices congruent to i modulo the number of sub-passes.
+    ///
+    /// # Arguments:
+    /// * `extents` - (start, end) address of each scrub area, end inclusive.
+    ///   Areas must start and end on cache line boundaries.
+    ///
+    /// * `cacheline_size` - Number of bytes in a cache line, a power of two
+    ///
+    /// * `cache_index_width` - Number of address bits in the cache index,
+    ///   less than the number of bits in a usize
+    ///
+    /// * `sub_passes` - Number of sub-passes in a pass, a power of two no
+    ///   larger than the number of cache indices
+    ///
+    /// * `sub_pass` - Which sub-pass, less than sub_passes
+    ///
+    /// # Returns:
+    /// The iterator or an Error if the areas are not valid. See
+    /// check_extents().
+    pub fn sub_pass(
+        extents: &[(usize, usize)],
+        cacheline_size: usize,
+        cache_index_width: usize,
+        sub_passes: usize,
+        sub_pass: usize,
+    ) -> Result<ScrubOrder, Error> {
+        if cache_index_width >= usize::BITS as usize {
+            return Err(Error::UnalignedValue);
+        }
+        let cache_lines = 1 << cache_index_width;
+        if !sub_passes.is_power_of_two()
+            || sub_passes > cache_lines
+            || sub_pass >= sub_passes
+        {
+            return Err(Error::UnalignedValue);
+        }
+        check_extents(extents, cacheline_size)?;
+
+        let areas = extents
+            .iter()
+            .map(|&(start, end)| AreaLines {
+                first: start / cacheline_size,
+                lines: (end - start) / cacheline_size + 1,
+            })
+            .collect();
+        Ok(ScrubOrder::from_lines(
+            areas,
+            cacheline_size,
+            cache_lines,
+            sub_passes,
+            sub_pass,
+        ))
+    }
+
+    /// Create an iterator over the addresses scrubbed in one pass of areas
+    /// of virtual memory. The areas were checked when they were created,
+    /// so only their line sizes and total size are checked here.
+    ///
+    /// # Arguments:
+    /// * `areas` - The scrub areas, all with the same cache line size
+    ///
+    /// * `cache_index_width` - Number of address bits in the cache index,
+    ///   less than the number of bits in a usize
+    ///
+    /// # Returns:
+    /// The iterator, otherwise Err(Error::NoMemAreas) if there are no
+    /// areas, Err(Error::MixedLineSizes) if their cache line sizes differ,
+    /// Err(Error::UnalignedValue) if the cache index width is too large or
+    /// Err(Error::AddressOverflow) if the areas together hold more bytes
+    /// than a usize can count
+    pub fn for_virt_areas(
+        areas: &[VirtScrubArea],
+        cache_index_width: usize,
+    ) -> Result<ScrubOrder, Error> {
+        let cacheline_size = line_size_of(areas)?;
+        if cache_index_width >= usize::BITS as usize {
+            return Err(Error::UnalignedValue);
+        }
+        areas.iter().try_fold(0usize, |bytes, area| {
+            bytes
+                .checked_add(area.lines().get() * cacheline_size)
+                .ok_or(Error::AddressOverflow)
+        })?;
+
+        let areas = areas
+            .iter()
+            .map(|area| AreaLines {
+                first: area.start() / cacheline_size,
+                lines: area.lines().get(),
+            })
+            .collect();
+        Ok(ScrubOrder::from_lines(
+            areas,
+            cacheline_size,
+            1 << cache_index_width,
+            1,
+            0,
+        ))
+    }
+
+    // Create an iterator over the lines of a sub-pass of areas already
+    // checked
+    fn from_lines(
+        areas: Vec<AreaLines>,
+        cacheline_size: usize,
+        cache_lines: usize,
+        sub_passes: usize,
+        sub_pass: usize,
+    ) -> ScrubOrder {
+        // Number of lines below line n in this sub-pass
+        let below = |n: usize| {
+            n / sub_passes + (n % sub_passes > sub_pass) as usize
+        };
+        let remaining = areas
+            .iter()
+            .map(|a: &AreaLines| below(a.first + a.lines) - below(a.first))
+            .sum();
+
+        let mut order = ScrubOrder {
+            areas,
+            cacheline_size,
+            cache_lines,
+            index: sub_pass,
+            index_step: sub_passes,
+            area: 0,
+            line: 0,
+            left: 0,
+            remaining,
+        };
+        order.start_area();
+        order
+    }
+
+    /// Create an iterator over the addresses scrubbed in one pass of the
+    /// given scrub areas
+    ///
+    /// # Arguments:
+    /// * `scrub_areas` - The areas to scrub
+    ///
+    /// * `cacheline_size` - Number of bytes in a cache line, a power of two
+    ///
+    /// * `cache_index_width` - Number of address bits in the cache index
+    pub fn for_areas<A>(
+        scrub_areas: &[MemArea<A>],
+        cacheline_size: usize,
+        cache_index_width: usize,
+    ) -> Result<ScrubOrder, Error>
+    where
+        A: AddrImplTrait<A>,
+        usize: From<A>,
+    {
+        let extents: Vec<(usize, usize)> = scrub_areas
+            .iter()
+            .map(|a| (usize::from(a.s.0), usize::from(a.e.0)))
+            .collect();
+        ScrubOrder::new(&extents, cacheline_size, cache_index_width)
+    }
+
+    // Returns the address and size of the buffer holding the lines of
+    // each area, so that scrubbing can leave it out
+    pub(crate) fn buffer(&self) -> (usize, usize) {
+        (
+            self.areas.as_ptr() as usize,
+            self.areas.capacity() * size_of::<AreaLines>(),
+        )
+    }
+
+    /// Returns the cache index of an address
+    pub fn cache_index(&self, addr: usize) -> usize {
+        (addr / self.cacheline_size) % self.cache_lines
+    }
+
+    /// Call a function with each address not yet returned, in the order
+    /// next() would return them, stopping at the first error. There are no
+    /// checks for each line: addresses are computed with wrapping
+    /// arithmetic that relies on the areas having been validated when the
+    /// ScrubOrder was created.
+    ///
+    /// # Arguments:
+    /// * `f` - Called with each address
+    ///
+    /// # Returns:
+    /// Ok(()) once every address has been given to `f`, otherwise the
+    /// first Err it returned. The iterator then continues after the
+    /// address for which it failed.
+    pub fn try_for_each_fast<E, F>(&mut self, mut f: F) -> Result<(), E>
+    where
+        F: FnMut(usize) -> Result<(), E>,
+    {
+        let (size, stride) = (self.cacheline_size, self.cache_lines);
+        while self.remaining != 0 {
+            let mut line = self.line;
+            for done in 1..=self.left {
+                let addr = line.wrapping_mul(size);
+                line = line.wrapping_add(stride);
+                if let Err(err) = f(addr) {
+                    self.line = line;
+                    self.left -= done;
+                    self.remaining -= done;
+                    return Err(err);
+                }
+            }
+            self.remaining -= self.left;
+            self.left = 0;
+            if self.remaining == 0 {
+                break;
+            }
+
+            self.area += 1;
+            if self.area == self.areas.len() {
+                self.area = 0;
+                self.index += self.index_step;
+            }
+            self.start_area();
+        }
+        Ok(())
+    }
+
+    // Find the lines in the current area with the current cache index
+    fn start_area(&mut self) {
+        let area = self.areas[self.area];
+        let skip =
+            self.index.wrapping_sub(area.first) & (self.cache_lines - 1);
+        if skip < area.lines {
+            self.line = area.first + skip;
+            self.left = (area.lines - skip - 1) / self.cache_lines + 1;
+        } else {
+            self.left = 0;
+        }
+    }
+}
+
+impl Iterator for ScrubOrder {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        while self.remaining != 0 {
+            if self.left != 0 {
+                // The line after the last may be beyond the address space
+                let line = self.line;
+                self.line = line.wrapping_add(self.cache_lines);
+                self.left -= 1;
+                self.remaining -= 1;
+                return Some(line * self.cacheline_size);
+            }
+
+            self.area += 1;
+            if self.area == self.areas.len() {
+                self.area = 0;
+                self.index += self.index_step;
+            }
+            self.start_area();
+        }
+        None
+    }
+
+    fn nth(&mut self, mut n: usize) -> Option<usize> {
+        while self.remaining != 0 {
+            if n < self.left {
+                self.line = self
+                    .line
+                    .wrapping_add(n.wrapping_mul(self.cache_lines));
+                self.left -= n;
+                self.remaining -= n;
+                return self.next();
+            }
+            n -= self.left;
+            self.remaining -= self.left;
+            self.left = 0;
+            if self.remaining == 0 {
+                break;
+            }
+
+            self.area += 1;
+            if self.area == self.areas.len() {
+                self.area = 0;
+                self.index += self.index_step;
+            }
+            self.start_area();
+        }
+        None
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.remaining, Some(self.remaining))
+    }
+}
+
+impl ExactSizeIterator for ScrubOrder {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_order() {
+        // Four cache indices of 64 bytes, the second area starting at
+        // cache index 2
+        let order =
+            ScrubOrder::new(&[(0, 511), (1152, 1407)], 64, 2).unwrap();
+        assert_eq!(order.len(), 12);
+        assert_eq!(order.cache_index(1152), 2);
+
+        let addrs: Vec<usize> = order.collect();
+        assert_eq!(
+            addrs,
+            vec![
+                0, 256, 1280, // index 0
+                64, 320, 1344, // index 1
+                128, 384, 1152, // index 2
+                192, 448, 1216, // index 3
+            ]
+        );
+
+        // Skipping lines lands where walking them would
+        let mut order =
+            ScrubOrder::new(&[(0, 511), (1152, 1407)], 64, 2).unwrap();
+        assert_eq!(order.nth(4), Some(320));
+        assert_eq!(order.len(), 7);
+        assert_eq!(order.nth(6), Some(1216));
+        assert_eq!(order.nth(1), None);
+    }
+
+    #[test]
+    fn test_virt_areas() {
+        // The same order as from the extents, without checking each area
+        // again
+        let base = |addr| std::ptr::NonNull::new(addr as *mut u8).unwrap();
+        let areas = [
+            VirtScrubArea::from_extent(base(0x1000), 0x11ff, 64).unwrap(),
+            VirtScrubArea::from_extent(base(0x1480), 0x157f, 64).unwrap(),
+        ];
+        let extents = [(0x1000, 0x11ff), (0x1480, 0x157f)];
+        let order = ScrubOrder::for_virt_areas(&areas, 2).unwrap();
+        assert_eq!(order.len(), 12);
+        assert!(order.eq(ScrubOrder::new(&extents, 64, 2).unwrap()));
+
+        let mixed = [
+            areas[0],
+            VirtScrubArea::from_extent(base(0x2000), 0x20ff, 128).unwrap(),
+        ];
+        assert_eq!(
+            ScrubOrder::for_virt_areas(&mixed, 2).unwrap_err(),
+            Error::MixedLineSizes
+        );
+        assert_eq!(
+            ScrubOrder::for_virt_areas(&[], 2).unwrap_err(),
+            Error::NoMemAreas
+        );
+        assert_eq!(
+            ScrubOrder::for_virt_areas(&areas, 64).unwrap_err(),
+            Error::UnalignedValue
+        );
+    }
+
+    #[test]
+    fn test_coverage() {
+        let extents = [(4096, 8191), (65536, 65536 + 1023)];
+        let mut addrs: Vec<usize> =
+            ScrubOrder::new(&extents, 64, 6).unwrap().collect();
+        addrs.sort();
+        let expected: Vec<usize> = (4096..8192)
+            .step_by(64)
+            .chain((65536..65536 + 1024).step_by(64))
+            .collect();
+        assert_eq!(addrs, expected);
+    }
+
+    #[test]
+    fn test_sub_pass() {
+        let extents = [(0, 511), (1152, 1407)];
+        let even: Vec<usize> = ScrubOrder::sub_pass(&extents, 64, 2, 2, 0)
+            .unwrap()
+            .collect();
+        let odd = ScrubOrder::sub_pass(&extents, 64, 2, 2, 1).unwrap();
+        assert_eq!(odd.len(), 6);
+        let odd: Vec<usize> = odd.collect();
+        assert_eq!(even, vec![0, 256, 1280, 128, 384, 1152]);
+        assert_eq!(odd, vec![64, 320, 1344, 192, 448, 1216]);
+
+        assert_eq!(
+            ScrubOrder::sub_pass(&extents, 64, 2, 8, 0).unwrap_err(),
+            Error::UnalignedValue
+        );
+        assert_eq!(
+            ScrubOrder::sub_pass(&extents, 64, 2, 2, 2).unwrap_err(),
+            Error::UnalignedValue
+        );
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert_eq!(
+            ScrubOrder::new(&[], 64, 4).unwrap_err(),
+            Error::NoMemAreas
+        );
+        assert_eq!(
+            ScrubOrder::new(&[(32, 127)], 64, 4).unwrap_err(),
+            Error::UnalignedStart
+        );
+        assert_eq!(
+            ScrubOrder::new(&[(0, 100)], 64, 4).unwrap_err(),
+            Error::UnalignedEnd
+        );
+        assert_eq!(
+            ScrubOrder::new(&[(0, 63)], 48, 4).unwrap_err(),
+            Error::UnalignedValue
+        );
+        assert_eq!(
+            ScrubOrder::new(&[(0, 63)], 64, 64).unwrap_err(),
+            Error::UnalignedValue
+        );
+    }
+
+    #[test]
+    fn test_small_areas() {
+        // Areas smaller than the cache, some wrapping around from the
+        // last cache index to the first
+        let extents: Vec<(usize, usize)> = (0..50)
+            .map(|i| (i * 17 * 64, (i * 17 + 2 + i % 5) * 64 - 1))
+            .collect();
+        let order = ScrubOrder::new(&extents, 64, 4).unwrap();
+        let addrs: Vec<usize> = order.clone().collect();
+        assert_eq!(addrs.len(), order.len());
+
+        // Each cache index in turn, in area order within an index
+        let mut expected = Vec::new();
+        for index in 0..16 {
+            for &(start, end) in &extents {
+                expected.extend(
+                    (start..=end)
+                        .step_by(64)
+                        .filter(|addr| (addr / 64) % 16 == index),
+                );
+            }
+        }
+        assert_eq!(addrs, expected);
+    }
+
+    #[test]
+    fn test_fast() {
+        let extents = [(0, 1023), (2048, 2303), (4096, 4159)];
+        let expected: Vec<usize> =
+            ScrubOrder::sub_pass(&extents, 64, 3, 2, 1)
+                .unwrap()
+                .collect();
+
+        // The same addresses as next()
+        let mut order =
+            ScrubOrder::sub_pass(&extents, 64, 3, 2, 1).unwrap();
+        let mut addrs = Vec::new();
+        order
+            .try_for_each_fast(|addr| {
+                addrs.push(addr);
+                Ok::<(), ()>(())
+            })
+            .unwrap();
+        assert_eq!(addrs, expected);
+        assert_eq!(order.next(), None);
+
+        // An error stops the walk after the line that failed
+        let mut order =
+            ScrubOrder::sub_pass(&extents, 64, 3, 2, 1).unwrap();
+        assert_eq!(
+            order.try_for_each_fast(|addr| match addr == expected[4] {
+                true => Err(addr),
+                false => Ok(()),
+            }),
+            Err(expected[4])
+        );
+        assert_eq!(order.len(), expected.len() - 5);
+        assert!(order.eq(expected[5..].iter().copied()));
+    }
+
+    #[test]
+    fn test_overflow() {
+        assert_eq!(
+            ScrubOrder::new(&[(0, usize::MAX)], 64, 4).unwrap_err(),
+            Error::AddressOverflow
+        );
+        let half = usize::MAX / 2;
+        assert_eq!(
+            ScrubOrder::new(&[(0, half), (half + 1, usize::MAX)], 64, 4)
+                .unwrap_err(),
+            Error::AddressOverflow
+        );
+
+        // The last line in the address space
+        let top = usize::MAX - 63;
+        let order = ScrubOrder::new(&[(top, usize::MAX)], 64, 4).unwrap();
+        assert_eq!(order.collect::<Vec<_>>(), vec![top]);
+    }
+}
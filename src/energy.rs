@@ -0,0 +1,209 @@
+// Accounting for the energy scrubbing uses. Users on battery, and data
+// centers with power budgets, have to weigh how fast to scrub against the
+// energy it costs, which needs real numbers rather than guesses. An
+// EnergyMeter reads an energy counter, such as RAPL or one supplied by the
+// platform, around each chunk scrubbed and records the difference in the
+// scrubber's statistics, giving the energy of each pass and per gigabyte
+// scrubbed.
+//
+// An energy counter measures a whole package or system, not just the
+// scrubber, so the energy of a chunk includes whatever else ran while it
+// was scrubbed. Running the scrubber in bursts on an otherwise quiet
+// system gives the most accurate numbers. A baseline power, the draw with
+// the scrubber idle, can be set or measured and is taken out of each
+// chunk's energy, leaving what scrubbing added.
+
+use std::io;
+use std::time::Duration;
+
+use crate::backend::*;
+use crate::base::*;
+use crate::clock::*;
+
+/// A counter of the energy used since some fixed time, such as the RAPL
+/// energy counter of a processor package
+pub trait EnergyCounter {
+    /// Returns the energy used so far in joules, which only ever grows
+    fn joules(&mut self) -> io::Result<f64>;
+}
+
+impl<F: FnMut() -> io::Result<f64>> EnergyCounter for F {
+    fn joules(&mut self) -> io::Result<f64> {
+        self()
+    }
+}
+
+/// Measures the energy used to scrub each chunk and records it in the
+/// energy statistics of a LineScrubber
+///
+/// * `counter` - The energy counter
+///
+/// * `baseline` - Power drawn when not scrubbing, in watts, taken out of
+///   the energy of each chunk
+///
+/// * `failures` - Number of chunks whose energy couldn't be measured
+pub struct EnergyMeter<C: EnergyCounter> {
+    counter: C,
+    baseline: f64,
+    failures: u64,
+}
+
+impl<C: EnergyCounter> EnergyMeter<C> {
+    /// Create a meter with no baseline power
+    ///
+    /// # Arguments:
+    /// * `counter` - The energy counter
+    pub fn new(counter: C) -> EnergyMeter<C> {
+        EnergyMeter {
+            counter,
+            baseline: 0.0,
+            failures: 0,
+        }
+    }
+
+    /// Set the power drawn when not scrubbing
+    ///
+    /// # Arguments:
+    /// * `watts` - The baseline power in watts, no less than zero
+    pub fn set_baseline(&mut self, watts: f64) {
+        self.baseline = watts.max(0.0);
+    }
+
+    /// Returns the power drawn when not scrubbing, in watts
+    pub fn baseline(&self) -> f64 {
+        self.baseline
+    }
+
+    /// Measure the power drawn when not scrubbing and use it as the
+    /// baseline
+    ///
+    /// # Arguments:
+    /// * `clock` - The clock to sleep and measure time with
+    ///
+    /// * `duration` - How long to measure for
+    ///
+    /// # Returns:
+    /// Ok(watts) with the baseline power, otherwise Err(io::Error) if the
+    /// counter couldn't be read
+    pub fn measure_baseline(
+        &mut self,
+        clock: &dyn Clock,
+        duration: Duration,
+    ) -> io::Result<f64> {
+        let before = self.counter.joules()?;
+        let started = clock.now();
+        clock.sleep(duration);
+        let after = self.counter.joules()?;
+        let secs = clock.now().saturating_duration_since(started);
+        if !secs.is_zero() {
+            self.set_baseline((after - before) / secs.as_secs_f64());
+        }
+        Ok(self.baseline)
+    }
+
+    /// Returns the number of chunks whose energy couldn't be measured
+    /// because the counter couldn't be read
+    pub fn failures(&self) -> u64 {
+        self.failures
+    }
+
+    /// Scrub a chunk, as LineScrubber::scrub() does, and record the energy
+    /// it used in the scrubber's statistics. If the counter can't be read
+    /// the chunk is still scrubbed but its energy isn't recorded.
+    ///
+    /// # Arguments:
+    /// * `scrubber` - The scrubber
+    ///
+    /// * `bytes` - Number of bytes to scrub
+    ///
+    /// # Returns:
+    /// As for LineScrubber::scrub()
+    pub fn scrub<B: ScrubBackend>(
+        &mut self,
+        scrubber: &mut LineScrubber<B>,
+        bytes: usize,
+    ) -> Result<(), Error> {
+        let before = self.counter.joules();
+        let started = scrubber.clock().now();
+        let scrubbed = scrubber.stats().bytes_scrubbed;
+        let passes = scrubber.stats().passes;
+        let result = scrubber.scrub(bytes);
+        let after = self.counter.joules();
+        let elapsed =
+            scrubber.clock().now().saturating_duration_since(started);
+
+        match (before, after) {
+            (Ok(before), Ok(after)) => {
+                let idle = self.baseline * elapsed.as_secs_f64();
+                let joules = (after - before - idle).max(0.0);
+                let stats = scrubber.stats_mut();
+                stats.energy.get_or_insert_default().record(
+                    joules,
+                    stats.bytes_scrubbed - scrubbed,
+                    stats.passes - passes,
+                );
+            }
+            _ => self.failures += 1,
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+    use std::time::{Instant, SystemTime};
+
+    struct NullBackend;
+
+    impl ScrubBackend for NullBackend {
+        fn read_line(&mut self, _addr: usize) -> Result<(), Error> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_meter() {
+        let clock = VirtualClock::at(Instant::now(), SystemTime::now());
+        let mut scrubber =
+            LineScrubber::new(NullBackend, &[(0x1000, 0x1fff)], 64, 0)
+                .unwrap();
+        scrubber.set_clock(Box::new(clock.clone()));
+
+        // The counter goes up by three joules each time it is read, and
+        // fails when told to
+        let joules = Rc::new(Cell::new(0.0));
+        let fail = Rc::new(Cell::new(false));
+        let (counter_joules, counter_fail) =
+            (joules.clone(), fail.clone());
+        let mut meter = EnergyMeter::new(move || {
+            if counter_fail.get() {
+                return Err(io::Error::from(io::ErrorKind::Other));
+            }
+            counter_joules.set(counter_joules.get() + 3.0);
+            Ok(counter_joules.get())
+        });
+
+        let watts = meter
+            .measure_baseline(&clock, Duration::from_secs(3))
+            .unwrap();
+        assert_eq!(watts, 1.0);
+        meter.set_baseline(0.0);
+
+        meter.scrub(&mut scrubber, 2048).unwrap();
+        meter.scrub(&mut scrubber, 2048).unwrap();
+        let energy = scrubber.stats().energy.unwrap();
+        assert_eq!(energy.joules, 6.0);
+        assert_eq!(energy.bytes, 4096);
+        assert_eq!(energy.last_pass_joules, Some(6.0));
+        assert_eq!(energy.pass_joules, 0.0);
+
+        fail.set(true);
+        meter.scrub(&mut scrubber, 2048).unwrap();
+        assert_eq!(meter.failures(), 1);
+        assert_eq!(scrubber.stats().energy.unwrap().bytes, 4096);
+        assert_eq!(scrubber.stats().bytes_scrubbed, 6144);
+    }
+}
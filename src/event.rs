@@ -0,0 +1,330 @@
+// Events reported while scrubbing. Anything interested in what the scrubber
+// is doing, such as a logger or a policy, implements EventSink and is handed
+// each event as it happens.
+
+use std::time::Duration;
+
+/// Severity of a detected memory error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ErrorSeverity {
+    Corrected,
+    Uncorrected,
+}
+
+/// Where repeated corrected errors at an address came from
+///
+/// * `Cache` - A stale copy of the line in the cache, since the errors
+///   stopped once the line was flushed
+///
+/// * `Memory` - The memory itself, since the errors went on after the
+///   line was flushed and read again
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FaultSite {
+    Cache,
+    Memory,
+}
+
+impl FaultSite {
+    /// Short name of the fault site
+    pub fn name(&self) -> &'static str {
+        match self {
+            FaultSite::Cache => "cache",
+            FaultSite::Memory => "memory",
+        }
+    }
+}
+
+/// A memory error detected while scrubbing
+///
+/// * `addr` - Address at which the error was detected
+///
+/// * `area` - Index of the scrub area containing the address, if known
+///
+/// * `severity` - Whether the error was corrected
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ErrorEvent {
+    pub addr: u64,
+    pub area: Option<usize>,
+    pub severity: ErrorSeverity,
+}
+
+/// One or more memory errors at the same address, as reported by an error
+/// source outside the scrubber
+///
+/// * `error` - The error
+///
+/// * `count` - Number of errors reported
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ErrorReport {
+    pub error: ErrorEvent,
+    pub count: u32,
+}
+
+/// Events generated by the scrubber
+///
+/// * `ChunkComplete` - A chunk of memory was scrubbed
+///     * `bytes` - Number of bytes in the chunk
+///     * `duration` - Time taken to scrub the chunk
+///
+/// * `PassComplete` - A pass over all scrub areas completed
+///     * `pass` - Number of passes completed so far
+///     * `epoch` - Epoch of the pass
+///     * `bytes` - Number of bytes in the pass
+///     * `duration` - Time since the previous pass completed
+///
+/// * `RateChange` - The number of bytes scrubbed per chunk changed
+///     * `old` - Previous number of bytes per chunk
+///     * `new` - New number of bytes per chunk
+///
+/// * `Error` - A memory error was detected
+///
+/// * `ErrorStorm` - A burst of corrected errors started in a scrub area
+///     * `area` - Index of the scrub area
+///     * `errors` - Number of errors that started the storm
+///
+/// * `StormCleared` - A scrub area's storm of errors ended
+///     * `area` - Index of the scrub area
+///
+/// * `Health` - The health score of a scrub area was computed
+///     * `area` - Index of the scrub area
+///     * `score` - Health score, from 100 down to 0
+///
+/// * `LineSkipped` - A cache line was not read because it could not be
+///   read safely, as when its memory is no longer mapped
+///     * `addr` - Address of the cache line
+///
+/// * `FaultLocated` - Repeated corrected errors in a cache line were traced
+///   to the cache or to memory by flushing the line and reading it again
+///     * `addr` - Address of the cache line
+///     * `site` - Where the errors came from
+///
+/// * `ThresholdCrossed` - The corrected errors in a block of memory
+///   reached the threshold for acting on them
+///     * `addr` - Address of the block
+///     * `errors` - Number of errors that crossed the threshold
+///
+/// * `ThresholdCleared` - A block of memory went enough passes without
+///   corrected errors to fall back below its threshold
+///     * `addr` - Address of the block
+///
+/// * `LineFlushed` - A cache line was flushed and read again from memory
+///   for a policy's FlushReread action, or couldn't be
+///     * `addr` - Address of the cache line
+///     * `flushed` - Whether the line was flushed. It is false when the
+///       backend can't flush cache lines.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ScrubEvent {
+    ChunkComplete {
+        bytes: usize,
+        duration: Duration,
+    },
+    PassComplete {
+        pass: u64,
+        epoch: u64,
+        bytes: usize,
+        duration: Duration,
+    },
+    RateChange {
+        old: usize,
+        new: usize,
+    },
+    Error(ErrorEvent),
+    ErrorStorm {
+        area: usize,
+        errors: usize,
+    },
+    StormCleared {
+        area: usize,
+    },
+    Health {
+        area: usize,
+        score: f64,
+    },
+    LineSkipped {
+        addr: u64,
+    },
+    FaultLocated {
+        addr: u64,
+        site: FaultSite,
+    },
+    ThresholdCrossed {
+        addr: u64,
+        errors: usize,
+    },
+    ThresholdCleared {
+        addr: u64,
+    },
+    LineFlushed {
+        addr: u64,
+        flushed: bool,
+    },
+}
+
+impl ScrubEvent {
+    /// Short name of the event type
+    pub fn name(&self) -> &'static str {
+        match self {
+            ScrubEvent::ChunkComplete { .. } => "chunk_complete",
+            ScrubEvent::PassComplete { .. } => "pass_complete",
+            ScrubEvent::RateChange { .. } => "rate_change",
+            ScrubEvent::Error(_) => "error",
+            ScrubEvent::ErrorStorm { .. } => "error_storm",
+            ScrubEvent::StormCleared { .. } => "storm_cleared",
+            ScrubEvent::Health { .. } => "health",
+            ScrubEvent::LineSkipped { .. } => "line_skipped",
+            ScrubEvent::FaultLocated { .. } => "fault_located",
+            ScrubEvent::ThresholdCrossed { .. } => "threshold_crossed",
+            ScrubEvent::ThresholdCleared { .. } => "threshold_cleared",
+            ScrubEvent::LineFlushed { .. } => "line_flushed",
+        }
+    }
+
+    /// Human readable description of the event
+    pub fn message(&self) -> String {
+        match self {
+            ScrubEvent::ChunkComplete { bytes, duration } => format!(
+                "scrubbed {} bytes in {:.6}s",
+                bytes,
+                duration.as_secs_f64()
+            ),
+            ScrubEvent::PassComplete {
+                pass,
+                epoch,
+                bytes,
+                duration,
+            } => format!(
+                "scrub pass {} (epoch {}) complete: {} bytes in {:.3}s",
+                pass,
+                epoch,
+                bytes,
+                duration.as_secs_f64()
+            ),
+            ScrubEvent::RateChange { old, new } => format!(
+                "scrub rate changed from {} to {} bytes per chunk",
+                old, new
+            ),
+            ScrubEvent::Error(e) => format!(
+                "{} memory error at {:#x}",
+                match e.severity {
+                    ErrorSeverity::Corrected => "corrected",
+                    ErrorSeverity::Uncorrected => "uncorrected",
+                },
+                e.addr
+            ),
+            ScrubEvent::ErrorStorm { area, errors } => format!(
+                "error storm in scrub area {}: {} corrected errors",
+                area, errors
+            ),
+            ScrubEvent::StormCleared { area } => {
+                format!("error storm in scrub area {} ended", area)
+            }
+            ScrubEvent::Health { area, score } => {
+                format!("scrub area {} health {:.1}", area, score)
+            }
+            ScrubEvent::LineSkipped { addr } => {
+                format!("skipped unreadable cache line at {:#x}", addr)
+            }
+            ScrubEvent::FaultLocated { addr, site } => format!(
+                "corrected errors at {:#x} came from the {}",
+                addr,
+                site.name()
+            ),
+            ScrubEvent::ThresholdCrossed { addr, errors } => format!(
+                "{} corrected errors at {:#x} crossed the threshold",
+                errors, addr
+            ),
+            ScrubEvent::ThresholdCleared { addr } => {
+                format!("corrected errors at {:#x} below threshold", addr)
+            }
+            ScrubEvent::LineFlushed { addr, flushed } => match flushed {
+                true => format!("flushed cache line at {:#x}", addr),
+                false => format!("can't flush cache line at {:#x}", addr),
+            },
+        }
+    }
+
+    /// The event as a list of (name, value) pairs, for sinks that support
+    /// structured data
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut fields = vec![("event", self.name().to_string())];
+
+        match self {
+            ScrubEvent::ChunkComplete { bytes, duration } => {
+                fields.push(("bytes", bytes.to_string()));
+                fields.push((
+                    "duration_secs",
+                    format!("{:.6}", duration.as_secs_f64()),
+                ));
+            }
+            ScrubEvent::PassComplete {
+                pass,
+                epoch,
+                bytes,
+                duration,
+            } => {
+                fields.push(("pass", pass.to_string()));
+                fields.push(("epoch", epoch.to_string()));
+                fields.push(("bytes", bytes.to_string()));
+                fields.push((
+                    "duration_secs",
+                    format!("{:.6}", duration.as_secs_f64()),
+                ));
+            }
+            ScrubEvent::RateChange { old, new } => {
+                fields.push(("old", old.to_string()));
+                fields.push(("new", new.to_string()));
+            }
+            ScrubEvent::Error(e) => {
+                fields.push(("addr", format!("{:#x}", e.addr)));
+                if let Some(area) = e.area {
+                    fields.push(("area", area.to_string()));
+                }
+                fields.push((
+                    "severity",
+                    match e.severity {
+                        ErrorSeverity::Corrected => "corrected",
+                        ErrorSeverity::Uncorrected => "uncorrected",
+                    }
+                    .to_string(),
+                ));
+            }
+            ScrubEvent::ErrorStorm { area, errors } => {
+                fields.push(("area", area.to_string()));
+                fields.push(("errors", errors.to_string()));
+            }
+            ScrubEvent::StormCleared { area } => {
+                fields.push(("area", area.to_string()));
+            }
+            ScrubEvent::Health { area, score } => {
+                fields.push(("area", area.to_string()));
+                fields.push(("score", format!("{:.1}", score)));
+            }
+            ScrubEvent::LineSkipped { addr } => {
+                fields.push(("addr", format!("{:#x}", addr)));
+            }
+            ScrubEvent::FaultLocated { addr, site } => {
+                fields.push(("addr", format!("{:#x}", addr)));
+                fields.push(("site", site.name().to_string()));
+            }
+            ScrubEvent::ThresholdCrossed { addr, errors } => {
+                fields.push(("addr", format!("{:#x}", addr)));
+                fields.push(("errors", errors.to_string()));
+            }
+            ScrubEvent::ThresholdCleared { addr } => {
+                fields.push(("addr", format!("{:#x}", addr)));
+            }
+            ScrubEvent::LineFlushed { addr, flushed } => {
+                fields.push(("addr", format!("{:#x}", addr)));
+                fields.push(("flushed", flushed.to_string()));
+            }
+        }
+
+        fields
+    }
+}
+
+/// Receiver for scrub events
+pub trait EventSink {
+    /// Called for each event as it occurs
+    fn event(&mut self, event: &ScrubEvent);
+}
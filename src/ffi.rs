@@ -0,0 +1,891 @@
+// C interface. C and C++ firmware can create a scrubber over memory it
+// names, scrub it a chunk at a time and destroy it, through an opaque
+// handle. The declarations are in include/memscrub.h, with a header-only
+// C++ wrapper in include/memscrub.hpp. Memory is read with one of the
+// crate's own backends, chosen in the configuration, so C needs no
+// callbacks. The caller must keep the areas mapped while the scrubber
+// exists.
+//
+// Nothing here panics on bad arguments: null pointers and invalid areas
+// are reported as a MemscrubStatus, which the C++ wrapper passes on
+// without using exceptions.
+//
+// The crate is built as a Rust library. Firmware wanting a static library
+// builds one with:
+//
+//  cargo rustc --lib --release --features ffi --crate-type staticlib
+//
+// Firmware is often linked against a prebuilt static library, so the
+// header it was compiled with may not match the library. The
+// configuration passed to memscrub_create() carries the ABI version of the
+// header, and the library refuses to create a scrubber for any other
+// version rather than misreading the structures. MEMSCRUB_ABI_VERSION
+// must be increased whenever a structure passed across the interface or
+// the value of a status changes, and the layout tests below updated to
+// match.
+
+use std::ffi::c_char;
+use std::ptr::{self, NonNull};
+use std::slice;
+use std::time::Duration;
+
+use crate::arch::*;
+use crate::area::*;
+use crate::backend::*;
+use crate::base::*;
+
+/// Version of the C interface. This must match MEMSCRUB_ABI_VERSION in
+/// include/memscrub.h.
+pub const MEMSCRUB_ABI_VERSION: u32 = 2;
+
+/// How a scrubber created from C reads cache lines
+///
+/// * `Portable` - A volatile read, as by RawBackend, on any architecture
+///
+/// * `Native` - The backend best suited to the architecture the library
+///   was built for, NativeBackend
+///
+/// * `X86_64` - A non-temporal read, prefetchnta and a mov, as by
+///   X86_64Backend
+///
+/// * `Aarch64` - A non-temporal LDNP, as by Aarch64Backend, followed by a
+///   DC CIVAC, which writes back and invalidates the line so that it
+///   isn't left in the cache
+///
+/// * `Riscv64` - A ld, as by Riscv64Backend
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub enum MemscrubReadStrategy {
+    Portable = 0,
+    Native = 1,
+    X86_64 = 2,
+    Aarch64 = 3,
+    Riscv64 = 4,
+}
+
+/// Configuration of a scrubber created from C
+///
+/// * `abi_version` - MEMSCRUB_ABI_VERSION from the header the caller was
+///   compiled with
+///
+/// * `read_strategy` - A MemscrubReadStrategy. This is a u32 rather than
+///   the enum since C may pass any value.
+///
+/// * `line_size` - Number of bytes in a cache line
+///
+/// * `index_width` - Number of address bits in the cache index
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub struct MemscrubConfig {
+    pub abi_version: u32,
+    pub read_strategy: u32,
+    pub line_size: usize,
+    pub index_width: usize,
+}
+
+/// A scrub area as passed from C
+///
+/// * `start` - First address of the area, on a cache line boundary
+///
+/// * `end` - Last address of the area, inclusive, one less than a cache
+///   line boundary
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub struct MemscrubArea {
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Statistics for a scrubber, as returned to C. Times are in nanoseconds.
+///
+/// * `bytes_scrubbed` - Total number of bytes scrubbed
+///
+/// * `chunks` - Number of times a chunk of memory was scrubbed
+///
+/// * `passes` - Number of complete passes through all scrub areas
+///
+/// * `epoch` - Epoch of the current pass
+///
+/// * `pass_offset` - Number of bytes scrubbed in the current pass
+///
+/// * `pass_size` - Number of bytes in a complete pass
+///
+/// * `scrub_ns` - Total time spent scrubbing
+///
+/// * `max_chunk_ns` - Longest time taken to scrub a single chunk
+///
+/// * `uptime_ns` - Time since statistics collection started
+///
+/// * `pass_errors` - Number of errors seen during the current pass
+///
+/// * `last_pass_errors` - Number of errors seen during the last complete
+///   pass
+///
+/// * `errors_corrected` - Corrected errors seen in all areas
+///
+/// * `errors_uncorrected` - Uncorrected errors seen in all areas
+///
+/// * `n_areas` - Number of scrub areas
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct MemscrubStats {
+    pub bytes_scrubbed: u64,
+    pub chunks: u64,
+    pub passes: u64,
+    pub epoch: u64,
+    pub pass_offset: u64,
+    pub pass_size: u64,
+    pub scrub_ns: u64,
+    pub max_chunk_ns: u64,
+    pub uptime_ns: u64,
+    pub pass_errors: u64,
+    pub last_pass_errors: u64,
+    pub errors_corrected: u64,
+    pub errors_uncorrected: u64,
+    pub n_areas: u64,
+}
+
+/// Value of MemscrubAreaStatus::staleness_ns for an area not yet
+/// completely scrubbed
+pub const MEMSCRUB_NEVER: u64 = u64::MAX;
+
+/// Status of a single scrub area, as returned to C, mirroring AreaStatus
+///
+/// * `start` - Address of the first byte of the area
+///
+/// * `end` - Address of the last byte of the area
+///
+/// * `staleness_ns` - Nanoseconds since the area was last completely
+///   scrubbed, or MEMSCRUB_NEVER
+///
+/// * `last_epoch` - Epoch of the last pass that completely scrubbed the
+///   area, or zero if none has
+///
+/// * `errors_corrected` - Corrected errors seen in the area
+///
+/// * `errors_uncorrected` - Uncorrected errors seen in the area
+///
+/// * `excluded_lines` - Number of cache lines left out of scrubbing
+///
+/// * `error_rate` - Estimated corrected errors per hour
+///
+/// * `priority` - Relative importance of scrubbing the area
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[repr(C)]
+pub struct MemscrubAreaStatus {
+    pub start: usize,
+    pub end: usize,
+    pub staleness_ns: u64,
+    pub last_epoch: u64,
+    pub errors_corrected: u64,
+    pub errors_uncorrected: u64,
+    pub excluded_lines: u64,
+    pub error_rate: f64,
+    pub priority: u32,
+}
+
+// Returns a Duration in nanoseconds, saturating
+fn nanos(duration: Duration) -> u64 {
+    duration.as_nanos().try_into().unwrap_or(u64::MAX)
+}
+
+/// Result of a call through the C interface. Everything other than Ok
+/// corresponds to an Error, except NullPointer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(C)]
+pub enum MemscrubStatus {
+    Ok = 0,
+    InternalError = 1,
+    UnalignedStart = 2,
+    UnalignedEnd = 3,
+    UnalignedSize = 4,
+    UnalignedValue = 5,
+    NoMemAreas = 6,
+    EmptyMemArea = 7,
+    ZeroSize = 8,
+    IteratorFailed = 9,
+    CheckpointMismatch = 10,
+    AddressOverflow = 11,
+    NullPointer = 12,
+    AbiMismatch = 13,
+    Unsupported = 14,
+    GuardHit = 15,
+    Untranslated = 16,
+    TooManyAreas = 17,
+    Overlap = 18,
+    FastPathUnavailable = 19,
+    InvertedRange = 20,
+    IoFailed = 21,
+    MixedLineSizes = 22,
+    NoSuchArea = 23,
+    MapFailed = 24,
+    NoSuchGroup = 25,
+    DuplicateGroup = 26,
+    NotAddressOrder = 27,
+    TooManySelectors = 28,
+    SendFailed = 29,
+}
+
+impl From<Error> for MemscrubStatus {
+    fn from(error: Error) -> Self {
+        match error {
+            Error::InternalError => MemscrubStatus::InternalError,
+            Error::UnalignedStart => MemscrubStatus::UnalignedStart,
+            Error::UnalignedEnd => MemscrubStatus::UnalignedEnd,
+            Error::UnalignedSize => MemscrubStatus::UnalignedSize,
+            Error::UnalignedValue => MemscrubStatus::UnalignedValue,
+            Error::NoMemAreas => MemscrubStatus::NoMemAreas,
+            Error::EmptyMemArea => MemscrubStatus::EmptyMemArea,
+            Error::ZeroSize => MemscrubStatus::ZeroSize,
+            Error::IteratorFailed => MemscrubStatus::IteratorFailed,
+            Error::CheckpointMismatch => {
+                MemscrubStatus::CheckpointMismatch
+            }
+            Error::AddressOverflow => MemscrubStatus::AddressOverflow,
+            Error::GuardHit => MemscrubStatus::GuardHit,
+            Error::Untranslated => MemscrubStatus::Untranslated,
+            Error::TooManyAreas => MemscrubStatus::TooManyAreas,
+            Error::Overlap => MemscrubStatus::Overlap,
+            Error::FastPathUnavailable => {
+                MemscrubStatus::FastPathUnavailable
+            }
+            Error::InvertedRange => MemscrubStatus::InvertedRange,
+            Error::IoFailed => MemscrubStatus::IoFailed,
+            Error::MixedLineSizes => MemscrubStatus::MixedLineSizes,
+            Error::NoSuchArea => MemscrubStatus::NoSuchArea,
+            Error::MapFailed => MemscrubStatus::MapFailed,
+            Error::NoSuchGroup => MemscrubStatus::NoSuchGroup,
+            Error::DuplicateGroup => MemscrubStatus::DuplicateGroup,
+            Error::NotAddressOrder => MemscrubStatus::NotAddressOrder,
+            Error::TooManySelectors => MemscrubStatus::TooManySelectors,
+            Error::SendFailed => MemscrubStatus::SendFailed,
+        }
+    }
+}
+
+impl<T> From<Result<T, Error>> for MemscrubStatus {
+    fn from(result: Result<T, Error>) -> Self {
+        match result {
+            Ok(_) => MemscrubStatus::Ok,
+            Err(e) => e.into(),
+        }
+    }
+}
+
+// The backend for each read strategy. Strategies for other architectures
+// can't be built and are refused when the scrubber is created.
+enum StrategyBackend {
+    Portable(RawBackend),
+    Native(NativeBackend),
+    #[cfg(all(feature = "arch-x86_64", target_arch = "x86_64"))]
+    X86_64(X86_64Backend),
+    #[cfg(all(feature = "arch-aarch64", target_arch = "aarch64"))]
+    Aarch64(Aarch64Backend),
+    #[cfg(all(feature = "arch-riscv64", target_arch = "riscv64"))]
+    Riscv64(Riscv64Backend),
+}
+
+impl StrategyBackend {
+    // Returns the backend for a read strategy, or None if it isn't one or
+    // isn't available on this architecture
+    //
+    // Safety: as for RawBackend::new()
+    unsafe fn new(strategy: u32) -> Option<StrategyBackend> {
+        let backend = match strategy {
+            0 => StrategyBackend::Portable(RawBackend::new()),
+            1 => StrategyBackend::Native(NativeBackend::new()),
+            #[cfg(all(feature = "arch-x86_64", target_arch = "x86_64"))]
+            2 => StrategyBackend::X86_64(X86_64Backend::new()),
+            #[cfg(all(feature = "arch-aarch64", target_arch = "aarch64"))]
+            3 => StrategyBackend::Aarch64(Aarch64Backend::new()),
+            #[cfg(all(feature = "arch-riscv64", target_arch = "riscv64"))]
+            4 => StrategyBackend::Riscv64(Riscv64Backend::new()),
+            _ => return None,
+        };
+        Some(backend)
+    }
+}
+
+impl ScrubBackend for StrategyBackend {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        match self {
+            StrategyBackend::Portable(b) => b.read_line(addr),
+            StrategyBackend::Native(b) => b.read_line(addr),
+            #[cfg(all(feature = "arch-x86_64", target_arch = "x86_64"))]
+            StrategyBackend::X86_64(b) => b.read_line(addr),
+            #[cfg(all(feature = "arch-aarch64", target_arch = "aarch64"))]
+            StrategyBackend::Aarch64(b) => {
+                b.read_line(addr).and_then(|_| b.flush_line(addr))
+            }
+            #[cfg(all(feature = "arch-riscv64", target_arch = "riscv64"))]
+            StrategyBackend::Riscv64(b) => b.read_line(addr),
+        }
+    }
+
+    fn flush_line(&mut self, addr: usize) -> Result<(), Error> {
+        match self {
+            StrategyBackend::Portable(b) => b.flush_line(addr),
+            StrategyBackend::Native(b) => b.flush_line(addr),
+            #[cfg(all(feature = "arch-x86_64", target_arch = "x86_64"))]
+            StrategyBackend::X86_64(b) => b.flush_line(addr),
+            #[cfg(all(feature = "arch-aarch64", target_arch = "aarch64"))]
+            StrategyBackend::Aarch64(b) => b.flush_line(addr),
+            #[cfg(all(feature = "arch-riscv64", target_arch = "riscv64"))]
+            StrategyBackend::Riscv64(b) => b.flush_line(addr),
+        }
+    }
+}
+
+/// A scrubber created through the C interface, only seen by C as a pointer
+pub struct MemscrubHandle {
+    scrubber: LineScrubber<StrategyBackend>,
+}
+
+/// Returns whether a read strategy is available in this library
+#[no_mangle]
+pub extern "C" fn memscrub_read_strategy_supported(strategy: u32) -> bool {
+    // Creating a backend does nothing unsafe, only reading through it
+    unsafe { StrategyBackend::new(strategy) }.is_some()
+}
+
+/// Returns the version of the C interface implemented by the library,
+/// MEMSCRUB_ABI_VERSION
+#[no_mangle]
+pub extern "C" fn memscrub_abi_version() -> u32 {
+    MEMSCRUB_ABI_VERSION
+}
+
+/// Create a scrubber
+///
+/// # Arguments:
+/// * `config` - Configuration of the scrubber
+///
+/// * `areas` - The scrub areas
+///
+/// * `n_areas` - Number of scrub areas
+///
+/// * `handle` - Set to the new scrubber on success, otherwise to null
+///
+/// # Returns:
+/// MemscrubStatus::Ok on success, MemscrubStatus::AbiMismatch if the
+/// configuration is for another version of the interface,
+/// MemscrubStatus::Unsupported if the read strategy isn't available,
+/// MemscrubStatus::NullPointer if an area starts at address zero,
+/// otherwise the reason for failure
+///
+/// # Safety
+/// `config` must be valid for reading. `areas` must point to `n_areas`
+/// MemscrubAreas, and every address in the areas must stay mapped and
+/// readable until the scrubber is destroyed. `handle` must be valid for
+/// writing.
+#[no_mangle]
+pub unsafe extern "C" fn memscrub_create(
+    config: *const MemscrubConfig,
+    areas: *const MemscrubArea,
+    n_areas: usize,
+    handle: *mut *mut MemscrubHandle,
+) -> MemscrubStatus {
+    if handle.is_null() {
+        return MemscrubStatus::NullPointer;
+    }
+    *handle = ptr::null_mut();
+    if config.is_null() {
+        return MemscrubStatus::NullPointer;
+    }
+    // Only the version is read until it is known to match, since the rest
+    // of the structure may be laid out differently
+    if ptr::addr_of!((*config).abi_version).read() != MEMSCRUB_ABI_VERSION
+    {
+        return MemscrubStatus::AbiMismatch;
+    }
+    let config = &*config;
+    if areas.is_null() {
+        return match n_areas {
+            0 => MemscrubStatus::NoMemAreas,
+            _ => MemscrubStatus::NullPointer,
+        };
+    }
+
+    // The areas are checked here, where they come in, and are valid from
+    // here on
+    let mut virt_areas = Vec::with_capacity(n_areas);
+    for area in slice::from_raw_parts(areas, n_areas) {
+        let base = match NonNull::new(area.start as *mut u8) {
+            Some(base) => base,
+            None => return MemscrubStatus::NullPointer,
+        };
+        match VirtScrubArea::from_extent(base, area.end, config.line_size)
+        {
+            Ok(area) => virt_areas.push(area),
+            Err(e) => return e.into(),
+        }
+    }
+    let backend = match StrategyBackend::new(config.read_strategy) {
+        Some(backend) => backend,
+        None => return MemscrubStatus::Unsupported,
+    };
+    match LineScrubber::from_areas(
+        backend,
+        &virt_areas,
+        config.index_width,
+    ) {
+        Ok(scrubber) => {
+            *handle = Box::into_raw(Box::new(MemscrubHandle { scrubber }));
+            MemscrubStatus::Ok
+        }
+        Err(e) => e.into(),
+    }
+}
+
+/// Scrub the next bytes of the pass, starting another pass as needed
+///
+/// # Arguments:
+/// * `handle` - The scrubber
+///
+/// * `bytes` - Number of bytes, a multiple of the cache line size
+///
+/// # Returns:
+/// MemscrubStatus::Ok on success, otherwise the reason for failure
+///
+/// # Safety
+/// `handle` must have been returned by memscrub_create() and not yet
+/// destroyed, and not be in use by another thread
+#[no_mangle]
+pub unsafe extern "C" fn memscrub_scrub(
+    handle: *mut MemscrubHandle,
+    bytes: usize,
+) -> MemscrubStatus {
+    match handle.as_mut() {
+        Some(handle) => handle.scrubber.scrub(bytes).into(),
+        None => MemscrubStatus::NullPointer,
+    }
+}
+
+/// Get the statistics for a scrubber
+///
+/// # Arguments:
+/// * `handle` - The scrubber
+///
+/// * `stats` - Set to the statistics
+///
+/// # Returns:
+/// MemscrubStatus::Ok on success, otherwise MemscrubStatus::NullPointer
+///
+/// # Safety
+/// `handle` must have been returned by memscrub_create() and not yet
+/// destroyed, and `stats` must be valid for writing
+#[no_mangle]
+pub unsafe extern "C" fn memscrub_get_stats(
+    handle: *const MemscrubHandle,
+    stats: *mut MemscrubStats,
+) -> MemscrubStatus {
+    let (handle, out) = match (handle.as_ref(), stats.as_mut()) {
+        (Some(handle), Some(out)) => (handle, out),
+        _ => return MemscrubStatus::NullPointer,
+    };
+    let scrubber = &handle.scrubber;
+    let stats = scrubber.stats();
+    let now = scrubber.clock().now();
+    *out = MemscrubStats {
+        bytes_scrubbed: stats.bytes_scrubbed,
+        chunks: stats.chunks,
+        passes: stats.passes,
+        epoch: stats.epoch,
+        pass_offset: stats.pass_offset as u64,
+        pass_size: stats.pass_size as u64,
+        scrub_ns: nanos(stats.scrub_time),
+        max_chunk_ns: nanos(stats.max_chunk_time),
+        uptime_ns: nanos(now.saturating_duration_since(stats.started)),
+        pass_errors: stats.pass_errors,
+        last_pass_errors: stats.last_pass_errors,
+        errors_corrected: stats.errors_corrected(),
+        errors_uncorrected: stats.errors_uncorrected(),
+        n_areas: stats.areas.len() as u64,
+    };
+    MemscrubStatus::Ok
+}
+
+/// Get the status of a scrub area
+///
+/// # Arguments:
+/// * `handle` - The scrubber
+///
+/// * `area` - Index of the area, less than MemscrubStats::n_areas
+///
+/// * `status` - Set to the status of the area
+///
+/// # Returns:
+/// MemscrubStatus::Ok on success, MemscrubStatus::NoSuchArea if there is
+/// no such area, otherwise MemscrubStatus::NullPointer
+///
+/// # Safety
+/// `handle` must have been returned by memscrub_create() and not yet
+/// destroyed, and `status` must be valid for writing
+#[no_mangle]
+pub unsafe extern "C" fn memscrub_get_area_status(
+    handle: *const MemscrubHandle,
+    area: usize,
+    status: *mut MemscrubAreaStatus,
+) -> MemscrubStatus {
+    let (handle, out) = match (handle.as_ref(), status.as_mut()) {
+        (Some(handle), Some(out)) => (handle, out),
+        _ => return MemscrubStatus::NullPointer,
+    };
+    let scrubber = &handle.scrubber;
+    let (stats, &(start, end)) = match (
+        scrubber.stats().areas.get(area),
+        scrubber.extents().get(area),
+    ) {
+        (Some(stats), Some(extent)) => (stats, extent),
+        _ => return MemscrubStatus::NoSuchArea,
+    };
+    *out = MemscrubAreaStatus {
+        start,
+        end,
+        staleness_ns: scrubber
+            .staleness(area)
+            .map_or(MEMSCRUB_NEVER, nanos),
+        last_epoch: stats.last_epoch.unwrap_or(0),
+        errors_corrected: stats.errors_corrected,
+        errors_uncorrected: stats.errors_uncorrected,
+        excluded_lines: stats.unscrubbed_lines(),
+        error_rate: stats.error_rate_at(scrubber.clock().now()),
+        priority: stats.priority,
+    };
+    MemscrubStatus::Ok
+}
+
+/// Destroy a scrubber. A null handle is ignored.
+///
+/// # Safety
+/// `handle` must be null or have been returned by memscrub_create() and
+/// not yet destroyed
+#[no_mangle]
+pub unsafe extern "C" fn memscrub_destroy(handle: *mut MemscrubHandle) {
+    if !handle.is_null() {
+        drop(Box::from_raw(handle));
+    }
+}
+
+// The name of each status, as a nul-terminated string
+const STATUS_NAMES: &[(MemscrubStatus, &[u8])] = &[
+    (MemscrubStatus::Ok, b"ok\0"),
+    (MemscrubStatus::InternalError, b"internal error\0"),
+    (MemscrubStatus::UnalignedStart, b"unaligned start\0"),
+    (MemscrubStatus::UnalignedEnd, b"unaligned end\0"),
+    (MemscrubStatus::UnalignedSize, b"unaligned size\0"),
+    (MemscrubStatus::UnalignedValue, b"unaligned value\0"),
+    (MemscrubStatus::NoMemAreas, b"no memory areas\0"),
+    (MemscrubStatus::EmptyMemArea, b"empty memory area\0"),
+    (MemscrubStatus::ZeroSize, b"zero size\0"),
+    (MemscrubStatus::IteratorFailed, b"iterator failed\0"),
+    (MemscrubStatus::CheckpointMismatch, b"checkpoint mismatch\0"),
+    (MemscrubStatus::AddressOverflow, b"address overflow\0"),
+    (MemscrubStatus::NullPointer, b"null pointer\0"),
+    (MemscrubStatus::AbiMismatch, b"ABI version mismatch\0"),
+    (MemscrubStatus::Unsupported, b"unsupported\0"),
+    (MemscrubStatus::GuardHit, b"guard range read\0"),
+    (MemscrubStatus::Untranslated, b"address not translated\0"),
+    (MemscrubStatus::TooManyAreas, b"too many areas\0"),
+    (MemscrubStatus::Overlap, b"overlapping ranges\0"),
+    (
+        MemscrubStatus::FastPathUnavailable,
+        b"fast path unavailable\0",
+    ),
+    (MemscrubStatus::InvertedRange, b"range inverted\0"),
+    (MemscrubStatus::IoFailed, b"I/O failed\0"),
+    (MemscrubStatus::MixedLineSizes, b"mixed cache line sizes\0"),
+    (MemscrubStatus::NoSuchArea, b"no such scrub area\0"),
+    (MemscrubStatus::MapFailed, b"mapping memory failed\0"),
+    (MemscrubStatus::NoSuchGroup, b"no such area group\0"),
+    (
+        MemscrubStatus::DuplicateGroup,
+        b"area group name already used\0",
+    ),
+    (
+        MemscrubStatus::NotAddressOrder,
+        b"lines not read in address order\0",
+    ),
+    (
+        MemscrubStatus::TooManySelectors,
+        b"too many channel selectors\0",
+    ),
+    (
+        MemscrubStatus::SendFailed,
+        b"checkpoint could not be sent\0",
+    ),
+];
+
+/// Returns the name of a status as a static, nul-terminated string. The
+/// status is taken as its value, so that a value this library doesn't
+/// know of, such as one from a newer header, is named "unknown" rather
+/// than being read as an enum it can't hold.
+#[no_mangle]
+pub extern "C" fn memscrub_status_name(status: u32) -> *const c_char {
+    let name = STATUS_NAMES
+        .iter()
+        .find(|(s, _)| *s as u32 == status)
+        .map_or(&b"unknown\0"[..], |&(_, name)| name);
+    name.as_ptr() as *const c_char
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ffi::CStr;
+    use std::mem;
+
+    #[test]
+    fn test_ffi() {
+        let buffer = vec![0u8; 8192];
+        let start = (buffer.as_ptr() as usize).next_multiple_of(64);
+        let areas = [MemscrubArea {
+            start,
+            end: start + 4095,
+        }];
+
+        let config = MemscrubConfig {
+            abi_version: MEMSCRUB_ABI_VERSION,
+            read_strategy: MemscrubReadStrategy::Portable as u32,
+            line_size: 64,
+            index_width: 4,
+        };
+        let mut handle = ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                memscrub_create(&config, areas.as_ptr(), 1, &mut handle),
+                MemscrubStatus::Ok
+            );
+            assert_eq!(memscrub_scrub(handle, 1024), MemscrubStatus::Ok);
+            assert_eq!(
+                memscrub_scrub(handle, 100),
+                MemscrubStatus::UnalignedSize
+            );
+            memscrub_destroy(handle);
+
+            let bad = [MemscrubArea {
+                start: start + 1,
+                end: start + 4095,
+            }];
+            assert_eq!(
+                memscrub_create(&config, bad.as_ptr(), 1, &mut handle),
+                MemscrubStatus::UnalignedStart
+            );
+            assert!(handle.is_null());
+            let null = [MemscrubArea {
+                start: 0,
+                end: 4095,
+            }];
+            assert_eq!(
+                memscrub_create(&config, null.as_ptr(), 1, &mut handle),
+                MemscrubStatus::NullPointer
+            );
+            assert_eq!(
+                memscrub_scrub(ptr::null_mut(), 64),
+                MemscrubStatus::NullPointer
+            );
+        }
+
+        let name =
+            memscrub_status_name(MemscrubStatus::NullPointer as u32);
+        let name = unsafe { CStr::from_ptr(name) };
+        assert_eq!(name.to_str(), Ok("null pointer"));
+        let name = unsafe { CStr::from_ptr(memscrub_status_name(999)) };
+        assert_eq!(name.to_str(), Ok("unknown"));
+        drop(buffer);
+    }
+
+    #[test]
+    fn test_stats() {
+        let buffer = vec![0u8; 8192];
+        let start = (buffer.as_ptr() as usize).next_multiple_of(64);
+        let areas = [
+            MemscrubArea {
+                start,
+                end: start + 1023,
+            },
+            MemscrubArea {
+                start: start + 2048,
+                end: start + 4095,
+            },
+        ];
+        let config = MemscrubConfig {
+            abi_version: MEMSCRUB_ABI_VERSION,
+            read_strategy: MemscrubReadStrategy::Portable as u32,
+            line_size: 64,
+            index_width: 4,
+        };
+
+        let mut handle = ptr::null_mut();
+        let mut stats = MemscrubStats::default();
+        let mut area = MemscrubAreaStatus::default();
+        unsafe {
+            memscrub_create(&config, areas.as_ptr(), 2, &mut handle);
+            assert_eq!(memscrub_scrub(handle, 3072), MemscrubStatus::Ok);
+            assert_eq!(memscrub_scrub(handle, 64), MemscrubStatus::Ok);
+            assert_eq!(
+                memscrub_get_stats(handle, &mut stats),
+                MemscrubStatus::Ok
+            );
+            assert_eq!(
+                memscrub_get_area_status(handle, 1, &mut area),
+                MemscrubStatus::Ok
+            );
+            assert_eq!(
+                memscrub_get_area_status(handle, 2, &mut area),
+                MemscrubStatus::NoSuchArea
+            );
+            assert_eq!(
+                memscrub_get_stats(handle, ptr::null_mut()),
+                MemscrubStatus::NullPointer
+            );
+            memscrub_destroy(handle);
+        }
+
+        assert_eq!(stats.bytes_scrubbed, 3136);
+        assert_eq!(stats.chunks, 2);
+        assert_eq!(stats.passes, 1);
+        assert_eq!(stats.epoch, 2);
+        assert_eq!(stats.pass_offset, 64);
+        assert_eq!(stats.pass_size, 3072);
+        assert_eq!(stats.n_areas, 2);
+        assert_eq!((area.start, area.end), (start + 2048, start + 4095));
+        assert_eq!(area.last_epoch, 1);
+        assert_ne!(area.staleness_ns, MEMSCRUB_NEVER);
+        drop(buffer);
+    }
+
+    #[test]
+    fn test_abi_version() {
+        assert_eq!(memscrub_abi_version(), MEMSCRUB_ABI_VERSION);
+        let area = MemscrubArea { start: 0, end: 63 };
+        let config = MemscrubConfig {
+            abi_version: MEMSCRUB_ABI_VERSION + 1,
+            read_strategy: MemscrubReadStrategy::Portable as u32,
+            line_size: 64,
+            index_width: 4,
+        };
+        let mut handle = ptr::null_mut();
+        let status =
+            unsafe { memscrub_create(&config, &area, 1, &mut handle) };
+        assert_eq!(status, MemscrubStatus::AbiMismatch);
+        assert!(handle.is_null());
+    }
+
+    #[test]
+    fn test_read_strategy() {
+        let buffer = vec![0u8; 8192];
+        let start = (buffer.as_ptr() as usize).next_multiple_of(64);
+        let area = MemscrubArea {
+            start,
+            end: start + 4095,
+        };
+        let mut config = MemscrubConfig {
+            abi_version: MEMSCRUB_ABI_VERSION,
+            read_strategy: MemscrubReadStrategy::Native as u32,
+            line_size: 64,
+            index_width: 4,
+        };
+
+        let mut handle = ptr::null_mut();
+        unsafe {
+            assert_eq!(
+                memscrub_create(&config, &area, 1, &mut handle),
+                MemscrubStatus::Ok
+            );
+            assert_eq!(memscrub_scrub(handle, 4096), MemscrubStatus::Ok);
+            memscrub_destroy(handle);
+
+            config.read_strategy = 99;
+            assert_eq!(
+                memscrub_create(&config, &area, 1, &mut handle),
+                MemscrubStatus::Unsupported
+            );
+        }
+
+        assert!(memscrub_read_strategy_supported(0));
+        assert!(memscrub_read_strategy_supported(1));
+        assert!(!memscrub_read_strategy_supported(99));
+        #[cfg(target_arch = "x86_64")]
+        assert!(!memscrub_read_strategy_supported(
+            MemscrubReadStrategy::Aarch64 as u32
+        ));
+        drop(buffer);
+    }
+
+    // Offset of the field after one at an offset with a size, where the
+    // field has an alignment, as laid out by a C compiler
+    const fn after(offset: usize, size: usize, align: usize) -> usize {
+        (offset + size).next_multiple_of(align)
+    }
+
+    // Changing any of these changes the ABI, and so MEMSCRUB_ABI_VERSION.
+    // The C++ test checks the header against the same values.
+    #[test]
+    fn test_layout() {
+        const WORD: usize = mem::size_of::<usize>();
+        const WORD_ALIGN: usize = mem::align_of::<usize>();
+        const U64_ALIGN: usize = mem::align_of::<u64>();
+
+        assert_eq!(mem::size_of::<MemscrubArea>(), 2 * WORD);
+        assert_eq!(mem::align_of::<MemscrubArea>(), WORD_ALIGN);
+        assert_eq!(mem::offset_of!(MemscrubArea, start), 0);
+        assert_eq!(mem::offset_of!(MemscrubArea, end), WORD);
+
+        // The u32 fields are followed by padding up to a word boundary
+        // where words are eight bytes, and by none where they are four
+        let line_size = after(4, 4, WORD_ALIGN);
+        assert_eq!(mem::offset_of!(MemscrubConfig, abi_version), 0);
+        assert_eq!(mem::offset_of!(MemscrubConfig, read_strategy), 4);
+        assert_eq!(mem::offset_of!(MemscrubConfig, line_size), line_size);
+        assert_eq!(
+            mem::offset_of!(MemscrubConfig, index_width),
+            line_size + WORD
+        );
+        assert_eq!(
+            mem::size_of::<MemscrubConfig>(),
+            after(line_size + WORD, WORD, WORD_ALIGN.max(4))
+        );
+
+        assert_eq!(mem::size_of::<MemscrubStats>(), 14 * 8);
+        assert_eq!(mem::offset_of!(MemscrubStats, pass_offset), 4 * 8);
+        assert_eq!(mem::offset_of!(MemscrubStats, n_areas), 13 * 8);
+
+        let staleness = after(WORD, WORD, U64_ALIGN);
+        assert_eq!(
+            mem::offset_of!(MemscrubAreaStatus, staleness_ns),
+            staleness
+        );
+        assert_eq!(
+            mem::offset_of!(MemscrubAreaStatus, error_rate),
+            staleness + 40
+        );
+        assert_eq!(
+            mem::offset_of!(MemscrubAreaStatus, priority),
+            staleness + 48
+        );
+        assert_eq!(
+            mem::size_of::<MemscrubAreaStatus>(),
+            after(staleness + 48, 4, WORD_ALIGN.max(U64_ALIGN))
+        );
+
+        assert_eq!(mem::size_of::<MemscrubStatus>(), 4);
+        assert_eq!(MemscrubStatus::NullPointer as u32, 12);
+        assert_eq!(MemscrubStatus::AbiMismatch as u32, 13);
+        assert_eq!(MemscrubStatus::Unsupported as u32, 14);
+        assert_eq!(MemscrubReadStrategy::Riscv64 as u32, 4);
+    }
+
+    // The test of the C++ wrapper, compiled by build.rs
+    #[cfg(memscrub_cpp_test)]
+    #[link(name = "memscrub_cpp_test", kind = "static")]
+    extern "C" {
+        fn memscrub_cpp_test() -> i32;
+    }
+
+    #[cfg(memscrub_cpp_test)]
+    #[test]
+    fn test_cpp() {
+        assert_eq!(unsafe { memscrub_cpp_test() }, 0);
+    }
+}
@@ -0,0 +1,499 @@
+// Telling cache faults from memory faults. Corrected errors that keep
+// coming from the same address usually mean a failing DRAM cell, but they
+// can also come from a stale copy of the line held in the cache, in which
+// case excluding or retiring the memory would be the wrong remedy. When a
+// line has had enough corrected errors, FlushRetry flushes it and reads it
+// again straight from memory. If errors go on after that, the fault is in
+// memory and the errors are passed on to the policy that escalates them,
+// such as a Blacklist. If they stop, the fault was in the cache and
+// nothing is escalated. Either way an event says where the fault was.
+//
+// The corrected errors in a line are held back until it is known where
+// they came from, so the escalating policy never acts on a cache fault. A
+// line is only taken to be flushed once the scrubber reports, with a
+// LineFlushed event, that it was. If the backend can't flush lines, where
+// the errors came from can't be known and those held back are passed on.
+// So are those of a line that goes a window without enough errors to be
+// flushed. Uncorrected errors and all other events are passed straight
+// on to the escalating policy.
+//
+// The errors in each line are counted with a Threshold whose blocks are
+// the cache lines, and a line is flushed as it crosses the threshold.
+
+use std::time::{Duration, Instant};
+
+use crate::base::*;
+use crate::event::*;
+use crate::policy::*;
+use crate::threshold::*;
+
+/// When lines are flushed and how long their errors are remembered
+///
+/// * `repeats` - Corrected errors in a line after which it is flushed
+///
+/// * `window` - Time after which a line's errors are forgotten. A line
+///   with no errors this long after it was flushed had a cache fault.
+///
+/// * `line_size` - Number of bytes in a cache line, a power of two
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FlushRetryPolicy {
+    pub repeats: usize,
+    pub window: Duration,
+    pub line_size: usize,
+}
+
+impl Default for FlushRetryPolicy {
+    fn default() -> Self {
+        FlushRetryPolicy {
+            repeats: 3,
+            window: Duration::from_secs(10 * 60),
+            line_size: 64,
+        }
+    }
+}
+
+// Corrected errors in one cache line
+//
+// held:     Errors held back until it is known where they came from
+// last:     Time of the last error, or of the flush
+// flushing: Whether the line has been asked to be flushed
+// flushed:  Whether the line has been flushed and read again
+#[derive(Clone, Debug)]
+struct LineErrors {
+    held: Vec<ScrubEvent>,
+    last: Instant,
+    flushing: bool,
+    flushed: bool,
+}
+
+/// A Policy flushing lines with repeated corrected errors, and passing
+/// errors on to another policy only once they are known to come from
+/// memory
+///
+/// * `policy` - When lines are flushed
+///
+/// * `threshold` - Corrected errors counted in each cache line
+///
+/// * `escalate` - Policy given errors from memory, and every other event
+///
+/// * `lines` - Corrected errors in each cache line, by line address
+///
+/// * `sinks` - Receivers of an event as each fault is located
+pub struct FlushRetry<'a> {
+    policy: FlushRetryPolicy,
+    threshold: Threshold,
+    escalate: Box<dyn Policy + 'a>,
+    lines: AddrMap<LineErrors>,
+    sinks: Vec<Box<dyn EventSink + 'a>>,
+}
+
+impl<'a> FlushRetry<'a> {
+    /// Create a FlushRetry
+    ///
+    /// # Arguments:
+    /// * `policy` - When lines are flushed
+    ///
+    /// * `escalate` - Policy given errors once they are known to come
+    ///   from memory
+    ///
+    /// # Returns:
+    /// Ok(FlushRetry) on success, otherwise Err(Error::ZeroSize) if the
+    /// number of repeats is zero or Err(Error::UnalignedValue) if the
+    /// line size isn't a power of two
+    pub fn new(
+        policy: FlushRetryPolicy,
+        escalate: Box<dyn Policy + 'a>,
+    ) -> Result<FlushRetry<'a>, Error> {
+        let threshold = Threshold::new(ThresholdPolicy {
+            errors: policy.repeats,
+            window: policy.window,
+            clean_passes: 1,
+            granularity: policy.line_size,
+        })?;
+        Ok(FlushRetry {
+            policy,
+            threshold,
+            escalate,
+            lines: AddrMap::new(),
+            sinks: Vec::new(),
+        })
+    }
+
+    /// Add a sink to receive an event as each fault is located
+    pub fn add_event_sink(&mut self, sink: Box<dyn EventSink + 'a>) {
+        self.sinks.push(sink);
+    }
+
+    /// Returns the policy
+    pub fn policy(&self) -> &FlushRetryPolicy {
+        &self.policy
+    }
+
+    /// Returns whether the line holding an address has been flushed and
+    /// is waiting to see whether its errors go on
+    pub fn is_flushed(&self, addr: usize) -> bool {
+        let line = self.threshold.block(addr);
+        self.lines.get(line).is_some_and(|l| l.flushed)
+    }
+
+    // Forget lines with no errors for a window. Those that were flushed
+    // had cache faults, and the errors of the others are passed on.
+    fn expire(&mut self, now: Instant) -> Vec<PolicyAction> {
+        let window = self.policy.window;
+        let expired: Vec<usize> = self
+            .lines
+            .iter()
+            .filter(|(_, e)| {
+                now.saturating_duration_since(e.last) >= window
+            })
+            .map(|(line, _)| line)
+            .collect();
+
+        let mut actions = Vec::new();
+        for line in expired {
+            let errors = match self.lines.remove(line) {
+                Some(errors) => errors,
+                None => continue,
+            };
+            self.threshold.clear(line);
+            match errors.flushed {
+                true => self.emit(&ScrubEvent::FaultLocated {
+                    addr: line as u64,
+                    site: FaultSite::Cache,
+                }),
+                false => actions.extend(self.release(&errors.held, now)),
+            }
+        }
+        actions
+    }
+
+    // Pass errors held back on to the escalating policy
+    fn release(
+        &mut self,
+        held: &[ScrubEvent],
+        now: Instant,
+    ) -> Vec<PolicyAction> {
+        held.iter()
+            .flat_map(|event| self.escalate.event(event, now))
+            .collect()
+    }
+
+    // Hold back a corrected error, flushing its line once it has had
+    // enough of them and passing them all on if it already has been
+    fn corrected(
+        &mut self,
+        error: &ErrorEvent,
+        event: &ScrubEvent,
+        now: Instant,
+    ) -> Vec<PolicyAction> {
+        let line = self.threshold.block(error.addr as usize);
+        let errors = self.lines.get_or_insert_with(line, || LineErrors {
+            held: Vec::new(),
+            last: now,
+            flushing: false,
+            flushed: false,
+        });
+        errors.held.push(*event);
+        errors.last = now;
+
+        // An error after the flush came from memory
+        if errors.flushed {
+            let held = std::mem::take(&mut errors.held);
+            self.lines.remove(line);
+            self.threshold.clear(line);
+            self.emit(&ScrubEvent::FaultLocated {
+                addr: line as u64,
+                site: FaultSite::Memory,
+            });
+            return self.release(&held, now);
+        }
+        // A line is asked to be flushed only as it crosses the threshold
+        if self.threshold.record(line, now).is_none() {
+            return Vec::new();
+        }
+        errors.flushing = true;
+        vec![PolicyAction::FlushReread { addr: line }]
+    }
+
+    // Note whether a line asked to be flushed was. Where the errors in a
+    // line that can't be flushed came from can't be known, so they are
+    // passed on.
+    fn flushed(
+        &mut self,
+        line: usize,
+        flushed: bool,
+        now: Instant,
+    ) -> Vec<PolicyAction> {
+        let errors = match self.lines.get_mut(line) {
+            Some(errors) if errors.flushing => errors,
+            _ => return Vec::new(),
+        };
+        errors.flushing = false;
+        if flushed {
+            errors.flushed = true;
+            errors.last = now;
+            return Vec::new();
+        }
+        let held = std::mem::take(&mut errors.held);
+        self.lines.remove(line);
+        self.threshold.clear(line);
+        self.release(&held, now)
+    }
+
+    // Send an event to all event sinks
+    fn emit(&mut self, event: &ScrubEvent) {
+        for sink in self.sinks.iter_mut() {
+            sink.event(event);
+        }
+    }
+}
+
+impl Policy for FlushRetry<'_> {
+    fn event(
+        &mut self,
+        event: &ScrubEvent,
+        now: Instant,
+    ) -> Vec<PolicyAction> {
+        let mut actions = self.expire(now);
+        match event {
+            ScrubEvent::Error(e)
+                if e.severity == ErrorSeverity::Corrected =>
+            {
+                actions.extend(self.corrected(e, event, now))
+            }
+            ScrubEvent::LineFlushed { addr, flushed } => {
+                actions.extend(self.flushed(
+                    *addr as usize,
+                    *flushed,
+                    now,
+                ));
+                actions.extend(self.escalate.event(event, now));
+            }
+            _ => actions.extend(self.escalate.event(event, now)),
+        }
+        actions
+    }
+
+    fn areas_changed(&mut self, extents: &[(usize, usize)]) {
+        self.escalate.areas_changed(extents);
+    }
+
+    fn config_changed(&mut self, change: &ConfigChange, now: Instant) {
+        self.escalate.config_changed(change, now);
+    }
+
+    fn buffers(&self) -> Vec<(usize, usize)> {
+        let mut buffers = self.threshold.buffers();
+        buffers.push(self.lines.buffer());
+        buffers
+            .extend(self.lines.iter().map(|(_, e)| vec_buffer(&e.held)));
+        buffers.extend(policy_buffers(self.escalate.as_ref()));
+        buffers.push(vec_buffer(&self.sinks));
+        buffers
+            .extend(self.sinks.iter().map(|s| value_buffer(s.as_ref())));
+        buffers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::*;
+    use crate::sim::*;
+    use crate::testutil::*;
+
+    fn corrected(addr: u64) -> ScrubEvent {
+        ScrubEvent::Error(ErrorEvent {
+            addr,
+            area: Some(0),
+            severity: ErrorSeverity::Corrected,
+        })
+    }
+
+    fn flush_retry(events: &Collector) -> FlushRetry<'static> {
+        let policy = FlushRetryPolicy {
+            repeats: 2,
+            window: Duration::from_secs(60),
+            line_size: 64,
+        };
+        let escalate = Blacklist {
+            corrected: true,
+            ..Blacklist::new(64)
+        };
+        let mut retry =
+            FlushRetry::new(policy, Box::new(escalate)).unwrap();
+        retry.add_event_sink(Box::new(events.clone()));
+        retry
+    }
+
+    #[test]
+    fn test_memory_fault() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let events = Collector::default();
+        let mut retry = flush_retry(&events);
+
+        // The first error is held back, the repeat flushes the line
+        assert!(retry.event(&corrected(0x1010), at(0)).is_empty());
+        assert_eq!(
+            retry.event(&corrected(0x1020), at(1)),
+            [PolicyAction::FlushReread { addr: 0x1000 }]
+        );
+        assert!(!retry.is_flushed(0x1000));
+        let flushed = ScrubEvent::LineFlushed {
+            addr: 0x1000,
+            flushed: true,
+        };
+        assert!(retry.event(&flushed, at(1)).is_empty());
+        assert!(retry.is_flushed(0x1000));
+
+        // Another error after the flush is from memory
+        let uncorrected = ScrubEvent::Error(ErrorEvent {
+            addr: 0x1000,
+            area: Some(0),
+            severity: ErrorSeverity::Uncorrected,
+        });
+        assert_eq!(
+            retry.event(&uncorrected, at(2)),
+            [PolicyAction::Exclude {
+                start: 0x1000,
+                end: 0x103f
+            }]
+        );
+        let exclude = PolicyAction::Exclude {
+            start: 0x1000,
+            end: 0x103f,
+        };
+        assert_eq!(retry.event(&corrected(0x1000), at(3)), [exclude; 3]);
+        assert_eq!(
+            events.events(),
+            [ScrubEvent::FaultLocated {
+                addr: 0x1000,
+                site: FaultSite::Memory
+            }]
+        );
+        assert!(!retry.is_flushed(0x1000));
+    }
+
+    #[test]
+    fn test_cache_fault() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let events = Collector::default();
+        let mut retry = flush_retry(&events);
+
+        retry.event(&corrected(0x2000), at(0));
+        retry.event(&corrected(0x2000), at(10));
+        let flushed = ScrubEvent::LineFlushed {
+            addr: 0x2000,
+            flushed: true,
+        };
+        retry.event(&flushed, at(10));
+
+        // No error for a window after the flush means a cache fault
+        let chunk = ScrubEvent::ChunkComplete {
+            bytes: 64,
+            duration: Duration::ZERO,
+        };
+        retry.event(&chunk, at(69));
+        assert!(events.events().is_empty());
+        retry.event(&chunk, at(70));
+        assert_eq!(
+            events.events(),
+            [ScrubEvent::FaultLocated {
+                addr: 0x2000,
+                site: FaultSite::Cache
+            }]
+        );
+
+        // Errors too far apart are never repeats, and are passed on when
+        // the window ends
+        assert!(retry.event(&corrected(0x3000), at(100)).is_empty());
+        assert_eq!(
+            retry.event(&corrected(0x3000), at(200)),
+            [PolicyAction::Exclude {
+                start: 0x3000,
+                end: 0x303f
+            }]
+        );
+        assert!(!retry.is_flushed(0x3000));
+    }
+
+    #[test]
+    fn test_no_flush() {
+        let start = Instant::now();
+        let events = Collector::default();
+        let mut retry = flush_retry(&events);
+
+        // Errors in a line that can't be flushed are passed on
+        retry.event(&corrected(0x1000), start);
+        retry.event(&corrected(0x1000), start);
+        let flushed = ScrubEvent::LineFlushed {
+            addr: 0x1000,
+            flushed: false,
+        };
+        let exclude = PolicyAction::Exclude {
+            start: 0x1000,
+            end: 0x103f,
+        };
+        assert_eq!(retry.event(&flushed, start), [exclude; 2]);
+        assert!(!retry.is_flushed(0x1000));
+        assert!(events.events().is_empty());
+
+        // As they are by a scrubber whose backend can't flush
+        let mut scrubber =
+            LineScrubber::new(Recorder::default(), &[(0, 8191)], 64, 4)
+                .unwrap();
+        scrubber.add_policy(Box::new(flush_retry(&events)));
+        let error = ErrorEvent {
+            addr: 0x1040,
+            area: Some(0),
+            severity: ErrorSeverity::Corrected,
+        };
+        scrubber.record_error(error).unwrap();
+        assert!(scrubber.excluded().is_empty());
+        scrubber.record_error(error).unwrap();
+        assert_eq!(scrubber.excluded(), [(0x1040, 0x107f)]);
+        assert!(scrubber.backend().reads().is_empty());
+    }
+
+    #[test]
+    fn test_scrubber() {
+        let events = Collector::default();
+        let mem = SimMemory::new(0, 8192, 64).unwrap();
+        let mut scrubber =
+            LineScrubber::new(mem, &[(0, 8191)], 64, 4).unwrap();
+        scrubber.add_policy(Box::new(flush_retry(&events)));
+
+        let error = ErrorEvent {
+            addr: 0x1040,
+            area: Some(0),
+            severity: ErrorSeverity::Corrected,
+        };
+        scrubber.record_error(error).unwrap();
+        assert_eq!(scrubber.backend().reads_at(0x1040), Some(0));
+        scrubber.record_error(error).unwrap();
+        assert_eq!(scrubber.backend().reads_at(0x1040), Some(1));
+
+        // An error after that is from memory, and doesn't flush again
+        scrubber.record_error(error).unwrap();
+        assert_eq!(scrubber.backend().reads_at(0x1040), Some(1));
+        assert_eq!(
+            events.events(),
+            [ScrubEvent::FaultLocated {
+                addr: 0x1040,
+                site: FaultSite::Memory
+            }]
+        );
+
+        let policy = FlushRetryPolicy {
+            repeats: 0,
+            ..FlushRetryPolicy::default()
+        };
+        let escalate = Box::new(DemandScrub { bytes: 0 });
+        assert_eq!(
+            FlushRetry::new(policy, escalate).err(),
+            Some(Error::ZeroSize)
+        );
+    }
+}
@@ -0,0 +1,297 @@
+// Fuzzing of scrub area validation and iteration. Random scrub areas and
+// cache geometries are run through ScrubOrder and the result is compared
+// with a simple model of the order the scrubber should use. The iterators
+// over MemAreas walk a ScrubOrder, so this covers them too. The same areas
+// are then scrubbed by a LineScrubber, a chunk at a time, to check that
+// the lines it reads are those of the model. The parsers of files and
+// tables the scrubber reads are given arbitrary bytes, to check that bad
+// input is rejected rather than causing a panic. The targets in fuzz/ call
+// these checks with inputs generated by cargo-fuzz:
+//
+//      cargo +nightly fuzz run scrub_order
+//      cargo +nightly fuzz run line_scrubber
+//      cargo +nightly fuzz run parsers
+//
+// Areas may lie anywhere in the address space, including at its very top,
+// so that overflow in address arithmetic shows up as a panic.
+
+use arbitrary::Arbitrary;
+
+use crate::backend::*;
+use crate::badblocks::*;
+use crate::base::*;
+use crate::dryrun::*;
+use crate::os::*;
+use crate::persist::*;
+use crate::trace::*;
+
+// Limits keeping the model small enough to run quickly
+const MAX_AREAS: usize = 8;
+const MAX_INDEX_WIDTH: u8 = 12;
+
+/// One scrub area, given in cache lines
+///
+/// * `start` - First cache line in the area
+///
+/// * `lines` - Number of cache lines in the area
+#[derive(Arbitrary, Clone, Copy, Debug)]
+pub struct FuzzArea {
+    pub start: usize,
+    pub lines: u16,
+}
+
+/// Input for check_scrub_order()
+///
+/// * `areas` - The scrub areas
+///
+/// * `line_shift` - Log2 of the cache line size
+///
+/// * `index_width` - Number of address bits in the cache index
+///
+/// * `chunk_lines` - Number of cache lines per chunk
+#[derive(Arbitrary, Clone, Debug)]
+pub struct FuzzInput {
+    pub areas: Vec<FuzzArea>,
+    pub line_shift: u8,
+    pub index_width: u8,
+    pub chunk_lines: u16,
+}
+
+// Convert the areas to address extents, dropping any that don't fit in the
+// address space
+fn fuzz_extents(
+    input: &FuzzInput,
+    line_size: usize,
+) -> Vec<(usize, usize)> {
+    input
+        .areas
+        .iter()
+        .take(MAX_AREAS)
+        .filter_map(|a| {
+            let start = a.start.checked_mul(line_size)?;
+            let len = (a.lines.max(1) as usize).checked_mul(line_size)?;
+            Some((start, start.checked_add(len - 1)?))
+        })
+        .collect()
+}
+
+// The order in which the scrubber should read the areas, found the slow
+// way: each line is put in the list for its cache index, then the lists
+// are joined
+fn model_order(
+    extents: &[(usize, usize)],
+    line_size: usize,
+    index_width: usize,
+) -> Vec<usize> {
+    let sets = 1usize << index_width;
+    let mut by_index = vec![Vec::new(); sets];
+    for &(start, end) in extents {
+        for line in start / line_size..=end / line_size {
+            by_index[line % sets].push(line * line_size);
+        }
+    }
+    by_index.concat()
+}
+
+// A backend recording the address of each line read
+struct FuzzBackend(Vec<usize>);
+
+impl ScrubBackend for FuzzBackend {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        self.0.push(addr);
+        Ok(())
+    }
+}
+
+// Returns the cache line size and index width given by an input
+fn fuzz_geometry(input: &FuzzInput) -> (usize, usize) {
+    let line_size = 1usize << (input.line_shift % 8);
+    let index_width = (input.index_width % (MAX_INDEX_WIDTH + 1)) as usize;
+    (line_size, index_width)
+}
+
+/// Check ScrubOrder against the model for a fuzzer-generated input,
+/// panicking on any difference
+pub fn check_scrub_order(input: &FuzzInput) {
+    let (line_size, index_width) = fuzz_geometry(input);
+    let extents = fuzz_extents(input, line_size);
+
+    let order = match ScrubOrder::new(&extents, line_size, index_width) {
+        Ok(order) => order,
+        Err(e) => {
+            assert!(extents.is_empty(), "valid areas rejected: {}", e);
+            return;
+        }
+    };
+    let expected = model_order(&extents, line_size, index_width);
+    assert_eq!(order.len(), expected.len());
+
+    // Reading the pass in chunks gives the same order as reading it at once
+    let mut order = order;
+    let mut addrs = Vec::with_capacity(expected.len());
+    let chunk = (input.chunk_lines as usize).max(1);
+    loop {
+        let before = addrs.len();
+        addrs.extend(order.by_ref().take(chunk));
+        if addrs.len() == before {
+            break;
+        }
+    }
+    assert_eq!(addrs, expected);
+
+    for &addr in &addrs {
+        assert_eq!(addr % line_size, 0);
+        assert!(extents.iter().any(|&(s, e)| addr >= s && addr <= e));
+    }
+
+    let trace = ScrubTrace::record(addrs.iter().copied());
+    let restored = ScrubTrace::from_bytes(&trace.to_bytes())
+        .expect("trace round trip failed");
+    assert_eq!(restored.verify(addrs), Ok(()));
+}
+
+/// Check that a LineScrubber scrubbing a pass a chunk at a time reads the
+/// lines of the model in its order, panicking on any difference
+pub fn check_line_scrubber(input: &FuzzInput) {
+    let (line_size, index_width) = fuzz_geometry(input);
+    let extents = fuzz_extents(input, line_size);
+
+    let backend = FuzzBackend(Vec::new());
+    let mut scrubber =
+        match LineScrubber::new(backend, &extents, line_size, index_width)
+        {
+            Ok(scrubber) => scrubber,
+            Err(e) => {
+                assert!(extents.is_empty(), "valid areas rejected: {}", e);
+                return;
+            }
+        };
+    let expected = model_order(&extents, line_size, index_width);
+
+    let size = expected.len() * line_size;
+    let chunk = (input.chunk_lines as usize).max(1) * line_size;
+    let mut done = 0;
+    while done < size {
+        let bytes = chunk.min(size - done);
+        scrubber.scrub(bytes).expect("scrub failed");
+        done += bytes;
+    }
+    assert_eq!(scrubber.backend().0, expected);
+    assert_eq!(scrubber.stats().passes, 1);
+    assert_eq!(scrubber.stats().bytes_scrubbed, size as u64);
+}
+
+/// Run the parsers of files and tables read by the scrubber over arbitrary
+/// bytes, panicking if any of them does. Input that a parser accepts must
+/// be written back as text that parses to the same value.
+pub fn check_parsers(data: &[u8]) {
+    let text = String::from_utf8_lossy(data);
+
+    if let Ok(state) = ScrubState::parse(&text) {
+        let again = ScrubState::parse(&state.to_text())
+            .expect("scrub state round trip failed");
+        assert_eq!(again.to_text(), state.to_text());
+    }
+    if let Ok(list) = BadBlockList::parse(&text) {
+        let again = BadBlockList::parse(&list.to_text())
+            .expect("bad block list round trip failed");
+        assert_eq!(again, list);
+    }
+    let _ = ScrubTrace::from_bytes(data);
+
+    #[cfg(target_os = "linux")]
+    {
+        for line in text.lines() {
+            let _ = McEvent::parse(line);
+        }
+        let _ = parse_maps(&text);
+    }
+    let _ = DimmMap::parse(&text);
+    let _ = DimmMap::from_dmidecode(&text);
+    let _ = SmbiosMemory::parse(data);
+    let _ = Iomem::parse(&text);
+    let _ = PhysSegs::parse(&text);
+    let _ = PromMemory::parse(&text);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use arbitrary::Unstructured;
+
+    #[test]
+    fn test_edges() {
+        // An area at the very top of the address space
+        let top = FuzzInput {
+            areas: vec![FuzzArea {
+                start: usize::MAX / 64,
+                lines: 1,
+            }],
+            line_shift: 6,
+            index_width: 4,
+            chunk_lines: 3,
+        };
+
+        // Overlapping areas, and an area that doesn't fit
+        let overlapping = FuzzInput {
+            areas: vec![
+                FuzzArea {
+                    start: 0,
+                    lines: 40,
+                },
+                FuzzArea {
+                    start: 8,
+                    lines: 16,
+                },
+                FuzzArea {
+                    start: usize::MAX,
+                    lines: 2,
+                },
+            ],
+            line_shift: 3,
+            index_width: 3,
+            chunk_lines: 0,
+        };
+
+        for input in [top, overlapping] {
+            check_scrub_order(&input);
+            check_line_scrubber(&input);
+        }
+    }
+
+    #[test]
+    fn test_parsers() {
+        check_parsers(
+            b"epoch 2\npasses 1\noffset 0x400\n\
+              area 0x0 0xfff 1 0 0 0 - 0x0-0xfff\n\
+              exclude 0x100 0x13f\nkeys physical\n",
+        );
+        check_parsers(
+            b"area 0x0 0xfff - 0 0 NaN 99999999999999999999 x\n",
+        );
+        check_parsers(b"0x1000 0x1fff\n0x10 0x0\n");
+        check_parsers(b"0-fff : System RAM\n  100-1ff : Kernel code\n");
+        check_parsers(&[0x11, 0xff, 0x00, 0x00, 0x00]);
+        check_parsers(b"");
+    }
+
+    #[test]
+    fn test_random() {
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut bytes = vec![0u8; 4096];
+        for _ in 0..64 {
+            for b in bytes.iter_mut() {
+                state ^= state << 13;
+                state ^= state >> 7;
+                state ^= state << 17;
+                *b = state as u8;
+            }
+            let mut u = Unstructured::new(&bytes);
+            if let Ok(input) = FuzzInput::arbitrary(&mut u) {
+                check_scrub_order(&input);
+                check_line_scrubber(&input);
+            }
+            check_parsers(&bytes[..state as usize % bytes.len()]);
+        }
+    }
+}
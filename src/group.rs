@@ -0,0 +1,657 @@
+// Named groups of scrub areas. Operators think of memory as, say, the
+// kernel, each tenant and the SRAM of a device rather than as a list of
+// address ranges, so AreaGroups puts scrub areas into named groups, which
+// may themselves be in larger groups, and lets each group have:
+//
+//  * a deadline, the longest its memory should go without being scrubbed,
+//    which also applies to every group within it, and
+//  * a rate budget, the most bandwidth scrubbing its memory may use.
+//
+// A pass covers every area, so a group whose deadline is shorter than a
+// pass has its areas scrubbed faster than the rest, with set_area_rate(),
+// by as much as its deadline needs and its budget, and those of the groups
+// it is in, allow. The statistics of the areas in a group, and in the
+// groups within it, are rolled up into one GroupStats.
+//
+// Areas are put in groups by index, but the scrubber's areas can be added,
+// removed and resized, so AreaGroups keeps the extent of each area and is
+// brought up to date with sync(). An area keeps its group for as long as
+// its start address is unchanged. As a policy, it is synced by the
+// scrubber itself.
+
+use std::time::{Duration, Instant};
+
+use crate::backend::*;
+use crate::base::*;
+use crate::event::*;
+use crate::policy::*;
+use crate::stats::*;
+
+/// A named group of scrub areas
+///
+/// * `name` - Name of the group, such as "kernel"
+///
+/// * `parent` - The group this group is in, if any
+///
+/// * `deadline` - Longest time the memory of the group should go without
+///   being scrubbed, if there is a limit
+///
+/// * `rate_budget` - Most bytes per second that scrubbing the memory of
+///   the group may use, if there is a limit
+#[derive(Clone, Debug, PartialEq)]
+pub struct AreaGroup {
+    pub name: String,
+    pub parent: Option<usize>,
+    pub deadline: Option<Duration>,
+    pub rate_budget: Option<u64>,
+}
+
+/// Statistics of the scrub areas in a group and the groups within it
+///
+/// * `name` - Name of the group
+///
+/// * `areas` - Number of scrub areas
+///
+/// * `size` - Number of bytes in the areas
+///
+/// * `excluded_lines` - Number of cache lines left out of scrubbing
+///
+/// * `errors_corrected` - Number of corrected errors
+///
+/// * `errors_uncorrected` - Number of uncorrected errors
+///
+/// * `error_rate` - Estimated corrected errors per hour
+///
+/// * `last_scrubbed` - Time at which the least recently scrubbed area was
+///   last completely scrubbed, or None if any area never has been
+///
+/// * `deadline` - Deadline of the group, including those of the groups it
+///   is in
+#[derive(Clone, Debug, PartialEq)]
+pub struct GroupStats {
+    pub name: String,
+    pub areas: usize,
+    pub size: usize,
+    pub excluded_lines: u64,
+    pub errors_corrected: u64,
+    pub errors_uncorrected: u64,
+    pub error_rate: f64,
+    pub last_scrubbed: Option<Instant>,
+    pub deadline: Option<Duration>,
+}
+
+impl GroupStats {
+    /// Returns the time since every area of the group was last completely
+    /// scrubbed, or None if one never has been
+    pub fn staleness(&self, now: Instant) -> Option<Duration> {
+        self.last_scrubbed.map(|t| now.saturating_duration_since(t))
+    }
+
+    /// Returns whether the group has been scrubbed within its deadline,
+    /// false if it never has been, or None if it has no deadline
+    pub fn within_deadline(&self, now: Instant) -> Option<bool> {
+        let deadline = self.deadline?;
+        Some(self.staleness(now).is_some_and(|s| s <= deadline))
+    }
+}
+
+/// Scrub areas organized into named groups
+///
+/// * `groups` - The groups. A group's parent comes before it.
+///
+/// * `members` - Group of each scrub area, if any
+///
+/// * `extents` - The (start, end) address of each scrub area, end
+///   inclusive
+#[derive(Clone, Debug, PartialEq)]
+pub struct AreaGroups {
+    groups: Vec<AreaGroup>,
+    members: Vec<Option<usize>>,
+    extents: Vec<(usize, usize)>,
+}
+
+impl AreaGroups {
+    /// Create AreaGroups with no groups for the scrub areas of a scrubber
+    ///
+    /// # Arguments:
+    /// * `extents` - The (start, end) address of each scrub area of the
+    ///   scrubber, end inclusive, as given by LineScrubber::extents()
+    pub fn new(extents: &[(usize, usize)]) -> AreaGroups {
+        AreaGroups {
+            groups: Vec::new(),
+            members: vec![None; extents.len()],
+            extents: extents.to_vec(),
+        }
+    }
+
+    /// Bring the scrub areas up to date with those of the scrubber. An
+    /// area whose start address is unchanged stays in its group, even if
+    /// its index or end has changed. Other areas are in no group.
+    ///
+    /// # Arguments:
+    /// * `extents` - The (start, end) address of each scrub area of the
+    ///   scrubber, end inclusive
+    pub fn sync(&mut self, extents: &[(usize, usize)]) {
+        self.members = extents
+            .iter()
+            .map(|&(start, _)| {
+                self.extents
+                    .iter()
+                    .position(|&(s, _)| s == start)
+                    .and_then(|area| self.members[area])
+            })
+            .collect();
+        self.extents = extents.to_vec();
+    }
+
+    /// Add a group, with no deadline or rate budget
+    ///
+    /// # Arguments:
+    /// * `name` - Name of the group
+    ///
+    /// * `parent` - Group the new group is in, if any
+    ///
+    /// # Returns:
+    /// Ok(group) with the index of the group, otherwise
+    /// Err(Error::DuplicateGroup) if there is already a group with the
+    /// name or Err(Error::NoSuchGroup) if there is no such parent
+    pub fn add_group(
+        &mut self,
+        name: &str,
+        parent: Option<usize>,
+    ) -> Result<usize, Error> {
+        if self.find(name).is_some() {
+            return Err(Error::DuplicateGroup);
+        }
+        if parent.is_some_and(|p| p >= self.groups.len()) {
+            return Err(Error::NoSuchGroup);
+        }
+        self.groups.push(AreaGroup {
+            name: name.to_string(),
+            parent,
+            deadline: None,
+            rate_budget: None,
+        });
+        Ok(self.groups.len() - 1)
+    }
+
+    /// Returns the index of the group with a name, if any
+    pub fn find(&self, name: &str) -> Option<usize> {
+        self.groups.iter().position(|g| g.name == name)
+    }
+
+    /// Returns the groups
+    pub fn groups(&self) -> &[AreaGroup] {
+        &self.groups
+    }
+
+    /// Set the deadline of a group
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::ZeroSize) if the deadline
+    /// is zero or Err(Error::NoSuchGroup) if there is no such group
+    pub fn set_deadline(
+        &mut self,
+        group: usize,
+        deadline: Option<Duration>,
+    ) -> Result<(), Error> {
+        if deadline == Some(Duration::ZERO) {
+            return Err(Error::ZeroSize);
+        }
+        let group =
+            self.groups.get_mut(group).ok_or(Error::NoSuchGroup)?;
+        group.deadline = deadline;
+        Ok(())
+    }
+
+    /// Set the rate budget of a group, in bytes per second
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::ZeroSize) if the budget is
+    /// zero or Err(Error::NoSuchGroup) if there is no such group
+    pub fn set_rate_budget(
+        &mut self,
+        group: usize,
+        rate_budget: Option<u64>,
+    ) -> Result<(), Error> {
+        if rate_budget == Some(0) {
+            return Err(Error::ZeroSize);
+        }
+        let group =
+            self.groups.get_mut(group).ok_or(Error::NoSuchGroup)?;
+        group.rate_budget = rate_budget;
+        Ok(())
+    }
+
+    /// Put a scrub area in a group, taking it out of any other
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::NoSuchArea) if there is no
+    /// such area or Err(Error::NoSuchGroup) if there is no such group
+    pub fn assign(
+        &mut self,
+        area: usize,
+        group: usize,
+    ) -> Result<(), Error> {
+        if group >= self.groups.len() {
+            return Err(Error::NoSuchGroup);
+        }
+        let member =
+            self.members.get_mut(area).ok_or(Error::NoSuchArea)?;
+        *member = Some(group);
+        Ok(())
+    }
+
+    /// Returns the group a scrub area was put in, if any
+    pub fn group_of(&self, area: usize) -> Option<usize> {
+        self.members.get(area).copied().flatten()
+    }
+
+    /// Returns whether a group is another group or within it
+    pub fn is_within(&self, group: usize, outer: usize) -> bool {
+        let mut group = Some(group);
+        while let Some(g) = group {
+            if g == outer {
+                return true;
+            }
+            group = self.groups.get(g).and_then(|g| g.parent);
+        }
+        false
+    }
+
+    /// Returns the scrub areas in a group and the groups within it
+    pub fn areas(&self, group: usize) -> Vec<usize> {
+        (0..self.members.len())
+            .filter(|&area| {
+                self.group_of(area)
+                    .is_some_and(|g| self.is_within(g, group))
+            })
+            .collect()
+    }
+
+    /// Returns the deadline of a group, the shortest of its own and those
+    /// of the groups it is in
+    pub fn deadline(&self, group: usize) -> Option<Duration> {
+        let mut deadline: Option<Duration> = None;
+        let mut group = self.groups.get(group);
+        while let Some(g) = group {
+            deadline = match (deadline, g.deadline) {
+                (Some(d), Some(gd)) => Some(d.min(gd)),
+                (d, gd) => d.or(gd),
+            };
+            group = g.parent.and_then(|p| self.groups.get(p));
+        }
+        deadline
+    }
+
+    /// Returns the rate multiplier for each scrub area that meets the
+    /// deadlines of the groups within their rate budgets. A group over
+    /// budget has the multipliers of its areas scaled down, but never
+    /// below one.
+    ///
+    /// # Arguments:
+    /// * `pass_duration` - Time a pass takes
+    pub fn rates(&self, pass_duration: Duration) -> Vec<usize> {
+        let mut rates: Vec<usize> = (0..self.members.len())
+            .map(|area| {
+                match self.group_of(area).and_then(|g| self.deadline(g)) {
+                    Some(deadline) => (pass_duration.as_secs_f64()
+                        / deadline.as_secs_f64())
+                    .ceil()
+                    .max(1.0)
+                        as usize,
+                    None => 1,
+                }
+            })
+            .collect();
+
+        // Groups within a group come after it, so budgets are applied
+        // from the innermost groups out
+        let secs = pass_duration.as_secs_f64();
+        for group in (0..self.groups.len()).rev() {
+            let budget = match self.groups[group].rate_budget {
+                Some(budget) if secs > 0.0 => budget as f64,
+                _ => continue,
+            };
+            let areas = self.areas(group);
+            let used: f64 = areas
+                .iter()
+                .map(|&a| {
+                    let (start, end) = self.extents[a];
+                    ((end - start + 1) * rates[a]) as f64 / secs
+                })
+                .sum();
+            if used > budget {
+                let scale = budget / used;
+                for &a in &areas {
+                    rates[a] = ((rates[a] as f64 * scale) as usize).max(1);
+                }
+            }
+        }
+        rates
+    }
+
+    /// Bring the scrub areas up to date with those of a scrubber, then set
+    /// the rate of each as given by rates()
+    ///
+    /// # Arguments:
+    /// * `scrubber` - The scrubber
+    ///
+    /// * `pass_duration` - Time a pass takes
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error)
+    pub fn apply<B: ScrubBackend>(
+        &mut self,
+        scrubber: &mut LineScrubber<B>,
+        pass_duration: Duration,
+    ) -> Result<(), Error> {
+        self.sync(scrubber.extents());
+        for (area, multiplier) in
+            self.rates(pass_duration).into_iter().enumerate()
+        {
+            if scrubber.area_rate(area) != multiplier {
+                scrubber.set_area_rate(area, multiplier)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns the statistics of the scrub areas in a group and the groups
+    /// within it
+    ///
+    /// # Arguments:
+    /// * `group` - The group
+    ///
+    /// * `stats` - Statistics of the scrubber
+    ///
+    /// * `now` - Time to decay the error rates to
+    ///
+    /// # Returns:
+    /// The statistics, or None if there is no such group
+    pub fn rollup(
+        &self,
+        group: usize,
+        stats: &ScrubStats,
+        now: Instant,
+    ) -> Option<GroupStats> {
+        let name = self.groups.get(group)?.name.clone();
+        let areas: Vec<&AreaStats> = self
+            .areas(group)
+            .into_iter()
+            .filter_map(|a| stats.areas.get(a))
+            .collect();
+        let last_scrubbed =
+            match areas.iter().all(|a| a.last_scrubbed.is_some()) {
+                true => areas.iter().filter_map(|a| a.last_scrubbed).min(),
+                false => None,
+            };
+        Some(GroupStats {
+            name,
+            areas: areas.len(),
+            size: areas.iter().map(|a| a.size).sum(),
+            excluded_lines: areas
+                .iter()
+                .map(|a| a.unscrubbed_lines())
+                .sum(),
+            errors_corrected: areas
+                .iter()
+                .map(|a| a.errors_corrected)
+                .sum(),
+            errors_uncorrected: areas
+                .iter()
+                .map(|a| a.errors_uncorrected)
+                .sum(),
+            error_rate: areas.iter().map(|a| a.error_rate_at(now)).sum(),
+            last_scrubbed,
+            deadline: self.deadline(group),
+        })
+    }
+
+    /// Returns the statistics of every group, in group order, with error
+    /// rates decayed to a given time
+    pub fn rollups(
+        &self,
+        stats: &ScrubStats,
+        now: Instant,
+    ) -> Vec<GroupStats> {
+        (0..self.groups.len())
+            .filter_map(|group| self.rollup(group, stats, now))
+            .collect()
+    }
+}
+
+// The rate of each grouped area is set again as each pass completes,
+// from the time that pass took
+impl Policy for AreaGroups {
+    fn event(
+        &mut self,
+        event: &ScrubEvent,
+        _now: Instant,
+    ) -> Vec<PolicyAction> {
+        match event {
+            ScrubEvent::PassComplete { duration, .. } => self
+                .rates(*duration)
+                .into_iter()
+                .enumerate()
+                .filter(|&(area, _)| self.group_of(area).is_some())
+                .map(|(area, multiplier)| PolicyAction::SetAreaRate {
+                    area,
+                    multiplier,
+                })
+                .collect(),
+            _ => Vec::new(),
+        }
+    }
+
+    fn areas_changed(&mut self, extents: &[(usize, usize)]) {
+        self.sync(extents);
+    }
+
+    fn buffers(&self) -> Vec<(usize, usize)> {
+        let mut buffers = vec![
+            vec_buffer(&self.groups),
+            vec_buffer(&self.members),
+            vec_buffer(&self.extents),
+        ];
+        buffers.extend(
+            self.groups
+                .iter()
+                .map(|g| (g.name.as_ptr() as usize, g.name.capacity())),
+        );
+        buffers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::*;
+
+    // Areas of 4, 4, 8 and 16 KiB
+    fn scrubber() -> LineScrubber<SimMemory> {
+        let mem = SimMemory::new(0, 32 * 1024, 64).unwrap();
+        let extents =
+            [(0, 4095), (4096, 8191), (8192, 16383), (16384, 32767)];
+        LineScrubber::new(mem, &extents, 64, 4).unwrap()
+    }
+
+    // "tenants" holds "tenant-a" with areas 0 and 1, and "tenant-b" with
+    // area 2. Area 3 is in "kernel".
+    fn groups(extents: &[(usize, usize)]) -> AreaGroups {
+        let mut groups = AreaGroups::new(extents);
+        let tenants = groups.add_group("tenants", None).unwrap();
+        let a = groups.add_group("tenant-a", Some(tenants)).unwrap();
+        let b = groups.add_group("tenant-b", Some(tenants)).unwrap();
+        let kernel = groups.add_group("kernel", None).unwrap();
+        groups.assign(0, a).unwrap();
+        groups.assign(1, a).unwrap();
+        groups.assign(2, b).unwrap();
+        groups.assign(3, kernel).unwrap();
+        groups
+    }
+
+    #[test]
+    fn test_groups() {
+        let scrubber = scrubber();
+        let mut groups = groups(scrubber.extents());
+        assert_eq!(
+            groups.add_group("kernel", None),
+            Err(Error::DuplicateGroup)
+        );
+        assert_eq!(
+            groups.add_group("x", Some(9)),
+            Err(Error::NoSuchGroup)
+        );
+        assert_eq!(groups.assign(4, 0), Err(Error::NoSuchArea));
+        assert_eq!(groups.assign(0, 9), Err(Error::NoSuchGroup));
+        assert_eq!(groups.set_deadline(9, None), Err(Error::NoSuchGroup));
+
+        let tenants = groups.find("tenants").unwrap();
+        let a = groups.find("tenant-a").unwrap();
+        assert!(groups.is_within(a, tenants));
+        assert!(!groups.is_within(tenants, a));
+        assert_eq!(groups.areas(tenants), [0, 1, 2]);
+        assert_eq!(groups.areas(a), [0, 1]);
+
+        // A group's deadline is the shortest of its own and those outside
+        groups
+            .set_deadline(tenants, Some(Duration::from_secs(60)))
+            .unwrap();
+        groups
+            .set_deadline(a, Some(Duration::from_secs(90)))
+            .unwrap();
+        assert_eq!(groups.deadline(a), Some(Duration::from_secs(60)));
+        assert_eq!(groups.deadline(groups.find("kernel").unwrap()), None);
+        assert_eq!(
+            groups.set_deadline(a, Some(Duration::ZERO)),
+            Err(Error::ZeroSize)
+        );
+    }
+
+    #[test]
+    fn test_rates() {
+        let mut scrubber = scrubber();
+        let mut groups = groups(scrubber.extents());
+        let tenants = groups.find("tenants").unwrap();
+        let kernel = groups.find("kernel").unwrap();
+        groups
+            .set_deadline(tenants, Some(Duration::from_secs(10)))
+            .unwrap();
+        groups
+            .set_deadline(kernel, Some(Duration::from_secs(20)))
+            .unwrap();
+
+        // A pass of 40s must be four times faster for the tenants and
+        // twice as fast for the kernel
+        let pass = Duration::from_secs(40);
+        assert_eq!(groups.rates(pass), [4, 4, 4, 2]);
+
+        // The kernel's 16 KiB at twice the rate would need 819.2 bytes/s
+        groups.set_rate_budget(kernel, Some(600)).unwrap();
+        assert_eq!(groups.rates(pass), [4, 4, 4, 1]);
+
+        // The tenants' 16 KiB at four times would need 1638.4 bytes/s
+        groups.set_rate_budget(tenants, Some(1000)).unwrap();
+        assert_eq!(groups.rates(pass), [2, 2, 2, 1]);
+
+        groups.apply(&mut scrubber, pass).unwrap();
+        assert_eq!(scrubber.area_rate(0), 2);
+        assert_eq!(scrubber.area_rate(3), 1);
+    }
+
+    #[test]
+    fn test_sync() {
+        let mut scrubber = scrubber();
+        let mut groups = groups(scrubber.extents());
+        let a = groups.find("tenant-a").unwrap();
+        let kernel = groups.find("kernel").unwrap();
+        groups
+            .set_deadline(kernel, Some(Duration::from_secs(20)))
+            .unwrap();
+
+        // The kernel's area moves down to index 2
+        let pass = Duration::from_secs(40);
+        scrubber.remove_area(2).unwrap();
+        groups.apply(&mut scrubber, pass).unwrap();
+        assert_eq!(groups.group_of(0), Some(a));
+        assert_eq!(groups.group_of(2), Some(kernel));
+        assert_eq!(scrubber.area_rate(2), 2);
+
+        // A new area is in no group
+        scrubber.add_area(8192, 16383).unwrap();
+        groups.apply(&mut scrubber, pass).unwrap();
+        assert_eq!(groups.group_of(2), Some(kernel));
+        assert_eq!(groups.group_of(3), None);
+        assert_eq!(scrubber.area_rate(3), 1);
+
+        // As a policy, the scrubber keeps it up to date. Any real pass
+        // takes longer than the kernel's deadline.
+        groups
+            .set_deadline(kernel, Some(Duration::from_nanos(1)))
+            .unwrap();
+        scrubber.add_policy(Box::new(groups));
+        scrubber.remove_area(0).unwrap();
+        scrubber.scrub(32 * 1024).unwrap();
+        assert!(scrubber.area_rate(1) > 1);
+        assert_eq!(scrubber.area_rate(2), 1);
+    }
+
+    #[test]
+    fn test_rollup() {
+        let mut scrubber = scrubber();
+        let mut groups = groups(scrubber.extents());
+        let tenants = groups.find("tenants").unwrap();
+        groups
+            .set_deadline(tenants, Some(Duration::from_secs(3600)))
+            .unwrap();
+        scrubber.stats_mut().areas[0].errors_corrected = 2;
+        scrubber.stats_mut().areas[2].errors_corrected = 3;
+        scrubber.stats_mut().areas[3].errors_corrected = 5;
+
+        let now = Instant::now();
+        let rollup =
+            groups.rollup(tenants, scrubber.stats(), now).unwrap();
+        assert_eq!(rollup.name, "tenants");
+        assert_eq!(rollup.areas, 3);
+        assert_eq!(rollup.size, 16 * 1024);
+        assert_eq!(rollup.errors_corrected, 5);
+        assert_eq!(rollup.within_deadline(now), Some(false));
+
+        // Error rates are decayed to the time of the rollup
+        scrubber.stats_mut().areas[0].error_rate = 4.0;
+        scrubber.stats_mut().areas[0].rate_updated = Some(now);
+        let later = now + ERROR_RATE_WINDOW;
+        let rollup =
+            groups.rollup(tenants, scrubber.stats(), later).unwrap();
+        assert!(
+            (rollup.error_rate - 4.0 / std::f64::consts::E).abs() < 1e-9
+        );
+
+        scrubber.scrub(32 * 1024).unwrap();
+        let rollups = groups.rollups(scrubber.stats(), Instant::now());
+        assert_eq!(rollups.len(), 4);
+        assert_eq!(
+            rollups[tenants].within_deadline(Instant::now()),
+            Some(true)
+        );
+        assert_eq!(rollups[3].within_deadline(Instant::now()), None);
+        assert!(groups.rollup(4, scrubber.stats(), now).is_none());
+    }
+
+    #[test]
+    fn test_policy() {
+        let mut scrubber = scrubber();
+        let mut groups = groups(scrubber.extents());
+        let kernel = groups.find("kernel").unwrap();
+        groups
+            .set_deadline(kernel, Some(Duration::from_nanos(1)))
+            .unwrap();
+        scrubber.add_policy(Box::new(groups));
+
+        // Any real pass takes longer than the kernel's deadline
+        scrubber.scrub(32 * 1024).unwrap();
+        assert!(scrubber.area_rate(3) > 1);
+        assert_eq!(scrubber.area_rate(0), 1);
+    }
+}
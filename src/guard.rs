@@ -0,0 +1,346 @@
+// Guard ranges, a check in the field for bugs in the bounds of the scrub
+// order. The tests surround each scrub area with guard lines and fail if
+// any of them is read; GuardBackend does the same in production builds.
+// It wraps the backend that reads memory and is given guard ranges, such
+// as the memory just before and after each scrub area or each mapping the
+// crate made for scrubbing. A read that falls in a guard range is never
+// passed on, so a bug that would read past the end of an area is caught
+// without touching memory that may belong to someone else or not be
+// mapped at all, and is recorded as a hit. Mappings the crate makes, such
+// as an UncachedMapping, can also be placed between inaccessible guard
+// pages: a read through a GuardBackend is counted as a hit, and one that
+// bypasses it faults instead of reading whatever lies next to the mapping.
+//
+// scrub_guarded() scrubs a chunk and, each time a pass completes, checks
+// that no guard was hit during it, so the bug is reported once per pass
+// rather than lost among the reads.
+
+use crate::backend::*;
+use crate::badblocks::*;
+use crate::base::*;
+use crate::coverage::*;
+
+/// Most hits kept by a GuardBackend; later ones are only counted
+pub const MAX_GUARD_HITS: usize = 64;
+
+/// A read that fell in a guard range
+///
+/// * `addr` - Address of the read
+///
+/// * `guard` - Index of the guard range it fell in
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GuardHit {
+    pub addr: usize,
+    pub guard: usize,
+}
+
+/// A backend that refuses and records reads in guard ranges, passing all
+/// others on to another backend
+///
+/// * `backend` - Reads each cache line outside the guard ranges
+///
+/// * `guards` - (start, end) address of each guard range, end inclusive
+///
+/// * `hits` - The first MAX_GUARD_HITS reads that fell in a guard range
+///
+/// * `hit_count` - Number of reads that fell in a guard range since the
+///   last check
+pub struct GuardBackend<B: ScrubBackend> {
+    backend: B,
+    guards: Vec<(usize, usize)>,
+    hits: Vec<GuardHit>,
+    hit_count: u64,
+}
+
+impl<B: ScrubBackend> GuardBackend<B> {
+    /// Create a GuardBackend with no guard ranges
+    pub fn new(backend: B) -> GuardBackend<B> {
+        GuardBackend {
+            backend,
+            guards: Vec::new(),
+            hits: Vec::new(),
+            hit_count: 0,
+        }
+    }
+
+    /// Add a guard range
+    ///
+    /// # Arguments:
+    /// * `start` - First address in the range
+    ///
+    /// * `end` - Last address in the range
+    ///
+    /// # Returns:
+    /// Ok(index) with the index of the guard range, otherwise
+    /// Err(Error::EmptyMemArea) if end is before start
+    pub fn add_guard(
+        &mut self,
+        start: usize,
+        end: usize,
+    ) -> Result<usize, Error> {
+        if end < start {
+            return Err(Error::EmptyMemArea);
+        }
+        self.guards.push((start, end));
+        Ok(self.guards.len() - 1)
+    }
+
+    /// Add guard ranges just before and after each scrub area, leaving out
+    /// any part that is in another scrub area. Overlapping and adjacent
+    /// areas are guarded as one.
+    ///
+    /// # Arguments:
+    /// * `extents` - (start, end) address of each scrub area, end
+    ///   inclusive
+    ///
+    /// * `bytes` - Number of bytes in each guard range
+    pub fn guard_areas(
+        &mut self,
+        extents: &[(usize, usize)],
+        bytes: usize,
+    ) {
+        if bytes == 0 {
+            return;
+        }
+        let mut merged: Vec<(usize, usize)> = Vec::new();
+        for &extent in extents {
+            add_range(&mut merged, extent);
+        }
+
+        for (i, &(start, end)) in merged.iter().enumerate() {
+            // Each range stops short of the neighbouring area, if any
+            let floor = match i {
+                0 => 0,
+                _ => merged[i - 1].1 + 1,
+            };
+            let ceiling = merged
+                .get(i + 1)
+                .and_then(|&(s, _)| s.checked_sub(1))
+                .unwrap_or(usize::MAX);
+            if let Some(before) = start.checked_sub(1) {
+                let low = start.saturating_sub(bytes).max(floor);
+                self.add_shared(low, before);
+            }
+            if let Some(after) = end.checked_add(1) {
+                let high = end.saturating_add(bytes).min(ceiling);
+                self.add_shared(after, high);
+            }
+        }
+    }
+
+    // Add a guard range unless it is empty or already there, as it is when
+    // the areas on either side of a gap share it
+    fn add_shared(&mut self, low: usize, high: usize) {
+        if low <= high && !self.guards.contains(&(low, high)) {
+            self.guards.push((low, high));
+        }
+    }
+
+    /// Returns the guard ranges
+    pub fn guards(&self) -> &[(usize, usize)] {
+        &self.guards
+    }
+
+    /// Returns the first reads that fell in a guard range since the last
+    /// check
+    pub fn hits(&self) -> &[GuardHit] {
+        &self.hits
+    }
+
+    /// Returns the number of reads that fell in a guard range since the
+    /// last check
+    pub fn hit_count(&self) -> u64 {
+        self.hit_count
+    }
+
+    /// Check that no guard range has been read since the last check, and
+    /// start counting again
+    ///
+    /// # Returns:
+    /// Ok(()) if none has, otherwise Err(Error::GuardHit), leaving the
+    /// hits to be looked at until the next check
+    pub fn check(&mut self) -> Result<(), Error> {
+        if self.hit_count == 0 {
+            self.hits.clear();
+            return Ok(());
+        }
+        self.hit_count = 0;
+        Err(Error::GuardHit)
+    }
+
+    /// Returns the backend reading each cache line
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns the backend reading each cache line, for changing it
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    // Record a read if it overlaps a guard range, returning whether it did
+    fn hit(&mut self, addr: usize, len: usize) -> bool {
+        let last = addr.saturating_add(len - 1);
+        let guard = match self
+            .guards
+            .iter()
+            .position(|&(s, e)| s <= last && e >= addr)
+        {
+            Some(guard) => guard,
+            None => return false,
+        };
+        if self.hits.len() < MAX_GUARD_HITS {
+            self.hits.push(GuardHit { addr, guard });
+        }
+        self.hit_count += 1;
+        true
+    }
+}
+
+impl<B: ScrubBackend> ScrubBackend for GuardBackend<B> {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        match self.hit(addr, 1) {
+            true => Ok(()),
+            false => self.backend.read_line(addr),
+        }
+    }
+
+    fn read_words(
+        &mut self,
+        addr: usize,
+        line_size: usize,
+        reads: usize,
+    ) -> Result<(), Error> {
+        match self.hit(addr, line_size) {
+            true => Ok(()),
+            false => self.backend.read_words(addr, line_size, reads),
+        }
+    }
+
+    fn begin_batch(&mut self) -> Result<(), Error> {
+        self.backend.begin_batch()
+    }
+
+    fn end_batch(&mut self) -> Result<(), Error> {
+        self.backend.end_batch()
+    }
+
+    fn end_window(&mut self) -> Result<(), Error> {
+        self.backend.end_window()
+    }
+
+    fn window_size(&self) -> Option<usize> {
+        self.backend.window_size()
+    }
+
+    fn flush_line(&mut self, addr: usize) -> Result<(), Error> {
+        match self.hit(addr, 1) {
+            true => Ok(()),
+            false => self.backend.flush_line(addr),
+        }
+    }
+
+    // Lines in guard ranges aren't reported as skipped, so that a read of
+    // one still reaches read_words() and is counted as a hit
+    fn skip_reason(&mut self, addr: usize) -> Option<SkipReason> {
+        self.backend.skip_reason(addr)
+    }
+
+    fn excluded_lines(&self, start: usize, end: usize) -> Option<u64> {
+        self.backend.excluded_lines(start, end)
+    }
+}
+
+/// Scrub a chunk, as LineScrubber::scrub() does, and check the guard
+/// ranges if the chunk completed a pass
+///
+/// # Arguments:
+/// * `scrubber` - The scrubber, reading through a GuardBackend
+///
+/// * `bytes` - Number of bytes to scrub
+///
+/// # Returns:
+/// Ok(()) on success, otherwise Err(Error::GuardHit) if a guard range was
+/// read during the pass, or another Err(Error) as for
+/// LineScrubber::scrub()
+pub fn scrub_guarded<B: ScrubBackend>(
+    scrubber: &mut LineScrubber<GuardBackend<B>>,
+    bytes: usize,
+) -> Result<(), Error> {
+    let passes = scrubber.stats().passes;
+    scrubber.scrub(bytes)?;
+    match scrubber.stats().passes == passes {
+        true => Ok(()),
+        false => scrubber.backend_mut().check(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn test_guard_areas() {
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        let mut backend = GuardBackend::new(Recorder(reads));
+        backend.guard_areas(
+            &[(0x1000, 0x1fff), (0x2040, 0x2fff), (0, 0x3f)],
+            0x80,
+        );
+        assert_eq!(
+            backend.guards(),
+            [
+                (0x40, 0xbf),
+                (0xf80, 0xfff),
+                (0x2000, 0x203f),
+                (0x3000, 0x307f),
+            ]
+        );
+        assert_eq!(backend.add_guard(2, 1), Err(Error::EmptyMemArea));
+
+        // An area at zero holding another is guarded as one area
+        let mut backend = GuardBackend::new(Recorder::default());
+        backend.guard_areas(&[(0, 0x1fff), (0x1000, 0x10ff)], 0x80);
+        assert_eq!(backend.guards(), [(0x2000, 0x207f)]);
+        let mut backend = GuardBackend::new(Recorder::default());
+        backend.guard_areas(&[(0, 0xfff), (0x800, usize::MAX)], 0x80);
+        assert!(backend.guards().is_empty());
+    }
+
+    #[test]
+    fn test_guarded() {
+        let reads = Rc::new(RefCell::new(Vec::new()));
+        let extents = [(0x1000, 0x10ff)];
+        let mut backend = GuardBackend::new(Recorder(reads.clone()));
+        backend.guard_areas(&extents, 64);
+        let mut scrubber =
+            LineScrubber::new(backend, &extents, 64, 0).unwrap();
+        scrub_guarded(&mut scrubber, 0x100).unwrap();
+        assert_eq!(scrubber.stats().passes, 1);
+
+        // A read past the end of the area is refused and reported at the
+        // end of the pass
+        scrubber.backend_mut().read_line(0x1100).unwrap();
+        scrub_guarded(&mut scrubber, 0x80).unwrap();
+        assert_eq!(
+            scrub_guarded(&mut scrubber, 0x80),
+            Err(Error::GuardHit)
+        );
+        let backend = scrubber.backend();
+        assert_eq!(
+            backend.hits(),
+            [GuardHit {
+                addr: 0x1100,
+                guard: 1
+            }]
+        );
+        assert_eq!(backend.hit_count(), 0);
+        assert!(!reads.borrow().contains(&0x1100));
+        assert_eq!(reads.borrow().len(), 8);
+        scrub_guarded(&mut scrubber, 0x100).unwrap();
+        assert!(scrubber.backend().hits().is_empty());
+    }
+}
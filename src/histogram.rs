@@ -0,0 +1,253 @@
+// Latency histograms. The mean and the longest chunk say little about how
+// much the scrubber disturbs the work sharing the machine with it; that is
+// in the tail of the distribution of the time each chunk and each batch of
+// reads takes. A LatencyHistogram keeps the whole distribution in the
+// manner of an HDR histogram: each power of two of nanoseconds is split
+// into LATENCY_SUB_BUCKETS buckets of equal width, so every time is kept
+// to within 1/LATENCY_SUB_BUCKETS of its value whatever its size, in a few
+// hundred counters at most. Buckets are only allocated up to the longest
+// time recorded.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// Number of buckets into which each power of two of nanoseconds is split
+pub const LATENCY_SUB_BUCKETS: usize = 8;
+
+// log2(LATENCY_SUB_BUCKETS)
+const SUB_BITS: u32 = LATENCY_SUB_BUCKETS.trailing_zeros();
+
+/// A bucket of a latency histogram
+///
+/// * `low` - Shortest time in the bucket
+///
+/// * `high` - Longest time in the bucket
+///
+/// * `count` - Number of times recorded in the bucket
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LatencyBucket {
+    pub low: Duration,
+    pub high: Duration,
+    pub count: u64,
+}
+
+/// Distribution of the times taken by something, such as scrubbing a
+/// chunk
+///
+/// * `counts` - Number of times recorded in each bucket
+///
+/// * `total` - Number of times recorded
+///
+/// * `sum_ns` - Sum of the times recorded, in nanoseconds
+///
+/// * `min_ns` - Shortest time recorded, in nanoseconds
+///
+/// * `max_ns` - Longest time recorded, in nanoseconds
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct LatencyHistogram {
+    counts: Vec<u64>,
+    total: u64,
+    sum_ns: u64,
+    min_ns: u64,
+    max_ns: u64,
+}
+
+impl LatencyHistogram {
+    /// Create an empty histogram
+    pub fn new() -> LatencyHistogram {
+        LatencyHistogram::default()
+    }
+
+    /// Record a time
+    pub fn record(&mut self, time: Duration) {
+        let ns = u64::try_from(time.as_nanos()).unwrap_or(u64::MAX);
+        let i = bucket_index(ns);
+        if i >= self.counts.len() {
+            self.counts.resize(i + 1, 0);
+        }
+        self.counts[i] += 1;
+        self.min_ns = match self.total {
+            0 => ns,
+            _ => self.min_ns.min(ns),
+        };
+        self.max_ns = self.max_ns.max(ns);
+        self.sum_ns = self.sum_ns.saturating_add(ns);
+        self.total += 1;
+    }
+
+    /// Add the times recorded in another histogram, as to combine those
+    /// of several scrubbers
+    pub fn merge(&mut self, other: &LatencyHistogram) {
+        if other.total == 0 {
+            return;
+        }
+        if other.counts.len() > self.counts.len() {
+            self.counts.resize(other.counts.len(), 0);
+        }
+        for (count, other) in self.counts.iter_mut().zip(&other.counts) {
+            *count += other;
+        }
+        self.min_ns = match self.total {
+            0 => other.min_ns,
+            _ => self.min_ns.min(other.min_ns),
+        };
+        self.max_ns = self.max_ns.max(other.max_ns);
+        self.sum_ns = self.sum_ns.saturating_add(other.sum_ns);
+        self.total += other.total;
+    }
+
+    /// Forget all times recorded
+    pub fn clear(&mut self) {
+        *self = LatencyHistogram::default();
+    }
+
+    /// Returns the number of times recorded
+    pub fn count(&self) -> u64 {
+        self.total
+    }
+
+    /// Returns the shortest time recorded, if any
+    pub fn min(&self) -> Option<Duration> {
+        (self.total != 0).then(|| Duration::from_nanos(self.min_ns))
+    }
+
+    /// Returns the longest time recorded, if any
+    pub fn max(&self) -> Option<Duration> {
+        (self.total != 0).then(|| Duration::from_nanos(self.max_ns))
+    }
+
+    /// Returns the mean of the times recorded, if any
+    pub fn mean(&self) -> Option<Duration> {
+        (self.total != 0)
+            .then(|| Duration::from_nanos(self.sum_ns / self.total))
+    }
+
+    /// Returns the time below which a fraction of the times recorded fall,
+    /// such as 0.99 for the 99th percentile. This is the longest time in
+    /// the bucket holding that time, or the longest time recorded if that
+    /// is shorter.
+    ///
+    /// # Arguments:
+    /// * `fraction` - Fraction of the times, from 0.0 to 1.0
+    ///
+    /// # Returns:
+    /// The time, or None if no times have been recorded
+    pub fn percentile(&self, fraction: f64) -> Option<Duration> {
+        if self.total == 0 {
+            return None;
+        }
+        let rank = ((fraction.clamp(0.0, 1.0) * self.total as f64).ceil()
+            as u64)
+            .max(1);
+        let mut seen = 0;
+        for (i, &count) in self.counts.iter().enumerate() {
+            seen += count;
+            if seen >= rank {
+                let high = bucket_bounds(i).1.min(self.max_ns);
+                return Some(Duration::from_nanos(high));
+            }
+        }
+        self.max()
+    }
+
+    /// Returns the buckets holding any times, shortest first
+    pub fn buckets(&self) -> impl Iterator<Item = LatencyBucket> + '_ {
+        self.counts
+            .iter()
+            .enumerate()
+            .filter(|&(_, &count)| count != 0)
+            .map(|(i, &count)| {
+                let (low, high) = bucket_bounds(i);
+                LatencyBucket {
+                    low: Duration::from_nanos(low),
+                    high: Duration::from_nanos(high),
+                    count,
+                }
+            })
+    }
+
+    // Returns the address and size of the buffer of counters, so that
+    // scrubbing can leave it out
+    pub(crate) fn buffer(&self) -> (usize, usize) {
+        (
+            self.counts.as_ptr() as usize,
+            self.counts.capacity() * size_of::<u64>(),
+        )
+    }
+}
+
+// Returns the index of the bucket holding a number of nanoseconds. Times
+// below twice LATENCY_SUB_BUCKETS have a bucket each.
+fn bucket_index(ns: u64) -> usize {
+    if ns < LATENCY_SUB_BUCKETS as u64 {
+        return ns as usize;
+    }
+    let exp = 63 - ns.leading_zeros();
+    let sub =
+        (ns >> (exp - SUB_BITS)) as usize & (LATENCY_SUB_BUCKETS - 1);
+    (exp - SUB_BITS + 1) as usize * LATENCY_SUB_BUCKETS + sub
+}
+
+// Returns the shortest and longest number of nanoseconds in a bucket
+fn bucket_bounds(i: usize) -> (u64, u64) {
+    if i < LATENCY_SUB_BUCKETS {
+        return (i as u64, i as u64);
+    }
+    let shift = (i / LATENCY_SUB_BUCKETS - 1) as u32;
+    let sub = (LATENCY_SUB_BUCKETS + i % LATENCY_SUB_BUCKETS) as u64;
+    let low = sub << shift;
+    (low, low + ((1u64 << shift) - 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_buckets() {
+        // Every time falls in the bucket whose bounds hold it
+        for ns in (0..4096).chain([u64::MAX - 1, u64::MAX]) {
+            let (low, high) = bucket_bounds(bucket_index(ns));
+            assert!(low <= ns && ns <= high, "{}", ns);
+        }
+        assert_eq!(bucket_bounds(bucket_index(15)), (15, 15));
+        assert_eq!(bucket_bounds(bucket_index(1000)), (960, 1023));
+    }
+
+    #[test]
+    fn test_histogram() {
+        let mut histogram = LatencyHistogram::new();
+        assert_eq!(histogram.percentile(0.5), None);
+        for us in 1..=100 {
+            histogram.record(Duration::from_micros(us));
+        }
+        assert_eq!(histogram.count(), 100);
+        assert_eq!(histogram.min(), Some(Duration::from_micros(1)));
+        assert_eq!(histogram.max(), Some(Duration::from_micros(100)));
+        assert_eq!(histogram.mean(), Some(Duration::from_nanos(50500)));
+
+        // Within the width of a bucket of the exact value
+        let p99 = histogram.percentile(0.99).unwrap();
+        assert!(p99 >= Duration::from_micros(99));
+        assert!(p99 <= Duration::from_micros(100));
+        let p50 = histogram.percentile(0.5).unwrap();
+        assert!(p50 >= Duration::from_micros(50));
+        assert!(p50 < Duration::from_micros(57));
+        assert_eq!(
+            histogram.percentile(1.0),
+            Some(Duration::from_micros(100))
+        );
+        let counted: u64 = histogram.buckets().map(|b| b.count).sum();
+        assert_eq!(counted, 100);
+
+        let mut other = LatencyHistogram::new();
+        other.record(Duration::from_secs(1));
+        histogram.merge(&other);
+        assert_eq!(histogram.count(), 101);
+        assert_eq!(histogram.max(), Some(Duration::from_secs(1)));
+        histogram.clear();
+        assert_eq!(histogram.count(), 0);
+    }
+}
@@ -0,0 +1,618 @@
+// Recording of scrub history for offline analysis. A HistoryRecorder is an
+// EventSink that turns chunk or pass completions into rows, each carrying the
+// errors observed since the previous row. Rows are appended to a CSV file or,
+// with the parquet feature, written to a Parquet file.
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::diag::*;
+use crate::event::*;
+use crate::stats::*;
+
+/// How often a row is recorded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HistoryGranularity {
+    /// One row for each chunk scrubbed
+    Chunk,
+    /// One row for each scrub area each time a pass completes, plus one for
+    /// any errors outside every area
+    Pass,
+}
+
+/// A single row of scrub history
+///
+/// * `timestamp` - Seconds since the Unix epoch at which the row was recorded
+///
+/// * `pass` - Number of passes completed when the row was recorded
+///
+/// * `area` - Label of the scrub area, [`UNATTRIBUTED`] for errors outside
+///   every area, or None for a row covering all areas
+///
+/// * `bytes` - Number of bytes scrubbed
+///
+/// * `duration` - Time taken to scrub them
+///
+/// * `errors_corrected` - Corrected errors observed since the previous row
+///
+/// * `errors_uncorrected` - Uncorrected errors observed since the previous
+///   row
+#[derive(Clone, Debug, PartialEq)]
+pub struct HistoryRow {
+    pub timestamp: f64,
+    pub pass: u64,
+    pub area: Option<String>,
+    pub bytes: usize,
+    pub duration: Duration,
+    pub errors_corrected: u64,
+    pub errors_uncorrected: u64,
+}
+
+/// Area label of the pass row holding errors outside every scrub area
+pub const UNATTRIBUTED: &str = "unattributed";
+
+// Turns events into rows. This is shared by all output formats.
+//
+// granularity: How often to generate rows
+// areas:       Label and size of each scrub area
+// errors:      (corrected, uncorrected) errors per area since the last row.
+//              An extra entry at the end collects errors outside any area.
+// passes:      Number of passes completed so far
+struct HistoryTracker {
+    granularity: HistoryGranularity,
+    areas: Vec<(String, usize)>,
+    errors: Vec<(u64, u64)>,
+    passes: u64,
+}
+
+impl HistoryTracker {
+    fn new(
+        stats: &ScrubStats,
+        granularity: HistoryGranularity,
+    ) -> HistoryTracker {
+        let areas: Vec<(String, usize)> = stats
+            .areas
+            .iter()
+            .enumerate()
+            .map(|(i, a)| {
+                let label =
+                    a.label.clone().unwrap_or_else(|| i.to_string());
+                (label, a.size)
+            })
+            .collect();
+        let errors = vec![(0, 0); areas.len() + 1];
+
+        HistoryTracker {
+            granularity,
+            areas,
+            errors,
+            passes: stats.passes,
+        }
+    }
+
+    fn timestamp() -> f64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+    }
+
+    // Take the errors accumulated for an area, or for all areas
+    fn take_errors(&mut self, area: Option<usize>) -> (u64, u64) {
+        match area {
+            Some(i) => std::mem::take(&mut self.errors[i]),
+            None => {
+                let mut total = (0, 0);
+                for e in self.errors.iter_mut() {
+                    let (c, u) = std::mem::take(e);
+                    total.0 += c;
+                    total.1 += u;
+                }
+                total
+            }
+        }
+    }
+
+    // Return the rows, if any, to be recorded for an event
+    fn rows(&mut self, event: &ScrubEvent) -> Vec<HistoryRow> {
+        let mut rows = Vec::new();
+
+        match *event {
+            ScrubEvent::Error(e) => {
+                let i = e.area.unwrap_or(self.areas.len());
+                let i = i.min(self.areas.len());
+                match e.severity {
+                    ErrorSeverity::Corrected => self.errors[i].0 += 1,
+                    ErrorSeverity::Uncorrected => self.errors[i].1 += 1,
+                }
+            }
+            ScrubEvent::ChunkComplete { bytes, duration } => {
+                if self.granularity == HistoryGranularity::Chunk {
+                    let (corrected, uncorrected) = self.take_errors(None);
+                    rows.push(HistoryRow {
+                        timestamp: Self::timestamp(),
+                        pass: self.passes,
+                        area: None,
+                        bytes,
+                        duration,
+                        errors_corrected: corrected,
+                        errors_uncorrected: uncorrected,
+                    });
+                }
+            }
+            ScrubEvent::PassComplete { pass, duration, .. } => {
+                self.passes = pass;
+                if self.granularity == HistoryGranularity::Pass {
+                    let timestamp = Self::timestamp();
+                    for i in 0..self.areas.len() {
+                        let (corrected, uncorrected) =
+                            self.take_errors(Some(i));
+                        rows.push(HistoryRow {
+                            timestamp,
+                            pass,
+                            area: Some(self.areas[i].0.clone()),
+                            bytes: self.areas[i].1,
+                            duration,
+                            errors_corrected: corrected,
+                            errors_uncorrected: uncorrected,
+                        });
+                    }
+
+                    let (corrected, uncorrected) =
+                        self.take_errors(Some(self.areas.len()));
+                    if corrected != 0 || uncorrected != 0 {
+                        rows.push(HistoryRow {
+                            timestamp,
+                            pass,
+                            area: Some(UNATTRIBUTED.to_string()),
+                            bytes: 0,
+                            duration,
+                            errors_corrected: corrected,
+                            errors_uncorrected: uncorrected,
+                        });
+                    }
+                }
+            }
+            ScrubEvent::RateChange { .. }
+            | ScrubEvent::ErrorStorm { .. }
+            | ScrubEvent::StormCleared { .. }
+            | ScrubEvent::Health { .. }
+            | ScrubEvent::LineSkipped { .. }
+            | ScrubEvent::FaultLocated { .. }
+            | ScrubEvent::ThresholdCrossed { .. }
+            | ScrubEvent::ThresholdCleared { .. }
+            | ScrubEvent::LineFlushed { .. } => {}
+        }
+
+        rows
+    }
+}
+
+const CSV_HEADER: &str = "timestamp,pass,area,bytes,duration_secs,\
+    errors_corrected,errors_uncorrected";
+
+/// Appends scrub history to a CSV file
+///
+/// * `tracker` - Converts events to rows
+///
+/// * `out` - Where the rows are written
+pub struct HistoryRecorder<W: Write> {
+    tracker: HistoryTracker,
+    out: W,
+}
+
+impl HistoryRecorder<BufWriter<File>> {
+    /// Open a CSV file for appending, writing a header if the file is new
+    ///
+    /// # Arguments:
+    /// * `path` - Path of the CSV file
+    ///
+    /// * `stats` - Statistics of the scrubber being recorded, used for the
+    ///   area labels and sizes
+    ///
+    /// * `granularity` - How often rows are recorded
+    pub fn create<P: AsRef<Path>>(
+        path: P,
+        stats: &ScrubStats,
+        granularity: HistoryGranularity,
+    ) -> io::Result<HistoryRecorder<BufWriter<File>>> {
+        let file =
+            OpenOptions::new().create(true).append(true).open(path)?;
+        let is_new = file.metadata()?.len() == 0;
+        let mut recorder =
+            HistoryRecorder::new(BufWriter::new(file), stats, granularity);
+        if is_new {
+            recorder.write_header()?;
+        }
+        Ok(recorder)
+    }
+}
+
+impl<W: Write> HistoryRecorder<W> {
+    /// Create a recorder writing CSV rows to the given writer. No header is
+    /// written.
+    pub fn new(
+        out: W,
+        stats: &ScrubStats,
+        granularity: HistoryGranularity,
+    ) -> HistoryRecorder<W> {
+        HistoryRecorder {
+            tracker: HistoryTracker::new(stats, granularity),
+            out,
+        }
+    }
+
+    /// Write the CSV header line
+    pub fn write_header(&mut self) -> io::Result<()> {
+        writeln!(self.out, "{}", CSV_HEADER)
+    }
+
+    // Quote a CSV field if needed
+    fn quote(field: &str) -> String {
+        if field.contains([',', '"', '\n']) {
+            format!("\"{}\"", field.replace('"', "\"\""))
+        } else {
+            field.to_string()
+        }
+    }
+
+    /// Write a single row
+    pub fn write_row(&mut self, row: &HistoryRow) -> io::Result<()> {
+        writeln!(
+            self.out,
+            "{:.6},{},{},{},{:.6},{},{}",
+            row.timestamp,
+            row.pass,
+            Self::quote(row.area.as_deref().unwrap_or("")),
+            row.bytes,
+            row.duration.as_secs_f64(),
+            row.errors_corrected,
+            row.errors_uncorrected
+        )
+    }
+
+    /// Flush buffered rows to the underlying writer
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+impl<W: Write> EventSink for HistoryRecorder<W> {
+    fn event(&mut self, event: &ScrubEvent) {
+        // Recording history must not stop scrubbing, so write errors are
+        // only reported as diagnostics
+        for row in self.tracker.rows(event) {
+            if let Err(e) = self.write_row(&row) {
+                diag!(Warning, "can't write history row: {}", e);
+            }
+        }
+        if let ScrubEvent::PassComplete { .. } = event {
+            if let Err(e) = self.flush() {
+                diag!(Warning, "can't flush history: {}", e);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "parquet")]
+pub use self::parquet_history::*;
+
+#[cfg(feature = "parquet")]
+mod parquet_history {
+    use super::*;
+    use parquet::basic::Compression;
+    use parquet::data_type::{ByteArray, ByteArrayType, DoubleType};
+    use parquet::data_type::{DataType, Int64Type};
+    use parquet::errors::ParquetError;
+    use parquet::file::properties::WriterProperties;
+    use parquet::file::writer::SerializedFileWriter;
+    use parquet::schema::parser::parse_message_type;
+    use std::sync::Arc;
+
+    const SCHEMA: &str = "
+        message scrub_history {
+            REQUIRED DOUBLE timestamp;
+            REQUIRED INT64 pass;
+            OPTIONAL BYTE_ARRAY area (UTF8);
+            REQUIRED INT64 bytes;
+            REQUIRED DOUBLE duration_secs;
+            REQUIRED INT64 errors_corrected;
+            REQUIRED INT64 errors_uncorrected;
+        }
+    ";
+
+    // Number of rows buffered before a row group is written
+    const DEFAULT_ROW_GROUP_SIZE: usize = 1024;
+
+    fn to_io(e: ParquetError) -> io::Error {
+        io::Error::other(e)
+    }
+
+    /// Writes scrub history to a Parquet file. Parquet files can't be
+    /// appended to, so rows are buffered and written as row groups, and the
+    /// file is only complete once close() is called.
+    ///
+    /// * `tracker` - Converts events to rows
+    ///
+    /// * `writer` - Parquet file writer
+    ///
+    /// * `rows` - Rows not yet written
+    ///
+    /// * `row_group_size` - Number of rows per row group
+    pub struct ParquetHistoryRecorder {
+        tracker: HistoryTracker,
+        writer: Option<SerializedFileWriter<File>>,
+        rows: Vec<HistoryRow>,
+        row_group_size: usize,
+    }
+
+    impl ParquetHistoryRecorder {
+        /// Create a new Parquet file
+        ///
+        /// # Arguments:
+        /// * `path` - Path of the file, which is truncated if it exists
+        ///
+        /// * `stats` - Statistics of the scrubber being recorded
+        ///
+        /// * `granularity` - How often rows are recorded
+        pub fn create<P: AsRef<Path>>(
+            path: P,
+            stats: &ScrubStats,
+            granularity: HistoryGranularity,
+        ) -> io::Result<ParquetHistoryRecorder> {
+            let schema =
+                Arc::new(parse_message_type(SCHEMA).map_err(to_io)?);
+            let props = Arc::new(
+                WriterProperties::builder()
+                    .set_compression(Compression::UNCOMPRESSED)
+                    .build(),
+            );
+            let file = File::create(path)?;
+            let writer = SerializedFileWriter::new(file, schema, props)
+                .map_err(to_io)?;
+
+            Ok(ParquetHistoryRecorder {
+                tracker: HistoryTracker::new(stats, granularity),
+                writer: Some(writer),
+                rows: Vec::new(),
+                row_group_size: DEFAULT_ROW_GROUP_SIZE,
+            })
+        }
+
+        /// Set the number of rows buffered per row group
+        pub fn set_row_group_size(&mut self, row_group_size: usize) {
+            self.row_group_size = row_group_size.max(1);
+        }
+
+        /// Write any buffered rows as a row group
+        pub fn flush(&mut self) -> io::Result<()> {
+            let writer = match self.writer.as_mut() {
+                None => return Ok(()),
+                Some(writer) => writer,
+            };
+            if self.rows.is_empty() {
+                return Ok(());
+            }
+
+            let rows = std::mem::take(&mut self.rows);
+            let mut group = writer.next_row_group().map_err(to_io)?;
+            let mut column = 0;
+            while let Some(mut col) = group.next_column().map_err(to_io)? {
+                match column {
+                    0 => write_column::<DoubleType>(
+                        &mut col,
+                        rows.iter().map(|r| r.timestamp).collect(),
+                    )?,
+                    1 => write_column::<Int64Type>(
+                        &mut col,
+                        rows.iter().map(|r| r.pass as i64).collect(),
+                    )?,
+                    2 => {
+                        let values: Vec<ByteArray> = rows
+                            .iter()
+                            .filter_map(|r| r.area.as_deref())
+                            .map(ByteArray::from)
+                            .collect();
+                        let levels: Vec<i16> = rows
+                            .iter()
+                            .map(|r| r.area.is_some() as i16)
+                            .collect();
+                        col.typed::<ByteArrayType>()
+                            .write_batch(&values, Some(&levels), None)
+                            .map_err(to_io)?;
+                    }
+                    3 => write_column::<Int64Type>(
+                        &mut col,
+                        rows.iter().map(|r| r.bytes as i64).collect(),
+                    )?,
+                    4 => write_column::<DoubleType>(
+                        &mut col,
+                        rows.iter()
+                            .map(|r| r.duration.as_secs_f64())
+                            .collect(),
+                    )?,
+                    5 => write_column::<Int64Type>(
+                        &mut col,
+                        rows.iter()
+                            .map(|r| r.errors_corrected as i64)
+                            .collect(),
+                    )?,
+                    _ => write_column::<Int64Type>(
+                        &mut col,
+                        rows.iter()
+                            .map(|r| r.errors_uncorrected as i64)
+                            .collect(),
+                    )?,
+                }
+                col.close().map_err(to_io)?;
+                column += 1;
+            }
+            group.close().map_err(to_io)?;
+            Ok(())
+        }
+
+        /// Write any buffered rows and the file footer
+        pub fn close(mut self) -> io::Result<()> {
+            self.flush()?;
+            match self.writer.take() {
+                None => Ok(()),
+                Some(writer) => writer.close().map(|_| ()).map_err(to_io),
+            }
+        }
+    }
+
+    fn write_column<T: DataType>(
+        col: &mut parquet::file::writer::SerializedColumnWriter<'_>,
+        values: Vec<T::T>,
+    ) -> io::Result<()> {
+        col.typed::<T>()
+            .write_batch(&values, None, None)
+            .map(|_| ())
+            .map_err(to_io)
+    }
+
+    impl EventSink for ParquetHistoryRecorder {
+        fn event(&mut self, event: &ScrubEvent) {
+            let rows = self.tracker.rows(event);
+            self.rows.extend(rows);
+            if self.rows.len() >= self.row_group_size {
+                if let Err(e) = self.flush() {
+                    diag!(Warning, "can't write history row group: {}", e);
+                }
+            }
+        }
+    }
+
+    impl Drop for ParquetHistoryRecorder {
+        fn drop(&mut self) {
+            if let Err(e) = self.flush() {
+                diag!(Warning, "can't write history row group: {}", e);
+            }
+            if let Some(writer) = self.writer.take() {
+                if let Err(e) = writer.close() {
+                    diag!(Warning, "can't close history file: {}", e);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+    use std::time::Instant;
+
+    fn error(area: Option<usize>, severity: ErrorSeverity) -> ScrubEvent {
+        ScrubEvent::Error(ErrorEvent {
+            addr: 0,
+            area,
+            severity,
+        })
+    }
+
+    #[test]
+    fn test_chunk_rows() {
+        let stats = ScrubStats::new(&[64, 64], Instant::now());
+        let mut out = Vec::new();
+        {
+            let mut recorder = HistoryRecorder::new(
+                &mut out,
+                &stats,
+                HistoryGranularity::Chunk,
+            );
+            recorder.write_header().unwrap();
+            recorder.event(&error(Some(0), ErrorSeverity::Corrected));
+            recorder.event(&error(None, ErrorSeverity::Uncorrected));
+            recorder.event(&ScrubEvent::ChunkComplete {
+                bytes: 64,
+                duration: Duration::from_millis(2),
+            });
+            recorder.event(&ScrubEvent::ChunkComplete {
+                bytes: 64,
+                duration: Duration::from_millis(2),
+            });
+        }
+
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0], CSV_HEADER);
+        assert!(lines[1].ends_with(",0,,64,0.002000,1,1"));
+        assert!(lines[2].ends_with(",0,,64,0.002000,0,0"));
+    }
+
+    #[test]
+    fn test_pass_rows() {
+        let mut stats = ScrubStats::new(&[64, 128], Instant::now());
+        stats.areas[1].label = Some("a,b".to_string());
+        let mut out = Vec::new();
+        {
+            let mut recorder = HistoryRecorder::new(
+                &mut out,
+                &stats,
+                HistoryGranularity::Pass,
+            );
+            recorder.event(&error(Some(1), ErrorSeverity::Corrected));
+            recorder.event(&ScrubEvent::ChunkComplete {
+                bytes: 192,
+                duration: Duration::from_millis(2),
+            });
+            recorder.event(&ScrubEvent::PassComplete {
+                pass: 1,
+                epoch: 1,
+                bytes: 192,
+                duration: Duration::from_secs(1),
+            });
+            recorder.event(&error(None, ErrorSeverity::Uncorrected));
+            recorder.event(&ScrubEvent::PassComplete {
+                pass: 2,
+                epoch: 1,
+                bytes: 192,
+                duration: Duration::from_secs(1),
+            });
+        }
+
+        let text = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert!(lines[0].ends_with(",1,0,64,1.000000,0,0"));
+        assert!(lines[1].ends_with(",1,\"a,b\",128,1.000000,1,0"));
+        assert!(lines[2].ends_with(",2,0,64,1.000000,0,0"));
+        assert!(lines[3].ends_with(",2,\"a,b\",128,1.000000,0,0"));
+        assert!(lines[4].ends_with(",2,unattributed,0,1.000000,0,1"));
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn test_parquet() {
+        use parquet::file::reader::{FileReader, SerializedFileReader};
+
+        let path = scratch_path("history.parquet");
+        let stats = ScrubStats::new(&[64, 64], Instant::now());
+        let mut recorder = ParquetHistoryRecorder::create(
+            &path,
+            &stats,
+            HistoryGranularity::Pass,
+        )
+        .unwrap();
+        recorder.set_row_group_size(2);
+        for pass in 1..=3 {
+            recorder.event(&ScrubEvent::PassComplete {
+                pass,
+                epoch: pass,
+                bytes: 128,
+                duration: Duration::from_secs(1),
+            });
+        }
+        recorder.close().unwrap();
+
+        let reader =
+            SerializedFileReader::new(File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 6);
+        assert_eq!(reader.num_row_groups(), 3);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+// Predicting how much scrubbing disturbs the cache. predict_impact() runs
+// a whole pass of ScrubOrder through a CacheSim with the geometry of a
+// ScrubConfig, a chunk at a time, and summarizes what each chunk does to
+// the cache: how many sets it fills lines into, and how much of the data
+// other work had in the cache it can displace. Larger chunks cost less
+// overhead per byte but disturb more of the cache each time the scrubber
+// runs, so comparing the predictions for a few chunk sizes lets the chunk
+// size be chosen from the numbers rather than by trial on the target.
+//
+// The prediction starts with a cold cache and models a single level of
+// LRU cache, so it is an estimate for comparing configurations, not a
+// measurement.
+
+use crate::base::*;
+use crate::cachesim::*;
+use crate::config::*;
+use crate::dryrun::*;
+
+/// Predicted effect on the cache of scrubbing in chunks of one size
+///
+/// * `chunk_bytes` - Number of bytes scrubbed per chunk
+///
+/// * `chunks` - Number of chunks in a pass
+///
+/// * `mean_sets_disturbed` - Mean number of sets a chunk fills lines into
+///
+/// * `max_sets_disturbed` - Most sets any chunk fills lines into
+///
+/// * `mean_disruption` - Mean fraction of the cache a chunk can displace
+///
+/// * `max_disruption` - Largest fraction of the cache any chunk can
+///   displace, the worst case for the working set of other work
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ImpactReport {
+    pub chunk_bytes: usize,
+    pub chunks: usize,
+    pub mean_sets_disturbed: f64,
+    pub max_sets_disturbed: usize,
+    pub mean_disruption: f64,
+    pub max_disruption: f64,
+}
+
+/// Predict the effect on the cache of scrubbing in chunks of a given size
+///
+/// # Arguments:
+/// * `config` - The cache geometry
+///
+/// * `extents` - (start, end) address of each scrub area, end inclusive
+///
+/// * `sub_passes` - Number of interleaved sub-passes in a pass, as for
+///   LineScrubber::set_sub_passes()
+///
+/// * `chunk_bytes` - Number of bytes scrubbed per chunk, a multiple of
+///   the cache line size
+///
+/// # Returns:
+/// Ok(ImpactReport) on success, otherwise Err(Error::ZeroSize) if the
+/// chunk size is zero, Err(Error::UnalignedSize) if it isn't a multiple
+/// of the cache line size, or another Err(Error) if the configuration or
+/// the areas are not valid
+pub fn predict_impact(
+    config: &ScrubConfig,
+    extents: &[(usize, usize)],
+    sub_passes: usize,
+    chunk_bytes: usize,
+) -> Result<ImpactReport, Error> {
+    let line_size = config.cacheline_size;
+    if chunk_bytes == 0 {
+        return Err(Error::ZeroSize);
+    }
+    if !line_size.is_power_of_two()
+        || !config.cache_lines.is_power_of_two()
+    {
+        return Err(Error::UnalignedValue);
+    }
+    if !chunk_bytes.is_multiple_of(line_size) {
+        return Err(Error::UnalignedSize);
+    }
+
+    let index_width = config.cache_lines.trailing_zeros() as usize;
+    let orders = (0..sub_passes.max(1))
+        .map(|sub_pass| {
+            ScrubOrder::sub_pass(
+                extents,
+                line_size,
+                index_width,
+                sub_passes,
+                sub_pass,
+            )
+        })
+        .collect::<Result<Vec<ScrubOrder>, Error>>()?;
+
+    let mut cache = CacheSim::new(
+        config.cache_lines,
+        config.ways,
+        line_size,
+        Replacement::Lru,
+    )?;
+    let capacity = (cache.sets() * cache.ways()) as f64;
+    let reports = cache
+        .run_chunks(orders.into_iter().flatten(), chunk_bytes / line_size);
+    if reports.is_empty() {
+        return Err(Error::NoMemAreas);
+    }
+
+    let chunks = reports.len();
+    let sets: usize = reports.iter().map(|r| r.sets_disturbed).sum();
+    let filled: usize = reports.iter().map(|r| r.ways_filled).sum();
+    let max_filled = reports.iter().map(|r| r.ways_filled).max();
+    Ok(ImpactReport {
+        chunk_bytes,
+        chunks,
+        mean_sets_disturbed: sets as f64 / chunks as f64,
+        max_sets_disturbed: reports
+            .iter()
+            .map(|r| r.sets_disturbed)
+            .max()
+            .unwrap_or(0),
+        mean_disruption: filled as f64 / chunks as f64 / capacity,
+        max_disruption: max_filled.unwrap_or(0) as f64 / capacity,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // 16 sets of 2 ways of 64-byte lines, a 2 KiB cache
+    fn config() -> ScrubConfig {
+        ScrubConfig {
+            cache_lines: 16,
+            ways: 2,
+            cacheline_size: 64,
+            data_size: 8,
+            addr_size: 8,
+        }
+    }
+
+    #[test]
+    fn test_chunk_sizes() {
+        // Each cache index has 16 lines in 16 KiB
+        let extents = [(0, 16 * 1024 - 1)];
+        let small = predict_impact(&config(), &extents, 1, 1024).unwrap();
+        assert_eq!(small.chunks, 16);
+        assert_eq!(small.mean_sets_disturbed, 1.0);
+        assert_eq!(small.max_sets_disturbed, 1);
+        assert_eq!(small.max_disruption, 2.0 / 32.0);
+
+        // A chunk of two cache indices disturbs two sets
+        let large = predict_impact(&config(), &extents, 1, 2048).unwrap();
+        assert_eq!(large.chunks, 8);
+        assert_eq!(large.max_sets_disturbed, 2);
+        assert_eq!(large.mean_disruption, 4.0 / 32.0);
+
+        // Interleaved sub-passes only change which sets, not how many
+        let split = predict_impact(&config(), &extents, 4, 2048).unwrap();
+        assert_eq!(split.max_sets_disturbed, 2);
+    }
+
+    #[test]
+    fn test_partial_chunks() {
+        // Chunks of 24 lines straddle two cache indices
+        let extents = [(0, 16 * 1024 - 1)];
+        let report = predict_impact(&config(), &extents, 1, 1536).unwrap();
+        assert_eq!(report.chunks, 11);
+        assert_eq!(report.max_sets_disturbed, 2);
+        assert!(report.mean_sets_disturbed > 1.0);
+        assert!(report.max_disruption <= 4.0 / 32.0);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let extents = [(0, 4095)];
+        assert_eq!(
+            predict_impact(&config(), &extents, 1, 0),
+            Err(Error::ZeroSize)
+        );
+        assert_eq!(
+            predict_impact(&config(), &extents, 1, 100),
+            Err(Error::UnalignedSize)
+        );
+        assert_eq!(
+            predict_impact(&config(), &extents, 3, 1024),
+            Err(Error::UnalignedValue)
+        );
+        let config = ScrubConfig {
+            cache_lines: 12,
+            ..config()
+        };
+        assert_eq!(
+            predict_impact(&config, &extents, 1, 1024),
+            Err(Error::UnalignedValue)
+        );
+    }
+}
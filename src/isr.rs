@@ -0,0 +1,404 @@
+// Scrubbing from interrupt context. Bare-metal firmware often scrubs from
+// a periodic timer interrupt, where the work done must be short, bounded
+// and must not allocate, format or panic. LineScrubber can't promise
+// these: it allocates when a pass starts and when policies act, and its
+// statistics keep per-area state. IsrSafeScrubber is a separate, much
+// smaller scrubber for this case. Everything it needs, including the
+// scrub areas, which are kept in a fixed-size array, is set up when it is
+// created, outside interrupt context. scrub_step() then does no more than
+// a fixed number of reads, with only checked arithmetic and no indexing
+// that could panic.
+//
+// Lines are visited in the same order as ScrubOrder, by cache index, so
+// an ISR scrubbing a few lines at a time doesn't keep evicting the lines
+// it just read. The cache indices an area holds lines for are worked out
+// from its line count, so finding the next line jumps over the indices in
+// between and takes time bounded by the number of areas, however many
+// cache indices there are.
+
+use crate::backend::*;
+use crate::base::*;
+
+/// Most cache lines scrub_step() reads in one call
+pub const ISR_MAX_LINES: usize = 64;
+
+/// What one call to scrub_step() did
+///
+/// * `lines` - Number of cache lines read
+///
+/// * `errors` - Number of reads, or calls to the batch hooks of the
+///   backend, that failed
+///
+/// * `pass_complete` - Whether a pass completed during the step
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct IsrStep {
+    pub lines: usize,
+    pub errors: usize,
+    pub pass_complete: bool,
+}
+
+/// A scrubber for calling from an interrupt handler. scrub_step() reads at
+/// most ISR_MAX_LINES cache lines, and does not allocate, format or panic,
+/// provided the backend doesn't.
+///
+/// * `backend` - Backend making the reads
+///
+/// * `extents` - (start, end) of each scrub area, end inclusive
+///
+/// * `areas` - Number of entries of `extents` in use
+///
+/// * `counts` - Number of cache lines in each scrub area
+///
+/// * `line_size` - Number of bytes in a cache line
+///
+/// * `cache_lines` - Number of cache indices
+///
+/// * `lines_per_step` - Number of lines read by each scrub_step()
+///
+/// * `pass_lines` - Number of lines in a pass
+///
+/// * `index` - Cache index being scrubbed
+///
+/// * `area` - Area being scrubbed
+///
+/// * `next` - Next address in the area with the cache index, if any
+///
+/// * `remaining` - Number of lines left in the pass
+///
+/// * `passes` - Number of passes completed
+///
+/// * `lines` - Number of lines read
+///
+/// * `errors` - Number of reads, or calls to the batch hooks, that failed
+///
+/// * `last_error` - Address of the most recent failed read
+pub struct IsrSafeScrubber<B: ScrubBackend, const N: usize> {
+    backend: B,
+    extents: [(usize, usize); N],
+    areas: usize,
+    counts: [usize; N],
+    line_size: usize,
+    cache_lines: usize,
+    lines_per_step: usize,
+    pass_lines: usize,
+    index: usize,
+    area: usize,
+    next: Option<usize>,
+    remaining: usize,
+    passes: u64,
+    lines: u64,
+    errors: u64,
+    last_error: Option<usize>,
+}
+
+impl<B: ScrubBackend, const N: usize> IsrSafeScrubber<B, N> {
+    /// Create the scrubber, outside interrupt context
+    ///
+    /// # Arguments:
+    /// * `backend` - Backend making the reads
+    ///
+    /// * `extents` - (start, end) address of each scrub area, end
+    ///   inclusive, no more than N of them
+    ///
+    /// * `line_size` - Number of bytes in a cache line, a power of two
+    ///
+    /// * `index_width` - Number of address bits in the cache index
+    ///
+    /// * `lines_per_step` - Number of lines read by each scrub_step(),
+    ///   from one to ISR_MAX_LINES
+    ///
+    /// # Returns:
+    /// The scrubber, otherwise Err(Error::TooManyAreas) if there are more
+    /// than N areas, Err(Error::UnalignedValue) if a size is out of range,
+    /// or an Error if the areas are not valid. See check_extents().
+    pub fn new(
+        backend: B,
+        extents: &[(usize, usize)],
+        line_size: usize,
+        index_width: usize,
+        lines_per_step: usize,
+    ) -> Result<IsrSafeScrubber<B, N>, Error> {
+        if extents.len() > N {
+            return Err(Error::TooManyAreas);
+        }
+        if index_width >= usize::BITS as usize
+            || !(1..=ISR_MAX_LINES).contains(&lines_per_step)
+        {
+            return Err(Error::UnalignedValue);
+        }
+        let pass_lines = check_extents(extents, line_size)?;
+
+        let mut areas = [(0, 0); N];
+        areas[..extents.len()].copy_from_slice(extents);
+        let mut counts = [0; N];
+        for (count, &(start, end)) in counts.iter_mut().zip(extents) {
+            *count = (end - start) / line_size + 1;
+        }
+        let mut scrubber = IsrSafeScrubber {
+            backend,
+            extents: areas,
+            areas: extents.len(),
+            counts,
+            line_size,
+            cache_lines: 1 << index_width,
+            lines_per_step,
+            pass_lines,
+            index: 0,
+            area: 0,
+            next: None,
+            remaining: 0,
+            passes: 0,
+            lines: 0,
+            errors: 0,
+            last_error: None,
+        };
+        scrubber.start_pass();
+        Ok(scrubber)
+    }
+
+    /// Scrub the next few lines, for calling from an interrupt handler.
+    /// Failed reads are counted and scrubbing goes on with the next line.
+    ///
+    /// # Returns:
+    /// What the step did
+    pub fn scrub_step(&mut self) -> IsrStep {
+        let mut step = IsrStep::default();
+        if self.backend.begin_batch().is_err() {
+            step.errors += 1;
+        }
+        while step.lines < self.lines_per_step {
+            let addr = match self.next_line() {
+                Some(addr) => addr,
+                None => break,
+            };
+            if self.backend.read_line(addr).is_err() {
+                step.errors += 1;
+                self.last_error = Some(addr);
+            }
+            step.lines += 1;
+            if self.remaining == 0 {
+                self.passes = self.passes.wrapping_add(1);
+                self.start_pass();
+                step.pass_complete = true;
+            }
+        }
+        if self.backend.end_batch().is_err() {
+            step.errors += 1;
+        }
+        self.lines = self.lines.wrapping_add(step.lines as u64);
+        self.errors = self.errors.wrapping_add(step.errors as u64);
+        step
+    }
+
+    /// Returns the number of passes completed
+    pub fn passes(&self) -> u64 {
+        self.passes
+    }
+
+    /// Returns the number of lines read
+    pub fn lines(&self) -> u64 {
+        self.lines
+    }
+
+    /// Returns the number of reads that failed
+    pub fn errors(&self) -> u64 {
+        self.errors
+    }
+
+    /// Returns the address of the most recent failed read, if any
+    pub fn last_error(&self) -> Option<usize> {
+        self.last_error
+    }
+
+    /// Returns the number of lines in a pass
+    pub fn pass_lines(&self) -> usize {
+        self.pass_lines
+    }
+
+    /// Returns the backend making the reads
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    // Go back to the first line of the pass
+    fn start_pass(&mut self) {
+        self.index = 0;
+        self.area = 0;
+        self.remaining = self.pass_lines;
+        self.next = self.first();
+    }
+
+    // Returns the next line of the pass, or None if there is none left
+    fn next_line(&mut self) -> Option<usize> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let addr = match self.next {
+            Some(addr) => addr,
+            None => self.next_run()?,
+        };
+
+        let (_, end) = *self.extents.get(self.area)?;
+        self.next = self
+            .line_size
+            .checked_mul(self.cache_lines)
+            .and_then(|stride| addr.checked_add(stride))
+            .filter(|&next| next <= end);
+        self.remaining -= 1;
+        Some(addr)
+    }
+
+    // Move on to the next area and cache index for which there are lines
+    // to scrub and return the first of them
+    fn next_run(&mut self) -> Option<usize> {
+        for area in self.area + 1..self.areas {
+            self.area = area;
+            if let Some(addr) = self.first() {
+                return Some(addr);
+            }
+        }
+
+        // No later area has lines with this cache index, so go to the
+        // lowest area with the nearest index that any area has lines for
+        let (index, area) = (0..self.areas)
+            .filter_map(|area| Some((self.next_index(area)?, area)))
+            .min()?;
+        self.index = index;
+        self.area = area;
+        self.first()
+    }
+
+    // Returns the first cache index after the current one for which the
+    // given area has lines. An area's lines have consecutive cache
+    // indices, wrapping around, starting with that of its first line.
+    fn next_index(&self, area: usize) -> Option<usize> {
+        let (start, _) = *self.extents.get(area)?;
+        let count = *self.counts.get(area)?;
+        let index = self.index.checked_add(1)?;
+        if index >= self.cache_lines {
+            return None;
+        }
+
+        let offset = index.wrapping_sub(start / self.line_size)
+            & (self.cache_lines - 1);
+        if offset < count {
+            return Some(index);
+        }
+        index
+            .checked_add(self.cache_lines - offset)
+            .filter(|&next| next < self.cache_lines)
+    }
+
+    // Returns the lowest address in the current area with the current
+    // cache index
+    fn first(&self) -> Option<usize> {
+        let (start, end) = *self.extents.get(self.area)?;
+        let line = start / self.line_size;
+        let skip = self.index.wrapping_sub(line) & (self.cache_lines - 1);
+        line.checked_add(skip)
+            .and_then(|l| l.checked_mul(self.line_size))
+            .filter(|&addr| addr <= end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dryrun::*;
+    use crate::sim::*;
+
+    struct Failing(usize);
+
+    impl ScrubBackend for Failing {
+        fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+            match addr == self.0 {
+                true => Err(Error::InternalError),
+                false => Ok(()),
+            }
+        }
+    }
+
+    #[test]
+    fn test_order() {
+        // The same lines in the same order as ScrubOrder
+        let extents = [(0, 255), (448, 1023)];
+        let order: Vec<usize> =
+            ScrubOrder::new(&extents, 64, 2).unwrap().collect();
+
+        let mem = SimMemory::new(0, 1024, 64).unwrap();
+        let mut scrubber =
+            IsrSafeScrubber::<_, 4>::new(mem, &extents, 64, 2, 5).unwrap();
+        assert_eq!(scrubber.pass_lines(), 13);
+        let steps: Vec<IsrStep> =
+            (0..3).map(|_| scrubber.scrub_step()).collect();
+        assert_eq!(steps[0].lines, 5);
+        assert!(!steps[1].pass_complete);
+        assert!(steps[2].pass_complete);
+        assert_eq!(scrubber.passes(), 1);
+        assert_eq!(scrubber.lines(), 15);
+
+        // Two lines into the second pass
+        for &addr in &order {
+            let reads = match order[..2].contains(&addr) {
+                true => 2,
+                false => 1,
+            };
+            assert_eq!(scrubber.backend().reads_at(addr), Some(reads));
+        }
+    }
+
+    #[test]
+    fn test_sparse() {
+        // With many more cache indices than lines, each step goes
+        // straight to the next index with lines rather than stepping
+        // through those in between
+        let extents = [(4096, 4223), (0, 127)];
+        let mem = SimMemory::new(0, 8192, 64).unwrap();
+        let mut scrubber =
+            IsrSafeScrubber::<_, 2>::new(mem, &extents, 64, 30, 4)
+                .unwrap();
+        let step = scrubber.scrub_step();
+        assert_eq!(step.lines, 4);
+        assert!(step.pass_complete);
+        for addr in [0, 64, 4096, 4160] {
+            assert_eq!(scrubber.backend().reads_at(addr), Some(1));
+        }
+
+        let order: Vec<usize> =
+            ScrubOrder::new(&extents, 64, 30).unwrap().collect();
+        assert_eq!(order, [0, 64, 4096, 4160]);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut scrubber = IsrSafeScrubber::<_, 1>::new(
+            Failing(128),
+            &[(0, 255)],
+            64,
+            4,
+            4,
+        )
+        .unwrap();
+        let step = scrubber.scrub_step();
+        assert_eq!(step.errors, 1);
+        assert_eq!(step.lines, 4);
+        assert_eq!(scrubber.last_error(), Some(128));
+    }
+
+    #[test]
+    fn test_limits() {
+        let extents = [(0, 63), (128, 191)];
+        assert!(matches!(
+            IsrSafeScrubber::<_, 1>::new(Failing(0), &extents, 64, 4, 1),
+            Err(Error::TooManyAreas)
+        ));
+        assert!(IsrSafeScrubber::<_, 2>::new(
+            Failing(0),
+            &extents,
+            64,
+            4,
+            ISR_MAX_LINES + 1
+        )
+        .is_err());
+    }
+}
@@ -9,19 +9,163 @@ extern crate num_traits;
 //use core::ops::{Add};
 //use core::ptr;
 //use num_traits::{PrimInt, Unsigned};
+use num_traits::ToPrimitive;
+use std::cell::Cell;
 use std::convert::From;
 //use std::iter;
 use std::marker::PhantomData;
 //use std::slice;
+use std::sync::mpsc::Receiver;
+use std::time::{Duration, Instant};
 
+mod adaptive;
 mod addr;
+mod alias;
+mod arch;
+mod area;
+#[cfg(feature = "audit")]
+mod audit;
+mod backend;
+mod badblocks;
+mod barrier;
 mod base;
+mod bench;
+mod budget;
+mod cachesim;
+mod channel;
+mod checkpoint;
+mod clock;
+mod compliance;
+mod config;
+mod control;
+mod coverage;
+mod daemon;
 mod data;
+mod dedup;
+mod desc;
+mod diag;
+mod dma;
+mod dryrun;
+mod energy;
+mod event;
+#[cfg(feature = "ffi")]
+mod ffi;
+mod flushretry;
+#[cfg(feature = "fuzz")]
+mod fuzz;
+mod group;
+mod guard;
+mod histogram;
+mod history;
+mod impact;
+mod isr;
+mod linker;
+#[cfg(feature = "mock")]
+mod mock;
+mod os;
+mod persist;
+mod planner;
+mod preflight;
+mod policy;
+mod quarantine;
+mod quiet;
+mod registry;
+mod rng;
+#[cfg(any(feature = "zephyr", feature = "freertos"))]
+mod rtos;
+mod sched;
+mod selftest;
+mod sim;
+mod staleness;
+mod standby;
+mod stats;
+mod storm;
+mod status;
+mod sync;
+#[cfg(test)]
+mod testutil;
+mod threshold;
+mod throttle;
+mod touch;
+mod trace;
+mod tune;
+mod validate;
+mod verify;
+mod wcet;
 
 use crate::addr::*;
+pub use crate::adaptive::*;
+pub use crate::alias::*;
+pub use crate::arch::*;
+pub use crate::area::*;
+#[cfg(feature = "audit")]
+pub use crate::audit::*;
+pub use crate::backend::*;
+pub use crate::badblocks::*;
+pub use crate::barrier::*;
 use crate::base::*;
+pub use crate::bench::*;
 //use crate::base::Error::*;
+pub use crate::budget::*;
+pub use crate::cachesim::*;
+pub use crate::channel::*;
+pub use crate::checkpoint::*;
+pub use crate::clock::*;
+pub use crate::compliance::*;
+pub use crate::config::*;
+pub use crate::control::*;
+pub use crate::coverage::*;
+pub use crate::daemon::*;
 use crate::data::*;
+pub use crate::dedup::*;
+pub use crate::desc::*;
+pub use crate::diag::*;
+pub use crate::dma::*;
+pub use crate::dryrun::*;
+pub use crate::energy::*;
+pub use crate::event::*;
+#[cfg(feature = "ffi")]
+pub use crate::ffi::*;
+pub use crate::flushretry::*;
+#[cfg(feature = "fuzz")]
+pub use crate::fuzz::*;
+pub use crate::group::*;
+pub use crate::guard::*;
+pub use crate::histogram::*;
+pub use crate::history::*;
+pub use crate::impact::*;
+pub use crate::isr::*;
+pub use crate::linker::*;
+#[cfg(feature = "mock")]
+pub use crate::mock::*;
+pub use crate::os::*;
+pub use crate::persist::*;
+pub use crate::planner::*;
+pub use crate::preflight::*;
+pub use crate::policy::*;
+pub use crate::quarantine::*;
+pub use crate::quiet::*;
+pub use crate::registry::*;
+pub use crate::rng::*;
+#[cfg(any(feature = "zephyr", feature = "freertos"))]
+pub use crate::rtos::*;
+pub use crate::sched::*;
+pub use crate::selftest::*;
+pub use crate::sim::*;
+pub use crate::staleness::*;
+pub use crate::standby::*;
+pub use crate::stats::*;
+pub use crate::storm::*;
+pub use crate::status::*;
+use crate::sync::Arc;
+pub use crate::threshold::*;
+pub use crate::throttle::*;
+pub use crate::touch::*;
+pub use crate::trace::*;
+pub use crate::tune::*;
+pub use crate::validate::*;
+pub use crate::verify::*;
+pub use crate::wcet::*;
 /*
 use crate::addr::{Addr, AddrImplTrait};
 use crate::base::{
@@ -31,7 +175,47 @@ use crate::base::{
 use crate::data::DataImplTrait;
 */
 
-struct AutoScrub<'a, const N: usize, const W: usize, const S: usize, D, A, I>
+// Returns the (start, end) address of each scrub area
+fn area_extents<A>(scrub_areas: &[MemArea<A>]) -> Vec<(usize, usize)>
+where
+    A: AddrImplTrait<A>,
+    usize: From<A>,
+{
+    scrub_areas
+        .iter()
+        .map(|a| (usize::from(a.s.0), usize::from(a.e.0)))
+        .collect()
+}
+
+// Returns the index of the scrub area holding a physical address, if any.
+// phys_pages holds the page size and the sorted physical page base and
+// area of each translated page; without it the areas are physically
+// addressed.
+fn phys_area<A>(scrub_areas: &[MemArea<A>],
+    phys_pages: &Option<(usize, Vec<(u64, usize)>)>, addr: u64)
+    -> Option<usize>
+where
+    A: AddrImplTrait<A>,
+    usize: From<A>,
+{
+    match phys_pages {
+        Some((page_size, pages)) => {
+            let page = addr & !(*page_size as u64 - 1);
+            pages.binary_search_by_key(&page, |&(p, _)| p).ok()
+                .map(|i| pages[i].1)
+        },
+        None => scrub_areas.iter().position(|a| {
+            usize::from(a.s.0) as u64 <= addr
+                && addr <= usize::from(a.e.0) as u64
+        }),
+    }
+}
+
+/// Scrubs memory in chunks whose sizes are chosen by an AutoScrubDesc.
+/// Create one with new() and scrub with run() or, a chunk at a time, with
+/// run_once(). The instance can be kept between runs and inspected.
+#[allow(deprecated)]
+pub struct AutoScrub<'a, const N: usize, const W: usize, const S: usize, D, A, I>
 where
     D: DataImplTrait<D>,
     A: AddrImplTrait<A>,
@@ -40,12 +224,27 @@ where
 {
     scrubber: MemoryScrubber<'a, N, W, S, D, A, I>,
     desc: &'a mut dyn AutoScrubDesc<N, W, S, D, A>,
+    stats: ScrubStats,
+    sinks: Vec<Box<dyn EventSink + 'a>>,
+    chunk_size: usize,
+    error_source: Option<Receiver<ErrorReport>>,
+    policies: Vec<Box<dyn Policy + 'a>>,
+    jitter: Option<Jitter>,
+    budget: Option<Arc<BandwidthBudget>>,
+    cancel: Option<CancelToken>,
+    control: Option<ScrubControl>,
+    chunk_bound: Option<ChunkBound>,
+    cache_partition: Option<CachePartition>,
+    health_weights: Option<HealthWeights>,
+    phys_pages: Option<(usize, Vec<(u64, usize)>)>,
+    clock: Box<dyn Clock + 'a>,
     // FIXME: Remove when possible. Right now, the compiler doesn't appear
     // to know that U is actually used when it's in CacheBase<CL>. So, this
     // works around that problem
     _marker1: PhantomData<D>,
 }
 
+#[allow(deprecated)]
 impl<'a, const N: usize, const W: usize, const S: usize, D, A, I>
     AutoScrub<'a, N, W, S, D, A, I>
 where
@@ -65,7 +264,7 @@ where
     ///
     /// # Returns:
     /// Ok(AutoScrub<_>> on success, otherwise Err(Error)
-    fn new(cache_in: &'a dyn CacheBase<N, W, S, D, A>,
+    pub fn new(cache_in: &'a dyn CacheBase<N, W, S, D, A>,
         scrub_areas: &'a [MemArea<A>],
         desc: &'a mut dyn AutoScrubDesc<N, W, S, D, A>) ->
         Result<AutoScrub<'a, N, W, S, D, A, I>, Error> {
@@ -76,39 +275,547 @@ where
             Ok(scrubber) => scrubber,
         };
 
+        let sizes: Vec<usize> = scrub_areas
+            .iter()
+            .map(|a| usize::from(a.e.0) - usize::from(a.s.0) + 1)
+            .collect();
+        let stats = ScrubStats::new(&sizes, Instant::now());
+
         Ok(AutoScrub::<'a, N, W, S, D, A, I> {
             scrubber:   scrubber,
             desc:       desc,
+            stats:      stats,
+            sinks:      Vec::new(),
+            chunk_size: 0,
+            error_source: None,
+            policies: Vec::new(),
+            jitter: None,
+            budget: None,
+            cancel: None,
+            control: None,
+            chunk_bound: None,
+            cache_partition: None,
+            health_weights: None,
+            phys_pages: None,
+            clock: Box::new(SystemClock),
             _marker1:   PhantomData,
         })
     }
 
-    // Run once through the scrubber loop
-    fn scrub(&mut self) -> Result<(), Error> {
+    /// Scrub until the AutoScrubDesc returns zero or the cancel token or
+    /// control, if any, is cancelled. Once cancelled, the current chunk is
+    /// completed and checkpoint() can be used to save the state for
+    /// resume(). While the control is paused, scrubbing waits between
+    /// chunks.
+    ///
+    /// # Returns:
+    /// Ok(ScrubSummary) describing the scrubbing done, otherwise Err(Error)
+    pub fn run(&mut self) -> Result<ScrubSummary, Error> {
+        let start_stats = self.stats.clone();
+        let started = self.clock.now();
+
+        let reason = loop {
+            if self.cancel.as_ref().is_some_and(|c| c.is_cancelled()) {
+                break StopReason::Cancelled;
+            }
+            if self.control.as_ref().is_some_and(|c| !c.wait()) {
+                break StopReason::Cancelled;
+            }
+            if self.run_once()?.is_none() {
+                break StopReason::Finished;
+            }
+        };
+
+        Ok(ScrubSummary::new(&start_stats, &self.stats,
+            self.clock.now() - started, reason))
+    }
+
+    /// Set the source of time used for waiting between chunks and for the
+    /// statistics, such as a VirtualClock so that scrubbing can be tested
+    /// without real sleeps. This should be done before scrubbing, since
+    /// the statistics are restarted at the time of the new clock.
+    pub fn set_clock(&mut self, clock: Box<dyn Clock + 'a>) {
+        let now = clock.now();
+        self.stats.started = now;
+        self.stats.pass_started = now;
+        self.clock = clock;
+    }
+
+    /// Limit the number of cache lines scrubbed in each chunk, so that a
+    /// chunk never takes longer than its time slot. Larger chunks asked for
+    /// by the AutoScrubDesc are cut down to the limit, which is reported
+    /// to the diagnostics each time the size of the chunk changes. The
+    /// longest chunk time seen is kept in the statistics as
+    /// max_chunk_time.
+    pub fn set_chunk_bound(&mut self, bound: ChunkBound) {
+        self.chunk_bound = Some(bound);
+    }
+
+    /// Set a token that, when cancelled, stops run() after the current
+    /// chunk
+    pub fn set_cancel_token(&mut self, token: CancelToken) {
+        self.cancel = Some(token);
+    }
+
+    /// Set a control through which other threads can pause, resume and
+    /// stop run(), and read the statistics as of the last chunk
+    pub fn set_control(&mut self, control: ScrubControl) {
+        self.control = Some(control);
+    }
+
+    /// Save the state needed to resume scrubbing where it stopped
+    pub fn checkpoint(&self) -> ScrubCheckpoint {
+        ScrubCheckpoint {
+            extents: area_extents(self.scrubber.scrub_areas()),
+            stats: self.stats.clone(),
+            chunk_size: self.chunk_size,
+        }
+    }
+
+    /// Continue from a checkpoint, restoring the statistics and the
+    /// position in the pass, so that the next chunk starts in the scrub
+    /// area and at the offset where the last one stopped
+    ///
+    /// # Arguments:
+    /// * `checkpoint` - State saved by checkpoint()
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::CheckpointMismatch) if the
+    /// checkpoint was taken with different scrub areas or its position is
+    /// not in them
+    pub fn resume(&mut self, checkpoint: ScrubCheckpoint)
+        -> Result<(), Error> {
+        if !checkpoint.matches(&area_extents(self.scrubber.scrub_areas())) {
+            return Err(Error::CheckpointMismatch);
+        }
+        let cursor = checkpoint.cursor()
+            .map_err(|_| Error::CheckpointMismatch)?;
+        self.scrubber.resume_at(cursor)
+            .map_err(|_| Error::CheckpointMismatch)?;
+        self.stats = checkpoint.stats;
+        self.chunk_size = checkpoint.chunk_size;
+        Ok(())
+    }
+
+    /// Scrub the next chunk, as given by the AutoScrubDesc
+    ///
+    /// # Returns:
+    /// Ok(Some(bytes)) with the number of bytes scrubbed, Ok(None) if the
+    /// AutoScrubDesc returned zero, otherwise Err(Error)
+    pub fn run_once(&mut self) -> Result<Option<usize>, Error> {
+        self.poll_errors();
+
+        let n = self.desc.next_with(&self.stats);
+        if n == Addr::<A>(0.into()) {
+            return Ok(None);
+        }
+        let asked = usize::from(n.0);
+        let line_size = self.scrubber.cacheline_size();
+        let mut chunk_size = asked;
+        if let Some(bound) = &self.chunk_bound {
+            let max_bytes = bound.max_lines().saturating_mul(line_size);
+            chunk_size = chunk_size.min(max_bytes);
+        }
+        let n: Addr<A> = chunk_size.into();
+        if chunk_size != self.chunk_size {
+            if chunk_size != asked {
+                diag!(Info, "chunk of {} bytes cut to {} by its bound",
+                    asked, chunk_size);
+            }
+            if self.chunk_size != 0 {
+                self.emit(&ScrubEvent::RateChange {
+                    old: self.chunk_size,
+                    new: chunk_size,
+                });
+            }
+            self.chunk_size = chunk_size;
+        }
+
+        if let Some(budget) = &self.budget {
+            let wait = budget.reserve(chunk_size, self.clock.now());
+            if !wait.is_zero() {
+                self.clock.sleep(wait);
+            }
+        }
+
+        let start = self.clock.now();
+        self.scrubber.scrub(n)?;
+        let now = self.clock.now();
+        if let Some(bound) = &mut self.chunk_bound {
+            bound.observe(chunk_size.div_ceil(line_size), now - start);
+        }
+        let passes = self.stats.passes;
+        let pass_started = self.stats.pass_started;
+        self.stats.record_chunk(chunk_size, now - start, now);
+        if let Some(control) = &self.control {
+            control.publish(&self.stats);
+        }
+        self.emit(&ScrubEvent::ChunkComplete {
+            bytes: chunk_size,
+            duration: now - start,
+        });
+        if self.stats.passes != passes {
+            self.emit(&ScrubEvent::PassComplete {
+                pass: self.stats.passes,
+                epoch: self.stats.epoch - 1,
+                bytes: self.stats.pass_size,
+                duration: now - pass_started,
+            });
+            if let Some(weights) = self.health_weights {
+                let scores = self.stats.health_scores(&weights, now);
+                for (area, score) in scores.into_iter().enumerate() {
+                    self.emit(&ScrubEvent::Health { area, score });
+                }
+            }
+        }
+
+        self.throttle(now - start);
+        Ok(Some(chunk_size))
+    }
+
+    /// Returns the underlying memory scrubber
+    pub fn scrubber(&self) -> &MemoryScrubber<'a, N, W, S, D, A, I> {
+        &self.scrubber
+    }
+
+    /// Add a policy limiting the scrub rate. When there is more than one,
+    /// the one allowing the lowest rate is used. It is added to the policy
+    /// pipeline as a Throttle.
+    pub fn add_throttle_policy(&mut self,
+        policy: Box<dyn ThrottlePolicy + 'a>) {
+        self.policies.push(Box::new(Throttle(policy)));
+    }
+
+    /// Add a policy to the pipeline given every event. The scrub rate is
+    /// the lowest rate allowed by any policy. The actions returned act on
+    /// the lines of a LineScrubber, which a MemoryScrubber can't do, so
+    /// they are left to policies added to a LineScrubber.
+    pub fn add_policy(&mut self, policy: Box<dyn Policy + 'a>) {
+        self.policies.push(policy);
+    }
+
+    /// Draw from a bandwidth budget before scrubbing each chunk. Scrubbers
+    /// sharing the budget keep their combined rate under its limit.
+    pub fn set_bandwidth_budget(&mut self, budget: Arc<BandwidthBudget>) {
+        self.budget = Some(budget);
+    }
+
+    /// Add random jitter to the wait between chunks, so that scrubbers
+    /// started together don't scrub in lock step
+    ///
+    /// # Arguments:
+    /// * `fraction` - Largest jitter as a fraction of the time from the
+    ///   start of one chunk to the start of the next. Zero disables jitter.
+    pub fn set_jitter(&mut self, fraction: f64) {
+        self.jitter = match fraction > 0.0 {
+            true => Some(Jitter::new(fraction)),
+            false => None,
+        };
+    }
+
+    /// Add random jitter to the wait between chunks, drawing on a source
+    /// of random numbers, such as a hardware random number generator
+    ///
+    /// # Arguments:
+    /// * `fraction` - Largest jitter as a fraction of the time from the
+    ///   start of one chunk to the start of the next. Zero disables jitter.
+    ///
+    /// * `rng` - Source of random numbers
+    pub fn set_jitter_rng(
+        &mut self,
+        fraction: f64,
+        rng: Box<dyn CloneRng>,
+    ) {
+        self.jitter = match fraction > 0.0 {
+            true => Some(Jitter::with_rng(fraction, rng)),
+            false => None,
+        };
+    }
+
+    // Wait long enough after scrubbing a chunk to keep to the rate allowed
+    // by the policies, waiting for as long as they pause scrubbing
+    fn throttle(&mut self, chunk_time: Duration) {
         loop {
-            let n = self.desc.next();
-            if n == Addr::<A>(0.into()) {
-                return Ok(());
+            let now = self.clock.now();
+            let rate = self.policies.iter_mut()
+                .map(|p| p.rate(now))
+                .fold(1.0, f64::min);
+            match throttle_delay(rate, chunk_time) {
+                None => self.clock.sleep(THROTTLE_PAUSE),
+                Some(delay) => {
+                    let delay = match &mut self.jitter {
+                        None => delay,
+                        Some(jitter) => jitter.apply(delay, chunk_time),
+                    };
+                    if !delay.is_zero() {
+                        self.clock.sleep(delay);
+                    }
+                    return;
+                }
             }
-            self.scrubber.scrub(n)?;
         }
     }
 
+    // Send an event to all event sinks and policies
+    fn emit(&mut self, event: &ScrubEvent) {
+        for sink in self.sinks.iter_mut() {
+            sink.event(event);
+        }
+        let now = self.clock.now();
+        for policy in self.policies.iter_mut() {
+            policy.event(event, now);
+        }
+    }
+
+    /// Set a channel from which errors detected elsewhere, such as by the
+    /// kernel's EDAC drivers, are received. Errors are taken from the
+    /// channel between chunks and handled as if passed to record_error(),
+    /// so their physical addresses are matched to scrub areas as it
+    /// describes. Each report is counted as many times as the errors it
+    /// holds but is passed on to the event sinks as a single event.
+    pub fn set_error_source(&mut self, source: Receiver<ErrorReport>) {
+        self.error_source = Some(source);
+    }
+
+    // Process any errors waiting in the error source
+    fn poll_errors(&mut self) {
+        let reports: Vec<ErrorReport> = match &self.error_source {
+            None => return,
+            Some(source) => source.try_iter().collect(),
+        };
+
+        for report in reports {
+            self.record_errors(report.error.addr, report.error.severity,
+                report.count);
+        }
+    }
+
+    /// Validate error detection end to end by injecting a correctable
+    /// error with ACPI EINJ, running through the scrubber loop, and
+    /// waiting for a corrected error to be reported in the scrub area
+    /// holding the address. Errors must be reported through an error
+    /// source, such as spawn_mc_event_feed(), for detection to be seen.
+    ///
+    /// # Arguments:
+    /// * `einj` - The EINJ interface
+    ///
+    /// * `addr` - Physical address at which to inject the error. It is
+    ///   matched to a scrub area through translate_areas(), if it has been
+    ///   called, otherwise the scrub areas must be physically addressed.
+    ///
+    /// * `timeout` - How long to wait for the report once scrubbing is done
+    ///
+    /// # Returns:
+    /// Ok(EinjReport) on success, otherwise Err(io::Error) if the error
+    /// could not be injected or scrubbing failed
+    #[cfg(target_os = "linux")]
+    pub fn validate_einj(&mut self, einj: &Einj, addr: u64,
+        timeout: Duration) -> std::io::Result<EinjReport> {
+        let area = phys_area(self.scrubber.scrub_areas(), &self.phys_pages,
+            addr).ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            "address is not in a scrub area"))?;
+        let before = self.stats.areas[area].errors_corrected;
+
+        let start = self.clock.now();
+        einj.inject_corrected(addr)?;
+        self.run().map_err(|e| std::io::Error::other(e.to_string()))?;
+
+        // The report may lag behind the read that triggered it
+        let deadline = self.clock.now() + timeout;
+        loop {
+            self.poll_errors();
+            let detected = self.stats.areas[area].errors_corrected != before;
+            let now = self.clock.now();
+            if detected || now >= deadline {
+                return Ok(EinjReport {
+                    addr,
+                    area,
+                    detected,
+                    elapsed: now - start,
+                });
+            }
+            self.clock.sleep((deadline - now).min(
+                Duration::from_millis(10)));
+        }
+    }
+
+    /// Add a sink to receive the events generated while scrubbing
+    pub fn add_event_sink(&mut self, sink: Box<dyn EventSink + 'a>) {
+        self.sinks.push(sink);
+    }
+
+    /// Translate the scrub areas to physical memory, so that errors, which
+    /// are reported by physical address, are attributed to the areas
+    /// holding them. Until this is called the scrub areas are taken to be
+    /// physically addressed. Pages with no translation, such as those not
+    /// yet faulted in, are left out, so this should be called again after
+    /// pages are added or moved.
+    ///
+    /// # Arguments:
+    /// * `translator` - Translates the addresses of the scrub areas, such
+    ///   as a PagemapTranslator
+    ///
+    /// * `page_size` - Number of bytes in a page, a power of two
+    ///
+    /// # Returns:
+    /// Ok(pages) with the number of pages translated, otherwise
+    /// Err(Error::UnalignedValue) if page_size isn't a power of two
+    pub fn translate_areas(&mut self,
+        translator: &mut dyn AddressTranslator, page_size: usize)
+        -> Result<usize, Error> {
+        if !page_size.is_power_of_two() {
+            return Err(Error::UnalignedValue);
+        }
+        let mut pages = Vec::new();
+        let extents = area_extents(self.scrubber.scrub_areas());
+        for (area, (start, end)) in extents.into_iter().enumerate() {
+            let mut page = start & !(page_size - 1);
+            while page <= end {
+                if let Some(phys) = translator.physical(page) {
+                    pages.push((phys & !(page_size as u64 - 1), area));
+                }
+                page = match page.checked_add(page_size) {
+                    Some(page) => page,
+                    None => break,
+                };
+            }
+        }
+        pages.sort();
+        let translated = pages.len();
+        self.phys_pages = Some((page_size, pages));
+        Ok(translated)
+    }
+
+    /// Report a memory error detected in scrubbed memory. The error is
+    /// attributed to the scrub area containing the address, counted in the
+    /// statistics, and passed on to the event sinks.
+    ///
+    /// # Arguments:
+    /// * `addr` - Physical address at which the error was detected, as
+    ///   reported by the memory controller. It is matched to a scrub area
+    ///   through translate_areas(), if it has been called, otherwise the
+    ///   scrub areas must be physically addressed.
+    ///
+    /// * `severity` - Whether the error was corrected
+    pub fn record_error(&mut self, addr: u64, severity: ErrorSeverity) {
+        self.record_errors(addr, severity, 1);
+    }
+
+    // Report a number of memory errors at the same address, as for
+    // record_error(). They are all counted in the statistics but passed on
+    // to the event sinks as a single event.
+    fn record_errors(&mut self, addr: u64, severity: ErrorSeverity,
+        count: u32) {
+        let area = phys_area(self.scrubber.scrub_areas(), &self.phys_pages,
+            addr);
+
+        if let Some(area) = area {
+            self.stats.record_errors(area,
+                severity == ErrorSeverity::Corrected, count,
+                self.clock.now());
+        }
+
+        self.emit(&ScrubEvent::Error(ErrorEvent {
+            addr,
+            area,
+            severity,
+        }));
+    }
+
+    /// Returns the statistics collected while scrubbing
+    pub fn stats(&self) -> &ScrubStats {
+        &self.stats
+    }
+
+    /// Give a scrub area a name to be used when reporting on it
+    ///
+    /// # Arguments:
+    /// * `area` - Index of the scrub area
+    ///
+    /// * `label` - Name for the area
+    pub fn set_area_label(&mut self, area: usize, label: &str) {
+        if let Some(area) = self.stats.areas.get_mut(area) {
+            area.label = Some(label.to_string());
+        }
+    }
+
+    /// Seed the statistics with errors recorded before the scrubber started,
+    /// such as those imported from rasdaemon, so that areas with a history
+    /// of errors are given priority
+    pub fn seed_error_history(&mut self, history: &ErrorHistory) {
+        history.seed(&mut self.stats);
+    }
+
+    /// Report the health score of each scrub area to the event sinks at the
+    /// end of every pass
+    ///
+    /// # Arguments:
+    /// * `weights` - Weighting of the things that lower the score
+    pub fn set_health_weights(&mut self, weights: HealthWeights) {
+        self.health_weights = Some(weights);
+    }
+
+    /// Record the cache partition the scrub thread is confined to, such as
+    /// that returned by Resctrl::apply(), so it is reported in the status
+    pub fn set_cache_partition(&mut self, partition: CachePartition) {
+        self.cache_partition = Some(partition);
+    }
+
+    /// Return a snapshot of the current state of the scrubber
+    pub fn status(&self) -> ScrubStatus {
+        let extents = area_extents(self.scrubber.scrub_areas());
+
+        let mut status = ScrubStatus::new(
+            &self.stats,
+            &extents,
+            ScrubConfig::new::<N, W, S, D, A>(),
+            self.clock.now(),
+        );
+        status.cache_partition = self.cache_partition.clone();
+        status
+    }
+
+    /// Return a snapshot of the current state of the scrubber as JSON, for
+    /// use by health endpoints and log shipping
+    #[cfg(feature = "serde")]
+    pub fn status_json(&self) -> String {
+        self.status().to_json()
+    }
+
+    /// Create an AutoScrub and run it until the AutoScrubDesc returns zero
+    ///
+    /// # Returns:
+    /// Ok(ScrubSummary) describing the scrubbing done, otherwise Err(Error)
     pub fn autoscrub(cache: &'a mut dyn CacheBase<N, W, S, D, A>,
         scrub_areas: &'a [MemArea<A>],
-        desc: &'a mut dyn AutoScrubDesc<N, W, S, D, A>) -> Result<(), Error> {
+        desc: &'a mut dyn AutoScrubDesc<N, W, S, D, A>) ->
+        Result<ScrubSummary, Error> {
         let mut autoscrub = Self::new(cache, scrub_areas, desc)?;
-        autoscrub.scrub()
+        autoscrub.run()
     }
 }
 
 /// This is the basic memory scrubber.
 ///
+/// It is deprecated in favor of LineScrubber, in backend.rs, which walks
+/// the scrub areas in the same order but leaves the reading of each cache
+/// line to a ScrubBackend, so that the same logic can run against
+/// SimMemory under Miri, and which carries the policies, exclusions and
+/// coverage tracking this one lacks. It is kept for AutoScrub, which is
+/// built on it, and for existing users of CacheBase.
+///
 /// # Attributes
 ///
 /// * `my_cache` - Cache description
 ///
 /// * 'my_scrub_areas` - List of MemAreas to be scrubbed
+///
+/// * `cursor` - Position at which the next scrub starts
+#[deprecated(
+    note = "use LineScrubber, which reads through any ScrubBackend, \
+            including SimMemory under Miri"
+)]
 pub struct MemoryScrubber<
     'a,
     const N: usize,
@@ -125,9 +832,11 @@ pub struct MemoryScrubber<
 {
     cache: &'a dyn CacheBase<N, W, S, D, A>,
     scrub_areas: &'a [MemArea<A>],
+    cursor: Cell<ScrubCursor>,
     _marker1: PhantomData<D>,
 }
 
+#[allow(deprecated)]
 impl<'a, const N: usize, const W: usize, const S: usize, D, A, I>
     MemoryScrubber<'a, N, W, S, D, A, I>
 where
@@ -137,14 +846,105 @@ where
     usize: From<A>,
 {
     fn new(cache: &'a dyn CacheBase<N, W, S, D, A>, scrub_areas: &'a [MemArea<A>]) -> Result<MemoryScrubber::<'a, N, W, S, D, A, I>, Error> {
+        // Reject areas whose sizes would overflow when scrubbing
+        check_extents(&area_extents(scrub_areas), S)?;
+
         Ok(MemoryScrubber::<'a, N, W, S, D, A, I> {
             cache: cache,
             scrub_areas: scrub_areas,
+            cursor: Cell::new(ScrubCursor::default()),
             _marker1: PhantomData,
         })
     }
+
+    /// Continue from a position in the pass, such as one saved in a
+    /// checkpoint. Lines before the position are not read until the next
+    /// pass.
+    ///
+    /// # Arguments:
+    /// * `cursor` - Scrub area and offset within it at which to start
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::UnalignedSize) if the
+    /// offset is not a multiple of the cache line size or
+    /// Err(Error::AddressOverflow) if it is beyond the end of the area
+    pub fn resume_at(&mut self, cursor: ScrubCursor) -> Result<(), Error> {
+        if cursor.offset % S != 0 {
+            return Err(Error::UnalignedSize);
+        }
+        let extents = area_extents(self.scrub_areas);
+        match extents.get(cursor.area) {
+            Some(&(start, end)) if cursor.offset <= end - start => {
+                self.cursor.set(cursor);
+                Ok(())
+            },
+            _ => Err(Error::AddressOverflow),
+        }
+    }
+
+    /// Compare this scrubber's cache description with the caches of the
+    /// machine it runs on. A cache line size or cache index width that
+    /// doesn't match the hardware still scrubs every line, but without the
+    /// cache-friendly ordering, so it is reported as a warning rather than
+    /// an error.
+    ///
+    /// # Returns:
+    /// A CacheCheck. agrees() is false if any disagreement was found.
+    pub fn check_hardware(&self) -> CacheCheck {
+        check_cache_hardware(S, self.cache.cache_index_width())
+    }
+}
+
+// The self-test scrubs its buffer with a MemoryScrubber of its own, which
+// lives only as long as the buffer, so the iterator must work for any
+// lifetime
+#[allow(deprecated)]
+impl<'a, const N: usize, const W: usize, const S: usize, D, A, I>
+    MemoryScrubber<'a, N, W, S, D, A, I>
+where
+    D: DataImplTrait<D>,
+    A: AddrImplTrait<A>,
+    I: for<'b> ScrubAreasIteratorBase<'b, N, W, S, D, A>,
+    usize: From<A>,
+{
+    /// Scrub a small buffer, allocated for the purpose, with a
+    /// MemoryScrubber using this scrubber's cache description and check
+    /// that each cache line was read exactly once and in cache index order.
+    /// The reads are made by the cache description's own read_cacheline(),
+    /// wrapped so that each is checked first. Run this at startup to catch
+    /// a misconfigured cache description, such as a wrong cache index
+    /// width, before it leaves memory unscrubbed.
+    ///
+    /// # Returns:
+    /// A SelfTestReport. passed() is false if any problem was found.
+    pub fn self_test(&self) -> SelfTestReport {
+        let mut report = SelfTestReport {
+            line_size: S,
+            index_width: self.cache.cache_index_width(),
+            ..Default::default()
+        };
+        if let Err(e) = self.cache.check_cache_params() {
+            report.problems.push(format!("invalid cache parameters: {}", e));
+            return report;
+        }
+        let buffer = match SelfTestBuffer::new(&mut report) {
+            Some(buffer) => buffer,
+            None => return report,
+        };
+
+        let cache = CheckingCache::new(self.cache, buffer.read_check(&report));
+        let areas = [MemArea::new(buffer.start.into(), buffer.end.into())];
+        let result = MemoryScrubber::<'_, N, W, S, D, A, I>::new(&cache, &areas)
+            .and_then(|scrubber| scrubber.scrub(report.buffer_size.into()));
+        if let Err(e) = result {
+            report.problems.push(format!("scrub failed: {}", e));
+        }
+        cache.report(&mut report);
+        report
+    }
 }
 
+#[allow(deprecated)]
 impl<'a, const N: usize, const W: usize, const S: usize, D, A, I>
     MemoryScrubberBase<'a, N, W, S, D, A, I> for MemoryScrubber<'a, N, W, S, D, A, I>
 where
@@ -171,6 +971,14 @@ where
         self.scrub_areas
     }
 
+    fn cursor(&self) -> ScrubCursor {
+        self.cursor.get()
+    }
+
+    fn set_cursor(&self, cursor: ScrubCursor) {
+        self.cursor.set(cursor);
+    }
+
     fn cacheline_width(&self) -> usize {
         S / std::mem::size_of::<D>()
     }
@@ -373,11 +1181,7 @@ impl<'a, const N: usize, const W: usize, const S: usize, D, A>
     ScrubAreasIterator<'a, N, W, S, D, A>
 where
     D: DataImplTrait<D>,
-    A: AddrImplTrait<A>,
-    /*
-        *mut D: From<A>,
-        usize: From<A>,
-    */
+    A: AddrImplTrait<A> + ToPrimitive,
 {
     pub fn new(
         cache: &'a Cache<N, W, S, D, A>,
@@ -399,13 +1203,7 @@ impl<'a, const N: usize, const W: usize, const S: usize, D, A>
     iter::Iterator for ScrubAreasIterator<'a, N, W, S, D, A>
 where
     D: DataImplTrait<D>,
-    A: AddrImplTrait<A>,
-    /*
-        *mut D: From<A>,
-        usize: From<A>,
-    // FIXME: Combine with the above?
-        usize: Add<A>,
-    */
+    A: AddrImplTrait<A> + ToPrimitive,
 {
     type Item = Addr<A>;
 
@@ -432,15 +1230,16 @@ where
     }
 }
 
-// This goes through all cache indices. The current cache index is kept at a
-// higher level to ensure continuity. That is, the previous cache index is
-// passed down to us and incremented here so that we don't restart the indices
-// each time this is called.
+// This goes through all cache indices, returning every line with cache
+// index 0 in each scrub area, then every line with cache index 1, and so
+// on, in the same order as ScrubOrder. It is a single cursor over (cache
+// index, scrub area), a ScrubOrder, rather than an iterator per scrub area
+// nested in an iterator per cache index. The first line and number of
+// lines of each area are computed once, so an area with no line at a
+// cache index, as most are when there are many small areas, is passed over
+// with a little arithmetic.
 //
-// cache:  Cache descriptor
-// scrub_areas: List of memory areas to be scrubbed
-// iterator:    An iterator for a scrubbing a single memory area
-// cur_index:   The index of the cache line we are scrubbing
+// order:       The cursor over the lines of the scrub areas
 
 pub struct CacheIndexIterator<
     'a,
@@ -457,40 +1256,40 @@ pub struct CacheIndexIterator<
         usize: From<A>,
     */
 {
-    cache: &'a Cache<N, W, S, D, A>,
-    scrub_areas: &'a [MemArea<A>],
-    iterator: MemAreasIterator<'a, N, W, S, D, A>,
-    cur_index: usize,
-    // FIXME: needed?
-    _marker1: PhantomData<D>,
+    order: ScrubOrder,
+    _marker1: PhantomData<&'a Cache<N, W, S, D, A>>,
 }
 
 impl<'a, const N: usize, const W: usize, const S: usize, D, A>
     CacheIndexIterator<'a, N, W, S, D, A>
 where
     D: DataImplTrait<D>,
-    A: AddrImplTrait<A>,
-    /*
-        *mut D: From<A>,
-        usize: From<A>,
-    */
+    A: AddrImplTrait<A> + ToPrimitive,
 {
     pub fn new(
         cache: &'a Cache<N, W, S, D, A>,
         scrub_areas: &'a [MemArea<A>],
     ) -> Result<CacheIndexIterator<'a, N, W, S, D, A>, Error> {
-        let cur_index = 0;
-        let iterator = MemAreasIterator::<N, W, S, D, A>::new(
-            cache,
-            scrub_areas,
-            cur_index,
-        )?;
+        // The end of a scrub area may be anywhere in its last cache line
+        let cacheline_width = Cacheline::<S, D>::cacheline_width();
+        let cacheline_size = 1usize << cacheline_width;
+        let extents = scrub_areas
+            .iter()
+            .map(|a| {
+                let start = a.start().0.to_usize();
+                match (start, a.end().0.to_usize()) {
+                    (Some(start), Some(end)) => {
+                        Ok((start, end | (cacheline_size - 1)))
+                    }
+                    _ => Err(Error::AddressOverflow),
+                }
+            })
+            .collect::<Result<Vec<(usize, usize)>, Error>>()?;
+        let order = ScrubOrder::new(&extents, cacheline_size,
+            cache.cache_index_width())?;
 
         Ok(CacheIndexIterator {
-            cache: cache,
-            scrub_areas: scrub_areas,
-            iterator: iterator,
-            cur_index: cur_index,
+            order,
             _marker1: PhantomData,
         })
     }
@@ -501,46 +1300,11 @@ impl<'a, const N: usize, const W: usize, const S: usize, D, A>
 where
     D: DataImplTrait<D>,
     A: AddrImplTrait<A>,
-    /*
-        *mut D: From<A>,
-        usize: From<A>,
-    // FIXME: Combine with the above?
-        usize: Add<A>,
-    */
 {
     type Item = Addr<A>;
 
     fn next(&mut self) -> Option<Self::Item> {
-        //println!("CacheIndexIterator::next(): entered");
-        let cache_index_width = self.cache.cache_index_width();
-        //println!("CacheIndexIterator::next(): after calling cache_index_width");
-        let cache_lines = 1 << cache_index_width;
-
-        loop {
-            //println!("CacheIndexIterator: next()");
-            let next = self.iterator.next();
-
-            if let Some(p) = next {
-                return Some(p);
-            }
-
-            self.cur_index += 1;
-
-            if self.cur_index == cache_lines {
-                return None;
-            }
-
-            self.iterator = match MemAreasIterator::<N, W, S, D, A>::new(
-                self.cache,
-                self.scrub_areas,
-                self.cur_index,
-            ) {
-                Err(e) => {
-                    panic!("MemAreasIterator::new failed: {}", e)
-                }
-                Ok(iterator) => iterator,
-            };
-        }
+        self.order.next().map(|p| Addr::<A>(p.into()))
     }
 }
 
@@ -587,10 +1351,9 @@ where
         scrub_areas: &'a [MemArea<A>],
         cur_index: usize,
     ) -> Result<MemAreasIterator<'a, N, W, S, D, A>, Error> {
-        // FIXME: restore this
-        //        if scrubber.scrub_areas().len() == 0 {
-        //            return Err(Error::NoMemAreas);
-        //        }
+        if scrub_areas.is_empty() {
+            return Err(Error::NoMemAreas);
+        }
 
         let iterator = MemAreaIterator::<N, W, S, D, A>::new(
             cache,
@@ -2246,7 +3009,7 @@ mod tests {
                     Err(e) => panic!("AutoScrub::new failed: {}", e),
                     Ok(autoscrub) => autoscrub,
                 };
-                match autoscrub.scrub() {
+                match autoscrub.run() {
                     Err(e) => panic!("autoscrub() failed: {}", e),
                     Ok(_) => {},
                 };
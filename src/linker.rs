@@ -0,0 +1,117 @@
+// Scrub areas for the RAM sections of a firmware image. The linker script
+// marks the start and end of each section with a symbol, such as
+// __data_start__ and __data_end__, and linker_section! turns such a pair
+// into the extent of a scrub area, so that each section is put under
+// scrubbing with one line:
+//
+//      let extents = [
+//          linker_section!(__data_start__, __data_end__, 64)?,
+//          linker_section!(__bss_start__, __bss_end__, 64)?,
+//      ];
+//
+// Sections need not start or end on cache line boundaries, so the extent
+// is widened to whole cache lines. The bytes added belong to the same
+// cache lines as the section and so are in RAM too.
+
+use crate::base::*;
+
+/// Returns the extent of a scrub area covering a section, widened to whole
+/// cache lines
+///
+/// # Arguments:
+/// * `start` - Address of the first byte of the section
+///
+/// * `end` - Address just past the last byte of the section, as given by
+///   the usual end symbols
+///
+/// * `line_size` - Number of bytes in a cache line, a power of two
+///
+/// # Returns:
+/// Ok((start, end)) with the end inclusive, otherwise
+/// Err(Error::UnalignedValue) if the line size isn't a power of two,
+/// Err(Error::EmptyMemArea) if the section is empty or
+/// Err(Error::AddressOverflow) if it reaches the top of the address space
+pub fn section_extent(
+    start: usize,
+    end: usize,
+    line_size: usize,
+) -> Result<(usize, usize), Error> {
+    if !line_size.is_power_of_two() {
+        return Err(Error::UnalignedValue);
+    }
+    if end <= start {
+        return Err(Error::EmptyMemArea);
+    }
+    let mask = line_size - 1;
+    let end = end.checked_add(mask).ok_or(Error::AddressOverflow)? & !mask;
+    Ok((start & !mask, end - 1))
+}
+
+/// The extent of a scrub area covering the section between two linker
+/// symbols, as given by section_extent(). The symbols are declared by the
+/// macro, so they need only be defined by the linker script.
+///
+/// # Arguments:
+/// * `start` - Symbol at the first byte of the section
+///
+/// * `end` - Symbol just past the last byte of the section
+///
+/// * `line_size` - Number of bytes in a cache line
+#[macro_export]
+macro_rules! linker_section {
+    ($start:ident, $end:ident, $line_size:expr) => {{
+        extern "C" {
+            static $start: u8;
+            static $end: u8;
+        }
+        // Only the addresses of the symbols are taken, never their values
+        #[allow(unused_unsafe)]
+        let (start, end) = unsafe {
+            (
+                ::core::ptr::addr_of!($start) as usize,
+                ::core::ptr::addr_of!($end) as usize,
+            )
+        };
+        $crate::section_extent(start, end, $line_size)
+    }};
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[no_mangle]
+    static MEMSCRUB_TEST_SECTION: u8 = 0;
+    #[no_mangle]
+    static MEMSCRUB_TEST_SECTION_END: u8 = 0;
+
+    #[test]
+    fn test_section_extent() {
+        assert_eq!(section_extent(4096, 8192, 64), Ok((4096, 8191)));
+        assert_eq!(section_extent(4100, 8190, 64), Ok((4096, 8191)));
+        assert_eq!(section_extent(4100, 4101, 64), Ok((4096, 4159)));
+        assert_eq!(
+            section_extent(4096, 4096, 64),
+            Err(Error::EmptyMemArea)
+        );
+        assert_eq!(section_extent(0, 64, 48), Err(Error::UnalignedValue));
+        assert_eq!(
+            section_extent(0, usize::MAX, 64),
+            Err(Error::AddressOverflow)
+        );
+    }
+
+    #[test]
+    fn test_macro() {
+        let start = &MEMSCRUB_TEST_SECTION as *const u8 as usize;
+        let end = &MEMSCRUB_TEST_SECTION_END as *const u8 as usize;
+        assert_eq!(
+            linker_section!(
+                MEMSCRUB_TEST_SECTION,
+                MEMSCRUB_TEST_SECTION_END,
+                64
+            ),
+            section_extent(start, end, 64)
+        );
+    }
+}
@@ -0,0 +1,212 @@
+// A cache description for unit tests of code that embeds the scrubber.
+// MockCacheDesc gives the cache geometry to the scrubber and, used as the
+// ScrubBackend, records every cache line address it is asked to read
+// instead of reading memory. The recorded reads can then be checked against
+// the scrub areas, so an integration can be tested against areas of any
+// size without allocating them. Used as the CacheBase of a MemoryScrubber,
+// it records each cache line the scrubber reads in the same way.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+
+use crate::addr::*;
+use crate::backend::*;
+use crate::base::*;
+use crate::data::*;
+
+/// How the recorded reads cover a set of scrub areas
+///
+/// * `lines` - Number of cache lines in the areas
+///
+/// * `min_reads` - Fewest reads of any line in the areas
+///
+/// * `max_reads` - Most reads of any line in the areas
+///
+/// * `missing` - Lines in the areas that were never read
+///
+/// * `outside` - Addresses read that are not in any area
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Coverage {
+    pub lines: usize,
+    pub min_reads: u32,
+    pub max_reads: u32,
+    pub missing: Vec<usize>,
+    pub outside: Vec<usize>,
+}
+
+/// A cache description that records reads instead of making them
+///
+/// * `line_size` - Number of bytes in a cache line
+///
+/// * `index_width` - Number of address bits in the cache index
+///
+/// * `reads` - Addresses read, in order
+#[derive(Clone, Debug)]
+pub struct MockCacheDesc {
+    line_size: usize,
+    index_width: usize,
+    reads: RefCell<Vec<usize>>,
+}
+
+impl MockCacheDesc {
+    /// Create a MockCacheDesc that has recorded no reads
+    ///
+    /// # Arguments:
+    /// * `line_size` - Number of bytes in a cache line
+    ///
+    /// * `index_width` - Number of address bits in the cache index
+    pub fn new(line_size: usize, index_width: usize) -> MockCacheDesc {
+        MockCacheDesc {
+            line_size,
+            index_width,
+            reads: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Returns the addresses read, in order
+    pub fn reads(&self) -> Vec<usize> {
+        self.reads.borrow().clone()
+    }
+
+    /// Forget the reads recorded so far
+    pub fn clear(&mut self) {
+        self.reads.get_mut().clear();
+    }
+
+    /// Compare the reads with the scrub areas
+    ///
+    /// # Arguments:
+    /// * `extents` - (start, end) address of each scrub area, end inclusive
+    pub fn coverage(&self, extents: &[(usize, usize)]) -> Coverage {
+        let mut counts: HashMap<usize, u32> = HashMap::new();
+        let mut coverage = Coverage::default();
+
+        for &addr in self.reads.borrow().iter() {
+            match extents.iter().any(|&(s, e)| addr >= s && addr <= e) {
+                true => {
+                    let line = addr - addr % self.line_size;
+                    *counts.entry(line).or_insert(0) += 1;
+                }
+                false => coverage.outside.push(addr),
+            }
+        }
+
+        coverage.min_reads = u32::MAX;
+        for &(start, end) in extents {
+            let mut line = start;
+            while line <= end {
+                let reads = counts.get(&line).copied().unwrap_or(0);
+                if reads == 0 {
+                    coverage.missing.push(line);
+                }
+                coverage.lines += 1;
+                coverage.min_reads = coverage.min_reads.min(reads);
+                coverage.max_reads = coverage.max_reads.max(reads);
+                line = match line.checked_add(self.line_size) {
+                    Some(line) => line,
+                    None => break,
+                };
+            }
+        }
+        if coverage.lines == 0 {
+            coverage.min_reads = 0;
+        }
+        coverage
+    }
+
+    /// Panic unless every line in the scrub areas was read exactly the
+    /// given number of times, and nothing else was read
+    ///
+    /// # Arguments:
+    /// * `extents` - (start, end) address of each scrub area, end inclusive
+    ///
+    /// * `passes` - Number of times each line should have been read
+    pub fn assert_coverage(
+        &self,
+        extents: &[(usize, usize)],
+        passes: u32,
+    ) {
+        let coverage = self.coverage(extents);
+        assert!(
+            coverage.outside.is_empty(),
+            "{} reads outside the scrub areas, first at {:#x}",
+            coverage.outside.len(),
+            coverage.outside[0]
+        );
+        assert!(
+            coverage.min_reads == passes && coverage.max_reads == passes,
+            "expected each of {} lines to be read {} times, but lines \
+             were read from {} to {} times ({} never read)",
+            coverage.lines,
+            passes,
+            coverage.min_reads,
+            coverage.max_reads,
+            coverage.missing.len()
+        );
+    }
+}
+
+impl ScrubBackend for MockCacheDesc {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        self.reads.get_mut().push(addr);
+        Ok(())
+    }
+
+    // The line is recorded once however many reads are made of it
+    fn read_words(
+        &mut self,
+        addr: usize,
+        _line_size: usize,
+        _reads: usize,
+    ) -> Result<(), Error> {
+        self.read_line(addr)
+    }
+}
+
+impl<const N: usize, const W: usize, const S: usize, D, A>
+    CacheBase<N, W, S, D, A> for MockCacheDesc
+where
+    D: DataImplTrait<D>,
+    A: AddrImplTrait<A>,
+    usize: From<A>,
+{
+    fn cache_index_width(&self) -> usize {
+        self.index_width
+    }
+
+    // The address is recorded, never dereferenced, so that the areas
+    // needn't be backed by memory
+    fn read_cacheline(&self, p: Addr<A>) {
+        self.reads.borrow_mut().push(usize::from(p.0));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_coverage() {
+        let extents = [(0, 4095), (65536, 69631)];
+        let mut scrubber =
+            LineScrubber::new(MockCacheDesc::new(64, 4), &extents, 64, 4)
+                .unwrap();
+        scrubber.scrub(8192).unwrap();
+        scrubber.backend().assert_coverage(&extents, 1);
+
+        scrubber.scrub(4096).unwrap();
+        let coverage = scrubber.backend().coverage(&extents);
+        assert_eq!(coverage.lines, 128);
+        assert_eq!((coverage.min_reads, coverage.max_reads), (1, 2));
+        assert!(coverage.missing.is_empty());
+    }
+
+    #[test]
+    #[should_panic(expected = "outside the scrub areas")]
+    fn test_outside() {
+        let mut mock = MockCacheDesc::new(64, 4);
+        mock.read_line(0).unwrap();
+        mock.read_line(4096).unwrap();
+        mock.assert_coverage(&[(0, 63)], 1);
+    }
+}
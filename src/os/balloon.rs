@@ -0,0 +1,376 @@
+// Awareness of memory a guest has given back to its hypervisor. In a VM,
+// pages inflated into the virtio balloon, and free pages reported to the
+// host through free page reporting, may have been discarded by the host.
+// Reading them makes the host fault them back in, wasting host memory and
+// undoing the balloon, and finds nothing worth scrubbing. Such pages are
+// skipped until they are returned to the guest.
+//
+// The guest kernel marks ballooned pages as offline in /proc/kpageflags,
+// which holds a 64-bit word of flags for each page frame. Free pages are
+// marked as buddy pages; they are only skipped when free page reporting
+// is in use, since otherwise the host still backs them. Reading
+// /proc/kpageflags requires root.
+//
+// Pages move in and out of the balloon while scrubbing, so BalloonBackend
+// keeps the ranges it skips from one refresh() to the next and should be
+// refreshed periodically, such as at the start of each pass.
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+
+use crate::backend::*;
+use crate::base::*;
+use crate::coverage::*;
+
+const KPAGEFLAGS_PATH: &str = "/proc/kpageflags";
+const BALLOON_DRIVER_PATH: &str = "/sys/bus/virtio/drivers/virtio_balloon";
+
+/// Size of the pages described by /proc/kpageflags on most systems
+pub const BALLOON_PAGE_SIZE: usize = 4096;
+
+// Page flags from /proc/kpageflags
+const KPF_BUDDY: u64 = 1 << 10;
+const KPF_OFFLINE: u64 = 1 << 23;
+
+// Number of page frames read from /proc/kpageflags at a time
+const SCAN_BATCH: usize = 4096;
+
+/// Finds the pages a guest has given back to its hypervisor
+///
+/// * `kpageflags` - File holding the flags of each page frame
+///
+/// * `driver` - Directory of the virtio balloon driver in sysfs
+///
+/// * `page_size` - Number of bytes in a page
+///
+/// * `free_page_reporting` - Whether free pages are also treated as given
+///   back to the hypervisor
+#[derive(Clone, Debug)]
+pub struct BalloonMonitor {
+    kpageflags: PathBuf,
+    driver: PathBuf,
+    page_size: usize,
+    free_page_reporting: bool,
+}
+
+impl BalloonMonitor {
+    /// Use /proc/kpageflags and the balloon driver in sysfs
+    pub fn new() -> BalloonMonitor {
+        BalloonMonitor::with_paths(
+            KPAGEFLAGS_PATH,
+            BALLOON_DRIVER_PATH,
+            BALLOON_PAGE_SIZE,
+        )
+    }
+
+    /// Use the given files, such as copies saved from another system
+    ///
+    /// # Arguments:
+    /// * `kpageflags` - File in the format of /proc/kpageflags
+    ///
+    /// * `driver` - Directory of the virtio balloon driver
+    ///
+    /// * `page_size` - Number of bytes in a page
+    pub fn with_paths<P: AsRef<Path>, Q: AsRef<Path>>(
+        kpageflags: P,
+        driver: Q,
+        page_size: usize,
+    ) -> BalloonMonitor {
+        BalloonMonitor {
+            kpageflags: kpageflags.as_ref().to_path_buf(),
+            driver: driver.as_ref().to_path_buf(),
+            page_size,
+            free_page_reporting: false,
+        }
+    }
+
+    /// Returns whether a virtio balloon device is bound to the driver
+    pub fn present(&self) -> bool {
+        self.driver.read_dir().is_ok_and(|mut entries| {
+            entries.any(|e| {
+                e.is_ok_and(|e| {
+                    e.file_name().to_string_lossy().starts_with("virtio")
+                })
+            })
+        })
+    }
+
+    /// Also skip free pages, for guests using free page reporting or free
+    /// page hinting, whose free pages the host may have discarded
+    pub fn set_free_page_reporting(&mut self, enable: bool) {
+        self.free_page_reporting = enable;
+    }
+
+    /// Returns the number of bytes in a page
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Find the pages in a range of physical addresses that have been
+    /// given back to the hypervisor
+    ///
+    /// # Arguments:
+    /// * `start` - First physical address in the range
+    ///
+    /// * `end` - Last physical address in the range
+    ///
+    /// # Returns:
+    /// Ok(ranges) with the (start, end) physical address of each run of
+    /// such pages, end inclusive, otherwise Err(io::Error) if the page
+    /// flags could not be read
+    pub fn scan(
+        &self,
+        start: usize,
+        end: usize,
+    ) -> io::Result<Vec<(usize, usize)>> {
+        let mask = match self.free_page_reporting {
+            true => KPF_OFFLINE | KPF_BUDDY,
+            false => KPF_OFFLINE,
+        };
+        let first = start / self.page_size;
+        let last = end / self.page_size;
+        let mut file = File::open(&self.kpageflags)?;
+        file.seek(SeekFrom::Start(first as u64 * 8))?;
+
+        let mut ranges: Vec<(usize, usize)> = Vec::new();
+        let mut buf = vec![0u8; SCAN_BATCH * 8];
+        let mut pfn = first;
+        while pfn <= last {
+            let count = (last - pfn + 1).min(SCAN_BATCH);
+            let read = read_full(&mut file, &mut buf[..count * 8])? / 8;
+            if read == 0 {
+                break;
+            }
+            for (i, word) in buf[..read * 8].chunks_exact(8).enumerate() {
+                let flags = u64::from_ne_bytes(word.try_into().unwrap());
+                if flags & mask == 0 {
+                    continue;
+                }
+                let page = (pfn + i) * self.page_size;
+                let page_end = page + self.page_size - 1;
+                match ranges.last_mut() {
+                    Some(range) if range.1 + 1 == page => {
+                        range.1 = page_end
+                    }
+                    _ => ranges.push((page, page_end)),
+                }
+            }
+            pfn += read;
+        }
+
+        // Clip to the range asked for
+        for range in ranges.iter_mut() {
+            *range = (range.0.max(start), range.1.min(end));
+        }
+        Ok(ranges)
+    }
+}
+
+impl Default for BalloonMonitor {
+    fn default() -> Self {
+        BalloonMonitor::new()
+    }
+}
+
+// Read until the buffer is full or the end of the file is reached
+fn read_full(file: &mut File, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match file.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(n) => total += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(total)
+}
+
+/// A backend that skips pages given back to the hypervisor. Addresses
+/// given to the backend must be physical addresses.
+///
+/// * `backend` - Backend making the reads
+///
+/// * `monitor` - Finds the pages to skip
+///
+/// * `extents` - (start, end) of each range scanned for pages to skip,
+///   end inclusive
+///
+/// * `ranges` - (start, end) of each range skipped, sorted, end inclusive
+pub struct BalloonBackend<B: ScrubBackend> {
+    backend: B,
+    monitor: BalloonMonitor,
+    extents: Vec<(usize, usize)>,
+    ranges: Vec<(usize, usize)>,
+}
+
+impl<B: ScrubBackend> BalloonBackend<B> {
+    /// Create a BalloonBackend. Nothing is skipped until refresh() is
+    /// called.
+    ///
+    /// # Arguments:
+    /// * `backend` - Backend making the reads
+    ///
+    /// * `monitor` - Finds the pages to skip
+    ///
+    /// * `extents` - (start, end) of each scrub area, end inclusive
+    pub fn new(
+        backend: B,
+        monitor: BalloonMonitor,
+        extents: &[(usize, usize)],
+    ) -> BalloonBackend<B> {
+        BalloonBackend {
+            backend,
+            monitor,
+            extents: extents.to_vec(),
+            ranges: Vec::new(),
+        }
+    }
+
+    /// Find the pages given back to the hypervisor again. Pages returned
+    /// to the guest since the last refresh are scrubbed once more.
+    ///
+    /// # Returns:
+    /// Ok(bytes) with the number of bytes now skipped, otherwise
+    /// Err(io::Error) if the page flags could not be read, in which case
+    /// the ranges skipped are unchanged
+    pub fn refresh(&mut self) -> io::Result<usize> {
+        let mut ranges = Vec::new();
+        for &(start, end) in &self.extents {
+            ranges.extend(self.monitor.scan(start, end)?);
+        }
+        ranges.sort();
+        self.ranges = ranges;
+        Ok(self.ranges.iter().map(|&(s, e)| e - s + 1).sum())
+    }
+
+    /// Returns the ranges skipped, sorted by address
+    pub fn ranges(&self) -> &[(usize, usize)] {
+        &self.ranges
+    }
+
+    /// Returns whether an address is skipped
+    pub fn is_ballooned(&self, addr: usize) -> bool {
+        let i = self.ranges.partition_point(|&(_, e)| e < addr);
+        self.ranges.get(i).is_some_and(|&(s, _)| s <= addr)
+    }
+
+    /// Returns the monitor finding the pages to skip
+    pub fn monitor(&self) -> &BalloonMonitor {
+        &self.monitor
+    }
+
+    /// Returns the backend making the reads
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+}
+
+impl<B: ScrubBackend> ScrubBackend for BalloonBackend<B> {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        match self.is_ballooned(addr) {
+            true => Ok(()),
+            false => self.backend.read_line(addr),
+        }
+    }
+
+    fn read_words(
+        &mut self,
+        addr: usize,
+        line_size: usize,
+        reads: usize,
+    ) -> Result<(), Error> {
+        match self.is_ballooned(addr) {
+            true => Ok(()),
+            false => self.backend.read_words(addr, line_size, reads),
+        }
+    }
+
+    fn begin_batch(&mut self) -> Result<(), Error> {
+        self.backend.begin_batch()
+    }
+
+    fn end_batch(&mut self) -> Result<(), Error> {
+        self.backend.end_batch()
+    }
+
+    fn end_window(&mut self) -> Result<(), Error> {
+        self.backend.end_window()
+    }
+
+    fn window_size(&self) -> Option<usize> {
+        self.backend.window_size()
+    }
+
+    fn flush_line(&mut self, addr: usize) -> Result<(), Error> {
+        match self.is_ballooned(addr) {
+            true => Ok(()),
+            false => self.backend.flush_line(addr),
+        }
+    }
+
+    fn skip_reason(&mut self, addr: usize) -> Option<SkipReason> {
+        match self.is_ballooned(addr) {
+            true => Some(SkipReason::Ballooned),
+            false => self.backend.skip_reason(addr),
+        }
+    }
+
+    fn excluded_lines(&self, start: usize, end: usize) -> Option<u64> {
+        self.backend.excluded_lines(start, end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+    use std::fs;
+
+    fn write_flags(path: &Path, flags: &[u64]) {
+        let bytes: Vec<u8> =
+            flags.iter().flat_map(|f| f.to_ne_bytes()).collect();
+        fs::write(path, bytes).unwrap();
+    }
+
+    #[test]
+    fn test_balloon() {
+        let dir = scratch_path("balloon");
+        let driver = dir.join("virtio_balloon");
+        fs::create_dir_all(&driver).unwrap();
+        let kpageflags = dir.join("kpageflags");
+
+        // Pages 1 and 2 ballooned, page 4 free
+        write_flags(
+            &kpageflags,
+            &[0, KPF_OFFLINE, KPF_OFFLINE, 0, KPF_BUDDY, 0],
+        );
+        let mut monitor =
+            BalloonMonitor::with_paths(&kpageflags, &driver, 64);
+        assert!(!monitor.present());
+        fs::create_dir(driver.join("virtio3")).unwrap();
+        assert!(monitor.present());
+
+        assert_eq!(monitor.scan(0, 383).unwrap(), [(64, 191)]);
+        assert_eq!(monitor.scan(100, 150).unwrap(), [(100, 150)]);
+        monitor.set_free_page_reporting(true);
+        assert_eq!(monitor.scan(0, 383).unwrap(), [(64, 191), (256, 319)]);
+
+        let mut backend =
+            BalloonBackend::new(Recorder::default(), monitor, &[(0, 383)]);
+        assert_eq!(backend.refresh().unwrap(), 192);
+        for addr in (0..384).step_by(64) {
+            backend.read_line(addr).unwrap();
+        }
+        assert_eq!(backend.backend().reads(), [0, 192, 320]);
+        assert_eq!(backend.skip_reason(64), Some(SkipReason::Ballooned));
+        assert_eq!(backend.skip_reason(0), None);
+
+        // Pages returned to the guest are scrubbed again
+        write_flags(&kpageflags, &[0; 6]);
+        assert_eq!(backend.refresh().unwrap(), 0);
+        assert!(!backend.is_ballooned(64));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
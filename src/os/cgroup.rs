@@ -0,0 +1,352 @@
+// Memory limits of the cgroup the scrubber runs in. A scrubber in a
+// container or a systemd slice with a memory limit is charged for the
+// pages it maps and touches, and mapping a large window of /dev/mem or of
+// a file can push the cgroup over its limit and wake the OOM killer.
+// CgroupMemory reads the limit and current usage of the scrubber's cgroup
+// so the mapped windows can be kept to a share of what is left.
+//
+// Both cgroup v2, with memory.max and memory.current in the cgroup's own
+// directory, and v1, with memory.limit_in_bytes and memory.usage_in_bytes
+// under the memory controller's hierarchy, are supported. v1 has no
+// "max"; an unlimited cgroup has a limit close to the largest i64, so
+// limits that large are taken as none.
+//
+// A MapBudget is shared by everything that maps memory for scrubbing, the
+// UncachedMappings and WindowedBackends, so that together they stay within
+// one budget. Its limit can follow the headroom of the cgroup, taken again
+// each time the budget is refreshed, as WindowedBackend does at the start
+// of each batch, so that the scrubber maps less as the rest of the cgroup
+// uses more.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::sync::{lock, Arc, Mutex};
+
+const PROC_CGROUP_PATH: &str = "/proc/self/cgroup";
+const CGROUP_ROOT: &str = "/sys/fs/cgroup";
+
+// Limits at least this large mean no limit in cgroup v1
+const V1_UNLIMITED: u64 = 1 << 62;
+
+/// The memory limit and usage of a cgroup
+///
+/// * `dir` - Directory holding the memory files of the cgroup
+#[derive(Clone, Debug)]
+pub struct CgroupMemory {
+    dir: PathBuf,
+}
+
+impl CgroupMemory {
+    /// Use the cgroup of this process, from /proc/self/cgroup
+    pub fn current() -> io::Result<CgroupMemory> {
+        let text = fs::read_to_string(PROC_CGROUP_PATH)?;
+        let dir = cgroup_dir(&text, Path::new(CGROUP_ROOT)).ok_or_else(
+            || io::Error::new(io::ErrorKind::NotFound, "no memory cgroup"),
+        )?;
+        Ok(CgroupMemory::with_dir(dir))
+    }
+
+    /// Use the memory files, of either cgroup version, in a directory
+    pub fn with_dir<P: AsRef<Path>>(dir: P) -> CgroupMemory {
+        CgroupMemory {
+            dir: dir.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Returns the memory limit of the cgroup in bytes, or None if it has
+    /// none
+    pub fn limit(&self) -> io::Result<Option<u64>> {
+        let text = self.read("memory.max", "memory.limit_in_bytes")?;
+        if text == "max" {
+            return Ok(None);
+        }
+        let limit = parse(&text)?;
+        Ok((limit < V1_UNLIMITED).then_some(limit))
+    }
+
+    /// Returns the memory used by the cgroup in bytes
+    pub fn usage(&self) -> io::Result<u64> {
+        parse(&self.read("memory.current", "memory.usage_in_bytes")?)
+    }
+
+    /// Returns the bytes the cgroup may use before reaching its limit, or
+    /// None if it has no limit
+    pub fn headroom(&self) -> io::Result<Option<u64>> {
+        Ok(match self.limit()? {
+            Some(limit) => Some(limit.saturating_sub(self.usage()?)),
+            None => None,
+        })
+    }
+
+    /// Returns the most memory to map at once: a fraction of the headroom
+    /// of the cgroup, no more than a maximum
+    ///
+    /// # Arguments:
+    /// * `fraction` - Fraction of the headroom, from 0.0 to 1.0
+    ///
+    /// * `max` - Most bytes to map, and the budget if there is no limit
+    pub fn window_budget(
+        &self,
+        fraction: f64,
+        max: usize,
+    ) -> io::Result<usize> {
+        Ok(match self.headroom()? {
+            Some(headroom) => {
+                let share = headroom as f64 * fraction.clamp(0.0, 1.0);
+                (share as usize).min(max)
+            }
+            None => max,
+        })
+    }
+
+    // Read the first of two memory files that exists, the one for cgroup
+    // v2 or the one for v1
+    fn read(&self, v2: &str, v1: &str) -> io::Result<String> {
+        let text = match fs::read_to_string(self.dir.join(v2)) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                fs::read_to_string(self.dir.join(v1))?
+            }
+            result => result?,
+        };
+        Ok(text.trim().to_string())
+    }
+}
+
+// BudgetState: The limit of a MapBudget and what is mapped under it
+// limit: Most bytes to map at once
+// mapped: Bytes mapped now
+// cgroup: Cgroup whose headroom the limit follows, with the fraction of it
+//  to take and the most bytes, if it follows one
+#[derive(Debug)]
+struct BudgetState {
+    limit: usize,
+    mapped: usize,
+    cgroup: Option<(CgroupMemory, f64, usize)>,
+}
+
+/// A budget of the bytes the mappings made for scrubbing may take at once,
+/// shared by every UncachedMapping and WindowedBackend given it. Clones
+/// share the budget.
+///
+/// * `state` - The limit and the bytes mapped
+#[derive(Clone, Debug)]
+pub struct MapBudget {
+    state: Arc<Mutex<BudgetState>>,
+}
+
+impl MapBudget {
+    /// Create a budget with a fixed limit
+    ///
+    /// # Arguments:
+    /// * `limit` - Most bytes to map at once
+    pub fn new(limit: usize) -> MapBudget {
+        MapBudget {
+            state: Arc::new(Mutex::new(BudgetState {
+                limit,
+                mapped: 0,
+                cgroup: None,
+            })),
+        }
+    }
+
+    /// Create a budget whose limit follows the headroom of a cgroup
+    ///
+    /// # Arguments:
+    /// * `cgroup` - The cgroup
+    ///
+    /// * `fraction` - Fraction of the headroom to map, from 0.0 to 1.0
+    ///
+    /// * `max` - Most bytes to map, and the limit if there is no limit on
+    ///   the cgroup
+    ///
+    /// # Returns:
+    /// Ok(MapBudget) on success, otherwise Err(io::Error) if the memory
+    /// files of the cgroup can't be read
+    pub fn from_cgroup(
+        cgroup: CgroupMemory,
+        fraction: f64,
+        max: usize,
+    ) -> io::Result<MapBudget> {
+        let limit = cgroup.window_budget(fraction, max)?;
+        Ok(MapBudget {
+            state: Arc::new(Mutex::new(BudgetState {
+                limit,
+                mapped: 0,
+                cgroup: Some((cgroup, fraction, max)),
+            })),
+        })
+    }
+
+    /// Take the limit again from the headroom of the cgroup, if it follows
+    /// one. What is mapped under the budget counts as used by the cgroup,
+    /// so the limit is what is mapped and the share of the headroom left,
+    /// no more than the maximum.
+    ///
+    /// # Returns:
+    /// Ok(limit) with the limit, otherwise Err(io::Error) if the memory
+    /// files of the cgroup can't be read
+    pub fn refresh(&self) -> io::Result<usize> {
+        let mut state = lock(&self.state);
+        if let Some((cgroup, fraction, max)) = &state.cgroup {
+            let share = cgroup.window_budget(*fraction, *max)?;
+            state.limit = state.mapped.saturating_add(share).min(*max);
+        }
+        Ok(state.limit)
+    }
+
+    /// Returns the most bytes to map at once
+    pub fn limit(&self) -> usize {
+        lock(&self.state).limit
+    }
+
+    /// Returns the bytes mapped under the budget now
+    pub fn mapped(&self) -> usize {
+        lock(&self.state).mapped
+    }
+
+    // Count bytes about to be mapped, if they fit in the budget. Returns
+    // whether they did.
+    pub(crate) fn try_reserve(&self, bytes: usize) -> bool {
+        let mut state = lock(&self.state);
+        match state.mapped.checked_add(bytes) {
+            Some(mapped) if mapped <= state.limit => {
+                state.mapped = mapped;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Count bytes about to be mapped, even over the budget
+    pub(crate) fn reserve(&self, bytes: usize) {
+        let mut state = lock(&self.state);
+        state.mapped = state.mapped.saturating_add(bytes);
+    }
+
+    // Count bytes unmapped
+    pub(crate) fn release(&self, bytes: usize) {
+        let mut state = lock(&self.state);
+        state.mapped = state.mapped.saturating_sub(bytes);
+    }
+}
+
+/// Returns the directory of the memory cgroup listed in text in the
+/// format of /proc/self/cgroup, preferring the memory controller of
+/// cgroup v1 to the unified v2 hierarchy
+///
+/// # Arguments:
+/// * `text` - Contents of a /proc/<pid>/cgroup file
+///
+/// * `root` - Where cgroups are mounted, usually /sys/fs/cgroup
+pub fn cgroup_dir(text: &str, root: &Path) -> Option<PathBuf> {
+    let mut unified = None;
+    for line in text.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (Some(_), Some(controllers), Some(path)) =
+            (fields.next(), fields.next(), fields.next())
+        else {
+            continue;
+        };
+        let path = path.trim_start_matches('/');
+        if controllers.split(',').any(|c| c == "memory") {
+            return Some(root.join("memory").join(path));
+        }
+        if controllers.is_empty() {
+            unified = Some(root.join(path));
+        }
+    }
+    unified
+}
+
+// Parse a number of bytes
+fn parse(text: &str) -> io::Result<u64> {
+    text.parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, text.to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn test_cgroup_dir() {
+        let root = Path::new("/sys/fs/cgroup");
+        assert_eq!(
+            cgroup_dir("0::/system.slice/scrub.service\n", root),
+            Some(root.join("system.slice/scrub.service"))
+        );
+        let v1 = "12:cpu,cpuacct:/a\n7:memory:/docker/abc\n0::/\n";
+        assert_eq!(
+            cgroup_dir(v1, root),
+            Some(root.join("memory/docker/abc"))
+        );
+        assert_eq!(cgroup_dir("garbage\n", root), None);
+    }
+
+    #[test]
+    fn test_limits() {
+        let dir = scratch_path("cgroup");
+        fs::create_dir_all(&dir).unwrap();
+        let cgroup = CgroupMemory::with_dir(&dir);
+
+        // cgroup v2
+        fs::write(dir.join("memory.max"), "1073741824\n").unwrap();
+        fs::write(dir.join("memory.current"), "805306368\n").unwrap();
+        assert_eq!(cgroup.limit().unwrap(), Some(1 << 30));
+        assert_eq!(cgroup.headroom().unwrap(), Some(256 << 20));
+        assert_eq!(cgroup.window_budget(0.25, 1 << 30).unwrap(), 64 << 20);
+        assert_eq!(cgroup.window_budget(1.0, 16 << 20).unwrap(), 16 << 20);
+        fs::write(dir.join("memory.max"), "max\n").unwrap();
+        assert_eq!(cgroup.headroom().unwrap(), None);
+        assert_eq!(cgroup.window_budget(0.25, 1 << 20).unwrap(), 1 << 20);
+
+        // cgroup v1, unlimited
+        fs::remove_file(dir.join("memory.max")).unwrap();
+        fs::remove_file(dir.join("memory.current")).unwrap();
+        fs::write(
+            dir.join("memory.limit_in_bytes"),
+            "9223372036854771712\n",
+        )
+        .unwrap();
+        fs::write(dir.join("memory.usage_in_bytes"), "4096\n").unwrap();
+        assert_eq!(cgroup.limit().unwrap(), None);
+        assert_eq!(cgroup.usage().unwrap(), 4096);
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(cgroup.limit().is_err());
+    }
+
+    #[test]
+    fn test_map_budget() {
+        let dir = scratch_path("cgroup-budget");
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("memory.max"), "1073741824\n").unwrap();
+        fs::write(dir.join("memory.current"), "805306368\n").unwrap();
+        let cgroup = CgroupMemory::with_dir(&dir);
+        let budget =
+            MapBudget::from_cgroup(cgroup, 0.25, 1 << 30).unwrap();
+        assert_eq!(budget.limit(), 64 << 20);
+
+        // Clones share the bytes mapped
+        let shared = budget.clone();
+        assert!(budget.try_reserve(48 << 20));
+        assert!(!shared.try_reserve(32 << 20));
+        assert!(shared.try_reserve(16 << 20));
+        shared.release(32 << 20);
+        assert_eq!(budget.mapped(), 32 << 20);
+
+        // The limit follows the headroom, counting what is mapped as used
+        fs::write(dir.join("memory.current"), "939524096\n").unwrap();
+        assert_eq!(budget.refresh().unwrap(), (32 << 20) + (32 << 20));
+        fs::write(dir.join("memory.current"), "1073741824\n").unwrap();
+        assert_eq!(budget.refresh().unwrap(), 32 << 20);
+        assert_eq!(MapBudget::new(4096).refresh().unwrap(), 4096);
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(budget.refresh().is_err());
+    }
+}
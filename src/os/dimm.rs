@@ -0,0 +1,492 @@
+// Attribution of physical addresses to memory modules. Operators replace
+// DIMMs, not scrub areas, so statistics and errors are more useful when
+// they are reported by module. A DimmMap holds the physical address range
+// of each module, either from a simple configuration file or from the
+// SMBIOS tables as printed by dmidecode, and counts the errors it sees by
+// module.
+//
+// The configuration file has one module per line:
+//
+//  <start> <end> <label> [channel=<name>] [rank=<n>]
+//
+// where the addresses are inclusive and may be in hex with a leading 0x.
+// Blank lines and lines starting with # are ignored.
+//
+// SMBIOS only describes the address range of each module when memory is
+// not interleaved across modules, and not all firmware provides it, so
+// the configuration file is the fallback. The raw tables can also be read
+// directly, without dmidecode, with Smbios.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+use crate::addr::*;
+use crate::event::*;
+use crate::stats::*;
+
+/// A memory module and the physical addresses it holds
+///
+/// * `start` - Lowest physical address in the module
+///
+/// * `end` - Highest physical address in the module
+///
+/// * `label` - Name of the module, usually its slot on the board
+///
+/// * `channel` - Name of the memory channel or bank, if known
+///
+/// * `rank` - Rank within the module, if known
+#[derive(Clone, Debug, PartialEq)]
+pub struct DimmRange {
+    pub start: u64,
+    pub end: u64,
+    pub label: String,
+    pub channel: Option<String>,
+    pub rank: Option<u32>,
+}
+
+/// Statistics for one memory module
+///
+/// * `label` - Name of the module
+///
+/// * `channel` - Name of the memory channel or bank, if known
+///
+/// * `bytes` - Number of bytes of the module inside scrub areas
+///
+/// * `last_scrubbed` - Time at which the module was last completely
+///   scrubbed, or None if part of it has not yet been scrubbed
+///
+/// * `errors_corrected` - Number of corrected errors in the module
+///
+/// * `errors_uncorrected` - Number of uncorrected errors in the module
+#[derive(Clone, Debug, PartialEq)]
+pub struct DimmStats {
+    pub label: String,
+    pub channel: Option<String>,
+    pub bytes: u64,
+    pub last_scrubbed: Option<Instant>,
+    pub errors_corrected: u64,
+    pub errors_uncorrected: u64,
+}
+
+/// Mapping from physical addresses to memory modules
+///
+/// * `dimms` - The modules, in address order
+///
+/// * `errors` - (corrected, uncorrected) error counts for each module
+///
+/// * `unattributed` - Number of errors at addresses in no module
+#[derive(Clone, Debug, Default)]
+pub struct DimmMap {
+    dimms: Vec<DimmRange>,
+    errors: Vec<(u64, u64)>,
+    unattributed: u64,
+}
+
+// Error for a bad line in the configuration file
+fn invalid(line: usize, what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: {}", line, what),
+    )
+}
+
+impl DimmMap {
+    /// Create a map of the given modules
+    ///
+    /// # Returns:
+    /// Ok(DimmMap), or Err(io::Error) if a range is empty or two ranges
+    /// overlap
+    pub fn new(mut dimms: Vec<DimmRange>) -> io::Result<DimmMap> {
+        dimms.sort_by_key(|d| d.start);
+        if let Some(d) = dimms.iter().find(|d| d.start > d.end) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} ends before it starts", d.label),
+            ));
+        }
+        if let Some(w) = dimms.windows(2).find(|w| w[1].start <= w[0].end)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} overlaps {}", w[0].label, w[1].label),
+            ));
+        }
+
+        Ok(DimmMap {
+            errors: vec![(0, 0); dimms.len()],
+            dimms,
+            unattributed: 0,
+        })
+    }
+
+    /// Parse the configuration file format described above
+    pub fn parse(text: &str) -> io::Result<DimmMap> {
+        let mut dimms = Vec::new();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+
+            let mut words = line.split_whitespace();
+            let mut addr =
+                || words.next().and_then(|w| parse_number(w, 10));
+            let (start, end) = match (addr(), addr()) {
+                (Some(start), Some(end)) => (start, end),
+                _ => return Err(invalid(n + 1, "bad address range")),
+            };
+            let label = words
+                .next()
+                .ok_or_else(|| invalid(n + 1, "missing label"))?;
+
+            let mut dimm = DimmRange {
+                start,
+                end,
+                label: label.to_string(),
+                channel: None,
+                rank: None,
+            };
+            for word in words {
+                match word.split_once('=') {
+                    Some(("channel", c)) => dimm.channel = Some(c.into()),
+                    Some(("rank", r)) => {
+                        dimm.rank = Some(
+                            r.parse()
+                                .map_err(|_| invalid(n + 1, "bad rank"))?,
+                        )
+                    }
+                    _ => return Err(invalid(n + 1, "unknown attribute")),
+                }
+            }
+            dimms.push(dimm);
+        }
+        DimmMap::new(dimms)
+    }
+
+    /// Read a configuration file
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<DimmMap> {
+        DimmMap::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Build the map from the output of `dmidecode -t 17,20`. Each memory
+    /// device mapped address (type 20) gives an address range and refers
+    /// to the memory device (type 17) that names the module.
+    pub fn from_dmidecode(text: &str) -> io::Result<DimmMap> {
+        // Handle and fields of each structure
+        let mut devices: HashMap<String, HashMap<&str, &str>> =
+            HashMap::new();
+        let mut mapped = Vec::new();
+
+        for block in text.split("\n\n") {
+            let mut lines = block.lines().map(str::trim);
+            let header = match lines.find(|l| l.starts_with("Handle ")) {
+                Some(header) => header,
+                None => continue,
+            };
+            let handle = header["Handle ".len()..]
+                .split(',')
+                .next()
+                .unwrap_or("")
+                .to_string();
+            let fields: HashMap<&str, &str> = lines
+                .filter_map(|l| l.split_once(':'))
+                .map(|(k, v)| (k.trim(), v.trim()))
+                .collect();
+
+            if header.contains("DMI type 17,") {
+                devices.insert(handle, fields);
+            } else if header.contains("DMI type 20,") {
+                mapped.push(fields);
+            }
+        }
+
+        let mut dimms = Vec::new();
+        for fields in mapped {
+            let range = fields
+                .get("Starting Address")
+                .and_then(|s| parse_number(s, 10))
+                .zip(
+                    fields
+                        .get("Ending Address")
+                        .and_then(|s| parse_number(s, 10)),
+                );
+            let device = fields
+                .get("Physical Device Handle")
+                .and_then(|h| devices.get(*h));
+            if let (Some((start, end)), Some(device)) = (range, device) {
+                dimms.push(DimmRange {
+                    start,
+                    end,
+                    label: device
+                        .get("Locator")
+                        .unwrap_or(&"")
+                        .to_string(),
+                    channel: device
+                        .get("Bank Locator")
+                        .map(|b| b.to_string()),
+                    rank: None,
+                });
+            }
+        }
+        if dimms.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no memory device mapped addresses in dmidecode output",
+            ));
+        }
+        DimmMap::new(dimms)
+    }
+
+    /// Returns the modules, in address order
+    pub fn dimms(&self) -> &[DimmRange] {
+        &self.dimms
+    }
+
+    /// Returns the module holding a physical address
+    pub fn lookup(&self, addr: u64) -> Option<&DimmRange> {
+        self.position(addr).map(|i| &self.dimms[i])
+    }
+
+    fn position(&self, addr: u64) -> Option<usize> {
+        let i = self.dimms.partition_point(|d| d.end < addr);
+        match self.dimms.get(i) {
+            Some(d) if d.start <= addr => Some(i),
+            _ => None,
+        }
+    }
+
+    /// Returns the number of errors at addresses in no module
+    pub fn unattributed(&self) -> u64 {
+        self.unattributed
+    }
+
+    /// The fields of an event, as given by ScrubEvent::fields(), with the
+    /// module, channel and rank added for errors
+    pub fn fields(
+        &self,
+        event: &ScrubEvent,
+    ) -> Vec<(&'static str, String)> {
+        let mut fields = event.fields();
+        if let ScrubEvent::Error(e) = event {
+            if let Some(dimm) = self.lookup(e.addr) {
+                fields.push(("dimm", dimm.label.clone()));
+                if let Some(channel) = &dimm.channel {
+                    fields.push(("channel", channel.clone()));
+                }
+                if let Some(rank) = dimm.rank {
+                    fields.push(("rank", rank.to_string()));
+                }
+            }
+        }
+        fields
+    }
+
+    /// Label the scrub areas that have no label with the modules they
+    /// overlap. An area spanning several modules is labelled with all of
+    /// them, joined by +.
+    ///
+    /// # Arguments:
+    /// * `extents` - (start, end) physical address of each scrub area,
+    ///   end inclusive, in the same order as the areas in `stats`
+    ///
+    /// * `stats` - Statistics for the scrub areas, whose labels are set
+    ///
+    /// # Returns:
+    /// The number of areas labelled
+    pub fn label_areas(
+        &self,
+        extents: &[(u64, u64)],
+        stats: &mut ScrubStats,
+    ) -> usize {
+        let mut labelled = 0;
+        for (&(start, end), area) in extents.iter().zip(&mut stats.areas) {
+            if area.label.is_some() {
+                continue;
+            }
+            let labels: Vec<&str> = self
+                .dimms
+                .iter()
+                .filter(|d| d.start <= end && start <= d.end)
+                .map(|d| d.label.as_str())
+                .collect();
+            if !labels.is_empty() {
+                area.label = Some(labels.join("+"));
+                labelled += 1;
+            }
+        }
+        labelled
+    }
+
+    /// Statistics for each module
+    ///
+    /// # Arguments:
+    /// * `extents` - (start, end) physical address of each scrub area,
+    ///   end inclusive, in the same order as the areas in `stats`
+    ///
+    /// * `stats` - Statistics for the scrub areas
+    ///
+    /// # Returns:
+    /// Statistics for each module, in address order. A module is only as
+    /// recently scrubbed as the least recently scrubbed area it overlaps.
+    pub fn stats(
+        &self,
+        extents: &[(u64, u64)],
+        stats: &ScrubStats,
+    ) -> Vec<DimmStats> {
+        self.dimms
+            .iter()
+            .zip(&self.errors)
+            .map(|(dimm, &(corrected, uncorrected))| {
+                let mut bytes = 0;
+                let mut last_scrubbed = None;
+                let mut scrubbed = true;
+                for (&(start, end), area) in
+                    extents.iter().zip(&stats.areas)
+                {
+                    let (lo, hi) =
+                        (start.max(dimm.start), end.min(dimm.end));
+                    if lo > hi {
+                        continue;
+                    }
+                    bytes += hi - lo + 1;
+                    match area.last_scrubbed {
+                        Some(t) => {
+                            last_scrubbed = Some(
+                                last_scrubbed
+                                    .map_or(t, |l: Instant| l.min(t)),
+                            )
+                        }
+                        None => scrubbed = false,
+                    }
+                }
+
+                DimmStats {
+                    label: dimm.label.clone(),
+                    channel: dimm.channel.clone(),
+                    bytes,
+                    last_scrubbed: last_scrubbed.filter(|_| scrubbed),
+                    errors_corrected: corrected,
+                    errors_uncorrected: uncorrected,
+                }
+            })
+            .collect()
+    }
+}
+
+impl EventSink for DimmMap {
+    // Count errors by the module holding the address
+    fn event(&mut self, event: &ScrubEvent) {
+        if let ScrubEvent::Error(e) = event {
+            match self.position(e.addr) {
+                Some(i) => match e.severity {
+                    ErrorSeverity::Corrected => self.errors[i].0 += 1,
+                    ErrorSeverity::Uncorrected => self.errors[i].1 += 1,
+                },
+                None => self.unattributed += 1,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = "# Two modules on one channel\n\
+                          0x0 0x3fffffff DIMM_A1 channel=A rank=0\n\
+                          \n\
+                          0x40000000 0x7fffffff DIMM_A2 channel=A\n";
+
+    const DMIDECODE: &str = "\
+Handle 0x0040, DMI type 17, 40 bytes
+Memory Device
+\tSize: 1 GB
+\tLocator: DIMM_B1
+\tBank Locator: BANK 1
+
+Handle 0x0041, DMI type 17, 40 bytes
+Memory Device
+\tSize: 1 GB
+\tLocator: DIMM_B2
+\tBank Locator: BANK 1
+
+Handle 0x0050, DMI type 20, 35 bytes
+Memory Device Mapped Address
+\tStarting Address: 0x00040000000
+\tEnding Address: 0x0007FFFFFFF
+\tRange Size: 1 GB
+\tPhysical Device Handle: 0x0041
+
+Handle 0x0051, DMI type 20, 35 bytes
+Memory Device Mapped Address
+\tStarting Address: 0x00000000000
+\tEnding Address: 0x0003FFFFFFF
+\tRange Size: 1 GB
+\tPhysical Device Handle: 0x0040
+";
+
+    fn error(addr: u64, severity: ErrorSeverity) -> ScrubEvent {
+        ScrubEvent::Error(ErrorEvent {
+            addr,
+            area: None,
+            severity,
+        })
+    }
+
+    #[test]
+    fn test_parse() {
+        let map = DimmMap::parse(CONFIG).unwrap();
+        assert_eq!(map.dimms().len(), 2);
+        assert_eq!(map.lookup(0x1000).unwrap().label, "DIMM_A1");
+        assert_eq!(map.lookup(0x1000).unwrap().rank, Some(0));
+        assert_eq!(map.lookup(0x7fffffff).unwrap().label, "DIMM_A2");
+        assert_eq!(map.lookup(0x80000000), None);
+
+        assert!(DimmMap::parse("0 100 A\n50 200 B\n").is_err());
+        assert!(DimmMap::parse("0 100\n").is_err());
+        assert!(DimmMap::parse("0 100 A slot=3\n").is_err());
+
+        let map = DimmMap::from_dmidecode(DMIDECODE).unwrap();
+        let labels: Vec<&str> =
+            map.dimms().iter().map(|d| d.label.as_str()).collect();
+        assert_eq!(labels, ["DIMM_B1", "DIMM_B2"]);
+        assert_eq!(map.dimms()[1].start, 0x40000000);
+        assert_eq!(map.dimms()[1].channel.as_deref(), Some("BANK 1"));
+        assert!(DimmMap::from_dmidecode("").is_err());
+    }
+
+    #[test]
+    fn test_stats() {
+        let mut map = DimmMap::parse(CONFIG).unwrap();
+        map.event(&error(0x1000, ErrorSeverity::Corrected));
+        map.event(&error(0x50000000, ErrorSeverity::Uncorrected));
+        map.event(&error(0x90000000, ErrorSeverity::Corrected));
+        assert_eq!(map.unattributed(), 1);
+
+        let fields = map.fields(&error(0x1000, ErrorSeverity::Corrected));
+        assert!(fields.contains(&("dimm", "DIMM_A1".to_string())));
+        assert!(fields.contains(&("channel", "A".to_string())));
+
+        // One area in the first module, one spanning both
+        let now = Instant::now();
+        let extents = [(0x0, 0xfffff), (0x3ff00000, 0x400fffff)];
+        let mut stats = ScrubStats::new(&[0x100000, 0x200000], now);
+        stats.areas[0].last_scrubbed = Some(now);
+
+        let dimms = map.stats(&extents, &stats);
+        assert_eq!(dimms[0].bytes, 0x200000);
+        assert_eq!(dimms[1].bytes, 0x100000);
+        assert_eq!(dimms[0].last_scrubbed, None);
+        assert_eq!(dimms[0].errors_corrected, 1);
+        assert_eq!(dimms[1].errors_uncorrected, 1);
+
+        stats.areas[1].last_scrubbed = Some(now);
+        assert_eq!(
+            map.stats(&extents, &stats)[0].last_scrubbed,
+            Some(now)
+        );
+    }
+}
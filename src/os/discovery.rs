@@ -0,0 +1,436 @@
+// Discovery of the physical memory to scrub. Each operating system
+// describes its RAM differently, so each has its own MemoryDiscovery, and
+// scrub areas are built from whichever the system provides:
+//
+//  Linux: the top-level System RAM ranges of /proc/iomem
+//
+//  FreeBSD: the physical segments given by the vm.phys_segs sysctl
+//
+//  illumos: the reg property of the memory node in the PROM device tree,
+//  as printed by prtconf -pv
+//
+// Reading the ranges usually requires root, as does reading the memory
+// they describe through /dev/mem.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::addr::*;
+
+const IOMEM_PATH: &str = "/proc/iomem";
+
+/// Finds the physical memory that can be scrubbed
+pub trait MemoryDiscovery {
+    /// Returns the (start, end) physical address of each range of RAM,
+    /// end inclusive, in address order
+    fn ram(&mut self) -> io::Result<Vec<(u64, u64)>>;
+}
+
+/// Find the RAM and turn it into scrub areas, shrinking each range to
+/// whole cache lines and leaving out those too small to hold one
+///
+/// # Arguments:
+/// * `discovery` - Finds the RAM
+///
+/// * `line_size` - Number of bytes in a cache line
+///
+/// # Returns:
+/// Ok(extents) with the (start, end) address of each scrub area, end
+/// inclusive, otherwise Err(io::Error) if the RAM could not be found
+pub fn discover_areas(
+    discovery: &mut dyn MemoryDiscovery,
+    line_size: usize,
+) -> io::Result<Vec<(usize, usize)>> {
+    let line = line_size as u64;
+    Ok(discovery
+        .ram()?
+        .into_iter()
+        .filter_map(|(start, end)| {
+            let start = start.checked_next_multiple_of(line)?;
+            let end = (end + 1) / line * line;
+            match start < end {
+                true => Some((start as usize, (end - 1) as usize)),
+                false => None,
+            }
+        })
+        .collect())
+}
+
+/// Returns the MemoryDiscovery for the system being run on
+pub fn system_discovery() -> io::Result<Box<dyn MemoryDiscovery>> {
+    #[cfg(target_os = "linux")]
+    return Ok(Box::new(Iomem::new()));
+    #[cfg(target_os = "freebsd")]
+    return Ok(Box::new(PhysSegs::new()));
+    #[cfg(target_os = "illumos")]
+    return Ok(Box::new(PromMemory::new()));
+    #[allow(unreachable_code)]
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "memory discovery not supported on this system",
+    ))
+}
+
+// Error for text that could not be parsed
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+/// RAM as listed in the Linux /proc/iomem
+///
+/// * `path` - File in the format of /proc/iomem
+pub struct Iomem {
+    path: PathBuf,
+}
+
+impl Iomem {
+    /// Use /proc/iomem
+    pub fn new() -> Iomem {
+        Iomem::with_path(IOMEM_PATH)
+    }
+
+    /// Use a file in the format of /proc/iomem
+    pub fn with_path<P: AsRef<Path>>(path: P) -> Iomem {
+        Iomem {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Returns the top-level System RAM ranges in text in the format of
+    /// /proc/iomem. Nested entries, such as the kernel image, are parts of
+    /// the range they are listed under.
+    pub fn parse(text: &str) -> io::Result<Vec<(u64, u64)>> {
+        let mut ranges = Vec::new();
+        for line in text.lines() {
+            if line.starts_with(' ') {
+                continue;
+            }
+            let Some((range, name)) = line.split_once(" : ") else {
+                continue;
+            };
+            if name.trim() != "System RAM" {
+                continue;
+            }
+            let (start, end) = range
+                .split_once('-')
+                .and_then(|(s, e)| {
+                    Some((parse_number(s, 16)?, parse_number(e, 16)?))
+                })
+                .ok_or_else(|| invalid("bad range in iomem"))?;
+            ranges.push((start, end));
+        }
+        Ok(ranges)
+    }
+}
+
+impl Default for Iomem {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryDiscovery for Iomem {
+    fn ram(&mut self) -> io::Result<Vec<(u64, u64)>> {
+        let ranges = Iomem::parse(&fs::read_to_string(&self.path)?)?;
+        // Without privileges, the kernel shows every address as zero
+        match ranges.iter().all(|&(s, e)| s == 0 && e == 0) {
+            true => Err(io::Error::from(io::ErrorKind::PermissionDenied)),
+            false => Ok(ranges),
+        }
+    }
+}
+
+/// RAM as given by the FreeBSD vm.phys_segs sysctl
+///
+/// * `text` - Output of the sysctl to use instead of reading it, if any
+pub struct PhysSegs {
+    text: Option<String>,
+}
+
+impl PhysSegs {
+    /// Read the vm.phys_segs sysctl
+    #[cfg(target_os = "freebsd")]
+    pub fn new() -> PhysSegs {
+        PhysSegs { text: None }
+    }
+
+    /// Use text in the format of the vm.phys_segs sysctl, such as output
+    /// saved from another system
+    pub fn from_text(text: &str) -> PhysSegs {
+        PhysSegs {
+            text: Some(text.to_string()),
+        }
+    }
+
+    /// Returns the segments in text in the format of vm.phys_segs. Each
+    /// segment has start and end lines, where the end is exclusive.
+    pub fn parse(text: &str) -> io::Result<Vec<(u64, u64)>> {
+        let mut ranges = Vec::new();
+        let mut start = None;
+        for line in text.lines() {
+            let Some((key, value)) = line.split_once(':') else {
+                continue;
+            };
+            match key.trim() {
+                "start" => start = parse_number(value, 16),
+                "end" => {
+                    let end = parse_number::<u64>(value, 16)
+                        .filter(|&end| end != 0)
+                        .zip(start.take())
+                        .ok_or_else(|| {
+                            invalid("bad segment in phys_segs")
+                        })?;
+                    ranges.push((end.1, end.0 - 1));
+                }
+                _ => {}
+            }
+        }
+        ranges.sort();
+        Ok(ranges)
+    }
+}
+
+#[cfg(target_os = "freebsd")]
+impl Default for PhysSegs {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Read a string sysctl
+#[cfg(target_os = "freebsd")]
+fn sysctl_string(name: &str) -> io::Result<String> {
+    use std::ffi::CString;
+    use std::ptr;
+
+    let name = CString::new(name)?;
+    let mut len = 0;
+    // SAFETY: a null buffer asks only for the length, written to len
+    let rc = unsafe {
+        libc::sysctlbyname(
+            name.as_ptr(),
+            ptr::null_mut(),
+            &mut len,
+            ptr::null(),
+            0,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let mut buf = vec![0u8; len];
+    // SAFETY: buf holds len bytes
+    let rc = unsafe {
+        libc::sysctlbyname(
+            name.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+            ptr::null(),
+            0,
+        )
+    };
+    if rc != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    buf.truncate(len);
+    Ok(String::from_utf8_lossy(&buf)
+        .trim_end_matches('\0')
+        .to_string())
+}
+
+impl MemoryDiscovery for PhysSegs {
+    fn ram(&mut self) -> io::Result<Vec<(u64, u64)>> {
+        match &self.text {
+            Some(text) => PhysSegs::parse(text),
+            #[cfg(target_os = "freebsd")]
+            None => PhysSegs::parse(&sysctl_string("vm.phys_segs")?),
+            #[cfg(not(target_os = "freebsd"))]
+            None => Err(io::Error::from(io::ErrorKind::Unsupported)),
+        }
+    }
+}
+
+/// RAM as given by the memory node of the illumos PROM device tree
+///
+/// * `text` - Output of prtconf -pv to use instead of running it, if any
+pub struct PromMemory {
+    text: Option<String>,
+}
+
+impl PromMemory {
+    /// Run prtconf -pv to read the device tree
+    #[cfg(target_os = "illumos")]
+    pub fn new() -> PromMemory {
+        PromMemory { text: None }
+    }
+
+    /// Use text in the format of prtconf -pv, such as output saved from
+    /// another system
+    pub fn from_text(text: &str) -> PromMemory {
+        PromMemory {
+            text: Some(text.to_string()),
+        }
+    }
+
+    /// Returns the ranges in the reg property of the memory node in text
+    /// in the format of prtconf -pv. The property is a list of 32-bit
+    /// cells, with the 64-bit address and size of each range taking two
+    /// cells each.
+    pub fn parse(text: &str) -> io::Result<Vec<(u64, u64)>> {
+        // The properties of a node may be in any order, so the reg
+        // property is kept until the end of the node
+        let mut reg = None;
+        let mut memory = false;
+        for line in text.lines().map(str::trim).chain(["Node"]) {
+            if line.starts_with("Node") {
+                if let (true, Some(reg)) = (memory, reg) {
+                    return PromMemory::parse_reg(reg);
+                }
+                (reg, memory) = (None, false);
+            } else if let Some(value) = line.strip_prefix("reg:") {
+                reg = Some(value);
+            } else if line == "name:  'memory'" {
+                memory = true;
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            "no memory node in device tree",
+        ))
+    }
+
+    // Parse the cells of a reg property
+    fn parse_reg(reg: &str) -> io::Result<Vec<(u64, u64)>> {
+        let cells: Vec<u64> = reg
+            .trim()
+            .split('.')
+            .map(|c| parse_number(c, 16))
+            .collect::<Option<Vec<u64>>>()
+            .filter(|cells| cells.len().is_multiple_of(4))
+            .ok_or_else(|| invalid("bad reg property"))?;
+        let mut ranges: Vec<(u64, u64)> = cells
+            .chunks_exact(4)
+            .filter(|c| c[2] != 0 || c[3] != 0)
+            .map(|c| {
+                let start = c[0] << 32 | c[1];
+                (start, start + (c[2] << 32 | c[3]) - 1)
+            })
+            .collect();
+        ranges.sort();
+        Ok(ranges)
+    }
+}
+
+#[cfg(target_os = "illumos")]
+impl Default for PromMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl MemoryDiscovery for PromMemory {
+    fn ram(&mut self) -> io::Result<Vec<(u64, u64)>> {
+        match &self.text {
+            Some(text) => PromMemory::parse(text),
+            #[cfg(target_os = "illumos")]
+            None => {
+                let output =
+                    std::process::Command::new("/usr/sbin/prtconf")
+                        .arg("-pv")
+                        .output()?;
+                PromMemory::parse(&String::from_utf8_lossy(&output.stdout))
+            }
+            #[cfg(not(target_os = "illumos"))]
+            None => Err(io::Error::from(io::ErrorKind::Unsupported)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    const IOMEM: &str = "\
+00000000-00000fff : Reserved
+00001000-0009fbff : System RAM
+000a0000-000bffff : PCI Bus 0000:00
+00100000-bffdffff : System RAM
+  01000000-01e00000 : Kernel code
+100000000-13fffffff : System RAM
+";
+
+    const PHYS_SEGS: &str = "
+SEGMENT 0:
+
+start:     0x10000
+end:       0x9d000
+domain:    0
+free list: 0xffffffff81a8fb40
+
+SEGMENT 1:
+
+start:     0x100000
+end:       0x200000
+domain:    0
+free list: 0xffffffff81a8fb40
+";
+
+    const PRTCONF: &str = "
+System Configuration:  Oxide  i86pc
+Node 0x000001
+    name:  'i86pc'
+    Node 0x000002
+        reg:  00000000.00000000
+        name:  'cpus'
+    Node 0x000003
+        reg:  00000000.00001000.00000000.0009e000.\
+00000001.00000000.00000000.40000000
+        name:  'memory'
+        device_type:  'memory'
+";
+
+    #[test]
+    fn test_iomem() {
+        let path = scratch_path("iomem");
+        fs::write(&path, IOMEM).unwrap();
+        let mut iomem = Iomem::with_path(&path);
+        assert_eq!(
+            iomem.ram().unwrap(),
+            [
+                (0x1000, 0x9fbff),
+                (0x100000, 0xbffdffff),
+                (0x100000000, 0x13fffffff)
+            ]
+        );
+
+        // Partial lines at the ends are left out
+        let areas = discover_areas(&mut iomem, 4096).unwrap();
+        assert_eq!(areas[0], (0x1000, 0x9efff));
+
+        fs::write(&path, "00000000-00000000 : System RAM\n").unwrap();
+        assert!(iomem.ram().is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_phys_segs() {
+        let mut segs = PhysSegs::from_text(PHYS_SEGS);
+        assert_eq!(
+            segs.ram().unwrap(),
+            [(0x10000, 0x9cfff), (0x100000, 0x1fffff)]
+        );
+        assert!(PhysSegs::parse("end: 0x1000\n").is_err());
+    }
+
+    #[test]
+    fn test_prom_memory() {
+        let mut memory = PromMemory::from_text(PRTCONF);
+        assert_eq!(
+            memory.ram().unwrap(),
+            [(0x1000, 0x9efff), (0x100000000, 0x13fffffff)]
+        );
+        assert!(PromMemory::parse("Node 0x1\n").is_err());
+    }
+}
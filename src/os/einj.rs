@@ -0,0 +1,169 @@
+// End-to-end validation using the ACPI error injection (EINJ) interface.
+// EINJ lets firmware plant an error at a chosen physical address. Planting a
+// correctable memory error and then checking that scrubbing causes the error
+// to be reported qualifies the whole path: the scrubber reaching the address,
+// the memory controller detecting the error, and the kernel reporting it.
+//
+// EINJ is controlled through debugfs and requires root, the einj kernel
+// module and firmware support. The address must be a physical address
+// within one of the scrub areas.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::addr::*;
+
+const EINJ_PATH: &str = "/sys/kernel/debug/apei/einj";
+
+/// EINJ error type for a correctable memory error
+pub const EINJ_MEMORY_CORRECTABLE: u32 = 0x8;
+
+// Mask applied to the injection address, limiting it to a page
+const EINJ_PAGE_MASK: u64 = !0xfff;
+
+// Flag indicating that param1 and param2 hold the address and mask
+const EINJ_FLAG_ADDRESS: u32 = 0x2;
+
+/// Interface to the ACPI error injection facility
+///
+/// * `path` - Directory holding the EINJ control files
+pub struct Einj {
+    path: PathBuf,
+}
+
+impl Einj {
+    /// Open the EINJ interface in debugfs
+    pub fn open() -> io::Result<Einj> {
+        Einj::with_path(EINJ_PATH)
+    }
+
+    /// Open an EINJ interface whose control files are in the given
+    /// directory
+    pub fn with_path<P: AsRef<Path>>(path: P) -> io::Result<Einj> {
+        let path = path.as_ref().to_path_buf();
+        if !path.join("error_inject").exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "ACPI EINJ not available",
+            ));
+        }
+        Ok(Einj { path })
+    }
+
+    /// Returns the error types the firmware can inject
+    pub fn available_error_types(&self) -> io::Result<Vec<u32>> {
+        let types =
+            fs::read_to_string(self.path.join("available_error_type"))?;
+        Ok(types
+            .lines()
+            .filter_map(|l| l.split_whitespace().next())
+            .filter_map(|t| parse_number(t, 16))
+            .collect())
+    }
+
+    /// Inject a correctable memory error at a physical address. Where the
+    /// kernel allows it, the firmware is told not to trigger the error
+    /// itself, so it is only reported when the address is next read, such
+    /// as by the scrubber.
+    ///
+    /// # Arguments:
+    /// * `addr` - Physical address at which to inject the error
+    pub fn inject_corrected(&self, addr: u64) -> io::Result<()> {
+        if !self
+            .available_error_types()?
+            .contains(&EINJ_MEMORY_CORRECTABLE)
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                "correctable memory error injection not supported",
+            ));
+        }
+
+        self.write("error_type", EINJ_MEMORY_CORRECTABLE)?;
+        if self.path.join("flags").exists() {
+            self.write("flags", EINJ_FLAG_ADDRESS)?;
+        }
+        self.write("param1", addr)?;
+        self.write("param2", EINJ_PAGE_MASK)?;
+        if self.path.join("notrigger").exists() {
+            self.write("notrigger", 1)?;
+        }
+        self.write("error_inject", 1)
+    }
+
+    // Write a value to a control file
+    fn write<T: std::fmt::LowerHex>(
+        &self,
+        file: &str,
+        value: T,
+    ) -> io::Result<()> {
+        fs::write(self.path.join(file), format!("{:#x}", value))
+    }
+}
+
+/// Outcome of an EINJ validation run
+///
+/// * `addr` - Physical address at which the error was injected
+///
+/// * `area` - Index of the scrub area containing the address
+///
+/// * `detected` - Whether a corrected error was reported in the area
+///
+/// * `elapsed` - Time from injection until the error was reported, or
+///   until the validation gave up
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct EinjReport {
+    pub addr: u64,
+    pub area: usize,
+    pub detected: bool,
+    pub elapsed: Duration,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn test_inject() {
+        let path = scratch_path("einj");
+        let _ = fs::remove_dir_all(&path);
+        assert!(Einj::with_path(&path).is_err());
+
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("error_inject"), "").unwrap();
+        fs::write(path.join("flags"), "").unwrap();
+        fs::write(
+            path.join("available_error_type"),
+            "0x00000002\tProcessor Uncorrectable non-fatal\n\
+             0x00000008\tMemory Correctable\n",
+        )
+        .unwrap();
+
+        let einj = Einj::with_path(&path).unwrap();
+        assert_eq!(einj.available_error_types().unwrap(), vec![0x2, 0x8]);
+        einj.inject_corrected(0x12345678).unwrap();
+
+        let read = |f: &str| fs::read_to_string(path.join(f)).unwrap();
+        assert_eq!(read("error_type"), "0x8");
+        assert_eq!(read("flags"), "0x2");
+        assert_eq!(read("param1"), "0x12345678");
+        assert_eq!(read("param2"), "0xfffffffffffff000");
+        assert_eq!(read("error_inject"), "0x1");
+        assert!(!path.join("notrigger").exists());
+
+        fs::write(path.join("notrigger"), "").unwrap();
+        einj.inject_corrected(0x12345678).unwrap();
+        assert_eq!(read("notrigger"), "0x1");
+
+        fs::write(path.join("available_error_type"), "0x00000002\n")
+            .unwrap();
+        assert_eq!(
+            einj.inject_corrected(0).unwrap_err().kind(),
+            io::ErrorKind::Unsupported
+        );
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
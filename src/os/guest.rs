@@ -0,0 +1,515 @@
+// Scrubbing of guest memory from the host. One agent on a host can protect
+// the memory of every VM by scrubbing the memory backing each guest's RAM
+// instead of running a scrubber in each guest. The guest RAM of a VMM such
+// as QEMU is one or more large mappings in its process, backed by a memfd,
+// by files on hugetlbfs or by anonymous memory, which are found in
+// /proc/<pid>/maps. They are read through /proc/<pid>/mem, so the VMM
+// needs no cooperation and the reads go through its page tables as the
+// guest's would. This requires ptrace access to the VMM, usually root.
+//
+// Reads are batched: the lines of a chunk are queued and read together at
+// the end of the batch, or once IOV_MAX are queued, with one
+// process_vm_readv(2) call. Where that is not available, they are read
+// with one pread(2) each through the mem file.
+//
+// Each VM gets its own scrub areas, labelled with the VM's name and the
+// backing of the mapping, and may have its own bandwidth budget so that
+// one large guest can't use the scrubbing bandwidth of the others. A chunk
+// whose reservation from the budget comes with a delay is scrubbed only
+// once the delay has passed.
+
+use std::fs::{self, File};
+use std::io;
+use std::mem;
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use crate::backend::*;
+use crate::base::*;
+use crate::budget::*;
+use crate::os::procmaps::*;
+use crate::stats::*;
+
+/// Smallest anonymous mapping taken to be guest RAM. Anonymous mappings
+/// are also used for the VMM's own heap and stacks, which are smaller.
+pub const GUEST_RAM_MIN: usize = 64 * 1024 * 1024;
+
+/// A mapping in a VMM holding guest RAM
+///
+/// * `start` - First address of the mapping in the VMM
+///
+/// * `end` - Last address of the mapping in the VMM
+///
+/// * `backing` - Name of the file backing the mapping, such as
+///   "/memfd:pc.ram", or None for anonymous memory
+#[derive(Clone, Debug, PartialEq)]
+pub struct GuestRegion {
+    pub start: usize,
+    pub end: usize,
+    pub backing: Option<String>,
+}
+
+/// A VM whose memory is scrubbed from the host
+///
+/// * `name` - Name of the VM, used in the area labels
+///
+/// * `pid` - Process ID of the VMM
+///
+/// * `regions` - Mappings in the VMM holding guest RAM
+#[derive(Clone, Debug, PartialEq)]
+pub struct GuestVm {
+    pub name: String,
+    pub pid: u32,
+    pub regions: Vec<GuestRegion>,
+}
+
+// Returns whether a mapped file is one guest RAM is kept in
+fn is_guest_backing(path: &str) -> bool {
+    path.starts_with("/memfd:")
+        || path.starts_with("/dev/hugepages")
+        || path.contains("hugetlbfs")
+}
+
+impl GuestVm {
+    /// Find the guest RAM of a running VMM
+    ///
+    /// # Arguments:
+    /// * `name` - Name of the VM
+    ///
+    /// * `pid` - Process ID of the VMM
+    pub fn discover(name: &str, pid: u32) -> io::Result<GuestVm> {
+        let maps = fs::read_to_string(format!("/proc/{}/maps", pid))?;
+        Ok(GuestVm::from_maps(name, pid, &maps))
+    }
+
+    /// Find the guest RAM in the contents of /proc/<pid>/maps. Readable
+    /// mappings of memfds and of files on hugetlbfs are taken, as are
+    /// readable anonymous mappings of at least GUEST_RAM_MIN bytes.
+    pub fn from_maps(name: &str, pid: u32, maps: &str) -> GuestVm {
+        let regions = parse_maps(maps)
+            .into_iter()
+            .filter_map(|segment| {
+                // A bss is anonymous memory, labelled with its file
+                let backing = match segment.kind {
+                    SegmentKind::Anonymous | SegmentKind::Bss => None,
+                    _ => segment.path.map(|p| {
+                        p.trim_end_matches(" (deleted)").to_string()
+                    }),
+                };
+
+                let guest = match &backing {
+                    Some(path) => is_guest_backing(path),
+                    None => {
+                        segment.end - segment.start + 1 >= GUEST_RAM_MIN
+                    }
+                };
+                match segment.readable && guest {
+                    true => Some(GuestRegion {
+                        start: segment.start,
+                        end: segment.end,
+                        backing,
+                    }),
+                    false => None,
+                }
+            })
+            .collect();
+
+        GuestVm {
+            name: name.to_string(),
+            pid,
+            regions,
+        }
+    }
+
+    /// Returns the (start, end) address of each region, end inclusive
+    pub fn extents(&self) -> Vec<(usize, usize)> {
+        self.regions.iter().map(|r| (r.start, r.end)).collect()
+    }
+
+    /// Returns the label of each region: the name of the VM and the
+    /// backing or, for anonymous memory, the address of the region
+    pub fn labels(&self) -> Vec<String> {
+        self.regions
+            .iter()
+            .map(|r| match &r.backing {
+                Some(backing) => format!("{}:{}", self.name, backing),
+                None => format!("{}:{:#x}", self.name, r.start),
+            })
+            .collect()
+    }
+}
+
+// Most lines queued before they are read
+const READ_BATCH: usize = 1024;
+
+/// A backend reading the memory of another process through
+/// /proc/<pid>/mem or process_vm_readv(2), a batch of lines at a time
+///
+/// * `file` - The open mem file
+///
+/// * `pid` - Process ID, if process_vm_readv() can be used
+///
+/// * `pending` - Addresses of the lines queued to be read
+pub struct ProcessMemory {
+    file: File,
+    pid: Option<libc::pid_t>,
+    pending: Vec<usize>,
+}
+
+impl ProcessMemory {
+    /// Open the memory of a process
+    pub fn open(pid: u32) -> io::Result<ProcessMemory> {
+        let mut memory =
+            ProcessMemory::with_path(format!("/proc/{}/mem", pid))?;
+        memory.pid = libc::pid_t::try_from(pid).ok();
+        Ok(memory)
+    }
+
+    /// Read memory through the given file, in the format of
+    /// /proc/<pid>/mem
+    pub fn with_path<P: AsRef<Path>>(
+        path: P,
+    ) -> io::Result<ProcessMemory> {
+        Ok(ProcessMemory {
+            file: File::open(path)?,
+            pid: None,
+            pending: Vec::new(),
+        })
+    }
+
+    // Read the queued lines
+    fn read_pending(&mut self) -> Result<(), Error> {
+        let pending = mem::take(&mut self.pending);
+        if pending.is_empty() {
+            return Ok(());
+        }
+        if let Some(pid) = self.pid {
+            match read_vm(pid, &pending) {
+                Err(err) if err.raw_os_error() == Some(libc::ENOSYS) => {
+                    self.pid = None
+                }
+                result => return result.map_err(|_| Error::IoFailed),
+            }
+        }
+
+        let mut word = [0u8; MAX_READ_SIZE];
+        pending.iter().try_for_each(|&addr| {
+            self.file
+                .read_exact_at(&mut word, addr as u64)
+                .map_err(|_| Error::IoFailed)
+        })
+    }
+}
+
+// Read a word at each address in another process with one system call
+fn read_vm(pid: libc::pid_t, addrs: &[usize]) -> io::Result<()> {
+    let mut words = vec![[0u8; MAX_READ_SIZE]; addrs.len()];
+    let local: Vec<libc::iovec> = words
+        .iter_mut()
+        .map(|word| libc::iovec {
+            iov_base: word.as_mut_ptr().cast(),
+            iov_len: MAX_READ_SIZE,
+        })
+        .collect();
+    let remote: Vec<libc::iovec> = addrs
+        .iter()
+        .map(|&addr| libc::iovec {
+            iov_base: addr as *mut libc::c_void,
+            iov_len: MAX_READ_SIZE,
+        })
+        .collect();
+
+    // The remote addresses are only read by the kernel, which checks
+    // them, and the local buffers outlive the call
+    let read = unsafe {
+        libc::process_vm_readv(
+            pid,
+            local.as_ptr(),
+            local.len() as libc::c_ulong,
+            remote.as_ptr(),
+            remote.len() as libc::c_ulong,
+            0,
+        )
+    };
+    match read {
+        -1 => Err(io::Error::last_os_error()),
+        // A short read stops at the first address that can't be read
+        read if read as usize != addrs.len() * MAX_READ_SIZE => {
+            Err(io::Error::from_raw_os_error(libc::EFAULT))
+        }
+        _ => Ok(()),
+    }
+}
+
+impl ScrubBackend for ProcessMemory {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        self.pending.push(addr);
+        match self.pending.len() >= READ_BATCH {
+            true => self.read_pending(),
+            false => Ok(()),
+        }
+    }
+
+    fn end_batch(&mut self) -> Result<(), Error> {
+        self.read_pending()
+    }
+}
+
+// A VM and the scrubber for its memory
+//
+// name:     Name of the VM
+// scrubber: Scrubs the guest RAM through the VMM
+// budget:   Bandwidth budget for the VM, if any
+// next:     Time at which the next chunk may be scrubbed
+// reserved: Bytes reserved from the budget and not yet scrubbed
+struct GuestScrubber {
+    name: String,
+    scrubber: LineScrubber<ProcessMemory>,
+    budget: Option<Arc<BandwidthBudget>>,
+    next: Option<Instant>,
+    reserved: Option<usize>,
+}
+
+/// Scrubs the memory of VMs from the host
+///
+/// * `line_size` - Number of bytes in a cache line
+///
+/// * `index_width` - Number of address bits in the cache index
+///
+/// * `vms` - The VMs being scrubbed
+pub struct HostScrubber {
+    line_size: usize,
+    index_width: usize,
+    vms: Vec<GuestScrubber>,
+}
+
+impl HostScrubber {
+    /// Create a HostScrubber with no VMs
+    ///
+    /// # Arguments:
+    /// * `line_size` - Number of bytes in a cache line
+    ///
+    /// * `index_width` - Number of address bits in the cache index
+    pub fn new(line_size: usize, index_width: usize) -> HostScrubber {
+        HostScrubber {
+            line_size,
+            index_width,
+            vms: Vec::new(),
+        }
+    }
+
+    /// Start scrubbing the memory of a VM, reading it through
+    /// /proc/<pid>/mem
+    ///
+    /// # Arguments:
+    /// * `vm` - The VM
+    ///
+    /// * `budget` - Bandwidth budget for the VM, or None for no limit
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(io::Error) if the memory of the
+    /// VMM can't be opened or its regions can't be scrubbed
+    pub fn add_vm(
+        &mut self,
+        vm: &GuestVm,
+        budget: Option<Arc<BandwidthBudget>>,
+    ) -> io::Result<()> {
+        let memory = ProcessMemory::open(vm.pid)?;
+        let mut scrubber = LineScrubber::new(
+            memory,
+            &vm.extents(),
+            self.line_size,
+            self.index_width,
+        )
+        .map_err(|e| {
+            io::Error::new(io::ErrorKind::InvalidInput, e.to_string())
+        })?;
+        for (area, label) in
+            scrubber.stats_mut().areas.iter_mut().zip(vm.labels())
+        {
+            area.label = Some(label);
+        }
+
+        self.vms.push(GuestScrubber {
+            name: vm.name.clone(),
+            scrubber,
+            budget,
+            next: None,
+            reserved: None,
+        });
+        Ok(())
+    }
+
+    /// Stop scrubbing the memory of a VM, such as when it shuts down
+    ///
+    /// # Returns:
+    /// The statistics for the VM, or None if there is no VM of that name
+    pub fn remove_vm(&mut self, name: &str) -> Option<ScrubStats> {
+        let i = self.vms.iter().position(|vm| vm.name == name)?;
+        Some(self.vms.remove(i).scrubber.stats().clone())
+    }
+
+    /// Returns the names of the VMs being scrubbed
+    pub fn vms(&self) -> Vec<&str> {
+        self.vms.iter().map(|vm| vm.name.as_str()).collect()
+    }
+
+    /// Returns the statistics for a VM
+    pub fn stats(&self, name: &str) -> Option<&ScrubStats> {
+        self.vms
+            .iter()
+            .find(|vm| vm.name == name)
+            .map(|vm| vm.scrubber.stats())
+    }
+
+    /// Scrub a chunk of the memory of each VM whose budget allows it. A
+    /// chunk the budget can't allow yet is reserved and scrubbed by the
+    /// first call once the budget's delay has passed.
+    ///
+    /// # Arguments:
+    /// * `chunk` - Number of bytes to scrub for each VM
+    ///
+    /// * `now` - The current time
+    ///
+    /// # Returns:
+    /// Ok(wait) with how long until a VM may next be scrubbed, otherwise
+    /// Err(Error) if scrubbing failed, as when a VM has exited
+    pub fn scrub(
+        &mut self,
+        chunk: usize,
+        now: Instant,
+    ) -> Result<Duration, Error> {
+        let mut wait = Duration::MAX;
+        for vm in self.vms.iter_mut() {
+            if let Some(next) = vm.next.filter(|&next| next > now) {
+                wait = wait.min(next - now);
+                continue;
+            }
+            let bytes = match vm.reserved.take() {
+                Some(bytes) => bytes,
+                None => {
+                    let delay = vm
+                        .budget
+                        .as_ref()
+                        .map_or(Duration::ZERO, |b| b.reserve(chunk, now));
+                    if !delay.is_zero() {
+                        vm.reserved = Some(chunk);
+                        vm.next = Some(now + delay);
+                        wait = wait.min(delay);
+                        continue;
+                    }
+                    chunk
+                }
+            };
+            vm.scrubber.scrub(bytes)?;
+            vm.next = None;
+            wait = Duration::ZERO;
+        }
+        Ok(match wait {
+            Duration::MAX => Duration::ZERO,
+            wait => wait,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process;
+
+    const MAPS: &str = "\
+5581a2c00000-5581a2e00000 r-xp 00000000 08:01 1234 /usr/bin/qemu
+7f0000000000-7f0040000000 rw-s 00000000 00:01 5678 /memfd:pc.ram (deleted)
+7f1000000000-7f1000200000 rw-p 00000000 00:00 0
+7f2000000000-7f2010000000 rw-p 00000000 00:00 0
+7f3000000000-7f3000200000 rw-s 00000000 00:0f 9999 /dev/hugepages/vm1
+7f4000000000-7f4040000000 ---p 00000000 00:00 0
+";
+
+    #[test]
+    fn test_maps() {
+        let vm = GuestVm::from_maps("vm1", 42, MAPS);
+        assert_eq!(
+            vm.extents(),
+            [
+                (0x7f0000000000, 0x7f003fffffff),
+                (0x7f2000000000, 0x7f200fffffff),
+                (0x7f3000000000, 0x7f30001fffff),
+            ]
+        );
+        assert_eq!(
+            vm.labels(),
+            [
+                "vm1:/memfd:pc.ram",
+                "vm1:0x7f2000000000",
+                "vm1:/dev/hugepages/vm1"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_process_memory() {
+        let buffer = vec![0u8; 4096];
+        let addr = buffer.as_ptr() as usize;
+        let mut memory = ProcessMemory::open(process::id()).unwrap();
+        let mut file = ProcessMemory::with_path("/proc/self/mem").unwrap();
+        for memory in [&mut memory, &mut file] {
+            // Lines are read at the end of the batch
+            memory.read_line(addr).unwrap();
+            memory.read_line(0).unwrap();
+            assert_eq!(memory.end_batch(), Err(Error::IoFailed));
+            memory.read_line(addr).unwrap();
+            assert_eq!(memory.end_batch(), Ok(()));
+
+            // Or once the queue is full
+            memory.read_line(0).unwrap();
+            for _ in 1..READ_BATCH - 1 {
+                memory.read_line(addr).unwrap();
+            }
+            assert_eq!(memory.read_line(addr), Err(Error::IoFailed));
+            assert_eq!(memory.end_batch(), Ok(()));
+        }
+    }
+
+    #[test]
+    fn test_host_scrubber() {
+        // Scrub a buffer in this process as if it were guest RAM
+        let buffer = vec![0u8; 8192];
+        let start = (buffer.as_ptr() as usize).next_multiple_of(64);
+        let vm = GuestVm {
+            name: "self".to_string(),
+            pid: process::id(),
+            regions: vec![GuestRegion {
+                start,
+                end: start + 4095,
+                backing: None,
+            }],
+        };
+
+        let mut host = HostScrubber::new(64, 4);
+        let budget = Arc::new(BandwidthBudget::new(1024, 1024));
+        host.add_vm(&vm, Some(budget)).unwrap();
+        assert_eq!(host.vms(), ["self"]);
+
+        // The second chunk waits for the budget
+        let now = Instant::now();
+        assert_eq!(host.scrub(1024, now), Ok(Duration::ZERO));
+        assert_eq!(host.scrub(1024, now), Ok(Duration::from_secs(1)));
+        assert_eq!(host.scrub(1024, now), Ok(Duration::from_secs(1)));
+        assert_eq!(host.stats("self").unwrap().bytes_scrubbed, 1024);
+        let later = now + Duration::from_secs(1);
+        assert_eq!(host.scrub(1024, later), Ok(Duration::ZERO));
+        let stats = host.stats("self").unwrap();
+        assert_eq!(stats.bytes_scrubbed, 2048);
+        assert_eq!(
+            stats.areas[0].label,
+            Some(format!("self:{:#x}", start))
+        );
+
+        assert!(host.remove_vm("self").is_some());
+        assert!(host.vms().is_empty());
+        drop(buffer);
+    }
+}
@@ -0,0 +1,292 @@
+// Checking a cache description against the hardware. A cache line size or
+// cache index width that doesn't match the machine doesn't stop scrubbing,
+// it just loses the benefit of cache-aware ordering, so nothing would
+// otherwise notice. The geometry the kernel reports in sysfs is compared
+// with the description given, and the cache line size is also estimated by
+// timing reads at increasing strides.
+
+use std::fs;
+use std::hint::black_box;
+use std::io;
+use std::path::Path;
+use std::time::Instant;
+
+/// Directory in which Linux describes the caches of the first CPU
+pub const SYSFS_CPU0_CACHE: &str = "/sys/devices/system/cpu/cpu0/cache";
+
+/// One level of cache, as described by the kernel
+///
+/// * `level` - Cache level, 1 for the cache closest to the CPU
+///
+/// * `kind` - "Data", "Instruction" or "Unified"
+///
+/// * `line_size` - Number of bytes in a cache line
+///
+/// * `sets` - Number of sets, which is the number of cache indices
+///
+/// * `ways` - Number of ways per set
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheLevel {
+    pub level: u32,
+    pub kind: String,
+    pub line_size: usize,
+    pub sets: usize,
+    pub ways: usize,
+}
+
+impl CacheLevel {
+    /// Returns the size of the cache in bytes
+    pub fn size(&self) -> usize {
+        self.line_size * self.sets * self.ways
+    }
+}
+
+/// Result of checking a cache description against the hardware
+///
+/// * `levels` - Caches described by the kernel, empty if unavailable
+///
+/// * `measured_line_size` - Cache line size estimated by timing, if the
+///   kernel describes no caches and an estimate was found
+///
+/// * `warnings` - Each disagreement found
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CacheCheck {
+    pub levels: Vec<CacheLevel>,
+    pub measured_line_size: Option<usize>,
+    pub warnings: Vec<String>,
+}
+
+impl CacheCheck {
+    /// Returns whether the cache description agrees with the hardware
+    pub fn agrees(&self) -> bool {
+        self.warnings.is_empty()
+    }
+}
+
+// Read a number from a sysfs attribute
+fn read_number(path: &Path) -> io::Result<usize> {
+    fs::read_to_string(path)?
+        .trim()
+        .parse()
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+/// Read the cache descriptions under a directory laid out as
+/// SYSFS_CPU0_CACHE, ordered by level
+pub fn read_cache_levels<P: AsRef<Path>>(
+    dir: P,
+) -> io::Result<Vec<CacheLevel>> {
+    let mut levels = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        let path = entry?.path();
+        let is_index = path
+            .file_name()
+            .and_then(|n| n.to_str())
+            .is_some_and(|n| n.starts_with("index"));
+        if !is_index {
+            continue;
+        }
+
+        levels.push(CacheLevel {
+            level: read_number(&path.join("level"))? as u32,
+            kind: fs::read_to_string(path.join("type"))?
+                .trim()
+                .to_string(),
+            line_size: read_number(&path.join("coherency_line_size"))?,
+            sets: read_number(&path.join("number_of_sets"))?,
+            ways: read_number(&path.join("ways_of_associativity"))?,
+        });
+    }
+    levels.sort_by(|a, b| (a.level, &a.kind).cmp(&(b.level, &b.kind)));
+    Ok(levels)
+}
+
+/// Estimate the cache line size by timing reads of a buffer much larger
+/// than the cache at increasing strides. Once the stride reaches the line
+/// size every read misses, so the time per read stops growing. Hardware
+/// prefetching can hide the effect, so this is only an estimate.
+///
+/// # Returns:
+/// The estimated line size, or None if no clear answer was found
+pub fn measure_line_size() -> Option<usize> {
+    const BUFFER: usize = 64 * 1024 * 1024;
+    const READS: usize = 1 << 20;
+    let buffer = vec![1u8; BUFFER];
+
+    let time_per_read = |stride: usize| {
+        let start = Instant::now();
+        let mut sum = 0u8;
+        let mut offset = 0;
+        for _ in 0..READS {
+            sum = sum.wrapping_add(black_box(buffer[offset]));
+            offset = (offset + stride) % BUFFER;
+        }
+        black_box(sum);
+        start.elapsed().as_secs_f64() / READS as f64
+    };
+
+    let strides: Vec<usize> = (3..=10).map(|s| 1 << s).collect();
+    let times: Vec<f64> =
+        strides.iter().map(|&s| time_per_read(s)).collect();
+    strides
+        .iter()
+        .zip(times.windows(2))
+        .find(|(_, t)| t[1] < t[0] * 1.2)
+        .map(|(&stride, _)| stride)
+}
+
+/// Compare a cache description with the caches the kernel describes
+///
+/// # Arguments:
+/// * `levels` - Caches described by the kernel
+///
+/// * `line_size` - Cache line size in the description
+///
+/// * `index_width` - Cache index width in the description
+///
+/// # Returns:
+/// A description of each disagreement
+pub fn compare_cache_levels(
+    levels: &[CacheLevel],
+    line_size: usize,
+    index_width: usize,
+) -> Vec<String> {
+    let mut warnings = Vec::new();
+
+    if let Some(longest) = levels.iter().map(|l| l.line_size).max() {
+        if longest != line_size {
+            warnings.push(format!(
+                "cache line size is {} bytes but the longest hardware cache \
+                 line is {} bytes",
+                line_size, longest
+            ));
+        }
+    }
+
+    // The cache with the most sets is the one whose eviction matters
+    let largest = levels
+        .iter()
+        .filter(|l| l.kind != "Instruction")
+        .max_by_key(|l| l.sets);
+    if let Some(largest) = largest {
+        let sets = 1usize.checked_shl(index_width as u32).unwrap_or(0);
+        if sets != largest.sets {
+            let width = largest.sets.next_power_of_two().trailing_zeros();
+            warnings.push(format!(
+                "cache index width {} gives {} sets but the level {} cache \
+                 has {} sets, suggesting a width of {}",
+                index_width, sets, largest.level, largest.sets, width
+            ));
+        }
+    }
+
+    warnings
+}
+
+/// Check a cache description against this machine, using the kernel's
+/// description of the caches or, if it has none, a timed estimate of the
+/// line size
+///
+/// # Arguments:
+/// * `line_size` - Cache line size in the description
+///
+/// * `index_width` - Cache index width in the description
+pub fn check_cache_hardware(
+    line_size: usize,
+    index_width: usize,
+) -> CacheCheck {
+    let levels = read_cache_levels(SYSFS_CPU0_CACHE).unwrap_or_default();
+    let mut warnings =
+        compare_cache_levels(&levels, line_size, index_width);
+
+    // Timing is only a tie-breaker when the kernel has nothing to say,
+    // and takes a large buffer, so it is only done then
+    let measured_line_size = match levels.is_empty() {
+        true => measure_line_size(),
+        false => None,
+    };
+    match measured_line_size {
+        Some(measured) if measured != line_size => warnings.push(format!(
+            "cache line size is {} bytes but timing suggests {}",
+            line_size, measured
+        )),
+        _ => {}
+    }
+
+    CacheCheck {
+        levels,
+        measured_line_size,
+        warnings,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    fn level(
+        level: u32,
+        kind: &str,
+        sets: usize,
+        ways: usize,
+    ) -> CacheLevel {
+        CacheLevel {
+            level,
+            kind: kind.to_string(),
+            line_size: 64,
+            sets,
+            ways,
+        }
+    }
+
+    #[test]
+    fn test_sysfs() {
+        let dir = scratch_path("cache");
+        let _ = fs::remove_dir_all(&dir);
+        for (i, (lvl, kind, sets, ways)) in
+            [(1, "Data", 64, 8), (2, "Unified", 1024, 16)]
+                .iter()
+                .enumerate()
+        {
+            let index = dir.join(format!("index{}", i));
+            fs::create_dir_all(&index).unwrap();
+            fs::write(index.join("level"), format!("{}\n", lvl)).unwrap();
+            fs::write(index.join("type"), format!("{}\n", kind)).unwrap();
+            fs::write(index.join("coherency_line_size"), "64\n").unwrap();
+            fs::write(index.join("number_of_sets"), format!("{}\n", sets))
+                .unwrap();
+            fs::write(
+                index.join("ways_of_associativity"),
+                format!("{}\n", ways),
+            )
+            .unwrap();
+        }
+        fs::write(dir.join("uevent"), "").unwrap();
+
+        let levels = read_cache_levels(&dir).unwrap();
+        assert_eq!(
+            levels,
+            vec![level(1, "Data", 64, 8), level(2, "Unified", 1024, 16)]
+        );
+        assert_eq!(levels[1].size(), 1024 * 1024);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_compare() {
+        let levels = [
+            level(1, "Data", 64, 8),
+            level(1, "Instruction", 64, 8),
+            level(2, "Unified", 1024, 16),
+        ];
+        assert!(compare_cache_levels(&levels, 64, 10).is_empty());
+        assert_eq!(compare_cache_levels(&levels, 128, 10).len(), 1);
+
+        let warnings = compare_cache_levels(&levels, 64, 6);
+        assert_eq!(warnings.len(), 1);
+        assert!(warnings[0].ends_with("a width of 10"));
+
+        assert!(compare_cache_levels(&[], 64, 6).is_empty());
+    }
+}
@@ -0,0 +1,206 @@
+// Feed of machine-check memory errors reported by the Linux kernel. EDAC
+// drivers report memory errors through the ras:mc_event trace event, which is
+// read here from the tracefs trace_pipe and converted into ErrorEvents. The
+// addresses reported are physical addresses.
+//
+// A trace line looks like:
+//
+//  <...>-123 [000] .... 123.456: mc_event: 1 Corrected error: memory read
+//  error on DIMM_A1 (mc:0 location:0:0:-1 address:0x12345000 grain:32
+//  syndrome:0x00000000 ...)
+//
+// all on one line.
+
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+
+use crate::addr::*;
+use crate::event::*;
+
+const TRACEFS_PATHS: [&str; 2] =
+    ["/sys/kernel/tracing", "/sys/kernel/debug/tracing"];
+const MC_EVENT_ENABLE: &str = "events/ras/mc_event/enable";
+const TRACE_PIPE: &str = "trace_pipe";
+
+/// A memory error reported by the kernel
+///
+/// * `error` - The error, with the physical address at which it occurred
+///
+/// * `count` - Number of errors the kernel reported in this event
+///
+/// * `label` - Label the EDAC driver gave the memory location, usually a
+///   DIMM name
+#[derive(Clone, Debug, PartialEq)]
+pub struct McEvent {
+    pub error: ErrorEvent,
+    pub count: u32,
+    pub label: String,
+}
+
+impl McEvent {
+    /// Parse a line from trace_pipe. Returns None for lines that are not
+    /// mc_event records or that report informational events.
+    pub fn parse(line: &str) -> Option<McEvent> {
+        let rest = &line[line.find("mc_event: ")? + "mc_event: ".len()..];
+        let mut words = rest.split_whitespace();
+        let count: u32 = words.next()?.parse().ok()?;
+        let severity = match words.next()? {
+            "Corrected" => ErrorSeverity::Corrected,
+            "Uncorrected" | "Deferred" | "Fatal" => {
+                ErrorSeverity::Uncorrected
+            }
+            _ => return None,
+        };
+
+        let addr_start = rest.find("address:0x")? + "address:0x".len();
+        let addr_str = &rest[addr_start..];
+        let addr_end = addr_str
+            .find(|c: char| !c.is_ascii_hexdigit())
+            .unwrap_or(addr_str.len());
+        let addr = parse_number(&addr_str[..addr_end], 16)?;
+
+        // The label sits between " on " and " (mc:"
+        let label = match (rest.find(" on "), rest.find(" (mc:")) {
+            (Some(s), Some(e)) if s + 4 <= e => rest[s + 4..e].to_string(),
+            _ => String::new(),
+        };
+
+        Some(McEvent {
+            error: ErrorEvent {
+                addr,
+                area: None,
+                severity,
+            },
+            count,
+            label,
+        })
+    }
+}
+
+/// Reads memory errors from a source of ras:mc_event trace lines
+///
+/// * `reader` - Source of trace lines
+pub struct McEventFeed<R: BufRead> {
+    reader: R,
+}
+
+impl McEventFeed<BufReader<File>> {
+    /// Enable the ras:mc_event trace event and open the tracefs trace_pipe.
+    /// This normally requires root.
+    pub fn open_tracefs() -> io::Result<McEventFeed<BufReader<File>>> {
+        let tracefs = Self::find_tracefs()?;
+        fs::write(tracefs.join(MC_EVENT_ENABLE), "1")?;
+        let pipe = File::open(tracefs.join(TRACE_PIPE))?;
+        Ok(McEventFeed::new(BufReader::new(pipe)))
+    }
+
+    // Find where tracefs is mounted
+    fn find_tracefs() -> io::Result<PathBuf> {
+        TRACEFS_PATHS
+            .iter()
+            .map(Path::new)
+            .find(|p| p.join(MC_EVENT_ENABLE).exists())
+            .map(Path::to_path_buf)
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::NotFound,
+                    "tracefs ras:mc_event not available",
+                )
+            })
+    }
+}
+
+impl<R: BufRead> McEventFeed<R> {
+    pub fn new(reader: R) -> McEventFeed<R> {
+        McEventFeed { reader }
+    }
+}
+
+impl<R: BufRead> Iterator for McEventFeed<R> {
+    type Item = io::Result<McEvent>;
+
+    // Block until the next memory error is read
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            match self.reader.read_line(&mut line) {
+                Err(e) => return Some(Err(e)),
+                Ok(0) => return None,
+                Ok(_) => {
+                    if let Some(event) = McEvent::parse(&line) {
+                        return Some(Ok(event));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Start a thread that reads memory errors from tracefs and sends them to
+/// the returned channel, one ErrorReport per mc_event, holding the number
+/// of errors the kernel reported in it. The channel can be handed to
+/// AutoScrub::set_error_source() so that kernel-reported errors are
+/// processed like any other error.
+pub fn spawn_mc_event_feed() -> io::Result<Receiver<ErrorReport>> {
+    let feed = McEventFeed::open_tracefs()?;
+    let (tx, rx) = mpsc::channel();
+
+    thread::Builder::new()
+        .name("memscrub-mce".to_string())
+        .spawn(move || {
+            for event in feed.map_while(Result::ok) {
+                let report = ErrorReport {
+                    error: event.error,
+                    count: event.count,
+                };
+                if tx.send(report).is_err() {
+                    return;
+                }
+            }
+        })?;
+
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let line = "  kworker/0:1-42 [000] .... 1234.567890: mc_event: \
+            2 Corrected errors: memory read error on DIMM_A1 (mc:0 \
+            location:0:0:-1 address:0x12345000 grain:32 \
+            syndrome:0x00000000 area:DRAM)\n";
+        let event = McEvent::parse(line).unwrap();
+        assert_eq!(event.count, 2);
+        assert_eq!(event.label, "DIMM_A1");
+        assert_eq!(event.error.addr, 0x12345000);
+        assert_eq!(event.error.severity, ErrorSeverity::Corrected);
+
+        let line = "x: mc_event: 1 Fatal error: on CPU (mc:0 \
+            location:0:0:0 address:0x0000000000abc000 grain:1)";
+        let event = McEvent::parse(line).unwrap();
+        assert_eq!(event.error.severity, ErrorSeverity::Uncorrected);
+        assert_eq!(event.error.addr, 0xabc000);
+
+        assert!(McEvent::parse("x: mc_event: 1 Info error: x").is_none());
+        assert!(McEvent::parse("x: sched_switch: prev=a").is_none());
+    }
+
+    #[test]
+    fn test_feed() {
+        let input = "junk\n\
+            a: mc_event: 1 Uncorrected error: on D0 (mc:0 address:0x40)\n\
+            b: mc_event: 1 Corrected error: on D1 (mc:0 address:0x80)\n";
+        let feed = McEventFeed::new(input.as_bytes());
+        let events: Vec<McEvent> = feed.map(|e| e.unwrap()).collect();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].label, "D0");
+        assert_eq!(events[1].error.addr, 0x80);
+    }
+}
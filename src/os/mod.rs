@@ -0,0 +1,83 @@
+// Discovery of the system the scrubber runs on and integration with it:
+// cache geometry and processor identification, power and error reporting,
+// error injection and logging. Everything here reads or writes operating
+// system interfaces, mostly those of Linux, with memory discovery for
+// Windows; the scrubbing itself doesn't depend on any of it.
+
+mod balloon;
+#[cfg(target_os = "linux")]
+mod cgroup;
+mod dimm;
+mod discovery;
+#[cfg(target_os = "linux")]
+mod einj;
+#[cfg(target_os = "linux")]
+mod guest;
+mod hwcache;
+#[cfg(target_os = "linux")]
+mod mce;
+#[cfg(target_os = "linux")]
+mod offline;
+#[cfg(target_os = "linux")]
+mod pagemap;
+#[cfg(target_os = "linux")]
+mod perf;
+mod power;
+mod presets;
+#[cfg(target_os = "linux")]
+mod rapl;
+#[cfg(target_os = "linux")]
+mod procmaps;
+#[cfg(feature = "rasdaemon")]
+mod rasdaemon;
+mod resctrl;
+#[cfg(target_os = "linux")]
+mod shm;
+mod smbios;
+#[cfg(feature = "syslog")]
+mod syslog;
+#[cfg(target_os = "linux")]
+mod uncached;
+#[cfg(target_os = "linux")]
+mod window;
+#[cfg(windows)]
+mod windows;
+
+pub use crate::os::balloon::*;
+#[cfg(target_os = "linux")]
+pub use crate::os::cgroup::*;
+pub use crate::os::dimm::*;
+pub use crate::os::discovery::*;
+#[cfg(target_os = "linux")]
+pub use crate::os::einj::*;
+#[cfg(target_os = "linux")]
+pub use crate::os::guest::*;
+pub use crate::os::hwcache::*;
+#[cfg(target_os = "linux")]
+pub use crate::os::mce::*;
+#[cfg(target_os = "linux")]
+pub use crate::os::offline::*;
+#[cfg(target_os = "linux")]
+pub use crate::os::pagemap::*;
+#[cfg(target_os = "linux")]
+pub use crate::os::perf::*;
+pub use crate::os::power::*;
+pub use crate::os::presets::*;
+#[cfg(target_os = "linux")]
+pub use crate::os::rapl::*;
+#[cfg(target_os = "linux")]
+pub use crate::os::procmaps::*;
+#[cfg(feature = "rasdaemon")]
+pub use crate::os::rasdaemon::*;
+pub use crate::os::resctrl::*;
+#[cfg(target_os = "linux")]
+pub use crate::os::shm::*;
+pub use crate::os::smbios::*;
+#[cfg(feature = "syslog")]
+pub use crate::os::syslog::*;
+#[cfg(target_os = "linux")]
+pub use crate::os::uncached::*;
+#[cfg(target_os = "linux")]
+pub use crate::os::window::*;
+#[cfg(windows)]
+pub use crate::os::windows::*;
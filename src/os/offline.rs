@@ -0,0 +1,76 @@
+// Page retirement through the Linux memory offlining interface. Writing a
+// physical address to soft_offline_page migrates the contents of the page
+// holding it elsewhere and stops the page from being used again, which is
+// the usual response to a page that keeps producing corrected errors.
+// hard_offline_page is for pages whose contents are already lost. Both
+// require root.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const MEMORY_PATH: &str = "/sys/devices/system/memory";
+
+/// Interface to page offlining
+///
+/// * `path` - Directory holding soft_offline_page and hard_offline_page
+pub struct SoftOffline {
+    path: PathBuf,
+}
+
+impl SoftOffline {
+    /// Use the offlining interface in sysfs
+    pub fn new() -> SoftOffline {
+        SoftOffline::with_path(MEMORY_PATH)
+    }
+
+    /// Use offlining control files in the given directory
+    pub fn with_path<P: AsRef<Path>>(path: P) -> SoftOffline {
+        SoftOffline {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Retire the page holding a physical address, keeping its contents
+    pub fn soft_offline(&self, addr: u64) -> io::Result<()> {
+        fs::write(
+            self.path.join("soft_offline_page"),
+            format!("{:#x}", addr),
+        )
+    }
+
+    /// Retire the page holding a physical address whose contents are lost.
+    /// Anything using the page is killed.
+    pub fn hard_offline(&self, addr: u64) -> io::Result<()> {
+        fs::write(
+            self.path.join("hard_offline_page"),
+            format!("{:#x}", addr),
+        )
+    }
+}
+
+impl Default for SoftOffline {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn test_offline() {
+        let path = scratch_path("offline");
+        fs::create_dir_all(&path).unwrap();
+
+        let offline = SoftOffline::with_path(&path);
+        offline.soft_offline(0x12345000).unwrap();
+        assert_eq!(
+            fs::read_to_string(path.join("soft_offline_page")).unwrap(),
+            "0x12345000"
+        );
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
@@ -0,0 +1,154 @@
+// Translation of the addresses of a process to physical addresses, through
+// /proc/<pid>/pagemap. The file holds a 64-bit entry for each virtual page
+// giving, among other things, whether the page is present and its page
+// frame number. Page frame numbers are only shown to processes with
+// CAP_SYS_ADMIN; others see zero, which is taken to mean no translation.
+
+use std::fs::File;
+use std::io;
+use std::os::unix::fs::FileExt;
+
+use crate::dedup::*;
+
+// Bits of a pagemap entry
+const PM_PRESENT: u64 = 1 << 63;
+const PM_PFN_MASK: u64 = (1 << 55) - 1;
+
+/// Translates the addresses of a process through its pagemap file
+///
+/// * `file` - The pagemap file
+///
+/// * `page_size` - Number of bytes in a page
+#[derive(Debug)]
+pub struct PagemapTranslator {
+    file: File,
+    page_size: usize,
+}
+
+impl PagemapTranslator {
+    /// Open the pagemap of a process
+    ///
+    /// # Arguments:
+    /// * `pid` - Process ID, or None for this process
+    pub fn open(pid: Option<u32>) -> io::Result<PagemapTranslator> {
+        let path = match pid {
+            Some(pid) => format!("/proc/{}/pagemap", pid),
+            None => "/proc/self/pagemap".to_string(),
+        };
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        Ok(PagemapTranslator {
+            file: File::open(path)?,
+            page_size: usize::try_from(page_size).unwrap_or(4096),
+        })
+    }
+
+    /// Returns the number of bytes in a page
+    pub fn page_size(&self) -> usize {
+        self.page_size
+    }
+
+    /// Returns the pagemap entry of the page holding an address
+    pub fn entry(&self, addr: usize) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        let offset = (addr / self.page_size) as u64 * 8;
+        self.file.read_exact_at(&mut buf, offset)?;
+        Ok(u64::from_ne_bytes(buf))
+    }
+
+    /// Returns the pagemap entries of a run of pages, read at once
+    ///
+    /// # Arguments:
+    /// * `addr` - Address in the first page
+    ///
+    /// * `pages` - Number of pages
+    pub fn entries(
+        &self,
+        addr: usize,
+        pages: usize,
+    ) -> io::Result<Vec<u64>> {
+        let mut buf = vec![0u8; pages * 8];
+        let offset = (addr / self.page_size) as u64 * 8;
+        self.file.read_exact_at(&mut buf, offset)?;
+        Ok(buf
+            .chunks_exact(8)
+            .map(|b| u64::from_ne_bytes(b.try_into().unwrap()))
+            .collect())
+    }
+
+    // Returns the physical address of an address in a page with a pagemap
+    // entry, if the page is present and its frame number is shown
+    fn translate(&self, entry: u64, addr: usize) -> Option<u64> {
+        let frame = entry & PM_PFN_MASK;
+        if entry & PM_PRESENT == 0 || frame == 0 {
+            return None;
+        }
+        let offset = (addr % self.page_size) as u64;
+        Some(frame * self.page_size as u64 + offset)
+    }
+}
+
+impl AddressTranslator for PagemapTranslator {
+    fn physical(&mut self, addr: usize) -> Option<u64> {
+        let entry = self.entry(addr).ok()?;
+        self.translate(entry, addr)
+    }
+
+    // Pages of another size, or runs the pagemap can't be read for all at
+    // once, are translated a page at a time
+    fn physical_pages(
+        &mut self,
+        addr: usize,
+        page_size: usize,
+        physical: &mut [Option<u64>],
+    ) {
+        let entries = match page_size == self.page_size {
+            true => self.entries(addr, physical.len()).ok(),
+            false => None,
+        };
+        let Some(entries) = entries else {
+            return translate_each(self, addr, page_size, physical);
+        };
+        for (i, (phys, entry)) in
+            physical.iter_mut().zip(entries).enumerate()
+        {
+            *phys = self.translate(entry, addr + i * page_size);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_self() {
+        let mut translator = PagemapTranslator::open(None).unwrap();
+        let data = vec![1u8; translator.page_size()];
+        let addr = data.as_ptr() as usize;
+        assert_eq!(
+            translator.entry(addr).unwrap() & PM_PRESENT,
+            PM_PRESENT
+        );
+
+        // Frame numbers are hidden without CAP_SYS_ADMIN
+        if let Some(phys) = translator.physical(addr) {
+            assert_eq!(
+                phys as usize % translator.page_size(),
+                addr % translator.page_size()
+            );
+        }
+
+        // A run of pages reads the same entries as a page at a time
+        let page_size = translator.page_size();
+        let mut physical = [None; 2];
+        translator.physical_pages(addr, page_size, &mut physical);
+        assert_eq!(physical[0], translator.physical(addr));
+        assert_eq!(
+            translator.entries(addr, 2).unwrap(),
+            [
+                translator.entry(addr).unwrap(),
+                translator.entry(addr + page_size).unwrap()
+            ]
+        );
+    }
+}
@@ -0,0 +1,172 @@
+// Hardware performance counters, through perf_event_open(2). A counter is
+// read as a Sensor giving the number of events per second since the last
+// read, so that, for example, the cache misses of the foreground work on a
+// CPU can drive an ImpactGuard. Counting other processes needs
+// CAP_PERFMON or a low enough /proc/sys/kernel/perf_event_paranoid.
+// Only events in user mode are counted, which is what the default
+// paranoia level allows and what foreground work mostly suffers.
+
+use std::fs::File;
+use std::io::{self, Read};
+use std::mem;
+use std::os::unix::io::FromRawFd;
+use std::time::Instant;
+
+use crate::throttle::*;
+
+/// Event types and events, from linux/perf_event.h
+pub const PERF_TYPE_HARDWARE: u32 = 0;
+pub const PERF_TYPE_SOFTWARE: u32 = 1;
+pub const PERF_COUNT_HW_CPU_CYCLES: u64 = 0;
+pub const PERF_COUNT_HW_INSTRUCTIONS: u64 = 1;
+pub const PERF_COUNT_HW_CACHE_REFERENCES: u64 = 2;
+pub const PERF_COUNT_HW_CACHE_MISSES: u64 = 3;
+pub const PERF_COUNT_SW_TASK_CLOCK: u64 = 1;
+
+// Bits of perf_event_attr.flags
+const EXCLUDE_KERNEL: u64 = 1 << 5;
+const EXCLUDE_HV: u64 = 1 << 6;
+
+// Flag to perf_event_open() to set close-on-exec on the descriptor
+const PERF_FLAG_FD_CLOEXEC: libc::c_ulong = 1 << 3;
+
+// The first version of struct perf_event_attr, which every kernel with
+// perf_event_open() accepts
+#[repr(C)]
+#[derive(Default)]
+struct PerfEventAttr {
+    type_: u32,
+    size: u32,
+    config: u64,
+    sample_period: u64,
+    sample_type: u64,
+    read_format: u64,
+    flags: u64,
+    wakeup_events: u32,
+    bp_type: u32,
+    config1: u64,
+}
+
+/// A performance counter, read as a Sensor in events per second. The
+/// first read returns zero.
+///
+/// * `file` - The perf event file descriptor
+///
+/// * `last` - Count and time at the previous read
+#[derive(Debug)]
+pub struct PerfCounter {
+    file: File,
+    last: Option<(u64, Instant)>,
+}
+
+impl PerfCounter {
+    /// Open a counter
+    ///
+    /// # Arguments:
+    /// * `type_` - Event type, such as PERF_TYPE_HARDWARE
+    ///
+    /// * `config` - Event within the type, such as
+    ///   PERF_COUNT_HW_CACHE_MISSES
+    ///
+    /// * `pid` - Process to count, 0 for this one or -1 for all of them
+    ///
+    /// * `cpu` - CPU to count on, or -1 for all of them. At least one of
+    ///   pid and cpu must not be -1.
+    pub fn open(
+        type_: u32,
+        config: u64,
+        pid: i32,
+        cpu: i32,
+    ) -> io::Result<PerfCounter> {
+        let attr = PerfEventAttr {
+            type_,
+            size: mem::size_of::<PerfEventAttr>() as u32,
+            config,
+            flags: EXCLUDE_KERNEL | EXCLUDE_HV,
+            ..Default::default()
+        };
+
+        // The attribute is only read during the call
+        let fd = unsafe {
+            libc::syscall(
+                libc::SYS_perf_event_open,
+                &attr as *const PerfEventAttr,
+                pid,
+                cpu,
+                -1,
+                PERF_FLAG_FD_CLOEXEC,
+            )
+        };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        // The descriptor was just opened and is owned by nothing else
+        let file = unsafe { File::from_raw_fd(fd as i32) };
+        Ok(PerfCounter { file, last: None })
+    }
+
+    /// Open a counter of the cache misses of every process on a CPU, the
+    /// interference that scrubbing causes on it
+    pub fn cache_misses(cpu: usize) -> io::Result<PerfCounter> {
+        let cpu = i32::try_from(cpu)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        PerfCounter::open(
+            PERF_TYPE_HARDWARE,
+            PERF_COUNT_HW_CACHE_MISSES,
+            -1,
+            cpu,
+        )
+    }
+
+    /// Returns the number of events counted since the counter was opened
+    pub fn count(&mut self) -> io::Result<u64> {
+        let mut buf = [0u8; 8];
+        self.file.read_exact(&mut buf)?;
+        Ok(u64::from_ne_bytes(buf))
+    }
+}
+
+impl Sensor for PerfCounter {
+    fn read(&mut self) -> io::Result<f64> {
+        let count = self.count()?;
+        let now = Instant::now();
+        let rate = match self.last {
+            Some((last, then)) if count >= last && now > then => {
+                (count - last) as f64 / (now - then).as_secs_f64()
+            }
+            _ => 0.0,
+        };
+        self.last = Some((count, now));
+        Ok(rate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layout() {
+        // PERF_ATTR_SIZE_VER0
+        assert_eq!(mem::size_of::<PerfEventAttr>(), 64);
+    }
+
+    #[test]
+    fn test_task_clock() {
+        // Perf events may not be allowed at all, as in some containers
+        let mut counter = match PerfCounter::open(
+            PERF_TYPE_SOFTWARE,
+            PERF_COUNT_SW_TASK_CLOCK,
+            0,
+            -1,
+        ) {
+            Ok(counter) => counter,
+            Err(_) => return,
+        };
+        assert_eq!(counter.read().unwrap(), 0.0);
+        let spin = Instant::now();
+        while spin.elapsed().as_millis() < 10 {}
+        assert!(counter.read().unwrap() > 0.0);
+    }
+}
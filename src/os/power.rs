@@ -0,0 +1,179 @@
+// Scheduling according to the power source. On battery, scrubbing is slowed
+// or paused to save energy; when AC power returns, scrubbing runs at full
+// rate again. Throttle policies can only slow scrubbing, so passes that
+// fell behind on battery are not made up by running faster.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use crate::throttle::*;
+
+const POWER_SUPPLY_PATH: &str = "/sys/class/power_supply";
+
+/// Where the system is getting its power
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PowerState {
+    Ac,
+    Battery,
+}
+
+/// Reports the current power source
+pub trait PowerSource {
+    /// Returns where the system is currently getting its power
+    fn state(&mut self) -> io::Result<PowerState>;
+}
+
+/// Power source read from the Linux power_supply class in sysfs. The
+/// system is on AC if any mains supply is online or if there is no battery
+/// at all.
+///
+/// * `path` - Directory holding the power supplies
+pub struct SysfsPowerSource {
+    path: PathBuf,
+}
+
+impl SysfsPowerSource {
+    /// Use the standard sysfs location
+    pub fn new() -> SysfsPowerSource {
+        SysfsPowerSource::with_path(POWER_SUPPLY_PATH)
+    }
+
+    /// Use power supplies in the given directory
+    pub fn with_path<P: AsRef<Path>>(path: P) -> SysfsPowerSource {
+        SysfsPowerSource {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+}
+
+impl Default for SysfsPowerSource {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PowerSource for SysfsPowerSource {
+    fn state(&mut self) -> io::Result<PowerState> {
+        let mut battery = false;
+
+        for entry in fs::read_dir(&self.path)? {
+            let path = entry?.path();
+            let read = |f: &str| {
+                fs::read_to_string(path.join(f))
+                    .map(|s| s.trim().to_string())
+                    .unwrap_or_default()
+            };
+            match read("type").as_str() {
+                "Mains" | "USB" if read("online") == "1" => {
+                    return Ok(PowerState::Ac);
+                }
+                "Battery" => battery = true,
+                _ => {}
+            }
+        }
+
+        match battery {
+            true => Ok(PowerState::Battery),
+            false => Ok(PowerState::Ac),
+        }
+    }
+}
+
+/// Throttles scrubbing while running on battery
+///
+/// * `source` - Source of the power state
+///
+/// * `battery_rate` - Fraction of the full rate used on battery
+///
+/// * `state` - Most recently seen power state
+pub struct PowerThrottle<P: PowerSource> {
+    source: P,
+    battery_rate: f64,
+    state: PowerState,
+}
+
+impl<P: PowerSource> PowerThrottle<P> {
+    /// Create a new PowerThrottle
+    ///
+    /// # Arguments:
+    /// * `source` - Source of the power state
+    ///
+    /// * `battery_rate` - Fraction of the full rate used on battery. Zero
+    ///   pauses scrubbing until AC power returns.
+    pub fn new(source: P, battery_rate: f64) -> PowerThrottle<P> {
+        PowerThrottle {
+            source,
+            battery_rate: battery_rate.clamp(0.0, 1.0),
+            state: PowerState::Ac,
+        }
+    }
+
+    /// Returns the most recently seen power state
+    pub fn state(&self) -> PowerState {
+        self.state
+    }
+}
+
+impl<P: PowerSource> ThrottlePolicy for PowerThrottle<P> {
+    fn rate(&mut self, _now: Instant) -> f64 {
+        // If the state can't be read, assume it hasn't changed
+        if let Ok(state) = self.source.state() {
+            self.state = state;
+        }
+
+        match self.state {
+            PowerState::Ac => 1.0,
+            PowerState::Battery => self.battery_rate,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    fn supply(dir: &Path, name: &str, kind: &str, online: &str) {
+        let path = dir.join(name);
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("type"), format!("{}\n", kind)).unwrap();
+        fs::write(path.join("online"), format!("{}\n", online)).unwrap();
+    }
+
+    #[test]
+    fn test_sysfs() {
+        let dir = scratch_path("power");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        let mut source = SysfsPowerSource::with_path(&dir);
+        assert_eq!(source.state().unwrap(), PowerState::Ac);
+
+        supply(&dir, "BAT0", "Battery", "");
+        supply(&dir, "AC", "Mains", "0");
+        assert_eq!(source.state().unwrap(), PowerState::Battery);
+
+        supply(&dir, "AC", "Mains", "1");
+        assert_eq!(source.state().unwrap(), PowerState::Ac);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_throttle() {
+        struct Fixed(PowerState);
+        impl PowerSource for Fixed {
+            fn state(&mut self) -> io::Result<PowerState> {
+                Ok(self.0)
+            }
+        }
+
+        let now = Instant::now();
+        let mut policy =
+            PowerThrottle::new(Fixed(PowerState::Battery), 0.1);
+        assert_eq!(policy.rate(now), 0.1);
+        assert_eq!(policy.state(), PowerState::Battery);
+        policy.source.0 = PowerState::Ac;
+        assert_eq!(policy.rate(now), 1.0);
+    }
+}
@@ -0,0 +1,277 @@
+// Cache descriptions for common processors, so that most users need not
+// work out their own cache geometry. Each preset describes the largest
+// cache whose geometry is fixed by the core design, which is the per-core
+// or per-cluster L2: the size of a shared L3 varies from part to part of
+// the same design, so a preset can't describe it. A preset can be chosen by
+// name or matched against /proc/cpuinfo.
+
+use std::fs;
+use std::io;
+
+use crate::addr::*;
+use crate::base::*;
+use crate::data::*;
+
+/// File from which the processor is identified
+pub const PROC_CPUINFO: &str = "/proc/cpuinfo";
+
+/// How a preset recognizes a processor in /proc/cpuinfo
+///
+/// * `X86` - vendor_id, cpu family and the inclusive ranges of model
+///
+/// * `Arm` - CPU implementer and the CPU part numbers
+///
+/// * `Uarch` - Prefix of the uarch line, used on RISC-V
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CpuMatch {
+    X86(&'static str, u32, &'static [(u32, u32)]),
+    Arm(u32, &'static [u32]),
+    Uarch(&'static str),
+}
+
+/// Cache geometry for one processor design
+///
+/// * `name` - Name by which the preset is chosen
+///
+/// * `cpu` - How the processor is recognized
+///
+/// * `line_size` - Number of bytes in a cache line
+///
+/// * `index_width` - Number of address bits in the cache index
+///
+/// * `ways` - Number of ways per set
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CachePreset {
+    pub name: &'static str,
+    pub cpu: CpuMatch,
+    pub line_size: usize,
+    pub index_width: usize,
+    pub ways: usize,
+}
+
+impl CachePreset {
+    /// Returns the size in bytes of the cache the preset describes
+    pub fn cache_size(&self) -> usize {
+        (self.line_size << self.index_width) * self.ways
+    }
+}
+
+/// The known presets
+pub const PRESETS: &[CachePreset] = &[
+    // 512 KiB 16-way L2, as on the Raspberry Pi 3. The L2 size is chosen by
+    // the SoC designer.
+    CachePreset {
+        name: "cortex-a53",
+        cpu: CpuMatch::Arm(0x41, &[0xd03]),
+        line_size: 64,
+        index_width: 9,
+        ways: 16,
+    },
+    // 1 MiB 16-way L2, as on the Raspberry Pi 4
+    CachePreset {
+        name: "cortex-a72",
+        cpu: CpuMatch::Arm(0x41, &[0xd08]),
+        line_size: 64,
+        index_width: 10,
+        ways: 16,
+    },
+    // 512 KiB 8-way L2
+    CachePreset {
+        name: "zen2",
+        cpu: CpuMatch::X86("AuthenticAMD", 0x17, &[(0x30, 0xaf)]),
+        line_size: 64,
+        index_width: 10,
+        ways: 8,
+    },
+    // 512 KiB 8-way L2
+    CachePreset {
+        name: "zen3",
+        cpu: CpuMatch::X86(
+            "AuthenticAMD",
+            0x19,
+            &[(0x00, 0x0f), (0x20, 0x5f)],
+        ),
+        line_size: 64,
+        index_width: 10,
+        ways: 8,
+    },
+    // 1 MiB 8-way L2
+    CachePreset {
+        name: "zen4",
+        cpu: CpuMatch::X86(
+            "AuthenticAMD",
+            0x19,
+            &[(0x10, 0x1f), (0x60, 0x7f), (0xa0, 0xaf)],
+        ),
+        line_size: 64,
+        index_width: 11,
+        ways: 8,
+    },
+    // 256 KiB 4-way L2 on client parts and 1 MiB 16-way on servers, both
+    // with 1024 sets. Kaby Lake through Comet Lake use the same core.
+    CachePreset {
+        name: "skylake",
+        cpu: CpuMatch::X86(
+            "GenuineIntel",
+            6,
+            &[
+                (0x4e, 0x4e),
+                (0x55, 0x55),
+                (0x5e, 0x5e),
+                (0x8e, 0x8e),
+                (0x9e, 0x9e),
+                (0xa5, 0xa6),
+            ],
+        ),
+        line_size: 64,
+        index_width: 10,
+        ways: 4,
+    },
+    // 512 KiB 8-way L2 on client parts and 1.25 MiB 20-way on servers,
+    // both with 1024 sets
+    CachePreset {
+        name: "icelake",
+        cpu: CpuMatch::X86(
+            "GenuineIntel",
+            6,
+            &[(0x6a, 0x6a), (0x6c, 0x6c), (0x7d, 0x7e)],
+        ),
+        line_size: 64,
+        index_width: 10,
+        ways: 8,
+    },
+    // 12 MiB 12-way L2 shared by the performance cluster of the M1, and
+    // 16 MiB 16-way on the M2, both with 8192 sets of 128-byte lines
+    CachePreset {
+        name: "apple-m",
+        cpu: CpuMatch::Arm(0x61, &[]),
+        line_size: 128,
+        index_width: 13,
+        ways: 12,
+    },
+    // 2 MiB 16-way L2, as on the FU740 and JH7110
+    CachePreset {
+        name: "sifive-u74",
+        cpu: CpuMatch::Uarch("sifive,u74"),
+        line_size: 64,
+        index_width: 11,
+        ways: 16,
+    },
+];
+
+/// Find a preset by name, ignoring case
+pub fn preset(name: &str) -> Option<&'static CachePreset> {
+    PRESETS.iter().find(|p| p.name.eq_ignore_ascii_case(name))
+}
+
+// Returns the value of the first line in cpuinfo with the given key
+fn cpuinfo_value<'a>(cpuinfo: &'a str, key: &str) -> Option<&'a str> {
+    cpuinfo.lines().find_map(|line| {
+        let (k, v) = line.split_once(':')?;
+        match k.trim() == key {
+            true => Some(v.trim()),
+            false => None,
+        }
+    })
+}
+
+// Parse a number as cpuinfo writes it, in hex if it starts with 0x
+fn cpuinfo_number(cpuinfo: &str, key: &str) -> Option<u32> {
+    parse_number(cpuinfo_value(cpuinfo, key)?, 10)
+}
+
+/// Find the preset for the processor described by the contents of
+/// /proc/cpuinfo. Only the first processor is looked at, so on a system
+/// with cores of more than one design the preset is for the boot CPU.
+///
+/// # Returns:
+/// The matching preset, or None if no preset matches
+pub fn match_cpuinfo(cpuinfo: &str) -> Option<&'static CachePreset> {
+    PRESETS.iter().find(|p| match p.cpu {
+        CpuMatch::X86(vendor, family, models) => {
+            cpuinfo_value(cpuinfo, "vendor_id") == Some(vendor)
+                && cpuinfo_number(cpuinfo, "cpu family") == Some(family)
+                && cpuinfo_number(cpuinfo, "model").is_some_and(|m| {
+                    models.iter().any(|&(lo, hi)| m >= lo && m <= hi)
+                })
+        }
+        CpuMatch::Arm(implementer, parts) => {
+            cpuinfo_number(cpuinfo, "CPU implementer") == Some(implementer)
+                && (parts.is_empty()
+                    || cpuinfo_number(cpuinfo, "CPU part")
+                        .is_some_and(|part| parts.contains(&part)))
+        }
+        CpuMatch::Uarch(prefix) => cpuinfo_value(cpuinfo, "uarch")
+            .is_some_and(|uarch| uarch.starts_with(prefix)),
+    })
+}
+
+/// Find the preset for the processor this is running on
+///
+/// # Returns:
+/// Ok(Some(preset)) if one matches, Ok(None) if none does, otherwise the
+/// error from reading PROC_CPUINFO
+pub fn detect_preset() -> io::Result<Option<&'static CachePreset>> {
+    Ok(match_cpuinfo(&fs::read_to_string(PROC_CPUINFO)?))
+}
+
+impl<const N: usize, const W: usize, const S: usize, D, A>
+    CacheBase<N, W, S, D, A> for CachePreset
+where
+    D: DataImplTrait<D>,
+    A: AddrImplTrait<A>,
+    usize: From<A>,
+{
+    // The cache line size is a type parameter of the scrubber, so a preset
+    // can only be used by a scrubber built for its line size
+    fn check_cache_params(&self) -> Result<(), Error> {
+        match S == self.line_size {
+            true => Ok(()),
+            false => Err(Error::UnalignedValue),
+        }
+    }
+
+    fn cache_index_width(&self) -> usize {
+        self.index_width
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ZEN3: &str = "processor\t: 0\n\
+                        vendor_id\t: AuthenticAMD\n\
+                        cpu family\t: 25\n\
+                        model\t\t: 33\n\
+                        model name\t: AMD Ryzen 9 5950X 16-Core Processor\n";
+    const A72: &str = "processor\t: 0\n\
+                       BogoMIPS\t: 108.00\n\
+                       CPU implementer\t: 0x41\n\
+                       CPU architecture: 8\n\
+                       CPU part\t: 0xd08\n";
+    const U74: &str = "processor\t: 0\n\
+                       hart\t\t: 1\n\
+                       isa\t\t: rv64imafdc\n\
+                       uarch\t\t: sifive,u74-mc\n";
+
+    #[test]
+    fn test_presets() {
+        for p in PRESETS {
+            assert!(p.line_size.is_power_of_two(), "{}", p.name);
+            assert_eq!(preset(p.name), Some(p));
+        }
+        assert_eq!(preset("Zen4").unwrap().cache_size(), 1024 * 1024);
+        assert_eq!(preset("skylake").unwrap().cache_size(), 256 * 1024);
+        assert_eq!(preset("pentium"), None);
+    }
+
+    #[test]
+    fn test_match() {
+        assert_eq!(match_cpuinfo(ZEN3).unwrap().name, "zen3");
+        assert_eq!(match_cpuinfo(A72).unwrap().name, "cortex-a72");
+        assert_eq!(match_cpuinfo(U74).unwrap().name, "sifive-u74");
+        assert_eq!(match_cpuinfo(&ZEN3.replace("25", "23")), None);
+        assert_eq!(match_cpuinfo(""), None);
+    }
+}
@@ -0,0 +1,307 @@
+// Scrub areas for the segments of this process. /proc/self/maps lists
+// every mapping of the process, and each is classified as the text,
+// read-only data, data or bss of the executable or a library, the heap,
+// a stack, or other anonymous memory. An application that wants its own
+// state protected, but not, say, a large mmapped cache, can add just its
+// data, bss and heap to a LineScrubber as labeled scrub areas.
+//
+// The kernel's special mappings, such as [vvar], and mappings that can't
+// be read are never added: reading them can fault.
+
+use std::fs;
+use std::io;
+
+use crate::addr::*;
+use crate::backend::*;
+use crate::base::*;
+
+const MAPS_PATH: &str = "/proc/self/maps";
+
+/// What a mapping of the process holds
+///
+/// * `Text` - Executable code of a file
+///
+/// * `ReadOnly` - Read-only data of a file
+///
+/// * `Data` - Writable data of a file, including any part of its bss
+///   that shares a page with the data
+///
+/// * `Bss` - The rest of the bss of a file, mapped anonymously just after
+///   its data
+///
+/// * `Heap` - The heap grown by brk()
+///
+/// * `Stack` - A stack
+///
+/// * `Anonymous` - Other anonymous memory, such as from mmap()
+///
+/// * `Special` - Memory the kernel maps into the process, such as [vdso]
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SegmentKind {
+    Text,
+    ReadOnly,
+    Data,
+    Bss,
+    Heap,
+    Stack,
+    Anonymous,
+    Special,
+}
+
+/// A mapping of the process
+///
+/// * `start` - First address of the mapping
+///
+/// * `end` - Last address of the mapping
+///
+/// * `readable` - Whether the mapping can be read
+///
+/// * `writable` - Whether the mapping can be written
+///
+/// * `path` - File or pseudo-file mapped, if any
+///
+/// * `kind` - What the mapping holds
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProcessSegment {
+    pub start: usize,
+    pub end: usize,
+    pub readable: bool,
+    pub writable: bool,
+    pub path: Option<String>,
+    pub kind: SegmentKind,
+}
+
+impl ProcessSegment {
+    /// Returns a label for the segment, such as "data:/usr/bin/app" or
+    /// "heap"
+    pub fn label(&self) -> String {
+        let kind = match self.kind {
+            SegmentKind::Text => "text",
+            SegmentKind::ReadOnly => "rodata",
+            SegmentKind::Data => "data",
+            SegmentKind::Bss => "bss",
+            SegmentKind::Heap => "heap",
+            SegmentKind::Stack => "stack",
+            SegmentKind::Anonymous => "anon",
+            SegmentKind::Special => "special",
+        };
+        match (&self.path, self.kind) {
+            (Some(path), SegmentKind::Text)
+            | (Some(path), SegmentKind::ReadOnly)
+            | (Some(path), SegmentKind::Data)
+            | (Some(path), SegmentKind::Bss) => {
+                format!("{}:{}", kind, path)
+            }
+            _ => kind.to_string(),
+        }
+    }
+}
+
+/// Parse the text of a maps file. Lines that can't be parsed are ignored.
+///
+/// # Arguments:
+/// * `text` - Contents of a /proc/<pid>/maps file
+///
+/// # Returns:
+/// The mappings, in address order
+pub fn parse_maps(text: &str) -> Vec<ProcessSegment> {
+    let mut segments: Vec<ProcessSegment> = Vec::new();
+    for line in text.lines() {
+        let mut fields = line.split_whitespace();
+        let (range, perms) = match (fields.next(), fields.next()) {
+            (Some(range), Some(perms)) => (range, perms.as_bytes()),
+            _ => continue,
+        };
+        // The path may contain spaces, as in "/tmp/a b (deleted)"
+        let path: Vec<&str> = fields.skip(3).collect();
+        let path = match path.is_empty() {
+            true => None,
+            false => Some(path.join(" ")),
+        };
+        let (start, end) = match parse_range(range) {
+            Some((start, end)) if end > start && perms.len() >= 3 => {
+                (start, end)
+            }
+            _ => continue,
+        };
+        let readable = perms[0] == b'r';
+        let writable = perms[1] == b'w';
+        let executable = perms[2] == b'x';
+
+        let kind = match path.as_deref() {
+            Some("[heap]") => SegmentKind::Heap,
+            Some(p) if p.starts_with("[stack") => SegmentKind::Stack,
+            Some(p) if p.starts_with('[') => SegmentKind::Special,
+            Some(_) if executable => SegmentKind::Text,
+            Some(_) if writable => SegmentKind::Data,
+            Some(_) => SegmentKind::ReadOnly,
+            None => SegmentKind::Anonymous,
+        };
+
+        // Anonymous memory right after the data of a file is its bss
+        let (kind, path) = match segments.last() {
+            Some(prev)
+                if kind == SegmentKind::Anonymous
+                    && writable
+                    && prev.kind == SegmentKind::Data
+                    && prev.end + 1 == start =>
+            {
+                (SegmentKind::Bss, prev.path.clone())
+            }
+            _ => (kind, path),
+        };
+
+        segments.push(ProcessSegment {
+            start,
+            end: end - 1,
+            readable,
+            writable,
+            path,
+            kind,
+        });
+    }
+    segments
+}
+
+// Parse an address range, "start-end" in hex with the end exclusive
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    let (start, end) = range.split_once('-')?;
+    Some((parse_number(start, 16)?, parse_number(end, 16)?))
+}
+
+/// Returns the mappings of this process, from /proc/self/maps
+pub fn self_segments() -> io::Result<Vec<ProcessSegment>> {
+    Ok(parse_maps(&fs::read_to_string(MAPS_PATH)?))
+}
+
+/// Add the readable segments of the given kinds as labeled scrub areas.
+/// Special segments are never added.
+///
+/// # Arguments:
+/// * `scrubber` - The scrubber, whose backend must be able to read the
+///   segments, as a RawBackend in this process can
+///
+/// * `segments` - Segments of the process, from self_segments()
+///
+/// * `kinds` - Kinds of segment to add
+///
+/// # Returns:
+/// Ok(areas) with the index of the scrub area added for each segment,
+/// otherwise Err(Error) if adding one failed
+pub fn add_segments<B: ScrubBackend>(
+    scrubber: &mut LineScrubber<B>,
+    segments: &[ProcessSegment],
+    kinds: &[SegmentKind],
+) -> Result<Vec<usize>, Error> {
+    let mut areas = Vec::new();
+    for segment in segments.iter().filter(|s| {
+        s.readable
+            && s.kind != SegmentKind::Special
+            && kinds.contains(&s.kind)
+    }) {
+        let area = scrubber.add_area(segment.start, segment.end)?;
+        scrubber.stats_mut().areas[area].label = Some(segment.label());
+        areas.push(area);
+    }
+    Ok(areas)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const MAPS: &str = "\
+55d0c0a00000-55d0c0a02000 r--p 00000000 fd:01 1234  /usr/bin/app
+55d0c0a02000-55d0c0a08000 r-xp 00002000 fd:01 1234  /usr/bin/app
+55d0c0a08000-55d0c0a0a000 r--p 00008000 fd:01 1234  /usr/bin/app
+55d0c0a0a000-55d0c0a0b000 rw-p 0000a000 fd:01 1234  /usr/bin/app
+55d0c0a0b000-55d0c0a0d000 rw-p 00000000 00:00 0
+55d0c1000000-55d0c1021000 rw-p 00000000 00:00 0    [heap]
+7f0000000000-7f0040000000 rw-p 00000000 00:00 0
+7f0040000000-7f0040001000 ---p 00000000 00:00 0
+7ffd00000000-7ffd00021000 rw-p 00000000 00:00 0    [stack]
+7ffd00100000-7ffd00104000 r--p 00000000 00:00 0    [vvar]
+garbage
+";
+
+    #[test]
+    fn test_parse() {
+        let segments = parse_maps(MAPS);
+        let kinds: Vec<SegmentKind> =
+            segments.iter().map(|s| s.kind).collect();
+        assert_eq!(
+            kinds,
+            [
+                SegmentKind::ReadOnly,
+                SegmentKind::Text,
+                SegmentKind::ReadOnly,
+                SegmentKind::Data,
+                SegmentKind::Bss,
+                SegmentKind::Heap,
+                SegmentKind::Anonymous,
+                SegmentKind::Anonymous,
+                SegmentKind::Stack,
+                SegmentKind::Special,
+            ]
+        );
+        assert_eq!(segments[3].start, 0x55d0c0a0a000);
+        assert_eq!(segments[3].end, 0x55d0c0a0afff);
+        assert_eq!(segments[4].label(), "bss:/usr/bin/app");
+        assert_eq!(segments[5].label(), "heap");
+        assert!(!segments[7].readable);
+    }
+
+    #[test]
+    fn test_add() {
+        struct Nothing;
+
+        impl ScrubBackend for Nothing {
+            fn read_line(&mut self, _addr: usize) -> Result<(), Error> {
+                Ok(())
+            }
+        }
+
+        let segments = parse_maps(MAPS);
+        let mut scrubber =
+            LineScrubber::new(Nothing, &[(0, 4095)], 64, 6).unwrap();
+        let areas = add_segments(
+            &mut scrubber,
+            &segments,
+            &[SegmentKind::Data, SegmentKind::Bss, SegmentKind::Heap],
+        )
+        .unwrap();
+        assert_eq!(areas, [1, 2, 3]);
+        let labels: Vec<Option<&str>> = scrubber.stats().areas[1..]
+            .iter()
+            .map(|a| a.label.as_deref())
+            .collect();
+        assert_eq!(
+            labels,
+            [
+                Some("data:/usr/bin/app"),
+                Some("bss:/usr/bin/app"),
+                Some("heap")
+            ]
+        );
+    }
+
+    #[test]
+    fn test_self() {
+        // A writable static is in the data or bss of the test binary
+        static STATE: AtomicUsize = AtomicUsize::new(0);
+        STATE.fetch_add(1, Ordering::Relaxed);
+        let addr = &STATE as *const AtomicUsize as usize;
+
+        let segments = self_segments().unwrap();
+        let segment = segments
+            .iter()
+            .find(|s| s.start <= addr && addr <= s.end)
+            .unwrap();
+        assert!(matches!(
+            segment.kind,
+            SegmentKind::Data | SegmentKind::Bss
+        ));
+    }
+}
@@ -0,0 +1,158 @@
+// Energy counters of Running Average Power Limit (RAPL), read through the
+// Linux powercap class. Each processor package is a top level zone, such
+// as intel-rapl:0, whose energy_uj counts the microjoules used by the
+// package and wraps round at max_energy_range_uj. AMD processors report
+// through the same intel-rapl zones. Reading energy_uj needs root on
+// recent kernels, since energy can leak what other processes are doing.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::energy::*;
+
+const POWERCAP_PATH: &str = "/sys/class/powercap";
+
+// RaplZone: One package's counter
+// path: Directory of the zone
+// range: Value at which energy_uj wraps round, in microjoules
+// last: Last value read, in microjoules
+// total: Microjoules counted since the zone was first read
+#[derive(Debug)]
+struct RaplZone {
+    path: PathBuf,
+    range: u64,
+    last: u64,
+    total: u64,
+}
+
+impl RaplZone {
+    // Read the zone's counter, counting the energy used since it was last
+    // read
+    fn update(&mut self) -> io::Result<u64> {
+        let now = read_uj(&self.path.join("energy_uj"))?;
+        let used = match now >= self.last {
+            true => now - self.last,
+            false => now + self.range.saturating_sub(self.last),
+        };
+        self.total += used;
+        self.last = now;
+        Ok(self.total)
+    }
+}
+
+/// The energy used by all processor packages, from their RAPL counters
+///
+/// * `zones` - Counter of each package
+#[derive(Debug)]
+pub struct RaplCounter {
+    zones: Vec<RaplZone>,
+}
+
+impl RaplCounter {
+    /// Use the package zones in the standard sysfs location
+    pub fn new() -> io::Result<RaplCounter> {
+        RaplCounter::with_path(POWERCAP_PATH)
+    }
+
+    /// Use the package zones in the given directory
+    ///
+    /// # Returns:
+    /// Ok(RaplCounter) on success, otherwise Err(io::Error) if there are
+    /// no package zones or their counters can't be read
+    pub fn with_path<P: AsRef<Path>>(path: P) -> io::Result<RaplCounter> {
+        let mut zones = Vec::new();
+        for entry in fs::read_dir(path)? {
+            let path = entry?.path();
+            let name = match path.file_name().and_then(|n| n.to_str()) {
+                Some(name) => name.to_string(),
+                None => continue,
+            };
+            // Subzones, such as intel-rapl:0:0 for the cores, are counted
+            // in their package already
+            if !name.starts_with("intel-rapl:")
+                || name.matches(':').count() != 1
+            {
+                continue;
+            }
+            let last = read_uj(&path.join("energy_uj"))?;
+            let range = read_uj(&path.join("max_energy_range_uj"))?;
+            zones.push(RaplZone {
+                path,
+                range,
+                last,
+                total: 0,
+            });
+        }
+        if zones.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no RAPL package zones",
+            ));
+        }
+        zones.sort_by(|a, b| a.path.cmp(&b.path));
+        Ok(RaplCounter { zones })
+    }
+
+    /// Returns the directory of each package zone
+    pub fn zones(&self) -> Vec<&Path> {
+        self.zones.iter().map(|z| z.path.as_path()).collect()
+    }
+}
+
+impl EnergyCounter for RaplCounter {
+    fn joules(&mut self) -> io::Result<f64> {
+        let mut total = 0;
+        for zone in &mut self.zones {
+            total += zone.update()?;
+        }
+        Ok(total as f64 / 1e6)
+    }
+}
+
+// Read a number of microjoules
+fn read_uj(path: &Path) -> io::Result<u64> {
+    let text = fs::read_to_string(path)?;
+    text.trim().parse().map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidData, text.trim().to_string())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    fn zone(dir: &Path, name: &str, uj: u64, range: u64) {
+        let path = dir.join(name);
+        fs::create_dir_all(&path).unwrap();
+        fs::write(path.join("energy_uj"), format!("{}\n", uj)).unwrap();
+        fs::write(
+            path.join("max_energy_range_uj"),
+            format!("{}\n", range),
+        )
+        .unwrap();
+    }
+
+    #[test]
+    fn test_rapl() {
+        let dir = scratch_path("rapl");
+        fs::create_dir_all(&dir).unwrap();
+        assert!(RaplCounter::with_path(&dir).is_err());
+
+        zone(&dir, "intel-rapl:0", 1_000_000, 10_000_000);
+        zone(&dir, "intel-rapl:1", 0, 10_000_000);
+        zone(&dir, "intel-rapl:0:0", 0, 10_000_000);
+        let mut rapl = RaplCounter::with_path(&dir).unwrap();
+        assert_eq!(rapl.zones().len(), 2);
+        assert_eq!(rapl.joules().unwrap(), 0.0);
+
+        // Package 0 wraps round
+        zone(&dir, "intel-rapl:0", 500_000, 10_000_000);
+        zone(&dir, "intel-rapl:1", 2_000_000, 10_000_000);
+        assert_eq!(rapl.joules().unwrap(), 11.5);
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(rapl.joules().is_err());
+    }
+}
@@ -0,0 +1,196 @@
+// Import of the corrected-error history kept by rasdaemon. rasdaemon records
+// each EDAC memory error in the mc_event table of an SQLite database. That
+// history is used to estimate an error rate for each scrub area so that a
+// newly started scrubber can favor memory already known to be weak.
+//
+// Addresses in the database are physical addresses, so the area extents
+// used here must be physical too.
+
+use rusqlite::{Connection, OpenFlags};
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::stats::*;
+
+/// Default location of the rasdaemon database
+pub const RASDAEMON_DB: &str = "/var/lib/rasdaemon/ras-mc_event.db";
+
+// The smallest period over which a rate is computed. This keeps a single
+// recent error from producing an absurd rate.
+const MIN_WINDOW_SECS: f64 = 3600.0;
+
+// Convert a rasdaemon timestamp, such as "2023-05-01 12:34:56 +0200", to
+// seconds since the Unix epoch
+fn parse_timestamp(s: &str) -> Option<i64> {
+    let mut parts = s.split_whitespace();
+    let date: Vec<i64> = parts
+        .next()?
+        .split('-')
+        .map(|v| v.parse().ok())
+        .collect::<Option<_>>()?;
+    let time: Vec<i64> = parts
+        .next()?
+        .split(':')
+        .map(|v| v.parse().ok())
+        .collect::<Option<_>>()?;
+    if date.len() != 3 || time.len() != 3 {
+        return None;
+    }
+    let offset = match parts.next() {
+        None => 0,
+        Some(tz) => {
+            let sign = if tz.starts_with('-') { -1 } else { 1 };
+            let tz: i64 =
+                tz.trim_start_matches(['+', '-']).parse().ok()?;
+            sign * ((tz / 100) * 3600 + (tz % 100) * 60)
+        }
+    };
+
+    // Days from the civil calendar date, after Howard Hinnant
+    let (y, m, d) = (date[0], date[1], date[2]);
+    let y = if m <= 2 { y - 1 } else { y };
+    let era = y.div_euclid(400);
+    let yoe = y - era * 400;
+    let mp = (m + 9) % 12;
+    let doy = (153 * mp + 2) / 5 + d - 1;
+    let doe = yoe * 365 + yoe / 4 - yoe / 100 + doy;
+    let days = era * 146097 + doe - 719468;
+
+    Some(days * 86400 + time[0] * 3600 + time[1] * 60 + time[2] - offset)
+}
+
+/// Read the rasdaemon error history and attribute it to scrub areas
+///
+/// # Arguments:
+/// * `path` - Path of the rasdaemon database, usually RASDAEMON_DB
+///
+/// * `extents` - (start, end) physical address of each scrub area, end
+///   inclusive
+///
+/// # Returns:
+/// The error history for the areas, or the SQLite error
+pub fn import_rasdaemon<P: AsRef<Path>>(
+    path: P,
+    extents: &[(u64, u64)],
+) -> rusqlite::Result<ErrorHistory> {
+    let conn = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY,
+    )?;
+    let mut stmt = conn.prepare(
+        "SELECT timestamp, err_count, err_type, address FROM mc_event",
+    )?;
+    let rows = stmt.query_map([], |row| {
+        Ok((
+            row.get::<_, String>(0)?,
+            row.get::<_, i64>(1)?,
+            row.get::<_, String>(2)?,
+            row.get::<_, i64>(3)?,
+        ))
+    })?;
+
+    let mut history = ErrorHistory {
+        areas: vec![AreaErrorHistory::default(); extents.len()],
+        unmatched: 0,
+    };
+    let mut oldest: Option<i64> = None;
+
+    for row in rows {
+        let (timestamp, count, err_type, addr) = row?;
+        let count = count.max(1) as u64;
+        let addr = addr as u64;
+
+        if let Some(t) = parse_timestamp(&timestamp) {
+            oldest = Some(oldest.map_or(t, |o| o.min(t)));
+        }
+
+        let area = extents
+            .iter()
+            .position(|(start, end)| *start <= addr && addr <= *end);
+        match area {
+            None => history.unmatched += count,
+            Some(i) => {
+                if err_type == "Corrected" {
+                    history.areas[i].errors_corrected += count;
+                } else if err_type != "Info" {
+                    history.areas[i].errors_uncorrected += count;
+                }
+            }
+        }
+    }
+
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs() as i64;
+    let window = oldest
+        .map_or(0.0, |o| (now - o) as f64)
+        .max(MIN_WINDOW_SECS);
+    for area in history.areas.iter_mut() {
+        area.error_rate = area.errors_corrected as f64 * 3600.0 / window;
+    }
+
+    Ok(history)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+    use std::fs;
+    use std::time::Instant;
+
+    #[test]
+    fn test_parse_timestamp() {
+        assert_eq!(parse_timestamp("1970-01-01 00:00:00 +0000"), Some(0));
+        assert_eq!(
+            parse_timestamp("2023-05-01 12:34:56 +0200"),
+            Some(1682937296)
+        );
+        assert_eq!(
+            parse_timestamp("2000-03-01 00:00:00"),
+            Some(951868800)
+        );
+        assert_eq!(parse_timestamp("garbage"), None);
+    }
+
+    #[test]
+    fn test_import() {
+        let path = scratch_path("rasdaemon.db");
+        let _ = fs::remove_file(&path);
+        {
+            let conn = Connection::open(&path).unwrap();
+            conn.execute_batch(
+                "CREATE TABLE mc_event (id INTEGER PRIMARY KEY, \
+                    timestamp TEXT, err_count INTEGER, err_type TEXT, \
+                    err_msg TEXT, label TEXT, mc INTEGER, \
+                    top_layer INTEGER, middle_layer INTEGER, \
+                    lower_layer INTEGER, address INTEGER, grain INTEGER, \
+                    syndrome INTEGER, driver_detail TEXT);
+                 INSERT INTO mc_event (timestamp, err_count, err_type, \
+                    address) VALUES
+                    ('2020-01-01 00:00:00 +0000', 3, 'Corrected', 4096),
+                    ('2020-01-02 00:00:00 +0000', 1, 'Corrected', 8192),
+                    ('2020-01-03 00:00:00 +0000', 1, 'Uncorrected', 8192),
+                    ('2020-01-04 00:00:00 +0000', 1, 'Corrected', 65536);",
+            )
+            .unwrap();
+        }
+
+        let history =
+            import_rasdaemon(&path, &[(0, 4095), (4096, 16383)]).unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(history.unmatched, 1);
+        assert_eq!(history.areas[0], AreaErrorHistory::default());
+        assert_eq!(history.areas[1].errors_corrected, 4);
+        assert_eq!(history.areas[1].errors_uncorrected, 1);
+        assert!(history.areas[1].error_rate > 0.0);
+
+        let mut stats = ScrubStats::new(&[4096, 12288], Instant::now());
+        history.seed(&mut stats);
+        assert_eq!(stats.areas[0].priority, 0);
+        assert_eq!(stats.areas[1].priority, 1);
+        assert_eq!(stats.errors_corrected(), 4);
+    }
+}
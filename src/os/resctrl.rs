@@ -0,0 +1,228 @@
+// Cooperation with cache allocation technology through the Linux resctrl
+// filesystem. Scrubbing reads every cache line and so fills the last level
+// cache with lines nothing else wants, evicting the workload's. Confining
+// the scrub thread to a class of service (CLOS) with a small L3 partition
+// limits the damage to that partition. A resource group is a directory in
+// /sys/fs/resctrl: its schemata file holds the capacity bitmask for each
+// cache and writing a thread ID to its tasks file moves the thread into
+// it. resctrl must be mounted and changing it requires root.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::addr::*;
+
+const RESCTRL_PATH: &str = "/sys/fs/resctrl";
+
+/// A resource group to confine a scrub thread to
+///
+/// * `group` - Name of the resource group, created if it doesn't exist
+///
+/// * `l3_mask` - L3 capacity bitmask to give the group on every cache, or
+///   None to join the group as it is
+#[derive(Clone, Debug, PartialEq)]
+pub struct CacheAllocation {
+    pub group: String,
+    pub l3_mask: Option<u64>,
+}
+
+/// The cache partition a scrub thread is confined to
+///
+/// * `group` - Name of the resource group
+///
+/// * `schemata` - Each line of the group's schemata file, such as
+///   "L3:0=3;1=3"
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CachePartition {
+    pub group: String,
+    pub schemata: Vec<String>,
+}
+
+impl CachePartition {
+    /// Returns the L3 capacity bitmask for each cache, by cache ID
+    pub fn l3_masks(&self) -> Vec<(u32, u64)> {
+        self.schemata
+            .iter()
+            .filter_map(|line| line.strip_prefix("L3:"))
+            .flat_map(|masks| masks.split(';'))
+            .filter_map(|mask| {
+                let (id, mask) = mask.split_once('=')?;
+                Some((id.trim().parse().ok()?, parse_number(mask, 16)?))
+            })
+            .collect()
+    }
+}
+
+/// Interface to the resctrl filesystem
+///
+/// * `path` - Directory at which resctrl is mounted
+pub struct Resctrl {
+    path: PathBuf,
+}
+
+// Returns the ID of the calling thread
+#[cfg(target_os = "linux")]
+fn thread_id() -> io::Result<u64> {
+    // SAFETY: gettid takes no arguments and can't fail
+    Ok(unsafe { libc::gettid() } as u64)
+}
+
+#[cfg(not(target_os = "linux"))]
+fn thread_id() -> io::Result<u64> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "resctrl not supported on this system",
+    ))
+}
+
+impl Resctrl {
+    /// Use resctrl mounted at its usual place
+    pub fn new() -> Resctrl {
+        Resctrl::with_path(RESCTRL_PATH)
+    }
+
+    /// Use resctrl mounted at the given directory
+    pub fn with_path<P: AsRef<Path>>(path: P) -> Resctrl {
+        Resctrl {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Create a resource group, or use it if it already exists, and give
+    /// it an L3 partition
+    ///
+    /// # Arguments:
+    /// * `group` - Name of the resource group
+    ///
+    /// * `l3_mask` - Capacity bitmask to use on every L3 cache, or None to
+    ///   leave the group's partition as it is
+    ///
+    /// # Returns:
+    /// Ok(CachePartition) with the resulting partition, otherwise
+    /// Err(io::Error)
+    pub fn create_group(
+        &self,
+        group: &str,
+        l3_mask: Option<u64>,
+    ) -> io::Result<CachePartition> {
+        let dir = self.path.join(group);
+        match fs::create_dir(&dir) {
+            Err(e) if e.kind() != io::ErrorKind::AlreadyExists => {
+                return Err(e)
+            }
+            _ => {}
+        }
+
+        if let Some(mask) = l3_mask {
+            // Caches not given a mask keep the default of all ways
+            let root = CachePartition {
+                group: String::new(),
+                schemata: read_schemata(&self.path)?,
+            };
+            let masks: Vec<String> = root
+                .l3_masks()
+                .iter()
+                .map(|(id, _)| format!("{}={:x}", id, mask))
+                .collect();
+            if masks.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::Unsupported,
+                    "no L3 cache allocation",
+                ));
+            }
+            fs::write(
+                dir.join("schemata"),
+                format!("L3:{}\n", masks.join(";")),
+            )?;
+        }
+
+        self.partition(group)
+    }
+
+    /// Returns the partition of a resource group
+    pub fn partition(&self, group: &str) -> io::Result<CachePartition> {
+        Ok(CachePartition {
+            group: group.to_string(),
+            schemata: read_schemata(&self.path.join(group))?,
+        })
+    }
+
+    /// Move the calling thread into a resource group
+    pub fn join(&self, group: &str) -> io::Result<()> {
+        fs::write(
+            self.path.join(group).join("tasks"),
+            format!("{}\n", thread_id()?),
+        )
+    }
+
+    /// Create or use a resource group as described and move the calling
+    /// thread into it
+    pub fn apply(
+        &self,
+        allocation: &CacheAllocation,
+    ) -> io::Result<CachePartition> {
+        let partition =
+            self.create_group(&allocation.group, allocation.l3_mask)?;
+        self.join(&allocation.group)?;
+        Ok(partition)
+    }
+
+    /// Remove a resource group. Threads in it return to the default group.
+    pub fn remove_group(&self, group: &str) -> io::Result<()> {
+        fs::remove_dir(self.path.join(group))
+    }
+}
+
+impl Default for Resctrl {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Read the lines of the schemata file in a resource group directory
+fn read_schemata(dir: &Path) -> io::Result<Vec<String>> {
+    Ok(fs::read_to_string(dir.join("schemata"))?
+        .lines()
+        .map(|line| line.trim().to_string())
+        .filter(|line| !line.is_empty())
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn test_resctrl() {
+        let path = scratch_path("resctrl");
+        fs::create_dir_all(&path).unwrap();
+        fs::write(
+            path.join("schemata"),
+            "    L3:0=fff;1=fff\n  MB:0=100\n",
+        )
+        .unwrap();
+
+        let resctrl = Resctrl::with_path(&path);
+        let allocation = CacheAllocation {
+            group: "scrub".to_string(),
+            l3_mask: Some(0x3),
+        };
+        let partition = resctrl.apply(&allocation).unwrap();
+        assert_eq!(partition.schemata, ["L3:0=3;1=3"]);
+        assert_eq!(partition.l3_masks(), [(0, 0x3), (1, 0x3)]);
+        let tasks =
+            fs::read_to_string(path.join("scrub").join("tasks")).unwrap();
+        assert!(tasks.trim().parse::<u64>().is_ok());
+
+        // Joining an existing group leaves its partition alone
+        let partition = resctrl.create_group("scrub", None).unwrap();
+        assert_eq!(partition.l3_masks(), [(0, 0x3), (1, 0x3)]);
+
+        fs::remove_dir_all(&path).unwrap();
+    }
+}
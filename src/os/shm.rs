@@ -0,0 +1,508 @@
+// Scrub areas for shared memory segments. A scrubbing agent running on its
+// own can protect the segments other processes use to talk to each other
+// by attaching to them and scrubbing them as its own memory. Both POSIX
+// shared memory, named and opened with shm_open(), and System V shared
+// memory, found by its key or ID, are supported. Segments are attached
+// read-only, since scrubbing only reads, so the agent needs only read
+// permission on them.
+//
+// A segment must stay attached for as long as its area is scrubbed, and
+// reading memory that has been detached faults. ShmAreas keeps each
+// segment together with the index of its scrub area, and on removal takes
+// the area out of the scrubber before detaching the segment. All areas of
+// a scrubber using ShmAreas must then be removed through it, so that it
+// can follow the indices of the areas as they move down. Nothing ties the
+// lifetime of the ShmAreas to the scrubber, so adding a segment is unsafe.
+//
+// Another process can truncate a POSIX object while it is mapped, and
+// reading a page of the mapping past the new end faults. The object is
+// kept open so that its size can be checked again, and ShmAreas::scrub()
+// does so before each chunk, shrinking the area to match.
+
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::ptr::{self, NonNull};
+
+use crate::area::*;
+use crate::backend::*;
+use crate::base::*;
+
+/// The kind of a shared memory segment
+///
+/// * `Posix` - A POSIX shared memory object, opened by name
+///
+/// * `SysV` - A System V shared memory segment, attached by ID
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ShmKind {
+    Posix,
+    SysV,
+}
+
+/// A shared memory segment attached read-only to this process. It is
+/// detached when dropped.
+///
+/// * `addr` - Address at which the segment is attached
+///
+/// * `len` - Number of bytes in the segment
+///
+/// * `kind` - Kind of the segment
+///
+/// * `label` - Label for the segment, such as "shm:/ring" or "sysv:42"
+///
+/// * `fd` - The open POSIX object, for checking its size
+#[derive(Debug)]
+pub struct ShmSegment {
+    addr: *mut libc::c_void,
+    len: usize,
+    kind: ShmKind,
+    label: String,
+    fd: Option<OwnedFd>,
+}
+
+// The segment is only read through its address, which is valid in any
+// thread
+unsafe impl Send for ShmSegment {}
+
+impl ShmSegment {
+    /// Open and map a POSIX shared memory object
+    ///
+    /// # Arguments:
+    /// * `name` - Name of the object, such as "/ring"
+    ///
+    /// # Returns:
+    /// Ok(ShmSegment) on success, otherwise Err(io::Error) if the object
+    /// can't be opened or mapped, or is empty
+    pub fn open_posix(name: &str) -> io::Result<ShmSegment> {
+        let cname = CString::new(name)
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidInput))?;
+        let fd =
+            unsafe { libc::shm_open(cname.as_ptr(), libc::O_RDONLY, 0) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        // Nothing else owns the descriptor just opened
+        let fd = unsafe { OwnedFd::from_raw_fd(fd) };
+        let (addr, len) = map_fd(fd.as_raw_fd())?;
+        Ok(ShmSegment {
+            addr,
+            len,
+            kind: ShmKind::Posix,
+            label: format!("shm:{}", name),
+            fd: Some(fd),
+        })
+    }
+
+    /// Attach a System V shared memory segment
+    ///
+    /// # Arguments:
+    /// * `id` - ID of the segment, as from shmget() or ipcs
+    ///
+    /// # Returns:
+    /// Ok(ShmSegment) on success, otherwise Err(io::Error) if the segment
+    /// can't be attached
+    pub fn attach_sysv(id: i32) -> io::Result<ShmSegment> {
+        let mut ds: libc::shmid_ds = unsafe { mem::zeroed() };
+        if unsafe { libc::shmctl(id, libc::IPC_STAT, &mut ds) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let len = ds.shm_segsz as usize;
+        if len == 0 {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let addr =
+            unsafe { libc::shmat(id, ptr::null(), libc::SHM_RDONLY) };
+        if addr as isize == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(ShmSegment {
+            addr,
+            len,
+            kind: ShmKind::SysV,
+            label: format!("sysv:{}", id),
+            fd: None,
+        })
+    }
+
+    /// Attach the System V shared memory segment with a given key
+    ///
+    /// # Arguments:
+    /// * `key` - Key of the segment, as from ftok()
+    ///
+    /// # Returns:
+    /// As for attach_sysv()
+    pub fn attach_sysv_key(key: libc::key_t) -> io::Result<ShmSegment> {
+        let id = unsafe { libc::shmget(key, 0, 0) };
+        if id < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        ShmSegment::attach_sysv(id)
+    }
+
+    /// Returns the kind of the segment
+    pub fn kind(&self) -> ShmKind {
+        self.kind
+    }
+
+    /// Returns the label for the segment
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    /// Returns the number of bytes in the segment
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the segment is empty, which it never is
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of bytes of the segment that can be read now.
+    /// A POSIX object may have been truncated since it was mapped. The
+    /// size of a System V segment never changes.
+    ///
+    /// # Returns:
+    /// Ok(len), no more than len(), otherwise Err(io::Error) if the size
+    /// of a POSIX object could not be found
+    pub fn current_len(&self) -> io::Result<usize> {
+        match &self.fd {
+            Some(fd) => Ok(fd_len(fd.as_raw_fd())?.min(self.len)),
+            None => Ok(self.len),
+        }
+    }
+
+    /// Returns the scrub area covering the segment, which takes in any
+    /// partial cache line at its end
+    ///
+    /// # Arguments:
+    /// * `line_size` - Number of bytes in a cache line
+    pub fn area(&self, line_size: usize) -> Result<VirtScrubArea, Error> {
+        let base = NonNull::new(self.addr.cast::<u8>())
+            .ok_or(Error::EmptyMemArea)?;
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        VirtScrubArea::from_pages(
+            base,
+            self.len,
+            line_size,
+            page_size as usize,
+        )
+    }
+}
+
+impl Drop for ShmSegment {
+    fn drop(&mut self) {
+        // Nothing refers to the segment once it is dropped
+        match self.kind {
+            ShmKind::Posix => unsafe { libc::munmap(self.addr, self.len) },
+            ShmKind::SysV => unsafe { libc::shmdt(self.addr) },
+        };
+    }
+}
+
+// Returns the size of an open file
+fn fd_len(fd: libc::c_int) -> io::Result<usize> {
+    let mut stat: libc::stat = unsafe { mem::zeroed() };
+    if unsafe { libc::fstat(fd, &mut stat) } < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.st_size as usize)
+}
+
+// Map the whole of an open file read-only, returning its address and size
+fn map_fd(fd: libc::c_int) -> io::Result<(*mut libc::c_void, usize)> {
+    let len = fd_len(fd)?;
+    if len == 0 {
+        return Err(io::Error::from(io::ErrorKind::InvalidInput));
+    }
+    let addr = unsafe {
+        libc::mmap(
+            ptr::null_mut(),
+            len,
+            libc::PROT_READ,
+            libc::MAP_SHARED,
+            fd,
+            0,
+        )
+    };
+    if addr == libc::MAP_FAILED {
+        return Err(io::Error::last_os_error());
+    }
+    Ok((addr, len))
+}
+
+/// The shared memory segments scrubbed by a LineScrubber, each with the
+/// index of its scrub area. Segments are detached only once their areas
+/// have been removed, so this must outlive any scrubbing of them.
+///
+/// * `segments` - Index of the scrub area of each segment, with the
+///   segment
+#[derive(Debug, Default)]
+pub struct ShmAreas {
+    segments: Vec<(usize, ShmSegment)>,
+}
+
+impl ShmAreas {
+    /// Create an empty set of segments
+    pub fn new() -> ShmAreas {
+        ShmAreas::default()
+    }
+
+    /// Add a segment to a scrubber as a scrub area labeled with the label
+    /// of the segment
+    ///
+    /// # Safety
+    /// The segment is detached when this is dropped, so this must outlive
+    /// any scrubbing of the area by the scrubber. The area must only be
+    /// removed through remove(), and other areas of the scrubber only
+    /// through remove() as well.
+    ///
+    /// # Arguments:
+    /// * `scrubber` - The scrubber, whose backend must read memory of this
+    ///   process, as a RawBackend does
+    ///
+    /// * `segment` - The segment, kept until its area is removed
+    ///
+    /// # Returns:
+    /// Ok(area) with the index of the new scrub area, otherwise Err(Error)
+    /// if it could not be added, in which case the segment is detached
+    pub unsafe fn add<B: ScrubBackend>(
+        &mut self,
+        scrubber: &mut LineScrubber<B>,
+        segment: ShmSegment,
+    ) -> Result<usize, Error> {
+        let (start, end) = segment.area(scrubber.line_size())?.extent();
+        let area = scrubber.add_area(start, end)?;
+        scrubber.stats_mut().areas[area].label =
+            Some(segment.label().to_string());
+        self.segments.push((area, segment));
+        Ok(area)
+    }
+
+    /// Remove a scrub area from a scrubber and detach its segment, if it
+    /// has one. Any area may be removed, and the indices of the segments
+    /// whose areas come after it move down one.
+    ///
+    /// # Arguments:
+    /// * `scrubber` - The scrubber
+    ///
+    /// * `area` - Index of the scrub area
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error) as for
+    /// LineScrubber::remove_area(), in which case nothing is detached
+    pub fn remove<B: ScrubBackend>(
+        &mut self,
+        scrubber: &mut LineScrubber<B>,
+        area: usize,
+    ) -> Result<(), Error> {
+        scrubber.remove_area(area)?;
+        // The area is gone, so the segment can no longer be read
+        self.segments.retain(|(a, _)| *a != area);
+        for (a, _) in self.segments.iter_mut().filter(|(a, _)| *a > area) {
+            *a -= 1;
+        }
+        Ok(())
+    }
+
+    /// Check the size of each segment again and fit its scrub area to the
+    /// part that can still be read, so that the scrubber doesn't fault on
+    /// pages past the end of a truncated POSIX object. The area grows
+    /// back, up to the size mapped, if the object does. A segment with
+    /// nothing left to read has its area removed and is detached.
+    ///
+    /// # Arguments:
+    /// * `scrubber` - The scrubber the segments were added to
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::IoFailed) if the size
+    /// of a segment could not be found, or Err(Error) as for
+    /// LineScrubber::resize_area() or remove()
+    pub fn refresh<B: ScrubBackend>(
+        &mut self,
+        scrubber: &mut LineScrubber<B>,
+    ) -> Result<(), Error> {
+        let line_size = scrubber.line_size();
+        let mut i = 0;
+        while let Some((area, segment)) = self.segments.get(i) {
+            let area = *area;
+            let len =
+                segment.current_len().map_err(|_| Error::IoFailed)?;
+            if len == 0 {
+                self.remove(scrubber, area)?;
+                continue;
+            }
+            let (start, end) = scrubber.extents()[area];
+            let fitted = start + len.div_ceil(line_size) * line_size - 1;
+            if fitted != end {
+                scrubber.resize_area(area, fitted)?;
+            }
+            i += 1;
+        }
+        Ok(())
+    }
+
+    /// Scrub a chunk, as LineScrubber::scrub() does, once the scrub areas
+    /// have been fitted to the segments by refresh()
+    ///
+    /// # Arguments:
+    /// * `scrubber` - The scrubber the segments were added to
+    ///
+    /// * `bytes` - Number of bytes to scrub
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error)
+    pub fn scrub<B: ScrubBackend>(
+        &mut self,
+        scrubber: &mut LineScrubber<B>,
+        bytes: usize,
+    ) -> Result<(), Error> {
+        self.refresh(scrubber)?;
+        scrubber.scrub(bytes)
+    }
+
+    /// Returns the index of the scrub area of the segment with a label,
+    /// if it is attached
+    pub fn find(&self, label: &str) -> Option<usize> {
+        self.segments
+            .iter()
+            .find(|(_, s)| s.label() == label)
+            .map(|&(a, _)| a)
+    }
+
+    /// Returns the index of the scrub area of each segment, with the
+    /// segment
+    pub fn segments(&self) -> impl Iterator<Item = (usize, &ShmSegment)> {
+        self.segments.iter().map(|(a, s)| (*a, s))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::process;
+
+    // Create a POSIX shared memory object of a given size, as another
+    // process would
+    fn create_posix(name: &str, len: usize) {
+        let cname = CString::new(name).unwrap();
+        unsafe {
+            let fd = libc::shm_open(
+                cname.as_ptr(),
+                libc::O_RDWR | libc::O_CREAT,
+                0o600,
+            );
+            assert!(fd >= 0);
+            assert_eq!(libc::ftruncate(fd, len as libc::off_t), 0);
+            libc::close(fd);
+        }
+    }
+
+    fn unlink_posix(name: &str) {
+        let cname = CString::new(name).unwrap();
+        unsafe { libc::shm_unlink(cname.as_ptr()) };
+    }
+
+    #[test]
+    fn test_posix() {
+        let name = format!("/memscrub-shm-{}", process::id());
+        create_posix(&name, 8190);
+        let segment = ShmSegment::open_posix(&name).unwrap();
+        unlink_posix(&name);
+        assert_eq!(segment.kind(), ShmKind::Posix);
+        assert_eq!(segment.len(), 8190);
+        assert_eq!(segment.label(), format!("shm:{}", name));
+
+        // The partial line at the end is scrubbed too
+        let area = segment.area(64).unwrap();
+        assert_eq!(area.lines().get(), 128);
+
+        // Another area, in this process's own memory, stays when the
+        // segment is removed
+        let buffer = vec![0u8; 8192];
+        let own = VirtScrubArea::from_slice(&buffer, 64).unwrap();
+        let mut scrubber = LineScrubber::from_areas(
+            unsafe { RawBackend::new() },
+            &[own],
+            6,
+        )
+        .unwrap();
+        let mut shm = ShmAreas::new();
+        let label = segment.label().to_string();
+        // shm outlives the scrubbing and removes the area
+        let area = unsafe { shm.add(&mut scrubber, segment) }.unwrap();
+        assert_eq!(area, 1);
+        assert_eq!(shm.find(&label), Some(1));
+        assert_eq!(
+            scrubber.stats().areas[1].label.as_deref(),
+            Some(label.as_str())
+        );
+        scrubber.scrub(own.lines().get() * 64 + 8192).unwrap();
+        shm.remove(&mut scrubber, 1).unwrap();
+        assert_eq!(shm.find(&label), None);
+        assert_eq!(scrubber.extents().len(), 1);
+
+        assert!(ShmSegment::open_posix(&name).is_err());
+        assert!(ShmSegment::open_posix("/a\0b").is_err());
+    }
+
+    #[test]
+    fn test_truncate() {
+        let name = format!("/memscrub-shm-trunc-{}", process::id());
+        create_posix(&name, 8192);
+        let segment = ShmSegment::open_posix(&name).unwrap();
+        let cname = CString::new(name.as_str()).unwrap();
+        let fd =
+            unsafe { libc::shm_open(cname.as_ptr(), libc::O_RDWR, 0) };
+        assert!(fd >= 0);
+        unlink_posix(&name);
+        let truncate = |len: libc::off_t| unsafe {
+            assert_eq!(libc::ftruncate(fd, len), 0);
+        };
+
+        let buffer = vec![0u8; 4096];
+        let own = VirtScrubArea::from_slice(&buffer, 64).unwrap();
+        let mut scrubber = LineScrubber::from_areas(
+            unsafe { RawBackend::new() },
+            &[own],
+            6,
+        )
+        .unwrap();
+        let mut shm = ShmAreas::new();
+        // shm outlives the scrubbing and removes the area
+        let area = unsafe { shm.add(&mut scrubber, segment) }.unwrap();
+        let (start, _) = scrubber.extents()[area];
+
+        // Reading the second page would fault once it is truncated away
+        truncate(4000);
+        shm.scrub(&mut scrubber, 2 * 8192).unwrap();
+        assert_eq!(scrubber.extents()[area], (start, start + 4031));
+        assert_eq!(scrubber.stats().areas[area].size, 4032);
+
+        truncate(0);
+        shm.scrub(&mut scrubber, 4096).unwrap();
+        assert_eq!(scrubber.extents().len(), 1);
+        assert_eq!(shm.segments().count(), 0);
+        unsafe { libc::close(fd) };
+    }
+
+    #[test]
+    fn test_sysv() {
+        let id = unsafe {
+            libc::shmget(libc::IPC_PRIVATE, 8192, libc::IPC_CREAT | 0o600)
+        };
+        assert!(id >= 0);
+        let segment = ShmSegment::attach_sysv(id);
+        // Marked for removal, it goes once the last process detaches
+        unsafe { libc::shmctl(id, libc::IPC_RMID, ptr::null_mut()) };
+        let segment = segment.unwrap();
+        assert_eq!(segment.kind(), ShmKind::SysV);
+        assert_eq!(segment.len(), 8192);
+        assert_eq!(segment.label(), format!("sysv:{}", id));
+        let (start, end) = segment.area(64).unwrap().extent();
+        assert_eq!(end - start, 8191);
+    }
+}
@@ -0,0 +1,425 @@
+// Discovery of the memory topology from the SMBIOS tables. Linux exports
+// the raw structure table at /sys/firmware/dmi/tables/DMI, so the modules
+// and the physical addresses they hold can be found without running
+// dmidecode or writing a configuration file. Three structure types are
+// used:
+//
+//  Type 17, Memory Device: one per slot, giving the slot and bank names
+//  and the size of the module installed, if any
+//
+//  Type 19, Memory Array Mapped Address: the physical address ranges
+//  backed by each memory array
+//
+//  Type 20, Memory Device Mapped Address: the physical address range held
+//  by a single module, referring to its type 17 structure
+//
+// Each structure has a four byte header giving its type, the length of its
+// formatted area and its handle, followed by the formatted area and then a
+// set of NUL terminated strings ending with an extra NUL. Fields that hold
+// strings give a one-based index into the set, with zero for none.
+//
+// Firmware that interleaves memory across modules usually has no type 20
+// structures, since no module holds a contiguous range, so the DIMM map
+// can only be built where memory is not interleaved.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::os::dimm::*;
+
+const DMI_PATH: &str = "/sys/firmware/dmi/tables/DMI";
+
+// Structure types
+const MEMORY_DEVICE: u8 = 17;
+const ARRAY_MAPPED_ADDRESS: u8 = 19;
+const DEVICE_MAPPED_ADDRESS: u8 = 20;
+const END_OF_TABLE: u8 = 127;
+
+// Start address giving that the extended addresses are used
+const EXTENDED_ADDRESS: u32 = 0xffffffff;
+
+/// A memory slot, from an SMBIOS Memory Device structure
+///
+/// * `handle` - Handle of the structure
+///
+/// * `locator` - Name of the slot, such as "DIMM_A1"
+///
+/// * `bank` - Name of the bank or channel, if given
+///
+/// * `size` - Number of bytes in the installed module, zero if the slot is
+///   empty, or None if unknown
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryDevice {
+    pub handle: u16,
+    pub locator: String,
+    pub bank: Option<String>,
+    pub size: Option<u64>,
+}
+
+/// A physical address range held by a memory array or a single module
+///
+/// * `start` - Lowest physical address in the range
+///
+/// * `end` - Highest physical address in the range
+///
+/// * `device` - Handle of the memory device holding the range, or None
+///   for the range of a whole memory array
+#[derive(Clone, Debug, PartialEq)]
+pub struct MappedRange {
+    pub start: u64,
+    pub end: u64,
+    pub device: Option<u16>,
+}
+
+/// The memory topology described by the SMBIOS tables
+///
+/// * `devices` - Each memory slot, installed or not
+///
+/// * `arrays` - Address ranges of the memory arrays
+///
+/// * `mapped` - Address ranges of the individual modules
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SmbiosMemory {
+    pub devices: Vec<MemoryDevice>,
+    pub arrays: Vec<MappedRange>,
+    pub mapped: Vec<MappedRange>,
+}
+
+// Error for a malformed table
+fn invalid(what: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, what.to_string())
+}
+
+// Read a little-endian field of a formatted area, if it is long enough
+fn field<const N: usize>(data: &[u8], offset: usize) -> Option<[u8; N]> {
+    data.get(offset..offset + N)?.try_into().ok()
+}
+
+fn u16_at(data: &[u8], offset: usize) -> Option<u16> {
+    field(data, offset).map(u16::from_le_bytes)
+}
+
+fn u32_at(data: &[u8], offset: usize) -> Option<u32> {
+    field(data, offset).map(u32::from_le_bytes)
+}
+
+fn u64_at(data: &[u8], offset: usize) -> Option<u64> {
+    field(data, offset).map(u64::from_le_bytes)
+}
+
+// Returns the string a string field refers to
+fn string_at(
+    data: &[u8],
+    offset: usize,
+    strings: &[String],
+) -> Option<String> {
+    let index = *data.get(offset)? as usize;
+    index.checked_sub(1).and_then(|i| strings.get(i)).cloned()
+}
+
+// Returns the size of a module from a Memory Device structure
+fn device_size(data: &[u8]) -> Option<u64> {
+    match u16_at(data, 0x0c)? {
+        0xffff => None,
+        0x7fff => {
+            u32_at(data, 0x1c).map(|mb| (mb as u64 & 0x7fffffff) << 20)
+        }
+        size if size & 0x8000 != 0 => Some(((size & 0x7fff) as u64) << 10),
+        size => Some((size as u64) << 20),
+    }
+}
+
+// Returns the address range of a mapped address structure, whose start and
+// end are in kilobytes unless the extended addresses, in bytes, are used
+fn mapped_range(data: &[u8], extended: usize) -> Option<(u64, u64)> {
+    let start = u32_at(data, 0x04)?;
+    let end = u32_at(data, 0x08)?;
+    match start {
+        EXTENDED_ADDRESS => {
+            Some((u64_at(data, extended)?, u64_at(data, extended + 8)?))
+        }
+        _ => Some(((start as u64) << 10, ((end as u64) << 10) | 0x3ff)),
+    }
+}
+
+impl SmbiosMemory {
+    /// Parse a raw SMBIOS structure table, as read from
+    /// /sys/firmware/dmi/tables/DMI
+    pub fn parse(table: &[u8]) -> io::Result<SmbiosMemory> {
+        let mut memory = SmbiosMemory::default();
+        let mut rest = table;
+
+        while rest.len() >= 4 {
+            let kind = rest[0];
+            let length = rest[1] as usize;
+            let handle = u16::from_le_bytes([rest[2], rest[3]]);
+            if length < 4 || length > rest.len() {
+                return Err(invalid("bad SMBIOS structure length"));
+            }
+            let data = &rest[..length];
+
+            // The strings end at the first pair of NULs
+            let tail = &rest[length..];
+            let strings_len = tail
+                .windows(2)
+                .position(|w| w == [0, 0])
+                .ok_or_else(|| invalid("unterminated SMBIOS strings"))?;
+            let strings: Vec<String> = tail[..strings_len]
+                .split(|&b| b == 0)
+                .filter(|s| !s.is_empty())
+                .map(|s| String::from_utf8_lossy(s).trim().to_string())
+                .collect();
+            rest = &tail[strings_len + 2..];
+
+            match kind {
+                MEMORY_DEVICE => memory.devices.push(MemoryDevice {
+                    handle,
+                    locator: string_at(data, 0x10, &strings)
+                        .unwrap_or_default(),
+                    bank: string_at(data, 0x11, &strings),
+                    size: device_size(data),
+                }),
+                ARRAY_MAPPED_ADDRESS => {
+                    if let Some((start, end)) = mapped_range(data, 0x0f) {
+                        memory.arrays.push(MappedRange {
+                            start,
+                            end,
+                            device: None,
+                        });
+                    }
+                }
+                DEVICE_MAPPED_ADDRESS => {
+                    let range = mapped_range(data, 0x13);
+                    if let Some(((start, end), device)) =
+                        range.zip(u16_at(data, 0x0c))
+                    {
+                        memory.mapped.push(MappedRange {
+                            start,
+                            end,
+                            device: Some(device),
+                        });
+                    }
+                }
+                END_OF_TABLE => break,
+                _ => {}
+            }
+        }
+        Ok(memory)
+    }
+
+    /// Returns the slots with a module installed
+    pub fn installed(&self) -> Vec<&MemoryDevice> {
+        self.devices
+            .iter()
+            .filter(|d| d.size.is_some_and(|size| size != 0))
+            .collect()
+    }
+
+    /// Returns the total number of bytes in the installed modules whose
+    /// size is known
+    pub fn installed_size(&self) -> u64 {
+        self.devices.iter().filter_map(|d| d.size).sum()
+    }
+
+    /// Build a DIMM map from the address ranges of the individual modules,
+    /// labelled by slot and with the bank as the channel
+    ///
+    /// # Returns:
+    /// Ok(DimmMap), otherwise Err(io::Error) with ErrorKind::NotFound if
+    /// the tables give no module address ranges, or as for DimmMap::new()
+    pub fn dimm_map(&self) -> io::Result<DimmMap> {
+        let dimms: Vec<DimmRange> = self
+            .mapped
+            .iter()
+            .filter_map(|range| {
+                let device = self
+                    .devices
+                    .iter()
+                    .find(|d| Some(d.handle) == range.device)?;
+                Some(DimmRange {
+                    start: range.start,
+                    end: range.end,
+                    label: device.locator.clone(),
+                    channel: device.bank.clone(),
+                    rank: None,
+                })
+            })
+            .collect();
+        if dimms.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no memory device mapped addresses in SMBIOS tables",
+            ));
+        }
+        DimmMap::new(dimms)
+    }
+}
+
+/// Interface to the SMBIOS tables exported by the kernel
+///
+/// * `path` - File holding the raw structure table
+pub struct Smbios {
+    path: PathBuf,
+}
+
+impl Smbios {
+    /// Use the table in /sys/firmware/dmi/tables
+    pub fn new() -> Smbios {
+        Smbios::with_path(DMI_PATH)
+    }
+
+    /// Use the table in the given file, such as a copy saved from another
+    /// system
+    pub fn with_path<P: AsRef<Path>>(path: P) -> Smbios {
+        Smbios {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Read the memory topology. Reading the table usually requires root.
+    pub fn memory(&self) -> io::Result<SmbiosMemory> {
+        SmbiosMemory::parse(&fs::read(&self.path)?)
+    }
+
+    /// Read the memory topology and build a DIMM map from it
+    pub fn dimm_map(&self) -> io::Result<DimmMap> {
+        self.memory()?.dimm_map()
+    }
+}
+
+impl Default for Smbios {
+    fn default() -> Self {
+        Smbios::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::stats::*;
+    use crate::testutil::*;
+    use std::time::Instant;
+
+    // Append a structure to a table
+    fn structure(
+        table: &mut Vec<u8>,
+        kind: u8,
+        handle: u16,
+        fields: &[u8],
+        strings: &[&str],
+    ) {
+        table.push(kind);
+        table.push(4 + fields.len() as u8);
+        table.extend_from_slice(&handle.to_le_bytes());
+        table.extend_from_slice(fields);
+        for s in strings {
+            table.extend_from_slice(s.as_bytes());
+            table.push(0);
+        }
+        if strings.is_empty() {
+            table.push(0);
+        }
+        table.push(0);
+    }
+
+    // Formatted area of a Memory Device structure, after the header
+    fn device(size: u16, extended: u32) -> Vec<u8> {
+        let mut fields = vec![0; 0x24];
+        fields[0x0c - 4..0x0e - 4].copy_from_slice(&size.to_le_bytes());
+        fields[0x10 - 4] = 1;
+        fields[0x11 - 4] = 2;
+        fields[0x1c - 4..0x20 - 4]
+            .copy_from_slice(&extended.to_le_bytes());
+        fields
+    }
+
+    // Formatted area of a Memory Device Mapped Address structure
+    fn mapped(start_kb: u32, end_kb: u32, device: u16) -> Vec<u8> {
+        let mut fields = vec![0; 0x23 - 4];
+        fields[0..4].copy_from_slice(&start_kb.to_le_bytes());
+        fields[4..8].copy_from_slice(&end_kb.to_le_bytes());
+        fields[8..10].copy_from_slice(&device.to_le_bytes());
+        fields
+    }
+
+    fn table() -> Vec<u8> {
+        let mut table = Vec::new();
+        structure(&mut table, 0, 0, &[0; 20], &["BIOS vendor"]);
+        structure(
+            &mut table,
+            MEMORY_DEVICE,
+            0x40,
+            &device(1024, 0),
+            &["DIMM_A1", "BANK 0"],
+        );
+        structure(
+            &mut table,
+            MEMORY_DEVICE,
+            0x41,
+            &device(0x7fff, 32 * 1024),
+            &["DIMM_A2", "BANK 0"],
+        );
+        structure(&mut table, MEMORY_DEVICE, 0x42, &device(0, 0), &[]);
+        structure(
+            &mut table,
+            DEVICE_MAPPED_ADDRESS,
+            0x50,
+            &mapped(0x100000, 0x1fffff, 0x41),
+            &[],
+        );
+        structure(
+            &mut table,
+            DEVICE_MAPPED_ADDRESS,
+            0x51,
+            &mapped(0, 0xfffff, 0x40),
+            &[],
+        );
+        structure(&mut table, END_OF_TABLE, 0xfeff, &[], &[]);
+        table
+    }
+
+    #[test]
+    fn test_parse() {
+        let memory = SmbiosMemory::parse(&table()).unwrap();
+        assert_eq!(memory.devices.len(), 3);
+        assert_eq!(memory.devices[0].locator, "DIMM_A1");
+        assert_eq!(memory.devices[0].bank.as_deref(), Some("BANK 0"));
+        assert_eq!(memory.devices[0].size, Some(1 << 30));
+        assert_eq!(memory.devices[1].size, Some(32 << 30));
+        assert_eq!(memory.devices[2].locator, "");
+        assert_eq!(memory.installed().len(), 2);
+        assert_eq!(memory.installed_size(), 33 << 30);
+        assert_eq!(memory.mapped[1].end, (1 << 30) - 1);
+
+        assert!(SmbiosMemory::parse(&[17, 2, 0, 0, 0, 0]).is_err());
+        assert!(SmbiosMemory::parse(&[17, 4, 0, 0, b'a']).is_err());
+    }
+
+    #[test]
+    fn test_dimm_map() {
+        let path = scratch_path("smbios");
+        fs::write(&path, table()).unwrap();
+        let map = Smbios::with_path(&path).dimm_map().unwrap();
+        fs::remove_file(&path).unwrap();
+
+        assert_eq!(map.lookup(0x1000).unwrap().label, "DIMM_A1");
+        assert_eq!(map.lookup(1 << 30).unwrap().label, "DIMM_A2");
+        assert_eq!(
+            map.lookup(1 << 30).unwrap().channel.as_deref(),
+            Some("BANK 0")
+        );
+
+        let extents = [(0, 0xfffff), (0x3ff00000, 0x400fffff)];
+        let mut stats =
+            ScrubStats::new(&[0x100000, 0x200000], Instant::now());
+        assert_eq!(map.label_areas(&extents, &mut stats), 2);
+        assert_eq!(stats.areas[0].label.as_deref(), Some("DIMM_A1"));
+        assert_eq!(
+            stats.areas[1].label.as_deref(),
+            Some("DIMM_A1+DIMM_A2")
+        );
+
+        assert!(SmbiosMemory::default().dimm_map().is_err());
+    }
+}
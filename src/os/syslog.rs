@@ -0,0 +1,252 @@
+// Event sinks that send scrub events to the standard Linux logging pipelines.
+// SyslogSink writes RFC 5424 messages, with the event fields as structured
+// data, to the local syslog socket. JournaldSink uses the journald native
+// protocol, so each field can be matched on with journalctl.
+//
+// Logging must never interfere with scrubbing, so send failures don't
+// stop it: the event is dropped and the failure reported as a warning
+// through the diagnostics sink. Chunk completions are too frequent to be
+// worth logging and are ignored.
+
+use std::io;
+use std::os::unix::net::UnixDatagram;
+use std::path::{Path, PathBuf};
+use std::process;
+
+use crate::diag::*;
+use crate::event::*;
+
+const SYSLOG_PATH: &str = "/dev/log";
+const JOURNALD_PATH: &str = "/run/systemd/journal/socket";
+const IDENTIFIER: &str = "memscrub";
+
+// syslog facility and severities
+const LOG_DAEMON: u8 = 3 << 3;
+const LOG_ERR: u8 = 3;
+const LOG_WARNING: u8 = 4;
+const LOG_NOTICE: u8 = 5;
+const LOG_INFO: u8 = 6;
+const LOG_DEBUG: u8 = 7;
+
+// Return the syslog severity for an event
+fn severity(event: &ScrubEvent) -> u8 {
+    match event {
+        ScrubEvent::ChunkComplete { .. } => LOG_DEBUG,
+        ScrubEvent::PassComplete { .. } => LOG_INFO,
+        ScrubEvent::RateChange { .. } => LOG_NOTICE,
+        ScrubEvent::ErrorStorm { .. } => LOG_WARNING,
+        ScrubEvent::StormCleared { .. } => LOG_NOTICE,
+        ScrubEvent::Health { .. } => LOG_INFO,
+        ScrubEvent::LineSkipped { .. } => LOG_WARNING,
+        ScrubEvent::ThresholdCrossed { .. } => LOG_WARNING,
+        ScrubEvent::ThresholdCleared { .. } => LOG_NOTICE,
+        ScrubEvent::LineFlushed { .. } => LOG_DEBUG,
+        ScrubEvent::FaultLocated { site, .. } => match site {
+            FaultSite::Cache => LOG_NOTICE,
+            FaultSite::Memory => LOG_WARNING,
+        },
+        ScrubEvent::Error(e) => match e.severity {
+            ErrorSeverity::Corrected => LOG_WARNING,
+            ErrorSeverity::Uncorrected => LOG_ERR,
+        },
+    }
+}
+
+/// Sends scrub events to syslog
+///
+/// * `socket` - Unbound datagram socket used for sending
+///
+/// * `path` - Path of the syslog socket
+///
+/// * `identifier` - APP-NAME to use in messages
+pub struct SyslogSink {
+    socket: UnixDatagram,
+    path: PathBuf,
+    identifier: String,
+}
+
+impl SyslogSink {
+    /// Create a sink using the standard syslog socket
+    pub fn new() -> io::Result<SyslogSink> {
+        SyslogSink::with_path(SYSLOG_PATH)
+    }
+
+    /// Create a sink sending to the syslog socket at the given path
+    pub fn with_path<P: AsRef<Path>>(path: P) -> io::Result<SyslogSink> {
+        Ok(SyslogSink {
+            socket: UnixDatagram::unbound()?,
+            path: path.as_ref().to_path_buf(),
+            identifier: IDENTIFIER.to_string(),
+        })
+    }
+
+    /// Set the APP-NAME used in messages
+    pub fn set_identifier(&mut self, identifier: &str) {
+        self.identifier = identifier.to_string();
+    }
+
+    // Format an event as an RFC 5424 message
+    fn format(&self, event: &ScrubEvent) -> String {
+        let pri = LOG_DAEMON | severity(event);
+        let mut sd = String::from("[memscrub");
+        for (name, value) in event.fields() {
+            // PARAM-VALUE must escape '"', '\' and ']'
+            let value = value
+                .replace('\\', "\\\\")
+                .replace('"', "\\\"")
+                .replace(']', "\\]");
+            sd.push_str(&format!(" {}=\"{}\"", name, value));
+        }
+        sd.push(']');
+
+        format!(
+            "<{}>1 - - {} {} {} {} {}",
+            pri,
+            self.identifier,
+            process::id(),
+            event.name(),
+            sd,
+            event.message()
+        )
+    }
+}
+
+impl EventSink for SyslogSink {
+    fn event(&mut self, event: &ScrubEvent) {
+        if let ScrubEvent::ChunkComplete { .. } = event {
+            return;
+        }
+        let msg = self.format(event);
+        if let Err(e) = self.socket.send_to(msg.as_bytes(), &self.path) {
+            diag!(Warning, "can't send to syslog: {}", e);
+        }
+    }
+}
+
+/// Sends scrub events to journald
+///
+/// * `socket` - Unbound datagram socket used for sending
+///
+/// * `path` - Path of the journald socket
+///
+/// * `identifier` - SYSLOG_IDENTIFIER to use in entries
+pub struct JournaldSink {
+    socket: UnixDatagram,
+    path: PathBuf,
+    identifier: String,
+}
+
+impl JournaldSink {
+    /// Create a sink using the standard journald socket
+    pub fn new() -> io::Result<JournaldSink> {
+        JournaldSink::with_path(JOURNALD_PATH)
+    }
+
+    /// Create a sink sending to the journald socket at the given path
+    pub fn with_path<P: AsRef<Path>>(path: P) -> io::Result<JournaldSink> {
+        Ok(JournaldSink {
+            socket: UnixDatagram::unbound()?,
+            path: path.as_ref().to_path_buf(),
+            identifier: IDENTIFIER.to_string(),
+        })
+    }
+
+    /// Set the SYSLOG_IDENTIFIER used in entries
+    pub fn set_identifier(&mut self, identifier: &str) {
+        self.identifier = identifier.to_string();
+    }
+
+    // Append a field in the journald native format. Values containing a
+    // newline must use the length-prefixed binary form.
+    fn push_field(buf: &mut Vec<u8>, name: &str, value: &str) {
+        buf.extend_from_slice(name.as_bytes());
+        if value.contains('\n') {
+            buf.push(b'\n');
+            buf.extend_from_slice(&(value.len() as u64).to_le_bytes());
+        } else {
+            buf.push(b'=');
+        }
+        buf.extend_from_slice(value.as_bytes());
+        buf.push(b'\n');
+    }
+
+    // Format an event as a journald native protocol datagram
+    fn format(&self, event: &ScrubEvent) -> Vec<u8> {
+        let mut buf = Vec::new();
+        Self::push_field(&mut buf, "MESSAGE", &event.message());
+        Self::push_field(
+            &mut buf,
+            "PRIORITY",
+            &severity(event).to_string(),
+        );
+        Self::push_field(&mut buf, "SYSLOG_IDENTIFIER", &self.identifier);
+        for (name, value) in event.fields() {
+            let name = format!("MEMSCRUB_{}", name.to_uppercase());
+            Self::push_field(&mut buf, &name, &value);
+        }
+        buf
+    }
+}
+
+impl EventSink for JournaldSink {
+    fn event(&mut self, event: &ScrubEvent) {
+        if let ScrubEvent::ChunkComplete { .. } = event {
+            return;
+        }
+        let msg = self.format(event);
+        if let Err(e) = self.socket.send_to(&msg, &self.path) {
+            diag!(Warning, "can't send to journald: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+    use std::fs;
+
+    fn receive(
+        path: &Path,
+        sink: &mut dyn EventSink,
+        event: &ScrubEvent,
+    ) -> String {
+        let _ = fs::remove_file(path);
+        let server = UnixDatagram::bind(path).unwrap();
+        sink.event(event);
+        let mut buf = [0u8; 1024];
+        let n = server.recv(&mut buf).unwrap();
+        let _ = fs::remove_file(path);
+        String::from_utf8_lossy(&buf[..n]).to_string()
+    }
+
+    #[test]
+    fn test_syslog() {
+        let path = scratch_path("syslog");
+        let mut sink = SyslogSink::with_path(&path).unwrap();
+        let event = ScrubEvent::Error(ErrorEvent {
+            addr: 0x1000,
+            area: Some(2),
+            severity: ErrorSeverity::Uncorrected,
+        });
+        let msg = receive(&path, &mut sink, &event);
+
+        assert!(msg.starts_with("<27>1 - - memscrub "));
+        assert!(msg.contains(" error [memscrub event=\"error\""));
+        assert!(msg.contains("addr=\"0x1000\" area=\"2\""));
+        assert!(msg.ends_with("uncorrected memory error at 0x1000"));
+    }
+
+    #[test]
+    fn test_journald() {
+        let path = scratch_path("journald");
+        let mut sink = JournaldSink::with_path(&path).unwrap();
+        let event = ScrubEvent::RateChange { old: 64, new: 128 };
+        let msg = receive(&path, &mut sink, &event);
+
+        assert!(msg.contains("PRIORITY=5\n"));
+        assert!(msg.contains("SYSLOG_IDENTIFIER=memscrub\n"));
+        assert!(msg.contains("MEMSCRUB_EVENT=rate_change\n"));
+        assert!(msg.contains("MEMSCRUB_NEW=128\n"));
+    }
+}
@@ -0,0 +1,450 @@
+// Uncached mappings of physical memory. Opening /dev/mem with O_SYNC makes
+// the kernel map it uncached, so reads through the mapping always go to
+// memory. This requires root and a kernel that allows access to the memory
+// through /dev/mem, which CONFIG_STRICT_DEVMEM restricts. Other files can
+// be mapped too, such as a device exposing memory, but O_SYNC only makes
+// the mapping uncached for /dev/mem and the devices that honour it: a
+// regular file is mapped through the page cache whatever the flags.
+//
+// A mapping may be placed between inaccessible guard pages, so that a read
+// past either end of it faults rather than reading another mapping. The
+// guard pages can also be given to a GuardBackend, which counts reads that
+// fall in them instead of passing them on.
+//
+// Mappings can be counted against a MapBudget shared with WindowedBackends
+// and other mappings. A mapping that would take the budget over its limit
+// fails instead of being made, and the bytes it held are returned to the
+// budget when it is dropped.
+
+use std::fs::OpenOptions;
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr::{self, NonNull};
+
+use crate::area::*;
+use crate::base::*;
+use crate::os::cgroup::*;
+
+const DEV_MEM: &str = "/dev/mem";
+
+/// A read-only, uncached mapping of memory
+///
+/// * `addr` - Address at which the memory is mapped
+///
+/// * `len` - Number of bytes mapped
+///
+/// * `guard` - Number of bytes of guard pages on each side of the mapping
+///
+/// * `budget` - Budget the mapping is counted against, if any
+#[derive(Debug)]
+pub struct UncachedMapping {
+    addr: *mut libc::c_void,
+    len: usize,
+    guard: usize,
+    budget: Option<MapBudget>,
+}
+
+impl UncachedMapping {
+    /// Map physical memory through /dev/mem
+    ///
+    /// # Arguments:
+    /// * `phys` - Physical address of the memory, page aligned
+    ///
+    /// * `len` - Number of bytes to map
+    pub fn map(phys: u64, len: usize) -> io::Result<UncachedMapping> {
+        UncachedMapping::map_file(DEV_MEM, phys, len)
+    }
+
+    /// Map physical memory through /dev/mem between guard pages
+    ///
+    /// # Arguments:
+    /// * `phys` - Physical address of the memory, page aligned
+    ///
+    /// * `len` - Number of bytes to map
+    ///
+    /// * `guard` - Number of bytes of guard pages on each side, rounded up
+    ///   to a whole number of pages
+    pub fn map_guarded(
+        phys: u64,
+        len: usize,
+        guard: usize,
+    ) -> io::Result<UncachedMapping> {
+        UncachedMapping::map_file_guarded(DEV_MEM, phys, len, guard)
+    }
+
+    /// Map physical memory through /dev/mem between guard pages, counting
+    /// the mapping against a budget
+    ///
+    /// # Arguments:
+    /// * `phys` - Physical address of the memory, page aligned
+    ///
+    /// * `len` - Number of bytes to map
+    ///
+    /// * `guard` - Number of bytes of guard pages on each side, rounded up
+    ///   to a whole number of pages
+    ///
+    /// * `budget` - The budget, which fails the mapping with
+    ///   io::ErrorKind::OutOfMemory if it has no room for it
+    pub fn map_within(
+        phys: u64,
+        len: usize,
+        guard: usize,
+        budget: &MapBudget,
+    ) -> io::Result<UncachedMapping> {
+        UncachedMapping::map_file_within(DEV_MEM, phys, len, guard, budget)
+    }
+
+    /// Map a scrub area of physical memory through /dev/mem
+    ///
+    /// # Arguments:
+    /// * `area` - The area, which must start on a page boundary
+    pub fn map_area(area: &PhysScrubArea) -> io::Result<UncachedMapping> {
+        UncachedMapping::map(area.start(), area.size())
+    }
+
+    /// Map part of a file opened with O_SYNC. Only /dev/mem and devices
+    /// that honour O_SYNC are mapped uncached; a regular file is mapped
+    /// through the page cache.
+    ///
+    /// # Arguments:
+    /// * `path` - File to map
+    ///
+    /// * `offset` - Offset in the file, page aligned
+    ///
+    /// * `len` - Number of bytes to map
+    pub fn map_file<P: AsRef<Path>>(
+        path: P,
+        offset: u64,
+        len: usize,
+    ) -> io::Result<UncachedMapping> {
+        UncachedMapping::map_file_guarded(path, offset, len, 0)
+    }
+
+    /// Map part of a file opened with O_SYNC between guard pages. As with
+    /// map_file(), a regular file is mapped through the page cache.
+    ///
+    /// # Arguments:
+    /// * `path` - File to map
+    ///
+    /// * `offset` - Offset in the file, page aligned
+    ///
+    /// * `len` - Number of bytes to map
+    ///
+    /// * `guard` - Number of bytes of guard pages on each side, rounded up
+    ///   to a whole number of pages
+    pub fn map_file_guarded<P: AsRef<Path>>(
+        path: P,
+        offset: u64,
+        len: usize,
+        guard: usize,
+    ) -> io::Result<UncachedMapping> {
+        UncachedMapping::map_budgeted(
+            path.as_ref(),
+            offset,
+            len,
+            guard,
+            None,
+        )
+    }
+
+    /// Map part of a file opened with O_SYNC between guard pages, counting
+    /// the mapping against a budget. As with map_file(), a regular file is
+    /// mapped through the page cache.
+    ///
+    /// # Arguments:
+    /// * `path` - File to map
+    ///
+    /// * `offset` - Offset in the file, page aligned
+    ///
+    /// * `len` - Number of bytes to map
+    ///
+    /// * `guard` - Number of bytes of guard pages on each side, rounded up
+    ///   to a whole number of pages
+    ///
+    /// * `budget` - The budget, which fails the mapping with
+    ///   io::ErrorKind::OutOfMemory if it has no room for it
+    pub fn map_file_within<P: AsRef<Path>>(
+        path: P,
+        offset: u64,
+        len: usize,
+        guard: usize,
+        budget: &MapBudget,
+    ) -> io::Result<UncachedMapping> {
+        UncachedMapping::map_budgeted(
+            path.as_ref(),
+            offset,
+            len,
+            guard,
+            Some(budget),
+        )
+    }
+
+    // Map part of a file, counting the pages mapped against the budget, if
+    // one is given
+    fn map_budgeted(
+        path: &Path,
+        offset: u64,
+        len: usize,
+        guard: usize,
+        budget: Option<&MapBudget>,
+    ) -> io::Result<UncachedMapping> {
+        let invalid = || io::Error::from(io::ErrorKind::InvalidInput);
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let guard =
+            guard.checked_next_multiple_of(page).ok_or_else(invalid)?;
+        let mapped =
+            len.checked_next_multiple_of(page).ok_or_else(invalid)?;
+        let total = guard
+            .checked_mul(2)
+            .and_then(|guards| guards.checked_add(mapped))
+            .ok_or_else(invalid)?;
+
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_SYNC)
+            .open(path)?;
+        let offset =
+            libc::off_t::try_from(offset).map_err(|_| invalid())?;
+        if let Some(budget) = budget {
+            if !budget.try_reserve(mapped) {
+                return Err(io::Error::from(io::ErrorKind::OutOfMemory));
+            }
+        }
+
+        // Reserve the mapping and its guard pages as inaccessible memory,
+        // then map the file over the middle of the reservation
+        let (hint, flags) = match guard {
+            0 => (ptr::null_mut(), libc::MAP_SHARED),
+            _ => {
+                let base = unsafe {
+                    libc::mmap(
+                        ptr::null_mut(),
+                        total,
+                        libc::PROT_NONE,
+                        libc::MAP_PRIVATE
+                            | libc::MAP_ANONYMOUS
+                            | libc::MAP_NORESERVE,
+                        -1,
+                        0,
+                    )
+                };
+                if base == libc::MAP_FAILED {
+                    let error = io::Error::last_os_error();
+                    if let Some(budget) = budget {
+                        budget.release(mapped);
+                    }
+                    return Err(error);
+                }
+                let hint = unsafe { base.cast::<u8>().add(guard) };
+                (hint.cast(), libc::MAP_SHARED | libc::MAP_FIXED)
+            }
+        };
+
+        // The mapping is read-only and owned by the returned value. With
+        // MAP_FIXED, it replaces only pages of the reservation made above.
+        let addr = unsafe {
+            libc::mmap(
+                hint,
+                len,
+                libc::PROT_READ,
+                flags,
+                file.as_raw_fd(),
+                offset,
+            )
+        };
+        if addr == libc::MAP_FAILED {
+            let error = io::Error::last_os_error();
+            if guard != 0 {
+                unsafe {
+                    libc::munmap(
+                        hint.cast::<u8>().sub(guard).cast(),
+                        total,
+                    )
+                };
+            }
+            if let Some(budget) = budget {
+                budget.release(mapped);
+            }
+            return Err(error);
+        }
+        Ok(UncachedMapping {
+            addr,
+            len,
+            guard,
+            budget: budget.cloned(),
+        })
+    }
+
+    /// Returns the (start, end) address of the mapping, end inclusive, for
+    /// use as the alias of a scrub area
+    pub fn extent(&self) -> (usize, usize) {
+        let start = self.addr as usize;
+        (start, start + self.len - 1)
+    }
+
+    /// Returns the mapping as a scrub area of virtual memory, taking in a
+    /// partial cache line at its end
+    ///
+    /// # Arguments:
+    /// * `line_size` - Number of bytes in a cache line
+    ///
+    /// # Returns:
+    /// Ok(VirtScrubArea) on success, otherwise Err(Error::UnalignedValue)
+    /// if the line size is not a power of two no larger than a page
+    pub fn area(&self, line_size: usize) -> Result<VirtScrubArea, Error> {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let base = NonNull::new(self.addr.cast::<u8>())
+            .ok_or(Error::InternalError)?;
+        VirtScrubArea::from_pages(base, self.len, line_size, page)
+    }
+
+    /// Returns the (start, end) address of the guard pages before and
+    /// after the mapping, end inclusive, for GuardBackend::add_guard(), or
+    /// nothing if the mapping has none
+    pub fn guards(&self) -> Vec<(usize, usize)> {
+        if self.guard == 0 {
+            return Vec::new();
+        }
+        let start = self.addr as usize;
+        let after = start + self.mapped_len();
+        vec![
+            (start - self.guard, start - 1),
+            (after, after + self.guard - 1),
+        ]
+    }
+
+    // Number of bytes of the mapping, which is whole pages
+    fn mapped_len(&self) -> usize {
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        self.len.next_multiple_of(page)
+    }
+}
+
+impl Drop for UncachedMapping {
+    fn drop(&mut self) {
+        // Nothing refers to the mapping or its guard pages once it is
+        // dropped
+        let start = self.addr as usize - self.guard;
+        let len = self.mapped_len() + 2 * self.guard;
+        unsafe { libc::munmap(start as *mut libc::c_void, len) };
+        if let Some(budget) = self.budget.as_ref() {
+            budget.release(self.mapped_len());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::alias::*;
+    use crate::backend::*;
+    use crate::guard::*;
+    use crate::os::procmaps::*;
+    use crate::testutil::*;
+    use std::fs;
+
+    #[test]
+    fn test_map() {
+        let path = scratch_path("uncached");
+        fs::write(&path, vec![0xa5u8; 8192]).unwrap();
+        let mapping = UncachedMapping::map_file(&path, 0, 8192).unwrap();
+        let (start, end) = mapping.extent();
+        assert_eq!(end - start, 8191);
+        assert_eq!(mapping.area(64).unwrap().extent(), (start, end));
+
+        // Scrub a made-up primary area through the mapping. Only the
+        // mapping is ever read.
+        let mut backend = AliasBackend::new(unsafe { RawBackend::new() });
+        backend
+            .add_alias((0x100000, 0x101fff), (start, end))
+            .unwrap();
+        let mut scrubber =
+            LineScrubber::new(backend, &[(0x100000, 0x101fff)], 64, 6)
+                .unwrap();
+        scrubber.scrub(8192).unwrap();
+
+        drop(mapping);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_guarded() {
+        let path = scratch_path("uncached-guarded");
+        fs::write(&path, vec![0xa5u8; 8192]).unwrap();
+        let mapping =
+            UncachedMapping::map_file_guarded(&path, 0, 8192, 1).unwrap();
+        let (start, end) = mapping.extent();
+        let guards = mapping.guards();
+        assert_eq!(guards.len(), 2);
+        assert_eq!(guards[0].1 + 1, start);
+        assert_eq!(guards[1].0, end + 1);
+
+        // The guard pages can't be read
+        let segments = self_segments().unwrap();
+        for &(low, high) in &guards {
+            assert!(segments
+                .iter()
+                .any(|s| s.start <= low && s.end >= high && !s.readable));
+        }
+
+        // A read of a guard page through a GuardBackend is counted instead
+        let mut backend = GuardBackend::new(unsafe { RawBackend::new() });
+        for &(low, high) in &guards {
+            backend.add_guard(low, high).unwrap();
+        }
+        let mut scrubber =
+            LineScrubber::new(backend, &[(start, end)], 64, 6).unwrap();
+        scrubber.scrub(8192).unwrap();
+        assert_eq!(scrubber.backend_mut().check(), Ok(()));
+        scrubber.backend_mut().read_line(end + 1).unwrap();
+        assert_eq!(scrubber.backend_mut().check(), Err(Error::GuardHit));
+
+        drop(scrubber);
+        drop(mapping);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_budget() {
+        let path = scratch_path("uncached-budget");
+        fs::write(&path, vec![0xa5u8; 8192]).unwrap();
+        let page = unsafe { libc::sysconf(libc::_SC_PAGESIZE) } as usize;
+        let budget = MapBudget::new(page);
+
+        // Only whole pages mapped count, not the guard pages
+        let first =
+            UncachedMapping::map_file_within(&path, 0, 100, 1, &budget)
+                .unwrap();
+        assert_eq!(budget.mapped(), page);
+        let error =
+            UncachedMapping::map_file_within(&path, 0, 100, 0, &budget)
+                .unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::OutOfMemory);
+        assert_eq!(budget.mapped(), page);
+
+        // A failed mapping gives its bytes back
+        drop(first);
+        assert_eq!(budget.mapped(), 0);
+        assert!(UncachedMapping::map_file_within(
+            scratch_path("uncached-missing"),
+            0,
+            100,
+            0,
+            &budget
+        )
+        .is_err());
+        assert!(UncachedMapping::map_file_within(
+            std::env::temp_dir(),
+            0,
+            page,
+            1,
+            &budget
+        )
+        .is_err());
+        assert_eq!(budget.mapped(), 0);
+
+        fs::remove_file(&path).unwrap();
+    }
+}
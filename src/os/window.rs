@@ -0,0 +1,424 @@
+// Scrubbing memory through windows mapped from a file, such as /dev/mem
+// for physical memory or a file whose page cache is to be scrubbed.
+// Mapping all of the memory at once costs page tables and, for files,
+// page cache charged to the scrubber's cgroup, which under a memory limit
+// can wake the OOM killer. WindowedBackend instead maps a window of the
+// file around each line as it is read, keeps only the most recently used
+// windows mapped up to a budget, and unmaps the least recently used as the
+// pass moves on. All windows are unmapped at the end of each batch, so
+// nothing stays mapped while the scrubber waits for its next chunk.
+//
+// The windows mapped can also be counted against a MapBudget shared with
+// other backends and mappings, whose limit can follow the headroom of the
+// cgroup. The budget is refreshed at the start of each batch, so the
+// scrubber maps less as the rest of the cgroup uses more.
+//
+// Addresses scrubbed are offsets in the file, which for /dev/mem are
+// physical addresses.
+//
+// A pass in the cache aware order visits every window once for each cache
+// index, so even a bounded set of windows would be remapped again and
+// again. A LineScrubber made with the backend therefore works through the
+// memory a window at a time: it scrubs all of the lines of a window in the
+// cache aware order and then moves on to the next, when the backend
+// unmaps the window it has finished. Windows are a multiple of the span of
+// addresses that covers every cache index once, so each window holds the
+// same number of lines with each cache index, and scrubbing it disturbs
+// the cache just as the same lines would in a pass over the whole memory.
+// Every line is still read once a pass, and a window is mapped again only
+// if a chunk ends part way through it. The other windows in the budget
+// serve the reads made out of the order of the pass, such as those of
+// boosted areas.
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::os::unix::fs::OpenOptionsExt;
+use std::os::unix::io::AsRawFd;
+use std::path::Path;
+use std::ptr;
+
+use crate::arch::*;
+use crate::backend::*;
+use crate::base::*;
+use crate::os::cgroup::*;
+
+const DEV_MEM: &str = "/dev/mem";
+
+/// Default size of each window mapped
+pub const DEFAULT_WINDOW_SIZE: usize = 2 << 20;
+
+// A mapped window
+//
+// offset: Offset in the file of the start of the window
+// addr:   Address at which the window is mapped
+// len:    Number of bytes mapped
+struct Window {
+    offset: usize,
+    addr: *mut libc::c_void,
+    len: usize,
+}
+
+/// A backend reading a file through a bounded set of mapped windows
+///
+/// * `file` - The file, opened read-only
+///
+/// * `window_size` - Number of bytes in each window
+///
+/// * `budget` - Most bytes mapped at once, a multiple of the window size
+///
+/// * `shared` - Budget shared with other mappings, if any
+///
+/// * `windows` - The windows mapped, least recently used first
+///
+/// * `maps` - Number of windows mapped so far
+pub struct WindowedBackend {
+    file: File,
+    window_size: usize,
+    budget: usize,
+    shared: Option<MapBudget>,
+    windows: VecDeque<Window>,
+    maps: u64,
+}
+
+// The windows are only read through their addresses, which are valid in
+// any thread
+unsafe impl Send for WindowedBackend {}
+
+impl WindowedBackend {
+    /// Read physical memory through /dev/mem, which is opened with O_SYNC
+    /// so the windows are mapped uncached
+    ///
+    /// # Arguments:
+    /// * `window_size` - Number of bytes in each window
+    ///
+    /// * `budget` - Most bytes to map at once
+    ///
+    /// # Returns:
+    /// As for open()
+    pub fn dev_mem(
+        window_size: usize,
+        budget: usize,
+    ) -> io::Result<WindowedBackend> {
+        let file = OpenOptions::new()
+            .read(true)
+            .custom_flags(libc::O_SYNC)
+            .open(DEV_MEM)?;
+        WindowedBackend::with_file(file, window_size, budget)
+    }
+
+    /// Read a file
+    ///
+    /// # Arguments:
+    /// * `path` - The file
+    ///
+    /// * `window_size` - Number of bytes in each window, a power of two
+    ///   no smaller than the page size, nor than the cache line size
+    ///   shifted left by the index width of the scrubber using it
+    ///
+    /// * `budget` - Most bytes to map at once. It is rounded down to a
+    ///   multiple of the window size, but is at least one window.
+    ///
+    /// # Returns:
+    /// Ok(WindowedBackend) on success, otherwise Err(io::Error) if the
+    /// file can't be opened or the window size is not valid
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        window_size: usize,
+        budget: usize,
+    ) -> io::Result<WindowedBackend> {
+        WindowedBackend::with_file(File::open(path)?, window_size, budget)
+    }
+
+    // Use an open file
+    fn with_file(
+        file: File,
+        window_size: usize,
+        budget: usize,
+    ) -> io::Result<WindowedBackend> {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        if !window_size.is_power_of_two()
+            || (window_size as libc::c_long) < page_size
+        {
+            return Err(io::Error::from(io::ErrorKind::InvalidInput));
+        }
+        let mut backend = WindowedBackend {
+            file,
+            window_size,
+            budget: window_size,
+            shared: None,
+            windows: VecDeque::new(),
+            maps: 0,
+        };
+        backend.set_budget(budget);
+        Ok(backend)
+    }
+
+    /// Change the most bytes mapped at once, as when the headroom of the
+    /// cgroup changes. Windows over the new budget are unmapped.
+    ///
+    /// # Arguments:
+    /// * `budget` - Most bytes to map at once, rounded down to a multiple
+    ///   of the window size, but at least one window
+    pub fn set_budget(&mut self, budget: usize) {
+        self.budget =
+            (budget / self.window_size).max(1) * self.window_size;
+        while self.mapped() > self.budget {
+            self.unmap_oldest();
+        }
+    }
+
+    /// Returns the most bytes mapped at once
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Count the windows mapped against a budget shared with other
+    /// mappings, as well as against the backend's own budget. At least one
+    /// window is mapped even if the shared budget has no room for it. The
+    /// shared budget is refreshed at the start of each batch.
+    ///
+    /// # Arguments:
+    /// * `budget` - The shared budget, or None to stop sharing one
+    pub fn set_map_budget(&mut self, budget: Option<MapBudget>) {
+        self.unmap_all();
+        self.shared = budget;
+    }
+
+    /// Returns the number of bytes in each window
+    pub fn window_size(&self) -> usize {
+        self.window_size
+    }
+
+    /// Returns the number of bytes mapped now
+    pub fn mapped(&self) -> usize {
+        self.windows.iter().map(|w| w.len).sum()
+    }
+
+    /// Returns the number of windows mapped so far
+    pub fn maps(&self) -> u64 {
+        self.maps
+    }
+
+    /// Unmap every window
+    pub fn unmap_all(&mut self) {
+        while !self.windows.is_empty() {
+            self.unmap_oldest();
+        }
+    }
+
+    // Returns the address at which an offset in the file is mapped,
+    // mapping its window if need be
+    fn map(&mut self, offset: usize) -> Result<usize, Error> {
+        let start = offset & !(self.window_size - 1);
+        if let Some(i) =
+            self.windows.iter().position(|w| w.offset == start)
+        {
+            // Now the most recently used
+            let window =
+                self.windows.remove(i).ok_or(Error::InternalError)?;
+            self.windows.push_back(window);
+        } else {
+            while self.mapped() + self.window_size > self.budget {
+                self.unmap_oldest();
+            }
+            if let Some(shared) = self.shared.clone() {
+                while !shared.try_reserve(self.window_size) {
+                    if self.windows.is_empty() {
+                        shared.reserve(self.window_size);
+                        break;
+                    }
+                    self.unmap_oldest();
+                }
+            }
+            let file_offset = libc::off_t::try_from(start)
+                .map_err(|_| Error::AddressOverflow)?;
+            let addr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    self.window_size,
+                    libc::PROT_READ,
+                    libc::MAP_SHARED,
+                    self.file.as_raw_fd(),
+                    file_offset,
+                )
+            };
+            if addr == libc::MAP_FAILED {
+                if let Some(shared) = self.shared.as_ref() {
+                    shared.release(self.window_size);
+                }
+                return Err(Error::MapFailed);
+            }
+            self.maps += 1;
+            self.windows.push_back(Window {
+                offset: start,
+                addr,
+                len: self.window_size,
+            });
+        }
+        let window = self.windows.back().ok_or(Error::InternalError)?;
+        Ok(window.addr as usize + (offset - start))
+    }
+
+    // Unmap the least recently used window
+    fn unmap_oldest(&mut self) {
+        if let Some(window) = self.windows.pop_front() {
+            // Nothing refers to the window once it is unmapped
+            unsafe { libc::munmap(window.addr, window.len) };
+            if let Some(shared) = self.shared.as_ref() {
+                shared.release(window.len);
+            }
+        }
+    }
+}
+
+impl ScrubBackend for WindowedBackend {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        let mapped = self.map(addr)?;
+        // The window holding the address was just mapped
+        unsafe { ptr::read_volatile(mapped as *const u8) };
+        Ok(())
+    }
+
+    fn begin_batch(&mut self) -> Result<(), Error> {
+        if let Some(shared) = self.shared.as_ref() {
+            shared.refresh().map_err(|_| Error::IoFailed)?;
+        }
+        Ok(())
+    }
+
+    fn end_batch(&mut self) -> Result<(), Error> {
+        self.unmap_all();
+        Ok(())
+    }
+
+    fn end_window(&mut self) -> Result<(), Error> {
+        self.unmap_all();
+        Ok(())
+    }
+
+    fn window_size(&self) -> Option<usize> {
+        Some(self.window_size)
+    }
+
+    fn flush_line(&mut self, addr: usize) -> Result<(), Error> {
+        let mapped = self.map(addr)?;
+        // The window holding the address was just mapped
+        match unsafe { flush_line(mapped) } {
+            true => Ok(()),
+            false => Err(Error::InternalError),
+        }
+    }
+}
+
+impl Drop for WindowedBackend {
+    fn drop(&mut self) {
+        self.unmap_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+    use std::fs;
+
+    #[test]
+    fn test_windows() {
+        let path = scratch_path("window");
+        fs::write(&path, vec![0x5au8; 64 * 1024]).unwrap();
+        assert!(WindowedBackend::open(&path, 3000, 8192).is_err());
+
+        let mut backend =
+            WindowedBackend::open(&path, 4096, 10000).unwrap();
+        assert_eq!(backend.budget(), 8192);
+
+        // Windows are reused, and the least recently used unmapped to
+        // stay within the budget
+        backend.read_line(0).unwrap();
+        backend.read_line(4096).unwrap();
+        backend.read_line(64).unwrap();
+        assert_eq!((backend.maps(), backend.mapped()), (2, 8192));
+        backend.read_line(8192).unwrap();
+        assert_eq!((backend.maps(), backend.mapped()), (3, 8192));
+        backend.read_line(128).unwrap();
+        assert_eq!(backend.maps(), 3);
+        backend.read_line(4096).unwrap();
+        assert_eq!(backend.maps(), 4);
+        backend.set_budget(0);
+        assert_eq!(backend.mapped(), 4096);
+
+        // A pass in the cache aware order maps each window once, and
+        // nothing is left mapped after a chunk
+        backend.set_budget(4 * 4096);
+        let mut scrubber =
+            LineScrubber::new(backend, &[(0, 64 * 1024 - 1)], 64, 6)
+                .unwrap();
+        assert_eq!(scrubber.window_size(), Some(4096));
+        scrubber.scrub(64 * 1024).unwrap();
+        assert_eq!(scrubber.stats().passes, 1);
+        assert_eq!(scrubber.backend().maps(), 4 + 16);
+        assert_eq!(scrubber.backend().mapped(), 0);
+
+        drop(scrubber);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_window_pass() {
+        let path = scratch_path("window-pass");
+        fs::write(&path, vec![0xa5u8; 64 * 1024]).unwrap();
+        let areas = [(0, 0xbfff), (0xc040, 0xffff)];
+
+        // A window must hold every cache index equally
+        let backend = WindowedBackend::open(&path, 16384, 0).unwrap();
+        assert!(matches!(
+            LineScrubber::new(backend, &areas, 64, 9),
+            Err(Error::UnalignedValue)
+        ));
+        let backend = WindowedBackend::open(&path, 16384, 0).unwrap();
+        let mut scrubber =
+            LineScrubber::new(backend, &areas, 64, 6).unwrap();
+
+        // Each window is mapped once a pass, and only one at a time
+        let bytes = 0xc000 + 0x3fc0;
+        scrubber.scrub(0x4000).unwrap();
+        assert_eq!(scrubber.backend().maps(), 1);
+        scrubber.scrub(bytes - 0x4000).unwrap();
+        assert_eq!(scrubber.backend().maps(), 4);
+        assert_eq!(scrubber.stats().passes, 1);
+        assert_eq!(scrubber.backend().mapped(), 0);
+
+        drop(scrubber);
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_shared_budget() {
+        let path = scratch_path("window-shared");
+        fs::write(&path, vec![0x3cu8; 64 * 1024]).unwrap();
+        let budget = MapBudget::new(4096);
+        let mut first = WindowedBackend::open(&path, 4096, 8192).unwrap();
+        let mut second = WindowedBackend::open(&path, 4096, 8192).unwrap();
+        first.set_map_budget(Some(budget.clone()));
+        second.set_map_budget(Some(budget.clone()));
+
+        // A backend gives up its own windows to stay within the shared
+        // budget, but always maps the one it reads from
+        first.read_line(0).unwrap();
+        first.read_line(4096).unwrap();
+        assert_eq!((first.mapped(), budget.mapped()), (4096, 4096));
+        second.read_line(0).unwrap();
+        assert_eq!((second.mapped(), budget.mapped()), (4096, 8192));
+        first.read_line(8192).unwrap();
+        assert_eq!((first.mapped(), budget.mapped()), (4096, 8192));
+
+        // Unmapping returns the windows to the budget
+        first.end_batch().unwrap();
+        second.set_map_budget(None);
+        assert_eq!(budget.mapped(), 0);
+
+        drop((first, second));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,269 @@
+// Memory discovery on Windows. Windows gives a process no access to
+// physical memory, so scrubbing there covers the memory mapped into the
+// scrubbing process, read through RawBackend. VirtualQuery walks the
+// address space to find the committed, readable regions that can serve as
+// scrub areas. GetPhysicallyInstalledSystemMemory and EnumPageFilesW
+// describe the memory of the system as a whole, for reporting how much of
+// it is covered.
+//
+// The functions are declared here rather than taken from a bindings crate
+// since only a handful are needed, all from kernel32. Thread priority and
+// affinity for the scrub thread are set in sched.rs with the functions
+// declared here.
+
+use std::ffi::c_void;
+use std::io;
+use std::mem;
+
+// Types and constants from the Windows headers. The structures match the
+// headers' layout, so not every field is read.
+pub(crate) type Handle = *mut c_void;
+type Bool = i32;
+
+const MEM_COMMIT: u32 = 0x1000;
+const MEM_PRIVATE: u32 = 0x20000;
+const MEM_MAPPED: u32 = 0x40000;
+const PAGE_GUARD: u32 = 0x100;
+const PAGE_READABLE: u32 = 0x02 // PAGE_READONLY
+    | 0x04 // PAGE_READWRITE
+    | 0x08 // PAGE_WRITECOPY
+    | 0x20 // PAGE_EXECUTE_READ
+    | 0x40 // PAGE_EXECUTE_READWRITE
+    | 0x80; // PAGE_EXECUTE_WRITECOPY
+
+pub(crate) const THREAD_PRIORITY_IDLE: i32 = -15;
+pub(crate) const THREAD_PRIORITY_LOWEST: i32 = -2;
+pub(crate) const THREAD_PRIORITY_BELOW_NORMAL: i32 = -1;
+pub(crate) const THREAD_PRIORITY_NORMAL: i32 = 0;
+pub(crate) const THREAD_PRIORITY_ABOVE_NORMAL: i32 = 1;
+pub(crate) const THREAD_PRIORITY_ERROR_RETURN: i32 = i32::MAX;
+
+#[allow(dead_code)]
+#[repr(C)]
+struct MemoryBasicInformation {
+    base_address: *mut c_void,
+    allocation_base: *mut c_void,
+    allocation_protect: u32,
+    #[cfg(target_pointer_width = "64")]
+    partition_id: u16,
+    region_size: usize,
+    state: u32,
+    protect: u32,
+    kind: u32,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct SystemInfo {
+    processor_architecture: u16,
+    reserved: u16,
+    page_size: u32,
+    minimum_application_address: *mut c_void,
+    maximum_application_address: *mut c_void,
+    active_processor_mask: usize,
+    number_of_processors: u32,
+    processor_type: u32,
+    allocation_granularity: u32,
+    processor_level: u16,
+    processor_revision: u16,
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct EnumPageFileInformation {
+    cb: u32,
+    reserved: u32,
+    total_size: usize,
+    total_in_use: usize,
+    peak_usage: usize,
+}
+
+type EnumPageFileCallback = unsafe extern "system" fn(
+    context: *mut c_void,
+    info: *mut EnumPageFileInformation,
+    filename: *const u16,
+) -> Bool;
+
+#[link(name = "kernel32")]
+extern "system" {
+    fn GetPhysicallyInstalledSystemMemory(kilobytes: *mut u64) -> Bool;
+    fn GetSystemInfo(info: *mut SystemInfo);
+    fn VirtualQuery(
+        address: *const c_void,
+        buffer: *mut MemoryBasicInformation,
+        length: usize,
+    ) -> usize;
+    fn K32EnumPageFilesW(
+        callback: EnumPageFileCallback,
+        context: *mut c_void,
+    ) -> Bool;
+    pub(crate) fn GetCurrentThread() -> Handle;
+    pub(crate) fn GetThreadPriority(thread: Handle) -> i32;
+    pub(crate) fn SetThreadPriority(thread: Handle, priority: i32)
+        -> Bool;
+    pub(crate) fn SetThreadAffinityMask(
+        thread: Handle,
+        mask: usize,
+    ) -> usize;
+}
+
+/// A page file
+///
+/// * `name` - Path of the file
+///
+/// * `size` - Number of bytes in the file
+///
+/// * `in_use` - Number of bytes in use
+///
+/// * `peak` - Largest number of bytes that have been in use
+#[derive(Clone, Debug, PartialEq)]
+pub struct PageFile {
+    pub name: String,
+    pub size: u64,
+    pub in_use: u64,
+    pub peak: u64,
+}
+
+// Returns the page size and the lowest and highest application addresses
+fn system_info() -> (usize, usize, usize) {
+    // SAFETY: SystemInfo is plain data, filled in by GetSystemInfo
+    let mut info: SystemInfo = unsafe { mem::zeroed() };
+    unsafe { GetSystemInfo(&mut info) };
+    (
+        info.page_size as usize,
+        info.minimum_application_address as usize,
+        info.maximum_application_address as usize,
+    )
+}
+
+/// Returns the number of bytes of memory installed, as given by the SMBIOS
+/// tables
+pub fn installed_memory() -> io::Result<u64> {
+    let mut kilobytes = 0u64;
+    // SAFETY: kilobytes is valid for the duration of the call
+    match unsafe { GetPhysicallyInstalledSystemMemory(&mut kilobytes) } {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(kilobytes * 1024),
+    }
+}
+
+// Add a page file to the Vec<PageFile> that context points to
+unsafe extern "system" fn add_page_file(
+    context: *mut c_void,
+    info: *mut EnumPageFileInformation,
+    filename: *const u16,
+) -> Bool {
+    let files = &mut *(context as *mut Vec<PageFile>);
+    let info = &*info;
+    let mut len = 0;
+    while *filename.add(len) != 0 {
+        len += 1;
+    }
+    let name = String::from_utf16_lossy(std::slice::from_raw_parts(
+        filename, len,
+    ));
+    let page_size = system_info().0 as u64;
+    files.push(PageFile {
+        name,
+        size: info.total_size as u64 * page_size,
+        in_use: info.total_in_use as u64 * page_size,
+        peak: info.peak_usage as u64 * page_size,
+    });
+    1
+}
+
+/// Returns the page files in use
+pub fn page_files() -> io::Result<Vec<PageFile>> {
+    let mut files: Vec<PageFile> = Vec::new();
+    // SAFETY: the callback is only called during the call, while files is
+    // valid, and only with the context given
+    let ok = unsafe {
+        K32EnumPageFilesW(
+            add_page_file,
+            &mut files as *mut Vec<PageFile> as *mut c_void,
+        )
+    };
+    match ok {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(files),
+    }
+}
+
+/// Find the committed, readable memory mapped into this process, other
+/// than executable images, for use as scrub areas. Regions are page
+/// aligned and adjacent regions are merged. Memory may be freed after it
+/// is found, so only regions known to stay mapped, such as those holding
+/// the data to be protected, should be scrubbed with RawBackend.
+///
+/// # Arguments:
+/// * `min_size` - Smallest region to return, to leave out stacks and small
+///   allocations
+///
+/// # Returns:
+/// The (start, end) address of each region, end inclusive
+pub fn mapped_regions(min_size: usize) -> Vec<(usize, usize)> {
+    let (_, mut addr, max) = system_info();
+    let mut regions: Vec<(usize, usize)> = Vec::new();
+
+    while addr < max {
+        // SAFETY: MemoryBasicInformation is plain data, filled in by
+        // VirtualQuery, which accepts any address
+        let mut info: MemoryBasicInformation = unsafe { mem::zeroed() };
+        let len = unsafe {
+            VirtualQuery(
+                addr as *const c_void,
+                &mut info,
+                mem::size_of::<MemoryBasicInformation>(),
+            )
+        };
+        if len == 0 || info.region_size == 0 {
+            break;
+        }
+
+        let start = info.base_address as usize;
+        let end = start + info.region_size - 1;
+        let usable = info.state == MEM_COMMIT
+            && (info.kind == MEM_PRIVATE || info.kind == MEM_MAPPED)
+            && info.protect & PAGE_READABLE != 0
+            && info.protect & PAGE_GUARD == 0;
+        if usable {
+            match regions.last_mut() {
+                Some(last) if last.1 + 1 == start => last.1 = end,
+                _ => regions.push((start, end)),
+            }
+        }
+        addr = start + info.region_size;
+    }
+
+    regions.retain(|&(start, end)| end - start + 1 >= min_size);
+    regions
+}
+
+/// Returns the current thread's priority, to check the effect of a
+/// ScrubThreadConfig
+pub fn thread_priority() -> io::Result<i32> {
+    // SAFETY: GetCurrentThread returns a pseudo handle that needs no
+    // closing
+    match unsafe { GetThreadPriority(GetCurrentThread()) } {
+        THREAD_PRIORITY_ERROR_RETURN => Err(io::Error::last_os_error()),
+        priority => Ok(priority),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_memory() {
+        assert!(installed_memory().unwrap() > 0);
+        assert!(page_files().is_ok());
+
+        // A buffer allocated here is in one of the regions
+        let buffer = vec![0u8; 1 << 20];
+        let addr = buffer.as_ptr() as usize;
+        assert!(mapped_regions(4096)
+            .iter()
+            .any(|&(start, end)| start <= addr && addr <= end));
+    }
+}
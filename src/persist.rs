@@ -0,0 +1,857 @@
+// Scrubbing state kept across restarts. Without it, a restarted scrubber
+// starts the first pass over, forgets which areas were scrubbed recently
+// and loses the error history that policies and health scores are based
+// on. A ScrubState holds what is worth keeping: the pass epoch and count,
+// the position in the current pass, the areas with their error counts and
+// when they were last scrubbed, and the ranges excluded from scrubbing.
+//
+// Areas are matched by a stable identifier rather than by index, since the
+// areas may be discovered in a different order, or some may be gone, after
+// a restart. The identifier is the label of the area if it has one and its
+// extent otherwise. The position in the pass is only restored if the areas
+// are the same, in the same order, as when the state was saved.
+//
+// The state is saved as text with one item per line:
+//
+//  epoch <epoch>
+//  passes <passes>
+//  offset <offset>
+//  area <start> <end> <epoch> <corrected> <uncorrected> <rate> <time> <id>
+//  exclude <start> <end>
+//  keys physical
+//
+// Addresses are in hex with a leading 0x. The epoch and time of an area
+// are - if it has not been scrubbed, and the time is in seconds since the
+// Unix epoch, since an Instant means nothing to the next process. The id
+// is the rest of the line. Blank lines and lines starting with # are
+// ignored.
+//
+// Virtual addresses don't survive a restart when the memory is mapped
+// afresh, as with address space randomization: the same physical memory
+// can come back at a different address. A state captured with
+// capture_physical() is keyed by physical address instead, through an
+// AddressTranslator such as PagemapTranslator. Areas without a label are
+// identified by the physical address of their first byte and their size,
+// and the excluded ranges are held as physical ranges, marked by the keys
+// line. resolve() turns such a state back into one for the virtual
+// addresses at which the memory is now mapped. A state can't be captured
+// while an excluded page can't be translated, as when it isn't present,
+// since its memory couldn't be found again; pages that can't be translated
+// when resolving aren't mapped to excluded memory and are left out.
+//
+// A StateSaver writes the state to a file on a periodic cadence. The file
+// is written under a temporary name, synced and renamed into place, so a
+// crash while saving leaves the previous state intact rather than a file
+// whose data never reached the disk.
+
+use std::fmt;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::addr::*;
+use crate::backend::*;
+use crate::badblocks::*;
+use crate::base::*;
+use crate::dedup::*;
+
+/// Saved state of a single scrub area
+///
+/// * `id` - Stable identifier for the area
+///
+/// * `start` - First address in the area
+///
+/// * `end` - Last address in the area
+///
+/// * `last_epoch` - Epoch of the last pass covering the area, if any
+///
+/// * `errors_corrected` - Number of corrected errors in the area
+///
+/// * `errors_uncorrected` - Number of uncorrected errors in the area
+///
+/// * `error_rate` - Estimated corrected errors per hour
+///
+/// * `last_scrubbed` - Time the last pass covering the area completed, if
+///   any
+#[derive(Clone, Debug, PartialEq)]
+pub struct AreaState {
+    pub id: String,
+    pub start: usize,
+    pub end: usize,
+    pub last_epoch: Option<u64>,
+    pub errors_corrected: u64,
+    pub errors_uncorrected: u64,
+    pub error_rate: f64,
+    pub last_scrubbed: Option<SystemTime>,
+}
+
+/// Scrubbing state to be restored after a restart
+///
+/// * `epoch` - Epoch of the pass in progress
+///
+/// * `passes` - Number of passes completed
+///
+/// * `pass_offset` - Number of bytes of the current pass scrubbed
+///
+/// * `areas` - State of each scrub area, in scrubbing order
+///
+/// * `excluded` - Ranges excluded from scrubbing, end inclusive
+///
+/// * `physical` - Whether the excluded ranges, and the ids of areas
+///   without labels, are physical addresses
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScrubState {
+    pub epoch: u64,
+    pub passes: u64,
+    pub pass_offset: usize,
+    pub areas: Vec<AreaState>,
+    pub excluded: Vec<(usize, usize)>,
+    pub physical: bool,
+}
+
+// Parse a field that is - when absent
+fn parse_optional<T: std::str::FromStr>(s: &str) -> Option<Option<T>> {
+    match s {
+        "-" => Some(None),
+        s => s.parse().ok().map(Some),
+    }
+}
+
+// Returns the stable identifier of an area
+fn area_id(label: &Option<String>, start: usize, end: usize) -> String {
+    label
+        .clone()
+        .unwrap_or_else(|| format!("{:#x}-{:#x}", start, end))
+}
+
+// Returns the identifier of an area keyed by physical address, or by its
+// extent if its first byte can't be translated
+fn physical_area_id(
+    label: &Option<String>,
+    start: usize,
+    end: usize,
+    translator: &mut dyn AddressTranslator,
+) -> String {
+    match (label, translator.physical(start)) {
+        (None, Some(phys)) => {
+            format!("phys {:#x}+{:#x}", phys, end - start + 1)
+        }
+        _ => area_id(label, start, end),
+    }
+}
+
+// Calls a function with each page of a virtual range, as (first, last,
+// physical address of first), where first and last are the virtual
+// addresses of the part of the page in the range. The physical address is
+// None for pages that can't be translated. Stops at the first error.
+fn for_each_page<F>(
+    start: usize,
+    end: usize,
+    page_size: usize,
+    translator: &mut dyn AddressTranslator,
+    mut f: F,
+) -> Result<(), Error>
+where
+    F: FnMut(usize, usize, Option<u64>) -> Result<(), Error>,
+{
+    let mask = page_size - 1;
+    let mut page = start & !mask;
+    loop {
+        let first = page.max(start);
+        let last = page.saturating_add(mask).min(end);
+        f(first, last, translator.physical(first))?;
+        page = match page.checked_add(page_size) {
+            Some(page) if page <= end => page,
+            _ => return Ok(()),
+        };
+    }
+}
+
+// Error for a bad line in a saved state
+fn invalid(line: usize) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("line {}: bad state", line + 1),
+    )
+}
+
+// Parse the fields of an area line, after the keyword
+fn parse_area(fields: &str) -> Option<AreaState> {
+    let mut words = fields.splitn(8, ' ');
+    let start = parse_number(words.next()?, 10)?;
+    let end = parse_number(words.next()?, 10)?;
+    let last_epoch = parse_optional(words.next()?)?;
+    let errors_corrected = words.next()?.parse().ok()?;
+    let errors_uncorrected = words.next()?.parse().ok()?;
+    let error_rate = words.next()?.parse().ok()?;
+    let last_scrubbed = parse_optional::<u64>(words.next()?)?
+        .map(|secs| UNIX_EPOCH + Duration::from_secs(secs));
+    let id = words.next().filter(|id| !id.is_empty())?.to_string();
+    Some(AreaState {
+        id,
+        start,
+        end,
+        last_epoch,
+        errors_corrected,
+        errors_uncorrected,
+        error_rate,
+        last_scrubbed,
+    })
+}
+
+impl ScrubState {
+    /// Capture the state of a scrubber
+    pub fn capture<B: ScrubBackend>(
+        scrubber: &LineScrubber<B>,
+    ) -> ScrubState {
+        let stats = scrubber.stats();
+        let (now, system_now) =
+            (scrubber.clock().now(), scrubber.clock().wall());
+        let areas = scrubber
+            .extents()
+            .iter()
+            .zip(&stats.areas)
+            .map(|(&(start, end), area)| AreaState {
+                id: area_id(&area.label, start, end),
+                start,
+                end,
+                last_epoch: area.last_epoch,
+                errors_corrected: area.errors_corrected,
+                errors_uncorrected: area.errors_uncorrected,
+                error_rate: area.error_rate,
+                last_scrubbed: area
+                    .last_scrubbed
+                    .map(|t| system_now - now.duration_since(t)),
+            })
+            .collect();
+
+        ScrubState {
+            epoch: stats.epoch,
+            passes: stats.passes,
+            pass_offset: stats.pass_offset,
+            areas,
+            excluded: scrubber.excluded().to_vec(),
+            physical: false,
+        }
+    }
+
+    /// Restore the state into a scrubber. Areas of the scrubber that are
+    /// not in the saved state keep their current statistics, and saved
+    /// areas that no longer exist are ignored.
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::CheckpointMismatch) if the
+    /// state is keyed by physical address and has not been resolved, or
+    /// another Err(Error) if the position in the pass could not be
+    /// restored
+    pub fn restore<B: ScrubBackend>(
+        &self,
+        scrubber: &mut LineScrubber<B>,
+    ) -> Result<(), Error> {
+        if self.physical {
+            return Err(Error::CheckpointMismatch);
+        }
+        let same_areas = scrubber
+            .extents()
+            .iter()
+            .copied()
+            .eq(self.areas.iter().map(|a| (a.start, a.end)));
+        let extents = scrubber.extents().to_vec();
+        let (now, system_now) =
+            (scrubber.clock().now(), scrubber.clock().wall());
+
+        let stats = scrubber.stats_mut();
+        for (area, &(start, end)) in stats.areas.iter_mut().zip(&extents) {
+            let id = area_id(&area.label, start, end);
+            let Some(saved) = self.areas.iter().find(|a| a.id == id)
+            else {
+                continue;
+            };
+            area.last_epoch = saved.last_epoch;
+            area.errors_corrected = saved.errors_corrected;
+            area.errors_uncorrected = saved.errors_uncorrected;
+            area.error_rate = saved.error_rate;
+            area.last_scrubbed = saved.last_scrubbed.and_then(|t| {
+                now.checked_sub(
+                    system_now.duration_since(t).unwrap_or_default(),
+                )
+            });
+        }
+        stats.set_epoch(self.epoch);
+        stats.passes = self.passes;
+
+        for &(start, end) in &self.excluded {
+            scrubber.exclude(start, end);
+        }
+        if same_areas && self.pass_offset != 0 {
+            scrubber.resume_at(self.pass_offset)?;
+        }
+        Ok(())
+    }
+
+    /// Capture the state of a scrubber keyed by physical address, so that
+    /// it can be restored after the memory is mapped at other addresses
+    ///
+    /// # Arguments:
+    /// * `scrubber` - The scrubber
+    ///
+    /// * `translator` - Translates the addresses of the scrubber
+    ///
+    /// * `page_size` - Number of bytes in a page, a power of two
+    ///
+    /// # Returns:
+    /// Ok(ScrubState) on success, otherwise Err(Error::UnalignedValue) if
+    /// the page size isn't a power of two, Err(Error::Untranslated) if an
+    /// excluded page can't be translated, or Err(Error::AddressOverflow)
+    /// if a physical address doesn't fit in a usize
+    pub fn capture_physical<B: ScrubBackend>(
+        scrubber: &LineScrubber<B>,
+        translator: &mut dyn AddressTranslator,
+        page_size: usize,
+    ) -> Result<ScrubState, Error> {
+        if !page_size.is_power_of_two() {
+            return Err(Error::UnalignedValue);
+        }
+        let mut state = ScrubState::capture(scrubber);
+        for (saved, area) in
+            state.areas.iter_mut().zip(&scrubber.stats().areas)
+        {
+            saved.id = physical_area_id(
+                &area.label,
+                saved.start,
+                saved.end,
+                translator,
+            );
+        }
+
+        // Physically contiguous pages make one range. An excluded page
+        // that can't be translated can't be found again after a restart,
+        // so the state can't be captured.
+        let mut excluded: Vec<(usize, usize)> = Vec::new();
+        for &(start, end) in scrubber.excluded() {
+            for_each_page(
+                start,
+                end,
+                page_size,
+                translator,
+                |f, l, p| {
+                    let p = p.ok_or(Error::Untranslated)?;
+                    let first = usize::try_from(p)
+                        .map_err(|_| Error::AddressOverflow)?;
+                    let last = first
+                        .checked_add(l - f)
+                        .ok_or(Error::AddressOverflow)?;
+                    match excluded.last_mut() {
+                        Some((_, e))
+                            if e.checked_add(1) == Some(first) =>
+                        {
+                            *e = last
+                        }
+                        _ => excluded.push((first, last)),
+                    }
+                    Ok(())
+                },
+            )?;
+        }
+        let mut merged = Vec::new();
+        for range in excluded {
+            add_range(&mut merged, range);
+        }
+        state.excluded = merged;
+        state.physical = true;
+        Ok(state)
+    }
+
+    /// Re-resolve a state keyed by physical address to the virtual
+    /// addresses at which a scrubber now has the memory mapped. A state
+    /// keyed by virtual address is returned unchanged.
+    ///
+    /// # Arguments:
+    /// * `scrubber` - The scrubber the state is to be restored into
+    ///
+    /// * `translator` - Translates the addresses of the scrubber
+    ///
+    /// * `page_size` - Number of bytes in a page, a power of two
+    ///
+    /// # Returns:
+    /// Ok(ScrubState) keyed by virtual address on success, otherwise
+    /// Err(Error::UnalignedValue) if the page size isn't a power of two
+    pub fn resolve<B: ScrubBackend>(
+        &self,
+        scrubber: &LineScrubber<B>,
+        translator: &mut dyn AddressTranslator,
+        page_size: usize,
+    ) -> Result<ScrubState, Error> {
+        if !page_size.is_power_of_two() {
+            return Err(Error::UnalignedValue);
+        }
+        if !self.physical {
+            return Ok(self.clone());
+        }
+
+        // Saved areas take the extent of the area with the same physical
+        // id, so that the position in the pass is kept if all match
+        let mut areas = self.areas.clone();
+        let extents = scrubber.extents();
+        for (area, &(start, end)) in
+            scrubber.stats().areas.iter().zip(extents)
+        {
+            let id = physical_area_id(&area.label, start, end, translator);
+            if let Some(saved) = areas.iter_mut().find(|a| a.id == id) {
+                saved.id = area_id(&area.label, start, end);
+                saved.start = start;
+                saved.end = end;
+            }
+        }
+
+        // Pages are matched to the excluded ranges by physical address.
+        // Pages that can't be translated now aren't mapped to the memory
+        // excluded, so they are left out.
+        let mut pages: Vec<(u64, usize, usize)> = Vec::new();
+        for &(start, end) in extents {
+            for_each_page(
+                start,
+                end,
+                page_size,
+                translator,
+                |f, l, p| {
+                    if let Some(p) = p {
+                        pages.push((p, f, l));
+                    }
+                    Ok(())
+                },
+            )?;
+        }
+        pages.sort_unstable();
+
+        let mut excluded = Vec::new();
+        for &(s, e) in &self.excluded {
+            let (s, e) = (s as u64, e as u64);
+            let i = pages.partition_point(|&(p, f, l)| {
+                p.saturating_add((l - f) as u64) < s
+            });
+            for &(p, f, l) in
+                pages[i..].iter().take_while(|&&(p, ..)| p <= e)
+            {
+                let last = p + (l - f) as u64;
+                if last < s {
+                    continue;
+                }
+                add_range(
+                    &mut excluded,
+                    (
+                        f + (s.max(p) - p) as usize,
+                        f + (e.min(last) - p) as usize,
+                    ),
+                );
+            }
+        }
+
+        Ok(ScrubState {
+            areas,
+            excluded,
+            physical: false,
+            ..self.clone()
+        })
+    }
+
+    /// Parse a state in the format described above
+    pub fn parse(text: &str) -> io::Result<ScrubState> {
+        let mut state = ScrubState::default();
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (key, rest) =
+                line.split_once(' ').ok_or_else(|| invalid(n))?;
+            let rest = rest.trim_start();
+            match key {
+                "epoch" => {
+                    state.epoch = rest.parse().map_err(|_| invalid(n))?
+                }
+                "passes" => {
+                    state.passes = rest.parse().map_err(|_| invalid(n))?
+                }
+                "offset" => {
+                    state.pass_offset =
+                        parse_number(rest, 10).ok_or_else(|| invalid(n))?
+                }
+                "area" => state
+                    .areas
+                    .push(parse_area(rest).ok_or_else(|| invalid(n))?),
+                "keys" => match rest {
+                    "physical" => state.physical = true,
+                    "virtual" => state.physical = false,
+                    _ => return Err(invalid(n)),
+                },
+                "exclude" => {
+                    let mut words = rest
+                        .split_whitespace()
+                        .map(|w| parse_number(w, 10));
+                    match (words.next(), words.next(), words.next()) {
+                        (Some(Some(start)), Some(Some(end)), None) => {
+                            state.excluded.push((start, end))
+                        }
+                        _ => return Err(invalid(n)),
+                    }
+                }
+                _ => return Err(invalid(n)),
+            }
+        }
+        Ok(state)
+    }
+
+    /// Return the state in the format described above
+    pub fn to_text(&self) -> String {
+        let mut text = format!(
+            "epoch {}\npasses {}\noffset {:#x}\n",
+            self.epoch, self.passes, self.pass_offset
+        );
+        for area in &self.areas {
+            let epoch =
+                area.last_epoch.map_or("-".to_string(), |e| e.to_string());
+            let time = area
+                .last_scrubbed
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map_or("-".to_string(), |d| d.as_secs().to_string());
+            text.push_str(&format!(
+                "area {:#x} {:#x} {} {} {} {} {} {}\n",
+                area.start,
+                area.end,
+                epoch,
+                area.errors_corrected,
+                area.errors_uncorrected,
+                area.error_rate,
+                time,
+                area.id
+            ));
+        }
+        for &(start, end) in &self.excluded {
+            text.push_str(&format!("exclude {:#x} {:#x}\n", start, end));
+        }
+        if self.physical {
+            text.push_str("keys physical\n");
+        }
+        text
+    }
+
+    /// Read a state from a file
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<ScrubState> {
+        ScrubState::parse(&fs::read_to_string(path)?)
+    }
+
+    /// Write the state to a file, replacing any previous state only once
+    /// the new one has been completely written
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        let mut file = fs::File::create(&temp)?;
+        file.write_all(self.to_text().as_bytes())?;
+        file.sync_all()?;
+        fs::rename(&temp, path)
+    }
+}
+
+/// Saves the state of a scrubber to a file periodically
+///
+/// * `path` - File holding the state
+///
+/// * `interval` - Minimum time between saves
+///
+/// * `last` - Time of the last save, if any
+///
+/// * `translator` - Translates the addresses of the scrubber, if the
+///   state is keyed by physical address
+///
+/// * `page_size` - Number of bytes in a page, for the translator
+pub struct StateSaver {
+    path: PathBuf,
+    interval: Duration,
+    last: Option<Instant>,
+    translator: Option<Box<dyn AddressTranslator + Send>>,
+    page_size: usize,
+}
+
+impl fmt::Debug for StateSaver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StateSaver")
+            .field("path", &self.path)
+            .field("interval", &self.interval)
+            .field("last", &self.last)
+            .field("physical", &self.translator.is_some())
+            .field("page_size", &self.page_size)
+            .finish()
+    }
+}
+
+impl StateSaver {
+    /// Create a saver
+    ///
+    /// # Arguments:
+    /// * `path` - File to hold the state
+    ///
+    /// * `interval` - Minimum time between saves
+    pub fn new<P: AsRef<Path>>(path: P, interval: Duration) -> StateSaver {
+        StateSaver {
+            path: path.as_ref().to_path_buf(),
+            interval,
+            last: None,
+            translator: None,
+            page_size: 0,
+        }
+    }
+
+    /// Key the state saved by physical address, so that it still applies
+    /// after the memory is mapped at other addresses, and re-resolve the
+    /// state loaded to the addresses the memory is mapped at now
+    ///
+    /// # Arguments:
+    /// * `translator` - Translates the addresses of the scrubber
+    ///
+    /// * `page_size` - Number of bytes in a page, a power of two
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::UnalignedValue) if the
+    /// page size isn't a power of two
+    pub fn set_translator(
+        &mut self,
+        translator: Box<dyn AddressTranslator + Send>,
+        page_size: usize,
+    ) -> Result<(), Error> {
+        if !page_size.is_power_of_two() {
+            return Err(Error::UnalignedValue);
+        }
+        self.translator = Some(translator);
+        self.page_size = page_size;
+        Ok(())
+    }
+
+    /// Returns the file holding the state
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Restore the saved state, if there is any, into a scrubber
+    ///
+    /// # Returns:
+    /// Ok(true) if state was restored, Ok(false) if none has been saved,
+    /// otherwise Err(io::Error) if it could not be read or restored
+    pub fn load<B: ScrubBackend>(
+        &mut self,
+        scrubber: &mut LineScrubber<B>,
+    ) -> io::Result<bool> {
+        let state = match ScrubState::load(&self.path) {
+            Ok(state) => state,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return Ok(false)
+            }
+            Err(e) => return Err(e),
+        };
+        let resolved = match self.translator.as_mut() {
+            Some(translator) => state.resolve(
+                scrubber,
+                translator.as_mut(),
+                self.page_size,
+            ),
+            None => Ok(state),
+        };
+        resolved.and_then(|state| state.restore(scrubber)).map_err(
+            |e| io::Error::new(io::ErrorKind::InvalidData, e.to_string()),
+        )?;
+        Ok(true)
+    }
+
+    /// Save the state of a scrubber now
+    pub fn save<B: ScrubBackend>(
+        &mut self,
+        scrubber: &LineScrubber<B>,
+        now: Instant,
+    ) -> io::Result<()> {
+        let state = match self.translator.as_mut() {
+            Some(translator) => ScrubState::capture_physical(
+                scrubber,
+                translator.as_mut(),
+                self.page_size,
+            )
+            .map_err(|e| io::Error::other(e.to_string()))?,
+            None => ScrubState::capture(scrubber),
+        };
+        state.save(&self.path)?;
+        self.last = Some(now);
+        Ok(())
+    }
+
+    /// Save the state of a scrubber if the interval has passed since the
+    /// last save
+    ///
+    /// # Returns:
+    /// Ok(true) if the state was saved, otherwise Ok(false) or
+    /// Err(io::Error) if saving failed
+    pub fn maybe_save<B: ScrubBackend>(
+        &mut self,
+        scrubber: &LineScrubber<B>,
+        now: Instant,
+    ) -> io::Result<bool> {
+        if self
+            .last
+            .is_some_and(|last| now.duration_since(last) < self.interval)
+        {
+            return Ok(false);
+        }
+        self.save(scrubber, now)?;
+        Ok(true)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::*;
+    use crate::testutil::*;
+
+    fn scrubber() -> LineScrubber<SimMemory> {
+        let mem = SimMemory::new(0, 16384, 64).unwrap();
+        LineScrubber::new(mem, &[(0, 4095), (8192, 12287)], 64, 4).unwrap()
+    }
+
+    #[test]
+    fn test_text() {
+        let mut scrubber = scrubber();
+        scrubber.scrub(8192 + 1024).unwrap();
+        scrubber.stats_mut().areas[1].label = Some("dimm 1".to_string());
+        scrubber.stats_mut().record_error(1, true, Instant::now());
+        scrubber.exclude(0x100, 0x13f);
+
+        let state = ScrubState::capture(&scrubber);
+        assert_eq!(state.epoch, 2);
+        assert_eq!(state.passes, 1);
+        assert_eq!(state.pass_offset, 1024);
+        assert_eq!(state.areas[0].id, "0x0-0xfff");
+        assert_eq!(state.areas[1].id, "dimm 1");
+        assert_eq!(state.areas[1].errors_corrected, 1);
+
+        let parsed = ScrubState::parse(&state.to_text()).unwrap();
+        assert_eq!(parsed.areas[1].id, "dimm 1");
+        assert_eq!(parsed.excluded, [(0x100, 0x13f)]);
+        assert!(parsed.areas[0].last_scrubbed.is_some());
+        assert!(ScrubState::parse("epoch x\n").is_err());
+        assert!(ScrubState::parse("bogus 1\n").is_err());
+    }
+
+    #[test]
+    fn test_restore() {
+        let mut scrubber = scrubber();
+        scrubber.scrub(8192 + 1024).unwrap();
+        scrubber.stats_mut().record_error(0, false, Instant::now());
+        scrubber.exclude(0x200, 0x23f);
+        let state =
+            ScrubState::parse(&ScrubState::capture(&scrubber).to_text())
+                .unwrap();
+
+        let mut restored = self::scrubber();
+        state.restore(&mut restored).unwrap();
+        let stats = restored.stats();
+        assert_eq!(stats.epoch, 2);
+        assert_eq!(stats.passes, 1);
+        assert_eq!(stats.pass_offset, 1024);
+        assert_eq!(stats.areas[0].errors_uncorrected, 1);
+        assert_eq!(stats.areas[0].last_epoch, Some(1));
+        assert_eq!(restored.excluded(), [(0x200, 0x23f)]);
+
+        // The rest of the pass completes it
+        restored.scrub(8192 - 1024).unwrap();
+        assert_eq!(restored.stats().passes, 2);
+
+        // Different areas get statistics by id but start a new pass
+        let mem = SimMemory::new(0, 16384, 64).unwrap();
+        let mut other =
+            LineScrubber::new(mem, &[(0, 4095)], 64, 4).unwrap();
+        state.restore(&mut other).unwrap();
+        assert_eq!(other.stats().pass_offset, 0);
+        assert_eq!(other.stats().areas[0].errors_uncorrected, 1);
+    }
+
+    #[test]
+    fn test_saver() {
+        let dir = scratch_path("persist");
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("state");
+        let mut saver = StateSaver::new(&path, Duration::from_secs(60));
+
+        let mut scrubber = scrubber();
+        assert!(!saver.load(&mut scrubber).unwrap());
+        let now = Instant::now();
+        assert!(saver.maybe_save(&scrubber, now).unwrap());
+        assert!(!saver.maybe_save(&scrubber, now).unwrap());
+        assert!(saver
+            .maybe_save(&scrubber, now + Duration::from_secs(60))
+            .unwrap());
+        assert!(saver.load(&mut scrubber).unwrap());
+
+        fs::write(&path, "area\n").unwrap();
+        assert!(saver.load(&mut scrubber).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_physical() {
+        // The same physical memory is mapped at 0x10000 in the first run
+        // and at 0x30000 in the second
+        let scrubber = |base: usize| {
+            let mem = SimMemory::new(0x10000, 0x30000, 64).unwrap();
+            let extents =
+                [(base, base + 0x1fff), (base + 0x4000, base + 0x5fff)];
+            LineScrubber::new(mem, &extents, 64, 4).unwrap()
+        };
+        let mut first = |addr: usize| Some(addr as u64 - 0x10000);
+        let mut second = |addr: usize| Some(addr as u64 - 0x30000);
+
+        let mut before = scrubber(0x10000);
+        before.scrub(8192 + 1024).unwrap();
+        before.stats_mut().record_error(1, true, Instant::now());
+        before.exclude(0x10f00, 0x1103f);
+        let state =
+            ScrubState::capture_physical(&before, &mut first, 4096)
+                .unwrap();
+        assert_eq!(state.areas[1].id, "phys 0x4000+0x2000");
+        assert_eq!(state.excluded, [(0xf00, 0x103f)]);
+        let mut missing = |addr: usize| match addr {
+            0x11000.. => None,
+            _ => Some(addr as u64 - 0x10000),
+        };
+        assert_eq!(
+            ScrubState::capture_physical(&before, &mut missing, 4096),
+            Err(Error::Untranslated)
+        );
+        let state = ScrubState::parse(&state.to_text()).unwrap();
+        assert!(state.physical);
+
+        let mut after = scrubber(0x30000);
+        assert_eq!(
+            state.restore(&mut after),
+            Err(Error::CheckpointMismatch)
+        );
+        let resolved = state.resolve(&after, &mut second, 4096).unwrap();
+        resolved.restore(&mut after).unwrap();
+        let stats = after.stats();
+        assert_eq!(stats.pass_offset, 9216);
+        assert_eq!(stats.areas[1].errors_corrected, 1);
+        assert_eq!(after.excluded(), [(0x30f00, 0x3103f)]);
+        assert!(state.resolve(&after, &mut second, 1000).is_err());
+
+        // A StateSaver with a translator does the same
+        let path = scratch_path("physical");
+        let mut saver = StateSaver::new(&path, Duration::from_secs(60));
+        saver.set_translator(Box::new(first), 4096).unwrap();
+        saver.save(&before, Instant::now()).unwrap();
+        let mut saver = StateSaver::new(&path, Duration::from_secs(60));
+        saver.set_translator(Box::new(second), 4096).unwrap();
+        let mut after = scrubber(0x30000);
+        assert!(saver.load(&mut after).unwrap());
+        assert_eq!(after.excluded(), [(0x30f00, 0x3103f)]);
+        fs::remove_file(&path).unwrap();
+    }
+}
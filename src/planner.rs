@@ -0,0 +1,341 @@
+// Planning which memory the software scrubber owns. On many systems the
+// memory controller patrol-scrubs DRAM in hardware, but other memory, such
+// as on-chip SRAM, persistent memory or memory on a device, has no patrol
+// scrubber. Given a description of the memory and of what hardware
+// covers, the planner works out the scrub areas left to software and the
+// rate at which each must be scrubbed to meet its interval. Memory that
+// neither hardware nor software can cover is reported as a gap.
+
+use std::time::Duration;
+
+use crate::base::*;
+
+/// The kind of memory in a region
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegionKind {
+    Dram,
+    Sram,
+    PersistentMemory,
+    DeviceMemory,
+}
+
+/// A region of memory that must be scrubbed
+///
+/// * `start` - First address in the region
+///
+/// * `end` - Last address in the region
+///
+/// * `label` - Name of the region
+///
+/// * `kind` - The kind of memory
+///
+/// * `interval` - Longest time allowed between scrubs of any byte
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemoryRegion {
+    pub start: usize,
+    pub end: usize,
+    pub label: String,
+    pub kind: RegionKind,
+    pub interval: Duration,
+}
+
+/// What memory there is and what covers it
+///
+/// * `regions` - Memory that must be scrubbed
+///
+/// * `hardware` - (start, end) of each range covered by a hardware patrol
+///   scrubber, end inclusive
+///
+/// * `excluded` - (start, end) of each range software must not read, such
+///   as device registers mixed in with device memory, end inclusive
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CoverageDesc {
+    pub regions: Vec<MemoryRegion>,
+    pub hardware: Vec<(usize, usize)>,
+    pub excluded: Vec<(usize, usize)>,
+}
+
+/// A scrub area the software scrubber owns
+///
+/// * `start` - First address in the area, cache line aligned
+///
+/// * `end` - Last address in the area
+///
+/// * `label` - Name of the region the area is part of
+///
+/// * `rate` - Bytes per second needed to meet the region's interval
+#[derive(Clone, Debug, PartialEq)]
+pub struct PlannedArea {
+    pub start: usize,
+    pub end: usize,
+    pub label: String,
+    pub rate: f64,
+}
+
+/// Why memory is left uncovered
+///
+/// * `Excluded` - Software must not read it
+///
+/// * `Unaligned` - Part of a cache line that is only partly in the region
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GapReason {
+    Excluded,
+    Unaligned,
+}
+
+/// Memory covered by neither hardware nor software
+///
+/// * `start` - First address in the gap
+///
+/// * `end` - Last address in the gap
+///
+/// * `label` - Name of the region the gap is part of
+///
+/// * `reason` - Why it is uncovered
+#[derive(Clone, Debug, PartialEq)]
+pub struct CoverageGap {
+    pub start: usize,
+    pub end: usize,
+    pub label: String,
+    pub reason: GapReason,
+}
+
+/// What the software scrubber must cover
+///
+/// * `areas` - Scrub areas owned by software, in address order within
+///   each region
+///
+/// * `gaps` - Memory left uncovered
+///
+/// * `warnings` - Description of each gap
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct CoveragePlan {
+    pub areas: Vec<PlannedArea>,
+    pub gaps: Vec<CoverageGap>,
+    pub warnings: Vec<String>,
+}
+
+impl CoveragePlan {
+    /// Returns the (start, end) of each area, end inclusive, for use as
+    /// scrub areas
+    pub fn extents(&self) -> Vec<(usize, usize)> {
+        self.areas.iter().map(|a| (a.start, a.end)).collect()
+    }
+
+    /// Returns the bytes per second needed to meet every interval
+    pub fn total_rate(&self) -> f64 {
+        self.areas.iter().map(|a| a.rate).sum()
+    }
+}
+
+// (start, end) of each of a set of ranges, end inclusive
+type Ranges = Vec<(usize, usize)>;
+
+// Split [start, end] into the parts outside of and inside of the cuts
+fn split(
+    start: usize,
+    end: usize,
+    cuts: &[(usize, usize)],
+) -> (Ranges, Ranges) {
+    let mut cuts: Ranges = cuts
+        .iter()
+        .filter(|&&(s, e)| s <= end && e >= start)
+        .map(|&(s, e)| (s.max(start), e.min(end)))
+        .collect();
+    cuts.sort();
+
+    let mut outside = Vec::new();
+    let mut inside: Ranges = Vec::new();
+    let mut next = Some(start);
+    for (s, e) in cuts {
+        // Merge overlapping cuts
+        if let Some(last) = inside.last_mut() {
+            if s <= last.1 {
+                last.1 = last.1.max(e);
+                next = last.1.checked_add(1).filter(|&n| n <= end);
+                continue;
+            }
+        }
+        if let Some(n) = next {
+            if n < s {
+                outside.push((n, s - 1));
+            }
+        }
+        inside.push((s, e));
+        next = e.checked_add(1).filter(|&n| n <= end);
+    }
+    if let Some(n) = next {
+        outside.push((n, end));
+    }
+    (outside, inside)
+}
+
+/// Work out the scrub areas and rates left to the software scrubber
+///
+/// # Arguments:
+/// * `desc` - The memory and what covers it
+///
+/// * `line_size` - Number of bytes in a cache line, a power of two
+///
+/// # Returns:
+/// Ok(CoveragePlan), otherwise Err(Error) if a region is empty or has a
+/// zero interval, or the cache line size is not a power of two
+pub fn plan_coverage(
+    desc: &CoverageDesc,
+    line_size: usize,
+) -> Result<CoveragePlan, Error> {
+    if !line_size.is_power_of_two() {
+        return Err(Error::UnalignedValue);
+    }
+    let mut plan = CoveragePlan::default();
+
+    for region in &desc.regions {
+        if region.start > region.end {
+            return Err(Error::EmptyMemArea);
+        }
+        if region.interval.is_zero() {
+            return Err(Error::ZeroSize);
+        }
+
+        let mut gap = |start, end, reason| {
+            plan.warnings.push(format!(
+                "{:#x}-{:#x} in {} is not scrubbed: {}",
+                start,
+                end,
+                region.label,
+                match reason {
+                    GapReason::Excluded =>
+                        "excluded from software scrubbing",
+                    GapReason::Unaligned => "partial cache line",
+                }
+            ));
+            plan.gaps.push(CoverageGap {
+                start,
+                end,
+                label: region.label.clone(),
+                reason,
+            });
+        };
+
+        let (software, _) =
+            split(region.start, region.end, &desc.hardware);
+        let mut owned = Vec::new();
+        for (start, end) in software {
+            let (readable, excluded) = split(start, end, &desc.excluded);
+            for (s, e) in excluded {
+                gap(s, e, GapReason::Excluded);
+            }
+
+            for (s, e) in readable {
+                // Only whole cache lines can be scrubbed
+                let first = s.checked_next_multiple_of(line_size);
+                let last = match e % line_size == line_size - 1 {
+                    true => Some(e),
+                    false => (e - e % line_size).checked_sub(1),
+                };
+                match first.zip(last).filter(|&(first, last)| first < last)
+                {
+                    Some((first, last)) => {
+                        if first > s {
+                            gap(s, first - 1, GapReason::Unaligned);
+                        }
+                        if last < e {
+                            gap(last + 1, e, GapReason::Unaligned);
+                        }
+                        owned.push((first, last));
+                    }
+                    None => gap(s, e, GapReason::Unaligned),
+                }
+            }
+        }
+
+        let secs = region.interval.as_secs_f64();
+        plan.areas.extend(owned.into_iter().map(|(start, end)| {
+            PlannedArea {
+                start,
+                end,
+                label: region.label.clone(),
+                rate: (end - start + 1) as f64 / secs,
+            }
+        }));
+    }
+
+    Ok(plan)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn region(start: usize, end: usize, label: &str) -> MemoryRegion {
+        MemoryRegion {
+            start,
+            end,
+            label: label.to_string(),
+            kind: RegionKind::Dram,
+            interval: Duration::from_secs(100),
+        }
+    }
+
+    #[test]
+    fn test_split() {
+        let cuts = [(20, 29), (25, 39), (60, 200)];
+        assert_eq!(
+            split(0, 99, &cuts),
+            (vec![(0, 19), (40, 59)], vec![(20, 39), (60, 99)])
+        );
+        assert_eq!(split(0, 9, &cuts), (vec![(0, 9)], vec![]));
+        assert_eq!(split(20, 39, &cuts), (vec![], vec![(20, 39)]));
+        assert_eq!(
+            split(0, usize::MAX, &[(0, 9)]),
+            (vec![(10, usize::MAX)], vec![(0, 9)])
+        );
+    }
+
+    #[test]
+    fn test_plan() {
+        let mut sram = region(0x1000, 0x1fff, "sram");
+        sram.kind = RegionKind::Sram;
+        let mut dev = region(0x10020, 0x1101f, "dev");
+        dev.kind = RegionKind::DeviceMemory;
+        dev.interval = Duration::from_secs(10);
+        let desc = CoverageDesc {
+            regions: vec![region(0x100000, 0x1fffff, "dram"), sram, dev],
+            hardware: vec![(0x100000, 0x1fffff)],
+            excluded: vec![(0x10800, 0x10bff)],
+        };
+
+        let plan = plan_coverage(&desc, 64).unwrap();
+        assert_eq!(
+            plan.extents(),
+            vec![(0x1000, 0x1fff), (0x10040, 0x107ff), (0x10c00, 0x10fff)]
+        );
+        assert_eq!(plan.areas[0].rate, 4096.0 / 100.0);
+        assert_eq!(plan.areas[2].rate, 1024.0 / 10.0);
+
+        let gaps: Vec<(usize, usize, GapReason)> = plan
+            .gaps
+            .iter()
+            .map(|g| (g.start, g.end, g.reason))
+            .collect();
+        assert_eq!(
+            gaps,
+            vec![
+                (0x10800, 0x10bff, GapReason::Excluded),
+                (0x10020, 0x1003f, GapReason::Unaligned),
+                (0x11000, 0x1101f, GapReason::Unaligned),
+            ]
+        );
+        assert_eq!(plan.warnings.len(), 3);
+
+        let desc = CoverageDesc {
+            regions: vec![region(0x1000, 0x1000, "tiny")],
+            ..Default::default()
+        };
+        let plan = plan_coverage(&desc, 64).unwrap();
+        assert!(plan.areas.is_empty());
+        assert_eq!(plan.gaps[0].reason, GapReason::Unaligned);
+
+        assert_eq!(plan_coverage(&desc, 48), Err(Error::UnalignedValue));
+    }
+}
@@ -0,0 +1,407 @@
+// Policies that react to what happens while scrubbing. Each Policy is
+// given every event, scrubbing and errors alike, and answers with actions
+// for the scrubber to take: changing the rate of an area, excluding a range
+// from scrubbing, scrubbing the memory near an address, or flushing a line
+// and reading it again from memory. A policy may also limit the overall
+// scrub rate, as a Throttle does for a ThrottlePolicy. Policies added to a
+// LineScrubber or an AutoScrub form a pipeline, each seeing every event,
+// so behaviors can be combined and new ones added without changing the
+// scrubbing loop. A LineScrubber applies the exclusions of all of them
+// first and then the other actions in order, so that a line excluded for
+// an uncorrected error isn't read again by another policy's action.
+
+use std::time::Instant;
+
+use crate::event::*;
+use crate::storm::*;
+use crate::throttle::*;
+
+/// Something for the scrubber to do in response to an event
+///
+/// * `SetAreaRate` - Scrub an area at a multiple of the normal rate, as for
+///   LineScrubber::set_area_rate()
+///     * `area` - Index of the scrub area
+///     * `multiplier` - Rate multiplier. One is the normal rate.
+///
+/// * `Exclude` - Stop scrubbing a range of addresses
+///     * `start` - First address in the range
+///     * `end` - Last address in the range
+///
+/// * `ScrubNear` - Scrub the memory around an address now
+///     * `addr` - The address
+///     * `bytes` - Number of bytes to scrub on each side of it
+///
+/// * `FlushReread` - Flush the cache line holding an address and read it
+///   again from memory, as for LineScrubber::flush_reread()
+///     * `addr` - The address
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum PolicyAction {
+    SetAreaRate { area: usize, multiplier: usize },
+    Exclude { start: usize, end: usize },
+    ScrubNear { addr: usize, bytes: usize },
+    FlushReread { addr: usize },
+}
+
+/// A change made to the configuration of a scrubber
+///
+/// * `Exclude` - A range of addresses was excluded from scrubbing, as by
+///   LineScrubber::exclude()
+///     * `start` - First address in the range
+///     * `end` - Last address in the range
+///
+/// * `AreaRate` - The rate multiplier of a scrub area changed, as by
+///   LineScrubber::set_area_rate()
+///     * `area` - Index of the scrub area
+///     * `multiplier` - New rate multiplier
+///
+/// * `AddArea` - A scrub area was added
+///     * `area` - Index of the new scrub area
+///     * `start` - First address in the area
+///     * `end` - Last address in the area
+///
+/// * `RemoveArea` - A scrub area was removed
+///     * `area` - Index the scrub area had
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[non_exhaustive]
+pub enum ConfigChange {
+    Exclude {
+        start: usize,
+        end: usize,
+    },
+    AreaRate {
+        area: usize,
+        multiplier: usize,
+    },
+    AddArea {
+        area: usize,
+        start: usize,
+        end: usize,
+    },
+    RemoveArea {
+        area: usize,
+    },
+}
+
+impl ConfigChange {
+    /// Short name of the change
+    pub fn name(&self) -> &'static str {
+        match self {
+            ConfigChange::Exclude { .. } => "exclude",
+            ConfigChange::AreaRate { .. } => "area_rate",
+            ConfigChange::AddArea { .. } => "add_area",
+            ConfigChange::RemoveArea { .. } => "remove_area",
+        }
+    }
+
+    /// The change as a list of (name, value) pairs, not including its
+    /// name
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        match *self {
+            ConfigChange::Exclude { start, end } => vec![
+                ("start", format!("{:#x}", start)),
+                ("end", format!("{:#x}", end)),
+            ],
+            ConfigChange::AreaRate { area, multiplier } => vec![
+                ("area", area.to_string()),
+                ("multiplier", multiplier.to_string()),
+            ],
+            ConfigChange::AddArea { area, start, end } => vec![
+                ("area", area.to_string()),
+                ("start", format!("{:#x}", start)),
+                ("end", format!("{:#x}", end)),
+            ],
+            ConfigChange::RemoveArea { area } => {
+                vec![("area", area.to_string())]
+            }
+        }
+    }
+}
+
+/// Decides what to do in response to scrub events
+pub trait Policy {
+    /// Called for each event as it occurs
+    ///
+    /// # Arguments:
+    /// * `event` - The event
+    ///
+    /// * `now` - The current time
+    ///
+    /// # Returns:
+    /// The actions to take, in order
+    fn event(
+        &mut self,
+        event: &ScrubEvent,
+        now: Instant,
+    ) -> Vec<PolicyAction>;
+
+    /// Returns the fraction of the full scrub rate to use, as for
+    /// ThrottlePolicy::rate(). By default the rate isn't limited.
+    ///
+    /// # Arguments:
+    /// * `now` - The current time
+    fn rate(&mut self, _now: Instant) -> f64 {
+        1.0
+    }
+
+    /// Called when the policy is added to a scrubber and whenever its
+    /// scrub areas change, as when one is added or removed. By default
+    /// nothing is done.
+    ///
+    /// # Arguments:
+    /// * `extents` - The (start, end) address of each scrub area, end
+    ///   inclusive
+    fn areas_changed(&mut self, _extents: &[(usize, usize)]) {}
+
+    /// Called for each change made to the configuration of the scrubber,
+    /// whether by a policy's action or by a direct call. By default
+    /// nothing is done.
+    ///
+    /// # Arguments:
+    /// * `change` - The change
+    ///
+    /// * `now` - The current time
+    fn config_changed(&mut self, _change: &ConfigChange, _now: Instant) {}
+
+    /// Returns the address and size of each buffer the policy holds on
+    /// the heap, including those of any policies and event sinks it
+    /// holds, so that a scrubber leaving its own state out of the pass
+    /// leaves them out too. By default there are none.
+    fn buffers(&self) -> Vec<(usize, usize)> {
+        Vec::new()
+    }
+}
+
+// Returns the address and size of the buffer of a Vec
+pub(crate) fn vec_buffer<T>(v: &Vec<T>) -> (usize, usize) {
+    (v.as_ptr() as usize, v.capacity() * size_of::<T>())
+}
+
+// Returns the address and size of a value, such as one in a Box
+pub(crate) fn value_buffer<T: ?Sized>(value: &T) -> (usize, usize) {
+    (value as *const T as *const u8 as usize, size_of_val(value))
+}
+
+// Returns the buffers of a boxed policy, including the box itself
+pub(crate) fn policy_buffers(policy: &dyn Policy) -> Vec<(usize, usize)> {
+    let mut buffers = policy.buffers();
+    buffers.push(value_buffer(policy));
+    buffers
+}
+
+/// Limits the scrub rate with a ThrottlePolicy, such as an ImpactGuard, as
+/// part of a policy pipeline
+pub struct Throttle<'a>(pub Box<dyn ThrottlePolicy + 'a>);
+
+impl Policy for Throttle<'_> {
+    fn event(
+        &mut self,
+        _event: &ScrubEvent,
+        _now: Instant,
+    ) -> Vec<PolicyAction> {
+        Vec::new()
+    }
+
+    fn rate(&mut self, now: Instant) -> f64 {
+        self.0.rate(now)
+    }
+
+    fn buffers(&self) -> Vec<(usize, usize)> {
+        vec![value_buffer(self.0.as_ref())]
+    }
+}
+
+/// Scrubs the memory around each error as soon as it is reported, since
+/// errors tend to cluster in a row or column of a device
+///
+/// * `bytes` - Number of bytes to scrub on each side of the error
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DemandScrub {
+    pub bytes: usize,
+}
+
+impl Policy for DemandScrub {
+    fn event(
+        &mut self,
+        event: &ScrubEvent,
+        _now: Instant,
+    ) -> Vec<PolicyAction> {
+        match event {
+            ScrubEvent::Error(e) => vec![PolicyAction::ScrubNear {
+                addr: e.addr as usize,
+                bytes: self.bytes,
+            }],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Stops scrubbing memory with uncorrected errors, since reading it again
+/// raises another machine check
+///
+/// * `granularity` - Size of the block excluded around each error, such
+///   as the cache line or page size, a power of two
+///
+/// * `corrected` - Whether corrected errors exclude their blocks too.
+///   Excluding on a single corrected error is too aggressive, so this is
+///   meant for a Blacklist behind an ErrorThreshold, which passes on only
+///   the corrected errors of blocks that have crossed its threshold.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Blacklist {
+    pub granularity: usize,
+    pub corrected: bool,
+}
+
+impl Blacklist {
+    /// Create a Blacklist excluding blocks with uncorrected errors only
+    ///
+    /// # Arguments:
+    /// * `granularity` - Size of the block excluded around each error, a
+    ///   power of two
+    pub fn new(granularity: usize) -> Blacklist {
+        Blacklist {
+            granularity,
+            corrected: false,
+        }
+    }
+}
+
+// Pages are the smallest block that can be taken out of service
+impl Default for Blacklist {
+    fn default() -> Self {
+        Blacklist::new(4096)
+    }
+}
+
+impl Policy for Blacklist {
+    fn event(
+        &mut self,
+        event: &ScrubEvent,
+        _now: Instant,
+    ) -> Vec<PolicyAction> {
+        match event {
+            ScrubEvent::Error(e)
+                if e.severity == ErrorSeverity::Uncorrected
+                    || self.corrected =>
+            {
+                let start = e.addr as usize & !(self.granularity - 1);
+                vec![PolicyAction::Exclude {
+                    start,
+                    end: start + (self.granularity - 1),
+                }]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+// Storms start on errors and end when checked after a quiet period, which
+// is done on every event
+impl Policy for StormDetector<'_> {
+    fn event(
+        &mut self,
+        event: &ScrubEvent,
+        now: Instant,
+    ) -> Vec<PolicyAction> {
+        let mut actions = Vec::new();
+        if let ScrubEvent::Error(e) = event {
+            if self.record_error(e, now) {
+                actions.push(PolicyAction::SetAreaRate {
+                    area: e.area.unwrap_or_default(),
+                    multiplier: self.policy().multiplier,
+                });
+            }
+        }
+        for area in self.update(now) {
+            actions.push(PolicyAction::SetAreaRate {
+                area,
+                multiplier: 1,
+            });
+        }
+        actions
+    }
+
+    fn buffers(&self) -> Vec<(usize, usize)> {
+        self.held_buffers()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::*;
+    use crate::sim::*;
+
+    fn error(addr: u64, severity: ErrorSeverity) -> ErrorEvent {
+        ErrorEvent {
+            addr,
+            area: None,
+            severity,
+        }
+    }
+
+    #[test]
+    fn test_pipeline() {
+        let mem = SimMemory::new(0, 8192, 64).unwrap();
+        let mut scrubber =
+            LineScrubber::new(mem, &[(0, 4095), (4096, 8191)], 64, 4)
+                .unwrap();
+        scrubber.add_policy(Box::new(DemandScrub { bytes: 128 }));
+        scrubber.add_policy(Box::new(Blacklist::new(1024)));
+        let storms = StormPolicy {
+            threshold: 2,
+            ..StormPolicy::default()
+        };
+        scrubber
+            .add_policy(Box::new(StormDetector::new(storms, 2).unwrap()));
+
+        // The lines around the error are scrubbed at once
+        scrubber
+            .record_error(error(0x1100, ErrorSeverity::Corrected))
+            .unwrap();
+        let reads: Vec<u32> = (0x1000..0x1200)
+            .step_by(64)
+            .map(|a| scrubber.backend().reads_at(a).unwrap())
+            .collect();
+        assert_eq!(reads, [0, 0, 1, 1, 1, 1, 1, 0]);
+        assert_eq!(scrubber.area_rate(1), 1);
+        assert_eq!(scrubber.stats().areas[1].errors_corrected, 1);
+
+        scrubber
+            .record_error(error(0x1200, ErrorSeverity::Corrected))
+            .unwrap();
+        assert_eq!(scrubber.area_rate(1), 4);
+
+        // The block with the uncorrected error is excluded before the
+        // lines around the error are scrubbed, so it is never read again
+        scrubber
+            .record_error(error(0x500, ErrorSeverity::Uncorrected))
+            .unwrap();
+        assert_eq!(scrubber.excluded(), [(0x400, 0x7ff)]);
+        assert_eq!(scrubber.backend().reads_at(0x500), Some(0));
+        scrubber.scrub(8192).unwrap();
+        assert_eq!(scrubber.backend().reads_at(0x400), Some(0));
+        assert_eq!(scrubber.backend().reads_at(0x500), Some(0));
+        assert_eq!(scrubber.backend().reads_at(0x800), Some(1));
+    }
+
+    #[test]
+    fn test_throttle() {
+        struct Half;
+        impl ThrottlePolicy for Half {
+            fn rate(&mut self, _now: Instant) -> f64 {
+                0.5
+            }
+        }
+
+        let now = Instant::now();
+        let mut throttle = Throttle(Box::new(Half));
+        assert_eq!(throttle.rate(now), 0.5);
+        let event = ScrubEvent::ChunkComplete {
+            bytes: 64,
+            duration: Default::default(),
+        };
+        assert!(throttle.event(&event, now).is_empty());
+        assert_eq!(DemandScrub { bytes: 64 }.rate(now), 1.0);
+    }
+}
@@ -0,0 +1,573 @@
+// Pre-flight validation of a scrubber configuration. A deployment pipeline
+// wants to know that a configuration will be accepted, and what it will
+// actually do, before it is rolled out to machines where a mistake means
+// a scrubber that doesn't start or quietly covers less than intended.
+// validate() takes a description of the scrubber, finds the RAM if asked
+// to, makes every check made when a MemoryScrubber or LineScrubber is
+// created and configured, translates the areas to physical memory if
+// asked to, and reports the result: the areas as they will be scrubbed
+// once aligned to cache lines, the ranges excluded, the aliases they are
+// read through, the schedule of a pass and how long a pass should take.
+//
+// The checks are made by configuring a LineScrubber whose backend never
+// reads, so nothing is read and the answers are those the real scrubber
+// would give. Every problem found is reported rather than only the first,
+// so a configuration can be fixed in one go.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+use crate::alias::*;
+use crate::area::*;
+use crate::backend::*;
+use crate::base::*;
+use crate::dedup::*;
+use crate::dryrun::*;
+use crate::os::*;
+
+/// A scrubber configuration to be validated
+///
+/// * `extents` - (start, end) address of each scrub area, end inclusive.
+///   Areas that aren't on cache line boundaries are shrunk to whole lines.
+///
+/// * `line_size` - Number of bytes in a cache line
+///
+/// * `index_width` - Number of address bits in the cache index
+///
+/// * `ways` - Number of ways in the cache, a power of two
+///
+/// * `reads_per_line` - Number of evenly spaced words read in each line,
+///   as for CacheBase::reads_per_cacheline(), with words of a usize
+///
+/// * `sub_passes` - Number of interleaved sub-passes in a pass, as for
+///   LineScrubber::set_sub_passes()
+///
+/// * `chunk_bytes` - Number of bytes scrubbed per chunk
+///
+/// * `chunk_alignment` - Alignment chunks end on, as for
+///   LineScrubber::set_chunk_alignment(), or zero for the cache line size
+///
+/// * `excluded` - (start, end) of each range never to be scrubbed, end
+///   inclusive, as for LineScrubber::declare_excluded()
+///
+/// * `aliases` - Each primary area read through an alias, and its alias,
+///   as (start, end) pairs, as for AliasBackend::add_alias()
+///
+/// * `line_time` - Expected time to read a cache line
+///
+/// * `chunk_interval` - Time between the starts of successive chunks,
+///   or zero if chunks are scrubbed back to back
+///
+/// * `page_size` - Number of bytes in a page, a power of two, for the
+///   translation of the areas to physical memory
+#[derive(Clone, Debug, PartialEq)]
+pub struct ScrubDesc {
+    pub extents: Vec<(usize, usize)>,
+    pub line_size: usize,
+    pub index_width: usize,
+    pub ways: usize,
+    pub reads_per_line: usize,
+    pub sub_passes: usize,
+    pub chunk_bytes: usize,
+    pub chunk_alignment: usize,
+    pub excluded: Vec<(usize, usize)>,
+    pub aliases: Vec<((usize, usize), (usize, usize))>,
+    pub line_time: Duration,
+    pub chunk_interval: Duration,
+    pub page_size: usize,
+}
+
+impl Default for ScrubDesc {
+    fn default() -> Self {
+        ScrubDesc {
+            extents: Vec::new(),
+            line_size: 64,
+            index_width: 10,
+            ways: 8,
+            reads_per_line: 1,
+            sub_passes: 1,
+            chunk_bytes: 64 * 1024,
+            chunk_alignment: 0,
+            excluded: Vec::new(),
+            aliases: Vec::new(),
+            line_time: Duration::from_nanos(100),
+            chunk_interval: Duration::ZERO,
+            page_size: 4096,
+        }
+    }
+}
+
+/// A scrub area as it will be scrubbed
+///
+/// * `requested` - (start, end) of the area as given or discovered
+///
+/// * `start` - First address scrubbed, on a cache line boundary
+///
+/// * `end` - Last address scrubbed, one less than a cache line boundary
+///
+/// * `lines` - Number of lines scrubbed in each pass, less those excluded
+///
+/// * `excluded_lines` - Number of lines in the area excluded
+///
+/// * `alias` - Address of the alias of `start`, if read through one
+///
+/// * `phys` - Physical address of `start`, if the areas were translated
+///   and it has one
+///
+/// * `untranslated_pages` - Number of pages of the area with no physical
+///   address, if the areas were translated
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EffectiveArea {
+    pub requested: (usize, usize),
+    pub start: usize,
+    pub end: usize,
+    pub lines: u64,
+    pub excluded_lines: u64,
+    pub alias: Option<usize>,
+    pub phys: Option<u64>,
+    pub untranslated_pages: u64,
+}
+
+/// What a scrubber configuration will do
+///
+/// * `areas` - The scrub areas, in the order given, with discovered areas
+///   last
+///
+/// * `excluded` - Ranges never scrubbed, widened to whole cache lines and
+///   merged
+///
+/// * `line_size` - Number of bytes in a cache line
+///
+/// * `chunk_bytes` - Number of bytes per chunk, rounded up to the chunk
+///   alignment
+///
+/// * `chunk_alignment` - Alignment chunks end on
+///
+/// * `sub_pass_lines` - Number of lines in each sub-pass
+///
+/// * `pass_bytes` - Number of bytes scrubbed in a pass
+///
+/// * `chunks_per_pass` - Number of chunks in a pass
+///
+/// * `pass_duration` - Predicted time taken by a pass
+///
+/// * `warnings` - Things done to make the configuration work, such as
+///   shrinking an area to whole cache lines
+///
+/// * `problems` - Reasons the configuration won't work. It is valid if
+///   there are none.
+#[derive(Clone, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PreflightReport {
+    pub areas: Vec<EffectiveArea>,
+    pub excluded: Vec<(usize, usize)>,
+    pub line_size: usize,
+    pub chunk_bytes: usize,
+    pub chunk_alignment: usize,
+    pub sub_pass_lines: Vec<usize>,
+    pub pass_bytes: usize,
+    pub chunks_per_pass: usize,
+    pub pass_duration: Duration,
+    pub warnings: Vec<String>,
+    pub problems: Vec<String>,
+}
+
+impl PreflightReport {
+    /// Returns whether the configuration is valid
+    pub fn is_ok(&self) -> bool {
+        self.problems.is_empty()
+    }
+
+    // Record a problem
+    fn problem(&mut self, what: &str, error: Error) {
+        self.problems.push(format!("{}: {}", what, error));
+    }
+}
+
+// A backend that must never be called, since nothing is scrubbed
+struct NoReads;
+
+impl ScrubBackend for NoReads {
+    fn read_line(&mut self, _addr: usize) -> Result<(), Error> {
+        Err(Error::InternalError)
+    }
+}
+
+/// Check a scrubber configuration and work out what it will do, without
+/// reading any memory
+///
+/// # Arguments:
+/// * `desc` - The configuration
+///
+/// * `discovery` - Finds RAM to scrub in addition to desc.extents, or
+///   None to scrub only those
+///
+/// * `translator` - Translates the addresses of the areas to physical
+///   memory, as for AutoScrub::translate_areas(), or None if they aren't
+///   translated
+///
+/// # Returns:
+/// The report. Its problems list everything wrong with the configuration.
+pub fn validate(
+    desc: &ScrubDesc,
+    discovery: Option<&mut dyn MemoryDiscovery>,
+    translator: Option<&mut dyn AddressTranslator>,
+) -> PreflightReport {
+    let mut report = PreflightReport {
+        line_size: desc.line_size,
+        ..PreflightReport::default()
+    };
+    if let Err(e) = bit_width(desc.line_size) {
+        report.problem("line size", e);
+        return report;
+    }
+
+    // The checks of CacheBase::check_cache_params() not made by
+    // LineScrubber::new()
+    if let Err(e) = bit_width(desc.ways) {
+        report.problem("ways", e);
+    }
+    let words = desc.line_size / std::mem::size_of::<usize>();
+    if !desc.reads_per_line.is_power_of_two()
+        || desc.reads_per_line > words.max(1)
+    {
+        report.problem("reads per line", Error::UnalignedValue);
+    }
+
+    let mut requested = desc.extents.clone();
+    if let Some(discovery) = discovery {
+        match discover_areas(discovery, desc.line_size) {
+            Ok(found) => requested.extend(found),
+            Err(e) => {
+                report.problems.push(format!("discovery: {}", e));
+            }
+        }
+    }
+
+    // Shrink each area to whole lines
+    let mut areas = Vec::new();
+    for &(start, end) in &requested {
+        match VirtScrubArea::within(start, end, desc.line_size) {
+            Ok(area) => {
+                if area.extent() != (start, end) {
+                    report.warnings.push(format!(
+                        "area {:#x}-{:#x} shrunk to {:#x}-{:#x}",
+                        start,
+                        end,
+                        area.start(),
+                        area.end()
+                    ));
+                }
+                areas.push(((start, end), area));
+            }
+            Err(_) => report.warnings.push(format!(
+                "area {:#x}-{:#x} holds no whole line and is left out",
+                start, end
+            )),
+        }
+    }
+    let extents: Vec<(usize, usize)> =
+        areas.iter().map(|(_, area)| area.extent()).collect();
+
+    // The checks of MemoryScrubber::new()
+    if let Err(e) = check_extents(&extents, desc.line_size) {
+        report.problem("areas", e);
+        return report;
+    }
+
+    // Overlapping areas are accepted, but their common lines are read
+    // twice a pass
+    let mut sorted = extents.clone();
+    sorted.sort_unstable();
+    for pair in sorted.windows(2) {
+        if pair[1].0 <= pair[0].1 {
+            report.warnings.push(format!(
+                "areas {:#x}-{:#x} and {:#x}-{:#x} overlap",
+                pair[0].0, pair[0].1, pair[1].0, pair[1].1
+            ));
+        }
+    }
+
+    let mut scrubber = match LineScrubber::new(
+        NoReads,
+        &extents,
+        desc.line_size,
+        desc.index_width,
+    ) {
+        Ok(scrubber) => scrubber,
+        Err(e) => {
+            report.problem("areas", e);
+            return report;
+        }
+    };
+
+    for &(start, end) in &desc.excluded {
+        if let Err(e) = scrubber.declare_excluded(start, end) {
+            let what = format!("exclusion {:#x}-{:#x}", start, end);
+            report.problem(&what, e);
+        }
+    }
+    if let Err(e) = scrubber.set_sub_passes(desc.sub_passes) {
+        report.problem("sub-passes", e);
+    }
+    let align = match desc.chunk_alignment {
+        0 => desc.line_size,
+        align => align,
+    };
+    if let Err(e) = scrubber.set_chunk_alignment(align) {
+        report.problem("chunk alignment", e);
+    }
+    if desc.chunk_bytes == 0 {
+        report.problem("chunk size", Error::ZeroSize);
+    }
+
+    let mut aliases = AliasBackend::new(NoReads);
+    for &(primary, alias) in &desc.aliases {
+        if let Err(e) = aliases.add_alias(primary, alias) {
+            let what =
+                format!("alias of {:#x}-{:#x}", primary.0, primary.1);
+            report.problem(&what, e);
+        }
+    }
+
+    // The schedule of a pass
+    report.excluded = scrubber.declared_excluded().to_vec();
+    let scan = split_extents(&extents, &report.excluded);
+    let sub_passes = scrubber.sub_pass().0;
+    report.sub_pass_lines = (0..sub_passes)
+        .filter_map(|sub_pass| {
+            ScrubOrder::sub_pass(
+                &scan,
+                desc.line_size,
+                desc.index_width,
+                sub_passes,
+                sub_pass,
+            )
+            .ok()
+        })
+        .map(|order| order.len())
+        .collect();
+    report.chunk_alignment = scrubber.chunk_alignment();
+    report.chunk_bytes =
+        desc.chunk_bytes.next_multiple_of(report.chunk_alignment);
+    report.pass_bytes = scrubber.stats().pass_size;
+    report.chunks_per_pass = match report.chunk_bytes {
+        0 => 0,
+        chunk => report.pass_bytes.div_ceil(chunk),
+    };
+    let lines = report.pass_bytes / desc.line_size;
+    let lines = u32::try_from(lines).unwrap_or(u32::MAX);
+    let chunks = u32::try_from(report.chunks_per_pass).unwrap_or(u32::MAX);
+    report.pass_duration = desc
+        .line_time
+        .saturating_mul(lines)
+        .saturating_add(desc.chunk_interval.saturating_mul(chunks));
+
+    for (i, &(requested, area)) in areas.iter().enumerate() {
+        let excluded_lines = scrubber.stats().areas[i].declared_lines;
+        let alias = aliases.translate(area.start());
+        report.areas.push(EffectiveArea {
+            requested,
+            start: area.start(),
+            end: area.end(),
+            lines: area.lines().get() as u64 - excluded_lines,
+            excluded_lines,
+            alias: (alias != area.start()).then_some(alias),
+            phys: None,
+            untranslated_pages: 0,
+        });
+    }
+    if let Some(translator) = translator {
+        translate(&mut report, translator, desc.page_size);
+    }
+    report
+}
+
+// Translate the areas of a report to physical memory a page at a time, as
+// AutoScrub::translate_areas() does
+fn translate(
+    report: &mut PreflightReport,
+    translator: &mut dyn AddressTranslator,
+    page_size: usize,
+) {
+    if let Err(e) = bit_width(page_size) {
+        report.problem("page size", e);
+        return;
+    }
+    let mut untranslated = 0;
+    for area in report.areas.iter_mut() {
+        area.phys = translator.physical(area.start);
+        let mut page = area.start & !(page_size - 1);
+        while page <= area.end {
+            if translator.physical(page).is_none() {
+                area.untranslated_pages += 1;
+            }
+            page = match page.checked_add(page_size) {
+                Some(page) => page,
+                None => break,
+            };
+        }
+        untranslated += area.untranslated_pages;
+    }
+    if untranslated != 0 {
+        report.warnings.push(format!(
+            "{} pages have no physical address and are left out of \
+             error attribution",
+            untranslated
+        ));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    struct Ram(Vec<(u64, u64)>);
+
+    impl MemoryDiscovery for Ram {
+        fn ram(&mut self) -> io::Result<Vec<(u64, u64)>> {
+            Ok(self.0.clone())
+        }
+    }
+
+    #[test]
+    fn test_validate() {
+        let desc = ScrubDesc {
+            extents: vec![
+                (0x1000, 0x1fff),
+                (0x3010, 0x3fff),
+                (0x5000, 0x5010),
+            ],
+            sub_passes: 2,
+            chunk_bytes: 1024,
+            excluded: vec![(0x1100, 0x1110)],
+            aliases: vec![((0x1000, 0x1fff), (0x9000, 0x9fff))],
+            line_time: Duration::from_micros(1),
+            chunk_interval: Duration::from_millis(1),
+            ..ScrubDesc::default()
+        };
+        let mut ram = Ram(vec![(0x10000, 0x10fff)]);
+        let report = validate(&desc, Some(&mut ram), None);
+        assert!(report.is_ok(), "{:?}", report.problems);
+
+        // The unaligned area is shrunk and the one too small left out
+        let extents: Vec<(usize, usize)> =
+            report.areas.iter().map(|a| (a.start, a.end)).collect();
+        assert_eq!(
+            extents,
+            [(0x1000, 0x1fff), (0x3040, 0x3fff), (0x10000, 0x10fff)]
+        );
+        assert_eq!(report.warnings.len(), 2);
+        assert_eq!(report.areas[0].alias, Some(0x9000));
+        assert_eq!(report.areas[1].alias, None);
+        assert_eq!(report.excluded, [(0x1100, 0x113f)]);
+        assert_eq!(report.areas[0].excluded_lines, 1);
+        assert_eq!(report.areas[0].lines, 63);
+
+        let lines = 63 + 63 + 64;
+        assert_eq!(report.sub_pass_lines.iter().sum::<usize>(), lines);
+        assert_eq!(report.pass_bytes, lines * 64);
+        assert_eq!(report.chunk_bytes, 1024);
+        assert_eq!(report.chunks_per_pass, 12);
+        assert_eq!(
+            report.pass_duration,
+            Duration::from_micros(lines as u64)
+                + Duration::from_millis(12)
+        );
+
+        // Lines read in cache order can't be read in aligned chunks
+        let desc = ScrubDesc {
+            chunk_alignment: 1024,
+            ..desc
+        };
+        let report = validate(&desc, Some(&mut ram), None);
+        assert_eq!(report.problems, ["chunk alignment: NotAddressOrder"]);
+    }
+
+    #[test]
+    fn test_problems() {
+        // Every problem is reported
+        let desc = ScrubDesc {
+            extents: vec![(0x1000, 0x1fff)],
+            sub_passes: 3,
+            chunk_bytes: 0,
+            chunk_alignment: 100,
+            aliases: vec![((0x1000, 0x1fff), (0x9000, 0x9000))],
+            ..ScrubDesc::default()
+        };
+        let report = validate(&desc, None, None);
+        assert!(!report.is_ok());
+        assert_eq!(report.problems.len(), 4, "{:?}", report.problems);
+
+        let desc = ScrubDesc {
+            extents: vec![(0x1000, 0x1fff)],
+            index_width: 64,
+            ..ScrubDesc::default()
+        };
+        assert_eq!(validate(&desc, None, None).problems.len(), 1);
+
+        // Overlapping areas are only warned about
+        let desc = ScrubDesc {
+            extents: vec![(0x1000, 0x1fff), (0x1800, 0x2fff)],
+            ..ScrubDesc::default()
+        };
+        let report = validate(&desc, None, None);
+        assert!(report.is_ok());
+        assert_eq!(report.warnings.len(), 1);
+
+        let desc = ScrubDesc {
+            extents: vec![(0x1000, 0x1010)],
+            ..ScrubDesc::default()
+        };
+        let report = validate(&desc, None, None);
+        assert_eq!(
+            report.problems,
+            [format!("areas: {}", Error::NoMemAreas)]
+        );
+
+        let desc = ScrubDesc {
+            extents: vec![(0x1000, 0x1fff)],
+            ways: 6,
+            reads_per_line: 16,
+            ..ScrubDesc::default()
+        };
+        assert_eq!(validate(&desc, None, None).problems.len(), 2);
+    }
+
+    #[test]
+    fn test_translate() {
+        // Only the first of the two pages of the second area is mapped
+        let desc = ScrubDesc {
+            extents: vec![(0x1000, 0x1fff), (0x3040, 0x4fff)],
+            ..ScrubDesc::default()
+        };
+        let mut translator = |addr: usize| match addr {
+            0x1000..=0x3fff => Some(addr as u64 + 0x100000),
+            _ => None,
+        };
+        let report = validate(&desc, None, Some(&mut translator));
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.areas[0].phys, Some(0x101000));
+        assert_eq!(report.areas[0].untranslated_pages, 0);
+        assert_eq!(report.areas[1].phys, Some(0x103040));
+        assert_eq!(report.areas[1].untranslated_pages, 1);
+        assert_eq!(report.warnings.len(), 1);
+    }
+
+    #[test]
+    fn test_top() {
+        // An area may end at the very top of the address space
+        let desc = ScrubDesc {
+            extents: vec![(usize::MAX - 0xfff, usize::MAX)],
+            index_width: 4,
+            ..ScrubDesc::default()
+        };
+        let report = validate(&desc, None, None);
+        assert!(report.is_ok(), "{:?}", report.problems);
+        assert_eq!(report.areas[0].lines, 64);
+        assert_eq!(report.pass_bytes, 0x1000);
+    }
+}
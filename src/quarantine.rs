@@ -0,0 +1,530 @@
+// Quarantine and retest of suspect cache lines. A line that keeps
+// producing corrected errors is taken out of normal passes and instead
+// retested at a short interval. A line that stays clean for enough
+// retests is reinstated; one that keeps failing, has an uncorrected error,
+// or doesn't come clean in time is escalated for page retirement, which on
+// Linux is done with SoftOffline in os/offline.rs.
+//
+// Errors are reported asynchronously, so a retest is counted as clean if
+// no error was recorded for the line since the retest before it.
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::backend::*;
+use crate::base::*;
+use crate::coverage::*;
+use crate::event::*;
+
+/// When lines are quarantined, reinstated and retired
+///
+/// * `errors_to_quarantine` - Corrected errors within `window` that put a
+///   line in quarantine
+///
+/// * `window` - Period over which errors are counted
+///
+/// * `retest_interval` - Time between retests of a quarantined line
+///
+/// * `retests_to_reinstate` - Consecutive clean retests that reinstate a
+///   line
+///
+/// * `errors_to_retire` - Errors while in quarantine that retire a line
+///
+/// * `max_quarantine` - Longest a line may stay in quarantine before it is
+///   retired
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuarantinePolicy {
+    pub errors_to_quarantine: u32,
+    pub window: Duration,
+    pub retest_interval: Duration,
+    pub retests_to_reinstate: u32,
+    pub errors_to_retire: u32,
+    pub max_quarantine: Duration,
+}
+
+impl Default for QuarantinePolicy {
+    fn default() -> Self {
+        QuarantinePolicy {
+            errors_to_quarantine: 3,
+            window: Duration::from_secs(24 * 60 * 60),
+            retest_interval: Duration::from_secs(60),
+            retests_to_reinstate: 60,
+            errors_to_retire: 3,
+            max_quarantine: Duration::from_secs(7 * 24 * 60 * 60),
+        }
+    }
+}
+
+/// A change in the state of a line, by the address of the line
+///
+/// * `Quarantined` - The line is no longer scrubbed in normal passes
+///
+/// * `Reinstated` - The line is scrubbed in normal passes again
+///
+/// * `Retire` - The page holding the line should be retired. The line is
+///   never scrubbed again.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum QuarantineAction {
+    Quarantined(usize),
+    Reinstated(usize),
+    Retire(usize),
+}
+
+/// A line in quarantine
+///
+/// * `since` - When the line was quarantined
+///
+/// * `next_retest` - When the line is next due to be retested
+///
+/// * `clean_retests` - Consecutive retests without an error
+///
+/// * `errors` - Errors seen while in quarantine
+///
+/// * `failed` - Whether an error was seen since the last retest
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuarantinedLine {
+    pub since: Instant,
+    pub next_retest: Instant,
+    pub clean_retests: u32,
+    pub errors: u32,
+    pub failed: bool,
+}
+
+/// Tracks suspect, quarantined and retired lines
+///
+/// * `policy` - When lines change state
+///
+/// * `line_size` - Number of bytes in a cache line
+///
+/// * `suspects` - Times of recent corrected errors in lines not in
+///   quarantine
+///
+/// * `quarantined` - Lines in quarantine
+///
+/// * `retired` - Lines escalated for retirement
+#[derive(Clone, Debug)]
+pub struct Quarantine {
+    policy: QuarantinePolicy,
+    line_size: usize,
+    suspects: HashMap<usize, Vec<Instant>>,
+    quarantined: BTreeMap<usize, QuarantinedLine>,
+    retired: BTreeSet<usize>,
+}
+
+impl Quarantine {
+    /// Create a Quarantine with no suspect lines
+    ///
+    /// # Arguments:
+    /// * `policy` - When lines change state
+    ///
+    /// * `line_size` - Number of bytes in a cache line, a power of two
+    pub fn new(
+        policy: QuarantinePolicy,
+        line_size: usize,
+    ) -> Result<Quarantine, Error> {
+        if !line_size.is_power_of_two() {
+            return Err(Error::UnalignedValue);
+        }
+        Ok(Quarantine {
+            policy,
+            line_size,
+            suspects: HashMap::new(),
+            quarantined: BTreeMap::new(),
+            retired: BTreeSet::new(),
+        })
+    }
+
+    // Address of the line holding an address
+    fn line(&self, addr: usize) -> usize {
+        addr & !(self.line_size - 1)
+    }
+
+    /// Returns whether the line holding an address is left out of normal
+    /// passes, because it is in quarantine or retired
+    pub fn is_skipped(&self, addr: usize) -> bool {
+        let line = self.line(addr);
+        self.quarantined.contains_key(&line)
+            || self.retired.contains(&line)
+    }
+
+    /// Returns the lines in quarantine
+    pub fn quarantined(&self) -> &BTreeMap<usize, QuarantinedLine> {
+        &self.quarantined
+    }
+
+    /// Returns the lines escalated for retirement
+    pub fn retired(&self) -> &BTreeSet<usize> {
+        &self.retired
+    }
+
+    /// Returns the number of lines in quarantine or retired that hold part
+    /// of a range of addresses, as for AreaStats::excluded_lines
+    pub fn excluded_in(&self, start: usize, end: usize) -> u64 {
+        let first = self.line(start);
+        (self.quarantined.range(first..=end).count()
+            + self.retired.range(first..=end).count()) as u64
+    }
+
+    /// Returns the number of bytes in a cache line
+    pub fn line_size(&self) -> usize {
+        self.line_size
+    }
+
+    /// Retire every line holding part of a range of addresses, as for
+    /// memory already known to be bad
+    ///
+    /// # Arguments:
+    /// * `start` - First address in the range
+    ///
+    /// * `end` - Last address in the range
+    pub fn retire_range(&mut self, start: usize, end: usize) {
+        let mut line = self.line(start);
+        while line <= end {
+            self.retire(line);
+            line = match line.checked_add(self.line_size) {
+                Some(line) => line,
+                None => break,
+            };
+        }
+    }
+
+    // Move a line to the retired set
+    fn retire(&mut self, line: usize) -> QuarantineAction {
+        self.quarantined.remove(&line);
+        self.suspects.remove(&line);
+        self.retired.insert(line);
+        QuarantineAction::Retire(line)
+    }
+
+    /// Record an error in a line
+    ///
+    /// # Arguments:
+    /// * `addr` - Address at which the error was detected
+    ///
+    /// * `severity` - Whether the error was corrected
+    ///
+    /// * `now` - Time at which the error was detected
+    ///
+    /// # Returns:
+    /// The change in state of the line, if any
+    pub fn record_error(
+        &mut self,
+        addr: usize,
+        severity: ErrorSeverity,
+        now: Instant,
+    ) -> Option<QuarantineAction> {
+        let line = self.line(addr);
+        if self.retired.contains(&line) {
+            return None;
+        }
+        if severity == ErrorSeverity::Uncorrected {
+            return Some(self.retire(line));
+        }
+
+        if let Some(q) = self.quarantined.get_mut(&line) {
+            q.errors += 1;
+            q.clean_retests = 0;
+            q.failed = true;
+            return match q.errors >= self.policy.errors_to_retire {
+                true => Some(self.retire(line)),
+                false => None,
+            };
+        }
+
+        let window = self.policy.window;
+        let times = self.suspects.entry(line).or_default();
+        times.retain(|&t| now.saturating_duration_since(t) < window);
+        times.push(now);
+        if times.len() < self.policy.errors_to_quarantine as usize {
+            return None;
+        }
+
+        self.suspects.remove(&line);
+        self.quarantined.insert(
+            line,
+            QuarantinedLine {
+                since: now,
+                next_retest: now + self.policy.retest_interval,
+                clean_retests: 0,
+                errors: 0,
+                failed: false,
+            },
+        );
+        Some(QuarantineAction::Quarantined(line))
+    }
+
+    /// Retest the quarantined lines that are due, reinstating or retiring
+    /// them as the policy says
+    ///
+    /// # Arguments:
+    /// * `backend` - Backend with which to read the lines
+    ///
+    /// * `now` - Current time
+    ///
+    /// # Returns:
+    /// Ok with the changes in state, otherwise Err(Error) if a line could
+    /// not be read
+    pub fn retest<B: ScrubBackend>(
+        &mut self,
+        backend: &mut B,
+        now: Instant,
+    ) -> Result<Vec<QuarantineAction>, Error> {
+        let due: Vec<usize> = self
+            .quarantined
+            .iter()
+            .filter(|(_, q)| q.next_retest <= now)
+            .map(|(&line, _)| line)
+            .collect();
+
+        let mut actions = Vec::new();
+        for line in due {
+            let q = self.quarantined.get_mut(&line).expect("line is due");
+            if !q.failed {
+                q.clean_retests += 1;
+            }
+            q.failed = false;
+
+            if q.clean_retests >= self.policy.retests_to_reinstate {
+                self.quarantined.remove(&line);
+                actions.push(QuarantineAction::Reinstated(line));
+            } else if now.saturating_duration_since(q.since)
+                >= self.policy.max_quarantine
+            {
+                actions.push(self.retire(line));
+            } else {
+                q.next_retest = now + self.policy.retest_interval;
+                backend.read_line(line)?;
+            }
+        }
+        Ok(actions)
+    }
+}
+
+/// A backend that skips quarantined and retired lines
+///
+/// * `backend` - Backend making the reads
+///
+/// * `quarantine` - Lines to skip
+pub struct QuarantineBackend<B: ScrubBackend> {
+    backend: B,
+    quarantine: Quarantine,
+}
+
+impl<B: ScrubBackend> QuarantineBackend<B> {
+    /// Create a QuarantineBackend
+    pub fn new(
+        backend: B,
+        quarantine: Quarantine,
+    ) -> QuarantineBackend<B> {
+        QuarantineBackend {
+            backend,
+            quarantine,
+        }
+    }
+
+    /// Returns the quarantine state
+    pub fn quarantine(&self) -> &Quarantine {
+        &self.quarantine
+    }
+
+    /// Record an error, as for Quarantine::record_error()
+    pub fn record_error(
+        &mut self,
+        addr: usize,
+        severity: ErrorSeverity,
+        now: Instant,
+    ) -> Option<QuarantineAction> {
+        self.quarantine.record_error(addr, severity, now)
+    }
+
+    /// Retest the quarantined lines that are due, as for
+    /// Quarantine::retest()
+    pub fn retest(
+        &mut self,
+        now: Instant,
+    ) -> Result<Vec<QuarantineAction>, Error> {
+        self.quarantine.retest(&mut self.backend, now)
+    }
+
+    /// Returns the backend making the reads
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+}
+
+impl<B: ScrubBackend> ScrubBackend for QuarantineBackend<B> {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        match self.quarantine.is_skipped(addr) {
+            true => Ok(()),
+            false => self.backend.read_line(addr),
+        }
+    }
+
+    fn read_words(
+        &mut self,
+        addr: usize,
+        line_size: usize,
+        reads: usize,
+    ) -> Result<(), Error> {
+        match self.quarantine.is_skipped(addr) {
+            true => Ok(()),
+            false => self.backend.read_words(addr, line_size, reads),
+        }
+    }
+
+    fn begin_batch(&mut self) -> Result<(), Error> {
+        self.backend.begin_batch()
+    }
+
+    fn end_batch(&mut self) -> Result<(), Error> {
+        self.backend.end_batch()
+    }
+
+    fn end_window(&mut self) -> Result<(), Error> {
+        self.backend.end_window()
+    }
+
+    fn window_size(&self) -> Option<usize> {
+        self.backend.window_size()
+    }
+
+    fn flush_line(&mut self, addr: usize) -> Result<(), Error> {
+        match self.quarantine.is_skipped(addr) {
+            true => Ok(()),
+            false => self.backend.flush_line(addr),
+        }
+    }
+
+    fn skip_reason(&mut self, addr: usize) -> Option<SkipReason> {
+        match self.quarantine.is_skipped(addr) {
+            true => Some(SkipReason::Quarantined),
+            false => self.backend.skip_reason(addr),
+        }
+    }
+
+    fn excluded_lines(&self, start: usize, end: usize) -> Option<u64> {
+        let inner = self.backend.excluded_lines(start, end).unwrap_or(0);
+        Some(self.quarantine.excluded_in(start, end) + inner)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::*;
+
+    fn policy() -> QuarantinePolicy {
+        QuarantinePolicy {
+            errors_to_quarantine: 2,
+            window: Duration::from_secs(100),
+            retest_interval: Duration::from_secs(10),
+            retests_to_reinstate: 3,
+            errors_to_retire: 2,
+            max_quarantine: Duration::from_secs(1000),
+        }
+    }
+
+    #[test]
+    fn test_reinstate() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mem = SimMemory::new(0, 4096, 64).unwrap();
+        let quarantine = Quarantine::new(policy(), 64).unwrap();
+        let mut backend = QuarantineBackend::new(mem, quarantine);
+        let corrected = ErrorSeverity::Corrected;
+
+        // Errors too far apart don't count together
+        assert_eq!(backend.record_error(0x104, corrected, at(0)), None);
+        assert_eq!(backend.record_error(0x108, corrected, at(200)), None);
+        assert_eq!(
+            backend.record_error(0x110, corrected, at(250)),
+            Some(QuarantineAction::Quarantined(0x100))
+        );
+
+        let mut scrubber =
+            LineScrubber::new(backend, &[(0, 4095)], 64, 2).unwrap();
+        scrubber.set_coverage_tracking(1).unwrap();
+        scrubber.scrub(4096).unwrap();
+        assert_eq!(scrubber.backend().backend().reads_at(0x100), Some(0));
+        assert_eq!(scrubber.backend().backend().reads_at(0x140), Some(1));
+
+        // The scrubber knows the line was left out of the pass
+        let coverage = &scrubber.coverage_history()[0];
+        assert_eq!(
+            coverage.reason_at(0x100),
+            Some(SkipReason::Quarantined)
+        );
+        assert_eq!(coverage.bytes(None), 64);
+        assert_eq!(scrubber.stats().areas[0].excluded_lines, 1);
+
+        // One error while in quarantine restarts the count of clean retests
+        let backend = scrubber.backend_mut();
+        assert!(backend.retest(at(255)).unwrap().is_empty());
+        assert!(backend.retest(at(260)).unwrap().is_empty());
+        backend.record_error(0x100, corrected, at(265));
+        assert!(backend.retest(at(270)).unwrap().is_empty());
+        assert!(backend.retest(at(280)).unwrap().is_empty());
+        assert!(backend.retest(at(290)).unwrap().is_empty());
+        assert_eq!(
+            backend.retest(at(300)).unwrap(),
+            [QuarantineAction::Reinstated(0x100)]
+        );
+        assert_eq!(backend.backend().reads_at(0x100), Some(4));
+        assert!(!backend.quarantine().is_skipped(0x100));
+        scrubber.scrub(64).unwrap();
+        assert_eq!(scrubber.stats().areas[0].excluded_lines, 0);
+    }
+
+    #[test]
+    fn test_retire() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut mem = SimMemory::new(0, 4096, 64).unwrap();
+        let mut q = Quarantine::new(policy(), 64).unwrap();
+        let corrected = ErrorSeverity::Corrected;
+
+        // Too many errors while in quarantine
+        q.record_error(0x40, corrected, at(0));
+        q.record_error(0x40, corrected, at(1));
+        q.record_error(0x40, corrected, at(2));
+        assert_eq!(
+            q.record_error(0x40, corrected, at(3)),
+            Some(QuarantineAction::Retire(0x40))
+        );
+        assert!(q.is_skipped(0x40));
+        assert_eq!(q.record_error(0x40, corrected, at(4)), None);
+
+        // An uncorrected error
+        assert_eq!(
+            q.record_error(0x80, ErrorSeverity::Uncorrected, at(5)),
+            Some(QuarantineAction::Retire(0x80))
+        );
+
+        assert_eq!(q.retired().len(), 2);
+        assert_eq!(q.excluded_in(0x44, 0x7f), 1);
+        assert_eq!(q.excluded_in(0, 0xfff), 2);
+
+        // Never clean for long enough
+        let mut q = Quarantine::new(
+            QuarantinePolicy {
+                errors_to_retire: 100,
+                max_quarantine: Duration::from_secs(50),
+                ..policy()
+            },
+            64,
+        )
+        .unwrap();
+        q.record_error(0xc0, corrected, at(0));
+        q.record_error(0xc0, corrected, at(0));
+        for t in (10..50).step_by(10) {
+            q.record_error(0xc0, corrected, at(t - 5));
+            assert!(q.retest(&mut mem, at(t)).unwrap().is_empty());
+        }
+        assert_eq!(
+            q.retest(&mut mem, at(50)).unwrap(),
+            [QuarantineAction::Retire(0xc0)]
+        );
+        assert_eq!(mem.reads_at(0xc0), Some(4));
+        assert!(Quarantine::new(policy(), 48).is_err());
+    }
+}
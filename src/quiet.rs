@@ -0,0 +1,423 @@
+// Quiet periods: times of day, such as market hours or test runs, during
+// which scrubbing must not disturb the system. QuietScheduler is an
+// AutoScrubDesc that pauses through quiet windows and sizes its chunks so
+// that each pass still completes within its deadline, scrubbing faster
+// outside the windows to catch up on the time lost inside them.
+//
+// Windows repeat daily. They are given in seconds after midnight in a fixed
+// offset from UTC and should not overlap.
+
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::addr::*;
+use crate::base::*;
+use crate::clock::*;
+use crate::data::*;
+use crate::stats::*;
+use crate::tune::*;
+
+const SECS_PER_DAY: f64 = 86400.0;
+
+/// A daily period during which scrubbing is paused
+///
+/// * `start` - Start of the window, in seconds after midnight
+///
+/// * `length` - Length of the window, at most one day
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct QuietWindow {
+    pub start: u32,
+    pub length: Duration,
+}
+
+impl QuietWindow {
+    /// Create a window from hours and minutes after midnight
+    pub fn new(hour: u32, minute: u32, length: Duration) -> QuietWindow {
+        QuietWindow {
+            start: (hour * 3600 + minute * 60) % 86400,
+            length: length.min(Duration::from_secs(86400)),
+        }
+    }
+}
+
+/// The set of quiet windows
+///
+/// * `windows` - The windows
+///
+/// * `utc_offset` - Offset from UTC, in seconds, of the time zone in which
+///   window start times are given
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct QuietWindows {
+    pub windows: Vec<QuietWindow>,
+    pub utc_offset: i32,
+}
+
+impl QuietWindows {
+    // Seconds since the epoch, in local time
+    fn local_secs(&self, t: SystemTime) -> f64 {
+        let secs = match t.duration_since(UNIX_EPOCH) {
+            Ok(d) => d.as_secs_f64(),
+            Err(e) => -e.duration().as_secs_f64(),
+        };
+        secs + self.utc_offset as f64
+    }
+
+    /// Returns how much longer the quiet window containing the given time
+    /// lasts, or None if the time is not in a quiet window
+    pub fn remaining(&self, t: SystemTime) -> Option<Duration> {
+        self.remaining_at(self.local_secs(t).rem_euclid(SECS_PER_DAY))
+    }
+
+    /// Returns true if the windows together cover the whole day, so that
+    /// a quiet period never ends
+    pub fn covers_day(&self) -> bool {
+        // Any gap between windows starts where one of them ends
+        !self.windows.is_empty()
+            && self.windows.iter().all(|w| {
+                let end = w.start as f64 + w.length.as_secs_f64();
+                self.remaining_at(end.rem_euclid(SECS_PER_DAY)).is_some()
+            })
+    }
+
+    // How much longer the quiet window containing the given time of day,
+    // in seconds after local midnight, lasts
+    fn remaining_at(&self, time_of_day: f64) -> Option<Duration> {
+        self.windows
+            .iter()
+            .filter_map(|w| {
+                let into = (time_of_day - w.start as f64)
+                    .rem_euclid(SECS_PER_DAY);
+                let length = w.length.as_secs_f64();
+                match into < length {
+                    true => Some(Duration::from_secs_f64(length - into)),
+                    false => None,
+                }
+            })
+            .max()
+    }
+
+    /// Returns how much of the time span starting at the given time falls
+    /// in quiet windows
+    ///
+    /// # Arguments:
+    /// * `from` - Start of the span
+    ///
+    /// * `span` - Length of the span
+    pub fn quiet_time(
+        &self,
+        from: SystemTime,
+        span: Duration,
+    ) -> Duration {
+        let a = self.local_secs(from);
+        let b = a + span.as_secs_f64();
+        let first_day = (a / SECS_PER_DAY).floor() - 1.0;
+        let last_day = (b / SECS_PER_DAY).floor();
+
+        let mut quiet = 0.0;
+        for w in &self.windows {
+            let mut day = first_day;
+            while day <= last_day {
+                let start = day * SECS_PER_DAY + w.start as f64;
+                let end = start + w.length.as_secs_f64();
+                quiet += (end.min(b) - start.max(a)).max(0.0);
+                day += 1.0;
+            }
+        }
+        Duration::from_secs_f64(quiet.min(span.as_secs_f64()))
+    }
+}
+
+/// An AutoScrubDesc that avoids quiet windows while completing each pass
+/// by a deadline. A chunk is scrubbed once per interval outside the quiet
+/// windows, sized so that the rest of the pass fits in the time left
+/// before the deadline, not counting quiet time.
+///
+/// * `windows` - Times at which scrubbing is paused
+///
+/// * `pass_period` - Time allowed for each pass
+///
+/// * `interval` - Time between the starts of chunks
+///
+/// * `min_chunk` - Smallest chunk to scrub
+///
+/// * `max_chunk` - Largest chunk to scrub, limiting how hard scrubbing
+///   catches up
+///
+/// * `align` - Chunk sizes are rounded up to a multiple of this, normally
+///   the cache line size
+///
+/// * `clock` - Source of time
+///
+/// * `next` - When the next chunk is due
+pub struct QuietScheduler<C: Clock> {
+    windows: QuietWindows,
+    pass_period: Duration,
+    interval: Duration,
+    min_chunk: usize,
+    max_chunk: usize,
+    align: usize,
+    clock: C,
+    next: Option<Instant>,
+}
+
+impl<C: Clock> QuietScheduler<C> {
+    /// Create a new QuietScheduler
+    ///
+    /// # Arguments:
+    /// * `windows` - Times at which scrubbing is paused
+    ///
+    /// * `pass_period` - Time allowed for each pass
+    ///
+    /// * `interval` - Time between the starts of chunks
+    ///
+    /// * `align` - Chunk sizes are rounded up to a multiple of this,
+    ///   normally the cache line size. This is also the smallest chunk.
+    ///
+    /// * `clock` - Source of time
+    pub fn new(
+        windows: QuietWindows,
+        pass_period: Duration,
+        interval: Duration,
+        align: usize,
+        clock: C,
+    ) -> QuietScheduler<C> {
+        let align = align.max(1);
+        QuietScheduler {
+            windows,
+            pass_period,
+            interval,
+            min_chunk: align,
+            max_chunk: usize::MAX - usize::MAX % align,
+            align,
+            clock,
+            next: None,
+        }
+    }
+
+    /// Set the smallest and largest chunk sizes. The smallest is rounded
+    /// up, and the largest down, to a multiple of the alignment.
+    ///
+    /// # Arguments:
+    /// * `min` - Smallest chunk size
+    ///
+    /// * `max` - Largest chunk size
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::InvertedRange) if, once
+    /// rounded, the smallest is larger than the largest
+    pub fn set_chunk_limits(
+        &mut self,
+        min: usize,
+        max: usize,
+    ) -> Result<(), Error> {
+        (self.min_chunk, self.max_chunk) =
+            chunk_limits(min, max, self.align)?;
+        Ok(())
+    }
+
+    // Wait for the next interval, then for any quiet window to end.
+    // Returns false, without waiting for the windows, if they cover the
+    // whole day and so would never end.
+    fn wait(&mut self) -> bool {
+        if let Some(next) = self.next {
+            let now = self.clock.now();
+            if next > now {
+                self.clock.sleep(next - now);
+            }
+        }
+        if self.windows.covers_day() {
+            return false;
+        }
+        while let Some(remaining) =
+            self.windows.remaining(self.clock.wall())
+        {
+            self.clock.sleep(remaining);
+        }
+        self.next = Some(self.clock.now() + self.interval);
+        true
+    }
+
+    /// Returns the number of bytes to scrub next. Without statistics, the
+    /// position in the pass is unknown and the smallest chunk is used.
+    /// Returns zero, ending the scrub, if the quiet windows cover the
+    /// whole day.
+    ///
+    /// # Arguments:
+    /// * `stats` - Statistics for the scrubbing done so far
+    pub fn next_chunk(&mut self, stats: Option<&ScrubStats>) -> usize {
+        if !self.wait() {
+            return 0;
+        }
+
+        let stats = match stats {
+            Some(stats) if stats.pass_size != 0 => stats,
+            _ => return self.min_chunk,
+        };
+
+        let left = (stats.pass_started + self.pass_period)
+            .saturating_duration_since(self.clock.now());
+        let available = left.saturating_sub(
+            self.windows.quiet_time(self.clock.wall(), left),
+        );
+        let slots = (available.as_secs_f64()
+            / self.interval.as_secs_f64())
+        .floor()
+        .max(1.0);
+        let remaining = stats.pass_size - stats.pass_offset;
+        let chunk = (remaining as f64 / slots).ceil() as usize;
+        let chunk = chunk.div_ceil(self.align) * self.align;
+        chunk.clamp(self.min_chunk, self.max_chunk)
+    }
+}
+
+impl<const N: usize, const S: usize, const W: usize, D, A, C: Clock>
+    AutoScrubDesc<N, S, W, D, A> for QuietScheduler<C>
+where
+    D: DataImplTrait<D>,
+    A: AddrImplTrait<A>,
+{
+    fn next(&mut self) -> Addr<A> {
+        self.next_chunk(None).into()
+    }
+
+    fn next_with(&mut self, stats: &ScrubStats) -> Addr<A> {
+        self.next_chunk(Some(stats)).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const HOUR: u64 = 3600;
+
+    // Midnight UTC on some day
+    fn midnight() -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(19000 * 86400)
+    }
+
+    fn business_hours() -> QuietWindows {
+        QuietWindows {
+            windows: vec![QuietWindow::new(
+                9,
+                0,
+                Duration::from_secs(8 * HOUR),
+            )],
+            utc_offset: 0,
+        }
+    }
+
+    #[test]
+    fn test_windows() {
+        let windows = business_hours();
+        let at = |h: u64| midnight() + Duration::from_secs(h * HOUR);
+
+        assert_eq!(windows.remaining(at(8)), None);
+        assert_eq!(
+            windows.remaining(at(10)),
+            Some(Duration::from_secs(7 * HOUR))
+        );
+        assert_eq!(windows.remaining(at(17)), None);
+
+        let day = Duration::from_secs(24 * HOUR);
+        assert_eq!(
+            windows.quiet_time(at(0), day),
+            Duration::from_secs(8 * HOUR)
+        );
+        assert_eq!(
+            windows.quiet_time(at(12), day),
+            Duration::from_secs(8 * HOUR)
+        );
+        assert_eq!(
+            windows.quiet_time(at(16), Duration::from_secs(2 * HOUR)),
+            Duration::from_secs(HOUR)
+        );
+
+        // A window past midnight, in a time zone an hour ahead of UTC
+        let windows = QuietWindows {
+            windows: vec![QuietWindow::new(
+                23,
+                0,
+                Duration::from_secs(2 * HOUR),
+            )],
+            utc_offset: 3600,
+        };
+        assert!(windows.remaining(at(22)).is_some());
+        assert!(windows.remaining(at(23)).is_some());
+        assert!(windows.remaining(at(0)).is_none());
+        assert!(!windows.covers_day());
+        assert!(!QuietWindows::default().covers_day());
+
+        // Overlapping windows that leave a gap, and ones that do not
+        let mut windows = QuietWindows {
+            windows: vec![
+                QuietWindow::new(0, 0, Duration::from_secs(12 * HOUR)),
+                QuietWindow::new(0, 0, Duration::from_secs(12 * HOUR)),
+            ],
+            utc_offset: 0,
+        };
+        assert!(!windows.covers_day());
+        windows.windows[1] =
+            QuietWindow::new(11, 0, Duration::from_secs(13 * HOUR));
+        assert!(windows.covers_day());
+    }
+
+    #[test]
+    fn test_scheduler() {
+        let start = Instant::now();
+        let clock = VirtualClock::at(
+            start,
+            midnight() + Duration::from_secs(8 * HOUR),
+        );
+        let mut sched = QuietScheduler::new(
+            business_hours(),
+            Duration::from_secs(12 * HOUR),
+            Duration::from_secs(HOUR),
+            64,
+            clock,
+        );
+
+        // Of the 12 hours until the deadline, 8 are quiet, leaving 4
+        // chunks for the pass
+        let mut stats = ScrubStats::new(&[16 * 1024], start);
+        assert_eq!(sched.next_chunk(Some(&stats)), 4096);
+
+        // The next chunk waits out the window, then has 3 hours for the
+        // remaining 12 KiB
+        stats.record_chunk(4096, Duration::ZERO, start);
+        assert_eq!(sched.next_chunk(Some(&stats)), 4096);
+        assert_eq!(
+            sched.clock.wall(),
+            midnight() + Duration::from_secs(17 * HOUR)
+        );
+
+        assert_eq!(sched.next_chunk(None), 64);
+
+        // Windows that cover the whole day never end, so the scheduler
+        // stops rather than waiting for them
+        let windows = QuietWindows {
+            windows: vec![QuietWindow::new(
+                0,
+                0,
+                Duration::from_secs(24 * HOUR),
+            )],
+            utc_offset: 0,
+        };
+        let clock = VirtualClock::at(start, midnight());
+        let mut sched = QuietScheduler::new(
+            windows,
+            Duration::from_secs(12 * HOUR),
+            Duration::from_secs(HOUR),
+            64,
+            clock,
+        );
+        assert_eq!(sched.next_chunk(None), 0);
+        assert_eq!(sched.clock.wall(), midnight());
+
+        // As do overlapping windows that together cover it
+        sched.windows.windows = vec![
+            QuietWindow::new(0, 0, Duration::from_secs(14 * HOUR)),
+            QuietWindow::new(12, 0, Duration::from_secs(12 * HOUR)),
+        ];
+        assert_eq!(sched.next_chunk(None), 0);
+    }
+}
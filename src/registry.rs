@@ -0,0 +1,546 @@
+// A registry of memory to scrub, for programs in which no one component
+// owns all of it. A large program has many subsystems, each with its own
+// buffers, caches and heaps worth scrubbing, which come and go as the
+// program runs. Rather than each subsystem running a scrubber of its own,
+// or one component having to know every area up front, subsystems register
+// their areas with a ScrubRegistry, either one the program makes or the
+// global one, and a single RegistryScrubber scrubs all of them with one
+// LineScrubber, so that they share one pass, one rate and one set of
+// policies.
+//
+// A Registration stands for a registered area and unregisters it when it
+// is dropped, so an owner drops the registration before freeing the
+// memory. The RegistryScrubber holds the lock of the registry while it
+// scrubs, and picks up any changes before it reads anything, so once a
+// registration has been dropped its memory is never read again.
+//
+// Registered areas need not be cache line aligned. Each is scrubbed only
+// over the whole cache lines within it, since the bytes either side may
+// belong to someone else, and an area with no whole line in it is not
+// scrubbed at all.
+
+use std::sync::OnceLock;
+
+use crate::backend::*;
+use crate::base::*;
+use crate::dryrun::*;
+use crate::sync::{lock, Arc, Mutex};
+
+/// An area of memory registered for scrubbing
+///
+/// * `owner` - Name of the subsystem that registered the area, also used
+///   as the label of its scrub area
+///
+/// * `start` - First address in the area
+///
+/// * `end` - Last address in the area
+#[derive(Clone, Debug, PartialEq)]
+pub struct RegisteredArea {
+    pub owner: String,
+    pub start: usize,
+    pub end: usize,
+}
+
+// RegistryState: What is shared between the owners of areas and the
+// scrubber
+// areas: Registered areas, each with its registration ID, in the order
+//  they were registered
+// next_id: ID for the next registration
+// generation: Count of changes to the areas
+#[derive(Debug, Default)]
+struct RegistryState {
+    areas: Vec<(u64, RegisteredArea)>,
+    next_id: u64,
+    generation: u64,
+}
+
+/// Areas of memory registered for scrubbing by any number of owners.
+/// Clones share the same areas.
+///
+/// * `state` - The registered areas
+#[derive(Clone, Debug)]
+pub struct ScrubRegistry {
+    state: Arc<Mutex<RegistryState>>,
+}
+
+static GLOBAL_REGISTRY: OnceLock<ScrubRegistry> = OnceLock::new();
+
+impl ScrubRegistry {
+    /// Create an empty registry
+    pub fn new() -> ScrubRegistry {
+        ScrubRegistry {
+            state: Arc::new(Mutex::new(RegistryState::default())),
+        }
+    }
+
+    /// Returns the registry shared by the whole program, created empty on
+    /// first use
+    pub fn global() -> &'static ScrubRegistry {
+        GLOBAL_REGISTRY.get_or_init(ScrubRegistry::new)
+    }
+
+    /// Register an area of memory for scrubbing. The memory must stay
+    /// readable until the returned Registration is dropped.
+    ///
+    /// # Arguments:
+    /// * `owner` - Name of the subsystem registering the area
+    ///
+    /// * `start` - First address in the area
+    ///
+    /// * `end` - Last address in the area
+    ///
+    /// # Returns:
+    /// Ok(Registration) on success, otherwise Err(Error::EmptyMemArea) if
+    /// end is before start
+    pub fn register(
+        &self,
+        owner: &str,
+        start: usize,
+        end: usize,
+    ) -> Result<Registration, Error> {
+        if end < start {
+            return Err(Error::EmptyMemArea);
+        }
+        let mut state = lock(&self.state);
+        let id = state.next_id;
+        state.next_id += 1;
+        state.generation += 1;
+        state.areas.push((
+            id,
+            RegisteredArea {
+                owner: owner.to_string(),
+                start,
+                end,
+            },
+        ));
+        Ok(Registration {
+            registry: self.clone(),
+            id,
+        })
+    }
+
+    /// Register the memory of a slice for scrubbing
+    ///
+    /// # Arguments:
+    /// * `owner` - Name of the subsystem registering the memory
+    ///
+    /// * `bytes` - The memory, which must not be freed until the returned
+    ///   Registration is dropped
+    ///
+    /// # Returns:
+    /// Ok(Registration) on success, otherwise Err(Error::EmptyMemArea) if
+    /// the slice is empty
+    pub fn register_slice(
+        &self,
+        owner: &str,
+        bytes: &[u8],
+    ) -> Result<Registration, Error> {
+        let start = bytes.as_ptr() as usize;
+        match bytes.len() {
+            0 => Err(Error::EmptyMemArea),
+            len => self.register(owner, start, start + len - 1),
+        }
+    }
+
+    /// Returns the registered areas, in the order they were registered
+    pub fn areas(&self) -> Vec<RegisteredArea> {
+        let state = lock(&self.state);
+        state.areas.iter().map(|(_, a)| a.clone()).collect()
+    }
+
+    /// Returns the areas registered by an owner
+    pub fn owner_areas(&self, owner: &str) -> Vec<RegisteredArea> {
+        let state = lock(&self.state);
+        state
+            .areas
+            .iter()
+            .filter(|(_, a)| a.owner == owner)
+            .map(|(_, a)| a.clone())
+            .collect()
+    }
+
+    /// Returns the number of registered areas
+    pub fn len(&self) -> usize {
+        lock(&self.state).areas.len()
+    }
+
+    /// Returns whether no areas are registered
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for ScrubRegistry {
+    fn default() -> Self {
+        ScrubRegistry::new()
+    }
+}
+
+/// An area registered with a ScrubRegistry. Dropping it unregisters the
+/// area, waiting for any scrubbing in progress, after which the memory of
+/// the area is no longer read.
+///
+/// * `registry` - The registry the area is registered with
+///
+/// * `id` - ID of the registration, unique within the registry
+#[derive(Debug)]
+pub struct Registration {
+    registry: ScrubRegistry,
+    id: u64,
+}
+
+impl Registration {
+    /// Returns the ID of the registration
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    /// Returns the registered area
+    pub fn area(&self) -> RegisteredArea {
+        let state = lock(&self.registry.state);
+        match state.areas.iter().find(|(id, _)| *id == self.id) {
+            Some((_, area)) => area.clone(),
+            None => unreachable!("registration without an area"),
+        }
+    }
+
+    /// Unregister the area, as dropping the registration does
+    pub fn unregister(self) {}
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut state = lock(&self.registry.state);
+        state.areas.retain(|(id, _)| *id != self.id);
+        state.generation += 1;
+    }
+}
+
+/// Scrubs every area registered with a ScrubRegistry with one
+/// LineScrubber, adding and removing its scrub areas as areas are
+/// registered and unregistered. Until there is an area to scrub there is
+/// no LineScrubber. Once there is, the same one is kept for as long as the
+/// RegistryScrubber, so its policies, exclusions and statistics survive
+/// every area being unregistered. A LineScrubber can't be left without a
+/// scrub area, so it then keeps the last one but is vacant, and scrubs
+/// nothing until another area is registered to take its place.
+///
+/// * `registry` - The registry whose areas are scrubbed
+///
+/// * `line_size` - Number of bytes in a cache line
+///
+/// * `index_width` - Number of address bits in the cache index
+///
+/// * `idle` - The backend, while there is no scrubber
+///
+/// * `scrubber` - The scrubber, while there are areas to scrub
+///
+/// * `vacant` - Whether every area has been unregistered, so that the
+///   one scrub area left is of memory that must not be read
+///
+/// * `ids` - Registration ID of each scrub area of the scrubber, none
+///   while it is vacant
+///
+/// * `generation` - Generation of the registry the scrub areas match
+pub struct RegistryScrubber<B: ScrubBackend> {
+    registry: ScrubRegistry,
+    line_size: usize,
+    index_width: usize,
+    idle: Option<B>,
+    scrubber: Option<LineScrubber<B>>,
+    vacant: bool,
+    ids: Vec<u64>,
+    generation: u64,
+}
+
+impl<B: ScrubBackend> RegistryScrubber<B> {
+    /// Create a scrubber for the areas of a registry
+    ///
+    /// # Arguments:
+    /// * `registry` - The registry
+    ///
+    /// * `backend` - Reads each cache line
+    ///
+    /// * `line_size` - Number of bytes in a cache line
+    ///
+    /// * `index_width` - Number of address bits in the cache index
+    ///
+    /// # Returns:
+    /// Ok(RegistryScrubber) on success, otherwise
+    /// Err(Error::UnalignedValue) if line_size isn't a power of two
+    pub fn new(
+        registry: ScrubRegistry,
+        backend: B,
+        line_size: usize,
+        index_width: usize,
+    ) -> Result<RegistryScrubber<B>, Error> {
+        if !line_size.is_power_of_two() {
+            return Err(Error::UnalignedValue);
+        }
+        Ok(RegistryScrubber {
+            registry,
+            line_size,
+            index_width,
+            idle: Some(backend),
+            scrubber: None,
+            vacant: false,
+            ids: Vec::new(),
+            generation: 0,
+        })
+    }
+
+    /// Bring the scrub areas up to date with the registry
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error) if a registered area could
+    /// not be added. Areas are added in the order they were registered and
+    /// the ones before it are kept.
+    pub fn sync(&mut self) -> Result<(), Error> {
+        let shared = self.registry.state.clone();
+        let state = lock(&shared);
+        self.apply(&state)
+    }
+
+    /// Scrub the next bytes of the registered areas, after bringing them
+    /// up to date with the registry. No area can be unregistered until
+    /// this returns.
+    ///
+    /// # Arguments:
+    /// * `bytes` - Number of bytes to scrub
+    ///
+    /// # Returns:
+    /// Ok(true) if memory was scrubbed, Ok(false) if there is nothing to
+    /// scrub, otherwise Err(Error) as for sync() or LineScrubber::scrub()
+    pub fn scrub(&mut self, bytes: usize) -> Result<bool, Error> {
+        let shared = self.registry.state.clone();
+        let state = lock(&shared);
+        self.apply(&state)?;
+        match self.scrubber.as_mut() {
+            Some(scrubber) if !self.vacant => {
+                scrubber.scrub(bytes).map(|_| true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Returns the scrubber, once there has been an area to scrub. While
+    /// it is vacant its one scrub area is that of the last area
+    /// unregistered.
+    pub fn scrubber(&self) -> Option<&LineScrubber<B>> {
+        self.scrubber.as_ref()
+    }
+
+    /// Returns the scrubber, once there has been an area to scrub, to
+    /// configure it
+    pub fn scrubber_mut(&mut self) -> Option<&mut LineScrubber<B>> {
+        self.scrubber.as_mut()
+    }
+
+    /// Returns whether every area has been unregistered since there was
+    /// a scrubber, as of the last sync, so that nothing is scrubbed
+    pub fn is_vacant(&self) -> bool {
+        self.vacant
+    }
+
+    /// Returns the registry
+    pub fn registry(&self) -> &ScrubRegistry {
+        &self.registry
+    }
+
+    /// Returns the registration ID of a scrub area, as of the last sync
+    pub fn registration(&self, area: usize) -> Option<u64> {
+        self.ids.get(area).copied()
+    }
+
+    // Make the scrub areas those of the registered areas with whole cache
+    // lines in them
+    fn apply(&mut self, state: &RegistryState) -> Result<(), Error> {
+        if state.generation == self.generation {
+            return Ok(());
+        }
+        let wanted: Vec<(u64, &str, (usize, usize))> = state
+            .areas
+            .iter()
+            .filter_map(|(id, a)| {
+                self.whole_lines(a).map(|e| (*id, a.owner.as_str(), e))
+            })
+            .collect();
+
+        // Remove the areas that have gone, last first so that the indices
+        // of those still to be checked don't move
+        for area in (0..self.ids.len()).rev() {
+            if wanted.iter().any(|(id, _, _)| *id == self.ids[area]) {
+                continue;
+            }
+            match self.scrubber.as_mut() {
+                Some(_) if self.ids.len() == 1 => self.vacant = true,
+                Some(scrubber) => scrubber.remove_area(area)?,
+                None => return Err(Error::InternalError),
+            }
+            self.ids.remove(area);
+        }
+
+        for (id, owner, (start, end)) in wanted {
+            if self.ids.contains(&id) {
+                continue;
+            }
+            let area = match (self.scrubber.as_mut(), self.idle.take()) {
+                (Some(scrubber), _) if self.vacant => {
+                    scrubber.replace_area(0, start, end)?;
+                    self.vacant = false;
+                    0
+                }
+                (Some(scrubber), _) => scrubber.add_area(start, end)?,
+                (None, Some(backend)) => {
+                    // Check the area first so the backend isn't lost
+                    let extents = [(start, end)];
+                    if let Err(e) = ScrubOrder::new(
+                        &extents,
+                        self.line_size,
+                        self.index_width,
+                    ) {
+                        self.idle = Some(backend);
+                        return Err(e);
+                    }
+                    self.scrubber = Some(LineScrubber::new(
+                        backend,
+                        &extents,
+                        self.line_size,
+                        self.index_width,
+                    )?);
+                    0
+                }
+                (None, None) => return Err(Error::InternalError),
+            };
+            if let Some(scrubber) = self.scrubber.as_mut() {
+                scrubber.stats_mut().areas[area].label =
+                    Some(owner.to_string());
+            }
+            self.ids.push(id);
+        }
+        self.generation = state.generation;
+        Ok(())
+    }
+
+    // Returns the first and last address of the whole cache lines in a
+    // registered area, if it has any
+    fn whole_lines(
+        &self,
+        area: &RegisteredArea,
+    ) -> Option<(usize, usize)> {
+        let mask = self.line_size - 1;
+        let first = area.start.checked_add(mask)? & !mask;
+        let last = match area.end & mask {
+            m if m == mask => area.end,
+            _ => (area.end & !mask).checked_sub(1)?,
+        };
+        (last > first).then_some((first, last))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    #[test]
+    fn test_registry() {
+        let registry = ScrubRegistry::new();
+        assert!(registry.is_empty());
+        let a = registry.register("net", 0x1000, 0x1fff).unwrap();
+        let b = registry.clone().register("disk", 0x4000, 0x4fff).unwrap();
+        let c = registry.register("net", 0x8000, 0x80ff).unwrap();
+        assert_ne!(a.id(), b.id());
+        assert_eq!(registry.len(), 3);
+        assert_eq!(b.area().owner, "disk");
+        assert_eq!(
+            registry
+                .owner_areas("net")
+                .iter()
+                .map(|a| a.start)
+                .collect::<Vec<_>>(),
+            [0x1000, 0x8000]
+        );
+        assert_eq!(
+            registry.register("x", 2, 1).unwrap_err(),
+            Error::EmptyMemArea
+        );
+        assert!(registry.register_slice("x", &[]).is_err());
+
+        drop(a);
+        c.unregister();
+        assert_eq!(registry.areas().len(), 1);
+        assert_eq!(registry.areas()[0].start, 0x4000);
+
+        let global = ScrubRegistry::global();
+        assert!(std::ptr::eq(global, ScrubRegistry::global()));
+    }
+
+    #[test]
+    fn test_scrubber() {
+        let reads = Recorder::default();
+        let registry = ScrubRegistry::new();
+        let mut driver =
+            RegistryScrubber::new(registry.clone(), reads.clone(), 64, 0)
+                .unwrap();
+        assert!(!driver.scrub(4096).unwrap());
+        assert!(driver.scrubber().is_none());
+
+        // Partial lines at either end are left alone, and an area with
+        // no whole line isn't scrubbed
+        let net = registry.register("net", 0x1010, 0x10ff).unwrap();
+        let tiny = registry.register("tiny", 0x3004, 0x3040).unwrap();
+        let disk = registry.register("disk", 0x2000, 0x207f).unwrap();
+        assert!(driver.scrub(64 * 5).unwrap());
+        let mut seen = reads.reads();
+        seen.sort();
+        assert_eq!(seen, [0x1040, 0x1080, 0x10c0, 0x2000, 0x2040]);
+        let scrubber = driver.scrubber().unwrap();
+        assert_eq!(
+            scrubber.extents(),
+            [(0x1040, 0x10ff), (0x2000, 0x207f)]
+        );
+        assert_eq!(
+            scrubber.stats().areas[1].label.as_deref(),
+            Some("disk")
+        );
+        assert_eq!(driver.registration(1), Some(disk.id()));
+
+        // Unregistered memory is no longer read
+        drop(net);
+        reads.clear();
+        driver.scrub(64 * 4).unwrap();
+        assert!(reads.reads().iter().all(|&a| a >= 0x2000));
+        assert_eq!(driver.registration(0), Some(disk.id()));
+
+        // With nothing left the scrubber is kept but reads nothing, until
+        // a new area takes the place of the last, even over its memory
+        let scrubbed = driver.scrubber().unwrap().stats().bytes_scrubbed;
+        driver.scrubber_mut().unwrap().exclude(0x9040, 0x907f);
+        drop(disk);
+        drop(tiny);
+        reads.clear();
+        assert!(!driver.scrub(4096).unwrap());
+        assert!(driver.is_vacant());
+        assert!(reads.reads().is_empty());
+        let heap = registry.register("heap", 0x2000, 0x207f).unwrap();
+        let _pool = registry.register("pool", 0x9000, 0x907f).unwrap();
+        assert!(driver.scrub(64 * 3).unwrap());
+        assert!(!driver.is_vacant());
+        let mut seen = reads.reads();
+        seen.sort();
+        assert_eq!(seen, [0x2000, 0x2040, 0x9000]);
+        let scrubber = driver.scrubber().unwrap();
+        assert_eq!(
+            scrubber.stats().areas[0].label.as_deref(),
+            Some("heap")
+        );
+        assert_eq!(scrubber.stats().areas[0].errors_corrected, 0);
+        assert!(scrubber.stats().bytes_scrubbed > scrubbed);
+        assert_eq!(driver.registration(0), Some(heap.id()));
+
+        assert!(
+            RegistryScrubber::new(registry, reads.clone(), 48, 0).is_err()
+        );
+    }
+}
@@ -0,0 +1,159 @@
+// Random numbers for the randomized modes, such as the jitter added
+// between chunks and the lines sampled for touch accounting. These need
+// numbers that keep scrubbers apart and samples unbiased, not numbers
+// that are hard to predict, so a small xorshift generator is the default.
+// Embedded targets often have a hardware random number generator and no
+// getrandom, so any source of random numbers can be used instead through
+// the Rng trait, which is implemented for closures returning each number.
+// Neither the trait nor XorShift64 needs anything beyond core and alloc.
+// XorShift64::from_time() seeds from the time and process ID where there
+// is an operating system to give them, and otherwise from the address of
+// its stack, which differs between tasks.
+//
+// Jitter and TouchSampler are Clone, so the sources they hold are boxed as
+// CloneRng, which any Rng that is Clone and Send is.
+
+#[cfg(any(unix, windows))]
+use std::process;
+#[cfg(any(unix, windows))]
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// A source of random numbers
+pub trait Rng {
+    /// Returns the next random number, with all bits equally likely to be
+    /// set
+    fn next_u64(&mut self) -> u64;
+
+    /// Returns a random number in [0, 1)
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+
+    /// Returns a random number in [0, n), or zero if n is zero. Numbers
+    /// below 2^64 mod n are drawn again, so that each result is equally
+    /// likely.
+    fn below(&mut self, n: u64) -> u64 {
+        if n == 0 {
+            return 0;
+        }
+        let least = n.wrapping_neg() % n;
+        loop {
+            let x = self.next_u64();
+            if x >= least {
+                return x % n;
+            }
+        }
+    }
+}
+
+/// A source of random numbers that can be cloned while boxed
+pub trait CloneRng: Rng + Send {
+    /// Returns a boxed copy of the source, which goes on to give the same
+    /// numbers
+    fn clone_box(&self) -> Box<dyn CloneRng>;
+}
+
+impl<T: Rng + Clone + Send + 'static> CloneRng for T {
+    fn clone_box(&self) -> Box<dyn CloneRng> {
+        Box::new(self.clone())
+    }
+}
+
+impl Clone for Box<dyn CloneRng> {
+    fn clone(&self) -> Box<dyn CloneRng> {
+        self.as_ref().clone_box()
+    }
+}
+
+impl<F: FnMut() -> u64> Rng for F {
+    fn next_u64(&mut self) -> u64 {
+        self()
+    }
+}
+
+/// A xorshift64 pseudo-random number generator
+///
+/// * `state` - Generator state, never zero
+#[derive(Clone, Debug)]
+pub struct XorShift64 {
+    state: u64,
+}
+
+impl XorShift64 {
+    /// Create a generator with a given seed, for reproducible numbers
+    pub fn new(seed: u64) -> XorShift64 {
+        XorShift64 {
+            // xorshift gets stuck at zero
+            state: seed.max(1),
+        }
+    }
+
+    /// Create a generator seeded from the time and process ID, so that
+    /// each instance differs. Without an operating system it is seeded
+    /// from the address of the caller's stack.
+    #[cfg(any(unix, windows))]
+    pub fn from_time() -> XorShift64 {
+        let nanos = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        XorShift64::new(nanos ^ ((process::id() as u64) << 32))
+    }
+
+    /// Create a generator seeded from the time and process ID, so that
+    /// each instance differs. Without an operating system it is seeded
+    /// from the address of the caller's stack.
+    #[cfg(not(any(unix, windows)))]
+    pub fn from_time() -> XorShift64 {
+        let local = 0u8;
+        XorShift64::new(&local as *const u8 as u64)
+    }
+}
+
+impl Rng for XorShift64 {
+    fn next_u64(&mut self) -> u64 {
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 7;
+        self.state ^= self.state << 17;
+        self.state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rng() {
+        let mut a = XorShift64::new(7);
+        let mut b = XorShift64::new(7);
+        assert_eq!(a.next_u64(), b.next_u64());
+        assert!((0..1000).all(|_| (0.0..1.0).contains(&a.next_f64())));
+        assert!((0..1000).all(|_| a.below(10) < 10));
+        assert_eq!(a.below(0), 0);
+
+        // A zero seed would give only zeroes
+        assert_ne!(XorShift64::new(0).next_u64(), 0);
+
+        // A hardware generator, here a counter, supplied as a closure
+        let mut count = 0;
+        let mut counter = || {
+            count += 1;
+            count
+        };
+        assert_eq!(counter.below(2), 1);
+        assert_eq!(counter.below(2), 0);
+
+        // Numbers that would favour the low results are drawn again
+        let n = 3 << 62;
+        let mut draws = [5, (1 << 62) + 5].into_iter();
+        let mut draw = || draws.next().unwrap();
+        assert_eq!(draw.below(n), (1 << 62) + 5);
+
+        // A boxed copy carries on from the same state
+        let mut a: Box<dyn CloneRng> = Box::new(XorShift64::new(7));
+        a.next_u64();
+        let mut b = a.clone();
+        assert_eq!(a.next_u64(), b.next_u64());
+    }
+}
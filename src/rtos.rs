@@ -0,0 +1,494 @@
+// Integration with real-time operating systems. Firmware on Zephyr or
+// FreeRTOS, with std through a port such as ESP-IDF, can't give the
+// scrubber a thread of its own to sleep in. Instead, scrubbing is driven
+// by the RTOS: from a Zephyr work item that reschedules itself, or from
+// the FreeRTOS idle hook, which runs whenever no task is ready and must
+// return quickly.
+//
+// The RTOS is reached through two small traits the firmware implements
+// with its own bindings, so no RTOS headers are needed here:
+//
+//  TickSource: the kernel tick counter, from which TickClock provides a
+//  Clock for the scheduling that the rest of the crate does
+//
+//  CriticalSection: entering and leaving a section in which the scrubbing
+//  task can't be preempted, used by CriticalBackend around each line read
+//  so that no interrupt sees a line half scrubbed and interrupt latency
+//  grows by at most the time to read one line
+//
+// All allocation is done when the scrubber is created, at system
+// initialization. A step of scrubbing allocates nothing unless policies
+// have been added to the LineScrubber.
+
+use std::time::{Duration, Instant};
+
+use crate::backend::*;
+use crate::base::*;
+use crate::clock::*;
+use crate::coverage::*;
+
+/// The kernel tick counter of an RTOS
+pub trait TickSource {
+    /// Returns the number of ticks since boot, as from k_uptime_ticks() or
+    /// xTaskGetTickCount()
+    fn ticks(&self) -> u64;
+
+    /// Returns the number of ticks per second
+    fn tick_rate(&self) -> u32;
+
+    /// Block the calling task for a number of ticks, as with k_sleep() or
+    /// vTaskDelay()
+    fn delay(&self, ticks: u64);
+}
+
+/// Disabling preemption around a short piece of work
+pub trait CriticalSection {
+    /// Enter the section, as with irq_lock() or taskENTER_CRITICAL(),
+    /// returning a key to leave it with
+    fn enter(&self) -> u32;
+
+    /// Leave the section entered with the given key
+    fn exit(&self, key: u32);
+}
+
+/// Converts a number of ticks to a Duration
+///
+/// # Arguments:
+/// * `ticks` - Number of ticks
+///
+/// * `rate` - Ticks per second
+pub fn ticks_to_duration(ticks: u64, rate: u32) -> Duration {
+    let rate = rate.max(1) as u64;
+    Duration::from_secs(ticks / rate)
+        + Duration::from_nanos((ticks % rate) * 1_000_000_000 / rate)
+}
+
+/// Converts a Duration to a number of ticks, rounding up so that waits are
+/// never too short
+///
+/// # Arguments:
+/// * `duration` - The Duration
+///
+/// * `rate` - Ticks per second
+pub fn duration_to_ticks(duration: Duration, rate: u32) -> u64 {
+    let nanos = duration.as_nanos() * rate as u128;
+    nanos.div_ceil(1_000_000_000) as u64
+}
+
+/// A Clock driven by the kernel tick counter. Times are Instants offset
+/// from when the clock was created by the number of ticks since then.
+///
+/// * `source` - The tick counter
+///
+/// * `base` - Instant corresponding to `base_ticks`
+///
+/// * `base_ticks` - Tick count when the clock was created
+pub struct TickClock<T: TickSource> {
+    source: T,
+    base: Instant,
+    base_ticks: u64,
+}
+
+impl<T: TickSource> TickClock<T> {
+    /// Create a clock from a tick counter
+    pub fn new(source: T) -> TickClock<T> {
+        TickClock {
+            base_ticks: source.ticks(),
+            source,
+            base: Instant::now(),
+        }
+    }
+
+    /// Returns the tick counter
+    pub fn source(&self) -> &T {
+        &self.source
+    }
+}
+
+impl<T: TickSource> Clock for TickClock<T> {
+    fn now(&self) -> Instant {
+        let ticks = self.source.ticks().saturating_sub(self.base_ticks);
+        self.base + ticks_to_duration(ticks, self.source.tick_rate())
+    }
+
+    fn sleep(&self, duration: Duration) {
+        let ticks = duration_to_ticks(duration, self.source.tick_rate());
+        if ticks != 0 {
+            self.source.delay(ticks);
+        }
+    }
+}
+
+/// A backend that reads each line in a critical section
+///
+/// * `backend` - Backend making the reads
+///
+/// * `critical` - Enters and leaves the critical section
+pub struct CriticalBackend<B: ScrubBackend, C: CriticalSection> {
+    backend: B,
+    critical: C,
+}
+
+impl<B: ScrubBackend, C: CriticalSection> CriticalBackend<B, C> {
+    /// Create a CriticalBackend
+    pub fn new(backend: B, critical: C) -> CriticalBackend<B, C> {
+        CriticalBackend { backend, critical }
+    }
+
+    /// Returns the backend making the reads
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+}
+
+impl<B: ScrubBackend, C: CriticalSection> ScrubBackend
+    for CriticalBackend<B, C>
+{
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        let key = self.critical.enter();
+        let result = self.backend.read_line(addr);
+        self.critical.exit(key);
+        result
+    }
+
+    fn read_words(
+        &mut self,
+        addr: usize,
+        line_size: usize,
+        reads: usize,
+    ) -> Result<(), Error> {
+        let key = self.critical.enter();
+        let result = self.backend.read_words(addr, line_size, reads);
+        self.critical.exit(key);
+        result
+    }
+
+    fn flush_line(&mut self, addr: usize) -> Result<(), Error> {
+        let key = self.critical.enter();
+        let result = self.backend.flush_line(addr);
+        self.critical.exit(key);
+        result
+    }
+
+    fn skip_reason(&mut self, addr: usize) -> Option<SkipReason> {
+        self.backend.skip_reason(addr)
+    }
+
+    fn excluded_lines(&self, start: usize, end: usize) -> Option<u64> {
+        self.backend.excluded_lines(start, end)
+    }
+
+    // A batch or window spans many lines, so it is not itself made
+    // critical
+    fn begin_batch(&mut self) -> Result<(), Error> {
+        self.backend.begin_batch()
+    }
+
+    fn end_batch(&mut self) -> Result<(), Error> {
+        self.backend.end_batch()
+    }
+
+    fn end_window(&mut self) -> Result<(), Error> {
+        self.backend.end_window()
+    }
+
+    fn window_size(&self) -> Option<usize> {
+        self.backend.window_size()
+    }
+}
+
+/// Scrubbing driven by a self-rescheduling Zephyr work item. The work
+/// handler calls run() and reschedules the item, with
+/// k_work_reschedule(), after the number of ticks it returns.
+///
+/// * `scrubber` - Scrubs the memory
+///
+/// * `chunk` - Number of bytes scrubbed each time the work item runs
+///
+/// * `period` - Time from the start of one chunk to the next
+///
+/// * `tick_rate` - Ticks per second
+#[cfg(feature = "zephyr")]
+pub struct ZephyrScrubWork<B: ScrubBackend> {
+    scrubber: LineScrubber<B>,
+    chunk: usize,
+    period: Duration,
+    tick_rate: u32,
+}
+
+#[cfg(feature = "zephyr")]
+impl<B: ScrubBackend> ZephyrScrubWork<B> {
+    /// Create the work, usually at system initialization
+    ///
+    /// # Arguments:
+    /// * `scrubber` - Scrubs the memory. Setting its clock to a TickClock
+    ///   keeps its statistics in kernel time.
+    ///
+    /// * `chunk` - Number of bytes scrubbed each time the work item runs
+    ///
+    /// * `period` - Time from the start of one chunk to the next
+    ///
+    /// * `tick_rate` - Ticks per second, CONFIG_SYS_CLOCK_TICKS_PER_SEC
+    pub fn new(
+        scrubber: LineScrubber<B>,
+        chunk: usize,
+        period: Duration,
+        tick_rate: u32,
+    ) -> ZephyrScrubWork<B> {
+        ZephyrScrubWork {
+            scrubber,
+            chunk,
+            period,
+            tick_rate,
+        }
+    }
+
+    /// Scrub a chunk, for calling from the work handler
+    ///
+    /// # Returns:
+    /// Ok(ticks) with the delay before the work should run again, at least
+    /// one tick, otherwise Err(Error) if scrubbing failed
+    pub fn run(&mut self) -> Result<u64, Error> {
+        self.scrubber.scrub(self.chunk)?;
+        Ok(duration_to_ticks(self.period, self.tick_rate).max(1))
+    }
+
+    /// Returns the scrubber
+    pub fn scrubber(&self) -> &LineScrubber<B> {
+        &self.scrubber
+    }
+}
+
+/// Scrubbing from the FreeRTOS idle hook. vApplicationIdleHook() calls
+/// idle_hook(), which scrubs a few lines each time the scheduler is idle,
+/// and nothing until the interval has passed once a pass completes.
+///
+/// * `scrubber` - Scrubs the memory
+///
+/// * `ticks` - The tick counter
+///
+/// * `lines` - Number of lines scrubbed per call, small enough that the
+///   hook returns quickly
+///
+/// * `interval` - Ticks from the start of one pass to the start of the
+///   next
+///
+/// * `pass_start` - Tick count when the current pass started
+#[cfg(feature = "freertos")]
+pub struct IdleHookScrubber<B: ScrubBackend, T: TickSource> {
+    scrubber: LineScrubber<B>,
+    ticks: T,
+    lines: usize,
+    interval: u64,
+    pass_start: u64,
+}
+
+#[cfg(feature = "freertos")]
+impl<B: ScrubBackend, T: TickSource> IdleHookScrubber<B, T> {
+    /// Create the scrubber, usually at system initialization
+    ///
+    /// # Arguments:
+    /// * `scrubber` - Scrubs the memory
+    ///
+    /// * `ticks` - The tick counter
+    ///
+    /// * `lines` - Number of lines scrubbed per call
+    ///
+    /// * `interval` - Time from the start of one pass to the start of the
+    ///   next. Zero starts each pass as soon as the last completes.
+    pub fn new(
+        scrubber: LineScrubber<B>,
+        ticks: T,
+        lines: usize,
+        interval: Duration,
+    ) -> IdleHookScrubber<B, T> {
+        IdleHookScrubber {
+            interval: duration_to_ticks(interval, ticks.tick_rate()),
+            pass_start: ticks.ticks(),
+            scrubber,
+            ticks,
+            lines: lines.max(1),
+        }
+    }
+
+    /// Scrub a few lines, for calling from vApplicationIdleHook()
+    ///
+    /// # Returns:
+    /// Ok(bytes) with the number of bytes scrubbed, zero while waiting for
+    /// the next pass, otherwise Err(Error) if scrubbing failed
+    pub fn idle_hook(&mut self) -> Result<usize, Error> {
+        let now = self.ticks.ticks();
+        let passes = self.scrubber.stats().passes;
+        if self.scrubber.stats().pass_offset == 0
+            && passes != 0
+            && now.wrapping_sub(self.pass_start) < self.interval
+        {
+            return Ok(0);
+        }
+        if self.scrubber.stats().pass_offset == 0 {
+            self.pass_start = now;
+        }
+
+        // Stop at the end of the pass rather than starting the next
+        let line_size = self.scrubber.line_size();
+        let left = self.scrubber.stats().pass_size
+            - self.scrubber.stats().pass_offset;
+        let bytes = (self.lines * line_size).min(left);
+        self.scrubber.scrub(bytes)?;
+        Ok(bytes)
+    }
+
+    /// Returns the scrubber
+    pub fn scrubber(&self) -> &LineScrubber<B> {
+        &self.scrubber
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    // A tick counter at 1000 ticks per second that advances only when
+    // delayed on or moved by the test
+    #[derive(Clone)]
+    struct Ticks(Rc<Cell<u64>>);
+
+    impl TickSource for Ticks {
+        fn ticks(&self) -> u64 {
+            self.0.get()
+        }
+
+        fn tick_rate(&self) -> u32 {
+            1000
+        }
+
+        fn delay(&self, ticks: u64) {
+            self.0.set(self.0.get() + ticks);
+        }
+    }
+
+    // Counts the critical sections entered and checks they are nested
+    // correctly
+    struct Counter(Rc<Cell<u32>>);
+
+    impl CriticalSection for Counter {
+        fn enter(&self) -> u32 {
+            self.0.set(self.0.get() + 1);
+            self.0.get()
+        }
+
+        fn exit(&self, key: u32) {
+            assert_eq!(key, self.0.get());
+        }
+    }
+
+    fn scrubber() -> LineScrubber<SimMemory> {
+        let mem = SimMemory::new(0, 4096, 64).unwrap();
+        LineScrubber::new(mem, &[(0, 1023)], 64, 4).unwrap()
+    }
+
+    #[test]
+    fn test_conversion() {
+        assert_eq!(
+            ticks_to_duration(1500, 1000),
+            Duration::from_millis(1500)
+        );
+        assert_eq!(
+            duration_to_ticks(Duration::from_micros(1500), 1000),
+            2
+        );
+        assert_eq!(duration_to_ticks(Duration::ZERO, 1000), 0);
+    }
+
+    #[test]
+    fn test_tick_clock() {
+        let ticks = Ticks(Rc::new(Cell::new(5000)));
+        let clock = TickClock::new(ticks.clone());
+        let start = clock.now();
+        clock.sleep(Duration::from_millis(250));
+        assert_eq!(ticks.ticks(), 5250);
+        assert_eq!(clock.now() - start, Duration::from_millis(250));
+    }
+
+    #[test]
+    fn test_critical_backend() {
+        let count = Rc::new(Cell::new(0));
+        let mem = SimMemory::new(0, 4096, 64).unwrap();
+        let backend = CriticalBackend::new(mem, Counter(count.clone()));
+        let mut scrubber =
+            LineScrubber::new(backend, &[(0, 1023)], 64, 4).unwrap();
+        scrubber.scrub(512).unwrap();
+        assert_eq!(count.get(), 8);
+
+        // Batches reach the backend making the reads
+        #[derive(Default)]
+        struct Batches {
+            begun: u32,
+            ended: u32,
+        }
+
+        impl ScrubBackend for Batches {
+            fn read_line(&mut self, _addr: usize) -> Result<(), Error> {
+                Ok(())
+            }
+
+            fn begin_batch(&mut self) -> Result<(), Error> {
+                self.begun += 1;
+                Ok(())
+            }
+
+            fn end_batch(&mut self) -> Result<(), Error> {
+                self.ended += 1;
+                Ok(())
+            }
+        }
+
+        let backend =
+            CriticalBackend::new(Batches::default(), Counter(count));
+        let mut scrubber =
+            LineScrubber::new(backend, &[(0, 1023)], 64, 4).unwrap();
+        scrubber.scrub(512).unwrap();
+        scrubber.scrub(512).unwrap();
+        assert_eq!(scrubber.backend().backend().begun, 2);
+        assert_eq!(scrubber.backend().backend().ended, 2);
+    }
+
+    #[cfg(feature = "zephyr")]
+    #[test]
+    fn test_zephyr_work() {
+        let mut work = ZephyrScrubWork::new(
+            scrubber(),
+            256,
+            Duration::from_millis(5),
+            100,
+        );
+        assert_eq!(work.run(), Ok(1));
+        assert_eq!(work.scrubber().stats().bytes_scrubbed, 256);
+    }
+
+    #[cfg(feature = "freertos")]
+    #[test]
+    fn test_idle_hook() {
+        let ticks = Ticks(Rc::new(Cell::new(0)));
+        let mut idle = IdleHookScrubber::new(
+            scrubber(),
+            ticks.clone(),
+            6,
+            Duration::from_secs(1),
+        );
+
+        // 16 lines in a pass, 6 at a time
+        assert_eq!(idle.idle_hook(), Ok(384));
+        assert_eq!(idle.idle_hook(), Ok(384));
+        assert_eq!(idle.idle_hook(), Ok(256));
+        assert_eq!(idle.scrubber().stats().passes, 1);
+
+        // Then nothing until a second has passed since the pass started
+        assert_eq!(idle.idle_hook(), Ok(0));
+        ticks.delay(1000);
+        assert_eq!(idle.idle_hook(), Ok(384));
+    }
+}
@@ -0,0 +1,293 @@
+// Scheduling control for scrub threads. Scrubbing is background work and
+// should not take CPU time from latency-critical work, so scrub threads can
+// be pinned to a set of CPUs and run at a reduced scheduling priority.
+// Where the last level cache can be partitioned, they can also be confined
+// to a small cache partition through resctrl.
+//
+// Linux and Windows are supported. Windows has no scheduling classes, so
+// they and the nice level are mapped onto thread priorities, and resctrl
+// is not available. On other systems applying a configuration that changes
+// anything returns an Unsupported error.
+
+use std::io;
+use std::thread::{self, JoinHandle};
+
+use crate::os::*;
+
+/// Scheduling class for a scrub thread
+///
+/// * `Normal` - The default time-sharing class
+///
+/// * `Batch` - Time-sharing, but treated as CPU-bound and never favored
+///   for wakeup
+///
+/// * `Idle` - Run only when the CPU would otherwise be idle
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum SchedClass {
+    #[default]
+    Normal,
+    Batch,
+    Idle,
+}
+
+/// Scheduling configuration for a scrub thread
+///
+/// * `cpus` - CPUs the thread may run on. Empty allows all CPUs.
+///
+/// * `class` - Scheduling class
+///
+/// * `nice` - Nice level, from -20 to 19, or None to leave it unchanged
+///
+/// * `cache` - Resource group to confine the thread's cache use to, or
+///   None to share the whole cache
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScrubThreadConfig {
+    pub cpus: Vec<usize>,
+    pub class: SchedClass,
+    pub nice: Option<i32>,
+    pub cache: Option<CacheAllocation>,
+}
+
+impl ScrubThreadConfig {
+    /// A configuration for scrubbing that never competes with other work:
+    /// the idle scheduling class at the lowest priority
+    pub fn background() -> ScrubThreadConfig {
+        ScrubThreadConfig {
+            cpus: Vec::new(),
+            class: SchedClass::Idle,
+            nice: Some(19),
+            cache: None,
+        }
+    }
+
+    /// Apply the configuration to the calling thread
+    pub fn apply(&self) -> io::Result<()> {
+        platform::set_affinity(&self.cpus)?;
+        platform::set_class(self.class)?;
+        if let Some(nice) = self.nice {
+            platform::set_nice(nice)?;
+        }
+        if let Some(cache) = &self.cache {
+            Resctrl::new().apply(cache)?;
+        }
+        Ok(())
+    }
+
+    /// Start a named thread with this configuration applied and run f in
+    /// it
+    ///
+    /// # Arguments:
+    /// * `name` - Name for the thread
+    ///
+    /// * `f` - Function to run, usually one that runs an AutoScrub
+    ///
+    /// # Returns:
+    /// A handle for the thread. Joining it gives the result of f, or the
+    /// error from applying the configuration, in which case f is not run.
+    pub fn spawn<F, T>(
+        &self,
+        name: &str,
+        f: F,
+    ) -> io::Result<JoinHandle<io::Result<T>>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let config = self.clone();
+        thread::Builder::new()
+            .name(name.to_string())
+            .spawn(move || {
+                config.apply()?;
+                Ok(f())
+            })
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod platform {
+    use super::SchedClass;
+    use std::io;
+    use std::mem;
+
+    pub fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+        if cpus.is_empty() {
+            return Ok(());
+        }
+
+        // SAFETY: cpu_set_t is plain data, all zeros is the empty set, and
+        // CPU_SET only writes within it
+        let mut set: libc::cpu_set_t = unsafe { mem::zeroed() };
+        for cpu in cpus {
+            if *cpu >= libc::CPU_SETSIZE as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("CPU {} out of range", cpu),
+                ));
+            }
+            unsafe { libc::CPU_SET(*cpu, &mut set) };
+        }
+
+        // SAFETY: set is a valid cpu_set_t of the size passed
+        let rc = unsafe {
+            libc::sched_setaffinity(
+                0,
+                mem::size_of::<libc::cpu_set_t>(),
+                &set,
+            )
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn set_class(class: SchedClass) -> io::Result<()> {
+        let policy = match class {
+            SchedClass::Normal => libc::SCHED_OTHER,
+            SchedClass::Batch => libc::SCHED_BATCH,
+            SchedClass::Idle => libc::SCHED_IDLE,
+        };
+        let param = libc::sched_param { sched_priority: 0 };
+
+        // SAFETY: param is valid for the duration of the call
+        let rc = unsafe { libc::sched_setscheduler(0, policy, &param) };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn set_nice(nice: i32) -> io::Result<()> {
+        // On Linux, PRIO_PROCESS with the thread ID affects only the
+        // calling thread
+        // SAFETY: gettid and setpriority take no pointers
+        let rc = unsafe {
+            let tid = libc::gettid();
+            libc::setpriority(libc::PRIO_PROCESS, tid as libc::id_t, nice)
+        };
+        if rc != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+#[cfg(windows)]
+mod platform {
+    use super::SchedClass;
+    use crate::os::*;
+    use std::io;
+
+    pub fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+        if cpus.is_empty() {
+            return Ok(());
+        }
+
+        let mut mask = 0usize;
+        for cpu in cpus {
+            if *cpu >= usize::BITS as usize {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("CPU {} out of range", cpu),
+                ));
+            }
+            mask |= 1 << cpu;
+        }
+        // SAFETY: GetCurrentThread returns a pseudo handle that needs no
+        // closing
+        match unsafe { SetThreadAffinityMask(GetCurrentThread(), mask) } {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    // Set the priority of the calling thread
+    fn set_priority(priority: i32) -> io::Result<()> {
+        // SAFETY: as for set_affinity()
+        match unsafe { SetThreadPriority(GetCurrentThread(), priority) } {
+            0 => Err(io::Error::last_os_error()),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn set_class(class: SchedClass) -> io::Result<()> {
+        set_priority(match class {
+            SchedClass::Normal => THREAD_PRIORITY_NORMAL,
+            SchedClass::Batch => THREAD_PRIORITY_BELOW_NORMAL,
+            SchedClass::Idle => THREAD_PRIORITY_IDLE,
+        })
+    }
+
+    // The nice level only ever lowers the priority set for the class, so
+    // that the idle class stays idle
+    pub fn set_nice(nice: i32) -> io::Result<()> {
+        let priority = match nice {
+            ..=-1 => THREAD_PRIORITY_ABOVE_NORMAL,
+            0 => THREAD_PRIORITY_NORMAL,
+            1..=9 => THREAD_PRIORITY_BELOW_NORMAL,
+            _ => THREAD_PRIORITY_LOWEST,
+        };
+        set_priority(priority.min(thread_priority()?))
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod platform {
+    use super::SchedClass;
+    use std::io;
+
+    fn unsupported() -> io::Error {
+        io::Error::new(
+            io::ErrorKind::Unsupported,
+            "scrub thread scheduling not supported on this system",
+        )
+    }
+
+    pub fn set_affinity(cpus: &[usize]) -> io::Result<()> {
+        match cpus.is_empty() {
+            true => Ok(()),
+            false => Err(unsupported()),
+        }
+    }
+
+    pub fn set_class(class: SchedClass) -> io::Result<()> {
+        match class {
+            SchedClass::Normal => Ok(()),
+            _ => Err(unsupported()),
+        }
+    }
+
+    pub fn set_nice(_nice: i32) -> io::Result<()> {
+        Err(unsupported())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_spawn() {
+        // Lowering priority and restricting to a CPU we are already
+        // allowed on never needs privileges
+        let config = ScrubThreadConfig {
+            cpus: vec![0],
+            class: SchedClass::Batch,
+            nice: Some(10),
+            cache: None,
+        };
+        let handle = config.spawn("memscrub-test", || 42).unwrap();
+        assert_eq!(handle.join().unwrap().unwrap(), 42);
+    }
+
+    #[test]
+    fn test_bad_cpu() {
+        let config = ScrubThreadConfig {
+            cpus: vec![usize::MAX],
+            ..ScrubThreadConfig::default()
+        };
+        let handle = config.spawn("memscrub-test", || ()).unwrap();
+        let err = handle.join().unwrap().unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+    }
+}
@@ -0,0 +1,398 @@
+// Startup self-test. A buffer allocated here is scrubbed for one pass with
+// the cache geometry the user supplied, and every read is checked: each
+// cache line must be read exactly once, at a cache line boundary, inside
+// the buffer, and in cache index order. A geometry that is wrong in a way
+// that would leave memory unscrubbed shows up as a problem in the report
+// rather than as missing coverage in production.
+//
+// self_test_geometry() scrubs with a LineScrubber through a checking
+// backend. MemoryScrubber::self_test() scrubs with a MemoryScrubber
+// through a CheckingCache wrapped round the user's cache description, so
+// the user's own read_cacheline() makes the reads.
+
+use std::cell::RefCell;
+
+use crate::addr::*;
+use crate::backend::*;
+use crate::base::*;
+use crate::data::*;
+
+/// Largest buffer the self-test will allocate. A cache index width needing
+/// more than this is reported as a problem.
+pub const SELF_TEST_MAX_BYTES: usize = 64 * 1024 * 1024;
+
+/// Number of cache lines with each cache index in the self-test buffer
+const SELF_TEST_WAYS: usize = 2;
+
+/// Result of a self-test
+///
+/// * `line_size` - Cache line size tested
+///
+/// * `index_width` - Cache index width tested
+///
+/// * `buffer_size` - Size of the buffer scrubbed, zero if none was
+///
+/// * `lines_expected` - Number of cache lines in the buffer
+///
+/// * `lines_read` - Number of reads made
+///
+/// * `missed` - Lines never read
+///
+/// * `repeated` - Lines read more than once
+///
+/// * `misaligned` - Reads not at a cache line boundary
+///
+/// * `outside` - Reads outside the buffer
+///
+/// * `out_of_order` - Reads with a lower cache index than the read before
+///
+/// * `problems` - Description of each problem found
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct SelfTestReport {
+    pub line_size: usize,
+    pub index_width: usize,
+    pub buffer_size: usize,
+    pub lines_expected: usize,
+    pub lines_read: usize,
+    pub missed: usize,
+    pub repeated: usize,
+    pub misaligned: usize,
+    pub outside: usize,
+    pub out_of_order: usize,
+    pub problems: Vec<String>,
+}
+
+impl SelfTestReport {
+    /// Returns whether the self-test found no problems
+    pub fn passed(&self) -> bool {
+        self.problems.is_empty()
+    }
+}
+
+// ReadCheck: Checks each read of one pass over the self-test buffer
+// start: Address of the first byte of the buffer
+// end: Address of the last byte of the buffer
+// line_size: Number of bytes in a cache line
+// sets: Number of cache indices
+// reads: Number of reads of each cache line in the buffer
+// last_index: Cache index of the last read
+// misaligned: Number of reads not at a cache line boundary
+// outside: Number of reads outside the buffer
+// out_of_order: Number of reads with a lower cache index than the last
+pub(crate) struct ReadCheck {
+    start: usize,
+    end: usize,
+    line_size: usize,
+    sets: usize,
+    reads: Vec<u32>,
+    last_index: usize,
+    misaligned: usize,
+    outside: usize,
+    out_of_order: usize,
+}
+
+impl ReadCheck {
+    // Check a read, returning whether it is inside the buffer and so may
+    // be made
+    pub(crate) fn check(&mut self, addr: usize) -> bool {
+        if addr < self.start || addr > self.end {
+            self.outside += 1;
+            return false;
+        }
+        if !addr.is_multiple_of(self.line_size) {
+            self.misaligned += 1;
+        }
+
+        let index = (addr / self.line_size) % self.sets;
+        if index < self.last_index {
+            self.out_of_order += 1;
+        }
+        self.last_index = index;
+
+        self.reads[(addr - self.start) / self.line_size] += 1;
+        true
+    }
+
+    // Fill in the report from the reads checked
+    pub(crate) fn report(&self, report: &mut SelfTestReport) {
+        report.lines_read =
+            self.reads.iter().map(|&r| r as usize).sum::<usize>()
+                + self.outside;
+        report.missed = self.reads.iter().filter(|&&r| r == 0).count();
+        report.repeated = self.reads.iter().filter(|&&r| r > 1).count();
+        report.misaligned = self.misaligned;
+        report.outside = self.outside;
+        report.out_of_order = self.out_of_order;
+
+        for (count, what) in [
+            (report.missed, "lines were never read"),
+            (report.repeated, "lines were read more than once"),
+            (report.misaligned, "reads were not on a cache line boundary"),
+            (report.outside, "reads were outside the buffer"),
+            (report.out_of_order, "reads were out of cache index order"),
+        ] {
+            if count != 0 {
+                report.problems.push(format!("{} {}", count, what));
+            }
+        }
+    }
+}
+
+// SelfTestBuffer: Memory scrubbed by the self-test
+// buffer: The allocation, held so that every read is of memory we own
+// start: Address of the first byte, on a cache line boundary
+// end: Address of the last byte
+pub(crate) struct SelfTestBuffer {
+    _buffer: Vec<u8>,
+    pub(crate) start: usize,
+    pub(crate) end: usize,
+}
+
+impl SelfTestBuffer {
+    // Allocate a buffer holding SELF_TEST_WAYS cache lines with each cache
+    // index. A geometry for which no buffer can be allocated is recorded
+    // as a problem in the report and None returned; otherwise the buffer
+    // size and number of lines expected are recorded.
+    pub(crate) fn new(
+        report: &mut SelfTestReport,
+    ) -> Option<SelfTestBuffer> {
+        let (line_size, index_width) =
+            (report.line_size, report.index_width);
+        if !line_size.is_power_of_two() {
+            report.problems.push(format!(
+                "cache line size {} is not a power of two",
+                line_size
+            ));
+            return None;
+        }
+        let size = 1usize
+            .checked_shl(index_width as u32)
+            .filter(|_| index_width < usize::BITS as usize)
+            .and_then(|sets| sets.checked_mul(line_size))
+            .and_then(|cache| cache.checked_mul(SELF_TEST_WAYS))
+            .filter(|&size| size <= SELF_TEST_MAX_BYTES);
+        let size = match size {
+            Some(size) => size,
+            None => {
+                report.problems.push(format!(
+                    "cache index width {} with {}-byte lines describes a \
+                     cache larger than {} bytes",
+                    index_width,
+                    line_size,
+                    SELF_TEST_MAX_BYTES / SELF_TEST_WAYS
+                ));
+                return None;
+            }
+        };
+        report.buffer_size = size;
+        report.lines_expected = size / line_size;
+
+        // Allocate an extra line so the buffer can start on a cache line
+        // boundary
+        let buffer = vec![0u8; size + line_size];
+        let start = (buffer.as_ptr() as usize).next_multiple_of(line_size);
+        Some(SelfTestBuffer {
+            _buffer: buffer,
+            start,
+            end: start + size - 1,
+        })
+    }
+
+    // Returns a ReadCheck for one pass over the buffer
+    pub(crate) fn read_check(&self, report: &SelfTestReport) -> ReadCheck {
+        ReadCheck {
+            start: self.start,
+            end: self.end,
+            line_size: report.line_size,
+            sets: 1 << report.index_width,
+            reads: vec![0; report.lines_expected],
+            last_index: 0,
+            misaligned: 0,
+            outside: 0,
+            out_of_order: 0,
+        }
+    }
+}
+
+// Backend that checks each read before making it
+struct CheckingBackend {
+    inner: RawBackend,
+    check: ReadCheck,
+}
+
+impl ScrubBackend for CheckingBackend {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        match self.check.check(addr) {
+            true => self.inner.read_line(addr),
+            false => Ok(()),
+        }
+    }
+}
+
+// CheckingCache: A cache description that checks each cache line read
+// through it before passing it on to the description it wraps, so that
+// the wrapped description's own read_cacheline() is tested
+// cache: The cache description being tested
+// check: Checks of the reads made
+pub(crate) struct CheckingCache<
+    'a,
+    const N: usize,
+    const W: usize,
+    const S: usize,
+    D,
+    A,
+> where
+    D: DataImplTrait<D>,
+    A: AddrImplTrait<A>,
+    usize: From<A>,
+{
+    cache: &'a dyn CacheBase<N, W, S, D, A>,
+    check: RefCell<ReadCheck>,
+}
+
+impl<'a, const N: usize, const W: usize, const S: usize, D, A>
+    CheckingCache<'a, N, W, S, D, A>
+where
+    D: DataImplTrait<D>,
+    A: AddrImplTrait<A>,
+    usize: From<A>,
+{
+    pub(crate) fn new(
+        cache: &'a dyn CacheBase<N, W, S, D, A>,
+        check: ReadCheck,
+    ) -> Self {
+        CheckingCache {
+            cache,
+            check: RefCell::new(check),
+        }
+    }
+
+    // Fill in the report from the reads checked
+    pub(crate) fn report(&self, report: &mut SelfTestReport) {
+        self.check.borrow().report(report);
+    }
+}
+
+impl<const N: usize, const W: usize, const S: usize, D, A>
+    CacheBase<N, W, S, D, A> for CheckingCache<'_, N, W, S, D, A>
+where
+    D: DataImplTrait<D>,
+    A: AddrImplTrait<A>,
+    usize: From<A>,
+{
+    fn check_cache_params(&self) -> Result<(), Error> {
+        self.cache.check_cache_params()
+    }
+
+    fn reads_per_cacheline(&self) -> usize {
+        self.cache.reads_per_cacheline()
+    }
+
+    fn cache_index_width(&self) -> usize {
+        self.cache.cache_index_width()
+    }
+
+    fn read_cacheline(&self, p: Addr<A>) {
+        if self.check.borrow_mut().check(usize::from(p.into())) {
+            self.cache.read_cacheline(p);
+        }
+    }
+}
+
+/// Scrub a buffer with the given cache geometry and check the reads
+///
+/// # Arguments:
+/// * `line_size` - Number of bytes in a cache line
+///
+/// * `index_width` - Number of address bits in the cache index
+///
+/// # Returns:
+/// A report of what was read and any problems found
+pub fn self_test_geometry(
+    line_size: usize,
+    index_width: usize,
+) -> SelfTestReport {
+    let mut report = SelfTestReport {
+        line_size,
+        index_width,
+        ..Default::default()
+    };
+    let buffer = match SelfTestBuffer::new(&mut report) {
+        Some(buffer) => buffer,
+        None => return report,
+    };
+
+    let backend = CheckingBackend {
+        // Only addresses inside the buffer are passed to the raw backend
+        inner: unsafe { RawBackend::new() },
+        check: buffer.read_check(&report),
+    };
+    let mut scrubber = match LineScrubber::new(
+        backend,
+        &[(buffer.start, buffer.end)],
+        line_size,
+        index_width,
+    ) {
+        Ok(scrubber) => scrubber,
+        Err(e) => {
+            report.problems.push(format!("invalid geometry: {}", e));
+            return report;
+        }
+    };
+    if let Err(e) = scrubber.scrub(report.buffer_size) {
+        report.problems.push(format!("scrub failed: {}", e));
+    }
+    scrubber.backend().check.report(&mut report);
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pass() {
+        let report = self_test_geometry(64, 10);
+        assert!(report.passed(), "{:?}", report.problems);
+        assert_eq!(report.buffer_size, 128 * 1024);
+        assert_eq!(report.lines_expected, 2048);
+        assert_eq!(report.lines_read, 2048);
+    }
+
+    #[test]
+    fn test_misconfigured() {
+        let report = self_test_geometry(48, 10);
+        assert!(!report.passed());
+
+        // A cache index width mistaken for an address width
+        let report = self_test_geometry(64, 40);
+        assert!(!report.passed());
+        assert_eq!(report.buffer_size, 0);
+    }
+
+    #[test]
+    fn test_read_check() {
+        let mut report = SelfTestReport {
+            line_size: 64,
+            index_width: 2,
+            ..Default::default()
+        };
+        let buffer = SelfTestBuffer::new(&mut report).unwrap();
+        assert_eq!(report.lines_expected, 8);
+
+        // The reads a cache description with the wrong index width makes
+        let mut check = buffer.read_check(&report);
+        for line in [0, 1, 4, 5, 2, 3, 6, 7, 7] {
+            assert!(check.check(buffer.start + line * 64));
+        }
+        assert!(!check.check(buffer.end + 1));
+        assert!(check.check(buffer.start + 1));
+        check.report(&mut report);
+        assert_eq!(report.lines_read, 11);
+        assert_eq!(report.repeated, 2);
+        assert_eq!(report.misaligned, 1);
+        assert_eq!(report.outside, 1);
+        assert_eq!(report.out_of_order, 3);
+        assert_eq!(report.problems.len(), 4);
+    }
+}
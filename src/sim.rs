@@ -0,0 +1,225 @@
+// Simulated memory for scrubbing without touching real memory. SimMemory
+// is a ScrubBackend holding an owned buffer that stands in for memory at a
+// chosen address. Reads are bounds-checked indexing, with no unsafe code, so
+// scrubbing logic built on it runs under Miri and on hosts where mapping
+// memory isn't possible. The number of times each cache line is read is
+// kept so that coverage can be checked.
+
+use crate::backend::*;
+use crate::base::*;
+
+/// Memory simulated by a buffer
+///
+/// * `base` - Address at which the simulated memory starts
+///
+/// * `line_size` - Number of bytes in a cache line
+///
+/// * `data` - Contents of the memory
+///
+/// * `reads` - Number of times each cache line has been read
+#[derive(Clone, Debug)]
+pub struct SimMemory {
+    base: usize,
+    line_size: usize,
+    data: Vec<u8>,
+    reads: Vec<u32>,
+}
+
+impl SimMemory {
+    /// Create zero-filled simulated memory
+    ///
+    /// # Arguments:
+    /// * `base` - Address at which the memory starts, a multiple of the
+    ///   cache line size
+    ///
+    /// * `size` - Number of bytes, a multiple of the cache line size
+    ///
+    /// * `line_size` - Number of bytes in a cache line
+    pub fn new(
+        base: usize,
+        size: usize,
+        line_size: usize,
+    ) -> Result<SimMemory, Error> {
+        if size == 0 {
+            return Err(Error::ZeroSize);
+        }
+        let end =
+            base.checked_add(size - 1).ok_or(Error::AddressOverflow)?;
+        check_extents(&[(base, end)], line_size)?;
+
+        Ok(SimMemory {
+            base,
+            line_size,
+            data: vec![0; size],
+            reads: vec![0; size / line_size],
+        })
+    }
+
+    /// Returns the (start, end) address of the memory, end inclusive, for
+    /// use as a scrub area
+    pub fn extent(&self) -> (usize, usize) {
+        (self.base, self.base + self.data.len() - 1)
+    }
+
+    /// Returns the contents of the memory
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        &mut self.data
+    }
+
+    /// Returns the number of times each cache line has been read, in
+    /// address order
+    pub fn reads(&self) -> &[u32] {
+        &self.reads
+    }
+
+    /// Returns the number of times the cache line holding an address has
+    /// been read, or None if the address is outside the memory
+    pub fn reads_at(&self, addr: usize) -> Option<u32> {
+        let offset = addr.checked_sub(self.base)?;
+        self.reads.get(offset / self.line_size).copied()
+    }
+
+    /// Forget all reads so far
+    pub fn clear_reads(&mut self) {
+        self.reads.iter_mut().for_each(|r| *r = 0);
+    }
+}
+
+impl ScrubBackend for SimMemory {
+    // An address outside the simulated memory means the scrub areas or the
+    // iteration over them are wrong
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        let offset = addr
+            .checked_sub(self.base)
+            .filter(|&offset| offset < self.data.len())
+            .ok_or(Error::InternalError)?;
+        let line = offset / self.line_size;
+        std::hint::black_box(self.data[line * self.line_size]);
+        self.reads[line] += 1;
+        Ok(())
+    }
+
+    // Every read is checked, but the line is counted as read only once
+    fn read_words(
+        &mut self,
+        addr: usize,
+        line_size: usize,
+        reads: usize,
+    ) -> Result<(), Error> {
+        self.read_line(addr)?;
+        let line = (addr - self.base) / self.line_size * self.line_size;
+        for offset in (0..line_size).step_by(line_size / reads).skip(1) {
+            let word = self
+                .data
+                .get(line + offset)
+                .ok_or(Error::InternalError)?;
+            std::hint::black_box(*word);
+        }
+        Ok(())
+    }
+
+    // There is no cache, so only the address is checked
+    fn flush_line(&mut self, addr: usize) -> Result<(), Error> {
+        addr.checked_sub(self.base)
+            .filter(|&offset| offset < self.data.len())
+            .ok_or(Error::InternalError)?;
+        Ok(())
+    }
+}
+
+impl SnapshotBackend for SimMemory {
+    fn read_snapshot(
+        &mut self,
+        addr: usize,
+        buf: &mut [u8],
+    ) -> Result<(), Error> {
+        self.read_line(addr)?;
+        let offset = addr - self.base;
+        let line = self
+            .data
+            .get(offset..offset + buf.len())
+            .ok_or(Error::InternalError)?;
+        buf.copy_from_slice(line);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scrub() {
+        let mem = SimMemory::new(0x10000, 64 * 1024, 64).unwrap();
+        let extent = mem.extent();
+        let mut scrubber =
+            LineScrubber::new(mem, &[extent], 64, 6).unwrap();
+
+        // One and a half passes, in 4 KiB chunks
+        let mut chunks = 24;
+        let summary = scrubber
+            .run(|_| match chunks {
+                0 => 0,
+                _ => {
+                    chunks -= 1;
+                    4096
+                }
+            })
+            .unwrap();
+        assert_eq!(summary.bytes_scrubbed, 96 * 1024);
+        assert_eq!(summary.passes, 1);
+
+        // Lines with the lower half of the cache indices were read twice
+        let mem = scrubber.backend();
+        for i in 0..1024 {
+            let addr = 0x10000 + i * 64;
+            let expected = match i % 64 < 32 {
+                true => 2,
+                false => 1,
+            };
+            assert_eq!(mem.reads_at(addr), Some(expected));
+        }
+
+        assert_eq!(scrubber.scrub(100), Err(Error::UnalignedSize));
+    }
+
+    #[test]
+    fn test_reads_per_line() {
+        let mem = SimMemory::new(0x10000, 4096, 64).unwrap();
+        let extent = mem.extent();
+        let mut scrubber =
+            LineScrubber::new(mem, &[extent], 64, 6).unwrap();
+        assert_eq!(
+            scrubber.set_reads_per_line(3),
+            Err(Error::UnalignedValue)
+        );
+        assert_eq!(
+            scrubber.set_reads_per_line(16),
+            Err(Error::UnalignedValue)
+        );
+        scrubber.set_reads_per_line(8).unwrap();
+
+        // Each line is still counted as read once
+        scrubber.scrub(4096).unwrap();
+        assert!(scrubber.backend().reads().iter().all(|&r| r == 1));
+    }
+
+    #[test]
+    fn test_bounds() {
+        let mut mem = SimMemory::new(4096, 4096, 64).unwrap();
+        assert_eq!(mem.extent(), (4096, 8191));
+        assert_eq!(mem.read_line(8192), Err(Error::InternalError));
+        assert_eq!(mem.read_line(0), Err(Error::InternalError));
+        assert_eq!(mem.read_line(8191), Ok(()));
+        assert_eq!(mem.reads_at(8128), Some(1));
+
+        assert_eq!(
+            SimMemory::new(usize::MAX - 63, 128, 64).unwrap_err(),
+            Error::AddressOverflow
+        );
+        assert_eq!(
+            SimMemory::new(32, 64, 64).unwrap_err(),
+            Error::UnalignedStart
+        );
+    }
+}
@@ -0,0 +1,533 @@
+// Scheduling by staleness. A pass scrubs the areas in a fixed order, so
+// after the scrubber has been paused, an area has been added part way
+// through or the rate has changed, some memory is far staler than the rest
+// and stays that way until the pass comes round to it. A
+// StalenessScheduler instead splits the scrub areas into segments and
+// always scrubs the segment that has gone longest without being scrubbed
+// for its deadline, so staleness evens itself out again without any
+// rebalancing by hand.
+//
+// Urgency is the time since a segment was last scrubbed divided by its
+// deadline, so a segment with a deadline of a minute that was scrubbed
+// thirty seconds ago is as urgent as one with a deadline of an hour that
+// was scrubbed half an hour ago. Urgency is then weighted by one more than
+// the priority of the segment's area, such as one seeded from the error
+// history with ErrorHistory::seed(), so an area with priority one comes
+// round twice as often as one with none. A segment that has never been
+// scrubbed is more urgent than any that has, and of those the segments of
+// the areas with the highest priority go first.
+//
+// All the segments of an area share its deadline and priority, so the
+// most urgent of them is the one scrubbed longest ago. Each area therefore
+// keeps its segments in a priority queue ordered by when they were last
+// scrubbed, and picking the next segment only compares the heads of the
+// queues. Segments are scrubbed with LineScrubber::scrub_segment(), so
+// they count in the pass statistics, coverage and history as if scrubbed
+// by a pass, and the scheduler keeps its own record of when each segment
+// was scrubbed.
+
+use std::cmp::Reverse;
+use std::collections::{BinaryHeap, HashMap};
+use std::time::{Duration, Instant};
+
+use crate::backend::*;
+use crate::base::*;
+use crate::stats::*;
+
+/// A segment of a scrub area scheduled by staleness
+///
+/// * `area` - Index of the scrub area the segment is in
+///
+/// * `start` - First address in the segment
+///
+/// * `end` - Last address in the segment
+///
+/// * `deadline` - Longest the segment should go without being scrubbed
+///
+/// * `last_scrubbed` - When the segment was last scrubbed, if it has been
+///
+/// * `priority` - Priority of the segment's area in the scrubber's
+///   statistics
+#[derive(Clone, Debug, PartialEq)]
+pub struct StaleSegment {
+    pub area: usize,
+    pub start: usize,
+    pub end: usize,
+    pub deadline: Duration,
+    pub last_scrubbed: Option<Instant>,
+    pub priority: u32,
+}
+
+impl StaleSegment {
+    /// Returns the time since the segment was last scrubbed divided by its
+    /// deadline and weighted by one more than its priority, or infinity if
+    /// it has never been scrubbed
+    ///
+    /// # Arguments:
+    /// * `now` - The current time
+    pub fn urgency(&self, now: Instant) -> f64 {
+        match self.last_scrubbed {
+            Some(t) => {
+                now.saturating_duration_since(t).as_secs_f64()
+                    / self.deadline.as_secs_f64()
+                    * (self.priority as f64 + 1.0)
+            }
+            None => f64::INFINITY,
+        }
+    }
+
+    /// Returns whether the segment has gone longer than its deadline
+    /// without being scrubbed
+    pub fn is_overdue(&self, now: Instant) -> bool {
+        match self.last_scrubbed {
+            Some(t) => now.saturating_duration_since(t) > self.deadline,
+            None => true,
+        }
+    }
+}
+
+// The index of each segment of an area, with when it was last scrubbed,
+// as a queue of the least recently scrubbed first
+type SegmentQueue = BinaryHeap<Reverse<(Option<Instant>, usize)>>;
+
+/// Scrubs the segments of a LineScrubber's areas most stale first, as an
+/// alternative to scrubbing them pass by pass with LineScrubber::scrub().
+/// The segments follow the scrub areas as they are added and removed.
+///
+/// * `segment_size` - Number of bytes in each segment, the last in an area
+///   being shorter if the area isn't a multiple of it
+///
+/// * `deadline` - Deadline of areas without one of their own
+///
+/// * `deadlines` - Extent of each area with a deadline of its own, with
+///   the deadline
+///
+/// * `extents` - Extents of the scrub areas the segments were made from
+///
+/// * `segments` - The segments of all of the scrub areas, in order
+///
+/// * `queues` - The index of each segment of each area, by when it was
+///   last scrubbed, least recently first
+#[derive(Clone, Debug)]
+pub struct StalenessScheduler {
+    segment_size: usize,
+    deadline: Duration,
+    deadlines: Vec<((usize, usize), Duration)>,
+    extents: Vec<(usize, usize)>,
+    segments: Vec<StaleSegment>,
+    queues: Vec<SegmentQueue>,
+}
+
+impl StalenessScheduler {
+    /// Create a scheduler
+    ///
+    /// # Arguments:
+    /// * `segment_size` - Number of bytes in each segment, a multiple of
+    ///   the cache line size
+    ///
+    /// * `deadline` - Longest any memory should go without being scrubbed,
+    ///   unless its area is given a deadline of its own
+    ///
+    /// # Returns:
+    /// Ok(StalenessScheduler) on success, otherwise Err(Error::ZeroSize)
+    /// if the segment size or deadline is zero
+    pub fn new(
+        segment_size: usize,
+        deadline: Duration,
+    ) -> Result<StalenessScheduler, Error> {
+        if segment_size == 0 || deadline.is_zero() {
+            return Err(Error::ZeroSize);
+        }
+        Ok(StalenessScheduler {
+            segment_size,
+            deadline,
+            deadlines: Vec::new(),
+            extents: Vec::new(),
+            segments: Vec::new(),
+            queues: Vec::new(),
+        })
+    }
+
+    /// Set the deadline of a scrub area, which it keeps for as long as it
+    /// is a scrub area, even as the areas before it are removed
+    ///
+    /// # Arguments:
+    /// * `scrubber` - The scrubber
+    ///
+    /// * `area` - Index of the scrub area
+    ///
+    /// * `deadline` - The deadline, or None for the default
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::NoSuchArea) if there is no
+    /// such area or Err(Error::ZeroSize) if the deadline is zero
+    pub fn set_area_deadline<B: ScrubBackend>(
+        &mut self,
+        scrubber: &LineScrubber<B>,
+        area: usize,
+        deadline: Option<Duration>,
+    ) -> Result<(), Error> {
+        let extent = match scrubber.extents().get(area) {
+            Some(&extent) => extent,
+            None => return Err(Error::NoSuchArea),
+        };
+        if deadline.is_some_and(|d| d.is_zero()) {
+            return Err(Error::ZeroSize);
+        }
+        self.deadlines.retain(|&(e, _)| e != extent);
+        if let Some(deadline) = deadline {
+            self.deadlines.push((extent, deadline));
+        }
+        self.sync(scrubber.extents());
+        let deadline = self.area_deadline(extent);
+        for &Reverse((_, i)) in self.queues[area].iter() {
+            self.segments[i].deadline = deadline;
+        }
+        Ok(())
+    }
+
+    /// Returns the segments, as of the last time they were scheduled
+    pub fn segments(&self) -> &[StaleSegment] {
+        &self.segments
+    }
+
+    /// Returns the index of the most urgent segment, if there are any.
+    /// Of segments equally urgent, the first with the highest priority is
+    /// chosen.
+    ///
+    /// # Arguments:
+    /// * `now` - The current time
+    pub fn most_urgent(&self, now: Instant) -> Option<usize> {
+        let mut best: Option<(usize, f64, u32)> = None;
+        for queue in &self.queues {
+            let Some(&Reverse((_, i))) = queue.peek() else {
+                continue;
+            };
+            let segment = &self.segments[i];
+            let urgency = segment.urgency(now);
+            if best.is_none_or(|(_, u, p)| {
+                urgency > u || (urgency == u && segment.priority > p)
+            }) {
+                best = Some((i, urgency, segment.priority));
+            }
+        }
+        best.map(|(i, _, _)| i)
+    }
+
+    /// Returns the number of segments past their deadlines
+    ///
+    /// # Arguments:
+    /// * `now` - The current time
+    pub fn overdue(&self, now: Instant) -> usize {
+        self.segments.iter().filter(|s| s.is_overdue(now)).count()
+    }
+
+    /// Scrub the most urgent segment
+    ///
+    /// # Arguments:
+    /// * `scrubber` - The scrubber whose areas are scheduled
+    ///
+    /// # Returns:
+    /// Ok(Some(segment)) with the index of the segment scrubbed, Ok(None)
+    /// if there are no segments, otherwise Err(Error) as for
+    /// LineScrubber::scrub_segment()
+    pub fn scrub_next<B: ScrubBackend>(
+        &mut self,
+        scrubber: &mut LineScrubber<B>,
+    ) -> Result<Option<usize>, Error> {
+        self.sync(scrubber.extents());
+        self.update_priorities(scrubber.stats());
+        let i = match self.most_urgent(scrubber.clock().now()) {
+            Some(i) => i,
+            None => return Ok(None),
+        };
+        self.scrub_segment(scrubber, i)?;
+        Ok(Some(i))
+    }
+
+    /// Scrub the most urgent segments until at least a number of bytes
+    /// have been read. Lines skipped, as when excluded, are not counted,
+    /// and scrubbing stops early if every segment has been scrubbed
+    /// without reading anything.
+    ///
+    /// # Arguments:
+    /// * `scrubber` - The scrubber whose areas are scheduled
+    ///
+    /// * `bytes` - Number of bytes to read
+    ///
+    /// # Returns:
+    /// Ok(bytes) with the number of bytes read, otherwise Err(Error) as
+    /// for scrub_next()
+    pub fn scrub<B: ScrubBackend>(
+        &mut self,
+        scrubber: &mut LineScrubber<B>,
+        bytes: usize,
+    ) -> Result<usize, Error> {
+        let mut covered = 0;
+        let mut idle = 0;
+        while covered < bytes {
+            self.sync(scrubber.extents());
+            self.update_priorities(scrubber.stats());
+            let Some(i) = self.most_urgent(scrubber.clock().now()) else {
+                break;
+            };
+            match self.scrub_segment(scrubber, i)? {
+                0 => idle += 1,
+                read => {
+                    covered += read;
+                    idle = 0;
+                }
+            }
+            if idle >= self.segments.len() {
+                break;
+            }
+        }
+        Ok(covered)
+    }
+
+    // Scrub a segment, which must be at the head of its area's queue, and
+    // put it back in the queue as scrubbed now. Returns the number of
+    // bytes read.
+    fn scrub_segment<B: ScrubBackend>(
+        &mut self,
+        scrubber: &mut LineScrubber<B>,
+        i: usize,
+    ) -> Result<usize, Error> {
+        let segment = &self.segments[i];
+        let (area, start, end) =
+            (segment.area, segment.start, segment.end);
+        let result = scrubber.scrub_segment(start, end);
+        let now = scrubber.clock().now();
+        self.segments[i].last_scrubbed = Some(now);
+        self.queues[area].pop();
+        self.queues[area].push(Reverse((Some(now), i)));
+        result
+    }
+
+    // Split the scrub areas into segments again if they have changed,
+    // keeping the times segments that are still there were scrubbed
+    fn sync(&mut self, extents: &[(usize, usize)]) {
+        if self.extents == extents {
+            return;
+        }
+        self.deadlines.retain(|(e, _)| extents.contains(e));
+        let old: HashMap<(usize, usize), Option<Instant>> = self
+            .segments
+            .drain(..)
+            .map(|s| ((s.start, s.end), s.last_scrubbed))
+            .collect();
+        self.queues.clear();
+        for (area, &(start, end)) in extents.iter().enumerate() {
+            let deadline = self.area_deadline((start, end));
+            let mut queue = BinaryHeap::new();
+            let mut seg_start = start;
+            loop {
+                let seg_end = seg_start
+                    .saturating_add(self.segment_size - 1)
+                    .min(end);
+                let last_scrubbed =
+                    old.get(&(seg_start, seg_end)).copied().flatten();
+                queue.push(Reverse((last_scrubbed, self.segments.len())));
+                self.segments.push(StaleSegment {
+                    area,
+                    start: seg_start,
+                    end: seg_end,
+                    deadline,
+                    last_scrubbed,
+                    priority: 0,
+                });
+                if seg_end == end {
+                    break;
+                }
+                seg_start = seg_end + 1;
+            }
+            self.queues.push(queue);
+        }
+        self.extents = extents.to_vec();
+    }
+
+    // Take the priority of each segment from its area's statistics, which
+    // may have changed since the last segment was scrubbed
+    fn update_priorities(&mut self, stats: &ScrubStats) {
+        for (area, queue) in self.queues.iter().enumerate() {
+            let priority = stats.areas.get(area).map_or(0, |a| a.priority);
+            let Some(&Reverse((_, i))) = queue.peek() else {
+                continue;
+            };
+            if self.segments[i].priority == priority {
+                continue;
+            }
+            for &Reverse((_, i)) in queue.iter() {
+                self.segments[i].priority = priority;
+            }
+        }
+    }
+
+    // Returns the deadline of the area with an extent
+    fn area_deadline(&self, extent: (usize, usize)) -> Duration {
+        self.deadlines
+            .iter()
+            .find(|&&(e, _)| e == extent)
+            .map_or(self.deadline, |&(_, d)| d)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::clock::*;
+    use crate::coverage::*;
+    use crate::testutil::*;
+    use std::time::SystemTime;
+
+    // A scrubber with two areas whose reads are recorded
+    fn scrubber(
+        reads: &Recorder,
+        clock: &VirtualClock,
+    ) -> LineScrubber<Recorder> {
+        let mut scrubber = LineScrubber::new(
+            reads.clone(),
+            &[(0x1000, 0x10ff), (0x2000, 0x207f)],
+            64,
+            0,
+        )
+        .unwrap();
+        scrubber.set_clock(Box::new(clock.clone()));
+        scrubber
+    }
+
+    #[test]
+    fn test_order() {
+        let reads = Recorder::default();
+        let clock = VirtualClock::at(Instant::now(), SystemTime::now());
+        let mut scrubber = scrubber(&reads, &clock);
+        let mut sched =
+            StalenessScheduler::new(128, Duration::from_secs(60)).unwrap();
+        assert!(
+            StalenessScheduler::new(0, Duration::from_secs(1)).is_err()
+        );
+
+        // Everything is scrubbed once, in order, to begin with
+        for _ in 0..3 {
+            sched.scrub_next(&mut scrubber).unwrap();
+            clock.advance(Duration::from_secs(10));
+        }
+        assert_eq!(
+            reads.reads(),
+            [0x1000, 0x1040, 0x1080, 0x10c0, 0x2000, 0x2040]
+        );
+        assert_eq!(sched.segments().len(), 3);
+        assert_eq!(sched.overdue(clock.now()), 0);
+
+        // An area with a shorter deadline comes round sooner
+        sched
+            .set_area_deadline(&scrubber, 1, Some(Duration::from_secs(15)))
+            .unwrap();
+        assert_eq!(sched.most_urgent(clock.now()), Some(2));
+        assert_eq!(sched.overdue(clock.now()), 0);
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(sched.overdue(clock.now()), 1);
+        reads.clear();
+        assert_eq!(sched.scrub(&mut scrubber, 128).unwrap(), 128);
+        assert_eq!(reads.reads(), [0x2000, 0x2040]);
+        assert_eq!(sched.most_urgent(clock.now()), Some(0));
+        assert_eq!(
+            sched.set_area_deadline(&scrubber, 5, None),
+            Err(Error::NoSuchArea)
+        );
+    }
+
+    #[test]
+    fn test_hot_add() {
+        let reads = Recorder::default();
+        let clock = VirtualClock::at(Instant::now(), SystemTime::now());
+        let mut scrubber = scrubber(&reads, &clock);
+        let mut sched =
+            StalenessScheduler::new(256, Duration::from_secs(60)).unwrap();
+        sched
+            .set_area_deadline(&scrubber, 1, Some(Duration::from_secs(30)))
+            .unwrap();
+        sched.scrub(&mut scrubber, 0x180).unwrap();
+        clock.advance(Duration::from_secs(5));
+
+        // A new area is scrubbed before anything else, and the areas
+        // already there keep their times and deadlines
+        scrubber.add_area(0x3000, 0x303f).unwrap();
+        scrubber.remove_area(0).unwrap();
+        reads.clear();
+        assert_eq!(sched.scrub_next(&mut scrubber).unwrap(), Some(1));
+        assert_eq!(reads.reads(), [0x3000]);
+        let segments = sched.segments();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].area, 0);
+        assert_eq!(segments[0].deadline, Duration::from_secs(30));
+        assert!(segments[0].last_scrubbed.is_some());
+        assert_eq!(segments[1].deadline, Duration::from_secs(60));
+    }
+
+    #[test]
+    fn test_priority() {
+        let reads = Recorder::default();
+        let clock = VirtualClock::at(Instant::now(), SystemTime::now());
+        let mut scrubber = scrubber(&reads, &clock);
+        let mut sched =
+            StalenessScheduler::new(128, Duration::from_secs(60)).unwrap();
+
+        // The second area has a history of errors, so it is visited first
+        let history = ErrorHistory {
+            areas: vec![
+                AreaErrorHistory::default(),
+                AreaErrorHistory {
+                    errors_corrected: 3,
+                    errors_uncorrected: 0,
+                    error_rate: 1.5,
+                },
+            ],
+            unmatched: 0,
+        };
+        history.seed(scrubber.stats_mut());
+        assert_eq!(sched.scrub_next(&mut scrubber).unwrap(), Some(2));
+        assert_eq!(reads.reads(), [0x2000, 0x2040]);
+        assert_eq!(sched.segments()[2].priority, 1);
+
+        // And comes round again before segments that have waited longer
+        sched.scrub(&mut scrubber, 256).unwrap();
+        clock.advance(Duration::from_secs(10));
+        assert_eq!(sched.scrub_next(&mut scrubber).unwrap(), Some(2));
+        clock.advance(Duration::from_secs(15));
+        assert_eq!(sched.most_urgent(clock.now()), Some(2));
+        assert!(
+            sched.segments()[0].urgency(clock.now())
+                < sched.segments()[2].urgency(clock.now())
+        );
+        assert_eq!(sched.overdue(clock.now()), 0);
+    }
+
+    #[test]
+    fn test_pass_path() {
+        // Four lines, two to each cache index, one of them excluded
+        let reads = Recorder::default();
+        let mut scrubber =
+            LineScrubber::new(reads.clone(), &[(0x1000, 0x10ff)], 64, 1)
+                .unwrap();
+        scrubber.exclude(0x1040, 0x107f);
+        scrubber.set_coverage_tracking(2).unwrap();
+        let mut sched =
+            StalenessScheduler::new(256, Duration::from_secs(60)).unwrap();
+
+        // The segment is read in cache index order, the excluded line not
+        // counting as read, and completes a pass
+        assert_eq!(sched.scrub(&mut scrubber, 128).unwrap(), 192);
+        assert_eq!(reads.reads(), [0x1000, 0x1080, 0x10c0]);
+        assert_eq!(scrubber.stats().passes, 1);
+        let history = scrubber.coverage_history();
+        assert_eq!(history.len(), 1);
+        assert!(history[0].complete);
+        assert_eq!(history[0].bytes(Some(SkipReason::Excluded)), 64);
+
+        // Nothing left to read
+        scrubber.exclude(0x1000, 0x10ff);
+        assert_eq!(sched.scrub(&mut scrubber, 128).unwrap(), 0);
+        assert_eq!(scrubber.stats().passes, 2);
+    }
+}
@@ -0,0 +1,370 @@
+// Hot-standby scrubbing. Where one scrubber failing must not leave memory
+// unscrubbed, a second, passive instance can stand by to take over. The
+// active scrubber sends its state through a CheckpointPublisher every so
+// often, and the Standby keeps the latest state it has received. If no
+// state arrives for longer than its timeout, the active scrubber is taken
+// to have failed and take_over() restores the latest state into the
+// standby's own scrubber, which carries on from the same place in the
+// same pass.
+//
+// The state sent is a ScrubState, so when each area was last scrubbed is
+// carried across as wall-clock time and the staleness of every area is
+// the same after the failover as it was before. Lines scrubbed after the
+// last state was sent are scrubbed again rather than skipped, so no line
+// goes longer between scrubs than it would have without the failure,
+// apart from the time taken to notice it. That time is at most the
+// timeout, which should be a few times the interval at which state is
+// sent, and should be allowed for in the deadlines of the areas.
+//
+// The channel the state goes through is up to the user, through the
+// CheckpointSender and CheckpointReceiver traits. Both are implemented
+// for std::sync::mpsc for standby scrubbers in the same process, and
+// ScrubState::to_text() and ScrubState::parse() give a form to send
+// between processes or machines.
+
+use std::sync::mpsc::{Receiver, Sender, SyncSender};
+use std::time::{Duration, Instant};
+
+use crate::backend::*;
+use crate::base::*;
+use crate::persist::*;
+
+/// Sends the state of the active scrubber to a standby
+pub trait CheckpointSender {
+    /// Send a state
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::SendFailed) if the standby
+    /// can't be reached
+    fn send(&mut self, state: &ScrubState) -> Result<(), Error>;
+}
+
+/// Receives the state of the active scrubber in a standby
+pub trait CheckpointReceiver {
+    /// Returns the next state received, if one is waiting. This must not
+    /// block.
+    fn try_recv(&mut self) -> Option<ScrubState>;
+}
+
+impl CheckpointSender for Sender<ScrubState> {
+    fn send(&mut self, state: &ScrubState) -> Result<(), Error> {
+        Sender::send(self, state.clone()).map_err(|_| Error::SendFailed)
+    }
+}
+
+impl CheckpointSender for SyncSender<ScrubState> {
+    fn send(&mut self, state: &ScrubState) -> Result<(), Error> {
+        SyncSender::send(self, state.clone())
+            .map_err(|_| Error::SendFailed)
+    }
+}
+
+impl CheckpointReceiver for Receiver<ScrubState> {
+    fn try_recv(&mut self) -> Option<ScrubState> {
+        Receiver::try_recv(self).ok()
+    }
+}
+
+/// Sends the state of the active scrubber to a standby on a periodic
+/// cadence
+///
+/// * `sender` - Channel to the standby
+///
+/// * `interval` - Minimum time between states sent
+///
+/// * `last` - Time the last state was sent, if any
+pub struct CheckpointPublisher {
+    sender: Box<dyn CheckpointSender + Send>,
+    interval: Duration,
+    last: Option<Instant>,
+}
+
+impl CheckpointPublisher {
+    /// Create a CheckpointPublisher
+    ///
+    /// # Arguments:
+    /// * `sender` - Channel to the standby
+    ///
+    /// * `interval` - Minimum time between states sent
+    pub fn new(
+        sender: Box<dyn CheckpointSender + Send>,
+        interval: Duration,
+    ) -> CheckpointPublisher {
+        CheckpointPublisher {
+            sender,
+            interval,
+            last: None,
+        }
+    }
+
+    /// Returns the minimum time between states sent
+    pub fn interval(&self) -> Duration {
+        self.interval
+    }
+
+    /// Send the state of a scrubber now
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error) if sending failed
+    pub fn publish<B: ScrubBackend>(
+        &mut self,
+        scrubber: &LineScrubber<B>,
+        now: Instant,
+    ) -> Result<(), Error> {
+        self.sender.send(&ScrubState::capture(scrubber))?;
+        self.last = Some(now);
+        Ok(())
+    }
+
+    /// Send the state of a scrubber if the interval has passed since the
+    /// last was sent
+    ///
+    /// # Returns:
+    /// Ok(true) if the state was sent, otherwise Ok(false) or Err(Error)
+    /// if sending failed
+    pub fn maybe_publish<B: ScrubBackend>(
+        &mut self,
+        scrubber: &LineScrubber<B>,
+        now: Instant,
+    ) -> Result<bool, Error> {
+        if self
+            .last
+            .is_some_and(|last| now.duration_since(last) < self.interval)
+        {
+            return Ok(false);
+        }
+        self.publish(scrubber, now)?;
+        Ok(true)
+    }
+}
+
+/// A passive scrubber instance, keeping the latest state of the active
+/// one so that it can take over
+///
+/// * `receiver` - Channel from the active scrubber
+///
+/// * `timeout` - Time without a state after which the active scrubber is
+///   taken to have failed
+///
+/// * `latest` - Latest state received, if any
+///
+/// * `heard` - Time the latest state was received, or the standby started
+///
+/// * `received` - Number of states received
+pub struct Standby {
+    receiver: Box<dyn CheckpointReceiver + Send>,
+    timeout: Duration,
+    latest: Option<ScrubState>,
+    heard: Instant,
+    received: u64,
+}
+
+impl Standby {
+    /// Create a Standby
+    ///
+    /// # Arguments:
+    /// * `receiver` - Channel from the active scrubber
+    ///
+    /// * `timeout` - Time without a state after which the active scrubber
+    ///   is taken to have failed, a few times the interval at which states
+    ///   are sent
+    ///
+    /// * `now` - Current time. The timeout runs from now until the first
+    ///   state is received.
+    ///
+    /// # Returns:
+    /// Ok(Standby) on success, otherwise Err(Error::ZeroSize) if the
+    /// timeout is zero
+    pub fn new(
+        receiver: Box<dyn CheckpointReceiver + Send>,
+        timeout: Duration,
+        now: Instant,
+    ) -> Result<Standby, Error> {
+        if timeout.is_zero() {
+            return Err(Error::ZeroSize);
+        }
+        Ok(Standby {
+            receiver,
+            timeout,
+            latest: None,
+            heard: now,
+            received: 0,
+        })
+    }
+
+    /// Take in any states that have arrived, keeping the latest
+    ///
+    /// # Returns:
+    /// The number of states that arrived
+    pub fn poll(&mut self, now: Instant) -> usize {
+        let arrived = self.drain();
+        if arrived != 0 {
+            self.heard = now;
+        }
+        arrived
+    }
+
+    /// Returns the latest state received, if any
+    pub fn latest(&self) -> Option<&ScrubState> {
+        self.latest.as_ref()
+    }
+
+    /// Returns the number of states received
+    pub fn received(&self) -> u64 {
+        self.received
+    }
+
+    /// Returns the time since the latest state was received, or since the
+    /// standby started if none has been
+    pub fn silence(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.heard)
+    }
+
+    /// Returns whether the active scrubber is taken to have failed, after
+    /// taking in any states that have arrived
+    pub fn active_failed(&mut self, now: Instant) -> bool {
+        self.poll(now);
+        self.silence(now) > self.timeout
+    }
+
+    /// Take over from the active scrubber, restoring the latest state into
+    /// the scrubber of the standby. The position in the pass is restored
+    /// if the scrub areas are the same as those of the active scrubber.
+    ///
+    /// # Arguments:
+    /// * `scrubber` - Scrubber of the standby, over the same memory as
+    ///   the active one
+    ///
+    /// # Returns:
+    /// Ok(true) if a state was restored, Ok(false) if none was ever
+    /// received, so the scrubber starts from the beginning, otherwise
+    /// Err(Error) if the state could not be restored
+    pub fn take_over<B: ScrubBackend>(
+        &mut self,
+        scrubber: &mut LineScrubber<B>,
+    ) -> Result<bool, Error> {
+        self.drain();
+        match &self.latest {
+            Some(state) => {
+                state.restore(scrubber)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    // Take in any states that have arrived, returning how many did
+    fn drain(&mut self) -> usize {
+        let mut arrived = 0;
+        while let Some(state) = self.receiver.try_recv() {
+            self.latest = Some(state);
+            arrived += 1;
+        }
+        self.received += arrived as u64;
+        arrived
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::*;
+    use std::sync::mpsc;
+
+    fn scrubber() -> LineScrubber<SimMemory> {
+        let mem = SimMemory::new(0, 16384, 64).unwrap();
+        LineScrubber::new(mem, &[(0, 4095), (8192, 12287)], 64, 4).unwrap()
+    }
+
+    #[test]
+    fn test_publish() {
+        let start = Instant::now();
+        let (sender, receiver) = mpsc::channel();
+        let mut publisher = CheckpointPublisher::new(
+            Box::new(sender),
+            Duration::from_secs(1),
+        );
+        let mut standby = Standby::new(
+            Box::new(receiver),
+            Duration::from_secs(3),
+            start,
+        )
+        .unwrap();
+        let active = scrubber();
+
+        assert!(publisher.maybe_publish(&active, start).unwrap());
+        let half = start + Duration::from_millis(500);
+        assert!(!publisher.maybe_publish(&active, half).unwrap());
+        let later = start + Duration::from_secs(1);
+        assert!(publisher.maybe_publish(&active, later).unwrap());
+        assert_eq!(standby.poll(later), 2);
+        assert_eq!(standby.received(), 2);
+        assert!(standby.latest().is_some());
+
+        // Silence longer than the timeout means the active one failed
+        assert!(!standby.active_failed(later + Duration::from_secs(3)));
+        assert!(standby.active_failed(later + Duration::from_secs(4)));
+
+        // Sending fails once the standby is gone
+        drop(standby);
+        assert_eq!(
+            publisher.publish(&active, later),
+            Err(Error::SendFailed)
+        );
+        assert!(Standby::new(
+            Box::new(mpsc::channel().1),
+            Duration::ZERO,
+            start
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn test_take_over() {
+        let start = Instant::now();
+        let (sender, receiver) = mpsc::channel();
+        let mut publisher = CheckpointPublisher::new(
+            Box::new(sender),
+            Duration::from_secs(1),
+        );
+        let mut standby = Standby::new(
+            Box::new(receiver),
+            Duration::from_secs(3),
+            start,
+        )
+        .unwrap();
+
+        // The active scrubber completes a pass and part of the next, then
+        // scrubs more after its last state is sent, and fails
+        let mut active = scrubber();
+        active.scrub(8192 + 1024).unwrap();
+        active.stats_mut().record_error(1, true, start);
+        publisher.publish(&active, start).unwrap();
+        active.scrub(2048).unwrap();
+        drop(active);
+
+        let mut backup = scrubber();
+        assert!(standby.take_over(&mut backup).unwrap());
+        let stats = backup.stats();
+        assert_eq!(stats.passes, 1);
+        assert_eq!(stats.pass_offset, 1024);
+        assert_eq!(stats.areas[1].errors_corrected, 1);
+        assert!(stats.areas[0].last_scrubbed.is_some());
+
+        // The rest of the pass, from the last state sent, completes it
+        backup.scrub(8192 - 1024).unwrap();
+        assert_eq!(backup.stats().passes, 2);
+
+        // With no state the standby starts from the beginning
+        let (_sender, receiver) = mpsc::channel();
+        let mut standby = Standby::new(
+            Box::new(receiver),
+            Duration::from_secs(3),
+            start,
+        )
+        .unwrap();
+        let mut fresh = scrubber();
+        assert!(!standby.take_over(&mut fresh).unwrap());
+        assert_eq!(fresh.stats().passes, 0);
+    }
+}
@@ -0,0 +1,892 @@
+// Statistics kept while scrubbing. The cache-aware ordering touches every
+// scrub area throughout a pass, so an area is only known to be completely
+// scrubbed when the pass it is part of completes. Per-area timestamps are
+// therefore updated at pass granularity.
+//
+// Each pass is identified by an epoch number, which increases by one as
+// each pass completes. The record of the last completed pass gives its
+// epoch and when it started and completed, which is the evidence that all
+// memory was covered between those times.
+//
+// An Instant has no meaning outside of the process that created it, so with
+// the serde feature times are serialized as the number of seconds before the
+// moment of serialization and turned back into Instants relative to the
+// moment of deserialization.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, Instant};
+
+use crate::histogram::*;
+
+/// Time over which the error rate of a scrub area decays, so that errors
+/// this long ago count for about a third as much as errors now
+pub const ERROR_RATE_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Statistics for a single scrub area
+///
+/// * `label` - Optional human-readable name for the area
+///
+/// * `size` - Number of bytes in the area
+///
+/// * `last_scrubbed` - Time at which the last pass covering this area
+///   completed, or None if no pass has completed yet
+///
+/// * `last_epoch` - Epoch of the last pass covering this area, or None if
+///   no pass has completed yet
+///
+/// * `errors_corrected` - Number of corrected errors attributed to the area
+///
+/// * `errors_uncorrected` - Number of uncorrected errors attributed to the
+///   area
+///
+/// * `error_rate` - Estimated corrected errors per hour, as of
+///   rate_updated. Each corrected error recorded adds to it and it decays
+///   exponentially over ERROR_RATE_WINDOW, so it follows recent errors.
+///
+/// * `rate_updated` - Time at which error_rate was last updated, or None
+///   if it was only set from history, in which case it doesn't decay
+///   until an error is recorded
+///
+/// * `priority` - Relative importance of scrubbing the area. Higher values
+///   are more important. A LineScrubber scrubs the area at one more than
+///   its priority times the rate of the pass, and a StalenessScheduler
+///   comes round to it as much more often.
+///
+/// * `excluded_lines` - Number of cache lines in the area left out of
+///   scrubbing, such as those quarantined or retired
+///
+/// * `declared_lines` - Number of cache lines in the area in ranges
+///   declared never to be scrubbed
+///
+/// * `unreadable_lines` - Number of times a cache line in the area was
+///   skipped because the validator rejected it
+///
+/// * `unreadable_warnings` - Number of warnings given about the area's
+///   unreadable lines
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AreaStats {
+    pub label: Option<String>,
+    pub size: usize,
+    #[cfg_attr(feature = "serde", serde(with = "serde_instant::option"))]
+    pub last_scrubbed: Option<Instant>,
+    pub last_epoch: Option<u64>,
+    pub errors_corrected: u64,
+    pub errors_uncorrected: u64,
+    pub error_rate: f64,
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, with = "serde_instant::option")
+    )]
+    pub rate_updated: Option<Instant>,
+    pub priority: u32,
+    pub excluded_lines: u64,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub declared_lines: u64,
+    pub unreadable_lines: u64,
+    pub unreadable_warnings: u64,
+}
+
+impl AreaStats {
+    pub fn new(size: usize) -> AreaStats {
+        AreaStats {
+            label: None,
+            size,
+            last_scrubbed: None,
+            last_epoch: None,
+            errors_corrected: 0,
+            errors_uncorrected: 0,
+            error_rate: 0.0,
+            rate_updated: None,
+            priority: 0,
+            excluded_lines: 0,
+            declared_lines: 0,
+            unreadable_lines: 0,
+            unreadable_warnings: 0,
+        }
+    }
+
+    /// Returns the number of cache lines in the area left out of
+    /// scrubbing, whether excluded or declared
+    pub fn unscrubbed_lines(&self) -> u64 {
+        self.excluded_lines + self.declared_lines
+    }
+
+    /// Returns the time since this area was last completely scrubbed
+    ///
+    /// # Arguments:
+    /// * `now` - Time to measure staleness against
+    ///
+    /// # Returns:
+    /// Some(Duration) once a pass has completed, otherwise None
+    pub fn staleness(&self, now: Instant) -> Option<Duration> {
+        self.last_scrubbed.map(|t| now.saturating_duration_since(t))
+    }
+
+    /// Returns the estimated corrected errors per hour, decayed to the
+    /// given time
+    pub fn error_rate_at(&self, now: Instant) -> f64 {
+        match self.rate_updated {
+            None => self.error_rate,
+            Some(updated) => {
+                let age = now.saturating_duration_since(updated);
+                self.error_rate
+                    * (-age.as_secs_f64()
+                        / ERROR_RATE_WINDOW.as_secs_f64())
+                    .exp()
+            }
+        }
+    }
+
+    // Add corrected errors to the decayed error rate. Each error adds the
+    // rate that one error per window would give, so a steady rate of
+    // errors is estimated as that rate.
+    fn record_corrected(&mut self, count: u64, now: Instant) {
+        let hours = ERROR_RATE_WINDOW.as_secs_f64() / 3600.0;
+        self.error_rate = self.error_rate_at(now) + count as f64 / hours;
+        self.rate_updated = Some(now);
+    }
+}
+
+/// A completed pass over all scrub areas
+///
+/// * `epoch` - Epoch of the pass
+///
+/// * `started` - Time at which the pass started
+///
+/// * `completed` - Time at which the pass completed
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct PassRecord {
+    pub epoch: u64,
+    #[cfg_attr(feature = "serde", serde(with = "serde_instant"))]
+    pub started: Instant,
+    #[cfg_attr(feature = "serde", serde(with = "serde_instant"))]
+    pub completed: Instant,
+}
+
+/// Energy used by scrubbing, as measured by an energy counter while
+/// chunks are scrubbed
+///
+/// * `joules` - Total energy used
+///
+/// * `bytes` - Number of bytes scrubbed while the energy was measured
+///
+/// * `pass_joules` - Energy used so far in the current pass
+///
+/// * `last_pass_joules` - Energy used by the last complete pass, if one
+///   has completed while the energy was measured
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct EnergyStats {
+    pub joules: f64,
+    pub bytes: u64,
+    pub pass_joules: f64,
+    pub last_pass_joules: Option<f64>,
+}
+
+impl EnergyStats {
+    /// Record the energy used to scrub a chunk. All of it is counted in
+    /// the pass the chunk completes, if it completes any.
+    ///
+    /// # Arguments:
+    /// * `joules` - Energy used
+    ///
+    /// * `bytes` - Number of bytes scrubbed
+    ///
+    /// * `passes` - Number of passes completed by the chunk
+    pub fn record(&mut self, joules: f64, bytes: u64, passes: u64) {
+        self.joules += joules;
+        self.bytes += bytes;
+        self.pass_joules += joules;
+        if passes > 0 {
+            self.last_pass_joules = Some(self.pass_joules / passes as f64);
+            self.pass_joules = 0.0;
+        }
+    }
+
+    /// Returns the energy used per gigabyte (10^9 bytes) scrubbed, if
+    /// anything has been scrubbed
+    pub fn joules_per_gb(&self) -> Option<f64> {
+        match self.bytes {
+            0 => None,
+            bytes => Some(self.joules * 1e9 / bytes as f64),
+        }
+    }
+}
+
+/// Statistics for a memory scrubber
+///
+/// * `areas` - Per-area statistics, in the same order as the scrub areas
+///
+/// * `bytes_scrubbed` - Total number of bytes scrubbed
+///
+/// * `chunks` - Number of times a chunk of memory was scrubbed
+///
+/// * `passes` - Number of complete passes through all scrub areas
+///
+/// * `pass_offset` - Number of bytes scrubbed in the current pass
+///
+/// * `pass_size` - Number of bytes in a complete pass
+///
+/// * `epoch` - Epoch of the current pass. The first pass is epoch 1.
+///
+/// * `last_pass` - The last completed pass, if any
+///
+/// * `scrub_time` - Total time spent scrubbing
+///
+/// * `started` - Time at which statistics collection started
+///
+/// * `pass_started` - Time at which the current pass started
+///
+/// * `pass_errors` - Number of errors, corrected or not, seen during the
+///   current pass
+///
+/// * `last_pass_errors` - Number of errors seen during the last complete
+///   pass
+///
+/// * `max_chunk_time` - Longest time taken to scrub a single chunk
+///
+/// * `chunk_latency` - Distribution of the times taken to scrub each
+///   chunk
+///
+/// * `batch_latency` - Distribution of the times taken by each batch of
+///   reads, from the start of the batch to its end, where the backend
+///   marks batches
+///
+/// * `energy` - Energy used by scrubbing, if it is being measured
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ScrubStats {
+    pub areas: Vec<AreaStats>,
+    pub bytes_scrubbed: u64,
+    pub chunks: u64,
+    pub passes: u64,
+    pub pass_offset: usize,
+    pub pass_size: usize,
+    pub epoch: u64,
+    pub last_pass: Option<PassRecord>,
+    pub scrub_time: Duration,
+    #[cfg_attr(feature = "serde", serde(with = "serde_instant"))]
+    pub started: Instant,
+    #[cfg_attr(feature = "serde", serde(with = "serde_instant"))]
+    pub pass_started: Instant,
+    pub pass_errors: u64,
+    pub last_pass_errors: u64,
+    pub max_chunk_time: Duration,
+    pub chunk_latency: LatencyHistogram,
+    pub batch_latency: LatencyHistogram,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub energy: Option<EnergyStats>,
+}
+
+impl ScrubStats {
+    /// Create statistics for areas of the given sizes
+    ///
+    /// # Arguments:
+    /// * `sizes` - Size, in bytes, of each scrub area
+    ///
+    /// * `now` - Time at which statistics collection starts
+    pub fn new(sizes: &[usize], now: Instant) -> ScrubStats {
+        let areas: Vec<AreaStats> =
+            sizes.iter().map(|size| AreaStats::new(*size)).collect();
+        let pass_size = sizes.iter().sum();
+
+        ScrubStats {
+            areas,
+            bytes_scrubbed: 0,
+            chunks: 0,
+            passes: 0,
+            pass_offset: 0,
+            pass_size,
+            epoch: 1,
+            last_pass: None,
+            scrub_time: Duration::ZERO,
+            started: now,
+            pass_started: now,
+            pass_errors: 0,
+            last_pass_errors: 0,
+            max_chunk_time: Duration::ZERO,
+            chunk_latency: LatencyHistogram::new(),
+            batch_latency: LatencyHistogram::new(),
+            energy: None,
+        }
+    }
+
+    /// Record the scrubbing of a chunk of memory
+    ///
+    /// # Arguments:
+    /// * `bytes` - Number of bytes scrubbed
+    ///
+    /// * `duration` - Time taken to scrub the chunk
+    ///
+    /// * `now` - Time at which the chunk was completed
+    pub fn record_chunk(
+        &mut self,
+        bytes: usize,
+        duration: Duration,
+        now: Instant,
+    ) {
+        self.record_chunk_skipping(bytes, 0, duration, now);
+    }
+
+    /// Record the scrubbing of a chunk of memory, some of whose lines were
+    /// skipped rather than read. The skipped bytes move the pass on but
+    /// are not counted as scrubbed.
+    ///
+    /// # Arguments:
+    /// * `bytes` - Number of bytes in the chunk, including those skipped
+    ///
+    /// * `skipped` - Number of bytes skipped
+    ///
+    /// * `duration` - Time taken to scrub the chunk
+    ///
+    /// * `now` - Time at which the chunk was completed
+    pub fn record_chunk_skipping(
+        &mut self,
+        bytes: usize,
+        skipped: usize,
+        duration: Duration,
+        now: Instant,
+    ) {
+        self.bytes_scrubbed += bytes.saturating_sub(skipped) as u64;
+        self.chunks += 1;
+        self.scrub_time += duration;
+        self.max_chunk_time = self.max_chunk_time.max(duration);
+        self.chunk_latency.record(duration);
+
+        if self.pass_size == 0 {
+            return;
+        }
+
+        self.pass_offset += bytes;
+        while self.pass_offset >= self.pass_size {
+            self.pass_offset -= self.pass_size;
+            self.passes += 1;
+            self.last_pass = Some(PassRecord {
+                epoch: self.epoch,
+                started: self.pass_started,
+                completed: now,
+            });
+            self.pass_started = now;
+            self.last_pass_errors = self.pass_errors;
+            self.pass_errors = 0;
+            for area in &mut self.areas {
+                area.last_scrubbed = Some(now);
+                area.last_epoch = Some(self.epoch);
+            }
+            self.epoch += 1;
+        }
+    }
+
+    /// Record the time taken by a batch of reads
+    ///
+    /// # Arguments:
+    /// * `duration` - Time from the start of the batch to its end
+    pub fn record_batch(&mut self, duration: Duration) {
+        self.batch_latency.record(duration);
+    }
+
+    /// Number the current pass, so that epochs continue from those of an
+    /// earlier run whose statistics were not kept. Later passes are
+    /// numbered from this one.
+    ///
+    /// # Arguments:
+    /// * `epoch` - Epoch for the current pass
+    pub fn set_epoch(&mut self, epoch: u64) {
+        self.epoch = epoch;
+    }
+
+    /// Record an error detected in a scrub area. Corrected errors are
+    /// counted in the area's error rate.
+    ///
+    /// # Arguments:
+    /// * `area` - Index of the scrub area in which the error occurred
+    ///
+    /// * `corrected` - True if the error was corrected
+    ///
+    /// * `now` - Time at which the error was detected
+    pub fn record_error(
+        &mut self,
+        area: usize,
+        corrected: bool,
+        now: Instant,
+    ) {
+        self.record_errors(area, corrected, 1, now);
+    }
+
+    /// Record a number of errors detected together in a scrub area, as
+    /// when the kernel reports several at once
+    ///
+    /// # Arguments:
+    /// * `area` - Index of the scrub area in which the errors occurred
+    ///
+    /// * `corrected` - True if the errors were corrected
+    ///
+    /// * `count` - Number of errors
+    ///
+    /// * `now` - Time at which the errors were detected
+    pub fn record_errors(
+        &mut self,
+        area: usize,
+        corrected: bool,
+        count: u32,
+        now: Instant,
+    ) {
+        let count = u64::from(count);
+        self.pass_errors += count;
+        if let Some(area) = self.areas.get_mut(area) {
+            if corrected {
+                area.errors_corrected += count;
+                area.record_corrected(count, now);
+            } else {
+                area.errors_uncorrected += count;
+            }
+        }
+    }
+
+    /// Total number of corrected errors in all areas
+    pub fn errors_corrected(&self) -> u64 {
+        self.areas.iter().map(|a| a.errors_corrected).sum()
+    }
+
+    /// Total number of uncorrected errors in all areas
+    pub fn errors_uncorrected(&self) -> u64 {
+        self.areas.iter().map(|a| a.errors_uncorrected).sum()
+    }
+
+    /// Scrub throughput, in bytes per second of scrubbing time
+    pub fn throughput(&self) -> f64 {
+        let secs = self.scrub_time.as_secs_f64();
+        if secs == 0.0 {
+            return 0.0;
+        }
+        self.bytes_scrubbed as f64 / secs
+    }
+}
+
+/// Weighting of the things that lower the health score of a scrub area.
+/// The score starts at 100 and each weight is subtracted for each unit of
+/// what it applies to, down to a score of zero.
+///
+/// * `error_rate` - Weight per corrected error per hour, using the
+///   area's decayed error rate
+///
+/// * `uncorrected` - Weight per uncorrected error
+///
+/// * `staleness` - Weight per hour since the area was last completely
+///   scrubbed
+///
+/// * `exclusions` - Weight per cache line excluded from scrubbing
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct HealthWeights {
+    pub error_rate: f64,
+    pub uncorrected: f64,
+    pub staleness: f64,
+    pub exclusions: f64,
+}
+
+impl Default for HealthWeights {
+    fn default() -> Self {
+        HealthWeights {
+            error_rate: 5.0,
+            uncorrected: 25.0,
+            staleness: 1.0,
+            exclusions: 2.0,
+        }
+    }
+}
+
+impl ScrubStats {
+    /// Returns the health score of a scrub area, from 100 for an area with
+    /// nothing wrong down to 0. An area that has never been completely
+    /// scrubbed is stale from the time statistics collection started.
+    ///
+    /// # Arguments:
+    /// * `area` - Index of the scrub area
+    ///
+    /// * `weights` - Weighting of the things that lower the score
+    ///
+    /// * `now` - Time to measure staleness against
+    ///
+    /// # Returns:
+    /// Some(score), or None if there is no such area
+    pub fn health(
+        &self,
+        area: usize,
+        weights: &HealthWeights,
+        now: Instant,
+    ) -> Option<f64> {
+        let area = self.areas.get(area)?;
+        let stale = area.staleness(now).unwrap_or_else(|| {
+            now.saturating_duration_since(self.started)
+        });
+        let penalty = area.error_rate_at(now) * weights.error_rate
+            + area.errors_uncorrected as f64 * weights.uncorrected
+            + stale.as_secs_f64() / 3600.0 * weights.staleness
+            + area.excluded_lines as f64 * weights.exclusions;
+        Some((100.0 - penalty).clamp(0.0, 100.0))
+    }
+
+    /// Returns the health score of every scrub area, as for health()
+    pub fn health_scores(
+        &self,
+        weights: &HealthWeights,
+        now: Instant,
+    ) -> Vec<f64> {
+        (0..self.areas.len())
+            .filter_map(|area| self.health(area, weights, now))
+            .collect()
+    }
+}
+
+/// Why autoscrubbing stopped
+///
+/// * `Finished` - The AutoScrubDesc returned zero
+///
+/// * `Cancelled` - The cancel token was cancelled
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[non_exhaustive]
+pub enum StopReason {
+    Finished,
+    Cancelled,
+}
+
+/// What happened during one run of autoscrubbing
+///
+/// * `bytes_scrubbed` - Number of bytes scrubbed
+///
+/// * `chunks` - Number of chunks scrubbed
+///
+/// * `passes` - Number of passes completed
+///
+/// * `elapsed` - Time from start to stop, including time spent waiting
+///
+/// * `reason` - Why scrubbing stopped
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ScrubSummary {
+    pub bytes_scrubbed: u64,
+    pub chunks: u64,
+    pub passes: u64,
+    pub elapsed: Duration,
+    pub reason: StopReason,
+}
+
+impl ScrubSummary {
+    /// Summarize the scrubbing done between two sets of statistics
+    ///
+    /// # Arguments:
+    /// * `start` - Statistics when scrubbing started
+    ///
+    /// * `end` - Statistics when scrubbing stopped
+    ///
+    /// * `elapsed` - Time from start to stop
+    ///
+    /// * `reason` - Why scrubbing stopped
+    pub fn new(
+        start: &ScrubStats,
+        end: &ScrubStats,
+        elapsed: Duration,
+        reason: StopReason,
+    ) -> ScrubSummary {
+        ScrubSummary {
+            bytes_scrubbed: end.bytes_scrubbed - start.bytes_scrubbed,
+            chunks: end.chunks - start.chunks,
+            passes: end.passes - start.passes,
+            elapsed,
+            reason,
+        }
+    }
+}
+
+/// Error history for a single scrub area
+///
+/// * `errors_corrected` - Corrected errors recorded in the area
+///
+/// * `errors_uncorrected` - Uncorrected errors recorded in the area
+///
+/// * `error_rate` - Corrected errors per hour over the recorded history
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct AreaErrorHistory {
+    pub errors_corrected: u64,
+    pub errors_uncorrected: u64,
+    pub error_rate: f64,
+}
+
+/// Error history for all scrub areas
+///
+/// * `areas` - History for each scrub area, in scrub area order
+///
+/// * `unmatched` - Number of errors at addresses outside all scrub areas
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ErrorHistory {
+    pub areas: Vec<AreaErrorHistory>,
+    pub unmatched: u64,
+}
+
+impl ErrorHistory {
+    /// Seed scrubber statistics with this history. Error counts are added,
+    /// error rates replaced, and priorities assigned so that the area with
+    /// the highest error rate has the highest priority. Areas without
+    /// errors get priority zero.
+    pub fn seed(&self, stats: &mut ScrubStats) {
+        let mut order: Vec<usize> = (0..self.areas.len()).collect();
+        order.sort_by(|a, b| {
+            self.areas[*a]
+                .error_rate
+                .total_cmp(&self.areas[*b].error_rate)
+        });
+
+        let mut priority = 0;
+        for i in order {
+            let history = &self.areas[i];
+            let area = match stats.areas.get_mut(i) {
+                None => continue,
+                Some(area) => area,
+            };
+            area.errors_corrected += history.errors_corrected;
+            area.errors_uncorrected += history.errors_uncorrected;
+            area.error_rate = history.error_rate;
+            if history.error_rate > 0.0 {
+                priority += 1;
+                area.priority = priority;
+            }
+        }
+    }
+}
+
+// Serialize an Instant as its age, in seconds, when serialized
+#[cfg(feature = "serde")]
+pub(crate) mod serde_instant {
+    use serde::{Deserialize, Deserializer, Serialize, Serializer};
+    use std::time::{Duration, Instant};
+
+    pub fn serialize<S: Serializer>(
+        t: &Instant,
+        serializer: S,
+    ) -> Result<S::Ok, S::Error> {
+        let age = Instant::now().saturating_duration_since(*t);
+        age.as_secs_f64().serialize(serializer)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Instant, D::Error> {
+        let age = f64::deserialize(deserializer)?;
+        let now = Instant::now();
+        let age = Duration::try_from_secs_f64(age).unwrap_or_default();
+        Ok(now.checked_sub(age).unwrap_or(now))
+    }
+
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            t: &Option<Instant>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            let age = t.map(|t| {
+                Instant::now().saturating_duration_since(t).as_secs_f64()
+            });
+            age.serialize(serializer)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<Instant>, D::Error> {
+            let age = Option::<f64>::deserialize(deserializer)?;
+            let now = Instant::now();
+            Ok(age.map(|age| {
+                let age =
+                    Duration::try_from_secs_f64(age).unwrap_or_default();
+                now.checked_sub(age).unwrap_or(now)
+            }))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pass_completion() {
+        let start = Instant::now();
+        let mut stats = ScrubStats::new(&[64, 128], start);
+        assert_eq!(stats.pass_size, 192);
+
+        stats.record_chunk(128, Duration::from_millis(1), start);
+        assert_eq!(stats.passes, 0);
+        assert!(stats.areas[0].last_scrubbed.is_none());
+
+        let later = start + Duration::from_secs(1);
+        stats.record_chunk(128, Duration::from_millis(1), later);
+        assert_eq!(stats.passes, 1);
+        assert_eq!(stats.pass_offset, 64);
+        assert_eq!(stats.areas[1].last_scrubbed, Some(later));
+        assert_eq!(stats.areas[1].last_epoch, Some(1));
+        assert_eq!(
+            stats.last_pass,
+            Some(PassRecord {
+                epoch: 1,
+                started: start,
+                completed: later,
+            })
+        );
+        assert_eq!(stats.epoch, 2);
+        assert_eq!(stats.bytes_scrubbed, 256);
+        assert_eq!(stats.max_chunk_time, Duration::from_millis(1));
+    }
+
+    #[test]
+    fn test_energy() {
+        let mut energy = EnergyStats::default();
+        assert_eq!(energy.joules_per_gb(), None);
+        energy.record(2.0, 500_000_000, 0);
+        energy.record(1.0, 250_000_000, 1);
+        assert_eq!(energy.last_pass_joules, Some(3.0));
+        assert_eq!(energy.pass_joules, 0.0);
+        energy.record(0.5, 250_000_000, 0);
+        assert_eq!(energy.pass_joules, 0.5);
+        assert_eq!(energy.joules_per_gb(), Some(3.5));
+    }
+
+    #[test]
+    fn test_epoch() {
+        let start = Instant::now();
+        let mut stats = ScrubStats::new(&[64], start);
+        stats.set_epoch(41);
+        stats.record_chunk(192, Duration::ZERO, start);
+        assert_eq!(stats.passes, 3);
+        assert_eq!(stats.epoch, 44);
+        assert_eq!(stats.last_pass.unwrap().epoch, 43);
+        assert_eq!(stats.areas[0].last_epoch, Some(43));
+    }
+
+    #[test]
+    fn test_health() {
+        let start = Instant::now();
+        let mut stats = ScrubStats::new(&[64, 64, 64], start);
+        let weights = HealthWeights::default();
+        stats.record_chunk(192, Duration::ZERO, start);
+        assert_eq!(stats.health(0, &weights, start), Some(100.0));
+
+        stats.areas[0].error_rate = 2.0;
+        stats.areas[1].errors_uncorrected = 5;
+        stats.areas[2].excluded_lines = 3;
+        let later = start + Duration::from_secs(7200);
+        assert_eq!(
+            stats.health_scores(&weights, later),
+            vec![88.0, 0.0, 92.0]
+        );
+        assert_eq!(stats.health(3, &weights, later), None);
+    }
+
+    #[test]
+    fn test_live_error_rate() {
+        let start = Instant::now();
+        let mut stats = ScrubStats::new(&[64, 64], start);
+        let weights = HealthWeights::default();
+        stats.record_chunk(128, Duration::ZERO, start);
+
+        // Errors at a steady rate of one an hour are scored as that rate
+        let hour = Duration::from_secs(3600);
+        let mut now = start;
+        for _ in 0..24 * 10 {
+            now += hour;
+            stats.record_chunk(128, Duration::ZERO, now);
+            stats.record_error(0, true, now);
+        }
+        stats.record_error(1, false, now);
+        let rate = stats.areas[0].error_rate_at(now);
+        assert!((rate - 1.0).abs() < 0.05, "{}", rate);
+        assert_eq!(stats.areas[1].error_rate_at(now), 0.0);
+        let score = stats.health(0, &weights, now).unwrap();
+        assert!((score - 95.0).abs() < 0.3, "{}", score);
+
+        // Once the errors stop the rate decays and the score recovers
+        let later = now + ERROR_RATE_WINDOW * 5;
+        assert!(stats.areas[0].error_rate_at(later) < 0.01);
+        let stale = stats.health(0, &weights, later).unwrap();
+        let fresh = stats.health(1, &weights, later).unwrap();
+        assert!(fresh - stale < 0.05, "{} {}", stale, fresh);
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut stats = ScrubStats::new(&[64, 64], Instant::now());
+        let now = Instant::now();
+        stats.record_error(0, true, now);
+        stats.record_error(1, true, now);
+        stats.record_error(1, false, now);
+        stats.record_error(7, false, now);
+        assert_eq!(stats.errors_corrected(), 2);
+        assert_eq!(stats.errors_uncorrected(), 1);
+        assert_eq!(stats.pass_errors, 4);
+
+        stats.record_chunk(128, Duration::ZERO, Instant::now());
+        assert_eq!(stats.last_pass_errors, 4);
+        assert_eq!(stats.pass_errors, 0);
+
+        // Errors reported together count as that many errors, in the
+        // error rate as well
+        let mut single = ScrubStats::new(&[64], now);
+        single.record_error(0, true, now);
+        single.record_error(0, true, now);
+        single.record_error(0, true, now);
+        stats.record_errors(0, true, 3, now);
+        assert_eq!(stats.errors_corrected(), 5);
+        assert_eq!(stats.pass_errors, 3);
+        let rate = stats.areas[0].error_rate_at(now);
+        let expected = single.areas[0].error_rate_at(now) + 1.0 / 24.0;
+        assert!((rate - expected).abs() < 1e-9, "{} {}", rate, expected);
+    }
+
+    #[test]
+    fn test_summary() {
+        let now = Instant::now();
+        let mut stats = ScrubStats::new(&[64, 64], now);
+        stats.record_chunk(64, Duration::from_millis(1), now);
+        let start = stats.clone();
+        stats.record_chunk(128, Duration::from_millis(2), now);
+        stats.record_chunk(128, Duration::from_millis(2), now);
+
+        let elapsed = Duration::from_secs(1);
+        let summary = ScrubSummary::new(
+            &start,
+            &stats,
+            elapsed,
+            StopReason::Finished,
+        );
+        assert_eq!(summary.bytes_scrubbed, 256);
+        assert_eq!(summary.chunks, 2);
+        assert_eq!(summary.passes, 2);
+        assert_eq!(summary.elapsed, elapsed);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_serde() {
+        let start = Instant::now() - Duration::from_secs(10);
+        let mut stats = ScrubStats::new(&[64, 64], start);
+        stats.areas[1].label = Some("heap".to_string());
+        stats.record_chunk(128, Duration::from_millis(3), start);
+        stats.record_error(1, false, start);
+
+        let json = serde_json::to_string(&stats).unwrap();
+        let copy: ScrubStats = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(copy.passes, 1);
+        assert_eq!(copy.bytes_scrubbed, 128);
+        assert_eq!(copy.scrub_time, Duration::from_millis(3));
+        assert_eq!(copy.areas[1].label.as_deref(), Some("heap"));
+        assert_eq!(copy.errors_uncorrected(), 1);
+        let age = copy.areas[0].staleness(Instant::now()).unwrap();
+        assert!(age >= Duration::from_secs(10));
+        assert!(age < Duration::from_secs(20));
+    }
+}
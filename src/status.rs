@@ -0,0 +1,191 @@
+// Point-in-time snapshot of the state of a memory scrubber. This is meant
+// for health endpoints and log shipping, so everything is reduced to plain
+// numbers and strings. With the serde feature, status_json() produces a
+// machine-readable version.
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::time::Instant;
+
+use crate::config::*;
+use crate::os::*;
+use crate::stats::*;
+
+/// Status of a single scrub area
+///
+/// * `label` - Name given to the area, if any
+///
+/// * `start` - Address of the first byte of the area
+///
+/// * `end` - Address of the last byte of the area
+///
+/// * `staleness_secs` - Seconds since the area was last completely
+///   scrubbed, or None if it has not been completely scrubbed yet
+///
+/// * `last_epoch` - Epoch of the last pass that completely scrubbed the
+///   area, or None if it has not been completely scrubbed yet
+///
+/// * `errors_corrected` - Corrected errors seen in the area
+///
+/// * `errors_uncorrected` - Uncorrected errors seen in the area
+///
+/// * `error_rate` - Estimated corrected errors per hour
+///
+/// * `priority` - Relative importance of scrubbing the area
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct AreaStatus {
+    pub label: Option<String>,
+    pub start: usize,
+    pub end: usize,
+    pub staleness_secs: Option<f64>,
+    pub last_epoch: Option<u64>,
+    pub errors_corrected: u64,
+    pub errors_uncorrected: u64,
+    pub error_rate: f64,
+    pub priority: u32,
+}
+
+/// Status of a memory scrubber
+///
+/// * `areas` - Status of each scrub area
+///
+/// * `passes` - Number of complete passes
+///
+/// * `epoch` - Epoch of the current pass
+///
+/// * `pass_offset` - Current position, as bytes scrubbed in this pass
+///
+/// * `pass_size` - Number of bytes in a complete pass
+///
+/// * `bytes_scrubbed` - Total bytes scrubbed
+///
+/// * `throughput` - Bytes scrubbed per second of scrubbing time
+///
+/// * `uptime_secs` - Seconds since the scrubber was created
+///
+/// * `errors_corrected` - Corrected errors seen in all areas
+///
+/// * `errors_uncorrected` - Uncorrected errors seen in all areas
+///
+/// * `max_chunk_secs` - Longest time taken to scrub a single chunk
+///
+/// * `config` - Scrubber configuration
+///
+/// * `cache_partition` - Cache partition the scrub thread is confined to,
+///   if any
+#[derive(Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct ScrubStatus {
+    pub areas: Vec<AreaStatus>,
+    pub passes: u64,
+    pub epoch: u64,
+    pub pass_offset: usize,
+    pub pass_size: usize,
+    pub bytes_scrubbed: u64,
+    pub throughput: f64,
+    pub uptime_secs: f64,
+    pub errors_corrected: u64,
+    pub errors_uncorrected: u64,
+    pub max_chunk_secs: f64,
+    pub config: ScrubConfig,
+    pub cache_partition: Option<CachePartition>,
+}
+
+impl ScrubStatus {
+    /// Build a status snapshot
+    ///
+    /// # Arguments:
+    /// * `stats` - Statistics kept by the scrubber
+    ///
+    /// * `extents` - (start, end) address of each scrub area, in the same
+    ///   order as stats.areas
+    ///
+    /// * `config` - Scrubber configuration
+    ///
+    /// * `now` - Time at which the snapshot is taken
+    pub fn new(
+        stats: &ScrubStats,
+        extents: &[(usize, usize)],
+        config: ScrubConfig,
+        now: Instant,
+    ) -> ScrubStatus {
+        let areas = stats
+            .areas
+            .iter()
+            .zip(extents.iter())
+            .map(|(area, (start, end))| AreaStatus {
+                label: area.label.clone(),
+                start: *start,
+                end: *end,
+                staleness_secs: area
+                    .staleness(now)
+                    .map(|d| d.as_secs_f64()),
+                last_epoch: area.last_epoch,
+                errors_corrected: area.errors_corrected,
+                errors_uncorrected: area.errors_uncorrected,
+                error_rate: area.error_rate_at(now),
+                priority: area.priority,
+            })
+            .collect();
+
+        ScrubStatus {
+            areas,
+            passes: stats.passes,
+            epoch: stats.epoch,
+            pass_offset: stats.pass_offset,
+            pass_size: stats.pass_size,
+            bytes_scrubbed: stats.bytes_scrubbed,
+            throughput: stats.throughput(),
+            uptime_secs: now
+                .saturating_duration_since(stats.started)
+                .as_secs_f64(),
+            errors_corrected: stats.errors_corrected(),
+            errors_uncorrected: stats.errors_uncorrected(),
+            max_chunk_secs: stats.max_chunk_time.as_secs_f64(),
+            config,
+            cache_partition: None,
+        }
+    }
+
+    /// Return the status as a JSON string
+    #[cfg(feature = "serde")]
+    pub fn to_json(&self) -> String {
+        // Serializing plain numbers and strings can't fail
+        serde_json::to_string(self)
+            .expect("ScrubStatus serialization failed")
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_status_json() {
+        let start = Instant::now();
+        let mut stats = ScrubStats::new(&[64, 64], start);
+        stats.areas[0].label = Some("kernel".to_string());
+        stats.record_chunk(128, Duration::from_millis(1), start);
+        stats.record_error(1, true, start);
+
+        let config = ScrubConfig::new::<1024, 16, 64, u64, usize>();
+        let status = ScrubStatus::new(
+            &stats,
+            &[(0x1000, 0x103f), (0x2000, 0x203f)],
+            config,
+            start + Duration::from_secs(2),
+        );
+        let json: serde_json::Value =
+            serde_json::from_str(&status.to_json()).unwrap();
+
+        assert_eq!(json["passes"], 1);
+        assert_eq!(json["epoch"], 2);
+        assert_eq!(json["areas"][0]["last_epoch"], 1);
+        assert_eq!(json["areas"][0]["label"], "kernel");
+        assert_eq!(json["areas"][0]["staleness_secs"], 2.0);
+        assert_eq!(json["areas"][1]["errors_corrected"], 1);
+        assert_eq!(json["config"]["cache_lines"], 1024);
+    }
+}
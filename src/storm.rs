@@ -0,0 +1,261 @@
+// Detection of error storms. A burst of corrected errors in one scrub area
+// is often the first sign of a failing device, and the sooner the area is
+// scrubbed again the more errors are corrected before a second bit goes
+// bad in the same word. When the corrected errors in an area within a
+// sliding window reach a threshold, the area is in a storm: an alert event
+// is sent and the area is scrubbed faster than the rest, using
+// LineScrubber::set_area_rate(). Once the area has gone a quiet period
+// without errors, it returns to the normal rate. The errors are counted
+// with a Threshold whose blocks are the areas, by index.
+
+use std::time::{Duration, Instant};
+
+use crate::backend::*;
+use crate::base::*;
+use crate::event::*;
+use crate::policy::*;
+use crate::threshold::*;
+
+/// When a storm starts and ends, and how it is handled
+///
+/// * `threshold` - Corrected errors within `window` that start a storm
+///
+/// * `window` - Period over which errors are counted
+///
+/// * `quiet_period` - Time without errors after which a storm ends
+///
+/// * `multiplier` - Scrub rate multiplier for an area in a storm
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct StormPolicy {
+    pub threshold: usize,
+    pub window: Duration,
+    pub quiet_period: Duration,
+    pub multiplier: usize,
+}
+
+impl Default for StormPolicy {
+    fn default() -> Self {
+        StormPolicy {
+            threshold: 10,
+            window: Duration::from_secs(60),
+            quiet_period: Duration::from_secs(10 * 60),
+            multiplier: 4,
+        }
+    }
+}
+
+/// Watches the corrected errors in each scrub area for storms
+///
+/// * `policy` - When storms start and end
+///
+/// * `areas` - Number of scrub areas
+///
+/// * `threshold` - Corrected errors in each area, by index. An area in a
+///   storm has crossed the threshold.
+///
+/// * `sinks` - Receivers of an event as each storm starts and ends
+pub struct StormDetector<'a> {
+    policy: StormPolicy,
+    areas: usize,
+    threshold: Threshold,
+    sinks: Vec<Box<dyn EventSink + 'a>>,
+}
+
+impl<'a> StormDetector<'a> {
+    /// Create a StormDetector
+    ///
+    /// # Arguments:
+    /// * `policy` - When storms start and end
+    ///
+    /// * `areas` - Number of scrub areas
+    ///
+    /// # Returns:
+    /// Ok(StormDetector) on success, otherwise Err(Error::ZeroSize) if the
+    /// threshold or multiplier is zero
+    pub fn new(
+        policy: StormPolicy,
+        areas: usize,
+    ) -> Result<StormDetector<'a>, Error> {
+        if policy.multiplier == 0 {
+            return Err(Error::ZeroSize);
+        }
+        let threshold = Threshold::new(ThresholdPolicy {
+            errors: policy.threshold,
+            window: policy.window,
+            clean_passes: 1,
+            granularity: 1,
+        })?;
+        Ok(StormDetector {
+            policy,
+            areas,
+            threshold,
+            sinks: Vec::new(),
+        })
+    }
+
+    /// Add a sink to receive an event as each storm starts and ends
+    pub fn add_event_sink(&mut self, sink: Box<dyn EventSink + 'a>) {
+        self.sinks.push(sink);
+    }
+
+    /// Returns the policy
+    pub fn policy(&self) -> &StormPolicy {
+        &self.policy
+    }
+
+    /// Returns whether a scrub area is in a storm
+    pub fn in_storm(&self, area: usize) -> bool {
+        area < self.areas && self.threshold.is_crossed(area)
+    }
+
+    /// Record an error in a scrub area. Uncorrected errors and errors
+    /// outside the scrub areas are ignored.
+    ///
+    /// # Arguments:
+    /// * `error` - The error
+    ///
+    /// * `now` - Time at which the error was detected
+    ///
+    /// # Returns:
+    /// true if the error started a storm
+    pub fn record_error(
+        &mut self,
+        error: &ErrorEvent,
+        now: Instant,
+    ) -> bool {
+        let area = match error.area {
+            Some(area) if area < self.areas => area,
+            _ => return false,
+        };
+        if error.severity != ErrorSeverity::Corrected {
+            return false;
+        }
+
+        // Errors in an area already in a storm only keep it going
+        let Some(errors) = self.threshold.record(area, now) else {
+            return false;
+        };
+        self.emit(&ScrubEvent::ErrorStorm { area, errors });
+        true
+    }
+
+    /// End the storms in areas that have been quiet long enough
+    ///
+    /// # Arguments:
+    /// * `now` - The current time
+    ///
+    /// # Returns:
+    /// The index of each area whose storm ended
+    pub fn update(&mut self, now: Instant) -> Vec<usize> {
+        let ended = self.threshold.quiet(self.policy.quiet_period, now);
+        for &area in &ended {
+            self.threshold.clear(area);
+            self.emit(&ScrubEvent::StormCleared { area });
+        }
+        ended
+    }
+
+    /// Set the scrub rate of each area according to whether it is in a
+    /// storm
+    pub fn apply<B: ScrubBackend>(
+        &self,
+        scrubber: &mut LineScrubber<B>,
+    ) -> Result<(), Error> {
+        for area in 0..self.areas {
+            let multiplier = match self.in_storm(area) {
+                true => self.policy.multiplier,
+                false => 1,
+            };
+            if scrubber.area_rate(area) != multiplier {
+                scrubber.set_area_rate(area, multiplier)?;
+            }
+        }
+        Ok(())
+    }
+
+    // Send an event to all event sinks
+    fn emit(&mut self, event: &ScrubEvent) {
+        for sink in self.sinks.iter_mut() {
+            sink.event(event);
+        }
+    }
+
+    // Returns the address and size of each buffer the detector holds, so
+    // that scrubbing can leave them out
+    pub(crate) fn held_buffers(&self) -> Vec<(usize, usize)> {
+        let mut buffers = self.threshold.buffers();
+        buffers.push(vec_buffer(&self.sinks));
+        buffers
+            .extend(self.sinks.iter().map(|s| value_buffer(s.as_ref())));
+        buffers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+
+    fn corrected(area: usize) -> ErrorEvent {
+        ErrorEvent {
+            addr: 0,
+            area: Some(area),
+            severity: ErrorSeverity::Corrected,
+        }
+    }
+
+    #[test]
+    fn test_storm() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let policy = StormPolicy {
+            threshold: 3,
+            window: Duration::from_secs(10),
+            quiet_period: Duration::from_secs(30),
+            multiplier: 2,
+        };
+        let events = Collector::default();
+        let mut detector = StormDetector::new(policy, 2).unwrap();
+        detector.add_event_sink(Box::new(events.clone()));
+        let mut scrubber = LineScrubber::new(
+            crate::sim::SimMemory::new(0, 8192, 64).unwrap(),
+            &[(0, 4095), (4096, 8191)],
+            64,
+            4,
+        )
+        .unwrap();
+
+        // Errors too far apart
+        assert!(!detector.record_error(&corrected(1), at(0)));
+        assert!(!detector.record_error(&corrected(1), at(8)));
+        assert!(!detector.record_error(&corrected(1), at(15)));
+        assert!(detector.record_error(&corrected(1), at(16)));
+        assert!(detector.in_storm(1) && !detector.in_storm(0));
+        assert_eq!(
+            events.events()[0],
+            ScrubEvent::ErrorStorm { area: 1, errors: 3 }
+        );
+        detector.apply(&mut scrubber).unwrap();
+        assert_eq!(scrubber.area_rate(1), 2);
+
+        // More errors keep the storm going
+        detector.record_error(&corrected(1), at(30));
+        assert!(detector.update(at(50)).is_empty());
+        assert_eq!(detector.update(at(60)), [1]);
+        assert_eq!(
+            events.events()[1],
+            ScrubEvent::StormCleared { area: 1 }
+        );
+        detector.apply(&mut scrubber).unwrap();
+        assert_eq!(scrubber.area_rate(1), 1);
+
+        assert!(StormDetector::new(
+            StormPolicy {
+                threshold: 0,
+                ..policy
+            },
+            1
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,25 @@
+// Synchronization primitives for state shared between threads. Model tests
+// build with --cfg loom, which substitutes the loom versions so that every
+// interleaving of the threads using them can be checked:
+//
+//      RUSTFLAGS="--cfg loom" cargo test --release --lib loom_tests
+
+#[cfg(loom)]
+pub(crate) use loom::sync::atomic::{AtomicBool, Ordering};
+#[cfg(loom)]
+pub(crate) use loom::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+#[cfg(not(loom))]
+pub(crate) use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(not(loom))]
+pub(crate) use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+
+// Lock a mutex. A thread that panicked while holding the lock can't have
+// left a simple flag or counter half updated, so a poisoned lock is used
+// as is.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
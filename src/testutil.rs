@@ -0,0 +1,81 @@
+// Fixtures shared by the unit tests: backends that record what they read,
+// an event sink that collects events and scratch paths for test files.
+
+use std::cell::RefCell;
+use std::env;
+use std::path::PathBuf;
+use std::process;
+use std::rc::Rc;
+
+use crate::backend::*;
+use crate::base::*;
+use crate::event::*;
+
+/// A backend that records the address of each line read. Clones share the
+/// record, so a test can keep one to inspect while a scrubber owns another.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Recorder(pub Rc<RefCell<Vec<usize>>>);
+
+impl Recorder {
+    /// Returns the addresses read so far, in the order they were read
+    pub fn reads(&self) -> Vec<usize> {
+        self.0.borrow().clone()
+    }
+
+    /// Forget the addresses read so far
+    pub fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+}
+
+impl ScrubBackend for Recorder {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        self.0.borrow_mut().push(addr);
+        Ok(())
+    }
+}
+
+/// A backend that logs each read as "<label> <address>" to a log that may
+/// be shared with other backends and hooks, to check the order of calls
+pub(crate) struct Logger(pub &'static str, pub Rc<RefCell<Vec<String>>>);
+
+impl ScrubBackend for Logger {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        self.1.borrow_mut().push(format!("{} {:#x}", self.0, addr));
+        Ok(())
+    }
+}
+
+/// An event sink that collects every event. Clones share the events
+/// collected, as for a Recorder.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Collector(pub Rc<RefCell<Vec<ScrubEvent>>>);
+
+impl Collector {
+    /// Returns the events collected so far, in the order they were sent
+    pub fn events(&self) -> Vec<ScrubEvent> {
+        self.0.borrow().clone()
+    }
+}
+
+impl EventSink for Collector {
+    fn event(&mut self, event: &ScrubEvent) {
+        self.0.borrow_mut().push(*event);
+    }
+}
+
+/// Returns a path in the temporary directory for a test's files, unique
+/// to this process
+///
+/// # Arguments:
+/// * `name` - Name of the test's file or directory, which may have an
+///   extension, such as "history.parquet"
+pub(crate) fn scratch_path(name: &str) -> PathBuf {
+    let file = match name.split_once('.') {
+        Some((stem, ext)) => {
+            format!("memscrub-{}-{}.{}", stem, process::id(), ext)
+        }
+        None => format!("memscrub-{}-{}", name, process::id()),
+    };
+    env::temp_dir().join(file)
+}
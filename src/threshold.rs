@@ -0,0 +1,555 @@
+// Error thresholds with hysteresis. Acting on a single corrected error is
+// too aggressive: most are one-off upsets that never recur, and excluding
+// or escalating on them takes good memory out of service. A Threshold
+// counts the corrected errors in each block of memory over a sliding
+// window, and a block crosses the threshold only when it has had enough of
+// them within the window. Once crossed, it stays so until enough passes
+// have completed without an error in it, so a block with errors that come
+// and go doesn't flap between the two states.
+//
+// ErrorThreshold puts one Threshold in front of any number of other
+// policies, such as a Blacklist and a DemandScrub, so that they all act on
+// the same blocks. Corrected errors in a block are held back from those
+// policies until the block crosses the threshold, then passed on until it
+// clears; uncorrected errors and all other events are passed straight on.
+// An event is sent as each block crosses its threshold and as it clears,
+// for alerting. StormDetector and FlushRetry count errors with a Threshold
+// of their own, in scrub areas and cache lines.
+//
+// Clearing a block doesn't undo what the policies behind did, such as
+// excluding the block, but means further errors in it must cross the
+// threshold again before the policies see them.
+
+use std::time::{Duration, Instant};
+
+use crate::base::*;
+use crate::event::*;
+use crate::policy::*;
+
+/// When blocks of memory cross their error threshold and clear again
+///
+/// * `errors` - Corrected errors within `window` that cross the threshold
+///
+/// * `window` - Period over which errors are counted
+///
+/// * `clean_passes` - Consecutive passes without an error after which a
+///   block clears
+///
+/// * `granularity` - Size of the blocks errors are counted in, such as
+///   the cache line or page size, a power of two
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ThresholdPolicy {
+    pub errors: usize,
+    pub window: Duration,
+    pub clean_passes: u64,
+    pub granularity: usize,
+}
+
+impl Default for ThresholdPolicy {
+    fn default() -> Self {
+        ThresholdPolicy {
+            errors: 3,
+            window: Duration::from_secs(60 * 60),
+            clean_passes: 2,
+            granularity: 4096,
+        }
+    }
+}
+
+// The errors in one block
+//
+// errors:  Times of the errors within the window, oldest first, while
+//          not crossed
+// last:    Time of the last error
+// crossed: Whether the block has crossed the threshold
+// clean:   Passes completed without an error since the block crossed
+// dirty:   Whether the block has had an error in the current pass
+#[derive(Clone, Debug)]
+struct BlockErrors {
+    errors: Vec<Instant>,
+    last: Instant,
+    crossed: bool,
+    clean: u64,
+    dirty: bool,
+}
+
+/// Counts corrected errors in blocks of memory against a threshold
+///
+/// * `policy` - When blocks cross the threshold and clear
+///
+/// * `blocks` - Errors in each block with any, by block address
+#[derive(Clone, Debug)]
+pub struct Threshold {
+    policy: ThresholdPolicy,
+    blocks: AddrMap<BlockErrors>,
+}
+
+impl Threshold {
+    /// Create a Threshold
+    ///
+    /// # Arguments:
+    /// * `policy` - When blocks cross the threshold and clear
+    ///
+    /// # Returns:
+    /// Ok(Threshold) on success, otherwise Err(Error::ZeroSize) if the
+    /// number of errors or clean passes is zero or
+    /// Err(Error::UnalignedValue) if the granularity isn't a power of two
+    pub fn new(policy: ThresholdPolicy) -> Result<Threshold, Error> {
+        if policy.errors == 0 || policy.clean_passes == 0 {
+            return Err(Error::ZeroSize);
+        }
+        if !policy.granularity.is_power_of_two() {
+            return Err(Error::UnalignedValue);
+        }
+        Ok(Threshold {
+            policy,
+            blocks: AddrMap::new(),
+        })
+    }
+
+    /// Returns the policy
+    pub fn policy(&self) -> &ThresholdPolicy {
+        &self.policy
+    }
+
+    /// Returns the address of the block holding an address
+    pub fn block(&self, addr: usize) -> usize {
+        addr & !(self.policy.granularity - 1)
+    }
+
+    /// Returns whether the block holding an address has crossed the
+    /// threshold
+    pub fn is_crossed(&self, addr: usize) -> bool {
+        self.blocks.get(self.block(addr)).is_some_and(|b| b.crossed)
+    }
+
+    /// Returns the addresses of the blocks that have crossed the
+    /// threshold, sorted
+    pub fn crossed(&self) -> Vec<usize> {
+        self.blocks
+            .iter()
+            .filter(|(_, b)| b.crossed)
+            .map(|(block, _)| block)
+            .collect()
+    }
+
+    /// Returns the addresses of the blocks that have crossed the
+    /// threshold and have had no error for a period, sorted
+    ///
+    /// # Arguments:
+    /// * `period` - Time without an error
+    ///
+    /// * `now` - The current time
+    pub fn quiet(&self, period: Duration, now: Instant) -> Vec<usize> {
+        self.blocks
+            .iter()
+            .filter(|(_, b)| {
+                b.crossed
+                    && now.saturating_duration_since(b.last) >= period
+            })
+            .map(|(block, _)| block)
+            .collect()
+    }
+
+    /// Clear the block holding an address and forget its errors
+    ///
+    /// # Returns:
+    /// true if the block had crossed the threshold
+    pub fn clear(&mut self, addr: usize) -> bool {
+        let block = self.block(addr);
+        self.blocks.remove(block).is_some_and(|b| b.crossed)
+    }
+
+    /// Record a corrected error
+    ///
+    /// # Arguments:
+    /// * `addr` - Address of the error
+    ///
+    /// * `now` - Time at which the error was detected
+    ///
+    /// # Returns:
+    /// The number of errors within the window if this error crossed the
+    /// threshold, otherwise None
+    pub fn record(&mut self, addr: usize, now: Instant) -> Option<usize> {
+        let window = self.policy.window;
+        let block =
+            self.blocks.get_or_insert_with(self.block(addr), || {
+                BlockErrors {
+                    errors: Vec::new(),
+                    last: now,
+                    crossed: false,
+                    clean: 0,
+                    dirty: false,
+                }
+            });
+        block.last = now;
+        block.dirty = true;
+        block.clean = 0;
+        if block.crossed {
+            return None;
+        }
+        block.errors.push(now);
+        expire(&mut block.errors, now, window);
+        if block.errors.len() < self.policy.errors {
+            return None;
+        }
+        let errors = block.errors.len();
+        block.errors.clear();
+        block.crossed = true;
+        Some(errors)
+    }
+
+    /// Count a completed pass. Blocks that have crossed the threshold and
+    /// have now gone enough passes without an error clear, and blocks
+    /// with no errors left within the window are forgotten.
+    ///
+    /// # Arguments:
+    /// * `now` - Time at which the pass completed
+    ///
+    /// # Returns:
+    /// The addresses of the blocks that cleared, sorted
+    pub fn pass_complete(&mut self, now: Instant) -> Vec<usize> {
+        let policy = self.policy;
+        let mut cleared = Vec::new();
+        self.blocks.retain(|addr, block| {
+            if !block.crossed {
+                expire(&mut block.errors, now, policy.window);
+                return !block.errors.is_empty();
+            }
+            match block.dirty {
+                true => block.dirty = false,
+                false => block.clean += 1,
+            }
+            if block.clean < policy.clean_passes {
+                return true;
+            }
+            cleared.push(addr);
+            false
+        });
+        cleared
+    }
+
+    // Returns the address and size of each buffer holding the errors, so
+    // that scrubbing can leave them out
+    pub(crate) fn buffers(&self) -> Vec<(usize, usize)> {
+        let mut buffers = vec![self.blocks.buffer()];
+        buffers.extend(
+            self.blocks.iter().map(|(_, b)| vec_buffer(&b.errors)),
+        );
+        buffers
+    }
+}
+
+// Drop the times of errors that have fallen out of the window
+fn expire(errors: &mut Vec<Instant>, now: Instant, window: Duration) {
+    let expired = errors
+        .partition_point(|&t| now.saturating_duration_since(t) > window);
+    errors.drain(..expired);
+}
+
+// A map from addresses to values, kept sorted by address in a Vec rather
+// than in a HashMap so that its buffer can be found and left out of the
+// pass
+#[derive(Clone, Debug)]
+pub(crate) struct AddrMap<V>(Vec<(usize, V)>);
+
+impl<V> AddrMap<V> {
+    pub(crate) fn new() -> AddrMap<V> {
+        AddrMap(Vec::new())
+    }
+
+    pub(crate) fn get(&self, addr: usize) -> Option<&V> {
+        let i = self.0.binary_search_by_key(&addr, |&(a, _)| a).ok()?;
+        Some(&self.0[i].1)
+    }
+
+    pub(crate) fn get_mut(&mut self, addr: usize) -> Option<&mut V> {
+        let i = self.0.binary_search_by_key(&addr, |&(a, _)| a).ok()?;
+        Some(&mut self.0[i].1)
+    }
+
+    pub(crate) fn get_or_insert_with<F: FnOnce() -> V>(
+        &mut self,
+        addr: usize,
+        f: F,
+    ) -> &mut V {
+        let i = match self.0.binary_search_by_key(&addr, |&(a, _)| a) {
+            Ok(i) => i,
+            Err(i) => {
+                self.0.insert(i, (addr, f()));
+                i
+            }
+        };
+        &mut self.0[i].1
+    }
+
+    pub(crate) fn remove(&mut self, addr: usize) -> Option<V> {
+        let i = self.0.binary_search_by_key(&addr, |&(a, _)| a).ok()?;
+        Some(self.0.remove(i).1)
+    }
+
+    pub(crate) fn retain<F: FnMut(usize, &mut V) -> bool>(
+        &mut self,
+        mut f: F,
+    ) {
+        self.0.retain_mut(|(addr, value)| f(*addr, value));
+    }
+
+    // Returns the entries in address order
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (usize, &V)> {
+        self.0.iter().map(|(addr, value)| (*addr, value))
+    }
+
+    pub(crate) fn buffer(&self) -> (usize, usize) {
+        vec_buffer(&self.0)
+    }
+}
+
+/// A Policy passing corrected errors on to other policies only for blocks
+/// of memory that have crossed a threshold
+///
+/// * `threshold` - Errors counted in each block
+///
+/// * `policies` - Policies given the errors of blocks that have crossed
+///   the threshold, and every other event
+///
+/// * `sinks` - Receivers of an event as each block crosses the threshold
+///   and clears
+pub struct ErrorThreshold<'a> {
+    threshold: Threshold,
+    policies: Vec<Box<dyn Policy + 'a>>,
+    sinks: Vec<Box<dyn EventSink + 'a>>,
+}
+
+impl<'a> ErrorThreshold<'a> {
+    /// Create an ErrorThreshold with no policies behind it
+    ///
+    /// # Arguments:
+    /// * `policy` - When blocks cross the threshold and clear
+    ///
+    /// # Returns:
+    /// Ok(ErrorThreshold) on success, otherwise Err(Error) as for
+    /// Threshold::new()
+    pub fn new(
+        policy: ThresholdPolicy,
+    ) -> Result<ErrorThreshold<'a>, Error> {
+        Ok(ErrorThreshold {
+            threshold: Threshold::new(policy)?,
+            policies: Vec::new(),
+            sinks: Vec::new(),
+        })
+    }
+
+    /// Add a policy to be given errors once their block has crossed the
+    /// threshold. Policies are given events in the order they were added.
+    pub fn add_policy(&mut self, policy: Box<dyn Policy + 'a>) {
+        self.policies.push(policy);
+    }
+
+    /// Add a sink to receive an event as each block crosses the threshold
+    /// and clears
+    pub fn add_event_sink(&mut self, sink: Box<dyn EventSink + 'a>) {
+        self.sinks.push(sink);
+    }
+
+    /// Returns the threshold
+    pub fn threshold(&self) -> &Threshold {
+        &self.threshold
+    }
+
+    // Send an event to all event sinks
+    fn emit(&mut self, event: &ScrubEvent) {
+        for sink in self.sinks.iter_mut() {
+            sink.event(event);
+        }
+    }
+}
+
+impl Policy for ErrorThreshold<'_> {
+    fn event(
+        &mut self,
+        event: &ScrubEvent,
+        now: Instant,
+    ) -> Vec<PolicyAction> {
+        match event {
+            ScrubEvent::Error(e)
+                if e.severity == ErrorSeverity::Corrected =>
+            {
+                let addr = e.addr as usize;
+                if let Some(errors) = self.threshold.record(addr, now) {
+                    let block = self.threshold.block(addr) as u64;
+                    self.emit(&ScrubEvent::ThresholdCrossed {
+                        addr: block,
+                        errors,
+                    });
+                } else if !self.threshold.is_crossed(addr) {
+                    return Vec::new();
+                }
+            }
+            ScrubEvent::PassComplete { .. } => {
+                for block in self.threshold.pass_complete(now) {
+                    self.emit(&ScrubEvent::ThresholdCleared {
+                        addr: block as u64,
+                    });
+                }
+            }
+            _ => {}
+        }
+        self.policies
+            .iter_mut()
+            .flat_map(|policy| policy.event(event, now))
+            .collect()
+    }
+
+    fn areas_changed(&mut self, extents: &[(usize, usize)]) {
+        for policy in self.policies.iter_mut() {
+            policy.areas_changed(extents);
+        }
+    }
+
+    fn config_changed(&mut self, change: &ConfigChange, now: Instant) {
+        for policy in self.policies.iter_mut() {
+            policy.config_changed(change, now);
+        }
+    }
+
+    fn buffers(&self) -> Vec<(usize, usize)> {
+        let mut buffers = self.threshold.buffers();
+        buffers.push(vec_buffer(&self.policies));
+        buffers.push(vec_buffer(&self.sinks));
+        buffers.extend(
+            self.policies
+                .iter()
+                .flat_map(|p| policy_buffers(p.as_ref())),
+        );
+        buffers
+            .extend(self.sinks.iter().map(|s| value_buffer(s.as_ref())));
+        buffers
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::*;
+    use crate::sim::*;
+    use crate::testutil::*;
+
+    fn policy() -> ThresholdPolicy {
+        ThresholdPolicy {
+            errors: 2,
+            window: Duration::from_secs(60),
+            clean_passes: 2,
+            granularity: 1024,
+        }
+    }
+
+    #[test]
+    fn test_threshold() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut threshold = Threshold::new(policy()).unwrap();
+
+        // Errors too far apart never cross
+        assert_eq!(threshold.record(0x1010, at(0)), None);
+        assert_eq!(threshold.record(0x1020, at(100)), None);
+        assert_eq!(threshold.record(0x1030, at(120)), Some(2));
+        assert!(threshold.is_crossed(0x13ff));
+        assert!(!threshold.is_crossed(0x1400));
+        assert_eq!(threshold.crossed(), [0x1000]);
+        assert!(threshold
+            .quiet(Duration::from_secs(10), at(125))
+            .is_empty());
+        assert_eq!(
+            threshold.quiet(Duration::from_secs(10), at(130)),
+            [0x1000]
+        );
+
+        // The pass with the errors isn't clean, and an error resets the
+        // count of clean passes
+        assert!(threshold.pass_complete(at(130)).is_empty());
+        assert!(threshold.pass_complete(at(140)).is_empty());
+        assert_eq!(threshold.record(0x1000, at(150)), None);
+        assert!(threshold.pass_complete(at(160)).is_empty());
+        assert!(threshold.pass_complete(at(170)).is_empty());
+        assert_eq!(threshold.pass_complete(at(180)), [0x1000]);
+        assert!(threshold.crossed().is_empty());
+
+        // Once cleared, the threshold must be crossed again
+        assert_eq!(threshold.record(0x1000, at(190)), None);
+        assert!(!threshold.is_crossed(0x1000));
+        assert_eq!(threshold.record(0x1000, at(191)), Some(2));
+        assert!(threshold.clear(0x1010));
+        assert!(!threshold.clear(0x1010));
+        assert_eq!(threshold.record(0x1000, at(192)), None);
+
+        for (errors, clean_passes, granularity) in
+            [(0, 1, 64), (1, 0, 64), (1, 1, 48)]
+        {
+            let policy = ThresholdPolicy {
+                errors,
+                window: Duration::ZERO,
+                clean_passes,
+                granularity,
+            };
+            assert!(Threshold::new(policy).is_err());
+        }
+    }
+
+    #[test]
+    fn test_error_threshold() {
+        let events = Collector::default();
+        let mem = SimMemory::new(0, 8192, 64).unwrap();
+        let mut scrubber =
+            LineScrubber::new(mem, &[(0, 8191)], 64, 4).unwrap();
+        let blacklist = Blacklist {
+            corrected: true,
+            ..Blacklist::new(1024)
+        };
+        let mut threshold = ErrorThreshold::new(policy()).unwrap();
+        threshold.add_policy(Box::new(DemandScrub { bytes: 1024 }));
+        threshold.add_policy(Box::new(blacklist));
+        threshold.add_event_sink(Box::new(events.clone()));
+        scrubber.add_policy(Box::new(threshold));
+
+        let error = |addr, severity| ErrorEvent {
+            addr,
+            area: None,
+            severity,
+        };
+
+        // A single corrected error isn't acted on, a second is, by every
+        // policy behind the threshold
+        scrubber
+            .record_error(error(0x1100, ErrorSeverity::Corrected))
+            .unwrap();
+        assert!(scrubber.excluded().is_empty());
+        assert_eq!(scrubber.backend().reads_at(0x1400), Some(0));
+        scrubber
+            .record_error(error(0x1200, ErrorSeverity::Corrected))
+            .unwrap();
+        assert_eq!(scrubber.excluded(), [(0x1000, 0x13ff)]);
+        assert_eq!(scrubber.backend().reads_at(0x1400), Some(1));
+
+        // Uncorrected errors are acted on at once
+        scrubber
+            .record_error(error(0x0, ErrorSeverity::Uncorrected))
+            .unwrap();
+        assert_eq!(scrubber.excluded(), [(0x0, 0x3ff), (0x1000, 0x13ff)]);
+
+        // Two clean passes clear the block
+        for _ in 0..3 {
+            scrubber.scrub(8192).unwrap();
+        }
+        assert_eq!(
+            events.events(),
+            [
+                ScrubEvent::ThresholdCrossed {
+                    addr: 0x1000,
+                    errors: 2
+                },
+                ScrubEvent::ThresholdCleared { addr: 0x1000 }
+            ]
+        );
+    }
+}
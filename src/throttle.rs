@@ -0,0 +1,568 @@
+// Throttling of the scrub rate. Between chunks, AutoScrub asks each of its
+// throttle policies what fraction of the full scrub rate to use and sleeps
+// long enough to bring the memory bandwidth used down to the smallest of
+// them. A rate of zero pauses scrubbing until a policy allows it to resume.
+//
+// SensorThrottle is a policy driven by a platform sensor, such as a hwmon
+// temperature or RAPL package power, or by a user callback. This matters on
+// fanless devices, where scrubbing measurably heats the SoC.
+//
+// ImpactGuard is a policy driven by a measure of the interference that
+// scrubbing causes the foreground work, such as its request latency or
+// the cache misses counted by a PerfCounter. Each time the measure is
+// over a threshold the rate is halved, and while it stays under the rate
+// is restored gradually, so that latency targets are protected without
+// tuning the scrub rate by hand.
+//
+// A policy such as an ImpactGuard changes its rate each time it is asked,
+// so it can only be asked by one scrubber. SharedThrottle lets the rate it
+// gives to the scrubber be read elsewhere, such as by an AdaptiveBackend
+// choosing how to read memory, through a RateReader.
+
+use std::cell::Cell;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+
+use crate::rng::*;
+
+const HWMON_PATH: &str = "/sys/class/hwmon";
+const RAPL_PATH: &str = "/sys/class/powercap/intel-rapl:0";
+
+/// How long to sleep before asking the policies again when scrubbing is
+/// paused
+pub const THROTTLE_PAUSE: Duration = Duration::from_secs(1);
+
+/// Decides how fast scrubbing may proceed
+pub trait ThrottlePolicy {
+    /// Returns the fraction of the full scrub rate to use, from 0.0, which
+    /// pauses scrubbing, to 1.0, which does no throttling
+    ///
+    /// # Arguments:
+    /// * `now` - The current time
+    fn rate(&mut self, now: Instant) -> f64;
+}
+
+/// Returns how long to wait after scrubbing a chunk so that the average
+/// rate is the given fraction of the rate at which the chunk was scrubbed,
+/// or None if scrubbing is paused
+///
+/// # Arguments:
+/// * `rate` - Fraction of the full rate, from 0.0 to 1.0
+///
+/// * `chunk_time` - Time taken to scrub the chunk
+pub fn throttle_delay(
+    rate: f64,
+    chunk_time: Duration,
+) -> Option<Duration> {
+    if rate <= 0.0 || rate.is_nan() {
+        return None;
+    }
+    let rate = rate.min(1.0);
+    Some(chunk_time.mul_f64(1.0 / rate - 1.0))
+}
+
+/// Random jitter added to the wait between chunks. Scrubbers started
+/// together with the same period, such as in many VMs or containers on one
+/// host, otherwise scrub in lock step and produce synchronized bursts of
+/// memory traffic.
+///
+/// * `fraction` - Largest jitter as a fraction of the time from the start
+///   of one chunk to the start of the next
+///
+/// * `rng` - Source of random numbers
+#[derive(Clone)]
+pub struct Jitter {
+    fraction: f64,
+    rng: Box<dyn CloneRng>,
+}
+
+impl fmt::Debug for Jitter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Jitter")
+            .field("fraction", &self.fraction)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Jitter {
+    /// Create a new Jitter seeded from the time and process ID, so that
+    /// each instance differs
+    ///
+    /// # Arguments:
+    /// * `fraction` - Largest jitter as a fraction of the chunk period
+    pub fn new(fraction: f64) -> Jitter {
+        Jitter::with_rng(fraction, Box::new(XorShift64::from_time()))
+    }
+
+    /// Create a new Jitter with a given seed, for reproducible delays
+    pub fn with_seed(fraction: f64, seed: u64) -> Jitter {
+        Jitter::with_rng(fraction, Box::new(XorShift64::new(seed)))
+    }
+
+    /// Create a new Jitter drawing on a source of random numbers, such as
+    /// a hardware random number generator
+    ///
+    /// # Arguments:
+    /// * `fraction` - Largest jitter as a fraction of the chunk period
+    ///
+    /// * `rng` - Source of random numbers
+    pub fn with_rng(fraction: f64, rng: Box<dyn CloneRng>) -> Jitter {
+        Jitter {
+            fraction: fraction.max(0.0),
+            rng,
+        }
+    }
+
+    /// Returns the delay with jitter added
+    ///
+    /// # Arguments:
+    /// * `delay` - Delay before the next chunk without jitter
+    ///
+    /// * `chunk_time` - Time taken to scrub the chunk
+    pub fn apply(
+        &mut self,
+        delay: Duration,
+        chunk_time: Duration,
+    ) -> Duration {
+        let period = delay + chunk_time;
+        delay + period.mul_f64(self.fraction * self.rng.next_f64())
+    }
+}
+
+/// A platform sensor reading
+pub trait Sensor {
+    /// Read the current value
+    fn read(&mut self) -> io::Result<f64>;
+}
+
+impl<F: FnMut() -> io::Result<f64>> Sensor for F {
+    fn read(&mut self) -> io::Result<f64> {
+        self()
+    }
+}
+
+/// A hwmon temperature sensor, read in degrees Celsius
+///
+/// * `path` - Path of the temp*_input file
+pub struct HwmonTemp {
+    path: PathBuf,
+}
+
+impl HwmonTemp {
+    /// Use the temp*_input file at the given path
+    pub fn new<P: AsRef<Path>>(path: P) -> HwmonTemp {
+        HwmonTemp {
+            path: path.as_ref().to_path_buf(),
+        }
+    }
+
+    /// Find the first temperature input of the hwmon device with the given
+    /// name, such as "coretemp" or "k10temp"
+    pub fn find(name: &str) -> io::Result<HwmonTemp> {
+        Self::find_in(HWMON_PATH, name)
+    }
+
+    // Find a hwmon device by name under the given directory
+    fn find_in<P: AsRef<Path>>(
+        dir: P,
+        name: &str,
+    ) -> io::Result<HwmonTemp> {
+        for entry in fs::read_dir(dir)? {
+            let path = entry?.path();
+            let dev_name = match fs::read_to_string(path.join("name")) {
+                Ok(n) => n,
+                Err(_) => continue,
+            };
+            if dev_name.trim() == name && path.join("temp1_input").exists()
+            {
+                return Ok(HwmonTemp::new(path.join("temp1_input")));
+            }
+        }
+        Err(io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("no hwmon temperature sensor named {}", name),
+        ))
+    }
+}
+
+impl Sensor for HwmonTemp {
+    fn read(&mut self) -> io::Result<f64> {
+        let millidegrees: i64 = fs::read_to_string(&self.path)?
+            .trim()
+            .parse()
+            .map_err(|_| io::Error::from(io::ErrorKind::InvalidData))?;
+        Ok(millidegrees as f64 / 1000.0)
+    }
+}
+
+/// Package power from RAPL, read in watts. Power is computed from the
+/// energy used since the previous read, so the first read returns zero.
+///
+/// * `path` - Path of the energy_uj file
+///
+/// * `last` - Energy counter and time at the previous read
+pub struct RaplPower {
+    path: PathBuf,
+    last: Option<(u64, Instant)>,
+}
+
+impl RaplPower {
+    /// Use the package 0 RAPL domain
+    pub fn new() -> RaplPower {
+        RaplPower::with_path(Path::new(RAPL_PATH).join("energy_uj"))
+    }
+
+    /// Use the energy_uj file at the given path
+    pub fn with_path<P: AsRef<Path>>(path: P) -> RaplPower {
+        RaplPower {
+            path: path.as_ref().to_path_buf(),
+            last: None,
+        }
+    }
+}
+
+impl Default for RaplPower {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Sensor for RaplPower {
+    fn read(&mut self) -> io::Result<f64> {
+        let energy: u64 =
+            fs::read_to_string(&self.path)?.trim().parse().map_err(
+                |_| io::Error::from(io::ErrorKind::InvalidData),
+            )?;
+        let now = Instant::now();
+        let power = match self.last {
+            // A counter wrap gives a bogus value, so report nothing
+            Some((last, then)) if energy >= last && now > then => {
+                (energy - last) as f64 / 1e6 / (now - then).as_secs_f64()
+            }
+            _ => 0.0,
+        };
+        self.last = Some((energy, now));
+        Ok(power)
+    }
+}
+
+/// Throttles scrubbing while a sensor reading is above a limit. Full rate
+/// resumes once the reading drops to the resume level, which should be
+/// below the limit so that scrubbing doesn't oscillate around it.
+///
+/// * `sensor` - Source of readings
+///
+/// * `limit` - Reading above which scrubbing is throttled
+///
+/// * `resume` - Reading at or below which full rate resumes
+///
+/// * `throttled_rate` - Fraction of the full rate used while throttled
+///
+/// * `throttled` - Whether scrubbing is currently throttled
+pub struct SensorThrottle<T: Sensor> {
+    sensor: T,
+    limit: f64,
+    resume: f64,
+    throttled_rate: f64,
+    throttled: bool,
+}
+
+impl<T: Sensor> SensorThrottle<T> {
+    /// Create a new SensorThrottle
+    ///
+    /// # Arguments:
+    /// * `sensor` - Source of readings
+    ///
+    /// * `limit` - Reading above which scrubbing is throttled
+    ///
+    /// * `resume` - Reading at or below which full rate resumes
+    ///
+    /// * `throttled_rate` - Fraction of the full rate used while
+    ///   throttled. Zero pauses scrubbing.
+    pub fn new(
+        sensor: T,
+        limit: f64,
+        resume: f64,
+        throttled_rate: f64,
+    ) -> SensorThrottle<T> {
+        SensorThrottle {
+            sensor,
+            limit,
+            resume,
+            throttled_rate: throttled_rate.clamp(0.0, 1.0),
+            throttled: false,
+        }
+    }
+
+    /// Returns whether scrubbing is currently throttled
+    pub fn is_throttled(&self) -> bool {
+        self.throttled
+    }
+}
+
+impl<T: Sensor> ThrottlePolicy for SensorThrottle<T> {
+    fn rate(&mut self, _now: Instant) -> f64 {
+        // If the sensor can't be read, stay in the current state
+        if let Ok(value) = self.sensor.read() {
+            if value > self.limit {
+                self.throttled = true;
+            } else if value <= self.resume {
+                self.throttled = false;
+            }
+        }
+
+        match self.throttled {
+            true => self.throttled_rate,
+            false => 1.0,
+        }
+    }
+}
+
+/// Halves the scrub rate each time a measure of interference with the
+/// foreground work is over a threshold, then restores it gradually while
+/// the measure stays at or under it
+///
+/// * `sensor` - Source of readings of the interference
+///
+/// * `threshold` - Reading above which the rate is halved
+///
+/// * `min_rate` - Lowest fraction of the full rate used
+///
+/// * `recovery` - Fraction of the full rate restored per second
+///
+/// * `rate` - Fraction of the full rate currently used
+///
+/// * `last` - Time the rate was last computed
+pub struct ImpactGuard<T: Sensor> {
+    sensor: T,
+    threshold: f64,
+    min_rate: f64,
+    recovery: f64,
+    rate: f64,
+    last: Option<Instant>,
+}
+
+impl<T: Sensor> ImpactGuard<T> {
+    /// Create a new ImpactGuard
+    ///
+    /// # Arguments:
+    /// * `sensor` - Source of readings of the interference, such as a
+    ///   callback returning the foreground latency
+    ///
+    /// * `threshold` - Reading above which the rate is halved
+    ///
+    /// * `min_rate` - Lowest fraction of the full rate used. Zero allows
+    ///   scrubbing to be paused.
+    ///
+    /// * `recovery` - Fraction of the full rate restored per second while
+    ///   the reading is at or under the threshold
+    pub fn new(
+        sensor: T,
+        threshold: f64,
+        min_rate: f64,
+        recovery: f64,
+    ) -> ImpactGuard<T> {
+        ImpactGuard {
+            sensor,
+            threshold,
+            min_rate: min_rate.clamp(0.0, 1.0),
+            recovery: recovery.max(0.0),
+            rate: 1.0,
+            last: None,
+        }
+    }
+
+    /// Returns the fraction of the full rate currently used
+    pub fn current_rate(&self) -> f64 {
+        self.rate
+    }
+}
+
+impl<T: Sensor> ThrottlePolicy for ImpactGuard<T> {
+    fn rate(&mut self, now: Instant) -> f64 {
+        let elapsed = match self.last {
+            Some(last) => now.saturating_duration_since(last),
+            None => Duration::ZERO,
+        };
+        self.last = Some(now);
+
+        // If the sensor can't be read, keep the current rate
+        if let Ok(value) = self.sensor.read() {
+            self.rate = match value > self.threshold {
+                true => self.rate / 2.0,
+                false => self.rate + self.recovery * elapsed.as_secs_f64(),
+            }
+            .clamp(self.min_rate, 1.0);
+        }
+        self.rate
+    }
+}
+
+/// A ThrottlePolicy whose rates can also be read through RateReaders
+///
+/// * `policy` - The policy giving the rates
+///
+/// * `rate` - The last rate given, shared with the readers
+pub struct SharedThrottle<'a> {
+    policy: Box<dyn ThrottlePolicy + 'a>,
+    rate: Rc<Cell<f64>>,
+}
+
+impl<'a> SharedThrottle<'a> {
+    /// Create a SharedThrottle, whose rate is 1.0 until it is first asked
+    ///
+    /// # Arguments:
+    /// * `policy` - The policy giving the rates
+    pub fn new(
+        policy: Box<dyn ThrottlePolicy + 'a>,
+    ) -> SharedThrottle<'a> {
+        SharedThrottle {
+            policy,
+            rate: Rc::new(Cell::new(1.0)),
+        }
+    }
+
+    /// Returns a reader of the last rate given
+    pub fn reader(&self) -> RateReader {
+        RateReader(self.rate.clone())
+    }
+}
+
+impl ThrottlePolicy for SharedThrottle<'_> {
+    fn rate(&mut self, now: Instant) -> f64 {
+        let rate = self.policy.rate(now);
+        self.rate.set(rate);
+        rate
+    }
+}
+
+/// A ThrottlePolicy giving the last rate a SharedThrottle gave, without
+/// asking its policy again
+#[derive(Clone, Debug)]
+pub struct RateReader(Rc<Cell<f64>>);
+
+impl RateReader {
+    /// Returns the last rate given
+    pub fn get(&self) -> f64 {
+        self.0.get()
+    }
+}
+
+impl ThrottlePolicy for RateReader {
+    fn rate(&mut self, _now: Instant) -> f64 {
+        self.0.get()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testutil::*;
+    use std::cell::Cell;
+
+    #[test]
+    fn test_throttle_delay() {
+        let chunk = Duration::from_millis(10);
+        assert_eq!(throttle_delay(1.0, chunk), Some(Duration::ZERO));
+        assert_eq!(
+            throttle_delay(0.25, chunk),
+            Some(Duration::from_millis(30))
+        );
+        assert_eq!(throttle_delay(0.0, chunk), None);
+    }
+
+    #[test]
+    fn test_jitter() {
+        let delay = Duration::from_millis(30);
+        let chunk = Duration::from_millis(10);
+        let mut jitter = Jitter::with_seed(0.5, 42);
+        let delays: Vec<Duration> =
+            (0..100).map(|_| jitter.apply(delay, chunk)).collect();
+        assert!(delays.iter().all(|d| *d >= delay));
+        assert!(delays.iter().all(|d| *d < Duration::from_millis(50)));
+        assert!(delays.iter().any(|d| *d != delays[0]));
+
+        let mut a = Jitter::with_seed(0.5, 7);
+        let mut b = Jitter::with_seed(0.5, 7);
+        assert_eq!(a.apply(delay, chunk), b.apply(delay, chunk));
+        let mut none = Jitter::with_seed(0.0, 7);
+        assert_eq!(none.apply(delay, chunk), delay);
+
+        // A hardware generator giving only zeroes adds no jitter
+        let mut zeroes = Jitter::with_rng(0.5, Box::new(|| 0));
+        assert_eq!(zeroes.apply(delay, chunk), delay);
+    }
+
+    #[test]
+    fn test_sensor_throttle() {
+        let temp = Cell::new(50.0);
+        let sensor = || -> io::Result<f64> { Ok(temp.get()) };
+        let mut policy = SensorThrottle::new(sensor, 80.0, 70.0, 0.5);
+        let now = Instant::now();
+
+        assert_eq!(policy.rate(now), 1.0);
+        temp.set(85.0);
+        assert_eq!(policy.rate(now), 0.5);
+        temp.set(75.0);
+        assert_eq!(policy.rate(now), 0.5);
+        temp.set(70.0);
+        assert_eq!(policy.rate(now), 1.0);
+        assert!(!policy.is_throttled());
+    }
+
+    #[test]
+    fn test_impact_guard() {
+        let latency = Cell::new(1.0);
+        let sensor = || -> io::Result<f64> { Ok(latency.get()) };
+        let mut policy = ImpactGuard::new(sensor, 5.0, 0.1, 0.05);
+        let start = Instant::now();
+        let at = |secs: u64| start + Duration::from_secs(secs);
+
+        assert_eq!(policy.rate(at(0)), 1.0);
+
+        // Each reading over the threshold halves the rate, down to the
+        // minimum
+        latency.set(8.0);
+        assert_eq!(policy.rate(at(1)), 0.5);
+        assert_eq!(policy.rate(at(2)), 0.25);
+        assert_eq!(policy.rate(at(3)), 0.125);
+        assert_eq!(policy.rate(at(4)), 0.1);
+
+        // Then it recovers at 5% a second
+        latency.set(2.0);
+        assert!((policy.rate(at(6)) - 0.2).abs() < 1e-9);
+        assert_eq!(policy.rate(at(30)), 1.0);
+        assert_eq!(policy.current_rate(), 1.0);
+    }
+
+    #[test]
+    fn test_hwmon() {
+        let dir = scratch_path("hwmon");
+        let hwmon = dir.join("hwmon3");
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&hwmon).unwrap();
+        fs::write(hwmon.join("name"), "coretemp\n").unwrap();
+        fs::write(hwmon.join("temp1_input"), "54500\n").unwrap();
+
+        let mut sensor = HwmonTemp::find_in(&dir, "coretemp").unwrap();
+        assert_eq!(sensor.read().unwrap(), 54.5);
+        assert!(HwmonTemp::find_in(&dir, "k10temp").is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_rapl() {
+        let path = scratch_path("rapl");
+        fs::write(&path, "1000000\n").unwrap();
+        let mut sensor = RaplPower::with_path(&path);
+        assert_eq!(sensor.read().unwrap(), 0.0);
+        std::thread::sleep(Duration::from_millis(10));
+        fs::write(&path, "2000000\n").unwrap();
+        assert!(sensor.read().unwrap() > 0.0);
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,340 @@
+// Sampled accounting of how often each cache line is touched. The tests
+// count the reads of every line to check that a pass reaches each line
+// exactly once, but a counter per line costs too much memory outside of
+// tests. A TouchSampler instead picks a random sample of lines at the
+// start of each pass and counts how often the pass touches those alone.
+// A sampled line not touched, or touched more than once, in a completed
+// pass points to a bug in the ordering of the lines. With a sample of n
+// lines, a bug affecting a fraction f of the lines goes unseen in a pass
+// with a probability of about (1 - f)^n, and a new sample is taken for
+// each pass, so even a small sample finds such bugs over a few passes.
+//
+// A line is touched when the pass reaches it, whether it is read or left
+// out, as when excluded or rejected by the validator. The extra reads of
+// boosted areas are not part of the pass and are not counted.
+//
+// LineScrubber::set_touch_sampling() turns the sampling on. Unless the
+// current pass has only just started, sampling starts with the next pass,
+// since the lines the current one has already reached aren't known.
+
+use std::fmt;
+
+use crate::base::*;
+use crate::rng::*;
+
+/// The touches of the sampled lines in a completed pass
+///
+/// * `epoch` - Epoch of the pass
+///
+/// * `sampled` - Number of lines sampled
+///
+/// * `missed` - Addresses of the sampled lines not touched
+///
+/// * `repeated` - Addresses of the sampled lines touched more than once,
+///   with the number of times each was touched
+#[derive(Clone, Debug, PartialEq)]
+pub struct TouchReport {
+    pub epoch: u64,
+    pub sampled: usize,
+    pub missed: Vec<usize>,
+    pub repeated: Vec<(usize, u32)>,
+}
+
+impl TouchReport {
+    /// Returns whether every sampled line was touched exactly once
+    pub fn is_clean(&self) -> bool {
+        self.missed.is_empty() && self.repeated.is_empty()
+    }
+}
+
+/// Totals of the touches of the sampled lines over all completed passes
+///
+/// * `passes` - Number of passes sampled
+///
+/// * `sampled` - Number of lines sampled
+///
+/// * `missed` - Number of sampled lines not touched
+///
+/// * `repeated` - Number of sampled lines touched more than once
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TouchTotals {
+    pub passes: u64,
+    pub sampled: u64,
+    pub missed: u64,
+    pub repeated: u64,
+}
+
+impl TouchTotals {
+    /// Returns the estimated fraction of lines not touched by a pass
+    pub fn missed_fraction(&self) -> f64 {
+        match self.sampled {
+            0 => 0.0,
+            sampled => self.missed as f64 / sampled as f64,
+        }
+    }
+}
+
+/// Counts the touches of a random sample of cache lines in each pass
+///
+/// * `lines` - Number of lines to sample in each pass
+///
+/// * `rng` - Source of random numbers for the sample
+///
+/// * `epoch` - Epoch of the pass being sampled, or None until the next
+///   pass starts
+///
+/// * `sample` - Addresses of the sampled lines, sorted
+///
+/// * `counts` - Number of touches of each sampled line
+///
+/// * `last` - Report of the last completed pass, if any
+///
+/// * `totals` - Totals over all completed passes
+#[derive(Clone)]
+pub struct TouchSampler {
+    lines: usize,
+    rng: Box<dyn CloneRng>,
+    epoch: Option<u64>,
+    sample: Vec<usize>,
+    counts: Vec<u32>,
+    last: Option<TouchReport>,
+    totals: TouchTotals,
+}
+
+impl fmt::Debug for TouchSampler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TouchSampler")
+            .field("lines", &self.lines)
+            .field("epoch", &self.epoch)
+            .field("sample", &self.sample)
+            .field("counts", &self.counts)
+            .field("last", &self.last)
+            .field("totals", &self.totals)
+            .finish_non_exhaustive()
+    }
+}
+
+impl TouchSampler {
+    /// Create a TouchSampler seeded from the time and process ID, so that
+    /// each instance samples different lines
+    ///
+    /// # Arguments:
+    /// * `lines` - Number of lines to sample in each pass. Fewer are
+    ///   sampled if a pass has fewer lines or the same line is picked
+    ///   twice.
+    ///
+    /// # Returns:
+    /// Ok(TouchSampler) on success, otherwise Err(Error::ZeroSize) if no
+    /// lines are to be sampled
+    pub fn new(lines: usize) -> Result<TouchSampler, Error> {
+        TouchSampler::with_rng(lines, Box::new(XorShift64::from_time()))
+    }
+
+    /// Create a TouchSampler with a given seed, for a reproducible sample
+    pub fn with_seed(
+        lines: usize,
+        seed: u64,
+    ) -> Result<TouchSampler, Error> {
+        TouchSampler::with_rng(lines, Box::new(XorShift64::new(seed)))
+    }
+
+    /// Create a TouchSampler drawing on a source of random numbers, such
+    /// as a hardware random number generator
+    ///
+    /// # Arguments:
+    /// * `lines` - Number of lines to sample in each pass
+    ///
+    /// * `rng` - Source of random numbers
+    ///
+    /// # Returns:
+    /// Ok(TouchSampler) on success, otherwise Err(Error::ZeroSize) if no
+    /// lines are to be sampled
+    pub fn with_rng(
+        lines: usize,
+        rng: Box<dyn CloneRng>,
+    ) -> Result<TouchSampler, Error> {
+        if lines == 0 {
+            return Err(Error::ZeroSize);
+        }
+        Ok(TouchSampler {
+            lines,
+            rng,
+            epoch: None,
+            sample: Vec::new(),
+            counts: Vec::new(),
+            last: None,
+            totals: TouchTotals::default(),
+        })
+    }
+
+    /// Returns the number of lines to sample in each pass
+    pub fn lines(&self) -> usize {
+        self.lines
+    }
+
+    /// Returns the addresses of the lines sampled in the current pass,
+    /// sorted, which is empty until a pass has started
+    pub fn sample(&self) -> &[usize] {
+        &self.sample
+    }
+
+    /// Returns the report of the last completed pass, if any
+    pub fn last(&self) -> Option<&TouchReport> {
+        self.last.as_ref()
+    }
+
+    /// Returns the totals over all completed passes
+    pub fn totals(&self) -> TouchTotals {
+        self.totals
+    }
+
+    /// Start sampling a pass, picking the lines to sample
+    ///
+    /// # Arguments:
+    /// * `areas` - (start, end) of each area the pass reads, end inclusive
+    ///
+    /// * `line_size` - Number of bytes in a cache line
+    ///
+    /// * `epoch` - Epoch of the pass
+    pub fn start_pass(
+        &mut self,
+        areas: &[(usize, usize)],
+        line_size: usize,
+        epoch: u64,
+    ) {
+        // Lines before each area, so a line number can be turned into an
+        // address
+        let mut before = Vec::with_capacity(areas.len());
+        let mut total = 0;
+        for &(start, end) in areas {
+            before.push(total);
+            total += (end - start + 1) / line_size;
+        }
+
+        self.sample.clear();
+        if total != 0 {
+            for _ in 0..self.lines {
+                let line = self.rng.below(total as u64) as usize;
+                let i = before.partition_point(|&b| b <= line) - 1;
+                self.sample
+                    .push(areas[i].0 + (line - before[i]) * line_size);
+            }
+        }
+        self.sample.sort_unstable();
+        self.sample.dedup();
+        self.counts = vec![0; self.sample.len()];
+        self.epoch = Some(epoch);
+    }
+
+    /// Count a touch of a line in the current pass
+    ///
+    /// # Arguments:
+    /// * `addr` - Address of the start of the line
+    pub fn touch(&mut self, addr: usize) {
+        if let Ok(i) = self.sample.binary_search(&addr) {
+            self.counts[i] = self.counts[i].saturating_add(1);
+        }
+    }
+
+    /// Finish sampling the current pass, adding it to the totals, if one
+    /// has started
+    ///
+    /// # Returns:
+    /// The report of the pass, or None if no pass was being sampled
+    pub fn finish_pass(&mut self) -> Option<&TouchReport> {
+        let epoch = self.epoch.take()?;
+        let mut report = TouchReport {
+            epoch,
+            sampled: self.sample.len(),
+            missed: Vec::new(),
+            repeated: Vec::new(),
+        };
+        for (&addr, &count) in self.sample.iter().zip(&self.counts) {
+            match count {
+                0 => report.missed.push(addr),
+                1 => {}
+                n => report.repeated.push((addr, n)),
+            }
+        }
+        self.totals.passes += 1;
+        self.totals.sampled += report.sampled as u64;
+        self.totals.missed += report.missed.len() as u64;
+        self.totals.repeated += report.repeated.len() as u64;
+        self.sample.clear();
+        self.counts.clear();
+        self.last = Some(report);
+        self.last.as_ref()
+    }
+
+    /// Stop sampling the current pass without reporting it, as when it is
+    /// restarted. Sampling starts again with the next pass.
+    pub fn abandon_pass(&mut self) {
+        self.epoch = None;
+        self.sample.clear();
+        self.counts.clear();
+    }
+
+    // Returns the address and size of the buffers of the sample, so that
+    // scrubbing can leave them out
+    pub(crate) fn buffers(&self) -> [(usize, usize); 2] {
+        [
+            (
+                self.sample.as_ptr() as usize,
+                self.sample.capacity() * size_of::<usize>(),
+            ),
+            (
+                self.counts.as_ptr() as usize,
+                self.counts.capacity() * size_of::<u32>(),
+            ),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sampler() {
+        assert_eq!(
+            TouchSampler::with_seed(0, 1).unwrap_err(),
+            Error::ZeroSize
+        );
+        let mut sampler = TouchSampler::with_seed(8, 42).unwrap();
+        assert!(sampler.finish_pass().is_none());
+
+        let areas = [(0x1000, 0x1fff), (0x8000, 0x87ff)];
+        sampler.start_pass(&areas, 64, 3);
+        let sample = sampler.sample().to_vec();
+        assert!(!sample.is_empty() && sample.len() <= 8);
+        assert!(sample.iter().all(|&a| a % 64 == 0
+            && areas.iter().any(|&(s, e)| s <= a && a <= e)));
+
+        // Touch every sampled line once, but the first twice and the last
+        // not at all
+        sampler.touch(sample[0]);
+        for &addr in &sample[..sample.len() - 1] {
+            sampler.touch(addr);
+        }
+        sampler.touch(0x40);
+        let report = sampler.finish_pass().unwrap().clone();
+        assert_eq!(report.epoch, 3);
+        assert_eq!(report.sampled, sample.len());
+        assert_eq!(report.missed, [sample[sample.len() - 1]]);
+        assert_eq!(report.repeated, [(sample[0], 2)]);
+        assert!(!report.is_clean());
+
+        let totals = sampler.totals();
+        assert_eq!(
+            (totals.passes, totals.missed, totals.repeated),
+            (1, 1, 1)
+        );
+        assert_eq!(totals.missed_fraction(), 1.0 / sample.len() as f64);
+
+        // An abandoned pass isn't reported
+        sampler.start_pass(&areas, 64, 4);
+        sampler.abandon_pass();
+        assert!(sampler.finish_pass().is_none());
+        assert_eq!(sampler.totals().passes, 1);
+    }
+}
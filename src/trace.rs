@@ -0,0 +1,274 @@
+// Golden traces of scrub address sequences. A trace records the exact
+// sequence of addresses touched in a pass so that a later pass, or a later
+// version of the scrubbing code, can be checked against it. This catches
+// changes in the order of scrubbing that would otherwise go unnoticed.
+//
+// Successive addresses are usually a fixed stride apart, so each address is
+// stored as the zigzag-encoded difference from the previous one, written as
+// a LEB128 variable length integer. A pass over gigabytes of memory then
+// takes a few bytes per cache line.
+
+/// A recorded sequence of addresses
+///
+/// * `len` - Number of addresses recorded
+///
+/// * `last` - The most recently recorded address
+///
+/// * `bytes` - The encoded differences between successive addresses
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct ScrubTrace {
+    len: usize,
+    last: usize,
+    bytes: Vec<u8>,
+}
+
+/// Where a sequence of addresses first differs from a trace
+///
+/// * `position` - Index in the sequence of the first difference
+///
+/// * `expected` - Address in the trace, None if the trace ended first
+///
+/// * `found` - Address in the sequence, None if the sequence ended first
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TraceMismatch {
+    pub position: usize,
+    pub expected: Option<usize>,
+    pub found: Option<usize>,
+}
+
+impl ScrubTrace {
+    /// Create an empty trace
+    pub fn new() -> ScrubTrace {
+        ScrubTrace::default()
+    }
+
+    /// Record every address from an iterator, such as a ScrubOrder
+    pub fn record<I>(addrs: I) -> ScrubTrace
+    where
+        I: IntoIterator<Item = usize>,
+    {
+        let mut trace = ScrubTrace::new();
+        for addr in addrs {
+            trace.push(addr);
+        }
+        trace
+    }
+
+    /// Append an address to the trace
+    pub fn push(&mut self, addr: usize) {
+        let delta = addr.wrapping_sub(self.last) as isize;
+        let mut zigzag =
+            ((delta << 1) ^ (delta >> (isize::BITS - 1))) as usize;
+        loop {
+            let byte = (zigzag & 0x7f) as u8;
+            zigzag >>= 7;
+            if zigzag == 0 {
+                self.bytes.push(byte);
+                break;
+            }
+            self.bytes.push(byte | 0x80);
+        }
+        self.last = addr;
+        self.len += 1;
+    }
+
+    /// Returns the number of addresses in the trace
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Returns whether the trace is empty
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the recorded addresses in order
+    pub fn iter(&self) -> TraceIter<'_> {
+        TraceIter {
+            bytes: &self.bytes,
+            addr: 0,
+            remaining: self.len,
+        }
+    }
+
+    /// Check that a sequence of addresses is identical to the trace
+    ///
+    /// # Arguments:
+    /// * `addrs` - The addresses to check
+    ///
+    /// # Returns:
+    /// Ok(()) if the sequences match, otherwise the first difference
+    pub fn verify<I>(&self, addrs: I) -> Result<(), TraceMismatch>
+    where
+        I: IntoIterator<Item = usize>,
+    {
+        let mut expected = self.iter();
+        let mut found = addrs.into_iter();
+        let mut position = 0;
+        loop {
+            match (expected.next(), found.next()) {
+                (None, None) => return Ok(()),
+                (e, f) if e != f => {
+                    return Err(TraceMismatch {
+                        position,
+                        expected: e,
+                        found: f,
+                    })
+                }
+                _ => position += 1,
+            }
+        }
+    }
+
+    /// Returns the trace in a compact form for storage. This is the number
+    /// of addresses, as eight little-endian bytes, followed by the encoded
+    /// differences.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(8 + self.bytes.len());
+        bytes.extend_from_slice(&(self.len as u64).to_le_bytes());
+        bytes.extend_from_slice(&self.bytes);
+        bytes
+    }
+
+    /// Read a trace stored with to_bytes()
+    ///
+    /// # Returns:
+    /// The trace, or None if the bytes are not a valid trace
+    pub fn from_bytes(bytes: &[u8]) -> Option<ScrubTrace> {
+        let (len, rest) = bytes.split_first_chunk::<8>()?;
+        let len = usize::try_from(u64::from_le_bytes(*len)).ok()?;
+        let mut trace = ScrubTrace {
+            len,
+            last: 0,
+            bytes: rest.to_vec(),
+        };
+
+        // Decoding checks that the trace holds exactly len addresses
+        let mut iter = trace.iter();
+        let mut last = 0;
+        for _ in 0..len {
+            last = iter.next()?;
+        }
+        if !iter.bytes.is_empty() {
+            return None;
+        }
+        trace.last = last;
+        Some(trace)
+    }
+}
+
+/// Iterator over the addresses in a ScrubTrace
+pub struct TraceIter<'a> {
+    bytes: &'a [u8],
+    addr: usize,
+    remaining: usize,
+}
+
+impl Iterator for TraceIter<'_> {
+    type Item = usize;
+
+    fn next(&mut self) -> Option<usize> {
+        if self.remaining == 0 {
+            return None;
+        }
+
+        let mut zigzag = 0usize;
+        let mut shift = 0;
+        loop {
+            let (&byte, rest) = self.bytes.split_first()?;
+            self.bytes = rest;
+            if shift >= usize::BITS {
+                return None;
+            }
+            zigzag |= ((byte & 0x7f) as usize) << shift;
+            shift += 7;
+            if byte & 0x80 == 0 {
+                break;
+            }
+        }
+
+        let delta = ((zigzag >> 1) as isize) ^ -((zigzag & 1) as isize);
+        self.addr = self.addr.wrapping_add(delta as usize);
+        self.remaining -= 1;
+        Some(self.addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dryrun::*;
+
+    fn order() -> ScrubOrder {
+        ScrubOrder::new(&[(0, 511), (1152, 1407)], 64, 2).unwrap()
+    }
+
+    #[test]
+    fn test_record() {
+        let trace = ScrubTrace::record(order());
+        assert_eq!(trace.len(), 12);
+        assert_eq!(
+            trace.iter().collect::<Vec<_>>(),
+            order().collect::<Vec<_>>()
+        );
+        assert_eq!(trace.verify(order()), Ok(()));
+
+        let trace = ScrubTrace::record([usize::MAX, 0, 1 << 40, 64]);
+        assert_eq!(
+            trace.iter().collect::<Vec<_>>(),
+            vec![usize::MAX, 0, 1 << 40, 64]
+        );
+    }
+
+    #[test]
+    fn test_golden() {
+        // Changing the order in which ScrubOrder visits addresses breaks
+        // this
+        let golden = [
+            12, 0, 0, 0, 0, 0, 0, 0, 0, 128, 4, 128, 16, 255, 18, 128, 4,
+            128, 16, 255, 18, 128, 4, 128, 12, 255, 14, 128, 4, 128, 12,
+        ];
+        let trace = ScrubTrace::from_bytes(&golden).unwrap();
+        assert_eq!(trace.verify(order()), Ok(()));
+        assert_eq!(ScrubTrace::record(order()).to_bytes(), golden);
+    }
+
+    #[test]
+    fn test_mismatch() {
+        let trace = ScrubTrace::record(order());
+
+        let mut addrs: Vec<usize> = order().collect();
+        addrs.swap(3, 4);
+        assert_eq!(
+            trace.verify(addrs),
+            Err(TraceMismatch {
+                position: 3,
+                expected: Some(64),
+                found: Some(320),
+            })
+        );
+
+        assert_eq!(
+            trace.verify(order().take(11)),
+            Err(TraceMismatch {
+                position: 11,
+                expected: Some(1216),
+                found: None,
+            })
+        );
+        assert_eq!(
+            trace.verify(order().chain([0])),
+            Err(TraceMismatch {
+                position: 12,
+                expected: None,
+                found: Some(0),
+            })
+        );
+
+        let bytes = trace.to_bytes();
+        assert!(
+            ScrubTrace::from_bytes(&bytes[..bytes.len() - 1]).is_none()
+        );
+        assert!(ScrubTrace::from_bytes(&bytes[..4]).is_none());
+    }
+}
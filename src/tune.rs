@@ -0,0 +1,252 @@
+// Tuning the scrub rate from how far the current pass is from its
+// deadline. DeadlineTuner keeps a chunk size and, each time it is asked
+// for a chunk, compares the fraction of the pass done with the fraction of
+// the pass period that has gone by. When the pass is behind, the chunk
+// grows; when it is ahead, it shrinks. The chunk carries over from pass to
+// pass, so a steady load settles on a steady size, and a transient load
+// that slows scrubbing is made up for before the deadline.
+//
+// Each adjustment is limited to a factor of two either way so that the
+// loop stays stable when chunks are asked for rarely.
+
+use std::time::Duration;
+
+use crate::addr::*;
+use crate::base::*;
+use crate::clock::*;
+use crate::data::*;
+use crate::stats::*;
+
+/// Default gain of a DeadlineTuner
+pub const TUNER_GAIN: f64 = 4.0;
+
+// Largest change in the chunk size made by one adjustment
+const MAX_STEP: f64 = 2.0;
+
+/// An AutoScrubDesc that sizes chunks to finish each pass by its
+/// deadline, correcting for being behind or ahead of schedule
+///
+/// * `pass_period` - Time allowed for each pass
+///
+/// * `chunk` - Current chunk size
+///
+/// * `min_chunk` - Smallest chunk to scrub
+///
+/// * `max_chunk` - Largest chunk to scrub
+///
+/// * `align` - Chunk sizes are rounded up to a multiple of this, normally
+///   the cache line size
+///
+/// * `gain` - How strongly the chunk size reacts to the schedule error
+///
+/// * `clock` - Source of time
+pub struct DeadlineTuner<C: Clock> {
+    pass_period: Duration,
+    chunk: usize,
+    min_chunk: usize,
+    max_chunk: usize,
+    align: usize,
+    gain: f64,
+    clock: C,
+}
+
+impl<C: Clock> DeadlineTuner<C> {
+    /// Create a new DeadlineTuner
+    ///
+    /// # Arguments:
+    /// * `pass_period` - Time allowed for each pass
+    ///
+    /// * `chunk` - Chunk size to start with
+    ///
+    /// * `align` - Chunk sizes are rounded up to a multiple of this,
+    ///   normally the cache line size. This is also the smallest chunk.
+    ///
+    /// * `clock` - Source of time
+    pub fn new(
+        pass_period: Duration,
+        chunk: usize,
+        align: usize,
+        clock: C,
+    ) -> DeadlineTuner<C> {
+        let align = align.max(1);
+        DeadlineTuner {
+            pass_period,
+            chunk: chunk.max(align).next_multiple_of(align),
+            min_chunk: align,
+            max_chunk: usize::MAX - usize::MAX % align,
+            align,
+            gain: TUNER_GAIN,
+            clock,
+        }
+    }
+
+    /// Set the smallest and largest chunk sizes. The smallest is rounded
+    /// up, and the largest down, to a multiple of the alignment.
+    ///
+    /// # Arguments:
+    /// * `min` - Smallest chunk size
+    ///
+    /// * `max` - Largest chunk size
+    ///
+    /// # Returns:
+    /// Ok(()) on success, otherwise Err(Error::InvertedRange) if, once
+    /// rounded, the smallest is larger than the largest
+    pub fn set_chunk_limits(
+        &mut self,
+        min: usize,
+        max: usize,
+    ) -> Result<(), Error> {
+        (self.min_chunk, self.max_chunk) =
+            chunk_limits(min, max, self.align)?;
+        self.chunk = self.chunk.clamp(self.min_chunk, self.max_chunk);
+        Ok(())
+    }
+
+    /// Set the gain, the relative change in the chunk size for a schedule
+    /// error of the whole pass. The default is TUNER_GAIN.
+    pub fn set_gain(&mut self, gain: f64) {
+        self.gain = gain.max(0.0);
+    }
+
+    /// Returns the current chunk size
+    pub fn chunk(&self) -> usize {
+        self.chunk
+    }
+
+    /// Returns how far the pass is behind schedule, as a fraction of the
+    /// pass. This is negative when the pass is ahead of schedule.
+    ///
+    /// # Arguments:
+    /// * `stats` - Statistics for the scrubbing done so far
+    pub fn schedule_error(&self, stats: &ScrubStats) -> f64 {
+        if stats.pass_size == 0 {
+            return 0.0;
+        }
+        let elapsed = self
+            .clock
+            .now()
+            .saturating_duration_since(stats.pass_started);
+        let expected = match self.pass_period.is_zero() {
+            true => 1.0,
+            false => (elapsed.as_secs_f64()
+                / self.pass_period.as_secs_f64())
+            .min(1.0),
+        };
+        expected - stats.pass_offset as f64 / stats.pass_size as f64
+    }
+
+    /// Returns the number of bytes to scrub next, adjusted for the
+    /// progress of the pass. Without statistics, the current chunk size is
+    /// used unchanged.
+    ///
+    /// # Arguments:
+    /// * `stats` - Statistics for the scrubbing done so far
+    pub fn next_chunk(&mut self, stats: Option<&ScrubStats>) -> usize {
+        let stats = match stats {
+            Some(stats) if stats.pass_size != 0 => stats,
+            _ => return self.chunk,
+        };
+
+        let factor = (1.0 + self.gain * self.schedule_error(stats))
+            .clamp(1.0 / MAX_STEP, MAX_STEP);
+        let chunk = (self.chunk as f64 * factor).round();
+        let chunk = match chunk >= self.max_chunk as f64 {
+            true => self.max_chunk,
+            false => (chunk as usize).next_multiple_of(self.align),
+        };
+        self.chunk = chunk.clamp(self.min_chunk, self.max_chunk);
+        self.chunk
+    }
+}
+
+// Returns the smallest and largest chunk sizes, rounded up and down to a
+// multiple of the alignment and no smaller than it, or
+// Err(Error::InvertedRange) if the smallest is then larger than the
+// largest
+pub(crate) fn chunk_limits(
+    min: usize,
+    max: usize,
+    align: usize,
+) -> Result<(usize, usize), Error> {
+    let max = max - max % align;
+    match min.max(align).checked_next_multiple_of(align) {
+        Some(min) if min <= max => Ok((min, max)),
+        _ => Err(Error::InvertedRange),
+    }
+}
+
+impl<const N: usize, const S: usize, const W: usize, D, A, C: Clock>
+    AutoScrubDesc<N, S, W, D, A> for DeadlineTuner<C>
+where
+    D: DataImplTrait<D>,
+    A: AddrImplTrait<A>,
+{
+    fn next(&mut self) -> Addr<A> {
+        self.next_chunk(None).into()
+    }
+
+    fn next_with(&mut self, stats: &ScrubStats) -> Addr<A> {
+        self.next_chunk(Some(stats)).into()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::time::{Instant, SystemTime};
+
+    #[test]
+    fn test_tuner() {
+        let start = Instant::now();
+        let clock = VirtualClock::at(start, SystemTime::now());
+        let mut tuner =
+            DeadlineTuner::new(Duration::from_secs(100), 4096, 64, clock);
+        tuner.set_chunk_limits(1024, 16384).unwrap();
+        let mut stats = ScrubStats::new(&[1 << 20], start);
+
+        // On schedule, the chunk is left alone
+        assert_eq!(tuner.next_chunk(Some(&stats)), 4096);
+
+        // A quarter of the pass behind, the chunk doubles
+        tuner.clock.sleep(Duration::from_secs(25));
+        assert_eq!(tuner.schedule_error(&stats), 0.25);
+        assert_eq!(tuner.next_chunk(Some(&stats)), 8192);
+
+        // Slightly behind, the chunk grows a little and stays aligned
+        stats.record_chunk(256 << 10, Duration::ZERO, start);
+        tuner.clock.sleep(Duration::from_secs(1));
+        assert_eq!(tuner.next_chunk(Some(&stats)), 8576);
+
+        // Ahead of schedule, it shrinks, but not below the minimum
+        stats.record_chunk(512 << 10, Duration::ZERO, start);
+        assert_eq!(tuner.next_chunk(Some(&stats)), 4288);
+        assert_eq!(tuner.next_chunk(Some(&stats)), 2176);
+        assert_eq!(tuner.next_chunk(Some(&stats)), 1088);
+        assert_eq!(tuner.next_chunk(Some(&stats)), 1024);
+
+        // Past the deadline, it grows up to the maximum
+        tuner.clock.sleep(Duration::from_secs(100));
+        for _ in 0..5 {
+            tuner.next_chunk(Some(&stats));
+        }
+        assert_eq!(tuner.chunk(), 16384);
+        assert_eq!(tuner.next_chunk(None), 16384);
+
+        // Limits are rounded in to the alignment
+        tuner.set_chunk_limits(1000, 2100).unwrap();
+        assert_eq!(tuner.chunk(), 2048);
+        assert_eq!(
+            tuner.set_chunk_limits(1000, 1000),
+            Err(Error::InvertedRange)
+        );
+        assert_eq!(
+            tuner.set_chunk_limits(4096, 1024),
+            Err(Error::InvertedRange)
+        );
+        assert_eq!(
+            tuner.set_chunk_limits(usize::MAX, usize::MAX),
+            Err(Error::InvertedRange)
+        );
+    }
+}
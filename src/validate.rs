@@ -0,0 +1,206 @@
+// Checking that memory can be read before reading it. Scrub areas can go
+// stale, as when memory they describe is unmapped, and reading such an
+// address kills the process. A LineScrubber given a ReadValidator asks it
+// about each cache line first and skips, and reports, lines that can't be
+// read instead of faulting on them.
+//
+// On Linux, MincoreValidator reads the readable mappings of the process
+// from /proc/self/maps once a pass, and asks the kernel, through
+// mincore(2), about each page if they can't be read. The mappings are
+// read again at the start of each pass and whenever the scrub areas
+// change. On bare metal, a closure can check the address against
+// whatever the firmware knows about its memory map.
+
+#[cfg(target_os = "linux")]
+use crate::os::*;
+
+/// Decides whether memory can be read
+pub trait ReadValidator {
+    /// Returns whether the bytes starting at an address can be read
+    ///
+    /// # Arguments:
+    /// * `addr` - Address of the first byte
+    ///
+    /// * `len` - Number of bytes
+    fn is_readable(&mut self, addr: usize, len: usize) -> bool;
+
+    /// Forget anything remembered about the memory, as it may have been
+    /// mapped or unmapped since. Called at the start of each pass and
+    /// whenever the scrub areas change.
+    fn invalidate(&mut self) {}
+}
+
+impl<F: FnMut(usize, usize) -> bool> ReadValidator for F {
+    fn is_readable(&mut self, addr: usize, len: usize) -> bool {
+        self(addr, len)
+    }
+}
+
+/// A ReadValidator checking that each line lies in a readable mapping of
+/// the process, as listed in /proc/self/maps. The mappings are read the
+/// first time they are needed after being invalidated. Should they not be
+/// readable, each page is checked with mincore(2) instead, which finds
+/// whether it is mapped but not whether it can be read.
+///
+/// * `page_size` - Number of bytes in a page
+///
+/// * `readable` - (start, end) of each range of readable mappings,
+///   sorted, end inclusive, None if not yet read or Some(None) if
+///   /proc/self/maps can't be read
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub struct MincoreValidator {
+    page_size: usize,
+    readable: Option<Option<Vec<(usize, usize)>>>,
+}
+
+#[cfg(target_os = "linux")]
+impl MincoreValidator {
+    /// Create a validator for this process
+    pub fn new() -> MincoreValidator {
+        let page_size = unsafe { libc::sysconf(libc::_SC_PAGESIZE) };
+        MincoreValidator {
+            page_size: usize::try_from(page_size).unwrap_or(4096),
+            readable: None,
+        }
+    }
+
+    // Returns the readable ranges of the process, reading them if need
+    // be, or None if /proc/self/maps can't be read
+    fn readable(&mut self) -> Option<&[(usize, usize)]> {
+        self.readable
+            .get_or_insert_with(|| {
+                let segments = self_segments().ok()?;
+                let mut ranges: Vec<(usize, usize)> = Vec::new();
+                for segment in segments.iter().filter(|s| s.readable) {
+                    match ranges.last_mut() {
+                        Some(last)
+                            if last.1.checked_add(1)
+                                == Some(segment.start) =>
+                        {
+                            last.1 = segment.end
+                        }
+                        _ => ranges.push((segment.start, segment.end)),
+                    }
+                }
+                Some(ranges)
+            })
+            .as_deref()
+    }
+
+    // Returns whether the page at an address is mapped
+    fn is_mapped(&self, page: usize) -> bool {
+        // mincore() writes one byte for the page and fails with ENOMEM if
+        // it is not mapped
+        let mut vec = 0u8;
+        let result = unsafe {
+            libc::mincore(
+                page as *mut libc::c_void,
+                self.page_size,
+                &mut vec,
+            )
+        };
+        result == 0
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl Default for MincoreValidator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl ReadValidator for MincoreValidator {
+    fn is_readable(&mut self, addr: usize, len: usize) -> bool {
+        let last = addr.saturating_add(len.max(1) - 1);
+        if let Some(ranges) = self.readable() {
+            let i = ranges.partition_point(|&(_, end)| end < addr);
+            return ranges.get(i).is_some_and(|&(start, end)| {
+                start <= addr && last <= end
+            });
+        }
+
+        let page_mask = !(self.page_size - 1);
+        (addr & page_mask..=last & page_mask)
+            .step_by(self.page_size)
+            .all(|page| self.is_mapped(page))
+    }
+
+    fn invalidate(&mut self) {
+        self.readable = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::ptr;
+
+    #[test]
+    fn test_closure() {
+        let mut validator =
+            |addr: usize, len: usize| addr >= 4096 && addr + len <= 8192;
+        assert!(validator.is_readable(4096, 64));
+        assert!(!validator.is_readable(0, 64));
+        assert!(!validator.is_readable(8160, 64));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_mincore() {
+        let mut validator = MincoreValidator::new();
+        let page_size = validator.page_size;
+        let data = vec![0u8; 4 * page_size];
+        assert!(validator.is_readable(data.as_ptr() as usize, data.len()));
+
+        // Unmap a page and, once the validator is invalidated, it is no
+        // longer readable
+        let len = 2 * page_size;
+        let addr = unsafe {
+            libc::mmap(
+                ptr::null_mut(),
+                len,
+                libc::PROT_READ,
+                libc::MAP_PRIVATE | libc::MAP_ANONYMOUS,
+                -1,
+                0,
+            )
+        };
+        assert_ne!(addr, libc::MAP_FAILED);
+        let addr = addr as usize;
+        validator.invalidate();
+        assert!(validator.is_readable(addr, len));
+        let hole = (addr + page_size) as *mut libc::c_void;
+        unsafe { libc::munmap(hole, page_size) };
+        validator.invalidate();
+        assert!(validator.is_readable(addr, 64));
+        assert!(!validator.is_readable(addr + page_size, 64));
+        assert!(!validator.is_readable(addr, len));
+
+        // A page that can't be read isn't readable even though mapped
+        unsafe {
+            libc::mprotect(
+                addr as *mut libc::c_void,
+                page_size,
+                libc::PROT_NONE,
+            )
+        };
+        assert!(validator.is_readable(addr, 64));
+        validator.invalidate();
+        assert!(!validator.is_readable(addr, 64));
+        unsafe { libc::munmap(addr as *mut libc::c_void, page_size) };
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn test_mincore_fallback() {
+        let mut validator = MincoreValidator::new();
+        validator.readable = Some(None);
+        let page_size = validator.page_size;
+        let data = vec![0u8; 4 * page_size];
+        assert!(validator.is_readable(data.as_ptr() as usize, data.len()));
+        assert!(!validator.is_readable(0, 64));
+    }
+}
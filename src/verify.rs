@@ -0,0 +1,260 @@
+// Verify-read mode, for detecting silent corruption where there is no ECC.
+// VerifyBackend wraps a backend that can return the contents of a cache
+// line and checks each line as it is scrubbed, either by reading it twice
+// and comparing the reads, which catches bits that read inconsistently, or
+// by comparing a checksum of the line with the one taken on the previous
+// pass, which catches bits that have changed. The checksum comparison is
+// only meaningful for memory that isn't written, such as code or read-only
+// data. Each mismatch is reported to the event sinks as an uncorrected
+// error.
+
+use std::collections::HashMap;
+
+use crate::backend::*;
+use crate::base::*;
+use crate::event::*;
+
+/// How each cache line is verified
+///
+/// * `DoubleRead` - Read the line twice and compare the contents
+///
+/// * `Checksum` - Compare a checksum of the line with the one from the
+///   previous pass
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VerifyMode {
+    DoubleRead,
+    Checksum,
+}
+
+// 64-bit FNV-1a hash
+fn checksum(data: &[u8]) -> u64 {
+    data.iter().fold(0xcbf29ce484222325, |hash, &byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+/// A backend verifying the contents of each cache line it reads
+///
+/// * `backend` - Backend making the reads
+///
+/// * `mode` - How lines are verified
+///
+/// * `first` - Contents of the line from the first read
+///
+/// * `second` - Contents of the line from the second read
+///
+/// * `checksums` - Checksum of each line, by address
+///
+/// * `mismatches` - Number of mismatches found
+///
+/// * `sinks` - Receivers of an error event for each mismatch
+pub struct VerifyBackend<'a, B: SnapshotBackend> {
+    backend: B,
+    mode: VerifyMode,
+    first: Vec<u8>,
+    second: Vec<u8>,
+    checksums: HashMap<usize, u64>,
+    mismatches: u64,
+    sinks: Vec<Box<dyn EventSink + 'a>>,
+}
+
+impl<'a, B: SnapshotBackend> VerifyBackend<'a, B> {
+    /// Create a verifying backend
+    ///
+    /// # Arguments:
+    /// * `backend` - Backend making the reads
+    ///
+    /// * `line_size` - Number of bytes in a cache line
+    ///
+    /// * `mode` - How lines are verified
+    pub fn new(
+        backend: B,
+        line_size: usize,
+        mode: VerifyMode,
+    ) -> VerifyBackend<'a, B> {
+        VerifyBackend {
+            backend,
+            mode,
+            first: vec![0; line_size],
+            second: vec![0; line_size],
+            checksums: HashMap::new(),
+            mismatches: 0,
+            sinks: Vec::new(),
+        }
+    }
+
+    /// Add a sink to receive an error event for each mismatch
+    pub fn add_event_sink(&mut self, sink: Box<dyn EventSink + 'a>) {
+        self.sinks.push(sink);
+    }
+
+    /// Returns the number of mismatches found
+    pub fn mismatches(&self) -> u64 {
+        self.mismatches
+    }
+
+    /// Forget the checksums taken so far, as must be done after memory
+    /// being verified by checksum is deliberately changed
+    pub fn clear_checksums(&mut self) {
+        self.checksums.clear();
+    }
+
+    /// Returns the backend making the reads
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns the backend making the reads
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    // Report a mismatch at the given address
+    fn mismatch(&mut self, addr: usize) {
+        self.mismatches += 1;
+        let event = ScrubEvent::Error(ErrorEvent {
+            addr: addr as u64,
+            area: None,
+            severity: ErrorSeverity::Uncorrected,
+        });
+        for sink in self.sinks.iter_mut() {
+            sink.event(&event);
+        }
+    }
+}
+
+impl<B: SnapshotBackend> ScrubBackend for VerifyBackend<'_, B> {
+    fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+        self.backend.read_snapshot(addr, &mut self.first)?;
+        let matched = match self.mode {
+            VerifyMode::DoubleRead => {
+                self.backend.read_snapshot(addr, &mut self.second)?;
+                self.first == self.second
+            }
+            VerifyMode::Checksum => {
+                let sum = checksum(&self.first);
+                self.checksums
+                    .insert(addr, sum)
+                    .is_none_or(|old| old == sum)
+            }
+        };
+        if !matched {
+            self.mismatch(addr);
+        }
+        Ok(())
+    }
+
+    // The whole line is read anyway
+    fn read_words(
+        &mut self,
+        addr: usize,
+        _line_size: usize,
+        _reads: usize,
+    ) -> Result<(), Error> {
+        self.read_line(addr)
+    }
+
+    fn begin_batch(&mut self) -> Result<(), Error> {
+        self.backend.begin_batch()
+    }
+
+    fn end_batch(&mut self) -> Result<(), Error> {
+        self.backend.end_batch()
+    }
+
+    fn end_window(&mut self) -> Result<(), Error> {
+        self.backend.end_window()
+    }
+
+    fn window_size(&self) -> Option<usize> {
+        self.backend.window_size()
+    }
+
+    fn flush_line(&mut self, addr: usize) -> Result<(), Error> {
+        self.backend.flush_line(addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::sim::*;
+    use crate::testutil::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    // Memory in which one byte reads differently every other time
+    struct Flaky {
+        mem: SimMemory,
+        addr: usize,
+        flip: bool,
+    }
+
+    impl ScrubBackend for Flaky {
+        fn read_line(&mut self, addr: usize) -> Result<(), Error> {
+            self.mem.read_line(addr)
+        }
+    }
+
+    impl SnapshotBackend for Flaky {
+        fn read_snapshot(
+            &mut self,
+            addr: usize,
+            buf: &mut [u8],
+        ) -> Result<(), Error> {
+            self.mem.read_snapshot(addr, buf)?;
+            if addr == self.addr {
+                self.flip = !self.flip;
+                buf[0] ^= self.flip as u8;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_checksum() {
+        let events = Rc::new(RefCell::new(Vec::new()));
+        let mem = SimMemory::new(0x10000, 4096, 64).unwrap();
+        let extent = mem.extent();
+        let mut verify = VerifyBackend::new(mem, 64, VerifyMode::Checksum);
+        verify.add_event_sink(Box::new(Collector(events.clone())));
+        let mut scrubber =
+            LineScrubber::new(verify, &[extent], 64, 4).unwrap();
+
+        scrubber.scrub(4096).unwrap();
+        scrubber.scrub(4096).unwrap();
+        assert!(events.borrow().is_empty());
+
+        scrubber.backend_mut().backend_mut().data_mut()[0x105] = 1;
+        scrubber.scrub(4096).unwrap();
+        assert_eq!(scrubber.backend().mismatches(), 1);
+        assert_eq!(
+            events.borrow()[0],
+            ScrubEvent::Error(ErrorEvent {
+                addr: 0x10100,
+                area: None,
+                severity: ErrorSeverity::Uncorrected,
+            })
+        );
+
+        // The change is now what is expected
+        scrubber.scrub(4096).unwrap();
+        assert_eq!(scrubber.backend().mismatches(), 1);
+    }
+
+    #[test]
+    fn test_double_read() {
+        let flaky = Flaky {
+            mem: SimMemory::new(0, 4096, 64).unwrap(),
+            addr: 0x200,
+            flip: false,
+        };
+        let mut verify =
+            VerifyBackend::new(flaky, 64, VerifyMode::DoubleRead);
+        for addr in (0..4096).step_by(64) {
+            verify.read_line(addr).unwrap();
+        }
+        assert_eq!(verify.mismatches(), 1);
+        assert_eq!(verify.backend().mem.reads_at(0x200), Some(2));
+    }
+}
@@ -0,0 +1,121 @@
+// Bounding of chunk execution time for hard real-time use. A scrub task
+// given a fixed time slot must never overrun it, so the number of cache
+// lines read in one chunk is limited to what fits in the slot at the worst
+// per-line cost. That cost can be configured from offline analysis or
+// measured as scrubbing proceeds, in which case the bound tightens whenever
+// a slower line is seen.
+
+use std::time::Duration;
+
+/// Limit on the number of cache lines scrubbed per chunk
+///
+/// * `Lines` - A fixed number of lines
+///
+/// * `Budget` - As many lines as fit in a time budget at a configured
+///   per-line cost
+///     * `budget` - Time allowed per chunk
+///     * `line_cost` - Worst-case time to scrub one line
+///
+/// * `Measured` - As many lines as fit in a time budget at the worst
+///   per-line cost observed so far
+///     * `budget` - Time allowed per chunk
+///     * `line_cost` - Worst per-line cost observed, zero before the first
+///       chunk
+///     * `initial_lines` - Lines allowed before any cost is observed
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChunkBound {
+    Lines(usize),
+    Budget {
+        budget: Duration,
+        line_cost: Duration,
+    },
+    Measured {
+        budget: Duration,
+        line_cost: Duration,
+        initial_lines: usize,
+    },
+}
+
+impl ChunkBound {
+    /// A bound measured from observed costs
+    ///
+    /// # Arguments:
+    /// * `budget` - Time allowed per chunk
+    ///
+    /// * `initial_lines` - Lines allowed before any cost is observed
+    pub fn measured(budget: Duration, initial_lines: usize) -> ChunkBound {
+        ChunkBound::Measured {
+            budget,
+            line_cost: Duration::ZERO,
+            initial_lines,
+        }
+    }
+
+    /// Returns the most cache lines a chunk may hold. This is never less
+    /// than one.
+    pub fn max_lines(&self) -> usize {
+        let lines = match *self {
+            ChunkBound::Lines(lines) => lines,
+            ChunkBound::Budget { budget, line_cost }
+            | ChunkBound::Measured {
+                budget, line_cost, ..
+            } if !line_cost.is_zero() => {
+                (budget.as_nanos() / line_cost.as_nanos()) as usize
+            }
+            ChunkBound::Budget { .. } => usize::MAX,
+            ChunkBound::Measured { initial_lines, .. } => initial_lines,
+        };
+        lines.max(1)
+    }
+
+    /// Record the time taken to scrub a chunk. A measured bound takes the
+    /// per-line cost into account.
+    ///
+    /// # Arguments:
+    /// * `lines` - Number of cache lines in the chunk
+    ///
+    /// * `duration` - Time taken to scrub the chunk
+    pub fn observe(&mut self, lines: usize, duration: Duration) {
+        if let ChunkBound::Measured { line_cost, .. } = self {
+            if lines != 0 {
+                let cost = duration.as_nanos() / lines as u128;
+                *line_cost =
+                    (*line_cost).max(Duration::from_nanos(cost as u64));
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bounds() {
+        assert_eq!(ChunkBound::Lines(16).max_lines(), 16);
+        assert_eq!(ChunkBound::Lines(0).max_lines(), 1);
+
+        let bound = ChunkBound::Budget {
+            budget: Duration::from_micros(100),
+            line_cost: Duration::from_nanos(40),
+        };
+        assert_eq!(bound.max_lines(), 2500);
+    }
+
+    #[test]
+    fn test_measured() {
+        let mut bound =
+            ChunkBound::measured(Duration::from_micros(10), 64);
+        assert_eq!(bound.max_lines(), 64);
+
+        bound.observe(64, Duration::from_nanos(64 * 50));
+        assert_eq!(bound.max_lines(), 200);
+
+        // A faster chunk doesn't loosen the bound
+        bound.observe(200, Duration::from_nanos(200 * 20));
+        assert_eq!(bound.max_lines(), 200);
+
+        bound.observe(100, Duration::from_nanos(100 * 100));
+        assert_eq!(bound.max_lines(), 100);
+    }
+}